@@ -18,6 +18,11 @@
 //! the first 2 | bytes are the length
 //!             the next 2 bytes are the packet id.
 //! ```
+//!
+//! Newer clients also seal every packet with 8 extra bytes right after the
+//! body, `TQClient` for the packets they send and `TQServer` for the packets
+//! they expect. The seal is not counted in the length and is not encrypted,
+//! see [`Seal`].
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use core::future::Future;
@@ -32,6 +37,60 @@ use tokio::io::{
 use tokio_stream::Stream;
 use tq_crypto::Cipher;
 
+/// Length of the seal appended to every packet when sealing is enabled.
+pub const SEAL_LEN: usize = 8;
+
+/// Controls the seal added at the end of every packet.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Seal {
+    /// Packets are not sealed, this is what the older clients speak.
+    #[default]
+    None,
+    /// Every packet ends with a seal, we expect `inbound` at the end of the
+    /// packets we read, and append `outbound` to the packets we write.
+    Classic {
+        inbound: [u8; SEAL_LEN],
+        outbound: [u8; SEAL_LEN],
+    },
+}
+
+impl Seal {
+    /// The seals used by a client talking to a server.
+    pub const CLIENT: Self = Self::Classic {
+        inbound: *b"TQServer",
+        outbound: *b"TQClient",
+    };
+    /// The seals used by a server talking to a client.
+    pub const SERVER: Self = Self::Classic {
+        inbound: *b"TQClient",
+        outbound: *b"TQServer",
+    };
+
+    /// The number of bytes the seal adds to every packet.
+    pub const fn size(&self) -> usize {
+        match self {
+            Self::None => 0,
+            Self::Classic { .. } => SEAL_LEN,
+        }
+    }
+
+    pub const fn is_none(&self) -> bool { matches!(self, Self::None) }
+
+    fn inbound(&self) -> Option<&[u8; SEAL_LEN]> {
+        match self {
+            Self::None => None,
+            Self::Classic { inbound, .. } => Some(inbound),
+        }
+    }
+
+    fn outbound(&self) -> Option<&[u8; SEAL_LEN]> {
+        match self {
+            Self::None => None,
+            Self::Classic { outbound, .. } => Some(outbound),
+        }
+    }
+}
+
 /// A simple State Machine for Decoding the Stream.
 #[derive(Debug, Clone, Copy)]
 enum DecodeState {
@@ -47,6 +106,8 @@ pub struct TQDecoder<S: AsyncRead + AsyncWrite, C: Cipher> {
     state: DecodeState,
     /// Cipher Used to Decrypt Packets
     cipher: C,
+    /// The seal we expect at the end of every packet.
+    seal: Seal,
     /// Buffer used when reading from the stream. Data is not returned from
     /// this buffer until an entire packet has been read.
     buf: BytesMut,
@@ -104,6 +165,14 @@ impl<S: AsyncRead + AsyncWrite, C: Cipher> TQDecoder<S, C> {
             // get type
             let packet_id = ty.as_ref().get_u16_le();
             tracing::trace!(%n, %packet_id, "decoded head");
+            // The length counts the head itself.
+            if n < 4 {
+                tracing::warn!(%n, %packet_id, "Frame too small!");
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Frame Too Small",
+                ));
+            }
             if n > 2048 {
                 tracing::warn!(%n, %packet_id, "Frame too big!");
                 return Err(io::Error::new(
@@ -115,7 +184,7 @@ impl<S: AsyncRead + AsyncWrite, C: Cipher> TQDecoder<S, C> {
         };
         // Ensure that the buffer has enough space to read the incoming
         // payload
-        self.buf.reserve(n - 4);
+        self.buf.reserve(n - 4 + self.seal.size());
         // Drop the header
         let _ = self.buf.split_to(4);
 
@@ -133,7 +202,7 @@ impl<S: AsyncRead + AsyncWrite, C: Cipher> TQDecoder<S, C> {
         // At this point, the buffer has already had the required capacity
         // reserved. All there is to do is read.
 
-        if self.buf.len() < n {
+        if self.buf.len() < n + self.seal.size() {
             tracing::trace!("Buffer too small, skipping");
             return Ok(None);
         }
@@ -142,6 +211,21 @@ impl<S: AsyncRead + AsyncWrite, C: Cipher> TQDecoder<S, C> {
         data.resize(n, 0);
         // Decrypt the data
         self.cipher.decrypt(buf, &mut data);
+        // Strip and validate the seal, it is not encrypted.
+        if let Some(expected) = self.seal.inbound() {
+            let seal = self.buf.split_to(SEAL_LEN);
+            if seal.as_ref() != expected {
+                tracing::warn!(?seal, "Invalid packet seal!");
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Invalid packet seal, expected {:?} but got {:?}",
+                        String::from_utf8_lossy(expected),
+                        String::from_utf8_lossy(&seal),
+                    ),
+                ));
+            }
+        }
         Ok(Some(data))
     }
 }
@@ -149,6 +233,8 @@ impl<S: AsyncRead + AsyncWrite, C: Cipher> TQDecoder<S, C> {
 pub struct TQEncoder<S: AsyncRead + AsyncWrite, C: Cipher> {
     /// Cipher Used to Encrypt Packets
     cipher: C,
+    /// The seal we append to every packet.
+    seal: Seal,
     /// Buffer used to stage data before writing it to the socket.
     buf: BytesMut,
     /// The Underlaying Write Half of Socket
@@ -161,12 +247,23 @@ impl<S: AsyncRead + AsyncWrite, C: Cipher> TQEncoder<S, C> {
     /// Items (u16, Bytes) got Encoded and Encrypted and sent to socket.
     #[tracing::instrument(skip(self, item))]
     pub async fn send(&mut self, item: (u16, Bytes)) -> Result<(), io::Error> {
+        self.queue(item)?;
+        self.flush().await?;
+        Ok(())
+    }
+
+    /// Encode and Encrypt the Item into the write buffer without sending it,
+    /// it gets sent on the next call to [`TQEncoder::flush`].
+    #[tracing::instrument(skip(self, item))]
+    pub fn queue(&mut self, item: (u16, Bytes)) -> Result<(), io::Error> {
         let buf = self.encode_data(item.0, item.1)?;
         self.buffer(&buf);
-        self.flush().await?;
         Ok(())
     }
 
+    /// How many bytes are waiting in the write buffer.
+    pub fn buffered(&self) -> usize { self.buf.len() }
+
     /// Close The Socket .. No More IO.
     #[tracing::instrument(skip(self))]
     pub async fn close(&mut self) -> Result<(), io::Error> {
@@ -192,10 +289,14 @@ impl<S: AsyncRead + AsyncWrite, C: Cipher> TQEncoder<S, C> {
             "\nServer -> Client ID({packet_id}) Length({n})\n{:?}",
             body.as_ref().hex_conf(config)
         );
-        let mut encrypted_data = BytesMut::with_capacity(n);
+        let mut encrypted_data = BytesMut::with_capacity(n + self.seal.size());
         encrypted_data.resize(n, 0);
         // encrypt data
         self.cipher.encrypt(&full_packet, &mut encrypted_data);
+        // the seal goes after the encrypted packet as is.
+        if let Some(seal) = self.seal.outbound() {
+            encrypted_data.extend_from_slice(seal);
+        }
         Ok(encrypted_data.freeze())
     }
 
@@ -221,7 +322,7 @@ impl<S: AsyncRead + AsyncWrite, C: Cipher> TQEncoder<S, C> {
 
     /// Flush the write buffer to the socket
     #[tracing::instrument(skip(self))]
-    async fn flush(&mut self) -> Result<(), io::Error> {
+    pub async fn flush(&mut self) -> Result<(), io::Error> {
         tracing::trace!("flushing data into stream");
         // As long as there is buffered data to write, try to write it.
         while self.buf.has_remaining() {
@@ -237,22 +338,31 @@ impl<S: AsyncRead + AsyncWrite, C: Cipher> TQEncoder<S, C> {
 pub struct TQCodec<S: AsyncRead + AsyncWrite, C: Cipher + Clone> {
     stream: S,
     cipher: C,
+    seal: Seal,
 }
 
 impl<S: AsyncRead + AsyncWrite, C: Cipher + Clone> TQCodec<S, C> {
-    pub fn new(stream: S, cipher: C) -> Self { Self { stream, cipher } }
+    pub fn new(stream: S, cipher: C, seal: Seal) -> Self {
+        Self {
+            stream,
+            cipher,
+            seal,
+        }
+    }
 
     pub fn split(self) -> (TQEncoder<S, C>, TQDecoder<S, C>) {
         let (rdr, wrt) = split(self.stream);
         let encoder = TQEncoder {
             buf: BytesMut::with_capacity(64),
             cipher: self.cipher.clone(),
+            seal: self.seal,
             wrt,
         };
         let decoder = TQDecoder {
             state: DecodeState::Head,
             buf: BytesMut::with_capacity(64),
             cipher: self.cipher,
+            seal: self.seal,
             rdr,
         };
         (encoder, decoder)
@@ -317,3 +427,124 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+    use tokio_stream::StreamExt;
+    use tq_crypto::NopCipher;
+
+    fn packets() -> Vec<(u16, Bytes)> {
+        vec![
+            (1052, Bytes::from_static(b"first packet")),
+            (1010, Bytes::from_static(&[1, 2, 3, 4, 5, 6, 7, 8])),
+            (1004, Bytes::from_static(b"")),
+        ]
+    }
+
+    async fn round_trip<C: Cipher + Clone + Unpin>(
+        cipher: C,
+        client: Seal,
+        server: Seal,
+    ) -> Vec<io::Result<(u16, Bytes)>> {
+        let (a, b) = duplex(1024);
+        let (mut encoder, _) = TQCodec::new(a, cipher.clone(), client).split();
+        let (_, mut decoder) = TQCodec::new(b, cipher, server).split();
+        for packet in packets() {
+            encoder.send(packet).await.unwrap();
+        }
+        encoder.close().await.unwrap();
+        let mut received = Vec::new();
+        while let Some(packet) = decoder.next().await {
+            let failed = packet.is_err();
+            received.push(packet);
+            if failed {
+                break;
+            }
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn round_trip_without_seal() {
+        let received = round_trip(NopCipher, Seal::None, Seal::None)
+            .await
+            .into_iter()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(received, packets());
+    }
+
+    #[tokio::test]
+    async fn round_trip_with_seal() {
+        let received = round_trip(NopCipher, Seal::CLIENT, Seal::SERVER)
+            .await
+            .into_iter()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(received, packets());
+    }
+
+    #[tokio::test]
+    async fn seal_is_appended_as_is() {
+        let (a, mut b) = duplex(1024);
+        let (mut encoder, _) = TQCodec::new(a, NopCipher, Seal::SERVER).split();
+        encoder
+            .send((1052, Bytes::from_static(b"hi")))
+            .await
+            .unwrap();
+        encoder.close().await.unwrap();
+        let mut wire = Vec::new();
+        b.read_to_end(&mut wire).await.unwrap();
+        // The length does not count the seal.
+        assert_eq!(&wire[..2], &6u16.to_le_bytes());
+        assert_eq!(&wire[2..4], &1052u16.to_le_bytes());
+        assert_eq!(&wire[4..6], b"hi");
+        assert_eq!(&wire[6..], b"TQServer");
+    }
+
+    #[tokio::test]
+    async fn mixed_seal_modes_fail() {
+        let received = round_trip(NopCipher, Seal::None, Seal::SERVER).await;
+        let err = received
+            .into_iter()
+            .find_map(Result::err)
+            .expect("unsealed packets should be rejected");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(
+            err.to_string().starts_with(
+                "Invalid packet seal, expected \"TQClient\" but got"
+            ),
+            "{err}"
+        );
+
+        // A client sealing its packets with the server seal.
+        let received = round_trip(NopCipher, Seal::SERVER, Seal::SERVER).await;
+        let err = received
+            .into_iter()
+            .find_map(Result::err)
+            .expect("wrong seal should be rejected");
+        assert_eq!(
+            err.to_string(),
+            "Invalid packet seal, expected \"TQClient\" but got \"TQServer\""
+        );
+    }
+
+    #[tokio::test]
+    async fn length_shorter_than_the_head_fails() {
+        // Found by the packet fuzz tests, the length has to at least cover
+        // the length and the id.
+        for len in 0..4u16 {
+            let (mut a, b) = duplex(64);
+            let mut wire = len.to_le_bytes().to_vec();
+            wire.extend_from_slice(&1052u16.to_le_bytes());
+            a.write_all(&wire).await.unwrap();
+            drop(a);
+            let (_, mut decoder) =
+                TQCodec::new(b, NopCipher, Seal::None).split();
+            let err = decoder.next().await.unwrap().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
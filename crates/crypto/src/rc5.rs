@@ -122,8 +122,25 @@ impl crate::Cipher for TQRC5 {
         }
     }
 
-    fn encrypt(&self, _src: &[u8], _dst: &mut [u8]) {
-        unimplemented!("RC5 encryption is not implemented")
+    /// The other way around of [`decrypt`](Self::decrypt), the way the
+    /// client encrypts passwords. Only whole blocks of 8 bytes are
+    /// encrypted.
+    fn encrypt(&self, src: &[u8], dst: &mut [u8]) {
+        dst.copy_from_slice(src);
+        let sub = self.sub;
+        for block in dst.chunks_exact_mut(8) {
+            let (a, b) = block.split_at(4);
+            let mut a = u32::from_le_bytes(a.try_into().unwrap());
+            let mut b = u32::from_le_bytes(b.try_into().unwrap());
+            a = a.wrapping_add(sub[0]);
+            b = b.wrapping_add(sub[1]);
+            for round in 1..=self.rounds as usize {
+                a = (a ^ b).rotate_left(b).wrapping_add(sub[2 * round]);
+                b = (b ^ a).rotate_left(a).wrapping_add(sub[2 * round + 1]);
+            }
+            block[..4].copy_from_slice(&a.to_le_bytes());
+            block[4..].copy_from_slice(&b.to_le_bytes());
+        }
     }
 }
 
@@ -147,5 +164,8 @@ mod tests {
                 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
             ]
         );
+        let mut encrypted = [0u8; 16];
+        rc5.encrypt(&res, &mut encrypted);
+        assert_eq!(encrypted, buf);
     }
 }
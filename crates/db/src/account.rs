@@ -12,6 +12,10 @@ pub struct Account {
     pub password: String,
     pub name: Option<String>,
     pub email: Option<String>,
+    /// Zero for players, anything above lets the account use GM commands.
+    pub gm_level: u8,
+    /// Extra account flags, like VIP or parental control, as a bitfield.
+    pub privileges: u32,
 }
 
 impl Account {
@@ -76,4 +80,56 @@ impl Account {
             Ok(self)
         }
     }
+
+    /// Whether the account has a warehouse password.
+    pub async fn has_warehouse_password(
+        pool: &SqlitePool,
+        id: i32,
+    ) -> Result<bool, Error> {
+        let (set,) = sqlx::query_as::<_, (bool,)>(
+            "SELECT warehouse_password IS NOT NULL FROM accounts WHERE account_id = ?;",
+        )
+        .bind(id)
+        .fetch_one(pool)
+        .await?;
+        Ok(set)
+    }
+
+    /// Sets the warehouse password of the account, hashed using bcrypt with
+    /// the given cost.
+    pub async fn set_warehouse_password(
+        pool: &SqlitePool,
+        id: i32,
+        password: &str,
+        cost: u32,
+    ) -> Result<(), Error> {
+        let hash = bcrypt::hash(password, cost)?;
+        sqlx::query(
+            "UPDATE accounts SET warehouse_password = ? WHERE account_id = ?;",
+        )
+        .bind(hash)
+        .bind(id)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Checks the password against the warehouse password of the account,
+    /// `None` if it has none.
+    pub async fn verify_warehouse_password(
+        pool: &SqlitePool,
+        id: i32,
+        password: &str,
+    ) -> Result<Option<bool>, Error> {
+        let (hash,) = sqlx::query_as::<_, (Option<String>,)>(
+            "SELECT warehouse_password FROM accounts WHERE account_id = ?;",
+        )
+        .bind(id)
+        .fetch_one(pool)
+        .await?;
+        match hash {
+            Some(hash) => Ok(Some(bcrypt::verify(password, &hash)?)),
+            None => Ok(None),
+        }
+    }
 }
@@ -1,4 +1,4 @@
-use sqlx::SqlitePool;
+use sqlx::{SqliteExecutor, SqlitePool};
 
 use crate::Error;
 
@@ -35,6 +35,12 @@ pub struct Character {
     pub health_points: i16,
     pub mana_points: i16,
     pub kill_points: i16,
+    /// Whether the character already got the starter kit.
+    pub received_starter_kit: bool,
+    /// The name of the character this one is married to, `None` if single.
+    pub spouse: String,
+    /// The bonus attribute points granted by rebirths.
+    pub rebirth_points: i16,
 }
 
 #[derive(Debug, sqlx::FromRow)]
@@ -45,6 +51,9 @@ pub struct Location {
 }
 
 impl Character {
+    /// What the client shows as the spouse of a single character.
+    pub const NO_SPOUSE: &'static str = "None";
+
     pub async fn from_account(
         pool: &SqlitePool,
         id: u32,
@@ -58,6 +67,22 @@ impl Character {
         Ok(maybe_character)
     }
 
+    /// How many characters the account has.
+    ///
+    /// It could be given a transaction, to count a character inserted in it.
+    pub async fn count_of_account<'e, E: SqliteExecutor<'e>>(
+        executor: E,
+        account_id: u32,
+    ) -> Result<u32, Error> {
+        let (count,) = sqlx::query_as::<_, (u32,)>(
+            "SELECT COUNT(*) FROM characters WHERE account_id = ?;",
+        )
+        .bind(account_id)
+        .fetch_one(executor)
+        .await?;
+        Ok(count)
+    }
+
     pub async fn name_taken(
         pool: &SqlitePool,
         name: &str,
@@ -76,17 +101,41 @@ impl Character {
         }
     }
 
-    pub async fn by_id(pool: &SqlitePool, id: i32) -> Result<Self, Error> {
+    /// The id of the character with that name, if there is one.
+    pub async fn id_by_name(
+        pool: &SqlitePool,
+        name: &str,
+    ) -> Result<Option<i32>, Error> {
+        let id = sqlx::query_as::<_, (i32,)>(
+            "SELECT character_id FROM characters WHERE name = ?;",
+        )
+        .bind(name)
+        .fetch_optional(pool)
+        .await?;
+        Ok(id.map(|(id,)| id))
+    }
+
+    pub async fn by_id<'e, E: SqliteExecutor<'e>>(
+        executor: E,
+        id: i32,
+    ) -> Result<Self, Error> {
         let c = sqlx::query_as::<_, Self>(
             "SELECT * FROM characters WHERE character_id = ?;",
         )
         .bind(id)
-        .fetch_one(pool)
+        .fetch_one(executor)
         .await?;
         Ok(c)
     }
 
-    pub async fn save(self, pool: &SqlitePool) -> Result<i32, Error> {
+    /// Inserts the character, returning its id.
+    ///
+    /// It could be given a transaction, to insert the character along with
+    /// anything depending on it.
+    pub async fn save<'e, E: SqliteExecutor<'e>>(
+        self,
+        executor: E,
+    ) -> Result<i32, Error> {
         let (id,) = sqlx::query_as::<_, (i32,)>(
             "
             INSERT INTO characters
@@ -94,13 +143,15 @@ impl Character {
                     account_id, realm_id, name, mesh, avatar,
                     hair_style, silver, current_class,
                     map_id, x, y, virtue, strength, agility,
-                    vitality, spirit, health_points, mana_points
+                    vitality, spirit, attribute_points, health_points,
+                    mana_points
                 )
             VALUES 
                 (
                     ?, ?, ?, ?, ?, ?,
                     ?, ?, ?, ?, ?, ?,
-                    ?, ?, ?, ?, ?, ?
+                    ?, ?, ?, ?, ?, ?,
+                    ?
                 )
             RETURNING character_id;
             ",
@@ -121,9 +172,10 @@ impl Character {
         .bind(self.agility)
         .bind(self.vitality)
         .bind(self.spirit)
+        .bind(self.attribute_points)
         .bind(self.health_points)
         .bind(self.mana_points)
-        .fetch_one(pool)
+        .fetch_one(executor)
         .await?;
         Ok(id)
     }
@@ -138,7 +190,12 @@ impl Character {
                 avatar = ?,
                 hair_style = ?,
                 silver = ?,
+                cps = ?,
                 current_class = ?,
+                previous_class = ?,
+                rebirths = ?,
+                level = ?,
+                experience = ?,
                 map_id = ?,
                 x = ?, y = ?, 
                 virtue = ?,
@@ -146,8 +203,12 @@ impl Character {
                 agility = ?,
                 vitality = ?,
                 spirit = ?,
+                attribute_points = ?,
                 health_points = ?,
-                mana_points = ?
+                mana_points = ?,
+                kill_points = ?,
+                spouse = ?,
+                rebirth_points = ?
             WHERE character_id = ?;
             ",
         )
@@ -156,7 +217,12 @@ impl Character {
         .bind(self.avatar)
         .bind(self.hair_style)
         .bind(self.silver)
+        .bind(self.cps)
         .bind(self.current_class)
+        .bind(self.previous_class)
+        .bind(self.rebirths)
+        .bind(self.level)
+        .bind(self.experience)
         .bind(self.map_id)
         .bind(self.x)
         .bind(self.y)
@@ -165,11 +231,233 @@ impl Character {
         .bind(self.agility)
         .bind(self.vitality)
         .bind(self.spirit)
+        .bind(self.attribute_points)
         .bind(self.health_points)
         .bind(self.mana_points)
+        .bind(self.kill_points)
+        .bind(self.spouse)
+        .bind(self.rebirth_points)
         .bind(self.character_id)
         .execute(pool)
         .await?;
         Ok(())
     }
+
+    /// Gives the character the starter kit, putting the items into its
+    /// inventory and adding the silver to its wallet, then marks the kit as
+    /// received so it is never granted again.
+    ///
+    /// Returns `false` without granting anything if the inventory has no room
+    /// for all the items, or if the kit was already received.
+    pub async fn grant_starter_kit(
+        &mut self,
+        pool: &SqlitePool,
+        item_types: &[i32],
+        silver: i64,
+        inventory_size: usize,
+    ) -> Result<bool, Error> {
+        let mut tx = pool.begin().await?;
+        let (in_inventory,) = sqlx::query_as::<_, (i64,)>(
+            "SELECT COUNT(*) FROM items WHERE character_id = ? AND position = ?;",
+        )
+        .bind(self.character_id)
+        .bind(crate::item::Item::INVENTORY)
+        .fetch_one(&mut *tx)
+        .await?;
+        if in_inventory as usize + item_types.len() > inventory_size {
+            return Ok(false);
+        }
+        let res = sqlx::query(
+            "
+            UPDATE characters
+            SET received_starter_kit = 1, silver = silver + ?
+            WHERE character_id = ? AND received_starter_kit = 0;
+            ",
+        )
+        .bind(silver)
+        .bind(self.character_id)
+        .execute(&mut *tx)
+        .await?;
+        if res.rows_affected() == 0 {
+            return Ok(false);
+        }
+        for item_type in item_types {
+            sqlx::query(
+                "INSERT INTO items (character_id, item_type, position) VALUES (?, ?, ?);",
+            )
+            .bind(self.character_id)
+            .bind(item_type)
+            .bind(crate::item::Item::INVENTORY)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        self.silver += silver;
+        self.received_starter_kit = true;
+        Ok(true)
+    }
+
+    /// Reborns the character: writes its new class, level and attributes,
+    /// uses up the required item from its inventory, and moves the
+    /// equipment it could not wear anymore to its inventory, or to its
+    /// mailbox once the inventory is full.
+    ///
+    /// The character should already hold its reborn state. Returns how many
+    /// of the unequipped items went to the mailbox, or `None` without
+    /// changing anything if the required item is gone.
+    pub async fn rebirth(
+        &self,
+        pool: &SqlitePool,
+        required_item_id: i32,
+        unequip: &[i32],
+        inventory_size: usize,
+    ) -> Result<Option<usize>, Error> {
+        use crate::item::Item;
+        let mut tx = pool.begin().await?;
+        let res = sqlx::query(
+            "DELETE FROM items WHERE item_id = ? AND character_id = ? AND position = ?;",
+        )
+        .bind(required_item_id)
+        .bind(self.character_id)
+        .bind(Item::INVENTORY)
+        .execute(&mut *tx)
+        .await?;
+        if res.rows_affected() == 0 {
+            return Ok(None);
+        }
+        sqlx::query(
+            "
+            UPDATE characters
+            SET
+                current_class = ?,
+                previous_class = ?,
+                rebirths = ?,
+                level = ?,
+                experience = ?,
+                strength = ?,
+                agility = ?,
+                vitality = ?,
+                spirit = ?,
+                attribute_points = ?,
+                health_points = ?,
+                mana_points = ?,
+                rebirth_points = ?
+            WHERE character_id = ?;
+            ",
+        )
+        .bind(self.current_class)
+        .bind(self.previous_class)
+        .bind(self.rebirths)
+        .bind(self.level)
+        .bind(self.experience)
+        .bind(self.strength)
+        .bind(self.agility)
+        .bind(self.vitality)
+        .bind(self.spirit)
+        .bind(self.attribute_points)
+        .bind(self.health_points)
+        .bind(self.mana_points)
+        .bind(self.rebirth_points)
+        .bind(self.character_id)
+        .execute(&mut *tx)
+        .await?;
+        let (in_inventory,) = sqlx::query_as::<_, (i64,)>(
+            "SELECT COUNT(*) FROM items WHERE character_id = ? AND position = ?;",
+        )
+        .bind(self.character_id)
+        .bind(Item::INVENTORY)
+        .fetch_one(&mut *tx)
+        .await?;
+        let room = inventory_size.saturating_sub(in_inventory as usize);
+        for (i, item_id) in unequip.iter().enumerate() {
+            let position = if i < room {
+                Item::INVENTORY
+            } else {
+                Item::MAILBOX
+            };
+            sqlx::query(
+                "UPDATE items SET position = ? WHERE item_id = ? AND character_id = ?;",
+            )
+            .bind(position)
+            .bind(item_id)
+            .bind(self.character_id)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(Some(unequip.len().saturating_sub(room)))
+    }
+
+    /// Marries the two characters, writing each one's name as the other's
+    /// spouse.
+    ///
+    /// Returns `false` without changing anything if any of them is already
+    /// married.
+    pub async fn marry(
+        pool: &SqlitePool,
+        (a_id, a_name): (i32, &str),
+        (b_id, b_name): (i32, &str),
+    ) -> Result<bool, Error> {
+        let mut tx = pool.begin().await?;
+        for (id, spouse) in [(a_id, b_name), (b_id, a_name)] {
+            let res = sqlx::query(
+                "UPDATE characters SET spouse = ? WHERE character_id = ? AND spouse = ?;",
+            )
+            .bind(spouse)
+            .bind(id)
+            .bind(Self::NO_SPOUSE)
+            .execute(&mut *tx)
+            .await?;
+            if res.rows_affected() == 0 {
+                return Ok(false);
+            }
+        }
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    /// Clears the spouse of the character and of whoever it was married to.
+    ///
+    /// Returns the name of the former spouse, `None` if the character was not
+    /// married.
+    pub async fn divorce(
+        pool: &SqlitePool,
+        id: i32,
+    ) -> Result<Option<String>, Error> {
+        let mut tx = pool.begin().await?;
+        let (name, spouse) = sqlx::query_as::<_, (String, String)>(
+            "SELECT name, spouse FROM characters WHERE character_id = ?;",
+        )
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+        if spouse == Self::NO_SPOUSE {
+            return Ok(None);
+        }
+        sqlx::query(
+            "
+            UPDATE characters
+            SET spouse = ?
+            WHERE character_id = ? OR (name = ? AND spouse = ?);
+            ",
+        )
+        .bind(Self::NO_SPOUSE)
+        .bind(id)
+        .bind(&spouse)
+        .bind(name)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(Some(spouse))
+    }
+
+    /// Deletes the character, along with its items and everything else it
+    /// owns. Returns `false` if there was no such character.
+    pub async fn delete(pool: &SqlitePool, id: i32) -> Result<bool, Error> {
+        let res = sqlx::query("DELETE FROM characters WHERE character_id = ?;")
+            .bind(id)
+            .execute(pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
 }
@@ -0,0 +1,44 @@
+use sqlx::SqlitePool;
+
+use crate::Error;
+
+/// Something a client did that it could only do if it was tampered with,
+/// kept for the staff to look into. Times are unix timestamps in seconds.
+#[derive(Clone, Debug, Default, PartialEq, Eq, sqlx::FromRow)]
+pub struct CheatLog {
+    pub cheat_id: i64,
+    pub character_id: i32,
+    /// What was caught, like `equip_position`.
+    pub kind: String,
+    pub detail: String,
+    pub caught_at: i64,
+}
+
+impl CheatLog {
+    pub async fn insert(&self, pool: &SqlitePool) -> Result<(), Error> {
+        sqlx::query(
+            "INSERT INTO cheat_log (character_id, kind, detail, caught_at) VALUES (?, ?, ?, ?);",
+        )
+        .bind(self.character_id)
+        .bind(&self.kind)
+        .bind(&self.detail)
+        .bind(self.caught_at)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Everything caught from the character, oldest first.
+    pub async fn of_character(
+        pool: &SqlitePool,
+        character_id: i32,
+    ) -> Result<Vec<Self>, Error> {
+        let logs = sqlx::query_as::<_, Self>(
+            "SELECT * FROM cheat_log WHERE character_id = ? ORDER BY cheat_id;",
+        )
+        .bind(character_id)
+        .fetch_all(pool)
+        .await?;
+        Ok(logs)
+    }
+}
@@ -0,0 +1,90 @@
+use sqlx::SqlitePool;
+
+use crate::Error;
+
+/// A single session of a client on the game server, from the moment it
+/// connected with a login token until it disconnected. Times are unix
+/// timestamps in seconds.
+#[derive(Clone, Debug, Default, PartialEq, Eq, sqlx::FromRow)]
+pub struct ConnectionLog {
+    pub session_id: i64,
+    pub account_id: i32,
+    pub character_id: Option<i32>,
+    pub ip: Option<String>,
+    pub connected_at: i64,
+    pub disconnected_at: Option<i64>,
+    pub disconnect_reason: Option<String>,
+}
+
+impl ConnectionLog {
+    pub async fn insert(&self, pool: &SqlitePool) -> Result<(), Error> {
+        sqlx::query(
+            "INSERT INTO connection_log (session_id, account_id, character_id, ip, connected_at) VALUES (?, ?, ?, ?, ?);",
+        )
+        .bind(self.session_id)
+        .bind(self.account_id)
+        .bind(self.character_id)
+        .bind(&self.ip)
+        .bind(self.connected_at)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// The highest session id written so far, zero if there is none.
+    pub async fn last_session_id(pool: &SqlitePool) -> Result<i64, Error> {
+        let (id,) = sqlx::query_as::<_, (i64,)>(
+            "SELECT COALESCE(MAX(session_id), 0) FROM connection_log;",
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(id)
+    }
+
+    /// Marks the session as ended at the given time, for the given reason.
+    pub async fn close(
+        pool: &SqlitePool,
+        session_id: i64,
+        disconnected_at: i64,
+        reason: &str,
+    ) -> Result<(), Error> {
+        sqlx::query(
+            "UPDATE connection_log SET disconnected_at = ?, disconnect_reason = ? WHERE session_id = ?;",
+        )
+        .bind(disconnected_at)
+        .bind(reason)
+        .bind(session_id)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn by_session(
+        pool: &SqlitePool,
+        session_id: i64,
+    ) -> Result<Option<Self>, Error> {
+        let log = sqlx::query_as::<_, Self>(
+            "SELECT * FROM connection_log WHERE session_id = ?;",
+        )
+        .bind(session_id)
+        .fetch_optional(pool)
+        .await?;
+        Ok(log)
+    }
+
+    /// The latest sessions of the account, newest first.
+    pub async fn latest_of_account(
+        pool: &SqlitePool,
+        account_id: i32,
+        limit: i64,
+    ) -> Result<Vec<Self>, Error> {
+        let logs = sqlx::query_as::<_, Self>(
+            "SELECT * FROM connection_log WHERE account_id = ? ORDER BY connected_at DESC LIMIT ?;",
+        )
+        .bind(account_id)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+        Ok(logs)
+    }
+}
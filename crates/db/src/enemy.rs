@@ -0,0 +1,109 @@
+use crate::Error;
+use sqlx::SqlitePool;
+
+/// Someone who killed a character, shown in the enemies list of its client.
+#[derive(Debug, Clone, Default, PartialEq, Eq, sqlx::FromRow)]
+pub struct Enemy {
+    pub character_id: i32,
+    pub enemy_id: i32,
+    /// The name of the enemy when it last killed the character.
+    pub enemy_name: String,
+    /// When it last killed the character, in seconds since the epoch.
+    pub killed_at: i64,
+}
+
+impl Enemy {
+    /// Records the enemy as the most recent one of the character, dropping
+    /// the oldest ones beyond the `cap` most recent.
+    ///
+    /// Returns the ids of the dropped enemies.
+    pub async fn record(
+        &self,
+        pool: &SqlitePool,
+        cap: i64,
+    ) -> Result<Vec<i32>, Error> {
+        let mut tx = pool.begin().await?;
+        // Deleted and inserted again, so it is the newest row even when it
+        // killed the character twice in the same second.
+        sqlx::query(
+            "DELETE FROM character_enemies WHERE character_id = ? AND enemy_id = ?;",
+        )
+        .bind(self.character_id)
+        .bind(self.enemy_id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            "INSERT INTO character_enemies (character_id, enemy_id, enemy_name, killed_at) VALUES (?, ?, ?, ?);",
+        )
+        .bind(self.character_id)
+        .bind(self.enemy_id)
+        .bind(&self.enemy_name)
+        .bind(self.killed_at)
+        .execute(&mut *tx)
+        .await?;
+        let evicted = sqlx::query_as::<_, (i32,)>(
+            "
+            DELETE FROM character_enemies
+            WHERE character_id = ? AND rowid NOT IN (
+                SELECT rowid FROM character_enemies
+                WHERE character_id = ?
+                ORDER BY killed_at DESC, rowid DESC
+                LIMIT ?
+            )
+            RETURNING enemy_id;
+            ",
+        )
+        .bind(self.character_id)
+        .bind(self.character_id)
+        .bind(cap)
+        .fetch_all(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(evicted.into_iter().map(|(id,)| id).collect())
+    }
+
+    /// The enemies of the character, the most recent first.
+    pub async fn of_character(
+        pool: &SqlitePool,
+        character_id: i32,
+    ) -> Result<Vec<Self>, Error> {
+        let enemies = sqlx::query_as::<_, Self>(
+            "SELECT * FROM character_enemies WHERE character_id = ? ORDER BY killed_at DESC, rowid DESC;",
+        )
+        .bind(character_id)
+        .fetch_all(pool)
+        .await?;
+        Ok(enemies)
+    }
+
+    /// The characters that have the given one as an enemy.
+    pub async fn held_by(
+        pool: &SqlitePool,
+        enemy_id: i32,
+    ) -> Result<Vec<i32>, Error> {
+        let ids = sqlx::query_as::<_, (i32,)>(
+            "SELECT character_id FROM character_enemies WHERE enemy_id = ?;",
+        )
+        .bind(enemy_id)
+        .fetch_all(pool)
+        .await?;
+        Ok(ids.into_iter().map(|(id,)| id).collect())
+    }
+
+    /// Removes the enemy from the list of the character, `false` if it was
+    /// not there.
+    pub async fn remove(
+        pool: &SqlitePool,
+        character_id: i32,
+        enemy_id: i32,
+    ) -> Result<bool, Error> {
+        let result = sqlx::query(
+            "DELETE FROM character_enemies WHERE character_id = ? AND enemy_id = ?;",
+        )
+        .bind(character_id)
+        .bind(enemy_id)
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
@@ -0,0 +1,170 @@
+use crate::Error;
+use sqlx::SqlitePool;
+
+/// A guild, also known as a syndicate, along with the silver in its funds.
+#[derive(Debug, Clone, Default, sqlx::FromRow)]
+pub struct Guild {
+    pub guild_id: i32,
+    pub name: String,
+    /// The character id of the leader.
+    pub leader_id: i32,
+    pub funds: i64,
+}
+
+/// A character in a guild, and its rank there.
+#[derive(Debug, Clone, Default, sqlx::FromRow)]
+pub struct GuildMember {
+    pub character_id: i32,
+    pub guild_id: i32,
+    pub rank: i16,
+    /// The name of the character, joined from the characters table.
+    pub name: String,
+}
+
+impl Guild {
+    pub async fn load_all(pool: &SqlitePool) -> Result<Vec<Self>, Error> {
+        let guilds = sqlx::query_as::<_, Self>("SELECT * FROM guilds;")
+            .fetch_all(pool)
+            .await?;
+        Ok(guilds)
+    }
+
+    /// Creates a guild led by the given character, with `rank` as the rank
+    /// of the leader.
+    ///
+    /// Returns `None` without creating anything if the name is taken or the
+    /// leader is already in a guild.
+    pub async fn create(
+        pool: &SqlitePool,
+        name: &str,
+        leader_id: i32,
+        rank: i16,
+        funds: i64,
+    ) -> Result<Option<Self>, Error> {
+        let mut tx = pool.begin().await?;
+        let guild = sqlx::query_as::<_, Self>(
+            "
+            INSERT INTO guilds (name, leader_id, funds)
+            SELECT ?1, ?2, ?3
+            WHERE NOT EXISTS (SELECT 1 FROM guilds WHERE name = ?1)
+                AND NOT EXISTS (
+                    SELECT 1 FROM guild_members WHERE character_id = ?2
+                )
+            RETURNING *;
+            ",
+        )
+        .bind(name)
+        .bind(leader_id)
+        .bind(funds)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(guild) = guild else {
+            return Ok(None);
+        };
+        sqlx::query(
+            "INSERT INTO guild_members (character_id, guild_id, rank) VALUES (?, ?, ?);",
+        )
+        .bind(leader_id)
+        .bind(guild.guild_id)
+        .bind(rank)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(Some(guild))
+    }
+
+    /// Adds `amount` silver to the funds of the guild, returns `false` if
+    /// there is no such guild.
+    pub async fn add_funds(
+        pool: &SqlitePool,
+        guild_id: i32,
+        amount: i64,
+    ) -> Result<bool, Error> {
+        let res = sqlx::query(
+            "UPDATE guilds SET funds = funds + ? WHERE guild_id = ?;",
+        )
+        .bind(amount)
+        .bind(guild_id)
+        .execute(pool)
+        .await?;
+        Ok(res.rows_affected() == 1)
+    }
+
+    /// Deletes the guild along with its memberships.
+    pub async fn disband(
+        pool: &SqlitePool,
+        guild_id: i32,
+    ) -> Result<(), Error> {
+        sqlx::query("DELETE FROM guilds WHERE guild_id = ?;")
+            .bind(guild_id)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+}
+
+impl GuildMember {
+    /// Returns the members of every guild.
+    pub async fn load_all(pool: &SqlitePool) -> Result<Vec<Self>, Error> {
+        let members = sqlx::query_as::<_, Self>(
+            "
+            SELECT m.character_id, m.guild_id, m.rank, c.name
+            FROM guild_members m
+            JOIN characters c ON c.character_id = m.character_id;
+            ",
+        )
+        .fetch_all(pool)
+        .await?;
+        Ok(members)
+    }
+
+    /// Adds the character to the guild, returns `false` if it is already in
+    /// a guild.
+    pub async fn join(
+        pool: &SqlitePool,
+        guild_id: i32,
+        character_id: i32,
+        rank: i16,
+    ) -> Result<bool, Error> {
+        let res = sqlx::query(
+            "
+            INSERT INTO guild_members (character_id, guild_id, rank)
+            VALUES (?, ?, ?)
+            ON CONFLICT (character_id) DO NOTHING;
+            ",
+        )
+        .bind(character_id)
+        .bind(guild_id)
+        .bind(rank)
+        .execute(pool)
+        .await?;
+        Ok(res.rows_affected() == 1)
+    }
+
+    /// Removes the character from whatever guild it is in.
+    pub async fn leave(
+        pool: &SqlitePool,
+        character_id: i32,
+    ) -> Result<(), Error> {
+        sqlx::query("DELETE FROM guild_members WHERE character_id = ?;")
+            .bind(character_id)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_rank(
+        pool: &SqlitePool,
+        character_id: i32,
+        rank: i16,
+    ) -> Result<(), Error> {
+        sqlx::query(
+            "UPDATE guild_members SET rank = ? WHERE character_id = ?;",
+        )
+        .bind(rank)
+        .bind(character_id)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
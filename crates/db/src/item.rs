@@ -0,0 +1,354 @@
+use crate::Error;
+use sqlx::SqlitePool;
+
+/// An item owned by a character, the position tells where it is kept, like
+/// the inventory or one of the equipment slots.
+#[derive(Debug, Clone, Default, sqlx::FromRow)]
+pub struct Item {
+    pub item_id: i32,
+    pub character_id: i32,
+    pub item_type: i32,
+    pub position: i16,
+    pub plus: i16,
+    pub gem_one: i16,
+    pub gem_two: i16,
+    /// How many are left in a stack, like the arrows in a pack.
+    pub amount: i16,
+}
+
+impl Item {
+    /// The position of items put up for sale in a vending stall.
+    pub const BOOTH: i16 = 255;
+    /// The equipment slots, from the head down to the garment, and the ring
+    /// of the left hand.
+    pub const EQUIPMENT: std::ops::RangeInclusive<i16> = 1..=10;
+    /// The position of items that are kept in the inventory.
+    pub const INVENTORY: i16 = 0;
+    /// The equipment slot of shields, arrows and second weapons.
+    pub const LEFT_HAND: i16 = 5;
+    /// The equipment slot of a second ring.
+    pub const LEFT_RING: i16 = 10;
+    /// The position of items waiting for a character that had no room for
+    /// them in its inventory.
+    pub const MAILBOX: i16 = 254;
+    /// The equipment slot of the weapon.
+    pub const RIGHT_HAND: i16 = 4;
+    /// The equipment slot of rings and bracelets.
+    pub const RING: i16 = 6;
+
+    /// Returns the items in the inventory of the given character.
+    pub async fn inventory_of(
+        pool: &SqlitePool,
+        character_id: i32,
+    ) -> Result<Vec<Self>, Error> {
+        let items = sqlx::query_as::<_, Self>(
+            "SELECT * FROM items WHERE character_id = ? AND position = ?;",
+        )
+        .bind(character_id)
+        .bind(Self::INVENTORY)
+        .fetch_all(pool)
+        .await?;
+        Ok(items)
+    }
+
+    /// Returns the item with the given id, if it is owned by that character.
+    pub async fn of_character(
+        pool: &SqlitePool,
+        item_id: i32,
+        character_id: i32,
+    ) -> Result<Option<Self>, Error> {
+        let item = sqlx::query_as::<_, Self>(
+            "SELECT * FROM items WHERE item_id = ? AND character_id = ?;",
+        )
+        .bind(item_id)
+        .bind(character_id)
+        .fetch_optional(pool)
+        .await?;
+        Ok(item)
+    }
+
+    /// Returns the items the character has equipped.
+    pub async fn equipment_of(
+        pool: &SqlitePool,
+        character_id: i32,
+    ) -> Result<Vec<Self>, Error> {
+        let items = sqlx::query_as::<_, Self>(
+            "SELECT * FROM items WHERE character_id = ? AND position BETWEEN ? AND ?;",
+        )
+        .bind(character_id)
+        .bind(Self::EQUIPMENT.start())
+        .bind(Self::EQUIPMENT.end())
+        .fetch_all(pool)
+        .await?;
+        Ok(items)
+    }
+
+    /// Moves the items waiting in the mailbox of the character to its
+    /// inventory, as long as there is room for them. Returns how many got
+    /// delivered.
+    pub async fn deliver_mail(
+        pool: &SqlitePool,
+        character_id: i32,
+        inventory_size: usize,
+    ) -> Result<u64, Error> {
+        let res = sqlx::query(
+            "
+            UPDATE items SET position = ?1
+            WHERE item_id IN (
+                SELECT item_id FROM items
+                WHERE character_id = ?2 AND position = ?3
+                ORDER BY item_id
+                LIMIT MAX(0, ?4 - (
+                    SELECT COUNT(*) FROM items
+                    WHERE character_id = ?2 AND position = ?1
+                ))
+            );
+            ",
+        )
+        .bind(Self::INVENTORY)
+        .bind(character_id)
+        .bind(Self::MAILBOX)
+        .bind(inventory_size as i64)
+        .execute(pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    /// Returns the item the character has equipped in the given position.
+    pub async fn equipped(
+        pool: &SqlitePool,
+        character_id: i32,
+        position: i16,
+    ) -> Result<Option<Self>, Error> {
+        let item = sqlx::query_as::<_, Self>(
+            "SELECT * FROM items WHERE character_id = ? AND position = ? LIMIT 1;",
+        )
+        .bind(character_id)
+        .bind(position)
+        .fetch_optional(pool)
+        .await?;
+        Ok(item)
+    }
+
+    /// Takes one out of the stack, returns `false` if it was already empty
+    /// or gone.
+    pub async fn use_one(&mut self, pool: &SqlitePool) -> Result<bool, Error> {
+        let amount = sqlx::query_as::<_, (i16,)>(
+            "
+            UPDATE items SET amount = amount - 1
+            WHERE item_id = ? AND character_id = ? AND amount > 0
+            RETURNING amount;
+            ",
+        )
+        .bind(self.item_id)
+        .bind(self.character_id)
+        .fetch_optional(pool)
+        .await?;
+        match amount {
+            Some((amount,)) => {
+                self.amount = amount;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    /// Moves the item back to the inventory, returns `false` if the item is
+    /// gone.
+    pub async fn unequip(&mut self, pool: &SqlitePool) -> Result<bool, Error> {
+        let res = sqlx::query(
+            "UPDATE items SET position = ? WHERE item_id = ? AND character_id = ?;",
+        )
+        .bind(Self::INVENTORY)
+        .bind(self.item_id)
+        .bind(self.character_id)
+        .execute(pool)
+        .await?;
+        self.position = Self::INVENTORY;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Moves the item from the inventory to the equipment slot, and the
+    /// `displaced` items from their slots back to the inventory. Returns
+    /// `false` without moving anything if the item is not in the inventory
+    /// anymore.
+    pub async fn equip(
+        &mut self,
+        pool: &SqlitePool,
+        position: i16,
+        displaced: &[i32],
+    ) -> Result<bool, Error> {
+        let mut tx = pool.begin().await?;
+        for item_id in displaced {
+            sqlx::query(
+                "UPDATE items SET position = ? WHERE item_id = ? AND character_id = ?;",
+            )
+            .bind(Self::INVENTORY)
+            .bind(item_id)
+            .bind(self.character_id)
+            .execute(&mut *tx)
+            .await?;
+        }
+        let res = sqlx::query(
+            "UPDATE items SET position = ? WHERE item_id = ? AND character_id = ? AND position = ?;",
+        )
+        .bind(position)
+        .bind(self.item_id)
+        .bind(self.character_id)
+        .bind(Self::INVENTORY)
+        .execute(&mut *tx)
+        .await?;
+        if res.rows_affected() == 0 {
+            return Ok(false);
+        }
+        tx.commit().await?;
+        self.position = position;
+        Ok(true)
+    }
+
+    /// Puts an item from the inventory up for sale, returns `false` if it is
+    /// not in the inventory anymore.
+    pub async fn list_in_booth(
+        &mut self,
+        pool: &SqlitePool,
+    ) -> Result<bool, Error> {
+        let res = sqlx::query(
+            "UPDATE items SET position = ? WHERE item_id = ? AND character_id = ? AND position = ?;",
+        )
+        .bind(Self::BOOTH)
+        .bind(self.item_id)
+        .bind(self.character_id)
+        .bind(Self::INVENTORY)
+        .execute(pool)
+        .await?;
+        let listed = res.rows_affected() > 0;
+        if listed {
+            self.position = Self::BOOTH;
+        }
+        Ok(listed)
+    }
+
+    /// Moves the items the character put up for sale back to its inventory,
+    /// or just the one with the given id, returns how many got moved.
+    pub async fn return_from_booth(
+        pool: &SqlitePool,
+        character_id: i32,
+        item_id: Option<i32>,
+    ) -> Result<u64, Error> {
+        let res = sqlx::query(
+            "
+            UPDATE items SET position = ?
+            WHERE character_id = ? AND position = ?
+                AND (? IS NULL OR item_id = ?);
+            ",
+        )
+        .bind(Self::INVENTORY)
+        .bind(character_id)
+        .bind(Self::BOOTH)
+        .bind(item_id)
+        .bind(item_id)
+        .execute(pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    /// Hands an item the seller put up for sale over to the buyer's
+    /// inventory.
+    ///
+    /// Returns `None` if the item is not for sale anymore, or if the buyer
+    /// has no room for it.
+    pub async fn sell(
+        pool: &SqlitePool,
+        item_id: i32,
+        seller_id: i32,
+        buyer_id: i32,
+        inventory_size: usize,
+    ) -> Result<Option<Self>, Error> {
+        let mut tx = pool.begin().await?;
+        let (in_inventory,) = sqlx::query_as::<_, (i64,)>(
+            "SELECT COUNT(*) FROM items WHERE character_id = ? AND position = ?;",
+        )
+        .bind(buyer_id)
+        .bind(Self::INVENTORY)
+        .fetch_one(&mut *tx)
+        .await?;
+        if in_inventory as usize >= inventory_size {
+            return Ok(None);
+        }
+        let item = sqlx::query_as::<_, Self>(
+            "
+            UPDATE items SET character_id = ?, position = ?
+            WHERE item_id = ? AND character_id = ? AND position = ?
+            RETURNING *;
+            ",
+        )
+        .bind(buyer_id)
+        .bind(Self::INVENTORY)
+        .bind(item_id)
+        .bind(seller_id)
+        .bind(Self::BOOTH)
+        .fetch_optional(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(item)
+    }
+
+    /// Puts a new item of the given type in the inventory of the character,
+    /// returns `None` if the inventory is full.
+    pub async fn give(
+        pool: &SqlitePool,
+        character_id: i32,
+        item_type: i32,
+        inventory_size: usize,
+    ) -> Result<Option<Self>, Error> {
+        let item = sqlx::query_as::<_, Self>(
+            "
+            INSERT INTO items (character_id, item_type, position)
+            SELECT ?1, ?2, ?3
+            WHERE (
+                SELECT COUNT(*) FROM items
+                WHERE character_id = ?1 AND position = ?3
+            ) < ?4
+            RETURNING *;
+            ",
+        )
+        .bind(character_id)
+        .bind(item_type)
+        .bind(Self::INVENTORY)
+        .bind(inventory_size as i64)
+        .fetch_optional(pool)
+        .await?;
+        Ok(item)
+    }
+
+    /// Deletes the item, returns `false` if it was already gone.
+    pub async fn delete(&self, pool: &SqlitePool) -> Result<bool, Error> {
+        let res = sqlx::query(
+            "DELETE FROM items WHERE item_id = ? AND character_id = ?;",
+        )
+        .bind(self.item_id)
+        .bind(self.character_id)
+        .execute(pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Writes the plus level and the gems of the item, returns `false` if the
+    /// item is gone.
+    pub async fn save_upgrades(
+        &self,
+        pool: &SqlitePool,
+    ) -> Result<bool, Error> {
+        let res = sqlx::query(
+            "UPDATE items SET plus = ?, gem_one = ?, gem_two = ? WHERE item_id = ? AND character_id = ?;",
+        )
+        .bind(self.plus)
+        .bind(self.gem_one)
+        .bind(self.gem_two)
+        .bind(self.item_id)
+        .bind(self.character_id)
+        .execute(pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+}
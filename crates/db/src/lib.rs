@@ -1,9 +1,28 @@
 pub mod account;
 pub mod character;
+pub mod cheat_log;
+pub mod connection_log;
+pub mod enemy;
 pub mod error;
+pub mod guild;
+pub mod item;
 pub mod map;
 pub mod npc;
+pub mod offline_message;
 pub mod portal;
 pub mod realm;
+pub mod scheduled_job;
+pub mod warehouse;
+pub mod weapon_skill;
 
 pub use error::Error;
+
+/// The latest migration applied to the database, `None` if none was.
+pub async fn version(pool: &sqlx::SqlitePool) -> Result<Option<i64>, Error> {
+    let (version,) = sqlx::query_as::<_, (Option<i64>,)>(
+        "SELECT MAX(version) FROM _sqlx_migrations WHERE success = 1;",
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(version)
+}
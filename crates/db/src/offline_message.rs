@@ -0,0 +1,92 @@
+use crate::Error;
+use sqlx::SqlitePool;
+
+/// A message sent to a character while it was offline, waiting for its next
+/// login.
+#[derive(Debug, Clone, Default, PartialEq, Eq, sqlx::FromRow)]
+pub struct OfflineMessage {
+    pub message_id: i64,
+    pub character_id: i32,
+    pub sender_name: String,
+    pub channel: i32,
+    pub message: String,
+    /// When it was sent, in seconds since the epoch.
+    pub sent_at: i64,
+}
+
+impl OfflineMessage {
+    /// Queues the message for its recipient, dropping the oldest ones it has
+    /// waiting beyond the `cap` newest.
+    pub async fn queue(
+        &self,
+        pool: &SqlitePool,
+        cap: i64,
+    ) -> Result<(), Error> {
+        let mut tx = pool.begin().await?;
+        sqlx::query(
+            "INSERT INTO offline_messages (character_id, sender_name, channel, message, sent_at) VALUES (?, ?, ?, ?, ?);",
+        )
+        .bind(self.character_id)
+        .bind(&self.sender_name)
+        .bind(self.channel)
+        .bind(&self.message)
+        .bind(self.sent_at)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            "
+            DELETE FROM offline_messages
+            WHERE character_id = ? AND message_id NOT IN (
+                SELECT message_id FROM offline_messages
+                WHERE character_id = ?
+                ORDER BY message_id DESC
+                LIMIT ?
+            );
+            ",
+        )
+        .bind(self.character_id)
+        .bind(self.character_id)
+        .bind(cap)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Takes the messages waiting for the character, oldest first, leaving
+    /// out the ones sent before `not_before`. None of them are left waiting.
+    pub async fn take(
+        pool: &SqlitePool,
+        character_id: i32,
+        not_before: i64,
+    ) -> Result<Vec<Self>, Error> {
+        let mut tx = pool.begin().await?;
+        let messages = sqlx::query_as::<_, Self>(
+            "SELECT * FROM offline_messages WHERE character_id = ? AND sent_at >= ? ORDER BY message_id;",
+        )
+        .bind(character_id)
+        .bind(not_before)
+        .fetch_all(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM offline_messages WHERE character_id = ?;")
+            .bind(character_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(messages)
+    }
+
+    /// How many messages are waiting for the character.
+    pub async fn count(
+        pool: &SqlitePool,
+        character_id: i32,
+    ) -> Result<i64, Error> {
+        let (count,) = sqlx::query_as::<_, (i64,)>(
+            "SELECT COUNT(*) FROM offline_messages WHERE character_id = ?;",
+        )
+        .bind(character_id)
+        .fetch_one(pool)
+        .await?;
+        Ok(count)
+    }
+}
@@ -11,9 +11,15 @@ pub struct Portal {
     pub to_map_id: i32,
     pub to_x: i16,
     pub to_y: i16,
+    /// What is special about the portal, see [`Portal::CITY_GATE`].
+    pub flags: i32,
 }
 
 impl Portal {
+    /// The portal is a city gate, the guards keep the flashing-name players
+    /// from going through it.
+    pub const CITY_GATE: i32 = 1 << 0;
+
     #[tracing::instrument]
     pub async fn by_map(
         pool: &SqlitePool,
@@ -10,7 +10,7 @@ pub struct Realm {
     pub realm_id: i32,
     pub name: String,
     pub game_ip_address: String,
-    pub game_port: i16,
+    pub game_port: u16,
 }
 
 impl Realm {
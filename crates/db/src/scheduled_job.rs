@@ -0,0 +1,36 @@
+use crate::Error;
+use sqlx::SqlitePool;
+
+/// When a scheduled job last ran, kept so a restart neither runs it twice
+/// nor skips it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, sqlx::FromRow)]
+pub struct ScheduledJob {
+    pub name: String,
+    /// The server time of the last run, in seconds since the epoch.
+    pub last_run: i64,
+}
+
+impl ScheduledJob {
+    /// Returns when every job that ever ran last ran.
+    pub async fn all(pool: &SqlitePool) -> Result<Vec<Self>, Error> {
+        let jobs = sqlx::query_as::<_, Self>("SELECT * FROM scheduled_jobs;")
+            .fetch_all(pool)
+            .await?;
+        Ok(jobs)
+    }
+
+    /// Saves when the job last ran, replacing the previous run.
+    pub async fn save(&self, pool: &SqlitePool) -> Result<(), Error> {
+        sqlx::query(
+            "
+            INSERT INTO scheduled_jobs (name, last_run) VALUES (?, ?)
+            ON CONFLICT (name) DO UPDATE SET last_run = excluded.last_run;
+            ",
+        )
+        .bind(&self.name)
+        .bind(self.last_run)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+use crate::item::Item;
+use crate::Error;
+use sqlx::SqlitePool;
+
+/// An item kept in the warehouse of an account, shared by all of its
+/// characters.
+///
+/// It leaves the `items` table while it is there, so deleting the character
+/// that put it in does not take it along.
+#[derive(Debug, Clone, Default, PartialEq, Eq, sqlx::FromRow)]
+pub struct WarehouseItem {
+    pub warehouse_item_id: i32,
+    pub account_id: i32,
+    pub item_type: i32,
+    pub plus: i16,
+    pub gem_one: i16,
+    pub gem_two: i16,
+    pub amount: i16,
+}
+
+impl WarehouseItem {
+    /// Returns the items in the warehouse of the account, in the order they
+    /// were put in.
+    pub async fn of_account(
+        pool: &SqlitePool,
+        account_id: i32,
+    ) -> Result<Vec<Self>, Error> {
+        let items = sqlx::query_as::<_, Self>(
+            "SELECT * FROM warehouse_items WHERE account_id = ? ORDER BY warehouse_item_id;",
+        )
+        .bind(account_id)
+        .fetch_all(pool)
+        .await?;
+        Ok(items)
+    }
+
+    /// Moves the item from the inventory of its character to the warehouse
+    /// of the account.
+    ///
+    /// Returns `None` without moving anything if the item is not in the
+    /// inventory anymore, or if the warehouse already holds `capacity`
+    /// items.
+    pub async fn deposit(
+        pool: &SqlitePool,
+        item: &Item,
+        account_id: i32,
+        capacity: usize,
+    ) -> Result<Option<Self>, Error> {
+        let mut tx = pool.begin().await?;
+        let stored = sqlx::query_as::<_, Self>(
+            "
+            INSERT INTO warehouse_items
+                (account_id, item_type, plus, gem_one, gem_two, amount)
+            SELECT ?1, item_type, plus, gem_one, gem_two, amount
+            FROM items
+            WHERE item_id = ?2 AND character_id = ?3 AND position = ?4
+                AND (
+                    SELECT COUNT(*) FROM warehouse_items WHERE account_id = ?1
+                ) < ?5
+            RETURNING *;
+            ",
+        )
+        .bind(account_id)
+        .bind(item.item_id)
+        .bind(item.character_id)
+        .bind(Item::INVENTORY)
+        .bind(capacity as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(stored) = stored else {
+            return Ok(None);
+        };
+        sqlx::query("DELETE FROM items WHERE item_id = ?;")
+            .bind(item.item_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(Some(stored))
+    }
+
+    /// Moves the item from the warehouse of the account to the inventory of
+    /// the character, where it gets a new item id.
+    ///
+    /// Returns `None` without moving anything if the item is not in that
+    /// warehouse anymore, or if the character has no room for it.
+    pub async fn withdraw(
+        pool: &SqlitePool,
+        warehouse_item_id: i32,
+        account_id: i32,
+        character_id: i32,
+        inventory_size: usize,
+    ) -> Result<Option<Item>, Error> {
+        let mut tx = pool.begin().await?;
+        let item = sqlx::query_as::<_, Item>(
+            "
+            INSERT INTO items
+                (character_id, item_type, position, plus, gem_one, gem_two, amount)
+            SELECT ?1, item_type, ?2, plus, gem_one, gem_two, amount
+            FROM warehouse_items
+            WHERE warehouse_item_id = ?3 AND account_id = ?4
+                AND (
+                    SELECT COUNT(*) FROM items
+                    WHERE character_id = ?1 AND position = ?2
+                ) < ?5
+            RETURNING *;
+            ",
+        )
+        .bind(character_id)
+        .bind(Item::INVENTORY)
+        .bind(warehouse_item_id)
+        .bind(account_id)
+        .bind(inventory_size as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(item) = item else {
+            return Ok(None);
+        };
+        sqlx::query("DELETE FROM warehouse_items WHERE warehouse_item_id = ?;")
+            .bind(warehouse_item_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(Some(item))
+    }
+}
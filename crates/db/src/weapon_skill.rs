@@ -0,0 +1,49 @@
+use crate::Error;
+use sqlx::SqlitePool;
+
+/// How good a character is with a kind of weapon, also known as its
+/// proficiency.
+#[derive(Debug, Clone, Default, PartialEq, Eq, sqlx::FromRow)]
+pub struct WeaponSkill {
+    pub character_id: i32,
+    /// The kind of weapon, the item type of the weapon divided by 1000.
+    pub skill_type: i32,
+    pub level: i16,
+    pub experience: i32,
+}
+
+impl WeaponSkill {
+    /// Returns the proficiencies of the character.
+    pub async fn of_character(
+        pool: &SqlitePool,
+        character_id: i32,
+    ) -> Result<Vec<Self>, Error> {
+        let skills = sqlx::query_as::<_, Self>(
+            "SELECT * FROM weapon_skills WHERE character_id = ? ORDER BY skill_type;",
+        )
+        .bind(character_id)
+        .fetch_all(pool)
+        .await?;
+        Ok(skills)
+    }
+
+    /// Saves the proficiency, replacing whatever the character had for that
+    /// kind of weapon.
+    pub async fn save(&self, pool: &SqlitePool) -> Result<(), Error> {
+        sqlx::query(
+            "
+            INSERT INTO weapon_skills (character_id, skill_type, level, experience)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT (character_id, skill_type)
+            DO UPDATE SET level = excluded.level, experience = excluded.experience;
+            ",
+        )
+        .bind(self.character_id)
+        .bind(self.skill_type)
+        .bind(self.level)
+        .bind(self.experience)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
@@ -3,7 +3,6 @@
 /// updating, attacking, exploit checking, etc.
 
 pub const SCREEN_DISTANCE: u16 = 18;
-pub const RADIAN_TO_DEGREE: f64 = 57.29;
 pub const MAX_DIFFERENCE_IN_ELEVATION: u16 = 210;
 
 /// This function returns true if an object is within the bounds of another
@@ -20,33 +19,12 @@ pub fn in_range(p1: (u16, u16), p2: (u16, u16), range: u16) -> bool {
 
 /// This function returns delta (x, y).
 pub fn delta(p1: (u16, u16), p2: (u16, u16)) -> (u16, u16) {
-    let x1 = p1.0 as i16;
-    let x2 = p2.0 as i16;
-    let y1 = p1.1 as i16;
-    let y2 = p2.1 as i16;
-    let delta_x = (x2 - x1).abs();
-    let delta_y = (y2 - y1).abs();
-    (delta_x as u16, delta_y as u16)
+    (p1.0.abs_diff(p2.0), p1.1.abs_diff(p2.1))
 }
 
 /// This function checks the elevation difference of two tiles.
 pub fn within_elevation(new: u16, initial: u16) -> bool {
-    let new = new as i16;
-    let initial = initial as i16;
-    (new - initial) < MAX_DIFFERENCE_IN_ELEVATION as i16
-}
-
-/// This function returns the angle for a jump or attack.
-pub fn get_angle(p1: (u16, u16), p2: (u16, u16)) -> f64 {
-    let (delta_x, delta_y) = delta(p1, p2);
-    let delta_x = delta_x as f64;
-    let delta_y = delta_y as f64;
-    let angle = (delta_y.atan2(delta_x) * RADIAN_TO_DEGREE) + 90.0;
-    if angle.is_sign_negative() {
-        270.0 + (90.0 - angle.abs())
-    } else {
-        angle
-    }
+    (new as i32 - initial as i32) < MAX_DIFFERENCE_IN_ELEVATION as i32
 }
 
 /// This function returns the distance between two objects.
@@ -58,15 +36,25 @@ pub fn get_distance(p1: (u16, u16), p2: (u16, u16)) -> f64 {
     ((x2 - x1).powi(2) + (y2 - y1).powi(2)).sqrt()
 }
 
-/// This function returns the direction for a jump or attack.
-pub fn get_direction_sector(p1: (u16, u16), p2: (u16, u16)) -> u8 {
-    let angle = get_angle(p1, p2);
-    let direction = (angle / 45.0).round() as u8;
-    if direction == 8 {
-        0
-    } else {
-        direction
+/// The direction, from 0 to 7, of a move by `(dx, dy)` the way the client
+/// counts them: 0 is south (`+y`), going clockwise through south west, west
+/// and so on up to 7 for south east. Each direction covers the 45 degrees
+/// around it, and not moving at all faces south.
+pub fn direction_from_delta(dx: i32, dy: i32) -> u8 {
+    if dx == 0 && dy == 0 {
+        return 0;
     }
+    let angle = (-dx as f64).atan2(dy as f64).to_degrees();
+    let angle = if angle < 0.0 { angle + 360.0 } else { angle };
+    (angle / 45.0).round() as u8 % 8
+}
+
+/// This function returns the direction for a jump or attack, from `p1`
+/// toward `p2`, see [`direction_from_delta`].
+pub fn get_direction_sector(p1: (u16, u16), p2: (u16, u16)) -> u8 {
+    let dx = p2.0 as i32 - p1.0 as i32;
+    let dy = p2.1 as i32 - p1.1 as i32;
+    direction_from_delta(dx, dy)
 }
 
 /// Check if a Point (px, py) lies inside a circle (x, y, r)
@@ -80,3 +68,180 @@ pub fn in_circle(
     let r2 = r.powi(2);
     dist_points < r2
 }
+
+/// Walks the tiles on the line from `p1` to `p2`, both ends included, using
+/// Bresenham's line algorithm.
+pub fn tiles_between(p1: (u16, u16), p2: (u16, u16)) -> TilesBetween {
+    let (x, y) = (p1.0 as i32, p1.1 as i32);
+    let (x2, y2) = (p2.0 as i32, p2.1 as i32);
+    let dx = (x2 - x).abs();
+    let dy = -(y2 - y).abs();
+    TilesBetween {
+        x,
+        y,
+        end: (x2, y2),
+        delta: (dx, dy),
+        step: (if x < x2 { 1 } else { -1 }, if y < y2 { 1 } else { -1 }),
+        err: dx + dy,
+        done: false,
+    }
+}
+
+/// The tiles on a line, see [`tiles_between`].
+#[derive(Debug, Clone)]
+pub struct TilesBetween {
+    x: i32,
+    y: i32,
+    end: (i32, i32),
+    delta: (i32, i32),
+    step: (i32, i32),
+    err: i32,
+    done: bool,
+}
+
+impl Iterator for TilesBetween {
+    type Item = (u16, u16);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        // Every tile on the way is between the two ends, so it fits.
+        let tile = (self.x as u16, self.y as u16);
+        if (self.x, self.y) == self.end {
+            self.done = true;
+            return Some(tile);
+        }
+        let (dx, dy) = self.delta;
+        let e2 = 2 * self.err;
+        if e2 >= dy {
+            self.err += dy;
+            self.x += self.step.0;
+        }
+        if e2 <= dx {
+            self.err += dx;
+            self.y += self.step.1;
+        }
+        Some(tile)
+    }
+}
+
+/// The chance, in percent, of an arrow hitting its target. Agile archers
+/// hit more often, agile targets dodge more often.
+pub fn archer_hit_chance(attacker_agility: u16, target_agility: u16) -> u32 {
+    let chance = 75 + attacker_agility as i32 / 2 - target_agility as i32 / 3;
+    chance.clamp(20, 95) as u32
+}
+
+/// The damage an arrow that hit deals, the agility of the target lets it
+/// dodge part of it, up to a half.
+pub fn archer_damage(attack: u32, target_agility: u16) -> u32 {
+    let dodge = (target_agility as u32 / 4).min(50);
+    (attack * (100 - dodge) / 100).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Coordinates near the edges and in the middle of the range.
+    const SAMPLES: [u16; 9] =
+        [0, 1, 2, 17, 18, 19, 32_767, u16::MAX - 1, u16::MAX];
+
+    fn pairs() -> impl Iterator<Item = ((u16, u16), (u16, u16))> {
+        SAMPLES.into_iter().flat_map(|x1| {
+            SAMPLES.into_iter().flat_map(move |y1| {
+                SAMPLES.into_iter().flat_map(move |x2| {
+                    SAMPLES.into_iter().map(move |y2| ((x1, y1), (x2, y2)))
+                })
+            })
+        })
+    }
+
+    #[test]
+    fn ranges_are_symmetric_and_do_not_overflow() {
+        for (a, b) in pairs() {
+            assert_eq!(delta(a, b), delta(b, a));
+            assert_eq!(in_screen(a, b), in_screen(b, a), "{a:?} {b:?}");
+            for r in [0, 1, SCREEN_DISTANCE, u16::MAX] {
+                assert_eq!(in_range(a, b, r), in_range(b, a, r));
+            }
+            assert_eq!(get_distance(a, b), get_distance(b, a));
+        }
+        assert!(in_screen((0, 0), (18, 18)));
+        assert!(!in_screen((0, 0), (19, 0)));
+        assert!(!in_screen((0, 0), (u16::MAX, u16::MAX)));
+        assert!(in_range((0, 0), (u16::MAX, u16::MAX), u16::MAX));
+    }
+
+    #[test]
+    fn directions_match_the_client() {
+        // The steps the client takes in each direction.
+        let xs = [0, -1, -1, -1, 0, 1, 1, 1];
+        let ys = [1, 1, 0, -1, -1, -1, 0, 1];
+        for dir in 0..8 {
+            let (dx, dy) = (xs[dir], ys[dir]);
+            assert_eq!(direction_from_delta(dx, dy), dir as u8);
+            assert_eq!(direction_from_delta(dx * 100, dy * 100), dir as u8);
+            // The opposite move faces the other way.
+            let opposite = direction_from_delta(-dx, -dy);
+            assert_eq!(opposite, (dir as u8 + 4) % 8);
+        }
+        assert_eq!(direction_from_delta(0, 0), 0);
+    }
+
+    #[test]
+    fn direction_sectors_change_at_their_boundaries() {
+        // 22.5 degrees off the axis is about 41 tiles over 100.
+        assert_eq!(direction_from_delta(-41, 100), 0);
+        assert_eq!(direction_from_delta(-42, 100), 1);
+        assert_eq!(direction_from_delta(41, 100), 0);
+        assert_eq!(direction_from_delta(42, 100), 7);
+        assert_eq!(direction_from_delta(-100, 41), 2);
+        assert_eq!(direction_from_delta(-100, 42), 1);
+        assert_eq!(direction_from_delta(100, -41), 6);
+        assert_eq!(direction_from_delta(100, -42), 5);
+        // Every sector is reached going all the way around.
+        let mut seen = [false; 8];
+        for step in 0..360 {
+            let angle = (step as f64).to_radians();
+            let dx = (-angle.sin() * 1000.0).round() as i32;
+            let dy = (angle.cos() * 1000.0).round() as i32;
+            seen[direction_from_delta(dx, dy) as usize] = true;
+        }
+        assert_eq!(seen, [true; 8]);
+    }
+
+    #[test]
+    fn directions_do_not_overflow_at_the_edges() {
+        for (a, b) in pairs() {
+            let dir = get_direction_sector(a, b);
+            assert!(dir < 8);
+            if a != b {
+                assert_eq!(get_direction_sector(b, a), (dir + 4) % 8);
+            }
+        }
+    }
+
+    #[test]
+    fn tiles_between_walk_from_one_end_to_the_other() {
+        let tiles: Vec<_> = tiles_between((0, 0), (3, 1)).collect();
+        assert_eq!(tiles, [(0, 0), (1, 0), (2, 1), (3, 1)]);
+        assert_eq!(tiles_between((5, 5), (5, 5)).collect::<Vec<_>>(), [(5, 5)]);
+        for (a, b) in [((0, 0), (9, 9)), ((4, 1), (0, 7)), ((10, 2), (3, 2))] {
+            let forth: Vec<_> = tiles_between(a, b).collect();
+            let (dx, dy) = delta(a, b);
+            assert_eq!(forth.len(), dx.max(dy) as usize + 1);
+            assert_eq!((forth[0], forth[forth.len() - 1]), (a, b));
+            // Every step goes to a neighboring tile.
+            for w in forth.windows(2) {
+                assert_eq!(delta(w[0], w[1]).0.max(delta(w[0], w[1]).1), 1);
+            }
+        }
+        // The edges of the map do not overflow.
+        let edge = u16::MAX;
+        assert_eq!(tiles_between((edge - 2, edge), (edge, edge)).count(), 3);
+        assert_eq!(tiles_between((edge, 0), (edge, 2)).last(), Some((edge, 2)));
+        assert_eq!(tiles_between((0, edge), (0, 0)).count(), edge as usize + 1);
+    }
+}
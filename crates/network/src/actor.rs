@@ -1,11 +1,14 @@
-use crate::{Error, PacketEncode};
+use crate::{Codec, DropReason, Error, PacketEncode};
 use async_trait::async_trait;
 use bytes::Bytes;
 use futures::TryFutureExt;
+use std::collections::HashMap;
 use std::hash::Hash;
+use std::net::SocketAddr;
 use std::ops::Deref;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::Sender;
 use tracing::instrument;
 
@@ -13,7 +16,15 @@ use tracing::instrument;
 pub enum Message {
     GenerateKeys(u64),
     Packet(u16, Bytes),
+    /// Packets going out together, written to the socket at once.
+    Batch(Vec<(u16, Bytes)>),
+    /// Write whatever packets are held back to the socket right away.
+    Flush,
+    /// Close the connection once the packets queued before it are written.
     Shutdown,
+    /// A last packet, written right before the connection closes, see
+    /// [`ActorHandle::shutdown_with`].
+    ShutdownWith(u16, Bytes),
 }
 
 /// This struct is the main actor type for the server. It is a wrapper around
@@ -32,6 +43,70 @@ pub struct Actor<S: ActorState> {
 pub struct ActorHandle {
     id: Arc<AtomicUsize>,
     tx: Sender<Message>,
+    latency: Arc<Latency>,
+    peer_addr: Arc<OnceLock<SocketAddr>>,
+    disconnect_reason: Arc<Mutex<Option<DisconnectReason>>>,
+    /// The packets from this client that got dropped, by reason.
+    drops: Arc<Mutex<HashMap<DropReason, u32>>>,
+    /// When the client last sent a packet that was not a passive one, see
+    /// [`Server::PASSIVE_PACKETS`](crate::Server::PASSIVE_PACKETS).
+    last_activity: Arc<Mutex<Instant>>,
+    /// How the packets sent to this client are encoded.
+    codec: Codec,
+}
+
+/// Why a client got disconnected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum DisconnectReason {
+    /// The client closed the connection, or we closed it as part of the
+    /// normal flow.
+    #[default]
+    ClientClosed,
+    /// The client stopped answering.
+    Timeout,
+    /// The server dropped the client on purpose.
+    Kicked,
+    /// Reading from or writing to the client failed.
+    Error,
+    /// The client kept sending packets we could not make sense of.
+    InvalidPackets,
+}
+
+impl DisconnectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ClientClosed => "client_closed",
+            Self::Timeout => "timeout",
+            Self::Kicked => "kicked",
+            Self::Error => "error",
+            Self::InvalidPackets => "invalid_packets",
+        }
+    }
+}
+
+/// How sending a packet to an actor went, so a broadcast could tell a client
+/// that went away from anything else going wrong.
+#[derive(Debug)]
+pub enum SendOutcome<E = Error> {
+    Sent,
+    /// The channel of the actor is closed, its client is gone.
+    Dead,
+    /// The actor is still there, but the packet could not be sent to it.
+    Failed(E),
+}
+
+impl<E> SendOutcome<E> {
+    pub fn is_dead(&self) -> bool { matches!(self, Self::Dead) }
+}
+
+/// Tracks the round trip time to the client, measured by sending it a token
+/// and timing how long it takes to echo it back.
+#[derive(Debug, Default)]
+struct Latency {
+    next_token: AtomicU32,
+    /// The token of the ping waiting for an answer, and when it was sent.
+    pending: Mutex<Option<(u32, Instant)>>,
+    rtt: Mutex<Option<Duration>>,
 }
 
 impl<S: ActorState> Hash for Actor<S> {
@@ -84,10 +159,26 @@ impl<S: ActorState> Actor<S> {
             handle: ActorHandle {
                 id: Arc::new(AtomicUsize::new(0)),
                 tx,
+                latency: Default::default(),
+                peer_addr: Default::default(),
+                disconnect_reason: Default::default(),
+                drops: Default::default(),
+                last_activity: Arc::new(Mutex::new(Instant::now())),
+                codec: Codec::default(),
             },
         }
     }
 
+    /// Encodes the packets going to and coming from the client using the
+    /// given codec.
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.handle.codec = codec;
+        self
+    }
+
+    /// The codec the packets of this client go through.
+    pub fn codec(&self) -> Codec { self.handle.codec }
+
     /// Returns a cheap clone of the actor handle
     pub fn handle(&self) -> ActorHandle { self.handle.clone() }
 
@@ -95,6 +186,23 @@ impl<S: ActorState> Actor<S> {
 
     pub fn set_id(&self, id: usize) { self.handle.set_id(id) }
 
+    /// The last measured round trip time to the client, if it ever answered
+    /// a ping.
+    pub fn rtt(&self) -> Option<Duration> { self.handle.rtt() }
+
+    /// The address of the client, if this actor is backed by a socket.
+    pub fn peer_addr(&self) -> Option<SocketAddr> { self.handle.peer_addr() }
+
+    /// Sets the address of the client, only the first call has any effect.
+    pub fn set_peer_addr(&self, addr: SocketAddr) {
+        let _ = self.handle.peer_addr.set(addr);
+    }
+
+    /// See [`ActorHandle::disconnect_reason`].
+    pub fn disconnect_reason(&self) -> DisconnectReason {
+        self.handle.disconnect_reason()
+    }
+
     /// Enqueue the packet and send it to the client connected to this actor
     #[instrument(skip(self, packet))]
     pub async fn send<P: PacketEncode>(
@@ -104,6 +212,14 @@ impl<S: ActorState> Actor<S> {
         self.handle.send(packet).await
     }
 
+    /// See [`ActorHandle::send_now`].
+    pub async fn send_now<P: PacketEncode>(
+        &self,
+        packet: P,
+    ) -> Result<(), P::Error> {
+        self.handle.send_now(packet).await
+    }
+
     /// Enqueue the packets and send it all at once to the client connected to
     /// this actor
     #[instrument(skip(self, packets))]
@@ -124,6 +240,14 @@ impl<S: ActorState> Actor<S> {
     pub async fn shutdown(&self) -> Result<(), Error> {
         self.handle.shutdown().await
     }
+
+    /// See [`ActorHandle::shutdown_with`].
+    pub async fn shutdown_with<P: PacketEncode>(
+        &self,
+        packet: P,
+    ) -> Result<(), P::Error> {
+        self.handle.shutdown_with(packet).await
+    }
 }
 
 impl ActorHandle {
@@ -131,17 +255,170 @@ impl ActorHandle {
 
     pub fn set_id(&self, id: usize) { self.id.store(id, Ordering::Relaxed); }
 
+    /// The codec the packets of this client go through.
+    pub fn codec(&self) -> Codec { self.codec }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr.get().copied()
+    }
+
+    /// Why the client got or is getting disconnected, defaults to
+    /// [`DisconnectReason::ClientClosed`] if no one said otherwise.
+    pub fn disconnect_reason(&self) -> DisconnectReason {
+        self.disconnect_reason
+            .lock()
+            .expect("disconnect reason lock poisoned")
+            .unwrap_or_default()
+    }
+
+    /// Records why the client is getting disconnected, the first reason given
+    /// is the one that sticks.
+    pub fn set_disconnect_reason(&self, reason: DisconnectReason) {
+        self.disconnect_reason
+            .lock()
+            .expect("disconnect reason lock poisoned")
+            .get_or_insert(reason);
+    }
+
+    /// Counts a packet from this client that got dropped for that reason,
+    /// returning how many got dropped for it so far.
+    pub fn record_drop(&self, reason: DropReason) -> u32 {
+        let mut drops = self.drops.lock().expect("drops lock poisoned");
+        let count = drops.entry(reason).or_default();
+        *count += 1;
+        *count
+    }
+
+    /// How many packets from this client got dropped for that reason.
+    pub fn drops(&self, reason: DropReason) -> u32 {
+        let drops = self.drops.lock().expect("drops lock poisoned");
+        drops.get(&reason).copied().unwrap_or_default()
+    }
+
+    /// The last measured round trip time to the client, if it ever answered
+    /// a ping.
+    pub fn rtt(&self) -> Option<Duration> {
+        *self.latency.rtt.lock().expect("rtt lock poisoned")
+    }
+
+    /// When the client last did something, the time it connected if it
+    /// did nothing yet.
+    pub fn last_activity(&self) -> Instant {
+        *self.last_activity.lock().expect("activity lock poisoned")
+    }
+
+    /// Records that the client did something at `now`.
+    pub fn touch(&self, now: Instant) {
+        let mut last =
+            self.last_activity.lock().expect("activity lock poisoned");
+        *last = (*last).max(now);
+    }
+
+    /// How long the client did nothing as of `now`.
+    pub fn idle_for(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.last_activity())
+    }
+
+    /// Starts a new ping sent at `now`, returning the token the client has
+    /// to echo back. A ping that was not answered yet gets forgotten.
+    pub fn start_ping(&self, now: Instant) -> u32 {
+        let token = self.latency.next_token.fetch_add(1, Ordering::Relaxed);
+        let mut pending =
+            self.latency.pending.lock().expect("ping lock poisoned");
+        *pending = Some((token, now));
+        token
+    }
+
+    /// Records the answer to a ping received at `now`, returning the round
+    /// trip time, or `None` if the token is not the one we are waiting for.
+    pub fn record_pong(&self, token: u32, now: Instant) -> Option<Duration> {
+        let mut pending =
+            self.latency.pending.lock().expect("ping lock poisoned");
+        match *pending {
+            Some((expected, sent_at)) if expected == token => {
+                *pending = None;
+                let rtt = now.saturating_duration_since(sent_at);
+                *self.latency.rtt.lock().expect("rtt lock poisoned") =
+                    Some(rtt);
+                Some(rtt)
+            },
+            _ => None,
+        }
+    }
+
     /// Enqueue the packet and send it to the client connected to this actor
     #[instrument(skip(self, packet))]
     pub async fn send<P: PacketEncode>(
         &self,
         packet: P,
     ) -> Result<(), P::Error> {
-        let msg = packet.encode()?;
+        let msg = packet.encode_with(self.codec)?;
         self.tx.send(msg.into()).map_err(Into::into).await?;
         Ok(())
     }
 
+    /// Whether the actor is gone, nothing sent to it goes anywhere anymore.
+    pub fn is_closed(&self) -> bool { self.tx.is_closed() }
+
+    /// Like [`ActorHandle::send`], but tells a dead actor apart from any
+    /// other failure.
+    pub async fn try_send<P: PacketEncode>(
+        &self,
+        packet: P,
+    ) -> SendOutcome<P::Error> {
+        match self.send(packet).await {
+            Ok(()) => SendOutcome::Sent,
+            Err(_) if self.is_closed() => SendOutcome::Dead,
+            Err(e) => SendOutcome::Failed(e),
+        }
+    }
+
+    /// Like [`ActorHandle::send_encoded`], but tells a dead actor apart from
+    /// any other failure.
+    pub async fn try_send_encoded(&self, packet: (u16, Bytes)) -> SendOutcome {
+        match self.send_encoded(packet).await {
+            Ok(()) => SendOutcome::Sent,
+            Err(_) if self.is_closed() => SendOutcome::Dead,
+            Err(e) => SendOutcome::Failed(e),
+        }
+    }
+
+    /// Enqueue a packet that was already encoded, so a packet going out to
+    /// many clients is only encoded once.
+    #[instrument(skip(self, packet), fields(packet_id = packet.0))]
+    pub async fn send_encoded(
+        &self,
+        packet: (u16, Bytes),
+    ) -> Result<(), Error> {
+        self.tx.send(packet.into()).await?;
+        Ok(())
+    }
+
+    /// Enqueue packets that were already encoded as a single message, they
+    /// are written to the socket together.
+    #[instrument(skip(self, packets), fields(count = packets.len()))]
+    pub async fn send_batch(
+        &self,
+        packets: Vec<(u16, Bytes)>,
+    ) -> Result<(), Error> {
+        self.tx.send(Message::Batch(packets)).await?;
+        Ok(())
+    }
+
+    /// Like [`ActorHandle::send`], but the packet is written to the socket
+    /// right away even if the server coalesces its writes, use it for
+    /// latency sensitive packets.
+    #[instrument(skip(self, packet))]
+    pub async fn send_now<P: PacketEncode>(
+        &self,
+        packet: P,
+    ) -> Result<(), P::Error> {
+        let msg = packet.encode_with(self.codec)?;
+        self.tx.send(msg.into()).map_err(Into::into).await?;
+        self.tx.send(Message::Flush).map_err(Into::into).await?;
+        Ok(())
+    }
+
     /// Enqueue the packets and send it all at once to the client connected to
     /// this actor
     #[instrument(skip(self, packets))]
@@ -152,7 +429,9 @@ impl ActorHandle {
     {
         let tasks = packets
             .into_iter()
-            .flat_map(|packet| packet.encode().map(|msg| msg.into()))
+            .flat_map(|packet| {
+                packet.encode_with(self.codec).map(|msg| msg.into())
+            })
             .map(|msg| self.tx.send(msg).map_err(crate::Error::from));
         // Wait for all the messages to be sent (in order)
         for task in tasks {
@@ -173,4 +452,94 @@ impl ActorHandle {
         self.tx.send(Message::Shutdown).await?;
         Ok(())
     }
+
+    /// Sends the packet and closes the connection right after, as a single
+    /// message, so nothing sent meanwhile from elsewhere could end up in
+    /// between or get the connection closed before the packet is written.
+    #[instrument(skip(self, packet))]
+    pub async fn shutdown_with<P: PacketEncode>(
+        &self,
+        packet: P,
+    ) -> Result<(), P::Error> {
+        let (id, bytes) = packet.encode_with(self.codec)?;
+        let msg = Message::ShutdownWith(id, bytes);
+        self.tx.send(msg).map_err(Into::into).await?;
+        Ok(())
+    }
+
+    /// Resolves once the connection is gone and nothing more could be sent
+    /// to it.
+    pub async fn closed(&self) { self.tx.closed().await }
+
+    /// Records the reason and closes the connection.
+    #[instrument(skip(self))]
+    pub async fn disconnect(
+        &self,
+        reason: DisconnectReason,
+    ) -> Result<(), Error> {
+        self.set_disconnect_reason(reason);
+        self.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle() -> ActorHandle {
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        Actor::<()>::new(tx).handle()
+    }
+
+    #[test]
+    fn drops_are_counted_by_reason() {
+        let handle = handle();
+        assert_eq!(handle.record_drop(DropReason::Decode), 1);
+        assert_eq!(handle.record_drop(DropReason::Decode), 2);
+        assert_eq!(handle.record_drop(DropReason::UnknownId), 1);
+        // Clones share the counters, they are the same client.
+        let clone = handle.clone();
+        assert_eq!(clone.drops(DropReason::Decode), 2);
+        assert_eq!(clone.drops(DropReason::RateLimited), 0);
+    }
+
+    #[test]
+    fn echoed_ping_records_rtt() {
+        let handle = handle();
+        let sent_at = Instant::now();
+        let token = handle.start_ping(sent_at);
+        let rtt = Duration::from_millis(42);
+        assert_eq!(handle.record_pong(token, sent_at + rtt), Some(rtt));
+        assert_eq!(handle.rtt(), Some(rtt));
+        // The same answer twice does not count.
+        let later = sent_at + rtt * 2;
+        assert_eq!(handle.record_pong(token, later), None);
+        assert_eq!(handle.rtt(), Some(rtt));
+    }
+
+    #[test]
+    fn unanswered_ping_leaves_rtt_unset() {
+        let handle = handle();
+        let sent_at = Instant::now();
+        let token = handle.start_ping(sent_at);
+        assert_eq!(handle.rtt(), None);
+        // Answers to anything but the pending ping are ignored.
+        let later = sent_at + Duration::from_millis(10);
+        assert_eq!(handle.record_pong(token.wrapping_add(1), later), None);
+        let newer = handle.start_ping(later);
+        assert_eq!(handle.record_pong(token, later), None);
+        assert_eq!(handle.rtt(), None);
+        assert!(handle.record_pong(newer, later).is_some());
+    }
+
+    #[tokio::test]
+    async fn first_disconnect_reason_sticks() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let handle = Actor::<()>::new(tx).handle();
+        assert_eq!(handle.disconnect_reason(), DisconnectReason::ClientClosed);
+        handle.disconnect(DisconnectReason::Kicked).await.unwrap();
+        assert!(matches!(rx.recv().await, Some(Message::Shutdown)));
+        handle.set_disconnect_reason(DisconnectReason::Error);
+        assert_eq!(handle.disconnect_reason(), DisconnectReason::Kicked);
+    }
 }
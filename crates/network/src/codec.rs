@@ -0,0 +1,119 @@
+use crate::Error;
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Turns packets into the bytes that go over the wire, and back.
+pub trait PacketCodec {
+    fn encode<T: Serialize>(packet: &T) -> Result<Bytes, Error>;
+    fn decode<T: DeserializeOwned>(bytes: &Bytes) -> Result<T, Error>;
+}
+
+/// The binary format the game client speaks, see [`tq_serde`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TQSerdeCodec;
+
+impl PacketCodec for TQSerdeCodec {
+    fn encode<T: Serialize>(packet: &T) -> Result<Bytes, Error> {
+        Ok(tq_serde::to_bytes(packet)?.freeze())
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &Bytes) -> Result<T, Error> {
+        Ok(tq_serde::from_bytes(bytes)?)
+    }
+}
+
+/// Packets as JSON, easy to read when debugging with our own tools. The game
+/// client does not understand it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl PacketCodec for JsonCodec {
+    fn encode<T: Serialize>(packet: &T) -> Result<Bytes, Error> {
+        Ok(serde_json::to_vec(packet)?.into())
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &Bytes) -> Result<T, Error> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// Which [`PacketCodec`] the packets of a server go through, picked using
+/// [`Server::CODEC`](crate::Server::CODEC).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    /// See [`TQSerdeCodec`].
+    #[default]
+    Binary,
+    /// See [`JsonCodec`].
+    Json,
+}
+
+impl Codec {
+    pub fn encode<T: Serialize>(self, packet: &T) -> Result<Bytes, Error> {
+        match self {
+            Self::Binary => TQSerdeCodec::encode(packet),
+            Self::Json => JsonCodec::encode(packet),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(
+        self,
+        bytes: &Bytes,
+    ) -> Result<T, Error> {
+        match self {
+            Self::Binary => TQSerdeCodec::decode(bytes),
+            Self::Json => JsonCodec::decode(bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PacketDecode, PacketEncode, PacketID};
+    use serde::Deserialize;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct MsgTest {
+        id: u32,
+        x: u16,
+        y: u16,
+        name: String,
+    }
+
+    impl PacketID for MsgTest {
+        const PACKET_ID: u16 = 1010;
+    }
+
+    fn packet() -> MsgTest {
+        MsgTest {
+            id: 1_000_001,
+            x: 300,
+            y: 278,
+            name: String::from("Alice"),
+        }
+    }
+
+    #[test]
+    fn packets_round_trip_through_the_binary_codec() {
+        let (id, bytes) = packet().encode_with(Codec::Binary).unwrap();
+        assert_eq!(id, MsgTest::PACKET_ID);
+        // The same bytes the game client gets without picking a codec.
+        assert_eq!(bytes, packet().encode().unwrap().1);
+        let decoded = MsgTest::decode_with(&bytes, Codec::Binary).unwrap();
+        assert_eq!(decoded, packet());
+    }
+
+    #[test]
+    fn packets_round_trip_through_the_json_codec() {
+        let (id, bytes) = packet().encode_with(Codec::Json).unwrap();
+        assert_eq!(id, MsgTest::PACKET_ID);
+        assert_eq!(
+            &bytes[..],
+            br#"{"id":1000001,"x":300,"y":278,"name":"Alice"}"#
+        );
+        let decoded = MsgTest::decode_with(&bytes, Codec::Json).unwrap();
+        assert_eq!(decoded, packet());
+    }
+}
@@ -1,3 +1,5 @@
+use crate::DisconnectReason;
+use std::error::Error as StdError;
 use thiserror::Error;
 use tokio::sync::mpsc::error::SendError;
 
@@ -5,12 +7,20 @@ use tokio::sync::mpsc::error::SendError;
 pub enum Error {
     #[error(transparent)]
     TQSerde(#[from] tq_serde::TQSerdeError),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
     #[error("Actor Send Error!")]
     SendError,
     #[error(transparent)]
     AddrParseError(#[from] std::net::AddrParseError),
     #[error(transparent)]
     IO(#[from] std::io::Error),
+    #[error("Task failed: {}", _0)]
+    Task(#[from] tokio::task::JoinError),
+    /// An error of the server using us, kept as it is so its source chain
+    /// is not lost.
+    #[error("{}", _0)]
+    Handler(#[source] Box<dyn StdError + Send + Sync>),
     #[error("{}", _0)]
     Other(String),
 }
@@ -18,3 +28,42 @@ pub enum Error {
 impl<T> From<SendError<T>> for Error {
     fn from(_: SendError<T>) -> Self { Self::SendError }
 }
+
+impl Error {
+    /// What a connection that ended with this error counts as.
+    pub fn disconnect_reason(&self) -> DisconnectReason {
+        match self {
+            Self::IO(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                DisconnectReason::Timeout
+            },
+            _ => DisconnectReason::Error,
+        }
+    }
+}
+
+/// What happens to the connection after its packet handler failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorAction {
+    /// The error is sent back to the client as a packet.
+    Reply,
+    /// The error is logged, and the next packets are handled as usual.
+    Log,
+    /// The error is logged, and the client is disconnected.
+    Disconnect,
+}
+
+/// The errors returned by a [`PacketHandler`](crate::PacketHandler), that
+/// could tell what should happen to the connection.
+pub trait HandlerError: StdError {
+    /// By default, every error is sent back to the client.
+    fn action(&self) -> ErrorAction { ErrorAction::Reply }
+}
+
+impl HandlerError for Error {
+    fn action(&self) -> ErrorAction {
+        match self {
+            Self::SendError | Self::IO(_) => ErrorAction::Disconnect,
+            _ => ErrorAction::Log,
+        }
+    }
+}
@@ -6,17 +6,25 @@ use std::error::Error as StdError;
 pub use async_trait::async_trait;
 pub use derive_packethandler::PacketHandler;
 pub use derive_packetid::PacketID;
-pub use tq_codec::TQCodec;
+pub use tq_codec::{Seal, TQCodec};
 pub use tq_crypto::{CQCipher, Cipher, NopCipher, TQCipher};
 
 mod error;
-pub use error::Error;
+pub use error::{Error, ErrorAction, HandlerError};
+
+mod codec;
+pub use codec::{Codec, JsonCodec, PacketCodec, TQSerdeCodec};
 
 mod actor;
-pub use actor::{Actor, ActorHandle, ActorState, Message};
+pub use actor::{
+    Actor, ActorHandle, ActorState, DisconnectReason, Message, SendOutcome,
+};
 
 mod server;
-pub use server::Server;
+pub use server::{Flushing, Overflow, Processing, Server};
+
+mod stats;
+pub use stats::{DropReason, InvalidPacketStats, DROP_LOG_INTERVAL};
 
 pub trait PacketID {
     const PACKET_ID: u16;
@@ -47,6 +55,13 @@ pub trait PacketEncode {
     /// client's send method. Encodes using byte ordering rules
     /// interoperable with the game client.
     fn encode(&self) -> Result<(u16, Bytes), Self::Error>;
+
+    /// Like [`PacketEncode::encode`], using the given codec. Packets that
+    /// hold already encoded bytes keep them as they are.
+    fn encode_with(&self, codec: Codec) -> Result<(u16, Bytes), Self::Error> {
+        let _ = codec;
+        self.encode()
+    }
 }
 
 pub trait PacketDecode {
@@ -58,11 +73,20 @@ pub trait PacketDecode {
     /// processing. Decoding follows TQ Digital's byte ordering rules for an
     /// all-binary protocol.
     fn decode(bytes: &Bytes) -> Result<Self::Packet, Self::Error>;
+
+    /// Like [`PacketDecode::decode`], using the given codec.
+    fn decode_with(
+        bytes: &Bytes,
+        codec: Codec,
+    ) -> Result<Self::Packet, Self::Error> {
+        let _ = codec;
+        Self::decode(bytes)
+    }
 }
 
 #[async_trait]
 pub trait PacketHandler {
-    type Error: StdError + PacketEncode + Send + Sync;
+    type Error: HandlerError + PacketEncode + Send + Sync;
     type ActorState: ActorState;
     type State: Send + Sync + 'static;
     async fn handle(
@@ -70,6 +94,21 @@ pub trait PacketHandler {
         state: &Self::State,
         actor: &Actor<Self::ActorState>,
     ) -> Result<(), Self::Error>;
+
+    /// Called when a packet from the actor got dropped without being
+    /// handled, a good place to count them. Returns how many drops went
+    /// unlogged since the last one if this one should get logged, `None`
+    /// to keep quiet.
+    ///
+    /// By default every drop gets logged.
+    fn on_dropped(
+        state: &Self::State,
+        actor: &Actor<Self::ActorState>,
+        reason: DropReason,
+    ) -> Option<u64> {
+        let _ = (state, actor, reason);
+        Some(0)
+    }
 }
 
 impl<T> PacketEncode for T
@@ -80,9 +119,11 @@ where
     type Packet = T;
 
     fn encode(&self) -> Result<(u16, Bytes), Self::Error> {
-        let id = Self::PACKET_ID;
-        let bytes = tq_serde::to_bytes(&self)?;
-        Ok((id, bytes.freeze()))
+        self.encode_with(Codec::Binary)
+    }
+
+    fn encode_with(&self, codec: Codec) -> Result<(u16, Bytes), Self::Error> {
+        Ok((Self::PACKET_ID, codec.encode(self)?))
     }
 }
 
@@ -94,7 +135,11 @@ where
     type Packet = T;
 
     fn decode(bytes: &Bytes) -> Result<T, Self::Error> {
-        Ok(tq_serde::from_bytes(bytes)?)
+        Self::decode_with(bytes, Codec::Binary)
+    }
+
+    fn decode_with(bytes: &Bytes, codec: Codec) -> Result<T, Self::Error> {
+        codec.decode(bytes)
     }
 }
 
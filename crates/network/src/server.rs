@@ -1,23 +1,96 @@
 use crate::actor::Message;
-use crate::{Actor, ActorState, Error, PacketHandler};
+use crate::{
+    Actor, ActorState, Codec, DisconnectReason, DropReason, Error, ErrorAction,
+    HandlerError, PacketHandler,
+};
 use async_trait::async_trait;
+use bytes::Bytes;
+use futures::future::{self, Either};
 use std::fmt::Debug;
+use std::io;
 use std::net::SocketAddr;
 use std::ops::Deref;
-use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::pin::pin;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{lookup_host, TcpListener, TcpSocket, ToSocketAddrs};
 use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::task::Builder;
-use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
-use tokio_stream::StreamExt;
-use tq_codec::{TQCodec, TQEncoder};
+use tokio_stream::wrappers::TcpListenerStream;
+use tokio_stream::{Stream, StreamExt};
+use tq_codec::{Seal, TQCodec, TQEncoder};
 use tq_crypto::Cipher;
 
+/// How the packets read from a client get processed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Processing {
+    /// Every packet is handled right after it is decoded, and the next packet
+    /// is not read from the socket until the handler returns.
+    #[default]
+    Inline,
+    /// Decoded packets are pushed into a bounded per-actor queue and handled
+    /// one by one, in order, by a worker that runs alongside the reader. A
+    /// slow handler does not stop the socket from being read.
+    Queued {
+        /// How many decoded packets could be waiting to be handled.
+        capacity: usize,
+        /// What to do when the queue is full.
+        overflow: Overflow,
+    },
+}
+
+/// What to do when a client sends packets faster than we could handle them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// Stop reading from the socket until the queue has room again.
+    #[default]
+    Wait,
+    /// Drop the client.
+    Disconnect,
+}
+
+/// When the packets sent to a client get written to the socket.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Flushing {
+    /// Every packet is written as soon as it is sent.
+    #[default]
+    Immediate,
+    /// Packets are held back and written together, once the oldest of them
+    /// waited for `delay` or once they add up to `max_bytes`. Packets sent
+    /// with [`ActorHandle::send_now`](crate::ActorHandle::send_now) flush
+    /// right away, along with whatever is held back.
+    Coalesced { delay: Duration, max_bytes: usize },
+}
+
 #[async_trait]
 pub trait Server: Sized + Send + Sync {
     type Cipher: Cipher;
     type ActorState: ActorState;
     type PacketHandler: PacketHandler<ActorState = Self::ActorState>;
 
+    /// The seal the clients of this server put at the end of every packet.
+    const SEAL: Seal = Seal::None;
+
+    /// How the packets of every client get processed.
+    const PROCESSING: Processing = Processing::Inline;
+
+    /// How the packets sent to every client get written to the socket.
+    const FLUSHING: Flushing = Flushing::Immediate;
+
+    /// How the packets of every client are encoded and decoded, anything but
+    /// [`Codec::Binary`] is only understood by our own tools.
+    const CODEC: Codec = Codec::Binary;
+
+    /// How many connections could be waiting to be accepted before the
+    /// system starts refusing new ones.
+    const BACKLOG: u32 = 1024;
+
+    /// The packets the client sends on its own, like the answers to pings,
+    /// they do not count as activity, see
+    /// [`ActorHandle::last_activity`](crate::ActorHandle::last_activity).
+    const PASSIVE_PACKETS: &'static [u16] = &[];
+
     /// Get Called once a Stream Got Connected, Returing Error here will stop
     /// the stream task and disconnect them from the server.
     #[tracing::instrument(skip(state))]
@@ -31,7 +104,8 @@ pub trait Server: Sized + Send + Sync {
     }
 
     /// Get Called right before ending the connection with that client.
-    /// good chance to clean up anything related to that actor.
+    /// good chance to clean up anything related to that actor, see
+    /// [`Actor::disconnect_reason`] for why it ended.
     #[tracing::instrument(skip(state, actor), fields(actor = actor.id()))]
     async fn on_disconnected(
         state: &<Self::PacketHandler as PacketHandler>::State,
@@ -42,6 +116,15 @@ pub trait Server: Sized + Send + Sync {
         Ok(())
     }
 
+    /// Resolves once the server should shut down on its own, besides Ctrl-C.
+    /// Never does by default.
+    async fn shutdown_signal(
+        state: &<Self::PacketHandler as PacketHandler>::State,
+    ) {
+        let _ = state;
+        std::future::pending::<()>().await
+    }
+
     /// Runs the server and listen on the configured Address for new
     /// Connections.
     #[tracing::instrument(skip(state))]
@@ -52,58 +135,62 @@ pub trait Server: Sized + Send + Sync {
     where
         A: Debug + ToSocketAddrs + Send + Sync,
     {
-        let listener = TcpListener::bind(addr).await?;
-        let main_loop_task = Builder::new().name("Server Main Loop").spawn(async {
-            let mut incoming = TcpListenerStream::new(listener);
-            tracing::trace!("Starting Server main loop");
-            tracing::info!("Server is Ready for New Connections.");
-            while let Some(stream) = incoming.next().await {
-                let stream = match stream {
-                    Ok(s) => {
-                        tracing::debug!(
-                            "Got Connection from {}",
-                            s.peer_addr()?
-                        );
-                        s.set_nodelay(true)?;
-                        s.set_linger(None)?;
-                        s.set_ttl(5)?;
-                        s
-                    },
-                    Err(e) => {
-                        tracing::error!(
-                            error = ?e,
-                            "Error while accepting new connection, dropping it."
-                        );
-                        continue;
-                    },
-                };
-                Builder::new().name("TCP Stream").spawn(async {
-                    tracing::trace!("Calling on_connected lifetime hook");
-                    Self::on_connected(state, stream.peer_addr()?)
-                        .await?;
-                    let (tx, rx) = mpsc::channel(1024);
-                    let actor = Actor::<Self::ActorState>::new(tx);
-                    match handle_stream::<Self>(stream, state, &actor, rx).await {
-                        Err(e) => {
-                            tracing::error!("{e}");
+        let listener = bind(addr, Self::BACKLOG).await?;
+        let main_loop_task =
+            Builder::new().name("Server Main Loop").spawn(async {
+                let incoming = TcpListenerStream::new(listener);
+                tracing::trace!("Starting Server main loop");
+                tracing::info!("Server is Ready for New Connections.");
+                accept_loop(incoming, |stream| {
+                    tracing::debug!(
+                        "Got Connection from {}",
+                        stream.peer_addr()?
+                    );
+                    stream.set_nodelay(true)?;
+                    stream.set_linger(None)?;
+                    stream.set_ttl(5)?;
+                    Builder::new().name("TCP Stream").spawn(async {
+                        tracing::trace!("Calling on_connected lifetime hook");
+                        let addr = stream.peer_addr()?;
+                        Self::on_connected(state, addr).await?;
+                        let (tx, rx) = mpsc::channel(1024);
+                        let actor = Actor::<Self::ActorState>::new(tx)
+                            .with_codec(Self::CODEC);
+                        actor.set_peer_addr(addr);
+                        match handle_stream::<Self, _>(
+                            stream, state, &actor, rx,
+                        )
+                        .await
+                        {
+                            Err(e) => {
+                                tracing::error!("{e}");
+                                actor.handle().set_disconnect_reason(
+                                    e.disconnect_reason(),
+                                );
+                            },
+                            Ok(_) => {
+                                tracing::debug!("Client Disconnected.");
+                            },
                         }
-                        Ok(_) => {
-                            tracing::debug!("Client Disconnected.");
-                        },
-                    }
-                    tracing::trace!("Calling on_disconnected lifetime hook");
-                    Self::on_disconnected(state, actor).await?;
-                    tracing::debug!("Task Ended.");
-                    Result::<_, Error>::Ok(())
-                })?;
-            }
-            Result::<_, Error>::Ok(())
-        })?;
+                        tracing::trace!(
+                            "Calling on_disconnected lifetime hook"
+                        );
+                        Self::on_disconnected(state, actor).await?;
+                        tracing::debug!("Task Ended.");
+                        Result::<_, Error>::Ok(())
+                    })?;
+                    Ok(())
+                })
+                .await
+            })?;
         let ctrl_c = tokio::signal::ctrl_c();
         tokio::select! {
             _ = ctrl_c => {
                 tracing::debug!("Ctrl-C received, shutting down.");
             },
+            _ = Self::shutdown_signal(state) => {
+                tracing::debug!("Asked to shut down.");
+            },
             _ = main_loop_task => {
                 tracing::debug!("Main Loop Task Ended, shutting down.");
             },
@@ -113,66 +200,900 @@ pub trait Server: Sized + Send + Sync {
     }
 }
 
+/// Binds a listener to the first of the addresses that works, with room for
+/// `backlog` connections waiting to be accepted.
+async fn bind<A: ToSocketAddrs>(
+    addr: A,
+    backlog: u32,
+) -> Result<TcpListener, Error> {
+    let mut last_error = None;
+    for addr in lookup_host(addr).await? {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        // Same as `TcpListener::bind`, to restart without waiting for the
+        // old connections to time out.
+        #[cfg(unix)]
+        socket.set_reuseaddr(true)?;
+        match socket.bind(addr).and_then(|_| socket.listen(backlog)) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    let e = last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any address",
+        )
+    });
+    Err(e.into())
+}
+
+/// The first wait after accepting a connection fails, it doubles with every
+/// failure in a row up to [`MAX_ACCEPT_BACKOFF`].
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(5);
+
+/// The longest wait after accepting a connection fails.
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How long to wait before accepting again, after running out of file
+/// descriptors or memory. Accepting right away would fail the same way.
+#[derive(Debug, Default)]
+struct AcceptBackoff {
+    /// The failures in a row so far.
+    failures: u32,
+}
+
+impl AcceptBackoff {
+    /// The wait after one more failure, somewhere between half and all of
+    /// the doubled wait so that servers sharing a host do not retry in
+    /// lockstep.
+    fn next_delay(&mut self) -> Duration {
+        let doubled = MIN_ACCEPT_BACKOFF
+            .saturating_mul(1 << self.failures.min(16))
+            .min(MAX_ACCEPT_BACKOFF);
+        self.failures = self.failures.saturating_add(1);
+        let half = doubled / 2;
+        half + half.mul_f64(rand::random::<f64>())
+    }
+
+    fn reset(&mut self) { self.failures = 0; }
+}
+
+/// Whether accepting failed because of that connection alone, the next one
+/// is fine to accept right away.
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// Hands every connection from `incoming` to `on_accept` until it ends.
+///
+/// Any other accept error, like running out of file descriptors
+/// (`EMFILE`/`ENFILE`), backs off before accepting again instead of spinning
+/// on the same error.
+async fn accept_loop<T, I, F>(
+    incoming: I,
+    mut on_accept: F,
+) -> Result<(), Error>
+where
+    I: Stream<Item = io::Result<T>>,
+    F: FnMut(T) -> Result<(), Error>,
+{
+    let mut incoming = pin!(incoming);
+    let mut backoff = AcceptBackoff::default();
+    while let Some(stream) = incoming.next().await {
+        match stream {
+            Ok(stream) => {
+                backoff.reset();
+                on_accept(stream)?;
+            },
+            Err(e) if is_connection_error(&e) => {
+                tracing::debug!(
+                    error = ?e,
+                    "Error while accepting new connection, dropping it."
+                );
+            },
+            Err(e) => {
+                let delay = backoff.next_delay();
+                tracing::error!(
+                    error = ?e,
+                    ?delay,
+                    "Error while accepting new connections, backing off."
+                );
+                tokio::time::sleep(delay).await;
+            },
+        }
+    }
+    Ok(())
+}
+
+/// How long the packets left to send get to reach the client once we stop
+/// reading from it, before the connection is dropped anyway.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
 #[tracing::instrument(skip_all, err)]
-async fn handle_stream<S: Server>(
-    stream: TcpStream,
+async fn handle_stream<S, T>(
+    stream: T,
     state: &<S::PacketHandler as PacketHandler>::State,
     actor: &Actor<S::ActorState>,
     rx: mpsc::Receiver<Message>,
-) -> Result<(), Error> {
+) -> Result<(), Error>
+where
+    S: Server,
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
     let cipher = S::Cipher::default();
-    let (encoder, mut decoder) = TQCodec::new(stream, cipher.clone()).split();
+    let (encoder, decoder) =
+        TQCodec::new(stream, cipher.clone(), S::SEAL).split();
     // Start MsgHandler in a seprate task.
-    let message_task = Builder::new()
+    let mut message_task = Builder::new()
         .name("Message Handler")
-        .spawn(handle_msg(rx, encoder, cipher))?;
+        .spawn(handle_msg(rx, encoder, cipher, S::FLUSHING))?;
 
-    while let Some(packet) = decoder.next().await {
-        let (id, bytes) = packet?;
-        if let Err(err) =
-            S::PacketHandler::handle((id, bytes), state, actor).await
-        {
-            let result = actor
-                .send(err)
+    let processing = async {
+        match S::PROCESSING {
+            Processing::Inline => {
+                process_inline::<S, _>(decoder, state, actor).await
+            },
+            Processing::Queued { capacity, overflow } => {
+                process_queued::<S, _>(
+                    decoder, state, actor, capacity, overflow,
+                )
                 .await
-                .map_err(|e| Error::Other(e.to_string()));
-            if let Err(e) = result {
+            },
+        }
+    };
+    // Once the message handler is gone, nothing we send reaches the client,
+    // so there is no point in reading from it either.
+    tokio::select! {
+        result = processing => {
+            tracing::debug!("Socket Closed, stopping task.");
+            // Answers sent right before we stopped, like the reason we are
+            // dropping the client, are still on their way out.
+            let drained = tokio::time::timeout(DRAIN_TIMEOUT, async {
+                let _ = actor.shutdown().await;
+                let _ = (&mut message_task).await;
+            });
+            if drained.await.is_err() {
+                tracing::debug!("Message Handler did not drain in time.");
+                message_task.abort();
+            }
+            result
+        },
+        joined = &mut message_task => {
+            tracing::debug!("Message Handler stopped, closing the socket.");
+            joined?
+        },
+    }
+}
+
+/// Handles every packet right after it is decoded.
+async fn process_inline<S, D>(
+    mut decoder: D,
+    state: &<S::PacketHandler as PacketHandler>::State,
+    actor: &Actor<S::ActorState>,
+) -> Result<(), Error>
+where
+    S: Server,
+    D: Stream<Item = std::io::Result<(u16, Bytes)>> + Unpin,
+{
+    while let Some(packet) = decoder.next().await {
+        if !handle_packet::<S>(packet?, state, actor).await {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Reads packets into a bounded queue while a worker handles them in order.
+///
+/// Once the socket is closed, the worker still handles what is left in the
+/// queue. If the worker stops, the reader is dropped too.
+async fn process_queued<S, D>(
+    mut decoder: D,
+    state: &<S::PacketHandler as PacketHandler>::State,
+    actor: &Actor<S::ActorState>,
+    capacity: usize,
+    overflow: Overflow,
+) -> Result<(), Error>
+where
+    S: Server,
+    D: Stream<Item = std::io::Result<(u16, Bytes)>> + Unpin,
+{
+    let (tx, mut rx) = mpsc::channel(capacity.max(1));
+    let reader = pin!(async move {
+        while let Some(packet) = decoder.next().await {
+            let packet = packet?;
+            match overflow {
+                Overflow::Wait => {
+                    if tx.send(packet).await.is_err() {
+                        break;
+                    }
+                },
+                Overflow::Disconnect => match tx.try_send(packet) {
+                    Ok(()) => {},
+                    Err(TrySendError::Full((id, _))) => {
+                        let reason = DropReason::RateLimited;
+                        let logged =
+                            S::PacketHandler::on_dropped(state, actor, reason);
+                        if let Some(suppressed) = logged {
+                            tracing::warn!(
+                                actor = actor.id(),
+                                %id,
+                                %capacity,
+                                %suppressed,
+                                "Packet queue is full, disconnecting."
+                            );
+                        }
+                        break;
+                    },
+                    Err(TrySendError::Closed(_)) => break,
+                },
+            }
+        }
+        Result::<_, Error>::Ok(())
+    });
+    let worker = pin!(async move {
+        while let Some(packet) = rx.recv().await {
+            if !handle_packet::<S>(packet, state, actor).await {
+                break;
+            }
+        }
+    });
+    match future::select(reader, worker).await {
+        Either::Left((result, worker)) => {
+            worker.await;
+            result
+        },
+        Either::Right(((), _)) => Ok(()),
+    }
+}
+
+/// Handles a single packet, then does what the error says if the handler
+/// failed, see [`HandlerError::action`]. Returns `false` if we should stop
+/// processing packets.
+async fn handle_packet<S: Server>(
+    packet: (u16, Bytes),
+    state: &<S::PacketHandler as PacketHandler>::State,
+    actor: &Actor<S::ActorState>,
+) -> bool {
+    let id = packet.0;
+    if !S::PASSIVE_PACKETS.contains(&id) {
+        actor.handle().touch(Instant::now());
+    }
+    let Err(err) = S::PacketHandler::handle(packet, state, actor).await else {
+        return true;
+    };
+    match err.action() {
+        ErrorAction::Reply => {
+            if let Err(e) = actor.send(err).await {
                 tracing::error!(
                     ?e,
                     "Got Error while sending error packet, stopping task."
                 );
-                break;
+                return false;
             }
-        }
+        },
+        ErrorAction::Log => {
+            tracing::error!(error = ?err, %id, "Failed to handle packet");
+        },
+        ErrorAction::Disconnect => {
+            tracing::debug!(error = ?err, %id, "Disconnecting after error");
+            actor
+                .handle()
+                .set_disconnect_reason(DisconnectReason::Error);
+            return false;
+        },
     }
-    message_task.abort();
-    tracing::debug!("Socket Closed, stopping task.");
-    Ok(())
+    true
 }
 
 #[tracing::instrument(skip(rx, encoder, cipher))]
-async fn handle_msg<C: Cipher>(
-    rx: mpsc::Receiver<Message>,
-    mut encoder: TQEncoder<TcpStream, C>,
+async fn handle_msg<T, C>(
+    mut rx: mpsc::Receiver<Message>,
+    mut encoder: TQEncoder<T, C>,
     cipher: C,
-) -> Result<(), Error> {
+    flushing: Flushing,
+) -> Result<(), Error>
+where
+    T: AsyncRead + AsyncWrite,
+    C: Cipher,
+{
     use Message::*;
-    let mut rx_stream = ReceiverStream::new(rx);
-    while let Some(msg) = rx_stream.next().await {
+    // When the oldest of the packets held back has to be written.
+    let mut deadline = None;
+    loop {
+        let msg = match deadline {
+            Some(at) => {
+                tokio::select! {
+                    msg = rx.recv() => msg,
+                    _ = tokio::time::sleep_until(at) => {
+                        encoder.flush().await?;
+                        deadline = None;
+                        continue;
+                    },
+                }
+            },
+            None => rx.recv().await,
+        };
+        let Some(msg) = msg else {
+            break;
+        };
         match msg {
             GenerateKeys(seed) => {
                 cipher.generate_keys(seed);
             },
-            Packet(id, bytes) => {
-                encoder.send((id, bytes)).await?;
+            Packet(id, bytes) => match flushing {
+                Flushing::Immediate => {
+                    encoder.send((id, bytes)).await?;
+                },
+                Flushing::Coalesced { delay, max_bytes } => {
+                    encoder.queue((id, bytes))?;
+                    if encoder.buffered() >= max_bytes {
+                        encoder.flush().await?;
+                        deadline = None;
+                    } else if deadline.is_none() {
+                        deadline = Some(tokio::time::Instant::now() + delay);
+                    }
+                },
             },
-            Shutdown => {
-                encoder.close().await?;
-                break;
+            Batch(packets) => {
+                for packet in packets {
+                    encoder.queue(packet)?;
+                }
+                match flushing {
+                    Flushing::Coalesced { delay, max_bytes }
+                        if encoder.buffered() < max_bytes =>
+                    {
+                        if deadline.is_none() {
+                            deadline =
+                                Some(tokio::time::Instant::now() + delay);
+                        }
+                    },
+                    _ => {
+                        encoder.flush().await?;
+                        deadline = None;
+                    },
+                }
+            },
+            Flush => {
+                encoder.flush().await?;
+                deadline = None;
+            },
+            // Nothing new could be sent from now on, but whatever is
+            // already queued still gets written before the socket closes.
+            Shutdown => rx.close(),
+            ShutdownWith(id, bytes) => {
+                encoder.queue((id, bytes))?;
+                rx.close();
             },
         };
     }
     tracing::debug!("Socket Closed, stopping handle message.");
+    encoder.flush().await?;
     encoder.close().await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InvalidPacketStats, PacketID};
+    use serde::Serialize;
+    use std::io;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+    use std::time::Instant;
+    use tokio::io::{
+        duplex, AsyncReadExt, AsyncWriteExt, DuplexStream, ReadBuf,
+    };
+    use tq_codec::TQCodec;
+    use tq_crypto::NopCipher;
+
+    /// A packet that takes a while to get handled.
+    const SLOW: u16 = 1;
+
+    /// A packet whose handler fails with an error that only gets logged.
+    const LOGGED: u16 = 3000;
+
+    /// A packet whose handler fails with an error that ends the connection.
+    const FATAL: u16 = 3001;
+
+    /// A packet whose handler fails with an error sent back to the client.
+    const REPLY: u16 = 3002;
+
+    #[derive(Debug, Serialize, thiserror::Error)]
+    #[error("test error")]
+    struct TestError {
+        #[serde(skip)]
+        action: ErrorAction,
+    }
+
+    impl PacketID for TestError {
+        const PACKET_ID: u16 = 0;
+    }
+
+    impl Default for TestError {
+        fn default() -> Self {
+            Self {
+                action: ErrorAction::Reply,
+            }
+        }
+    }
+
+    impl HandlerError for TestError {
+        fn action(&self) -> ErrorAction { self.action }
+    }
+
+    /// The ids of the packets, in the order they got handled, and the
+    /// packets that got dropped.
+    #[derive(Default)]
+    struct Handled(Mutex<Vec<u16>>, InvalidPacketStats);
+
+    struct TestHandler;
+
+    #[async_trait]
+    impl PacketHandler for TestHandler {
+        type ActorState = ();
+        type Error = TestError;
+        type State = Handled;
+
+        async fn handle(
+            (id, _): (u16, Bytes),
+            state: &Self::State,
+            _actor: &Actor<Self::ActorState>,
+        ) -> Result<(), Self::Error> {
+            if id == SLOW {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+            state.0.lock().unwrap().push(id);
+            match id {
+                LOGGED => Err(TestError {
+                    action: ErrorAction::Log,
+                }),
+                FATAL => Err(TestError {
+                    action: ErrorAction::Disconnect,
+                }),
+                REPLY => Err(TestError::default()),
+                _ => Ok(()),
+            }
+        }
+
+        fn on_dropped(
+            state: &Self::State,
+            _actor: &Actor<Self::ActorState>,
+            reason: DropReason,
+        ) -> Option<u64> {
+            state.1.record(None, reason, Instant::now())
+        }
+    }
+
+    struct InlineServer;
+
+    impl Server for InlineServer {
+        type ActorState = ();
+        type Cipher = NopCipher;
+        type PacketHandler = TestHandler;
+    }
+
+    struct QueuedServer;
+
+    impl Server for QueuedServer {
+        type ActorState = ();
+        type Cipher = NopCipher;
+        type PacketHandler = TestHandler;
+
+        const PROCESSING: Processing = Processing::Queued {
+            capacity: 64,
+            overflow: Overflow::Wait,
+        };
+    }
+
+    struct StrictServer;
+
+    impl Server for StrictServer {
+        type ActorState = ();
+        type Cipher = NopCipher;
+        type PacketHandler = TestHandler;
+
+        const PROCESSING: Processing = Processing::Queued {
+            capacity: 1,
+            overflow: Overflow::Disconnect,
+        };
+    }
+
+    /// Holds back the packets it sends for a while before writing them.
+    struct CoalescedServer;
+
+    impl Server for CoalescedServer {
+        type ActorState = ();
+        type Cipher = NopCipher;
+        type PacketHandler = TestHandler;
+
+        const FLUSHING: Flushing = Flushing::Coalesced {
+            delay: Duration::from_millis(50),
+            max_bytes: 1024,
+        };
+    }
+
+    /// A packet the way the client sends it, with an empty body.
+    fn frame(id: u16) -> Vec<u8> {
+        let body = [0u8; 28];
+        let len = (body.len() + 4) as u16;
+        [&len.to_le_bytes()[..], &id.to_le_bytes(), &body].concat()
+    }
+
+    /// Sends a slow packet followed by a few others through a pipe that only
+    /// holds two of them, so the client could only write the rest as fast as
+    /// the server reads them. Returns how long writing took, and the order
+    /// the packets got handled in.
+    async fn send_packets<S>(ids: &[u16]) -> (Duration, Vec<u16>)
+    where
+        S: Server<ActorState = (), PacketHandler = TestHandler>,
+    {
+        let (mut client, server) = duplex(64);
+        let state = Handled::default();
+        let (tx, rx) = mpsc::channel(16);
+        let actor = Actor::<()>::new(tx);
+        let client = async {
+            let started = tokio::time::Instant::now();
+            for id in ids {
+                client.write_all(&frame(*id)).await.unwrap();
+            }
+            let elapsed = started.elapsed();
+            client.shutdown().await.unwrap();
+            elapsed
+        };
+        let server = handle_stream::<S, _>(server, &state, &actor, rx);
+        let (elapsed, result) = tokio::join!(client, server);
+        result.unwrap();
+        (elapsed, state.0.into_inner().unwrap())
+    }
+
+    #[tokio::test]
+    async fn overflowing_the_queue_is_counted() {
+        let (mut client, server) = duplex(64);
+        let state = Handled::default();
+        let (tx, rx) = mpsc::channel(16);
+        let actor = Actor::<()>::new(tx);
+        let client = async {
+            for id in [SLOW, 2, 3, 4] {
+                // The server stops reading once it drops the client.
+                if client.write_all(&frame(id)).await.is_err() {
+                    break;
+                }
+            }
+        };
+        let server =
+            handle_stream::<StrictServer, _>(server, &state, &actor, rx);
+        let ((), result) = tokio::join!(client, server);
+        result.unwrap();
+        let dropped = state.1.count(None, DropReason::RateLimited);
+        assert_eq!(dropped, 1);
+        assert_eq!(state.1.count(None, DropReason::Decode), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_handler_does_not_block_reading() {
+        let ids: Vec<_> = [SLOW].into_iter().chain(2..10).collect();
+        // The clock is paused, it only moves once the slow handler is all
+        // that is left to wait for.
+        let (elapsed, _) = send_packets::<InlineServer>(&ids).await;
+        assert!(elapsed >= Duration::from_millis(200), "{elapsed:?}");
+        let (elapsed, _) = send_packets::<QueuedServer>(&ids).await;
+        assert_eq!(elapsed, Duration::ZERO);
+    }
+
+    /// Keeps the client connected without sending anything, and stops the
+    /// message handler by shutting the actor down.
+    async fn stop_message_handler<S>()
+    where
+        S: Server<ActorState = (), PacketHandler = TestHandler>,
+    {
+        let (_client, server) = duplex(64);
+        let state = Handled::default();
+        let (tx, rx) = mpsc::channel(16);
+        let actor = Actor::<()>::new(tx);
+        // The handler reads it as soon as it starts.
+        actor.shutdown().await.unwrap();
+        let server = handle_stream::<S, _>(server, &state, &actor, rx);
+        let result = tokio::time::timeout(Duration::from_secs(1), server)
+            .await
+            .expect("connection was not torn down");
+        result.unwrap();
+        assert!(actor.send(TestError::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn handler_errors_decide_what_happens_to_the_connection() {
+        let (mut client, server) = duplex(64);
+        let state = Handled::default();
+        let (tx, mut rx) = mpsc::channel(16);
+        let actor = Actor::<()>::new(tx);
+        let client = async {
+            for id in [2, LOGGED, 3, FATAL, 4] {
+                // The server stops reading once it drops the client.
+                if client.write_all(&frame(id)).await.is_err() {
+                    break;
+                }
+            }
+        };
+        let server = process_inline::<InlineServer, _>(
+            TQCodec::new(server, NopCipher, Seal::None).split().1,
+            &state,
+            &actor,
+        );
+        let ((), result) = tokio::join!(client, server);
+        result.unwrap();
+        // Going on after the logged error, but not after the fatal one.
+        assert_eq!(state.0.into_inner().unwrap(), [2, LOGGED, 3, FATAL]);
+        assert_eq!(actor.disconnect_reason(), DisconnectReason::Error);
+        // Neither got sent back to the client.
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn answers_reach_the_client_before_the_close() {
+        let (mut client, server) = duplex(64);
+        let state = Handled::default();
+        let (tx, rx) = mpsc::channel(16);
+        let actor = Actor::<()>::new(tx);
+        let client = async {
+            client.write_all(&frame(REPLY)).await.unwrap();
+            // Hanging up right away, the answer is still held back then.
+            client.shutdown().await.unwrap();
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            received
+        };
+        let server =
+            handle_stream::<CoalescedServer, _>(server, &state, &actor, rx);
+        let (received, result) = tokio::join!(client, server);
+        result.unwrap();
+        assert_eq!(received.len(), PACKET_LEN);
+        // Nothing more could be sent once the connection is closing.
+        assert!(actor.send(TestError::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn shutdown_writes_what_was_queued_before_it() {
+        let (actor, mut client, _) = message_handler(Flushing::Coalesced {
+            delay: Duration::from_secs(60),
+            max_bytes: 1024,
+        });
+        for _ in 0..3 {
+            actor.send(TestError::default()).await.unwrap();
+        }
+        actor.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received.len(), 3 * PACKET_LEN);
+    }
+
+    #[tokio::test]
+    async fn last_packet_is_written_before_the_close() {
+        #[derive(Serialize)]
+        struct Goodbye;
+
+        impl PacketID for Goodbye {
+            const PACKET_ID: u16 = 1;
+        }
+
+        let (actor, mut client, _) = message_handler(Flushing::Immediate);
+        actor.send(TestError::default()).await.unwrap();
+        actor.shutdown_with(Goodbye).await.unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        let ids: Vec<_> = received
+            .chunks(PACKET_LEN)
+            .map(|frame| u16::from_le_bytes([frame[2], frame[3]]))
+            .collect();
+        assert_eq!(ids, [TestError::PACKET_ID, Goodbye::PACKET_ID]);
+        assert!(actor.send(TestError::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn stopped_message_handler_tears_down_connection() {
+        stop_message_handler::<InlineServer>().await;
+        stop_message_handler::<QueuedServer>().await;
+    }
+
+    #[tokio::test]
+    async fn queued_packets_keep_their_order() {
+        let ids: Vec<_> = [2, SLOW, 3, 4, SLOW, 5].into_iter().collect();
+        let (_, handled) = send_packets::<QueuedServer>(&ids).await;
+        assert_eq!(handled, ids);
+        let (_, handled) = send_packets::<InlineServer>(&ids).await;
+        assert_eq!(handled, ids);
+    }
+
+    /// A pipe that counts how many times it got written to.
+    struct CountWrites {
+        inner: DuplexStream,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl AsyncRead for CountWrites {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for CountWrites {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+            if res.is_ready() {
+                self.writes.fetch_add(1, Ordering::Relaxed);
+            }
+            res
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    /// Starts a message handler writing to a pipe, returns the actor
+    /// sending to it, the client end of the pipe and the write counter.
+    fn message_handler(
+        flushing: Flushing,
+    ) -> (Actor<()>, DuplexStream, Arc<AtomicUsize>) {
+        let (client, server) = duplex(64 * 1024);
+        let writes = Arc::new(AtomicUsize::new(0));
+        let server = CountWrites {
+            inner: server,
+            writes: writes.clone(),
+        };
+        let cipher = NopCipher;
+        let (encoder, _) = TQCodec::new(server, cipher, Seal::None).split();
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(handle_msg(rx, encoder, cipher, flushing));
+        (Actor::<()>::new(tx), client, writes)
+    }
+
+    /// The size of a [`TestError`] packet on the wire.
+    const PACKET_LEN: usize = 4;
+
+    #[tokio::test]
+    async fn coalesced_bursts_are_written_once() {
+        let (actor, mut client, writes) = message_handler(Flushing::Immediate);
+        for _ in 0..10 {
+            actor.send(TestError::default()).await.unwrap();
+        }
+        let mut buf = [0u8; 10 * PACKET_LEN];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(writes.load(Ordering::Relaxed), 10);
+
+        let (actor, mut client, writes) =
+            message_handler(Flushing::Coalesced {
+                delay: Duration::from_millis(50),
+                max_bytes: 1024,
+            });
+        let started = Instant::now();
+        for _ in 0..10 {
+            actor.send(TestError::default()).await.unwrap();
+        }
+        client.read_exact(&mut buf).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(writes.load(Ordering::Relaxed), 1);
+
+        // Going over the size limit does not wait for the delay.
+        let (actor, mut client, writes) =
+            message_handler(Flushing::Coalesced {
+                delay: Duration::from_secs(60),
+                max_bytes: 5 * PACKET_LEN,
+            });
+        for _ in 0..10 {
+            actor.send(TestError::default()).await.unwrap();
+        }
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            client.read_exact(&mut buf),
+        )
+        .await
+        .expect("packets were held back")
+        .unwrap();
+        assert_eq!(writes.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn send_now_skips_coalescing() {
+        let (actor, mut client, writes) =
+            message_handler(Flushing::Coalesced {
+                delay: Duration::from_secs(60),
+                max_bytes: 1024,
+            });
+        actor.send(TestError::default()).await.unwrap();
+        actor.send_now(TestError::default()).await.unwrap();
+        let mut buf = [0u8; 2 * PACKET_LEN];
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            client.read_exact(&mut buf),
+        )
+        .await
+        .expect("packets were held back")
+        .unwrap();
+        assert_eq!(writes.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn batches_are_written_at_once() {
+        let (actor, mut client, writes) = message_handler(Flushing::Immediate);
+        let packet =
+            crate::PacketEncode::encode(&TestError::default()).unwrap();
+        actor.handle().send_batch(vec![packet; 10]).await.unwrap();
+        let mut buf = [0u8; 10 * PACKET_LEN];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(writes.load(Ordering::Relaxed), 1);
+    }
+
+    /// The wait after `failures` failures in a row, before the jitter.
+    fn doubled(failures: u32) -> Duration {
+        (MIN_ACCEPT_BACKOFF * 2u32.pow(failures)).min(MAX_ACCEPT_BACKOFF)
+    }
+
+    #[test]
+    fn accept_backoff_is_capped_and_resets() {
+        let mut backoff = AcceptBackoff::default();
+        for failures in 0..12 {
+            let delay = backoff.next_delay();
+            let doubled = doubled(failures);
+            assert!(delay >= doubled / 2 && delay <= doubled, "{delay:?}");
+        }
+        backoff.reset();
+        assert!(backoff.next_delay() <= MIN_ACCEPT_BACKOFF);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn accept_errors_back_off() {
+        let emfile = || io::Error::other("Too many open files (os error 24)");
+        let reset = || io::Error::from(io::ErrorKind::ConnectionReset);
+        let incoming =
+            futures::stream::iter((0..8).map(|_| Err(emfile())).chain([
+                Err(reset()),
+                Ok(1),
+                Ok(2),
+            ]));
+        let mut accepted = Vec::new();
+        let started = tokio::time::Instant::now();
+        accept_loop(incoming, |n| {
+            accepted.push(n);
+            Ok(())
+        })
+        .await
+        .unwrap();
+        assert_eq!(accepted, [1, 2]);
+        // The clock is paused, it only moves when the loop sleeps.
+        let least: Duration = (0..8).map(|i| doubled(i) / 2).sum();
+        let most: Duration = (0..8).map(doubled).sum();
+        let waited = started.elapsed();
+        assert!(waited >= least && waited <= most, "{waited:?}");
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often a drop gets logged for the same address and reason, the drops
+/// in between are only counted.
+pub const DROP_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Why a packet from a client got dropped without being handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DropReason {
+    /// The packet could not be decoded.
+    Decode,
+    /// The server does not handle packets with that id.
+    UnknownId,
+    /// The client sent packets faster than we could handle them.
+    RateLimited,
+}
+
+impl DropReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Decode => "decode",
+            Self::UnknownId => "unknown_id",
+            Self::RateLimited => "rate_limited",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counter {
+    count: u64,
+    /// When this drop got logged last, `None` if it never did.
+    logged_at: Option<Instant>,
+    /// The drops since the last one that got logged.
+    suppressed: u64,
+}
+
+/// Counts the packets dropped from every address, by reason, to tell abuse
+/// apart from the odd broken packet.
+///
+/// Clients without a known address are counted under `None`.
+#[derive(Debug, Default)]
+pub struct InvalidPacketStats {
+    counters: Mutex<HashMap<(Option<IpAddr>, DropReason), Counter>>,
+}
+
+impl InvalidPacketStats {
+    pub fn new() -> Self { Self::default() }
+
+    /// Counts a dropped packet. Returns how many drops went unlogged since
+    /// the last one if this one should get logged, `None` if it was logged
+    /// less than [`DROP_LOG_INTERVAL`] ago.
+    pub fn record(
+        &self,
+        ip: Option<IpAddr>,
+        reason: DropReason,
+        now: Instant,
+    ) -> Option<u64> {
+        let mut counters =
+            self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        let counter = counters.entry((ip, reason)).or_default();
+        counter.count += 1;
+        let due = counter.logged_at.is_none_or(|at| {
+            now.saturating_duration_since(at) >= DROP_LOG_INTERVAL
+        });
+        if due {
+            counter.logged_at = Some(now);
+            Some(std::mem::take(&mut counter.suppressed))
+        } else {
+            counter.suppressed += 1;
+            None
+        }
+    }
+
+    /// How many packets from that address got dropped for that reason.
+    pub fn count(&self, ip: Option<IpAddr>, reason: DropReason) -> u64 {
+        let counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        counters.get(&(ip, reason)).map_or(0, |c| c.count)
+    }
+
+    /// Every counter, the highest first.
+    pub fn snapshot(&self) -> Vec<(Option<IpAddr>, DropReason, u64)> {
+        let counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        let mut snapshot: Vec<_> = counters
+            .iter()
+            .map(|(&(ip, reason), c)| (ip, reason, c.count))
+            .collect();
+        snapshot
+            .sort_by(|a, b| b.2.cmp(&a.2).then((a.0, a.1).cmp(&(b.0, b.1))));
+        snapshot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const IP: Option<IpAddr> = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+
+    #[test]
+    fn counters_are_kept_by_address_and_reason() {
+        let stats = InvalidPacketStats::new();
+        let now = Instant::now();
+        let reasons = [
+            DropReason::Decode,
+            DropReason::UnknownId,
+            DropReason::RateLimited,
+        ];
+        for (i, reason) in reasons.into_iter().enumerate() {
+            for _ in 0..=i {
+                stats.record(IP, reason, now);
+            }
+            assert_eq!(stats.count(IP, reason), i as u64 + 1);
+            assert_eq!(stats.count(None, reason), 0);
+        }
+        stats.record(None, DropReason::Decode, now);
+        assert_eq!(stats.count(None, DropReason::Decode), 1);
+        assert_eq!(
+            stats.snapshot(),
+            [
+                (IP, DropReason::RateLimited, 3),
+                (IP, DropReason::UnknownId, 2),
+                (None, DropReason::Decode, 1),
+                (IP, DropReason::Decode, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn floods_are_logged_once_per_interval() {
+        let stats = InvalidPacketStats::new();
+        let now = Instant::now();
+        assert_eq!(stats.record(IP, DropReason::Decode, now), Some(0));
+        for _ in 0..100 {
+            assert_eq!(stats.record(IP, DropReason::Decode, now), None);
+        }
+        // Other reasons are logged on their own.
+        assert_eq!(stats.record(IP, DropReason::UnknownId, now), Some(0));
+        let later = now + DROP_LOG_INTERVAL;
+        assert_eq!(stats.record(IP, DropReason::Decode, later), Some(100));
+        assert_eq!(stats.count(IP, DropReason::Decode), 102);
+    }
+}
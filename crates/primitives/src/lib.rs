@@ -122,7 +122,7 @@ impl Gauge {
     pub fn is_empty(&self) -> bool { self.current == 0 }
 
     pub fn increment(&mut self, amount: u16) {
-        self.current = (self.current + amount).min(self.max);
+        self.current = self.current.saturating_add(amount).min(self.max);
     }
 
     pub fn decrement(&mut self, amount: u16) {
@@ -15,6 +15,24 @@ impl<'de> Deserializer<'de> {
             input: Cursor::new(input),
         }
     }
+
+    /// Reads a single byte, `Eof` if there is none left.
+    fn read_u8(&mut self) -> Result<u8, TQSerdeError> {
+        if !self.input.has_remaining() {
+            return Err(TQSerdeError::Eof);
+        }
+        Ok(self.input.get_u8())
+    }
+
+    /// Reads a byte with the length of what follows it, then that many
+    /// bytes. `Eof` if the input ends before them.
+    fn read_prefixed(&mut self) -> Result<bytes::Bytes, TQSerdeError> {
+        let length = self.read_u8()? as usize;
+        if self.input.remaining() < length {
+            return Err(TQSerdeError::Eof);
+        }
+        Ok(self.input.copy_to_bytes(length))
+    }
 }
 /// Deserialize the given Bytes into `T`.
 pub fn from_bytes<'a, T>(s: &'a [u8]) -> Result<T, TQSerdeError>
@@ -38,6 +56,9 @@ macro_rules! impl_nums {
             V: serde::de::Visitor<'de>,
         {
             use std::mem::size_of;
+            if self.input.remaining() < size_of::<$ty>() {
+                return Err(TQSerdeError::Eof);
+            }
             let value = self.input.get_uint_le(size_of::<$ty>()) as $ty;
             visitor.$visitor_method(value)
         }
@@ -79,7 +100,7 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
         V: Visitor<'de>,
     {
         // 0 = false, 1 = true
-        let value = self.input.get_u8();
+        let value = self.read_u8()?;
         match value {
             0 => visitor.visit_bool(false),
             1 => visitor.visit_bool(true),
@@ -91,7 +112,7 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
     where
         V: Visitor<'de>,
     {
-        let value = self.input.get_u8();
+        let value = self.read_u8()?;
         visitor.visit_char(value as char)
     }
 
@@ -106,8 +127,7 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
     where
         V: Visitor<'de>,
     {
-        let length = self.input.get_u8();
-        let string_bytes = self.input.copy_to_bytes(length as usize);
+        let string_bytes = self.read_prefixed()?;
         let val = String::from_utf8_lossy(&string_bytes);
         let val = val.trim_end_matches('\0');
         visitor.visit_string(val.to_string())
@@ -132,8 +152,7 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
     where
         V: Visitor<'de>,
     {
-        let length = self.input.get_u8();
-        let bytes = self.input.copy_to_bytes(length as usize);
+        let bytes = self.read_prefixed()?;
         visitor.visit_byte_buf(bytes.to_vec())
     }
 
@@ -321,3 +340,36 @@ fn test_struct_de() {
         test
     );
 }
+
+#[test]
+fn test_short_input_is_eof() {
+    use serde::Deserialize;
+    #[derive(Deserialize, Debug)]
+    #[allow(dead_code)]
+    struct MsgTransfer {
+        account_id: u32,
+        realm_id: u32,
+        version: u16,
+    }
+    let input = [1u8, 0, 0, 0, 2, 0, 0, 0];
+    let err = from_bytes::<MsgTransfer>(&input).unwrap_err();
+    assert!(matches!(err, TQSerdeError::Eof));
+}
+
+#[test]
+fn string_longer_than_input_is_eof() {
+    use serde::Deserialize;
+    #[derive(Deserialize, Debug)]
+    #[allow(dead_code)]
+    struct MsgTalk {
+        color: u32,
+        sender: String,
+    }
+    // Found by the packet fuzz tests, the length says 3 bytes but only 2
+    // follow it.
+    let input = [1u8, 0, 0, 0, 3, b'h', b'i'];
+    let err = from_bytes::<MsgTalk>(&input).unwrap_err();
+    assert!(matches!(err, TQSerdeError::Eof));
+    let err = from_bytes::<MsgTalk>(&[1, 0, 0, 0]).unwrap_err();
+    assert!(matches!(err, TQSerdeError::Eof));
+}
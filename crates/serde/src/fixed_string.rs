@@ -89,8 +89,13 @@ impl Serialize for FixedString<16, Encrypted> {
         &self,
         serializer: S,
     ) -> Result<S::Ok, S::Error> {
-        // FIXME: encrypt password
-        encode_fixed_string::<16>(&self.inner).serialize(serializer)
+        let rc5 = TQRC5::new();
+        let mut pass_encrypted_bytes = [0u8; 16];
+        rc5.encrypt(
+            &encode_fixed_string::<16>(&self.inner),
+            &mut pass_encrypted_bytes,
+        );
+        pass_encrypted_bytes.serialize(serializer)
     }
 }
 
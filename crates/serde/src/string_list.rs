@@ -169,9 +169,20 @@ impl<'de> serde::Deserialize<'de> for StringList {
             ) -> Result<Self::Value, E> {
                 let mut strings = Vec::new();
                 let mut reader = bytes::Bytes::copy_from_slice(v);
+                // Some packets only carry the list for some of their actions.
+                if !reader.has_remaining() {
+                    return Ok(StringList { inner: strings });
+                }
                 let len = reader.get_u8() as usize;
-                for _ in 0..len {
+                for i in 0..len {
+                    let eof = || serde::de::Error::invalid_length(i, &self);
+                    if !reader.has_remaining() {
+                        return Err(eof());
+                    }
                     let string_len = reader.get_u8() as usize;
+                    if reader.remaining() < string_len {
+                        return Err(eof());
+                    }
                     let string_bytes = reader.copy_to_bytes(string_len);
                     let string = std::str::from_utf8(&string_bytes)
                         .map(|s| s.trim_end_matches('\0'))
@@ -244,6 +255,27 @@ mod tests {
         assert_eq!(list, deserialized);
     }
 
+    #[test]
+    fn test_deserialize_missing_list() {
+        #[derive(Debug, Deserialize)]
+        struct Msg {
+            action: u32,
+            names: StringList,
+        }
+        let msg: Msg = crate::from_bytes(&[3, 0, 0, 0]).unwrap();
+        assert_eq!(msg.action, 3);
+        assert!(msg.names.is_empty());
+    }
+
+    #[test]
+    fn test_deserialize_truncated_list() {
+        // Found by the packet fuzz tests, two strings are announced but the
+        // second one is cut short.
+        let input = [2, 2, b'h', b'i', 5, b'w', b'o'];
+        assert!(crate::from_bytes::<StringList>(&input).is_err());
+        assert!(crate::from_bytes::<StringList>(&[3, 1, b'a']).is_err());
+    }
+
     #[test]
     fn test_serialize_deserialize_msg() {
         #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
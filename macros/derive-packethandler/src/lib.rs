@@ -1,70 +1,91 @@
 use proc_macro::TokenStream;
 use quote::quote;
 use syn::parse::{Parse, ParseStream};
-use syn::{parse_macro_input, Data, DataEnum, DeriveInput, Expr, Ident, Token};
+use syn::{parse_macro_input, Data, DeriveInput, Expr, Ident, Token};
 
 struct Args {
     actor_state: Expr,
     state: Expr,
+    /// An optional `fn(u16) -> Option<&'static str>` used to name the
+    /// packets we do not handle.
+    names: Option<Expr>,
+    /// An optional `fn(&State) -> &tq_network::InvalidPacketStats` where
+    /// the dropped packets get counted.
+    stats: Option<Expr>,
+    /// An optional `async fn(&State, &Actor<ActorState>, DropReason) ->
+    /// Result<(), Error>` called after a packet from the actor got dropped,
+    /// to act on clients sending garbage.
+    dropped: Option<Expr>,
 }
 
 impl Parse for Args {
     fn parse(input: ParseStream) -> syn::Result<Self> {
-        let ident1: Ident = input.parse().map_err(|e| {
-            syn::Error::new(
-                e.span(),
-                "expected `state` or `actor_state` but got nothing",
-            )
-        })?;
-        let _: Token!(=) = input
-            .parse()
-            .map_err(|e| syn::Error::new(e.span(), "expected `=`"))?;
-        let ident1_value: Expr = input
-            .parse()
-            .map_err(|e| syn::Error::new(e.span(), "expected `Expr`"))?;
-        let _: Token!(,) = input
-            .parse()
-            .map_err(|e| syn::Error::new(e.span(), "expected `,`"))?;
-        let ident2: Ident = input.parse().map_err(|e| {
-            syn::Error::new(
-                e.span(),
-                "expected `state` or `actor_state` but got nothing",
-            )
-        })?;
-        let _: Token!(=) = input
-            .parse()
-            .map_err(|e| syn::Error::new(e.span(), "expected `=`"))?;
-        let ident2_value: Expr = input
-            .parse()
-            .map_err(|e| syn::Error::new(e.span(), "expected `Expr`"))?;
-        let (state, actor_state) = match (ident1, ident2) {
-            (ident1, ident2)
-                if ident1 == "state" && ident2 == "actor_state" =>
-            {
-                (ident1_value, ident2_value)
-            },
-            (ident1, ident2)
-                if ident1 == "actor_state" && ident2 == "state" =>
-            {
-                (ident2_value, ident1_value)
-            },
-            (v1, v2) => {
+        let mut state = None;
+        let mut actor_state = None;
+        let mut names = None;
+        let mut stats = None;
+        let mut dropped = None;
+        while !input.is_empty() {
+            let ident: Ident = input.parse().map_err(|e| {
+                syn::Error::new(
+                    e.span(),
+                    "expected `state`, `actor_state`, `names`, `stats` or `dropped`",
+                )
+            })?;
+            let _: Token!(=) = input
+                .parse()
+                .map_err(|e| syn::Error::new(e.span(), "expected `=`"))?;
+            let value: Expr = input
+                .parse()
+                .map_err(|e| syn::Error::new(e.span(), "expected `Expr`"))?;
+            let slot = if ident == "state" {
+                &mut state
+            } else if ident == "actor_state" {
+                &mut actor_state
+            } else if ident == "names" {
+                &mut names
+            } else if ident == "stats" {
+                &mut stats
+            } else if ident == "dropped" {
+                &mut dropped
+            } else {
                 return Err(syn::Error::new(
-                    input.span(),
+                    ident.span(),
                     format!(
-                    "expected `state` or `actor_state` but got {v1} and {v2}",
-                ),
-                ))
-            },
+                        "expected `state`, `actor_state`, `names`, `stats` or `dropped` but got {ident}",
+                    ),
+                ));
+            };
+            if slot.replace(value).is_some() {
+                return Err(syn::Error::new(
+                    ident.span(),
+                    format!("duplicate `{ident}`"),
+                ));
+            }
+            if !input.is_empty() {
+                let _: Token!(,) = input
+                    .parse()
+                    .map_err(|e| syn::Error::new(e.span(), "expected `,`"))?;
+            }
+        }
+        let state = state
+            .ok_or_else(|| syn::Error::new(input.span(), "missing `state`"))?;
+        let actor_state = actor_state.ok_or_else(|| {
+            syn::Error::new(input.span(), "missing `actor_state`")
+        })?;
+        let args = Self {
+            state,
+            actor_state,
+            names,
+            stats,
+            dropped,
         };
-
-        let args = Self { state, actor_state };
         Ok(args)
     }
 }
 fn derive_packet_handler(input: DeriveInput) -> syn::Result<TokenStream> {
-    let body = if let Data::Enum(e) = input.data {
-        body(e)?
+    let e = if let Data::Enum(e) = input.data {
+        e
     } else {
         return Err(syn::Error::new(
             input.ident.span(),
@@ -81,12 +102,36 @@ fn derive_packet_handler(input: DeriveInput) -> syn::Result<TokenStream> {
             syn::Error::new(name.span(),"Missing State and ActorState! please add #[handle(state = .., actor_state = ...)] on the enum"),
         )?;
     let args: Args = attr.parse_args()?;
+    let variants: Vec<_> = e
+        .variants
+        .into_iter()
+        .filter(|v| v.fields.is_empty())
+        .map(|v| v.ident)
+        .collect();
+    let body = body(&variants, args.names.as_ref(), args.dropped.as_ref())?;
+    let on_dropped = args.stats.map(|stats| {
+        quote! {
+            fn on_dropped(
+                state: &Self::State,
+                actor: &tq_network::Actor<Self::ActorState>,
+                reason: tq_network::DropReason,
+            ) -> Option<u64> {
+                let ip = actor.peer_addr().map(|addr| addr.ip());
+                (#stats)(state).record(ip, reason, ::std::time::Instant::now())
+            }
+        }
+    });
     let state = args.state;
     let actor_state = args.actor_state;
     let generics = input.generics;
     let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
     // Build the output, possibly using quasi-quotation
     let expanded = quote! {
+        impl #impl_generics #name #ty_generics #where_clause {
+            /// The ids of all the packets handled by this handler.
+            pub const PACKET_IDS: &'static [u16] = &[#(<#variants as tq_network::PacketID>::PACKET_ID),*];
+        }
+
         #[async_trait::async_trait]
         impl #impl_generics tq_network::PacketHandler for #name #ty_generics #where_clause {
             type Error = crate::Error;
@@ -102,25 +147,38 @@ fn derive_packet_handler(input: DeriveInput) -> syn::Result<TokenStream> {
                     #body
                     Ok(())
                 }
+
+            #on_dropped
         }
     };
     Ok(expanded.into())
 }
 
-fn body(e: DataEnum) -> syn::Result<proc_macro2::TokenStream> {
-    let vars = e.variants.into_iter().filter(|v| v.fields.is_empty());
-    let match_stms = vars.into_iter().map(|v| {
-        let ident = v.ident;
+fn body(
+    variants: &[Ident],
+    names: Option<&Expr>,
+    dropped: Option<&Expr>,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let after_dropped = dropped.map(|dropped| {
+        quote! {
+            (#dropped)(state, actor, reason).await?;
+        }
+    });
+    let match_stms = variants.iter().map(|ident| {
         quote! {
             #ident::PACKET_ID => {
-                let maybe_msg = <#ident as tq_network::PacketDecode>::decode(&packet.1);
+                let maybe_msg = <#ident as tq_network::PacketDecode>::decode_with(&packet.1, actor.codec());
                 match maybe_msg {
                     Ok(msg) => {
                         tracing::debug!(target: "cq_msg", "{msg:?}");
                         msg.process(state, actor).await?;
                     },
                     Err(e) => {
-                        tracing::error!(id = %packet.0, error = ?e, "Failed to decode packet");
+                        let reason = tq_network::DropReason::Decode;
+                        if let Some(suppressed) = Self::on_dropped(state, actor, reason) {
+                            tracing::error!(id = %packet.0, error = ?e, %suppressed, "Failed to decode packet");
+                        }
+                        #after_dropped
                         return Ok(());
                     }
                 }
@@ -128,11 +186,24 @@ fn body(e: DataEnum) -> syn::Result<proc_macro2::TokenStream> {
             },
         }
     });
+    let unknown = match names {
+        Some(names) => quote! {
+            let name = (#names)(packet.0).unwrap_or("Unknown");
+            tracing::warn!(id = %packet.0, %name, %suppressed, "Got Unhandled Packet");
+        },
+        None => quote! {
+            tracing::warn!(id = %packet.0, %suppressed, "Got Unknown Packet");
+        },
+    };
     let tokens = quote! {
         match packet.0 {
             #(#match_stms)*
             _ => {
-                tracing::warn!(id = %packet.0, "Got Unknown Packet");
+                let reason = tq_network::DropReason::UnknownId;
+                if let Some(suppressed) = Self::on_dropped(state, actor, reason) {
+                    #unknown
+                }
+                #after_dropped
             }
         }
     };
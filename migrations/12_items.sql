@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS items (
+    item_id INTEGER PRIMARY KEY,
+    character_id INTEGER NOT NULL CONSTRAINT fk_character REFERENCES characters(character_id) ON DELETE CASCADE,
+    item_type INTEGER NOT NULL CHECK (item_type > 0),
+    position INTEGER NOT NULL DEFAULT 0 CHECK (position >= 0)
+);
+
+CREATE INDEX IF NOT EXISTS idx_items_character ON items (character_id, position);
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE characters ADD COLUMN received_starter_kit BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE accounts ADD COLUMN gm_level INTEGER NOT NULL DEFAULT 0 CHECK (gm_level >= 0 AND gm_level <= 255);
+ALTER TABLE accounts ADD COLUMN privileges INTEGER NOT NULL DEFAULT 0;
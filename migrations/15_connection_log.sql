@@ -0,0 +1,12 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS connection_log (
+    session_id INTEGER PRIMARY KEY,
+    account_id INTEGER NOT NULL,
+    character_id INTEGER DEFAULT NULL,
+    ip TEXT DEFAULT NULL,
+    connected_at INTEGER NOT NULL,
+    disconnected_at INTEGER DEFAULT NULL,
+    disconnect_reason TEXT DEFAULT NULL
+);
+
+CREATE INDEX IF NOT EXISTS connection_log_account ON connection_log (account_id, connected_at);
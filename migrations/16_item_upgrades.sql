@@ -0,0 +1,4 @@
+-- Add migration script here
+ALTER TABLE items ADD COLUMN plus INTEGER NOT NULL DEFAULT 0 CHECK (plus >= 0);
+ALTER TABLE items ADD COLUMN gem_one INTEGER NOT NULL DEFAULT 0 CHECK (gem_one >= 0 AND gem_one <= 255);
+ALTER TABLE items ADD COLUMN gem_two INTEGER NOT NULL DEFAULT 0 CHECK (gem_two >= 0 AND gem_two <= 255);
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE characters ADD COLUMN spouse TEXT NOT NULL DEFAULT 'None';
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE items ADD COLUMN amount INTEGER NOT NULL DEFAULT 1 CHECK (amount >= 0);
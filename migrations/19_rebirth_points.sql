@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE characters ADD COLUMN rebirth_points INTEGER NOT NULL DEFAULT 0 CHECK (rebirth_points >= 0);
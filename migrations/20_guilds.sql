@@ -0,0 +1,15 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS guilds (
+    guild_id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    leader_id INTEGER NOT NULL CONSTRAINT fk_leader REFERENCES characters(character_id) ON DELETE CASCADE,
+    funds INTEGER NOT NULL DEFAULT 0 CHECK (funds >= 0)
+);
+
+CREATE TABLE IF NOT EXISTS guild_members (
+    character_id INTEGER PRIMARY KEY CONSTRAINT fk_character REFERENCES characters(character_id) ON DELETE CASCADE,
+    guild_id INTEGER NOT NULL CONSTRAINT fk_guild REFERENCES guilds(guild_id) ON DELETE CASCADE,
+    rank INTEGER NOT NULL CHECK (rank > 0)
+);
+
+CREATE INDEX IF NOT EXISTS idx_guild_members_guild ON guild_members (guild_id);
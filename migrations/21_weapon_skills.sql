@@ -0,0 +1,8 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS weapon_skills (
+    character_id INTEGER NOT NULL CONSTRAINT fk_character REFERENCES characters(character_id) ON DELETE CASCADE,
+    skill_type INTEGER NOT NULL,
+    level INTEGER NOT NULL DEFAULT 0 CHECK (level >= 0),
+    experience INTEGER NOT NULL DEFAULT 0 CHECK (experience >= 0),
+    PRIMARY KEY (character_id, skill_type)
+);
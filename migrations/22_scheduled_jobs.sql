@@ -0,0 +1,6 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS scheduled_jobs (
+    name TEXT PRIMARY KEY NOT NULL,
+    -- The server time of the last run, in seconds since the epoch.
+    last_run INTEGER NOT NULL
+);
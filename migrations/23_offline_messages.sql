@@ -0,0 +1,12 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS offline_messages (
+    message_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    character_id INTEGER NOT NULL CONSTRAINT fk_character REFERENCES characters(character_id) ON DELETE CASCADE,
+    sender_name TEXT NOT NULL,
+    channel INTEGER NOT NULL,
+    message TEXT NOT NULL,
+    -- When it was sent, in seconds since the epoch.
+    sent_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS offline_messages_character ON offline_messages (character_id, message_id);
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE accounts ADD COLUMN warehouse_password TEXT;
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE portals ADD COLUMN flags INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,12 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS character_enemies (
+    character_id INTEGER NOT NULL CONSTRAINT fk_character REFERENCES characters(character_id) ON DELETE CASCADE,
+    enemy_id INTEGER NOT NULL CONSTRAINT fk_enemy REFERENCES characters(character_id) ON DELETE CASCADE,
+    -- The name of the enemy when it last killed the character.
+    enemy_name TEXT NOT NULL,
+    -- When it last killed the character, in seconds since the epoch.
+    killed_at INTEGER NOT NULL,
+    PRIMARY KEY (character_id, enemy_id)
+);
+
+CREATE INDEX IF NOT EXISTS character_enemies_enemy ON character_enemies (enemy_id);
@@ -0,0 +1,11 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS cheat_log (
+    cheat_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    character_id INTEGER NOT NULL,
+    -- What was caught, like `equip_position`.
+    kind TEXT NOT NULL,
+    detail TEXT NOT NULL,
+    caught_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS cheat_log_character ON cheat_log (character_id, caught_at);
@@ -0,0 +1,12 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS warehouse_items (
+    warehouse_item_id INTEGER PRIMARY KEY,
+    account_id INTEGER NOT NULL CONSTRAINT fk_account REFERENCES accounts(account_id) ON DELETE CASCADE,
+    item_type INTEGER NOT NULL CHECK (item_type > 0),
+    plus INTEGER NOT NULL DEFAULT 0 CHECK (plus >= 0),
+    gem_one INTEGER NOT NULL DEFAULT 0 CHECK (gem_one >= 0 AND gem_one <= 255),
+    gem_two INTEGER NOT NULL DEFAULT 0 CHECK (gem_two >= 0 AND gem_two <= 255),
+    amount INTEGER NOT NULL DEFAULT 1 CHECK (amount >= 0)
+);
+
+CREATE INDEX IF NOT EXISTS warehouse_items_account ON warehouse_items (account_id);
@@ -1,6 +1,6 @@
 use bytes::Bytes;
 use thiserror::Error;
-use tq_network::{ErrorPacket, PacketEncode};
+use tq_network::{ErrorAction, ErrorPacket, HandlerError, PacketEncode};
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -42,3 +42,14 @@ impl PacketEncode for Error {
         }
     }
 }
+
+impl HandlerError for Error {
+    fn action(&self) -> ErrorAction {
+        match self {
+            Self::Msg(..) => ErrorAction::Reply,
+            Self::Network(e) => e.action(),
+            Self::IO(_) => ErrorAction::Disconnect,
+            _ => ErrorAction::Log,
+        }
+    }
+}
@@ -1,6 +1,8 @@
 pub mod error;
 pub mod packets;
+mod server;
 pub mod state;
 
 pub use error::Error;
-pub use state::State;
+pub use server::{AuthServer, AuthServerHandler};
+pub use state::{ActorState, State};
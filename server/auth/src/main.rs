@@ -5,25 +5,10 @@
 //! will be transferred to the message server of their choice.
 
 use std::env;
-use tq_network::{PacketHandler, Server, TQCipher};
+use tq_network::Server;
 
-use auth::packets::{MsgAccount, MsgConnect};
-use auth::{Error, State};
-
-struct AuthServer;
-
-impl Server for AuthServer {
-    type ActorState = ();
-    type Cipher = TQCipher;
-    type PacketHandler = AuthServerHandler;
-}
-
-#[derive(Debug, PacketHandler)]
-#[handle(state = State, actor_state = ())]
-pub enum AuthServerHandler {
-    MsgAccount,
-    MsgConnect,
-}
+use auth::state::FAILED_LOGINS_WINDOW;
+use auth::{AuthServer, Error, State};
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
@@ -50,7 +35,7 @@ Copyright 2020-2022 Shady Khalifa (@shekohex)
     tracing::info!("Initializing State ..");
     let static_state = {
         let state = State::init().await?;
-        Box::leak(Box::new(state)) as *mut _
+        Box::leak(Box::new(state)) as *mut State
     };
     tracing::info!("Initializing server...");
     let auth_port = env::var("AUTH_PORT")?;
@@ -58,6 +43,13 @@ Copyright 2020-2022 Shady Khalifa (@shekohex)
     // SAFETY: We are the only owner of this Box, and we are deref
     // it. This happens only once, so no one else can access.
     let state = unsafe { &*static_state };
+    tokio::spawn(async move {
+        let mut sweep = tokio::time::interval(FAILED_LOGINS_WINDOW);
+        loop {
+            sweep.tick().await;
+            state.failed_logins().sweep();
+        }
+    });
     AuthServer::run(format!("0.0.0.0:{}", auth_port), state).await?;
     unsafe {
         // SAFETY: We are the only owner of this Box, and we are dropping
@@ -8,4 +8,7 @@ mod msg_connect;
 pub use msg_connect::MsgConnect;
 
 mod msg_transfer;
-pub use msg_transfer::MsgTransfer;
+pub use msg_transfer::{MsgTransfer, TRANSFER_VERSION};
+
+mod msg_register;
+pub use msg_register::MsgRegister;
@@ -1,14 +1,14 @@
 use super::{MsgConnectEx, MsgTransfer};
 use crate::packets::RejectionCode;
-use crate::state::State;
-use crate::Error;
+use crate::state::{LoginInfo, State};
+use crate::{ActorState, Error};
 use async_trait::async_trait;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tq_db::account::Account;
 use tq_network::{Actor, PacketID, PacketProcess};
 use tq_serde::{String16, TQPassword};
 
-#[derive(Debug, Deserialize, PacketID)]
+#[derive(Debug, Serialize, Deserialize, PacketID)]
 #[packet(id = 1051)]
 pub struct MsgAccount {
     pub username: String16,
@@ -22,7 +22,7 @@ pub struct MsgAccount {
 
 #[async_trait]
 impl PacketProcess for MsgAccount {
-    type ActorState = ();
+    type ActorState = ActorState;
     type Error = Error;
     type State = State;
 
@@ -32,6 +32,16 @@ impl PacketProcess for MsgAccount {
         actor: &Actor<Self::ActorState>,
     ) -> Result<(), Self::Error> {
         let pool = state.pool();
+        if state.failed_logins().is_locked(&self.username) {
+            tracing::warn!(
+                username = %self.username,
+                "Too many failed login attempts"
+            );
+            actor
+                .send(RejectionCode::AccountMaxLoginAttempts.packet())
+                .await?;
+            return Ok(());
+        }
         let maybe_accont =
             Account::auth(pool, &self.username, &self.password).await;
         let account = match maybe_accont {
@@ -40,6 +50,7 @@ impl PacketProcess for MsgAccount {
                 let res = match e {
                     tq_db::Error::InvalidPassword
                     | tq_db::Error::AccountNotFound => {
+                        state.failed_logins().record(&self.username);
                         RejectionCode::InvalidPassword.packet()
                     },
                     _ => {
@@ -51,14 +62,28 @@ impl PacketProcess for MsgAccount {
                 return Ok(());
             },
         };
+        state.failed_logins().clear(&self.username);
         actor.set_id(account.account_id as usize);
-        let res = match MsgTransfer::handle(state, actor, &self.realm).await {
+        actor.set_login(LoginInfo {
+            account_id: account.account_id as u32,
+            username: self.username.to_string(),
+            realm: self.realm.to_string(),
+        });
+        let res = match MsgTransfer::handle(state, actor, &account, &self.realm)
+            .await
+        {
             Ok(res) => res,
-            _ => {
+            Err(e) => {
                 tracing::warn!(
                     account_id = account.account_id,
+                    error = %e,
                     "Failed to transfer account"
                 );
+                // Rejections, like an unknown realm, are for the client to
+                // show, it would wait for an answer forever otherwise.
+                if let Error::Msg(..) = e {
+                    actor.send(e).await?;
+                }
                 return Ok(());
             },
         };
@@ -1,5 +1,5 @@
 use crate::state::State;
-use crate::Error;
+use crate::{ActorState, Error};
 use serde::Deserialize;
 use tq_network::{Actor, PacketID, PacketProcess};
 use tq_serde::String16;
@@ -17,7 +17,7 @@ pub struct MsgConnect {
 
 #[async_trait::async_trait]
 impl PacketProcess for MsgConnect {
-    type ActorState = ();
+    type ActorState = ActorState;
     type Error = Error;
     type State = State;
 
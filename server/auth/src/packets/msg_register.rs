@@ -0,0 +1,100 @@
+use crate::state::State;
+use crate::{ActorState, Error};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sqlx::error::ErrorKind;
+use tq_db::account::Account;
+use tq_network::{Actor, PacketID, PacketProcess};
+use tq_serde::{String16, TQPassword};
+
+/// Creates an account with the given username and password. The server
+/// answers with the same packet, carrying the id of the new account, or zero
+/// if the account could not be created.
+///
+/// Registration is closed unless `ALLOW_REGISTRATION` is set, see
+/// [`State::registration_open`].
+#[derive(Debug, Default, Serialize, Deserialize, PacketID)]
+#[packet(id = 1060)]
+pub struct MsgRegister {
+    pub username: String16,
+    pub password: TQPassword,
+    pub account_id: u32,
+}
+
+impl MsgRegister {
+    pub fn new(username: &str, password: &str) -> Self {
+        Self {
+            username: username.into(),
+            password: password.into(),
+            account_id: 0,
+        }
+    }
+
+    /// Whether the username could be used for a new account.
+    fn is_valid_username(&self) -> bool {
+        !self.username.is_empty()
+            && self.username.chars().all(|c| c.is_ascii_alphanumeric())
+    }
+
+    /// Creates the account, `None` if the username is taken.
+    async fn create_account(
+        &self,
+        state: &State,
+    ) -> Result<Option<Account>, Error> {
+        let account = Account {
+            username: self.username.to_string(),
+            password: self.password.to_string(),
+            ..Default::default()
+        };
+        match account.create(state.pool()).await {
+            Ok(account) => Ok(Some(account)),
+            Err(tq_db::Error::Db(sqlx::Error::Database(e)))
+                if e.kind() == ErrorKind::UniqueViolation =>
+            {
+                Ok(None)
+            },
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl PacketProcess for MsgRegister {
+    type ActorState = ActorState;
+    type Error = Error;
+    type State = State;
+
+    async fn process(
+        &self,
+        state: &Self::State,
+        actor: &Actor<Self::ActorState>,
+    ) -> Result<(), Self::Error> {
+        let account = if !state.registration_open() {
+            tracing::warn!(username = %self.username, "Registration is closed");
+            None
+        } else if !self.is_valid_username() {
+            tracing::warn!(username = %self.username, "Invalid username");
+            None
+        } else {
+            self.create_account(state).await?
+        };
+        let account_id = match account {
+            Some(account) => {
+                tracing::info!(
+                    account_id = account.account_id,
+                    username = %self.username,
+                    "Account registered"
+                );
+                account.account_id as u32
+            },
+            None => 0,
+        };
+        let res = MsgRegister {
+            username: self.username.clone(),
+            account_id,
+            ..Default::default()
+        };
+        actor.send(res).await?;
+        Ok(())
+    }
+}
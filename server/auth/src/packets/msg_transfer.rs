@@ -1,12 +1,14 @@
 use super::{AccountCredentials, RejectionCode};
-use crate::Error;
-use serde::{Deserialize, Serialize};
+use crate::{ActorState, Error};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
 use tokio::net::TcpStream;
 use tokio_stream::StreamExt;
+use tq_db::account::Account;
 use tq_db::realm::Realm;
 use tq_network::{
     Actor, CQCipher, IntoErrorPacket, PacketDecode, PacketEncode, PacketID,
-    TQCodec,
+    Seal, TQCodec,
 };
 use tracing::Instrument;
 
@@ -20,16 +22,47 @@ pub struct MsgTransfer {
     pub realm_id: u32,
     #[serde(skip_serializing)]
     pub token: u64,
+    #[serde(deserialize_with = "transfer_version")]
+    pub version: u16,
+    pub gm_level: u8,
+    pub privileges: u32,
+    /// The secret shared with the game server, see
+    /// [`State::transfer_secret`](crate::State::transfer_secret).
+    pub secret: u64,
+}
+
+/// The version of [`MsgTransfer`] we send, the game server must speak the
+/// same one.
+pub const TRANSFER_VERSION: u16 = 3;
+
+/// Older game servers answer without a version, so reading it fails before we
+/// get to check it.
+fn transfer_version<'de, D>(deserializer: D) -> Result<u16, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let version = u16::deserialize(deserializer).map_err(|_| {
+        D::Error::custom(format!(
+            "MsgTransfer has no version, the game server is older than v{TRANSFER_VERSION}"
+        ))
+    })?;
+    if version != TRANSFER_VERSION {
+        return Err(D::Error::custom(format!(
+            "MsgTransfer v{version} is not supported, expected v{TRANSFER_VERSION}"
+        )));
+    }
+    Ok(version)
 }
 
 impl MsgTransfer {
-    #[tracing::instrument(skip(state, actor))]
+    #[tracing::instrument(skip(state, actor, account), fields(account_id = account.account_id))]
     pub async fn handle(
         state: &crate::State,
-        actor: &Actor<()>,
+        actor: &Actor<ActorState>,
+        account: &Account,
         realm: &str,
     ) -> Result<AccountCredentials, Error> {
-        let maybe_realm = Realm::by_name(state.pool(), realm).await?;
+        let maybe_realm = state.realm(realm).await?;
         // Check if there is a realm with that name
         let realm = match maybe_realm {
             Some(realm) => realm,
@@ -56,25 +89,40 @@ impl MsgTransfer {
                     error = ?e,
                     "Failed to connect to realm"
                 );
-                actor.send(RejectionCode::ServerDown.packet()).await?;
-                actor.shutdown().await?;
+                state.forget_realm(&realm.name);
+                actor
+                    .shutdown_with(RejectionCode::ServerDown.packet())
+                    .await?;
                 return Err(e.into());
             },
         };
-        Self::transfer(actor, realm, stream).await
+        let name = realm.name.clone();
+        let res =
+            Self::transfer(account, realm, stream, state.transfer_secret())
+                .await;
+        if res.is_err() {
+            state.forget_realm(&name);
+        }
+        res
     }
 
-    #[tracing::instrument(skip(actor, stream), err, fields(realm = realm.name))]
+    #[tracing::instrument(skip(account, stream), err, fields(realm = realm.name))]
     async fn transfer(
-        actor: &Actor<()>,
+        account: &Account,
         realm: Realm,
         stream: TcpStream,
+        secret: u64,
     ) -> Result<AccountCredentials, Error> {
         let cipher = CQCipher::new();
-        let (mut encoder, mut decoder) = TQCodec::new(stream, cipher).split();
+        let (mut encoder, mut decoder) =
+            TQCodec::new(stream, cipher, Seal::None).split();
         let transfer = MsgTransfer {
-            account_id: actor.id() as u32,
+            account_id: account.account_id as u32,
             realm_id: realm.realm_id as u32,
+            version: TRANSFER_VERSION,
+            gm_level: account.gm_level,
+            privileges: account.privileges,
+            secret,
             ..Default::default()
         };
 
@@ -0,0 +1,196 @@
+//! The account server itself, what it listens for and how it cleans up after
+//! a client.
+
+use crate::packets::{MsgAccount, MsgConnect, MsgRegister};
+use crate::{ActorState, State};
+use async_trait::async_trait;
+use tq_network::{
+    Actor, ActorState as _, PacketHandler, Seal, Server, TQCipher,
+};
+
+pub struct AuthServer;
+
+#[async_trait]
+impl Server for AuthServer {
+    type ActorState = ActorState;
+    type Cipher = TQCipher;
+    type PacketHandler = AuthServerHandler;
+
+    const SEAL: Seal = Seal::None;
+
+    /// Get Called right before ending the connection with that client.
+    #[tracing::instrument(skip(_state, actor))]
+    async fn on_disconnected(
+        _state: &<Self::PacketHandler as PacketHandler>::State,
+        actor: Actor<Self::ActorState>,
+    ) -> Result<(), tq_network::Error> {
+        if let Some(login) = actor.take_login() {
+            tracing::debug!(
+                account_id = login.account_id,
+                realm = %login.realm,
+                "Login session ended"
+            );
+        }
+        ActorState::dispose(&actor, actor.handle()).await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, PacketHandler)]
+#[handle(state = State, actor_state = ActorState)]
+pub enum AuthServerHandler {
+    MsgAccount,
+    MsgConnect,
+    MsgRegister,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::MsgTransfer;
+    use bytes::Bytes;
+    use serde::Deserialize;
+    use sqlx::sqlite::SqlitePoolOptions;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_stream::StreamExt;
+    use tq_network::{CQCipher, PacketDecode, PacketEncode, PacketID, TQCodec};
+
+    /// The token the fake game server hands out.
+    const TOKEN: u64 = 0xC0FFEE;
+    /// The secret the fake game server expects.
+    const SECRET: u64 = 0x5EC2E7;
+
+    /// What the client reads back after a login, an accepted login and a
+    /// rejection are the same packet id. A rejection has a zero where the
+    /// token starts and the code right after it.
+    #[derive(Debug, Deserialize, PacketID)]
+    #[packet(id = 1055)]
+    struct LoginAnswer {
+        id: u32,
+        code: u32,
+    }
+
+    impl LoginAnswer {
+        fn token(&self) -> u64 { self.id as u64 | (self.code as u64) << 32 }
+    }
+
+    /// A game server that answers every transfer carrying [`SECRET`] with
+    /// [`TOKEN`].
+    async fn fake_game_server() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (mut encoder, mut decoder) =
+                    TQCodec::new(stream, TQCipher::new(), Seal::None).split();
+                let Some(Ok((_, bytes))) = decoder.next().await else {
+                    continue;
+                };
+                // The secret is the last thing in the transfer.
+                let secret = bytes[bytes.len() - 8..].try_into().unwrap();
+                if u64::from_le_bytes(secret) != SECRET {
+                    continue;
+                }
+                // The token is not sent to us, it goes right after the
+                // account and realm ids.
+                let mut answer = bytes.to_vec();
+                answer.splice(8..8, TOKEN.to_le_bytes());
+                let answer = (MsgTransfer::PACKET_ID, Bytes::from(answer));
+                encoder.send(answer).await.unwrap();
+            }
+        });
+        port
+    }
+
+    /// Runs the account server on a free port, and returns that port.
+    async fn run_auth_server(state: &'static State) -> u16 {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        tokio::spawn(AuthServer::run(format!("127.0.0.1:{port}"), state));
+        port
+    }
+
+    /// A headless client: connects, sends the packet and reads the answer.
+    async fn ask<P: PacketEncode, A: PacketDecode<Packet = A>>(
+        port: u16,
+        packet: P,
+    ) -> A {
+        let stream = loop {
+            match TcpStream::connect(("127.0.0.1", port)).await {
+                Ok(stream) => break stream,
+                // Not listening yet.
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        let (mut encoder, mut decoder) =
+            TQCodec::new(stream, CQCipher::new(), Seal::None).split();
+        encoder.send(packet.encode().ok().unwrap()).await.unwrap();
+        let (_, bytes) = decoder.next().await.unwrap().unwrap();
+        A::decode(&bytes).ok().unwrap()
+    }
+
+    fn login(username: &str, password: &str) -> MsgAccount {
+        MsgAccount {
+            username: username.into(),
+            password: password.into(),
+            realm: "CoEmu".into(),
+            rejection_code: 0,
+            account_id: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn headless_client_registers_and_logs_in() {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::migrate!("../../migrations").run(&pool).await.unwrap();
+        // Nothing listens there, the realm is down at first.
+        let down = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        sqlx::query(
+            "UPDATE realms SET game_ip_address = '127.0.0.1', game_port = ? WHERE name = 'CoEmu';",
+        )
+        .bind(down)
+        .execute(&pool)
+        .await
+        .unwrap();
+        let state = State::with_pool(pool.clone())
+            .with_registration(true)
+            .with_transfer_secret(SECRET);
+        let state: &'static State = Box::leak(Box::new(state));
+        let port = run_auth_server(state).await;
+
+        let register = MsgRegister::new("alice", "secret");
+        let registered: MsgRegister = ask(port, register).await;
+        assert_ne!(registered.account_id, 0);
+        // The username is taken now.
+        let again: MsgRegister =
+            ask(port, MsgRegister::new("alice", "other")).await;
+        assert_eq!(again.account_id, 0);
+
+        let wrong: LoginAnswer = ask(port, login("alice", "wrong")).await;
+        assert_eq!((wrong.id, wrong.code), (0, 1));
+        let down: LoginAnswer = ask(port, login("alice", "secret")).await;
+        assert_eq!((down.id, down.code), (0, 10));
+
+        // The realm moved, it is looked up again.
+        let game_port = fake_game_server().await;
+        sqlx::query("UPDATE realms SET game_port = ? WHERE name = 'CoEmu';")
+            .bind(game_port)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let accepted: LoginAnswer = ask(port, login("alice", "secret")).await;
+        assert_eq!(accepted.token(), TOKEN);
+    }
+}
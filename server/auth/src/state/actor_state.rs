@@ -0,0 +1,40 @@
+use std::sync::Mutex;
+
+/// Information about a login that is currently in progress on a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoginInfo {
+    pub account_id: u32,
+    pub username: String,
+    pub realm: String,
+}
+
+#[derive(Debug)]
+pub struct ActorState {
+    login: Mutex<Option<LoginInfo>>,
+}
+
+#[async_trait::async_trait]
+impl tq_network::ActorState for ActorState {
+    fn init() -> Self {
+        ActorState {
+            login: Mutex::new(None),
+        }
+    }
+}
+
+impl ActorState {
+    /// Stores the login information after the account got authenticated.
+    pub fn set_login(&self, info: LoginInfo) {
+        *self.login.lock().expect("login lock poisoned") = Some(info);
+    }
+
+    /// Returns the in-progress login, if any.
+    pub fn login(&self) -> Option<LoginInfo> {
+        self.login.lock().expect("login lock poisoned").clone()
+    }
+
+    /// Takes the in-progress login out, leaving nothing behind.
+    pub fn take_login(&self) -> Option<LoginInfo> {
+        self.login.lock().expect("login lock poisoned").take()
+    }
+}
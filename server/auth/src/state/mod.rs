@@ -0,0 +1,303 @@
+use crate::Error;
+use sqlx::sqlite::{SqlitePool, SqlitePoolOptions};
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tq_db::realm::Realm;
+
+mod actor_state;
+pub use actor_state::{ActorState, LoginInfo};
+
+/// How many failed logins are allowed in [`FAILED_LOGINS_WINDOW`] before the
+/// account gets locked.
+pub const MAX_FAILED_LOGINS: u32 = 5;
+/// The window in which failed logins are counted.
+pub const FAILED_LOGINS_WINDOW: Duration = Duration::from_secs(5 * 60);
+/// How many usernames the failed logins are tracked for at most, anyone
+/// could send logins for made up usernames.
+pub const MAX_TRACKED_USERNAMES: usize = 10_000;
+
+type Realms = RwLock<HashMap<String, Realm>>;
+
+#[derive(Debug)]
+pub struct State {
+    pool: SqlitePool,
+    /// Realms we already looked up, keyed by name. A realm is forgotten once
+    /// connecting to it fails, so it is read again if it moved.
+    realms: Realms,
+    failed_logins: FailedLogins,
+    /// Whether anyone could create an account, see
+    /// [`MsgRegister`](crate::packets::MsgRegister).
+    registration_open: bool,
+    /// Sent with every [`MsgTransfer`](crate::packets::MsgTransfer), the
+    /// game servers turn away the ones without it.
+    transfer_secret: u64,
+}
+
+impl State {
+    /// Init The State.
+    /// Should only get called once.
+    pub async fn init() -> Result<Self, Error> {
+        let data_dir = dotenvy::var("DATA_LOCATION")?;
+        let default_db_location =
+            format!("sqlite://{data_dir}/coemu.db?mode=rwc");
+        let db_url =
+            dotenvy::var("DATABASE_URL").unwrap_or(default_db_location);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(42)
+            .min_connections(4)
+            .connect(&db_url)
+            .await?;
+        let registration_open = dotenvy::var("ALLOW_REGISTRATION")
+            .map(|v| v.parse().unwrap_or(false))
+            .unwrap_or(false);
+        let transfer_secret = dotenvy::var("TRANSFER_SECRET")?
+            .trim()
+            .parse()
+            .map_err(|_| {
+            Error::State("TRANSFER_SECRET must be a number")
+        })?;
+        Ok(Self::with_pool(pool)
+            .with_registration(registration_open)
+            .with_transfer_secret(transfer_secret))
+    }
+
+    pub fn with_pool(pool: SqlitePool) -> Self {
+        Self {
+            pool,
+            realms: Default::default(),
+            failed_logins: FailedLogins::new(
+                MAX_FAILED_LOGINS,
+                FAILED_LOGINS_WINDOW,
+            ),
+            registration_open: false,
+            transfer_secret: 0,
+        }
+    }
+
+    /// Opens or closes the registration of new accounts.
+    pub fn with_registration(mut self, open: bool) -> Self {
+        self.registration_open = open;
+        self
+    }
+
+    /// Sets the secret shared with the game servers.
+    pub fn with_transfer_secret(mut self, secret: u64) -> Self {
+        self.transfer_secret = secret;
+        self
+    }
+
+    /// Get access to the database pool
+    pub fn pool(&self) -> &SqlitePool { &self.pool }
+
+    /// Get access to the failed logins tracker.
+    pub fn failed_logins(&self) -> &FailedLogins { &self.failed_logins }
+
+    /// Whether new accounts could be registered.
+    pub fn registration_open(&self) -> bool { self.registration_open }
+
+    pub fn transfer_secret(&self) -> u64 { self.transfer_secret }
+
+    /// Looks up a realm by its name, hitting the database only if we did not
+    /// see that realm before.
+    pub async fn realm(&self, name: &str) -> Result<Option<Realm>, Error> {
+        let cached = self
+            .realms
+            .read()
+            .expect("realms lock poisoned")
+            .get(name)
+            .cloned();
+        if cached.is_some() {
+            return Ok(cached);
+        }
+        let realm = Realm::by_name(self.pool(), name).await?;
+        if let Some(ref realm) = realm {
+            self.realms
+                .write()
+                .expect("realms lock poisoned")
+                .insert(name.to_owned(), realm.clone());
+        }
+        Ok(realm)
+    }
+
+    /// Forgets the realm we looked up with that name, the next login reads
+    /// it from the database again.
+    pub fn forget_realm(&self, name: &str) {
+        self.realms
+            .write()
+            .expect("realms lock poisoned")
+            .remove(name);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FailedLogin {
+    count: u32,
+    first_at: Instant,
+}
+
+/// Tracks failed login attempts per username, an account gets locked once it
+/// reaches the maximum number of failures in the tracking window.
+///
+/// Failures older than the window are dropped by [`FailedLogins::sweep`]. At
+/// most [`MAX_TRACKED_USERNAMES`] usernames are tracked, once there are that
+/// many a new one is not tracked until a sweep makes room, so flooding us
+/// with made up usernames could never unlock an account.
+#[derive(Debug)]
+pub struct FailedLogins {
+    max_attempts: u32,
+    window: Duration,
+    capacity: usize,
+    attempts: Mutex<HashMap<String, FailedLogin>>,
+}
+
+impl FailedLogins {
+    pub fn new(max_attempts: u32, window: Duration) -> Self {
+        Self {
+            max_attempts,
+            window,
+            capacity: MAX_TRACKED_USERNAMES,
+            attempts: Default::default(),
+        }
+    }
+
+    /// Sets how many usernames are tracked at most.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Records a failed login, returns the number of failures in the current
+    /// window.
+    pub fn record(&self, username: &str) -> u32 {
+        self.record_at(username, Instant::now())
+    }
+
+    /// Returns `true` if this username reached the maximum failed attempts.
+    pub fn is_locked(&self, username: &str) -> bool {
+        self.is_locked_at(username, Instant::now())
+    }
+
+    /// Forgets about the failures of this username, usually after a
+    /// successful login.
+    pub fn clear(&self, username: &str) {
+        self.attempts
+            .lock()
+            .expect("failed logins lock poisoned")
+            .remove(username);
+    }
+
+    /// Drops the failures older than the window.
+    pub fn sweep(&self) { self.sweep_at(Instant::now()) }
+
+    fn record_at(&self, username: &str, now: Instant) -> u32 {
+        let mut attempts =
+            self.attempts.lock().expect("failed logins lock poisoned");
+        if let Some(failed) = attempts.get_mut(username) {
+            if self.expired(failed, now) {
+                *failed = FailedLogin {
+                    count: 0,
+                    first_at: now,
+                };
+            }
+            failed.count += 1;
+            return failed.count;
+        }
+        if attempts.len() >= self.capacity {
+            tracing::warn!(
+                %username,
+                "Too many usernames with failed logins, not tracked"
+            );
+            return 0;
+        }
+        attempts.insert(
+            username.to_owned(),
+            FailedLogin {
+                count: 1,
+                first_at: now,
+            },
+        );
+        1
+    }
+
+    fn sweep_at(&self, now: Instant) {
+        self.attempts
+            .lock()
+            .expect("failed logins lock poisoned")
+            .retain(|_, f| !self.expired(f, now));
+    }
+
+    fn is_locked_at(&self, username: &str, now: Instant) -> bool {
+        let mut attempts =
+            self.attempts.lock().expect("failed logins lock poisoned");
+        match attempts.get(username) {
+            Some(f) if self.expired(f, now) => {
+                attempts.remove(username);
+                false
+            },
+            Some(f) => f.count >= self.max_attempts,
+            None => false,
+        }
+    }
+
+    fn expired(&self, failed: &FailedLogin, now: Instant) -> bool {
+        now.duration_since(failed.first_at) > self.window
+    }
+
+    #[cfg(test)]
+    fn tracked(&self) -> usize {
+        self.attempts
+            .lock()
+            .expect("failed logins lock poisoned")
+            .len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_logins_lock_after_max_attempts() {
+        let tracker = FailedLogins::new(3, Duration::from_secs(60));
+        let now = Instant::now();
+        assert_eq!(tracker.record_at("test", now), 1);
+        assert_eq!(tracker.record_at("test", now), 2);
+        assert!(!tracker.is_locked_at("test", now));
+        assert_eq!(tracker.record_at("test", now), 3);
+        assert!(tracker.is_locked_at("test", now));
+        assert!(!tracker.is_locked_at("other", now));
+        // The lock expires with the window.
+        let later = now + Duration::from_secs(61);
+        assert!(!tracker.is_locked_at("test", later));
+        assert_eq!(tracker.record_at("test", later), 1);
+        tracker.clear("test");
+        assert!(!tracker.is_locked_at("test", later));
+    }
+
+    #[test]
+    fn failed_logins_do_not_pile_up() {
+        let tracker =
+            FailedLogins::new(3, Duration::from_secs(60)).with_capacity(2);
+        let now = Instant::now();
+        let at = |secs| now + Duration::from_secs(secs);
+        for _ in 0..3 {
+            tracker.record_at("victim", at(0));
+        }
+        tracker.record_at("a", at(1));
+        assert!(tracker.is_locked_at("victim", at(2)));
+        // Full, made up usernames could not push the victim out.
+        for name in ["b", "c", "d"] {
+            assert_eq!(tracker.record_at(name, at(2)), 0);
+        }
+        assert_eq!(tracker.tracked(), 2);
+        assert!(tracker.is_locked_at("victim", at(3)));
+        assert_eq!(tracker.record_at("a", at(3)), 2);
+        // Expired ones are dropped by the sweep, making room again.
+        tracker.sweep_at(at(30));
+        assert_eq!(tracker.tracked(), 2);
+        tracker.sweep_at(at(61));
+        assert_eq!(tracker.tracked(), 1);
+        assert_eq!(tracker.record_at("b", at(61)), 1);
+    }
+}
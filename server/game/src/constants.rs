@@ -6,6 +6,8 @@ pub const ANSWER_OK: &str = "ANSWER_OK";
 pub const NEW_ROLE: &str = "NEW_ROLE";
 
 pub const MAX_TXT_LEN: usize = 250;
+/// The longest name the client could show.
+pub const MAX_NAME_LEN: usize = 15;
 
 pub const HAIR_STYLES: [i16; 12] =
     [10, 11, 13, 14, 15, 24, 30, 35, 37, 38, 39, 40];
@@ -13,6 +15,20 @@ pub const HAIR_STYLES: [i16; 12] =
 pub const WALK_XCOORDS: [i8; 8] = [0, -1, -1, -1, 0, 1, 1, 1];
 pub const WALK_YCOORDS: [i8; 8] = [1, 1, 0, -1, -1, -1, 0, 1];
 
+/// How many items could be kept in the inventory.
+pub const INVENTORY_SIZE: usize = 40;
+
+/// The most silver a character could have, the client shows it as a 32 bit
+/// number.
+pub const MAX_SILVER: u64 = u32::MAX as u64;
+
+/// Where new characters start, and where the ones whose map is gone end up.
+pub const NEWBIE_MAP_ID: u32 = 1010;
+pub const NEWBIE_LOCATION: (u16, u16) = (61, 109);
+
+pub const METEOR: u32 = 1088001;
+pub const METEOR_TEAR: u32 = 1088002;
+
 pub const NPC_ID_MIN: u32 = 1;
 pub const DYN_NPC_ID_MIN: u32 = 100001;
 pub const DYN_NPC_ID_MAX: u32 = 199999;
@@ -23,6 +39,8 @@ pub const PET_ID_MAX: u32 = 599999;
 pub const NPC_ID_MAX: u32 = 700000;
 pub const CALL_PET_ID_MIN: u32 = 700001;
 pub const CALL_PET_ID_MAX: u32 = 799999;
+pub const FLOOR_ITEM_ID_MIN: u32 = 800001;
+pub const FLOOR_ITEM_ID_MAX: u32 = 999999;
 pub const CHARACTER_ID_MIN: u32 = 1000000;
 pub const CHARACTER_ID_MAX: u32 = 10000000;
 
@@ -42,6 +60,71 @@ pub const fn is_call_pet(id: u32) -> bool {
     id >= CALL_PET_ID_MIN && id <= CALL_PET_ID_MAX
 }
 
+pub const fn is_floor_item(id: u32) -> bool {
+    id >= FLOOR_ITEM_ID_MIN && id <= FLOOR_ITEM_ID_MAX
+}
+
 pub const fn is_character(id: u32) -> bool {
     id >= CHARACTER_ID_MIN && id <= CHARACTER_ID_MAX
 }
+
+/// Attributes (strength, agility, vitality, spirit) a Trojan starts with.
+pub const TROJAN_BASE_ATTRIBUTES: [u16; 4] = [4, 6, 12, 0];
+/// Attributes (strength, agility, vitality, spirit) a Warrior starts with.
+pub const WARRIOR_BASE_ATTRIBUTES: [u16; 4] = [4, 6, 12, 0];
+/// Attributes (strength, agility, vitality, spirit) an Archer starts with.
+pub const ARCHER_BASE_ATTRIBUTES: [u16; 4] = [4, 6, 12, 0];
+/// Attributes (strength, agility, vitality, spirit) a Taoist starts with.
+pub const TAOIST_BASE_ATTRIBUTES: [u16; 4] = [2, 6, 12, 10];
+
+/// How many attribute points a character earns for every level.
+pub const ATTRIBUTE_POINTS_PER_LEVEL: u16 = 3;
+
+/// Returns the level 1 attributes of the given class profession, the
+/// profession could be any promotion of the base class (e.g. 15 for a
+/// Trojan Master).
+pub const fn base_attributes(class: u8) -> [u16; 4] {
+    match class {
+        20..=29 => WARRIOR_BASE_ATTRIBUTES,
+        40..=49 => ARCHER_BASE_ATTRIBUTES,
+        100..=199 => TAOIST_BASE_ATTRIBUTES,
+        _ => TROJAN_BASE_ATTRIBUTES,
+    }
+}
+
+/// Returns the total attribute points a character of that class should have
+/// distributed at the given level.
+pub const fn base_attribute_points(class: u8, level: u16) -> u16 {
+    let [strength, agility, vitality, spirit] = base_attributes(class);
+    let per_level = level.saturating_sub(1) * ATTRIBUTE_POINTS_PER_LEVEL;
+    strength + agility + vitality + spirit + per_level
+}
+
+/// The highest level a character could reach.
+pub const MAX_LEVEL: u16 = 130;
+
+/// The experience a character at the given level needs to level up, or
+/// `None` at the max level.
+///
+/// This is an approximation of the client's curve, until the level table
+/// gets loaded from the client data.
+pub const fn level_up_experience(level: u16) -> Option<u64> {
+    if level >= MAX_LEVEL {
+        return None;
+    }
+    let level = level as u64;
+    Some(level * level * level * 20 + 100)
+}
+
+/// Maximum health points for the given attributes.
+pub const fn max_health_points(
+    strength: u16,
+    agility: u16,
+    vitality: u16,
+    spirit: u16,
+) -> u16 {
+    (strength * 3) + (agility * 3) + (spirit * 3) + (vitality * 24)
+}
+
+/// Maximum mana points for the given spirit.
+pub const fn max_mana_points(spirit: u16) -> u16 { spirit * 5 }
@@ -1,9 +1,13 @@
 use std::sync::atomic::{AtomicU16, AtomicU32, AtomicU64};
+use std::sync::Arc;
 
+use arc_swap::ArcSwap;
 use atomic::{Atomic, Ordering};
+use bytemuck::NoUninit;
 use primitives::{Gauge, Location};
 
 use crate::constants;
+use crate::entities::CharacterRecord;
 
 bitflags::bitflags! {
   /// These values can be found in `statuseffect.ini` in the `ini` folder of the client.
@@ -43,6 +47,19 @@ bitflags::bitflags! {
   }
 }
 
+/// Where an entity is and where it was right before its latest move, kept
+/// together so they are always read and updated at once.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Locations {
+    pub current: Location,
+    pub prev: Location,
+}
+
+// SAFETY: two `Location`s, 8 bytes each and aligned to 8, so there is no
+// padding in between.
+unsafe impl NoUninit for Locations {}
+
 /// A More Advanced Entity Used to be composed with Other Entites Like Player or
 /// Monster.
 #[derive(Debug)]
@@ -53,11 +70,12 @@ pub struct Entity {
     /// How that entity looks like?
     mesh: AtomicU32,
     /// Could be player name, Monster name .. or anything.
-    name: String,
+    name: ArcSwap<String>,
     /// The Current MapID of that entity.
     map_id: AtomicU32,
-    /// Current Location (X, Y, Direction)
-    location: Atomic<Location>,
+    /// Current Location (X, Y, Direction) and the Old one .. used in
+    /// calculations with the new location.
+    locations: Atomic<Locations>,
 
     // *** Advanced Entity Props ***
     /// Set of flags shows the current entity status.
@@ -68,13 +86,37 @@ pub struct Entity {
     action: AtomicU16,
     /// Old MapID
     prev_map_id: AtomicU32,
-    /// The Old Location .. used in calculations with the new location.
-    prev_location: Atomic<Location>,
     /// Health Points
     hp: Atomic<Gauge>,
 }
 
 impl Entity {
+    /// Creates a new entity that lives only in the game world, like a floor
+    /// item or a monster.
+    pub fn new(
+        id: u32,
+        name: String,
+        mesh: u32,
+        map_id: u32,
+        location: Location,
+    ) -> Self {
+        Self {
+            id,
+            mesh: AtomicU32::new(mesh),
+            name: ArcSwap::from_pointee(name),
+            map_id: AtomicU32::new(map_id),
+            locations: Atomic::new(Locations {
+                current: location,
+                prev: location,
+            }),
+            flags: AtomicU64::new(Flags::NONE.bits()),
+            level: AtomicU16::new(0),
+            action: AtomicU16::new(100),
+            prev_map_id: AtomicU32::new(map_id),
+            hp: Atomic::new(Gauge::default()),
+        }
+    }
+
     pub fn id(&self) -> u32 { self.id }
 
     pub fn is_character(&self) -> bool { constants::is_character(self.id) }
@@ -89,7 +131,17 @@ impl Entity {
 
     pub fn is_terrain_npc(&self) -> bool { constants::is_terrain_npc(self.id) }
 
-    pub fn name(&self) -> &str { &self.name }
+    pub fn is_floor_item(&self) -> bool { constants::is_floor_item(self.id) }
+
+    pub fn name(&self) -> Arc<String> { self.name.load_full() }
+
+    /// Renames the entity, see [`State::rename_character`] for characters.
+    ///
+    /// [`State::rename_character`]: crate::State::rename_character
+    pub fn set_name(&self, name: String) -> &Self {
+        self.name.store(Arc::new(name));
+        self
+    }
 
     pub fn flags(&self) -> Flags {
         Flags::from_bits(self.flags.load(Ordering::Relaxed))
@@ -117,12 +169,26 @@ impl Entity {
         self
     }
 
-    pub fn location(&self) -> Location { self.location.load(Ordering::Relaxed) }
+    pub fn location(&self) -> Location { self.locations().current }
 
+    /// The current and previous locations, read at once.
+    pub fn locations(&self) -> Locations {
+        self.locations.load(Ordering::Acquire)
+    }
+
+    /// Moves the entity to `value`, the location it leaves becomes the
+    /// previous one in the same swap.
     pub fn set_location(&self, value: Location) -> &Self {
-        let prev_location = self.location();
-        self.prev_location.store(prev_location, Ordering::Relaxed);
-        self.location.store(value, Ordering::Relaxed);
+        let _ = self.locations.fetch_update(
+            Ordering::AcqRel,
+            Ordering::Acquire,
+            |locations| {
+                Some(Locations {
+                    current: value,
+                    prev: locations.current,
+                })
+            },
+        );
         self
     }
 
@@ -144,19 +210,22 @@ impl Entity {
         self.prev_map_id.load(Ordering::Relaxed)
     }
 
-    pub fn prev_location(&self) -> Location {
-        self.prev_location.load(Ordering::Relaxed)
-    }
+    pub fn prev_location(&self) -> Location { self.locations().prev }
 
     pub fn hp(&self) -> Gauge { self.hp.load(Ordering::Relaxed) }
 
+    pub fn set_hp(&self, value: Gauge) -> &Self {
+        self.hp.store(value, Ordering::Relaxed);
+        self
+    }
+
     pub fn is_alive(&self) -> bool { !self.flags().contains(Flags::DEAD) }
 
     pub fn is_dead(&self) -> bool { self.flags().contains(Flags::DEAD) }
 }
 
-impl From<&tq_db::character::Character> for Entity {
-    fn from(v: &tq_db::character::Character) -> Self {
+impl From<&CharacterRecord> for Entity {
+    fn from(v: &CharacterRecord) -> Self {
         // TODO: handle more flags.
         let flags = {
             let f = Flags::NONE;
@@ -170,14 +239,16 @@ impl From<&tq_db::character::Character> for Entity {
         Self {
             id: (v.character_id as u32) + constants::CHARACTER_ID_MIN,
             mesh: AtomicU32::new(v.mesh as _),
-            name: v.name.clone(),
+            name: ArcSwap::from_pointee(v.name.clone()),
             map_id: AtomicU32::new(v.map_id as _),
-            location: Atomic::new(Location::new(v.x as _, v.y as _, 0)),
+            locations: Atomic::new(Locations {
+                current: Location::new(v.x as _, v.y as _, 0),
+                prev: Location::default(),
+            }),
             flags: AtomicU64::new(flags.bits()),
             level: AtomicU16::new(v.level as _),
             action: AtomicU16::new(100),
             prev_map_id: AtomicU32::new(v.map_id as _),
-            prev_location: Atomic::new(Location::default()),
             hp: Atomic::new(Gauge {
                 current: v.health_points as _,
                 // TODO: handle max hp.
@@ -192,18 +263,16 @@ impl From<&tq_db::npc::Npc> for Entity {
         Self {
             id: (v.id as u32),
             mesh: AtomicU32::new(v.look as _),
-            name: v.name.clone(),
+            name: ArcSwap::from_pointee(v.name.clone()),
             map_id: AtomicU32::new(v.map_id as _),
-            location: Atomic::new(Location::new(
-                v.x as _,
-                v.y as _,
-                (v.look % 10) as _,
-            )),
+            locations: Atomic::new(Locations {
+                current: Location::new(v.x as _, v.y as _, (v.look % 10) as _),
+                prev: Location::default(),
+            }),
             flags: AtomicU64::new(Flags::NONE.bits()),
             level: AtomicU16::new(v.level as _),
             action: AtomicU16::new(100),
             prev_map_id: AtomicU32::new(v.map_id as _),
-            prev_location: Atomic::new(Location::default()),
             hp: Atomic::new(Gauge::default()),
         }
     }
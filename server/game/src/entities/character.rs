@@ -1,14 +1,43 @@
-use crate::entities::{Entity, GameEntity};
+use crate::entities::{
+    CharacterRecord, CharacterState, Entity, Flags, GameEntity,
+};
 use crate::packets::{
-    ActionType, MsgAction, MsgMapInfo, MsgPlayer, MsgWeather,
+    ActionType, AttributeKind, MsgAction, MsgMapInfo, MsgPlayer, MsgUserAttrib,
+    MsgWeather,
+};
+use crate::systems::{
+    ActionCooldowns, Applied, EffectKind, ExperienceBatch, ItemEffect, Screen,
+    Stacking, Stall, StatusEffects, XpBar, XpSkill, XP_FULL, XP_SKILL_DURATION,
 };
-use crate::systems::Screen;
 use crate::utils::LoHi;
-use crate::Error;
+use crate::world::Map;
+use crate::{constants, Error};
 use arc_swap::ArcSwapWeak;
-use std::sync::atomic::{AtomicU16, Ordering};
+use atomic::Atomic;
+use parking_lot::{Mutex, RwLock};
+use primitives::{Gauge, Location};
+use std::sync::atomic::{
+    AtomicBool, AtomicU16, AtomicU32, AtomicU64, Ordering,
+};
 use std::sync::{Arc, Weak};
-use tq_network::ActorHandle;
+use std::time::{Duration, Instant};
+use tq_network::{ActorHandle, PacketEncode, PacketID};
+
+/// How many times saving a character is tried while the database could not
+/// be reached.
+const SAVE_ATTEMPTS: u32 = 3;
+
+/// How long to wait before trying to save a character again.
+const SAVE_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// How far a [`Character::move_to`] went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Moved {
+    /// Within the screen, the observers saw the character moving.
+    InScreen,
+    /// Out of the screen, it got reloaded around the destination.
+    Jumped,
+}
 
 /// This struct encapsulates the game character for a player. The player
 /// controls the character as the protagonist of the Conquer Online storyline.
@@ -17,22 +46,97 @@ use tq_network::ActorHandle;
 /// also controls the character's professions and abilities.
 #[derive(Debug)]
 pub struct Character {
-    inner: tq_db::character::Character,
+    /// The persistent fields that rarely change, the hot ones are kept in
+    /// atomics here and in the entity.
+    record: RwLock<CharacterRecord>,
     entity: Entity,
     owner: ActorHandle,
     elevation: AtomicU16,
     screen: ArcSwapWeak<Screen>,
+    strength: AtomicU16,
+    agility: AtomicU16,
+    vitality: AtomicU16,
+    spirit: AtomicU16,
+    attribute_points: AtomicU16,
+    /// Mana Points
+    mp: Atomic<Gauge>,
+    /// Whether the character is alive, and what it is busy with.
+    state: Atomic<CharacterState>,
+    /// Whether the client finished loading the character into the world.
+    in_world: AtomicBool,
+    /// Whether the character is allowed to reallocate its attributes once.
+    allot_granted: AtomicBool,
+    /// The character we are trading with, zero if none.
+    trade_partner: AtomicU32,
+    /// The character that proposed to marry us, zero if none.
+    suitor: AtomicU32,
+    /// The NPC whose dialog we have open, zero if none.
+    dialog_npc: AtomicU32,
+    /// The attack the equipped weapons add, see
+    /// [`refresh_equipment_stats`](crate::systems::refresh_equipment_stats).
+    equipment_attack: AtomicU32,
+    /// When the character last went through a portal.
+    last_portal: Mutex<Option<Instant>>,
+    /// When the character last asked for its screen to be sent again.
+    last_screen_refresh: Mutex<Option<Instant>>,
+    /// When a position the client claimed was last accepted.
+    last_position_claim: Mutex<Option<Instant>>,
+    /// When the character last took or dealt damage.
+    last_combat_at: Mutex<Option<Instant>>,
+    /// When the character last attacked, and the like.
+    cooldowns: ActionCooldowns,
+    /// The temporary effects the character is under.
+    status_effects: StatusEffects,
+    xp: XpBar,
+    /// The items for sale while vending.
+    stall: Stall,
+    experience: AtomicU64,
+    /// Experience gained but not sent to the client yet.
+    experience_batch: ExperienceBatch,
 }
 
 impl Character {
     pub fn new(owner: ActorHandle, inner: tq_db::character::Character) -> Self {
+        let inner = CharacterRecord::from(inner);
         let entity = Entity::from(&inner);
+        let [strength, agility, vitality, spirit] =
+            [inner.strength, inner.agility, inner.vitality, inner.spirit]
+                .map(|v| v as u16);
+        let max_hp =
+            constants::max_health_points(strength, agility, vitality, spirit);
+        let hp = entity.hp();
+        entity.set_hp(Gauge::new(hp.current().min(max_hp), max_hp));
+        let max_mp = constants::max_mana_points(spirit);
+        let mp = Gauge::new((inner.mana_points as u16).min(max_mp), max_mp);
         Self {
             entity,
             owner,
-            inner,
             elevation: Default::default(),
             screen: Default::default(),
+            strength: AtomicU16::new(strength),
+            agility: AtomicU16::new(agility),
+            vitality: AtomicU16::new(vitality),
+            spirit: AtomicU16::new(spirit),
+            attribute_points: AtomicU16::new(inner.attribute_points as _),
+            mp: Atomic::new(mp),
+            state: Atomic::new(CharacterState::Alive),
+            in_world: AtomicBool::new(false),
+            allot_granted: AtomicBool::new(false),
+            trade_partner: AtomicU32::new(0),
+            suitor: AtomicU32::new(0),
+            dialog_npc: AtomicU32::new(0),
+            equipment_attack: AtomicU32::new(0),
+            last_portal: Mutex::new(None),
+            last_screen_refresh: Mutex::new(None),
+            last_position_claim: Mutex::new(None),
+            last_combat_at: Mutex::new(None),
+            cooldowns: ActionCooldowns::new(),
+            status_effects: StatusEffects::new(),
+            xp: XpBar::new(),
+            stall: Stall::new(),
+            experience: AtomicU64::new(inner.experience as _),
+            experience_batch: ExperienceBatch::new(),
+            record: RwLock::new(inner),
         }
     }
 
@@ -53,43 +157,770 @@ impl Character {
     #[inline]
     pub fn id(&self) -> u32 { self.entity.id() }
 
+    pub fn x(&self) -> u16 { self.entity.location().x }
+
+    pub fn y(&self) -> u16 { self.entity.location().y }
+
+    /// Where the character was on the X axis before its latest move.
+    pub fn prev_x(&self) -> u16 { self.entity.prev_location().x }
+
+    /// Where the character was on the Y axis before its latest move.
+    pub fn prev_y(&self) -> u16 { self.entity.prev_location().y }
+
+    /// Moves the character to `(x, y)` on its map, keeping its direction.
+    pub fn set_position(&self, x: u16, y: u16) {
+        let mut location = self.entity.location();
+        location.x = x;
+        location.y = y;
+        self.entity.set_location(location);
+    }
+
     pub fn elevation(&self) -> u16 { self.elevation.load(Ordering::Relaxed) }
 
     pub fn set_elevation(&self, value: u16) {
         self.elevation.store(value, Ordering::Relaxed);
     }
 
-    pub fn hair_style(&self) -> u16 { self.inner.hair_style as u16 }
+    /// The id of the character in the database.
+    pub fn character_id(&self) -> i32 { self.record.read().character_id }
+
+    pub fn account_id(&self) -> u32 { self.record.read().account_id as u32 }
+
+    pub fn realm_id(&self) -> u32 { self.record.read().realm_id as u32 }
+
+    pub fn hair_style(&self) -> u16 { self.record.read().hair_style as u16 }
+
+    pub fn avatar(&self) -> u16 { self.record.read().avatar as u16 }
+
+    pub fn silver(&self) -> u64 { self.record.read().silver as u64 }
+
+    /// Gives `amount` silver to the character, refusing all of it if that
+    /// would take it over [`constants::MAX_SILVER`].
+    pub fn add_silver(&self, amount: u64) -> Result<(), Error> {
+        let mut record = self.record.write();
+        let silver = record.silver as u64;
+        match silver
+            .checked_add(amount)
+            .filter(|total| *total <= constants::MAX_SILVER)
+        {
+            Some(total) => {
+                record.silver = total as _;
+                Ok(())
+            },
+            None => Err(Error::SilverOverflow(silver, amount)),
+        }
+    }
+
+    /// Takes `amount` silver from the character, refusing it if the
+    /// character does not have that much.
+    pub fn spend_silver(&self, amount: u64) -> Result<(), Error> {
+        let mut record = self.record.write();
+        let silver = record.silver as u64;
+        match silver.checked_sub(amount) {
+            Some(left) => {
+                record.silver = left as _;
+                Ok(())
+            },
+            None => Err(Error::NotEnoughSilver(silver, amount)),
+        }
+    }
+
+    pub fn cps(&self) -> u64 { self.record.read().cps as u64 }
+
+    pub fn experience(&self) -> u64 { self.experience.load(Ordering::Relaxed) }
+
+    pub fn strength(&self) -> u16 { self.strength.load(Ordering::Relaxed) }
+
+    pub fn agility(&self) -> u16 { self.agility.load(Ordering::Relaxed) }
+
+    pub fn vitality(&self) -> u16 { self.vitality.load(Ordering::Relaxed) }
+
+    pub fn spirit(&self) -> u16 { self.spirit.load(Ordering::Relaxed) }
+
+    pub fn attribute_points(&self) -> u16 {
+        self.attribute_points.load(Ordering::Relaxed)
+    }
+
+    pub fn hp(&self) -> Gauge { self.entity.hp() }
+
+    pub fn health_points(&self) -> u16 { self.hp().current() }
+
+    pub fn max_health_points(&self) -> u16 { self.hp().max }
+
+    pub fn mp(&self) -> Gauge { self.mp.load(Ordering::Relaxed) }
+
+    pub fn mana_points(&self) -> u16 { self.mp().current() }
+
+    pub fn max_mana_points(&self) -> u16 { self.mp().max }
+
+    pub fn kill_points(&self) -> u16 { self.record.read().kill_points as u16 }
+
+    pub fn current_class(&self) -> u8 { self.record.read().current_class as u8 }
+
+    pub fn previous_class(&self) -> u8 {
+        self.record.read().previous_class as u8
+    }
+
+    pub fn rebirths(&self) -> u8 { self.record.read().rebirths as u8 }
+
+    /// The bonus attribute points the character got from its rebirths.
+    pub fn rebirth_points(&self) -> u16 {
+        self.record.read().rebirth_points as u16
+    }
+
+    /// Takes on the class, level and attributes written by a rebirth, the
+    /// character comes out of it with full health and mana.
+    ///
+    /// Returns the changes to send to the client.
+    pub fn apply_rebirth(&self, reborn: &CharacterRecord) -> MsgUserAttrib {
+        {
+            let mut record = self.record.write();
+            record.current_class = reborn.current_class;
+            record.previous_class = reborn.previous_class;
+            record.rebirths = reborn.rebirths;
+            record.rebirth_points = reborn.rebirth_points;
+        }
+        let level = reborn.level as u16;
+        self.entity.set_level(level);
+        self.experience
+            .store(reborn.experience as u64, Ordering::Relaxed);
+        let stats = [
+            (&self.strength, reborn.strength, AttributeKind::Strength),
+            (&self.agility, reborn.agility, AttributeKind::Agility),
+            (&self.vitality, reborn.vitality, AttributeKind::Vitality),
+            (&self.spirit, reborn.spirit, AttributeKind::Spirit),
+            (
+                &self.attribute_points,
+                reborn.attribute_points,
+                AttributeKind::AttributePoints,
+            ),
+        ];
+        let mut msg = MsgUserAttrib::new(self.id())
+            .with(AttributeKind::Class, reborn.current_class as u64)
+            .with(AttributeKind::Rebirths, reborn.rebirths as u64)
+            .with(AttributeKind::Level, level as u64)
+            .with(AttributeKind::Experience, reborn.experience as u64);
+        for (stat, value, kind) in stats {
+            stat.store(value as u16, Ordering::Relaxed);
+            msg = msg.with(kind, value as u64);
+        }
+        let max_hp = constants::max_health_points(
+            self.strength(),
+            self.agility(),
+            self.vitality(),
+            self.spirit(),
+        );
+        self.entity.set_hp(Gauge::new(max_hp, max_hp));
+        let max_mp = constants::max_mana_points(self.spirit());
+        self.mp.store(Gauge::new(max_mp, max_mp), Ordering::Relaxed);
+        msg.with(AttributeKind::MaxHealth, max_hp as u64)
+            .with(AttributeKind::Health, max_hp as u64)
+            .with(AttributeKind::MaxMana, max_mp as u64)
+            .with(AttributeKind::Mana, max_mp as u64)
+    }
+
+    /// Whether the client finished loading, telling us with
+    /// [`LoginCompeleted`](crate::packets::ActionType::LoginCompeleted).
+    /// Until then the character takes no part in the game: spawns sent to it
+    /// are held back, the world ticks skip it and its gameplay packets get
+    /// rejected.
+    pub fn in_world(&self) -> bool { self.in_world.load(Ordering::Relaxed) }
+
+    /// Marks the character as in the world, returns whether it already was.
+    pub fn enter_world(&self) -> bool {
+        self.in_world.swap(true, Ordering::Relaxed)
+    }
+
+    /// Allows the character to reallocate its attributes once using
+    /// [`MsgAllot`](crate::packets::MsgAllot).
+    pub fn grant_allot(&self) {
+        self.allot_granted.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the character was granted a reallocation it did not use yet.
+    pub fn allot_granted(&self) -> bool {
+        self.allot_granted.load(Ordering::Relaxed)
+    }
+
+    /// Applies a new attributes distribution (strength, agility, vitality,
+    /// spirit) to the character, recomputing the health and mana maxima and
+    /// clamping the current values to them.
+    ///
+    /// The distribution accounts for every point the character has, spent or
+    /// not, so the unspent points are used up.
+    ///
+    /// Returns the attribute updates that should be sent to the client.
+    pub fn apply_allotment(
+        &self,
+        allotment: [u16; 4],
+    ) -> Result<MsgUserAttrib, Error> {
+        let expected = constants::base_attribute_points(
+            self.current_class(),
+            self.entity.level(),
+        ) + self.rebirth_points();
+        let total = allotment
+            .iter()
+            .try_fold(0u16, |acc, v| acc.checked_add(*v))
+            .unwrap_or(u16::MAX);
+        if total != expected {
+            return Err(Error::InvalidAllotment(expected, total));
+        }
+        let mut msg = self.set_attributes(allotment);
+        if self.attribute_points.swap(0, Ordering::Relaxed) != 0 {
+            msg = msg.with(AttributeKind::AttributePoints, 0);
+        }
+        Ok(msg)
+    }
+
+    /// Spends unspent attribute points, adding `points` (strength, agility,
+    /// vitality, spirit) to the attributes and recomputing the health and
+    /// mana maxima. Nothing changes if the character does not have that many
+    /// points to spend.
+    ///
+    /// Returns the attribute updates that should be sent to the client.
+    pub fn apply_attribute_points(
+        &self,
+        points: [u16; 4],
+    ) -> Result<MsgUserAttrib, Error> {
+        let total = points
+            .iter()
+            .try_fold(0u16, |acc, v| acc.checked_add(*v))
+            .unwrap_or(u16::MAX);
+        let available = self
+            .attribute_points
+            .try_update(Ordering::Relaxed, Ordering::Relaxed, |p| {
+                p.checked_sub(total)
+            })
+            .map_err(|available| {
+                Error::NotEnoughAttributePoints(available, total)
+            })?;
+        let current = [
+            self.strength(),
+            self.agility(),
+            self.vitality(),
+            self.spirit(),
+        ];
+        let mut allotment = [0; 4];
+        for (i, value) in allotment.iter_mut().enumerate() {
+            *value = current[i].saturating_add(points[i]);
+        }
+        let left = available - total;
+        let msg = self
+            .set_attributes(allotment)
+            .with(AttributeKind::AttributePoints, left as u64);
+        Ok(msg)
+    }
+
+    /// Sets the attributes (strength, agility, vitality, spirit), recomputing
+    /// the health and mana maxima and clamping the current values to them.
+    fn set_attributes(&self, allotment: [u16; 4]) -> MsgUserAttrib {
+        let [strength, agility, vitality, spirit] = allotment;
+        let mut msg = MsgUserAttrib::new(self.id());
+        let stats = [
+            (&self.strength, strength, AttributeKind::Strength),
+            (&self.agility, agility, AttributeKind::Agility),
+            (&self.vitality, vitality, AttributeKind::Vitality),
+            (&self.spirit, spirit, AttributeKind::Spirit),
+        ];
+        for (stat, value, kind) in stats {
+            if stat.swap(value, Ordering::Relaxed) != value {
+                msg = msg.with(kind, value as u64);
+            }
+        }
+
+        let max_hp =
+            constants::max_health_points(strength, agility, vitality, spirit);
+        let old_hp = self.entity.hp();
+        let hp = Gauge::new(old_hp.current().min(max_hp), max_hp);
+        self.entity.set_hp(hp);
+        if old_hp.max != hp.max {
+            msg = msg.with(AttributeKind::MaxHealth, hp.max as u64);
+        }
+        if old_hp.current() != hp.current() {
+            msg = msg.with(AttributeKind::Health, hp.current() as u64);
+        }
+
+        let max_mp = constants::max_mana_points(spirit);
+        let old_mp = self.mp();
+        let mp = Gauge::new(old_mp.current().min(max_mp), max_mp);
+        self.mp.store(mp, Ordering::Relaxed);
+        if old_mp.max != mp.max {
+            msg = msg.with(AttributeKind::MaxMana, mp.max as u64);
+        }
+        if old_mp.current() != mp.current() {
+            msg = msg.with(AttributeKind::Mana, mp.current() as u64);
+        }
+        msg
+    }
+
+    /// Whether the effect would do nothing to the character right now, like
+    /// healing a character that is already at full health.
+    pub fn is_full_for(&self, effect: ItemEffect) -> bool {
+        match effect {
+            ItemEffect::Heal { hp, mp } => {
+                (hp == 0 || self.entity.hp().is_full())
+                    && (mp == 0 || self.mp().is_full())
+            },
+            // Using it again would be ignored.
+            ItemEffect::Buff { kind, .. } => {
+                kind.stacking() == Stacking::Ignore
+                    && self.status_effects.is_active(kind)
+            },
+        }
+    }
 
-    pub fn avatar(&self) -> u16 { self.inner.avatar as u16 }
+    /// Applies the effect of a consumable item to the character.
+    ///
+    /// Returns the health and mana updates that should be sent to the
+    /// client, a buff tells the client about itself.
+    pub async fn apply_item_effect(
+        &self,
+        effect: ItemEffect,
+    ) -> Result<MsgUserAttrib, Error> {
+        let mut msg = MsgUserAttrib::new(self.id());
+        match effect {
+            ItemEffect::Heal { hp, mp } => {
+                if hp > 0 {
+                    let mut gauge = self.entity.hp();
+                    gauge.increment(hp);
+                    self.entity.set_hp(gauge);
+                    msg =
+                        msg.with(AttributeKind::Health, gauge.current() as u64);
+                }
+                if mp > 0 {
+                    let mut gauge = self.mp();
+                    gauge.increment(mp);
+                    self.mp.store(gauge, Ordering::Relaxed);
+                    msg = msg.with(AttributeKind::Mana, gauge.current() as u64);
+                }
+            },
+            ItemEffect::Buff {
+                kind,
+                power,
+                duration,
+            } => {
+                self.apply_status_effect(kind, power, duration).await?;
+            },
+        }
+        Ok(msg)
+    }
 
-    pub fn silver(&self) -> u64 { self.inner.silver as u64 }
+    /// Uses up `amount` mana points, returns the mana left or `None` without
+    /// using anything if there is not enough.
+    pub fn spend_mana(&self, amount: u16) -> Option<u16> {
+        self.mp
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |mut mp| {
+                (mp.current() >= amount).then(|| {
+                    mp.decrement(amount);
+                    mp
+                })
+            })
+            .ok()
+            .map(|mp| mp.current() - amount)
+    }
 
-    pub fn cps(&self) -> u64 { self.inner.cps as u64 }
+    pub fn status_effects(&self) -> &StatusEffects { &self.status_effects }
 
-    pub fn experience(&self) -> u64 { self.inner.experience as u64 }
+    pub fn cooldowns(&self) -> &ActionCooldowns { &self.cooldowns }
 
-    pub fn strength(&self) -> u16 { self.inner.strength as u16 }
+    /// Puts the character under a status effect for the given duration, and
+    /// shows it to the character and everyone around if it is new.
+    #[tracing::instrument(skip(self), fields(me = self.entity.id()))]
+    pub async fn apply_status_effect(
+        &self,
+        kind: EffectKind,
+        power: u16,
+        duration: Duration,
+    ) -> Result<Applied, Error> {
+        self.apply_status_effect_at(kind, power, duration, Instant::now())
+            .await
+    }
 
-    pub fn agility(&self) -> u16 { self.inner.agility as u16 }
+    /// Same as [`Self::apply_status_effect`], starting from `now`.
+    pub async fn apply_status_effect_at(
+        &self,
+        kind: EffectKind,
+        power: u16,
+        duration: Duration,
+        now: Instant,
+    ) -> Result<Applied, Error> {
+        let applied = self.status_effects.apply(kind, power, duration, now);
+        if applied == Applied::Added {
+            self.sync_status_flags().await?;
+        }
+        Ok(applied)
+    }
 
-    pub fn vitality(&self) -> u16 { self.inner.vitality as u16 }
+    /// Called periodically, hurts the character if poisoned and removes the
+    /// effects that expired by `now`, notifying the client about them.
+    #[tracing::instrument(skip(self), fields(me = self.entity.id()))]
+    pub async fn tick_status_effects(&self, now: Instant) -> Result<(), Error> {
+        if let Some(poison) = self.status_effects.get(EffectKind::Poison) {
+            let mut hp = self.entity.hp();
+            // Poison never kills.
+            let damage = (poison.power * poison.stacks as u16)
+                .min(hp.current().saturating_sub(1));
+            if damage > 0 {
+                hp.decrement(damage);
+                self.entity.set_hp(hp);
+                let msg = MsgUserAttrib::single(
+                    self.id(),
+                    AttributeKind::Health,
+                    hp.current() as u64,
+                );
+                self.owner.send(msg).await?;
+            }
+        }
+        let expired = self.status_effects.expire(now);
+        if !expired.is_empty() {
+            tracing::trace!(?expired, "Status effects expired");
+            self.sync_status_flags().await?;
+        }
+        Ok(())
+    }
 
-    pub fn spirit(&self) -> u16 { self.inner.spirit as u16 }
+    /// Updates the character flags to match its status effects, and sends
+    /// them to the character and everyone around.
+    async fn sync_status_flags(&self) -> Result<(), Error> {
+        let flags = (self.entity.flags() - EffectKind::all_flags())
+            | self.status_effects.flags();
+        self.entity.set_flags(flags);
+        let msg = MsgUserAttrib::single(
+            self.id(),
+            AttributeKind::Flags,
+            flags.bits(),
+        );
+        self.owner.send(msg.clone()).await?;
+        if let Ok(screen) = self.try_screen() {
+            screen.send_message(msg).await?;
+        }
+        Ok(())
+    }
 
-    pub fn attribute_points(&self) -> u16 { self.inner.attribute_points as u16 }
+    pub fn xp_points(&self) -> u8 { self.xp.points() }
 
-    pub fn health_points(&self) -> u16 { self.inner.health_points as u16 }
+    /// Records an attack the character made at `now`, filling its XP circle.
+    pub async fn on_attack(&self, now: Instant) -> Result<(), Error> {
+        *self.last_combat_at.lock() = Some(now);
+        if let Some(points) = self.xp.on_attack(now) {
+            self.sync_xp(points).await?;
+        }
+        Ok(())
+    }
 
-    pub fn mana_points(&self) -> u16 { self.inner.mana_points as u16 }
+    /// Records that the character got attacked at `now`, putting it in
+    /// battle stance.
+    pub fn on_attacked(&self, now: Instant) {
+        *self.last_combat_at.lock() = Some(now);
+        self.xp.enter_combat(now);
+    }
 
-    pub fn kill_points(&self) -> u16 { self.inner.kill_points as u16 }
+    /// When the character last took or dealt damage.
+    pub fn last_combat_at(&self) -> Option<Instant> {
+        *self.last_combat_at.lock()
+    }
 
-    pub fn current_class(&self) -> u8 { self.inner.current_class as u8 }
+    /// Whether the character fought within `window` before `now`.
+    pub fn in_combat(&self, now: Instant, window: Duration) -> bool {
+        self.last_combat_at()
+            .is_some_and(|t| now.saturating_duration_since(t) < window)
+    }
 
-    pub fn previous_class(&self) -> u8 { self.inner.previous_class as u8 }
+    /// Called on every world tick, fills the XP circle while in battle
+    /// stance and empties it once out of combat for too long.
+    pub async fn tick_xp(&self, now: Instant) -> Result<(), Error> {
+        if let Some(points) = self.xp.tick(now) {
+            self.sync_xp(points).await?;
+        }
+        Ok(())
+    }
+
+    /// Triggers an XP skill, using up the full XP circle. Returns `false` if
+    /// the circle was not full.
+    #[tracing::instrument(skip(self), fields(me = self.entity.id()))]
+    pub async fn use_xp_skill(
+        &self,
+        skill: XpSkill,
+        now: Instant,
+    ) -> Result<bool, Error> {
+        if !self.xp.consume() {
+            return Ok(false);
+        }
+        self.sync_xp(0).await?;
+        let (kind, power) = skill.effect();
+        self.apply_status_effect_at(kind, power, XP_SKILL_DURATION, now)
+            .await?;
+        Ok(true)
+    }
+
+    /// Tells the client how full the XP circle is, and shows everyone
+    /// around whether it is full.
+    async fn sync_xp(&self, points: u8) -> Result<(), Error> {
+        let msg = MsgUserAttrib::single(
+            self.id(),
+            AttributeKind::XpCircle,
+            points as u64,
+        );
+        self.owner.send(msg).await?;
+        let full = points >= XP_FULL;
+        let flags = self.entity.flags();
+        if flags.contains(Flags::XP_CIRCLE) != full {
+            let flags = if full {
+                flags | Flags::XP_CIRCLE
+            } else {
+                flags - Flags::XP_CIRCLE
+            };
+            self.entity.set_flags(flags);
+            let msg = MsgUserAttrib::single(
+                self.id(),
+                AttributeKind::Flags,
+                flags.bits(),
+            );
+            self.owner.send(msg.clone()).await?;
+            if let Ok(screen) = self.try_screen() {
+                screen.send_message(msg).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Gives experience to the character, leveling it up as needed.
+    ///
+    /// Gains get batched and sent by [`Self::flush_experience`], unless the
+    /// character levels up, then the client gets told right away.
+    #[tracing::instrument(skip(self), fields(me = self.entity.id()))]
+    pub async fn gain_experience(
+        &self,
+        amount: u64,
+        now: Instant,
+    ) -> Result<(), Error> {
+        let mut experience = self.experience().saturating_add(amount);
+        let old_level = self.entity.level();
+        let mut level = old_level;
+        while let Some(needed) = constants::level_up_experience(level) {
+            if experience < needed {
+                break;
+            }
+            experience -= needed;
+            level += 1;
+        }
+        if constants::level_up_experience(level).is_none() {
+            // Nothing to gain at the max level.
+            experience = 0;
+        }
+        self.experience.store(experience, Ordering::Relaxed);
+        if level == old_level {
+            self.experience_batch.mark(now);
+            return Ok(());
+        }
+        self.experience_batch.clear();
+        let points =
+            (level - old_level) * constants::ATTRIBUTE_POINTS_PER_LEVEL;
+        let points =
+            self.attribute_points.fetch_add(points, Ordering::Relaxed) + points;
+        self.entity.set_level(level);
+        tracing::debug!(%old_level, %level, "Leveled up");
+        let msg = MsgUserAttrib::new(self.id())
+            .with(AttributeKind::Level, level as u64)
+            .with(AttributeKind::AttributePoints, points as u64)
+            .with(AttributeKind::Experience, experience);
+        self.owner.send(msg).await?;
+        if let Ok(screen) = self.try_screen() {
+            let msg = MsgUserAttrib::single(
+                self.id(),
+                AttributeKind::Level,
+                level as u64,
+            );
+            screen.send_message(msg).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends the batched experience to the client if it was held for at
+    /// least `window`.
+    pub async fn flush_experience(
+        &self,
+        now: Instant,
+        window: Duration,
+    ) -> Result<(), Error> {
+        if self.experience_batch.take_due(now, window) {
+            let msg = MsgUserAttrib::single(
+                self.id(),
+                AttributeKind::Experience,
+                self.experience(),
+            );
+            self.owner.send(msg).await?;
+        }
+        Ok(())
+    }
 
-    pub fn rebirths(&self) -> u8 { self.inner.rebirths as u8 }
+    pub fn state(&self) -> CharacterState { self.state.load(Ordering::Acquire) }
+
+    pub fn is_alive(&self) -> bool { self.state() != CharacterState::Dead }
+
+    /// Moves the character to the state `to`, returns the state it was in.
+    ///
+    /// Fails without changing anything if the character could not go from
+    /// its current state to `to`, see [`CharacterState::can_become`].
+    pub fn try_transition(
+        &self,
+        to: CharacterState,
+    ) -> Result<CharacterState, Error> {
+        let from = self
+            .state
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |from| {
+                from.can_become(to).then_some(to)
+            })
+            .map_err(|from| Error::InvalidStateTransition(from, to))?;
+        // Keep the flag the client knows the dead by in sync.
+        if to == CharacterState::Dead {
+            self.entity.set_flags(self.entity.flags() | Flags::DEAD);
+        } else if from == CharacterState::Dead {
+            self.entity.set_flags(self.entity.flags() - Flags::DEAD);
+        }
+        Ok(from)
+    }
+
+    pub fn stall(&self) -> &Stall { &self.stall }
+
+    pub fn trade_partner(&self) -> Option<u32> {
+        match self.trade_partner.load(Ordering::Relaxed) {
+            0 => None,
+            id => Some(id),
+        }
+    }
+
+    pub fn set_trade_partner(&self, partner: Option<u32>) {
+        self.trade_partner
+            .store(partner.unwrap_or_default(), Ordering::Relaxed);
+    }
+
+    pub fn is_trading(&self) -> bool { self.trade_partner().is_some() }
+
+    /// The name of the character we are married to, `None` if single.
+    pub fn spouse(&self) -> String { self.record.read().spouse.clone() }
+
+    pub fn set_spouse(&self, spouse: &str) {
+        self.record.write().spouse = spouse.to_owned();
+    }
+
+    pub fn is_married(&self) -> bool {
+        self.record.read().spouse != tq_db::character::Character::NO_SPOUSE
+    }
+
+    /// Remembers that the character got a marriage proposal, replacing any
+    /// earlier one.
+    pub fn set_suitor(&self, suitor: u32) {
+        self.suitor.store(suitor, Ordering::Relaxed);
+    }
+
+    /// Consumes the pending marriage proposal, returns the id of whoever made
+    /// it.
+    pub fn take_suitor(&self) -> Option<u32> {
+        match self.suitor.swap(0, Ordering::Relaxed) {
+            0 => None,
+            id => Some(id),
+        }
+    }
+
+    /// The NPC the character is talking to, answers to its dialog are meant
+    /// for it.
+    pub fn dialog_npc(&self) -> Option<u32> {
+        match self.dialog_npc.load(Ordering::Relaxed) {
+            0 => None,
+            id => Some(id),
+        }
+    }
+
+    pub fn set_dialog_npc(&self, npc: Option<u32>) {
+        self.dialog_npc
+            .store(npc.unwrap_or_default(), Ordering::Relaxed);
+    }
+
+    /// The attack the equipped weapons add to the strength.
+    pub fn equipment_attack(&self) -> u32 {
+        self.equipment_attack.load(Ordering::Relaxed)
+    }
+
+    pub fn set_equipment_attack(&self, attack: u32) {
+        self.equipment_attack.store(attack, Ordering::Relaxed);
+    }
+
+    /// Marks a portal use at `now`, unless the character already used one
+    /// within `cooldown`, then returns `false` and nothing changes.
+    pub fn try_use_portal(&self, now: Instant, cooldown: Duration) -> bool {
+        let mut last = self.last_portal.lock();
+        match *last {
+            Some(t) if now.saturating_duration_since(t) < cooldown => false,
+            _ => {
+                *last = Some(now);
+                true
+            },
+        }
+    }
+
+    /// Marks a screen refresh at `now`, unless the character already asked
+    /// for one within `cooldown`, see [`Character::try_use_portal`].
+    pub fn try_refresh_screen(&self, now: Instant, cooldown: Duration) -> bool {
+        let mut last = self.last_screen_refresh.lock();
+        match *last {
+            Some(t) if now.saturating_duration_since(t) < cooldown => false,
+            _ => {
+                *last = Some(now);
+                true
+            },
+        }
+    }
+
+    /// Marks a position claim `distance` tiles away accepted at `now`, unless
+    /// the character could not have gone that far since the last one, see
+    /// [`claim_reach`](crate::systems::claim_reach), then returns `false` and
+    /// nothing changes.
+    pub fn try_claim_position(&self, now: Instant, distance: u16) -> bool {
+        let mut last = self.last_position_claim.lock();
+        let reach = last.map(|t| {
+            crate::systems::claim_reach(now.saturating_duration_since(t))
+        });
+        match reach {
+            Some(reach) if distance > reach => false,
+            _ => {
+                *last = Some(now);
+                true
+            },
+        }
+    }
+
+    /// Reallocates the character attributes, using up its reallocation grant,
+    /// persists them and notifies the client with whatever changed. A wrong
+    /// distribution leaves the grant for another try.
+    #[tracing::instrument(skip(self, state), fields(me = self.entity.id()))]
+    pub async fn reallot(
+        &self,
+        state: &crate::State,
+        allotment: [u16; 4],
+    ) -> Result<(), Error> {
+        let msg = self.apply_allotment(allotment)?;
+        self.allot_granted.store(false, Ordering::Relaxed);
+        self.save(state).await?;
+        if !msg.is_empty() {
+            self.owner.send(msg).await?;
+        }
+        Ok(())
+    }
+
+    /// Spends unspent attribute points, persists the attributes and notifies
+    /// the client with whatever changed.
+    #[tracing::instrument(skip(self, state), fields(me = self.entity.id()))]
+    pub async fn spend_attribute_points(
+        &self,
+        state: &crate::State,
+        points: [u16; 4],
+    ) -> Result<(), Error> {
+        let msg = self.apply_attribute_points(points)?;
+        self.save(state).await?;
+        self.owner.send(msg).await?;
+        Ok(())
+    }
 
     pub async fn kick_back(&self) -> Result<(), Error> {
         let location = self.entity.location();
@@ -105,6 +936,58 @@ impl Character {
         Ok(())
     }
 
+    /// Moves the character to `(x, y)` on its map, facing `direction`. Every
+    /// move within a map goes through here.
+    ///
+    /// The client gets `packet` first, then the character changes region and
+    /// its screen follows: the observers in range see `packet` and the ones
+    /// out of range are dropped. A move further than the screen reaches
+    /// reloads the screen around the destination instead.
+    #[tracing::instrument(skip(self, state, packet), fields(me = self.entity.id()))]
+    pub async fn move_to<P>(
+        &self,
+        state: &crate::State,
+        (x, y): (u16, u16),
+        direction: u8,
+        packet: P,
+    ) -> Result<Moved, Error>
+    where
+        P: PacketEncode + PacketID + Clone + Send + Sync + 'static,
+        Error: From<P::Error>,
+    {
+        let map = state.try_map(self.entity.map_id())?;
+        let screen = self.try_screen()?;
+        let me = screen.try_character()?;
+        let from = self.entity.location();
+        let moved = if tq_math::in_screen((from.x, from.y), (x, y)) {
+            Moved::InScreen
+        } else {
+            Moved::Jumped
+        };
+        if moved == Moved::Jumped {
+            screen.remove_from_observers().await?;
+            screen.clear()?;
+        }
+        self.entity.set_location(Location::new(x, y, direction));
+        if let Some(tile) = map.tile(x, y) {
+            self.set_elevation(tile.elevation);
+        }
+        self.owner.send(packet.clone()).await?;
+        map.update_region_for(me);
+        crate::systems::prewarm_portals(state, &map, (x, y));
+        match moved {
+            Moved::InScreen => screen.send_movement(state, packet).await?,
+            Moved::Jumped => screen.load_surroundings(state).await?,
+        }
+        Ok(moved)
+    }
+
+    /// Moves the character to `(x, y)` on the given map, or the closest
+    /// available tile, see [`Map::find_available_tile`]. Observers around the
+    /// old location see the character disappear, observers around the new
+    /// location see it appear, and the character's screen gets reloaded
+    /// with whatever is around the destination. Within the same map it is a
+    /// [`Character::move_to`].
     #[tracing::instrument(skip(self, state), fields(me = self.entity.id()))]
     pub async fn teleport(
         &self,
@@ -113,32 +996,74 @@ impl Character {
         (x, y): (u16, u16),
     ) -> Result<(), Error> {
         let mut location = self.entity.location();
+        let new_map = state.try_map(map_id)?;
+        new_map.load().await?;
+        // Never onto a wall or someone's stall.
+        let (x, y) = new_map
+            .find_available_tile((x, y), crate::world::PLACEMENT_RADIUS)
+            .ok_or(Error::TileNotFound(x, y))?;
         let xy = u32::constract(y, x);
+        // The client knows the maps by the ones they are copies of.
         let msg = MsgAction::new(
             self.entity.id(),
-            map_id,
+            new_map.map_id(),
             xy,
             location.direction as u16,
             ActionType::Teleport,
         );
-        let new_map = state.try_map(map_id)?;
-        new_map.load().await?;
         let tile = new_map.tile(x, y).ok_or(Error::TileNotFound(x, y))?;
-        // remove from old map
-        if let Ok(old_map) = state.try_map(self.entity.map_id()) {
-            old_map.remove_entity_by_id_and_location(
-                self.id(),
-                self.entity().location(),
-            )?;
-            self.try_screen()?.remove_from_observers().await?;
+        if self.entity.map_id() == map_id {
+            self.move_to(state, (x, y), location.direction, msg).await?;
+            return Ok(());
         }
+        let screen = self.try_screen()?;
+        let me = screen.try_character()?;
+        screen.remove_from_observers().await?;
+        screen.clear()?;
+        let old_map_id = self.entity.map_id();
+        let old_map = state.try_map(old_map_id).ok();
+        let old_location = self.entity.location();
         location.x = x;
         location.y = y;
         self.entity.set_location(location).set_map_id(map_id);
         self.set_elevation(tile.elevation);
         self.owner.send(msg).await?;
         self.owner.send(MsgWeather::new(new_map.weather())).await?;
-        self.owner.send(MsgMapInfo::from_map(new_map)).await?;
+        self.owner.send(MsgMapInfo::from_map(&new_map)).await?;
+        // move to the new map and show us to whoever is around.
+        match old_map {
+            Some(old_map) => {
+                Map::transfer_entity(&old_map, &new_map, me, old_location)
+                    .await?
+            },
+            None => new_map.insert_entity(me).await?,
+        }
+        screen.load_surroundings(state).await?;
+        crate::systems::destroy_instance_if_empty(state, old_map_id)?;
+        Ok(())
+    }
+
+    /// Saves the character and takes it out of the game world, its observers
+    /// see it leaving and it gets removed from its map and the state.
+    #[tracing::instrument(skip(self, state), fields(me = self.entity.id()))]
+    pub async fn leave_world(&self, state: &crate::State) -> Result<(), Error> {
+        crate::systems::close_stall(state, self).await?;
+        self.save(state).await?;
+        self.try_screen()?.remove_from_observers().await?;
+        state.remove_entity(self.id());
+        let mymap = state.try_map(self.entity.map_id())?;
+        mymap.remove_entity_by_id_and_location(
+            self.id(),
+            self.entity.location(),
+        )?;
+        crate::systems::destroy_instance_if_empty(state, mymap.id())?;
+        // The character is gone either way, only the ones holding it as an
+        // enemy miss out.
+        if let Err(error) =
+            crate::systems::notify_enemy_status(state, self, false).await
+        {
+            tracing::warn!(%error, "Failed to tell the enemies we left");
+        }
         Ok(())
     }
 
@@ -149,8 +1074,14 @@ impl Character {
     ) -> Result<(), Error> {
         match observer.as_ref() {
             GameEntity::Character(c) => {
-                self.send_spawn(&c.owner()).await?;
-                c.send_spawn(&self.owner).await?;
+                // Whoever is still loading gets the other one once done, see
+                // `Character::in_world`.
+                if c.in_world() {
+                    self.send_spawn(&c.owner()).await?;
+                }
+                if self.in_world() {
+                    c.send_spawn(&self.owner).await?;
+                }
             },
             _ => {
                 // We only exchange spawn packets with characters
@@ -159,39 +1090,47 @@ impl Character {
         Ok(())
     }
 
+    /// An up to date copy of the persistent fields of the character.
+    pub fn snapshot(&self) -> CharacterRecord {
+        let location = self.entity.location();
+        let mut record = self.record.read().clone();
+        record.name = self.entity.name().to_string();
+        record.mesh = self.entity.mesh() as _;
+        record.level = self.entity.level() as _;
+        record.experience = self.experience() as _;
+        record.map_id = self.entity.map_id() as _;
+        record.x = location.x as _;
+        record.y = location.y as _;
+        record.strength = self.strength() as _;
+        record.agility = self.agility() as _;
+        record.vitality = self.vitality() as _;
+        record.spirit = self.spirit() as _;
+        record.attribute_points = self.attribute_points() as _;
+        record.health_points = self.health_points() as _;
+        record.mana_points = self.mana_points() as _;
+        record
+    }
+
     #[tracing::instrument(skip(self, state), fields(me = self.entity.id()))]
     pub async fn save(&self, state: &crate::State) -> Result<(), Error> {
-        let location = self.entity.location();
-        let e = tq_db::character::Character {
-            character_id: self.inner.character_id,
-            account_id: self.inner.account_id,
-            realm_id: self.inner.realm_id,
-            name: self.entity.name().to_string(),
-            mesh: self.entity.mesh() as _,
-            avatar: self.avatar() as _,
-            hair_style: self.hair_style() as _,
-            silver: self.silver() as _,
-            cps: self.cps() as _,
-            current_class: self.current_class() as _,
-            previous_class: self.previous_class() as _,
-            rebirths: self.rebirths() as _,
-            level: self.entity.level() as _,
-            experience: self.experience() as _,
-            map_id: self.entity.map_id() as _,
-            x: location.x as _,
-            y: location.y as _,
-            virtue: self.inner.virtue,
-            strength: self.strength() as _,
-            agility: self.agility() as _,
-            vitality: self.vitality() as _,
-            spirit: self.spirit() as _,
-            attribute_points: self.attribute_points() as _,
-            health_points: self.health_points() as _,
-            mana_points: self.mana_points() as _,
-            kill_points: self.kill_points() as _,
-        };
-        e.update(state.pool()).await?;
-        Ok(())
+        let mut record = self.snapshot();
+        // Instances are gone after a restart, the character comes back to
+        // the map it is a copy of.
+        if let Some(map) = state.map_by_id(self.entity.map_id()) {
+            if map.is_copy() {
+                record.map_id = map.map_id() as _;
+                let (x, y) = map.revive_tile();
+                record.x = x as _;
+                record.y = y as _;
+            }
+        }
+        let record = tq_db::character::Character::from(record);
+        let pool = state.pool();
+        crate::error::retry_transient(SAVE_ATTEMPTS, SAVE_RETRY_DELAY, || {
+            let record = record.clone();
+            async move { Ok(record.update(pool).await?) }
+        })
+        .await
     }
 
     #[tracing::instrument(skip(self, to), fields(me = self.entity.id()))]
@@ -205,3 +1144,515 @@ impl Character {
         Ok(())
     }
 }
+
+/// Write locks the records of both characters and hands them to `f`, in the
+/// order the characters were given.
+///
+/// Whatever the order of the arguments, the character with the lower id is
+/// always locked first, so two of these running at once on the same pair could
+/// not deadlock. Returns `None` if both are the same character.
+pub fn with_two_characters<F, R>(
+    a: &Character,
+    b: &Character,
+    f: F,
+) -> Option<R>
+where
+    F: FnOnce(&mut CharacterRecord, &mut CharacterRecord) -> R,
+{
+    if a.id() == b.id() {
+        return None;
+    }
+    let r = if a.id() < b.id() {
+        let mut a_record = a.record.write();
+        let mut b_record = b.record.write();
+        f(&mut a_record, &mut b_record)
+    } else {
+        let mut b_record = b.record.write();
+        let mut a_record = a.record.write();
+        f(&mut a_record, &mut b_record)
+    };
+    Some(r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::Locations;
+    use crate::packets::{BaseClass, BodyType, MsgRegister};
+    use crate::systems::Stat;
+    use crate::test_utils::*;
+    use crate::world::Maps;
+    use crate::ActorState;
+    use futures::FutureExt;
+    use primitives::{Location, Size};
+    use tq_network::Actor;
+
+    fn make_character(level: i16) -> Character {
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let actor = Actor::<ActorState>::new(tx);
+        let mut inner = MsgRegister::build_character_with(
+            String::from("test"),
+            BodyType::MuscularMale,
+            BaseClass::Trojan,
+            1,
+            1,
+            &mut crate::systems::GameRng::seeded(1).fork(),
+        )
+        .unwrap();
+        inner.level = level;
+        Character::new(actor.handle(), inner)
+    }
+
+    #[test]
+    fn silver_stays_within_bounds() {
+        let me = make_character(1);
+        let start = me.silver();
+        me.add_silver(500).unwrap();
+        assert_eq!(me.silver(), start + 500);
+        me.spend_silver(start + 200).unwrap();
+        assert_eq!(me.silver(), 300);
+
+        assert!(matches!(
+            me.spend_silver(301),
+            Err(Error::NotEnoughSilver(300, 301))
+        ));
+        assert_eq!(me.silver(), 300);
+
+        let room = constants::MAX_SILVER - 300;
+        assert!(matches!(
+            me.add_silver(room + 1),
+            Err(Error::SilverOverflow(300, _))
+        ));
+        assert!(me.add_silver(u64::MAX).is_err());
+        assert_eq!(me.silver(), 300);
+        me.add_silver(room).unwrap();
+        assert_eq!(me.silver(), constants::MAX_SILVER);
+    }
+
+    #[test]
+    fn position_reads_are_never_torn() {
+        let c = Arc::new(make_character(1));
+        c.set_position(0, 0);
+        let writer = {
+            let c = c.clone();
+            std::thread::spawn(move || {
+                for i in 0..100_000u32 {
+                    let v = (i % 1000) as u16;
+                    c.set_position(v, v);
+                }
+            })
+        };
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let c = c.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100_000 {
+                        let loc = c.entity().location();
+                        assert_eq!(loc.x, loc.y, "torn position read");
+                    }
+                })
+            })
+            .collect();
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn prev_position_is_the_one_right_before() {
+        let c = make_character(1);
+        c.set_position(10, 20);
+        c.set_position(11, 21);
+        assert_eq!((c.x(), c.y()), (11, 21));
+        assert_eq!((c.prev_x(), c.prev_y()), (10, 20));
+        c.set_position(15, 25);
+        assert_eq!((c.x(), c.y()), (15, 25));
+        assert_eq!((c.prev_x(), c.prev_y()), (11, 21));
+    }
+
+    #[test]
+    fn prev_position_moves_with_the_current_one() {
+        let c = Arc::new(make_character(1));
+        c.set_position(999, 999);
+        c.set_position(0, 0);
+        let writer = {
+            let c = c.clone();
+            std::thread::spawn(move || {
+                for i in 1..100_000u32 {
+                    let v = (i % 1000) as u16;
+                    c.set_position(v, v);
+                }
+            })
+        };
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let c = c.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100_000 {
+                        let Locations { current, prev } =
+                            c.entity().locations();
+                        assert_eq!(current.x, (prev.x + 1) % 1000);
+                    }
+                })
+            })
+            .collect();
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn save_writes_the_snapshot() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                me.set_position(123, 321);
+                let mut hp = me.hp();
+                hp.decrement(5);
+                me.entity().set_hp(hp);
+                me.gain_experience(7, Instant::now()).await?;
+
+                let snapshot = me.snapshot();
+                assert_eq!((snapshot.x, snapshot.y), (123, 321));
+                assert_eq!(snapshot.health_points as u16, hp.current());
+                assert_eq!(snapshot.experience as u64, me.experience());
+                assert_eq!(snapshot.character_id, me.character_id());
+
+                me.save(&state).await?;
+                let saved = tq_db::character::Character::by_id(
+                    state.pool(),
+                    me.character_id(),
+                )
+                .await?;
+                assert_eq!(CharacterRecord::from(saved), snapshot);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[test]
+    fn state_transitions() {
+        use CharacterState::*;
+        let c = make_character(1);
+        assert_eq!(c.state(), Alive);
+        assert_eq!(c.try_transition(Trading).unwrap(), Alive);
+        assert!(!c.state().can_be_attacked());
+        // Has to stop trading first.
+        assert!(matches!(
+            c.try_transition(Vending),
+            Err(Error::InvalidStateTransition(Trading, Vending))
+        ));
+        assert_eq!(c.state(), Trading);
+        c.try_transition(Alive).unwrap();
+        c.try_transition(Frozen).unwrap();
+        assert!(!c.state().can_move());
+        assert!(c.state().can_be_attacked());
+
+        c.try_transition(Dead).unwrap();
+        assert!(!c.is_alive());
+        assert!(c.entity().is_dead());
+        for to in [Dead, Frozen, Trading, Vending] {
+            assert!(c.try_transition(to).is_err(), "dead to {to:?}");
+        }
+        c.try_transition(Alive).unwrap();
+        assert!(c.is_alive());
+        assert!(c.entity().is_alive());
+    }
+
+    #[test]
+    fn allotment_total_mismatch() {
+        let c = make_character(1);
+        let err = c.apply_allotment([10, 10, 10, 10]).unwrap_err();
+        assert!(matches!(err, Error::InvalidAllotment(22, 40)));
+        // Nothing should change.
+        assert_eq!(c.strength(), 4);
+        assert_eq!(c.vitality(), 12);
+    }
+
+    #[test]
+    fn allotment_recomputes_max_hp() {
+        let c = make_character(3);
+        // Level 3 Trojan has 22 + 6 points.
+        c.apply_allotment([4, 6, 18, 0]).unwrap();
+        assert_eq!(c.vitality(), 18);
+        assert_eq!(c.max_health_points(), 4 * 3 + 6 * 3 + 18 * 24);
+        assert_eq!(c.max_mana_points(), 0);
+    }
+
+    #[test]
+    fn allotment_clamps_current_hp() {
+        let c = make_character(1);
+        let before = c.health_points();
+        assert_eq!(before, c.max_health_points());
+        let msg = c.apply_allotment([22, 0, 0, 0]).unwrap();
+        assert_eq!(c.max_health_points(), 22 * 3);
+        assert_eq!(c.health_points(), 22 * 3);
+        assert!(!msg.is_empty());
+    }
+
+    #[tokio::test]
+    async fn teleport_reconciles_screens() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let arena = u32::from(Maps::Arena);
+                let horse = u32::from(Maps::Horse);
+                for map_id in [arena, horse] {
+                    state
+                        .try_map(map_id)?
+                        .load_blank(Size::new(100, 100))
+                        .await?;
+                }
+                let [(a, mut a_rx), (b, mut b_rx)] = actors;
+                for (actor, x) in [(&a, 50), (&b, 52)] {
+                    let e = actor.entity();
+                    e.basic().set_map_id(arena);
+                    e.basic().set_location(Location::new(x, 50, 0));
+                    state.try_map(arena)?.insert_entity(e).await?;
+                }
+                a.screen().load_surroundings(&state).await?;
+                assert_eq!(packets_of::<MsgPlayer>(&mut a_rx).len(), 1);
+                assert_eq!(packets_of::<MsgPlayer>(&mut b_rx).len(), 1);
+
+                let a_id = a.entity().id();
+                let b_id = b.entity().id();
+                let me = a.entity();
+                let me = me.as_character().unwrap();
+                me.teleport(&state, horse, (30, 30)).await?;
+                // The old observer sees us leaving.
+                let leaves: Vec<_> = packets_of::<MsgAction>(&mut b_rx)
+                    .into_iter()
+                    .filter(|msg| {
+                        matches!(
+                            ActionType::from(msg.action_type),
+                            ActionType::LeaveMap
+                        )
+                    })
+                    .collect();
+                assert_eq!(leaves.len(), 1);
+                assert_eq!(leaves[0].character_id, a_id);
+                assert!(b.screen().with_entities(|c| !c.contains_key(&a_id)));
+                assert!(a.screen().with_entities(|c| c.is_empty()));
+
+                let other = b.entity();
+                let other = other.as_character().unwrap();
+                other.teleport(&state, horse, (32, 30)).await?;
+                // The new observer sees us coming, and we see them.
+                assert_eq!(packets_of::<MsgPlayer>(&mut a_rx).len(), 1);
+                assert_eq!(packets_of::<MsgPlayer>(&mut b_rx).len(), 1);
+                assert!(a.screen().with_entities(|c| c.contains_key(&b_id)));
+                assert!(b.screen().with_entities(|c| c.contains_key(&a_id)));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    /// How many regions of the map have the entity in them.
+    fn regions_with(map: &Map, id: u32) -> usize {
+        map.with_regions(|regions| {
+            regions
+                .iter()
+                .filter(|r| r.try_entities(id).is_some())
+                .count()
+        })
+    }
+
+    #[tokio::test]
+    async fn moves_update_region_and_screen_once() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let arena = u32::from(Maps::Arena);
+                let map = state.try_map(arena)?;
+                map.load_blank(Size::new(100, 100)).await?;
+                let [(a, mut a_rx), (b, mut b_rx)] = actors;
+                for (actor, x) in [(&a, 35), (&b, 40)] {
+                    let e = actor.entity();
+                    e.basic().set_map_id(arena);
+                    e.basic().set_location(Location::new(x, 40, 0));
+                    map.insert_entity(e).await?;
+                }
+                a.screen().load_surroundings(&state).await?;
+                packets_of::<MsgPlayer>(&mut a_rx);
+                packets_of::<MsgPlayer>(&mut b_rx);
+
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let a_id = me.id();
+                let step = |x: u16, y: u16| {
+                    let xy = u32::constract(y, x);
+                    MsgAction::new(a_id, xy, xy, 0, ActionType::Jump)
+                };
+                // Into the next region, still within the screen.
+                let moved =
+                    me.move_to(&state, (37, 40), 0, step(37, 40)).await?;
+                assert_eq!(moved, Moved::InScreen);
+                assert_eq!((me.x(), me.y()), (37, 40));
+                assert_eq!(me.entity().prev_location().x, 35);
+                assert_eq!(regions_with(&map, a_id), 1);
+                assert!(map
+                    .region(37, 40)
+                    .unwrap()
+                    .try_entities(a_id)
+                    .is_some());
+                assert_eq!(packets_of::<MsgAction>(&mut a_rx).len(), 1);
+                assert_eq!(packets_of::<MsgAction>(&mut b_rx).len(), 1);
+
+                // Too far for the screen, it gets reloaded.
+                let moved =
+                    me.move_to(&state, (90, 90), 0, step(90, 90)).await?;
+                assert_eq!(moved, Moved::Jumped);
+                assert_eq!(regions_with(&map, a_id), 1);
+                assert!(map
+                    .region(90, 90)
+                    .unwrap()
+                    .try_entities(a_id)
+                    .is_some());
+                assert_eq!(packets_of::<MsgAction>(&mut a_rx).len(), 1);
+                let left: Vec<_> =
+                    packets_of::<MsgAction>(&mut b_rx).into_iter().collect();
+                assert_eq!(left.len(), 1);
+                assert!(matches!(
+                    ActionType::from(left[0].action_type),
+                    ActionType::LeaveMap
+                ));
+                assert!(a.screen().with_entities(|c| c.is_empty()));
+                assert!(b.screen().with_entities(|c| !c.contains_key(&a_id)));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    /// Reads the flags sent in a single attribute [`MsgUserAttrib`].
+    fn sent_flags(msg: &MsgUserAttrib) -> Flags {
+        let attributes: Vec<_> = msg.attributes().collect();
+        assert_eq!(attributes.len(), 1);
+        assert_eq!(attributes[0].0, AttributeKind::Flags);
+        Flags::from_bits_retain(attributes[0].1)
+    }
+
+    #[tokio::test]
+    async fn timed_buff_applies_and_expires() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |_state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let kind = EffectKind::AttackBoost;
+                let duration = Duration::from_secs(10);
+                let applied =
+                    me.apply_status_effect(kind, 20, duration).await?;
+                assert_eq!(applied, Applied::Added);
+                assert!(me.entity().flags().contains(Flags::STIGMA));
+                let effects = me.status_effects();
+                assert_eq!(effects.fold(Stat::Attack, 100), 120);
+                let sent = packets_of::<MsgUserAttrib>(&mut a_rx);
+                assert_eq!(sent.len(), 1);
+                assert!(sent_flags(&sent[0]).contains(Flags::STIGMA));
+
+                // Nothing happens before it expires.
+                me.tick_status_effects(Instant::now()).await?;
+                assert!(effects.is_active(kind));
+                assert!(packets_of::<MsgUserAttrib>(&mut a_rx).is_empty());
+
+                let later = Instant::now() + duration;
+                me.tick_status_effects(later).await?;
+                assert!(!effects.is_active(kind));
+                assert!(!me.entity().flags().contains(Flags::STIGMA));
+                assert_eq!(effects.fold(Stat::Attack, 100), 100);
+                let sent = packets_of::<MsgUserAttrib>(&mut a_rx);
+                assert_eq!(sent.len(), 1);
+                assert!(!sent_flags(&sent[0]).contains(Flags::STIGMA));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    /// Reads the attributes sent in a [`MsgUserAttrib`].
+    fn sent_attributes(msg: &MsgUserAttrib) -> Vec<(AttributeKind, u64)> {
+        msg.attributes().collect()
+    }
+
+    #[tokio::test]
+    async fn small_experience_gains_are_batched() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let window = state.experience_window();
+                let start = me.experience();
+                let now = Instant::now();
+                for i in 0..3 {
+                    me.gain_experience(10, now + window / 4 * i).await?;
+                }
+                me.flush_experience(now + window / 2, window).await?;
+                assert!(packets_of::<MsgUserAttrib>(&mut a_rx).is_empty());
+
+                me.flush_experience(now + window, window).await?;
+                let sent = packets_of::<MsgUserAttrib>(&mut a_rx);
+                assert_eq!(sent.len(), 1);
+                assert_eq!(
+                    sent_attributes(&sent[0]),
+                    [(AttributeKind::Experience, start + 30)]
+                );
+                // Nothing left to flush.
+                me.flush_experience(now + window * 2, window).await?;
+                assert!(packets_of::<MsgUserAttrib>(&mut a_rx).is_empty());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn level_up_flushes_right_away() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let level = me.entity().level();
+                let points = me.attribute_points();
+                let now = Instant::now();
+                me.gain_experience(10, now).await?;
+                let needed = constants::level_up_experience(level).unwrap();
+                let overflow = 5;
+                let rest = needed - me.experience() + overflow;
+                me.gain_experience(rest, now).await?;
+
+                assert_eq!(me.entity().level(), level + 1);
+                assert_eq!(me.experience(), overflow);
+                let sent = packets_of::<MsgUserAttrib>(&mut a_rx);
+                assert_eq!(sent.len(), 1);
+                let points = points + constants::ATTRIBUTE_POINTS_PER_LEVEL;
+                assert_eq!(
+                    sent_attributes(&sent[0]),
+                    [
+                        (AttributeKind::Level, level as u64 + 1),
+                        (AttributeKind::AttributePoints, points as u64),
+                        (AttributeKind::Experience, overflow),
+                    ]
+                );
+                // The level up sent everything, nothing is left batched.
+                let window = state.experience_window();
+                me.flush_experience(now + window, window).await?;
+                assert!(packets_of::<MsgUserAttrib>(&mut a_rx).is_empty());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+
+/// The persistent part of a [`Character`](super::Character), as it is stored
+/// in the database.
+///
+/// The live character only keeps the fields that rarely change in here, the
+/// hot ones live in atomics, use
+/// [`Character::snapshot`](super::Character::snapshot) to get an up to date
+/// record.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CharacterRecord {
+    pub character_id: i32,
+    pub account_id: i32,
+    pub realm_id: i32,
+    pub name: String,
+    pub mesh: i32,
+    pub avatar: i16,
+    pub hair_style: i16,
+    pub silver: i64,
+    pub cps: i64,
+    pub current_class: i16,
+    pub previous_class: i16,
+    pub rebirths: i16,
+    pub level: i16,
+    pub experience: i64,
+    pub map_id: i32,
+    pub x: i16,
+    pub y: i16,
+    pub virtue: i16,
+    pub strength: i16,
+    pub agility: i16,
+    pub vitality: i16,
+    pub spirit: i16,
+    pub attribute_points: i16,
+    pub health_points: i16,
+    pub mana_points: i16,
+    pub kill_points: i16,
+    pub received_starter_kit: bool,
+    pub spouse: String,
+    pub rebirth_points: i16,
+}
+
+impl From<tq_db::character::Character> for CharacterRecord {
+    fn from(v: tq_db::character::Character) -> Self {
+        Self {
+            character_id: v.character_id,
+            account_id: v.account_id,
+            realm_id: v.realm_id,
+            name: v.name,
+            mesh: v.mesh,
+            avatar: v.avatar,
+            hair_style: v.hair_style,
+            silver: v.silver,
+            cps: v.cps,
+            current_class: v.current_class,
+            previous_class: v.previous_class,
+            rebirths: v.rebirths,
+            level: v.level,
+            experience: v.experience,
+            map_id: v.map_id,
+            x: v.x,
+            y: v.y,
+            virtue: v.virtue,
+            strength: v.strength,
+            agility: v.agility,
+            vitality: v.vitality,
+            spirit: v.spirit,
+            attribute_points: v.attribute_points,
+            health_points: v.health_points,
+            mana_points: v.mana_points,
+            kill_points: v.kill_points,
+            received_starter_kit: v.received_starter_kit,
+            spouse: v.spouse,
+            rebirth_points: v.rebirth_points,
+        }
+    }
+}
+
+impl From<CharacterRecord> for tq_db::character::Character {
+    fn from(v: CharacterRecord) -> Self {
+        Self {
+            character_id: v.character_id,
+            account_id: v.account_id,
+            realm_id: v.realm_id,
+            name: v.name,
+            mesh: v.mesh,
+            avatar: v.avatar,
+            hair_style: v.hair_style,
+            silver: v.silver,
+            cps: v.cps,
+            current_class: v.current_class,
+            previous_class: v.previous_class,
+            rebirths: v.rebirths,
+            level: v.level,
+            experience: v.experience,
+            map_id: v.map_id,
+            x: v.x,
+            y: v.y,
+            virtue: v.virtue,
+            strength: v.strength,
+            agility: v.agility,
+            vitality: v.vitality,
+            spirit: v.spirit,
+            attribute_points: v.attribute_points,
+            health_points: v.health_points,
+            mana_points: v.mana_points,
+            kill_points: v.kill_points,
+            received_starter_kit: v.received_starter_kit,
+            spouse: v.spouse,
+            rebirth_points: v.rebirth_points,
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use bytemuck::NoUninit;
+
+/// What the character is up to, it decides what the character is allowed to
+/// do and what could be done to it.
+///
+/// The state only changes through
+/// [`Character::try_transition`](super::Character::try_transition), which
+/// refuses the changes that make no sense, like a dead character starting to
+/// trade.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, NoUninit)]
+#[repr(u8)]
+pub enum CharacterState {
+    #[default]
+    Alive,
+    Dead,
+    /// Could not move nor attack, but still could get hurt.
+    Frozen,
+    Trading,
+    /// Selling items in a booth.
+    Vending,
+}
+
+impl CharacterState {
+    /// Whether the character could go from this state to `to`.
+    ///
+    /// Everything goes through [`Self::Alive`], except for dying which could
+    /// happen at any time; the dead only get back by being revived.
+    pub const fn can_become(self, to: Self) -> bool {
+        use CharacterState::*;
+        matches!(
+            (self, to),
+            (Alive, Dead | Frozen | Trading | Vending)
+                | (Frozen | Trading | Vending, Alive | Dead)
+                | (Dead, Alive)
+        )
+    }
+
+    /// Whether the character could walk or jump around.
+    pub const fn can_move(self) -> bool {
+        matches!(self, Self::Alive | Self::Trading)
+    }
+
+    pub const fn can_attack(self) -> bool { matches!(self, Self::Alive) }
+
+    pub const fn can_be_attacked(self) -> bool {
+        matches!(self, Self::Alive | Self::Frozen)
+    }
+}
@@ -1,19 +1,152 @@
-pub trait FloorItem: Default {
-    fn money(&self) -> u32;
-    fn map_id(&self) -> u32;
-    fn x(&self) -> u16;
-    fn y(&self) -> u16;
+use primitives::Location;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+use crate::entities::{Character, Entity};
+use crate::packets::MsgMapItem;
+use crate::{constants, systems, Error};
+
+/// How long after being dropped an item is kept for whoever it was dropped
+/// for.
+pub const OWNER_PROTECTION: Duration = Duration::from_secs(10);
+
+/// How long an item lies on the floor before it vanishes.
+pub const FLOOR_ITEM_EXPIRY: Duration = Duration::from_secs(60);
+
+/// What is lying on the ground.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FloorItemKind {
+    /// An item with the given item type.
+    Item(u32),
+    /// A pile of silver.
+    Silver(u32),
+}
+
+/// An item lying on the floor of a map, it could be an item that got dropped
+/// by a killed monster, or some silver.
+#[derive(Debug)]
+pub struct FloorItem {
+    entity: Entity,
+    kind: FloorItemKind,
+    dropped_at: Instant,
+    /// The character the item was dropped for, if any.
+    owner: Option<u32>,
 }
 
-#[derive(Debug, Clone, Default)]
-pub struct Item;
+impl FloorItem {
+    /// Creates a new floor item, the id should be allocated from the
+    /// [`IdAllocator`](crate::state::IdAllocator) as a
+    /// [`IdKind::FloorItem`](crate::state::IdKind::FloorItem).
+    pub fn new(
+        id: u32,
+        kind: FloorItemKind,
+        map_id: u32,
+        location: Location,
+    ) -> Self {
+        debug_assert!(constants::is_floor_item(id));
+        let item_type = item_type_of(kind);
+        Self {
+            entity: Entity::new(id, String::new(), item_type, map_id, location),
+            kind,
+            dropped_at: Instant::now(),
+            owner: None,
+        }
+    }
+
+    /// The same item, kept for the given character for a while, see
+    /// [`OWNER_PROTECTION`].
+    pub fn with_owner(self, character_id: u32) -> Self {
+        Self {
+            owner: Some(character_id),
+            ..self
+        }
+    }
+
+    /// The same item, dropped at another time, like an item restored from
+    /// a snapshot that keeps its age.
+    pub fn with_dropped_at(self, dropped_at: Instant) -> Self {
+        Self { dropped_at, ..self }
+    }
+
+    #[inline]
+    pub fn id(&self) -> u32 { self.entity.id() }
+
+    #[inline]
+    pub fn entity(&self) -> &Entity { &self.entity }
+
+    pub fn kind(&self) -> FloorItemKind { self.kind }
 
-impl FloorItem for Item {
-    fn money(&self) -> u32 { 0 }
+    pub fn map_id(&self) -> u32 { self.entity.map_id() }
 
-    fn map_id(&self) -> u32 { 0 }
+    pub fn location(&self) -> Location { self.entity.location() }
 
-    fn x(&self) -> u16 { 0 }
+    pub fn dropped_at(&self) -> Instant { self.dropped_at }
+
+    pub fn owner(&self) -> Option<u32> { self.owner }
+
+    /// Whether the item is still kept for the given character, the client
+    /// highlights it as theirs.
+    pub fn is_protected_for(&self, character_id: u32) -> bool {
+        self.owner == Some(character_id)
+            && self.dropped_at.elapsed() < OWNER_PROTECTION
+    }
+
+    /// Whether the item lay there for longer than [`FLOOR_ITEM_EXPIRY`].
+    pub fn is_expired(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.dropped_at) >= FLOOR_ITEM_EXPIRY
+    }
+
+    /// Returns the amount of silver in that pile, or zero if it is an item.
+    pub fn money(&self) -> u32 {
+        match self.kind {
+            FloorItemKind::Silver(amount) => amount,
+            FloorItemKind::Item(_) => 0,
+        }
+    }
+
+    /// The item type the client uses to render this item, silver piles use
+    /// different item types based on the amount.
+    pub fn item_type(&self) -> u32 { item_type_of(self.kind) }
+
+    /// The color the client renders the item with, gems are colored by
+    /// their grade and meteor tears apart from meteors.
+    pub fn color(&self) -> u8 {
+        match self.kind {
+            FloorItemKind::Item(item_type) => color_of(item_type),
+            FloorItemKind::Silver(_) => 0,
+        }
+    }
+
+    #[tracing::instrument(skip(self, to), fields(item = self.id()))]
+    pub(super) async fn send_spawn(&self, to: &Character) -> Result<(), Error> {
+        to.owner()
+            .send(MsgMapItem::create_for(self, to.id()))
+            .await?;
+        Ok(())
+    }
+}
+
+fn color_of(item_type: u32) -> u8 {
+    if let Some(gem) = systems::gem_of(item_type) {
+        // The units are the grade of the gem.
+        return gem % 10;
+    }
+    match item_type {
+        constants::METEOR_TEAR => 1,
+        _ => 0,
+    }
+}
 
-    fn y(&self) -> u16 { 0 }
+fn item_type_of(kind: FloorItemKind) -> u32 {
+    match kind {
+        FloorItemKind::Item(item_type) => item_type,
+        FloorItemKind::Silver(amount) => match amount {
+            0..=9 => 1090000,
+            10..=99 => 1090010,
+            100..=999 => 1090020,
+            1000..=2999 => 1091000,
+            3000..=9999 => 1091010,
+            _ => 1091020,
+        },
+    }
 }
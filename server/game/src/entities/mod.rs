@@ -2,21 +2,35 @@ use crate::Error;
 use tq_network::ActorHandle;
 
 mod floor_item;
-pub use floor_item::{FloorItem, Item};
+pub use floor_item::{FloorItem, FloorItemKind, FLOOR_ITEM_EXPIRY};
 
 mod basic;
-pub use basic::Entity;
+pub use basic::{Entity, Flags, Locations};
 
 mod character;
-pub use character::Character;
+pub use character::{with_two_characters, Character, Moved};
+
+mod character_state;
+pub use character_state::CharacterState;
+
+mod character_record;
+pub use character_record::CharacterRecord;
 
 mod npc;
 pub use npc::{Npc, NpcBase, NpcKind, NpcSort};
 
+mod monster;
+pub use monster::{Monster, MonsterStats, MonsterType};
+
+// Game entities always live behind an `Arc`, so boxing the bigger variants
+// would not save anything.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum GameEntity {
     Character(Character),
     Npc(Npc),
+    FloorItem(FloorItem),
+    Monster(Monster),
 }
 
 impl From<Character> for GameEntity {
@@ -27,12 +41,22 @@ impl From<Npc> for GameEntity {
     fn from(v: Npc) -> Self { Self::Npc(v) }
 }
 
+impl From<FloorItem> for GameEntity {
+    fn from(v: FloorItem) -> Self { Self::FloorItem(v) }
+}
+
+impl From<Monster> for GameEntity {
+    fn from(v: Monster) -> Self { Self::Monster(v) }
+}
+
 impl GameEntity {
     /// Returns the ID of the Game Entity.
     pub fn id(&self) -> u32 {
         match self {
             Self::Character(v) => v.id(),
             Self::Npc(v) => v.id(),
+            Self::FloorItem(v) => v.id(),
+            Self::Monster(v) => v.id(),
         }
     }
 
@@ -42,7 +66,7 @@ impl GameEntity {
     pub fn owner(&self) -> Option<ActorHandle> {
         match self {
             Self::Character(v) => Some(v.owner()),
-            Self::Npc(..) => None,
+            Self::Npc(..) | Self::FloorItem(..) | Self::Monster(..) => None,
         }
     }
 
@@ -50,11 +74,19 @@ impl GameEntity {
         match self {
             Self::Character(v) => v.entity(),
             Self::Npc(v) => v.entity(),
+            Self::FloorItem(v) => v.entity(),
+            Self::Monster(v) => v.entity(),
         }
     }
 
-    /// This method sends the spawn packet to another entity
+    /// This method sends the spawn packet to another entity, unless it is a
+    /// character still loading into the world, it gets everything in its
+    /// screen once done.
     pub async fn send_spawn(&self, to: &Self) -> Result<(), Error> {
+        if to.as_character().is_some_and(|c| !c.in_world()) {
+            tracing::trace!(id = self.id(), to = to.id(), "Spawn held back");
+            return Ok(());
+        }
         match (self, to) {
             (Self::Character(from), Self::Character(to)) => {
                 from.send_spawn(&to.owner()).await
@@ -62,6 +94,12 @@ impl GameEntity {
             (Self::Npc(from), Self::Character(to)) => {
                 from.send_spawn(&to.owner()).await
             },
+            (Self::FloorItem(from), Self::Character(to)) => {
+                from.send_spawn(to).await
+            },
+            (Self::Monster(from), Self::Character(to)) => {
+                from.send_spawn(&to.owner()).await
+            },
             _ => todo!("send_spawn for non-character entities"),
         }
     }
@@ -93,4 +131,32 @@ impl GameEntity {
             None
         }
     }
+
+    /// Returns `true` if the game entity is [`FloorItem`].
+    ///
+    /// [`FloorItem`]: GameEntity::FloorItem
+    #[must_use]
+    pub fn is_floor_item(&self) -> bool { matches!(self, Self::FloorItem(..)) }
+
+    pub fn as_floor_item(&self) -> Option<&FloorItem> {
+        if let Self::FloorItem(v) = self {
+            Some(v)
+        } else {
+            None
+        }
+    }
+
+    /// Returns `true` if the game entity is [`Monster`].
+    ///
+    /// [`Monster`]: GameEntity::Monster
+    #[must_use]
+    pub fn is_monster(&self) -> bool { matches!(self, Self::Monster(..)) }
+
+    pub fn as_monster(&self) -> Option<&Monster> {
+        if let Self::Monster(v) = self {
+            Some(v)
+        } else {
+            None
+        }
+    }
 }
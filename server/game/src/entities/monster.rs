@@ -0,0 +1,129 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use primitives::{Gauge, Location};
+use tq_network::ActorHandle;
+
+use crate::entities::Entity;
+use crate::packets::MsgPlayer;
+use crate::systems::DropTable;
+use crate::{constants, Error};
+
+/// The stats every monster of a [`MonsterType`] starts with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MonsterStats {
+    /// How the monster looks like in the client.
+    pub lookface: u32,
+    pub level: u16,
+    /// The maximum health points.
+    pub life: u16,
+    /// How far the monster could hit from, in tiles.
+    pub attack_range: u8,
+    /// How long the monster takes to move one tile.
+    pub move_speed: Duration,
+    /// How far the monster could see players from, in tiles.
+    pub view_range: u8,
+}
+
+impl Default for MonsterStats {
+    fn default() -> Self {
+        Self {
+            lookface: 0,
+            level: 1,
+            life: 1,
+            attack_range: 1,
+            move_speed: Duration::from_millis(1000),
+            view_range: 15,
+        }
+    }
+}
+
+/// Describes a kind of monster, every monster spawned in the world is an
+/// instance of one of these types.
+#[derive(Debug, Clone, Default)]
+pub struct MonsterType {
+    id: u32,
+    name: String,
+    stats: MonsterStats,
+    drops: DropTable,
+}
+
+impl MonsterType {
+    pub fn new(id: u32, name: impl Into<String>) -> Self {
+        Self {
+            id,
+            name: name.into(),
+            stats: MonsterStats::default(),
+            drops: DropTable::default(),
+        }
+    }
+
+    /// Sets the stats of this monster type.
+    pub fn with_stats(mut self, stats: MonsterStats) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Attaches a drop table to this monster type.
+    pub fn with_drops(mut self, drops: DropTable) -> Self {
+        self.drops = drops;
+        self
+    }
+
+    pub fn id(&self) -> u32 { self.id }
+
+    pub fn name(&self) -> &str { &self.name }
+
+    pub fn stats(&self) -> &MonsterStats { &self.stats }
+
+    /// What this monster could drop when it gets killed.
+    pub fn drops(&self) -> &DropTable { &self.drops }
+}
+
+/// A monster spawned on a map.
+#[derive(Debug)]
+pub struct Monster {
+    entity: Entity,
+    kind: Arc<MonsterType>,
+}
+
+impl Monster {
+    /// Spawns a new monster of the given type with full health, the id
+    /// should be allocated from the
+    /// [`IdAllocator`](crate::state::IdAllocator) as a
+    /// [`IdKind::Monster`](crate::state::IdKind::Monster).
+    pub fn new(
+        id: u32,
+        kind: Arc<MonsterType>,
+        map_id: u32,
+        location: Location,
+    ) -> Self {
+        debug_assert!(constants::is_monster(id));
+        let stats = kind.stats();
+        let entity = Entity::new(
+            id,
+            kind.name().to_owned(),
+            stats.lookface,
+            map_id,
+            location,
+        );
+        entity
+            .set_level(stats.level)
+            .set_hp(Gauge::full(stats.life));
+        Self { entity, kind }
+    }
+
+    #[inline]
+    pub fn id(&self) -> u32 { self.entity.id() }
+
+    #[inline]
+    pub fn entity(&self) -> &Entity { &self.entity }
+
+    /// The type this monster is an instance of.
+    pub fn kind(&self) -> &Arc<MonsterType> { &self.kind }
+
+    pub async fn send_spawn(&self, to: &ActorHandle) -> Result<(), Error> {
+        to.send(MsgPlayer::from_monster(self)).await?;
+        Ok(())
+    }
+}
@@ -119,6 +119,12 @@ impl Npc {
 
     pub fn is_booth(&self) -> bool { self.kind == NpcKind::Booth }
 
+    /// The pole of the guild war, see [`crate::events::guild_war`].
+    pub fn is_pole(&self) -> bool { self.kind == NpcKind::SynFlag }
+
+    /// A gate of the castle, see [`crate::events::guild_war`].
+    pub fn is_gate(&self) -> bool { self.kind == NpcKind::CityGate }
+
     #[tracing::instrument(skip(self, to), fields(npc = self.entity.id()))]
     pub(super) async fn send_spawn(
         &self,
@@ -1,7 +1,10 @@
 use bytes::Bytes;
+use sqlx::error::ErrorKind;
+use std::future::Future;
+use std::time::Duration;
 use thiserror::Error;
 use tokio::sync::{mpsc, oneshot};
-use tq_network::{ErrorPacket, PacketEncode};
+use tq_network::{Codec, ErrorAction, ErrorPacket, HandlerError, PacketEncode};
 
 use crate::packets::MsgTalk;
 
@@ -18,9 +21,17 @@ pub enum Error {
     #[error(transparent)]
     Env(#[from] std::env::VarError),
     #[error(transparent)]
-    Sqlx(#[from] sqlx::Error),
+    Sqlx(sqlx::Error),
     #[error(transparent)]
-    Db(#[from] tq_db::Error),
+    Db(tq_db::Error),
+    #[error("Name already taken: {}", _0)]
+    DuplicateName(String),
+    #[error("Name not allowed: {}", _0)]
+    NameNotAllowed(String),
+    #[error("Foreign key violation: {}", _0)]
+    ForeignKeyViolation(String),
+    #[error("Constraint violation: {}", _0)]
+    ConstraintViolation(String),
     #[error("State Error: {}", _0)]
     State(&'static str),
     #[error(transparent)]
@@ -33,10 +44,14 @@ pub enum Error {
     ParseInt(#[from] std::num::ParseIntError),
     #[error(transparent)]
     ParseFloat(#[from] std::num::ParseFloatError),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
     #[error("{}", _0)]
     Other(String),
-    #[error("Msg {}", _0)]
-    Msg(u16, Bytes),
+    /// A message told to the player, kept as a packet so it gets encoded
+    /// with the codec of whoever it goes to.
+    #[error("Msg {}", _0.message)]
+    Msg(Box<MsgTalk>),
     #[error("Map Region not found!")]
     MapRegionNotFound,
     #[error("Map not found!")]
@@ -51,6 +66,8 @@ pub enum Error {
     CharacterNotFound,
     #[error("Screen not found!")]
     ScreenNotFound,
+    #[error("Not in the world yet!")]
+    NotInWorld,
     #[error("Map Tile Not found at ({0}, {1})!")]
     TileNotFound(u16, u16),
     #[error("Invalid Scene File Name!")]
@@ -59,6 +76,92 @@ pub enum Error {
     InvalidBodyType,
     #[error("Invalid Class!")]
     InvalidClass,
+    #[error("Invalid Allotment, expected {0} points but got {1}!")]
+    InvalidAllotment(u16, u16),
+    #[error("Not enough attribute points, {0} left but spending {1}!")]
+    NotEnoughAttributePoints(u16, u16),
+    #[error("Not enough silver, {0} left but spending {1}!")]
+    NotEnoughSilver(u64, u64),
+    #[error("Too much silver, {0} and {1} more is over the cap!")]
+    SilverOverflow(u64, u64),
+    #[error("Invalid character state transition from {0:?} to {1:?}!")]
+    InvalidStateTransition(
+        crate::entities::CharacterState,
+        crate::entities::CharacterState,
+    ),
+    #[error("Invalid map region size {0}!")]
+    InvalidRegionSize(primitives::Size<u32>),
+    #[error("Ran out of {0:?} ids!")]
+    IdsExhausted(crate::state::IdKind),
+    #[error("Script Error: {}", _0)]
+    Script(#[from] Box<rhai::EvalAltResult>),
+}
+
+impl Error {
+    /// Whether trying again a bit later could work, like when the database
+    /// could not be reached for a moment, unlike a bug or a missing row.
+    pub fn is_transient(&self) -> bool {
+        use sqlx::Error as Sqlx;
+        matches!(
+            self,
+            Self::Sqlx(Sqlx::PoolTimedOut | Sqlx::Io(_) | Sqlx::WorkerCrashed)
+                | Self::IO(_)
+        )
+    }
+}
+
+/// Runs `f` up to `attempts` times, waiting `delay` in between, for as long
+/// as it fails with a transient error, see [`Error::is_transient`].
+pub async fn retry_transient<T, F, Fut>(
+    attempts: u32,
+    delay: Duration,
+    mut f: F,
+) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Err(error) if error.is_transient() && attempt < attempts => {
+                tracing::warn!(%error, attempt, "Transient error, retrying");
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            },
+            res => return res,
+        }
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    /// Violated constraints get their own variants, so a taken name could be
+    /// told apart from the database going away.
+    fn from(e: sqlx::Error) -> Self {
+        let Some(db) = e.as_database_error() else {
+            return Self::Sqlx(e);
+        };
+        let message = db.message().to_owned();
+        match db.kind() {
+            ErrorKind::UniqueViolation => Self::DuplicateName(message),
+            ErrorKind::ForeignKeyViolation => {
+                Self::ForeignKeyViolation(message)
+            },
+            ErrorKind::NotNullViolation | ErrorKind::CheckViolation => {
+                Self::ConstraintViolation(message)
+            },
+            _ => Self::Sqlx(e),
+        }
+    }
+}
+
+impl From<tq_db::Error> for Error {
+    fn from(e: tq_db::Error) -> Self {
+        match e {
+            tq_db::Error::Db(e) => e.into(),
+            e => Self::Db(e),
+        }
+    }
 }
 
 impl<T> From<mpsc::error::SendError<T>> for Error {
@@ -69,11 +172,8 @@ impl From<oneshot::error::RecvError> for Error {
     fn from(_: oneshot::error::RecvError) -> Self { Self::RecvError }
 }
 
-impl<T: PacketEncode> From<ErrorPacket<T>> for Error {
-    fn from(v: ErrorPacket<T>) -> Self {
-        let (id, bytes) = v.0.encode().unwrap();
-        Self::Msg(id, bytes)
-    }
+impl From<ErrorPacket<MsgTalk>> for Error {
+    fn from(v: ErrorPacket<MsgTalk>) -> Self { Self::Msg(Box::new(v.0)) }
 }
 
 impl PacketEncode for Error {
@@ -81,15 +181,19 @@ impl PacketEncode for Error {
     type Packet = ();
 
     fn encode(&self) -> Result<(u16, Bytes), Self::Error> {
+        self.encode_with(Codec::Binary)
+    }
+
+    fn encode_with(&self, codec: Codec) -> Result<(u16, Bytes), Self::Error> {
         match self {
-            Self::Msg(id, bytes) => Ok((*id, bytes.clone())),
+            Self::Msg(msg) => Ok(msg.encode_with(codec)?),
             Self::MapNotFound => {
                 let msg = MsgTalk::from_system(
                     0,
                     crate::packets::TalkChannel::TopLeft,
                     "Map not found!",
                 );
-                let (id, bytes) = msg.encode()?;
+                let (id, bytes) = msg.encode_with(codec)?;
                 Ok((id, bytes))
             },
             Self::MapRegionNotFound => {
@@ -98,7 +202,7 @@ impl PacketEncode for Error {
                     crate::packets::TalkChannel::TopLeft,
                     "Map Region not found!",
                 );
-                let (id, bytes) = msg.encode()?;
+                let (id, bytes) = msg.encode_with(codec)?;
                 Ok((id, bytes))
             },
             Self::LoginTokenNotFound => {
@@ -107,7 +211,7 @@ impl PacketEncode for Error {
                     crate::packets::TalkChannel::Login,
                     "Login Token not found!",
                 );
-                let (id, bytes) = msg.encode()?;
+                let (id, bytes) = msg.encode_with(codec)?;
                 Ok((id, bytes))
             },
             Self::CreationTokenNotFound => {
@@ -116,7 +220,7 @@ impl PacketEncode for Error {
                     crate::packets::TalkChannel::Register,
                     "Creation Token not found!",
                 );
-                let (id, bytes) = msg.encode()?;
+                let (id, bytes) = msg.encode_with(codec)?;
                 Ok((id, bytes))
             },
             Self::RealmNotFound => {
@@ -125,7 +229,7 @@ impl PacketEncode for Error {
                     crate::packets::TalkChannel::Login,
                     "Realm not found!",
                 );
-                let (id, bytes) = msg.encode()?;
+                let (id, bytes) = msg.encode_with(codec)?;
                 Ok((id, bytes))
             },
             Self::CharacterNotFound => {
@@ -134,7 +238,25 @@ impl PacketEncode for Error {
                     crate::packets::TalkChannel::TopLeft,
                     "Character not found!",
                 );
-                let (id, bytes) = msg.encode()?;
+                let (id, bytes) = msg.encode_with(codec)?;
+                Ok((id, bytes))
+            },
+            Self::DuplicateName(_) => {
+                let msg = MsgTalk::from_system(
+                    0,
+                    crate::packets::TalkChannel::TopLeft,
+                    "Name already taken!",
+                );
+                let (id, bytes) = msg.encode_with(codec)?;
+                Ok((id, bytes))
+            },
+            Self::NameNotAllowed(_) => {
+                let msg = MsgTalk::from_system(
+                    0,
+                    crate::packets::TalkChannel::TopLeft,
+                    "Name not allowed!",
+                );
+                let (id, bytes) = msg.encode_with(codec)?;
                 Ok((id, bytes))
             },
             Self::ScreenNotFound => {
@@ -143,7 +265,7 @@ impl PacketEncode for Error {
                     crate::packets::TalkChannel::TopLeft,
                     "Screen not found!",
                 );
-                let (id, bytes) = msg.encode()?;
+                let (id, bytes) = msg.encode_with(codec)?;
                 Ok((id, bytes))
             },
             Self::TileNotFound(x, y) => {
@@ -152,7 +274,7 @@ impl PacketEncode for Error {
                     crate::packets::TalkChannel::TopLeft,
                     format!("Map Tile Not found at ({}, {})!", x, y),
                 );
-                let (id, bytes) = msg.encode()?;
+                let (id, bytes) = msg.encode_with(codec)?;
                 Ok((id, bytes))
             },
             Self::InvalidSceneFileName => {
@@ -161,7 +283,7 @@ impl PacketEncode for Error {
                     crate::packets::TalkChannel::TopLeft,
                     "Invalid Scene File Name!",
                 );
-                let (id, bytes) = msg.encode()?;
+                let (id, bytes) = msg.encode_with(codec)?;
                 Ok((id, bytes))
             },
             Self::InvalidBodyType => {
@@ -170,7 +292,7 @@ impl PacketEncode for Error {
                     crate::packets::TalkChannel::Register,
                     "Invalid Body Type!",
                 );
-                let (id, bytes) = msg.encode()?;
+                let (id, bytes) = msg.encode_with(codec)?;
                 Ok((id, bytes))
             },
             Self::InvalidClass => {
@@ -179,7 +301,30 @@ impl PacketEncode for Error {
                     crate::packets::TalkChannel::Register,
                     "Invalid Class!",
                 );
-                let (id, bytes) = msg.encode()?;
+                let (id, bytes) = msg.encode_with(codec)?;
+                Ok((id, bytes))
+            },
+            Self::InvalidAllotment(expected, got) => {
+                let msg = MsgTalk::from_system(
+                    0,
+                    crate::packets::TalkChannel::TopLeft,
+                    format!(
+                        "Invalid Allotment, expected {} points but got {}!",
+                        expected, got
+                    ),
+                );
+                let (id, bytes) = msg.encode_with(codec)?;
+                Ok((id, bytes))
+            },
+            Self::NotEnoughAttributePoints(left, spent) => {
+                let msg = MsgTalk::from_system(
+                    0,
+                    crate::packets::TalkChannel::TopLeft,
+                    format!(
+                        "You only have {left} attribute points, not {spent}!"
+                    ),
+                );
+                let (id, bytes) = msg.encode_with(codec)?;
                 Ok((id, bytes))
             },
             e => Err(Self::Other(e.to_string())),
@@ -187,6 +332,186 @@ impl PacketEncode for Error {
     }
 }
 
+impl HandlerError for Error {
+    /// The errors the player could do something about are told to them, the
+    /// connection going away ends it, and anything else is only logged.
+    fn action(&self) -> ErrorAction {
+        match self {
+            Self::Network(e) => e.action(),
+            Self::IO(_) | Self::SendError | Self::RecvError => {
+                ErrorAction::Disconnect
+            },
+            Self::Msg(..)
+            | Self::MapNotFound
+            | Self::MapRegionNotFound
+            | Self::LoginTokenNotFound
+            | Self::CreationTokenNotFound
+            | Self::RealmNotFound
+            | Self::CharacterNotFound
+            | Self::DuplicateName(_)
+            | Self::NameNotAllowed(_)
+            | Self::ScreenNotFound
+            | Self::TileNotFound(..)
+            | Self::InvalidSceneFileName
+            | Self::InvalidBodyType
+            | Self::InvalidClass
+            | Self::InvalidAllotment(..)
+            | Self::NotEnoughAttributePoints(..) => ErrorAction::Reply,
+            _ => ErrorAction::Log,
+        }
+    }
+}
+
 impl From<Error> for tq_network::Error {
-    fn from(v: Error) -> Self { Self::Other(v.to_string()) }
+    fn from(v: Error) -> Self {
+        match v {
+            Error::Network(e) => e,
+            e => Self::Handler(Box::new(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use futures::FutureExt;
+    use tq_network::{PacketDecode, PacketID};
+
+    #[tokio::test]
+    async fn violated_constraints_are_told_apart() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, _| {
+            async move {
+                // The first test account is already there.
+                let (username,) = sqlx::query_as::<_, (String,)>(
+                    "SELECT username FROM accounts WHERE account_id = 1;",
+                )
+                .fetch_one(state.pool())
+                .await?;
+                let res = sqlx::query(
+                    "INSERT INTO accounts (username, password) VALUES (?, '');",
+                )
+                .bind(username)
+                .execute(state.pool())
+                .await;
+                let e = Error::from(res.unwrap_err());
+                assert!(matches!(e, Error::DuplicateName(_)), "{e:?}");
+
+                let res = sqlx::query(
+                    "INSERT INTO accounts (username, password) VALUES (?, '');",
+                )
+                .bind("a".repeat(17))
+                .execute(state.pool())
+                .await;
+                let e = Error::from(res.unwrap_err());
+                assert!(matches!(e, Error::ConstraintViolation(_)), "{e:?}");
+
+                // Anything else is left as it is.
+                let res = sqlx::query("SELECT * FROM nowhere;")
+                    .execute(state.pool())
+                    .await;
+                let e = Error::from(res.unwrap_err());
+                assert!(matches!(e, Error::Sqlx(_)), "{e:?}");
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn database_errors_are_mapped_through_tq_db() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, _| {
+            async move {
+                let mut character =
+                    tq_db::character::Character::from_account(state.pool(), 1)
+                        .await?
+                        .ok_or(Error::CharacterNotFound)?;
+                character.account_id = 4242;
+                character.name = "orphan".into();
+                let e = Error::from(
+                    character.save(state.pool()).await.unwrap_err(),
+                );
+                assert!(matches!(e, Error::ForeignKeyViolation(_)), "{e:?}");
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn transient_database_errors_are_retried() {
+        let mut attempts = 0;
+        let res = retry_transient(3, Duration::from_millis(250), || {
+            attempts += 1;
+            let res = match attempts {
+                1 => Err(Error::Sqlx(sqlx::Error::PoolTimedOut)),
+                _ => Ok(attempts),
+            };
+            async move { res }
+        })
+        .await;
+        assert_eq!(res.unwrap(), 2);
+
+        // Bugs are not going away by trying again.
+        let mut attempts = 0;
+        let res: Result<(), _> =
+            retry_transient(3, Duration::from_millis(250), || {
+                attempts += 1;
+                async { Err(Error::CharacterNotFound) }
+            })
+            .await;
+        assert!(matches!(res, Err(Error::CharacterNotFound)));
+        assert_eq!(attempts, 1);
+
+        // Nor is the database, after a while.
+        let mut attempts = 0;
+        let res: Result<(), _> =
+            retry_transient(3, Duration::from_millis(250), || {
+                attempts += 1;
+                async { Err(Error::Sqlx(sqlx::Error::PoolTimedOut)) }
+            })
+            .await;
+        assert!(res.unwrap_err().is_transient());
+        assert_eq!(attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn missing_map_on_teleport_is_told_to_the_player() -> Result<(), Error>
+    {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let e = me.teleport(&state, 4242, (50, 50)).await.unwrap_err();
+                assert!(matches!(e, Error::MapNotFound), "{e:?}");
+                assert_eq!(e.action(), ErrorAction::Reply);
+                let (id, bytes) = e.encode()?;
+                assert_eq!(id, MsgTalk::PACKET_ID);
+                let msg = MsgTalk::decode(&bytes)?;
+                assert_eq!(msg.message, "Map not found!");
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[test]
+    fn errors_keep_their_source_through_the_network_error() {
+        use std::error::Error as _;
+        let e = Error::from(tq_db::Error::Db(sqlx::Error::PoolTimedOut));
+        assert_eq!(e.action(), ErrorAction::Log);
+        let e = tq_network::Error::from(e);
+        let source = e.source().expect("no source");
+        let source = source.downcast_ref::<Error>().expect("not ours");
+        assert!(source.is_transient());
+
+        let e = Error::Network(tq_network::Error::SendError);
+        assert_eq!(e.action(), ErrorAction::Disconnect);
+        let e = tq_network::Error::from(e);
+        assert!(matches!(e, tq_network::Error::SendError));
+    }
 }
@@ -0,0 +1,601 @@
+//! The weekly guild war.
+//!
+//! During the war window, guilds attack the pole in the middle of the war
+//! map. Every hit is credited to the guild of the attacker, and once the pole
+//! falls, the guild that dealt the most damage to it takes it over and the
+//! pole stands again with full health. Whoever holds the pole when the window
+//! closes wins the war, the prize goes to its funds, and only its members
+//! could pass the gates of the castle until the next war.
+//!
+//! The pole and the gates are NPCs of the war map. Attacking the pole goes
+//! through [`crate::systems::physical_attack`], and the gate tiles are shut
+//! for everyone else by [`crate::systems::can_step`].
+
+use crate::packets::MsgTalk;
+use crate::world::Maps;
+use crate::{Error, State};
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// The health of the pole, every time it stands.
+pub const POLE_HP: u32 = 10_000_000;
+
+/// The map the pole stands on, the running scores are only announced there.
+pub const WAR_MAP: u32 = Maps::Faction as u32;
+
+/// How often the scores get announced while the war is running.
+pub const SCORE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many guilds get listed when announcing the scores.
+const TOP_GUILDS: usize = 5;
+
+/// The weekly time window the war runs in, written like `sat 20:00-21:00`.
+///
+/// Could be configured using the `GUILD_WAR_WINDOW` environment variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WarWindow {
+    weekday: Weekday,
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl Default for WarWindow {
+    fn default() -> Self {
+        Self {
+            weekday: Weekday::Sat,
+            start: NaiveTime::from_hms_opt(20, 0, 0).expect("valid time"),
+            end: NaiveTime::from_hms_opt(21, 0, 0).expect("valid time"),
+        }
+    }
+}
+
+impl WarWindow {
+    /// Loads the window from the environment, falling back to the default
+    /// one if it is not configured.
+    pub fn from_env() -> Result<Self, Error> {
+        match dotenvy::var("GUILD_WAR_WINDOW") {
+            Ok(window) => window.parse(),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// Whether the war should be running at the given time.
+    pub fn contains(&self, now: NaiveDateTime) -> bool {
+        now.weekday() == self.weekday
+            && now.time() >= self.start
+            && now.time() < self.end
+    }
+}
+
+impl FromStr for WarWindow {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::Other(format!("Invalid war window: {s:?}"));
+        let (weekday, times) = s.trim().split_once(' ').ok_or_else(invalid)?;
+        let (start, end) = times.trim().split_once('-').ok_or_else(invalid)?;
+        let weekday = weekday.parse().map_err(|_| invalid())?;
+        let start = NaiveTime::parse_from_str(start.trim(), "%H:%M")
+            .map_err(|_| invalid())?;
+        let end = NaiveTime::parse_from_str(end.trim(), "%H:%M")
+            .map_err(|_| invalid())?;
+        if end <= start {
+            return Err(invalid());
+        }
+        Ok(Self {
+            weekday,
+            start,
+            end,
+        })
+    }
+}
+
+/// A snapshot of the war.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WarState {
+    pub running: bool,
+    /// The guild holding the pole, if any.
+    pub owner: Option<u32>,
+    pub pole_hp: u32,
+    /// The damage every guild dealt to the pole since it last stood, the
+    /// highest first.
+    pub scores: Vec<(u32, u64)>,
+}
+
+/// How a war ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WarResult {
+    /// The guild holding the pole when the war ended.
+    pub winner: Option<u32>,
+    /// The silver the winner gets.
+    pub prize: u32,
+}
+
+#[derive(Debug)]
+struct Battle {
+    pole_hp: u32,
+    damage: HashMap<u32, u64>,
+    last_scores: Instant,
+}
+
+impl Battle {
+    fn new(now: Instant) -> Self {
+        Self {
+            pole_hp: POLE_HP,
+            damage: HashMap::new(),
+            last_scores: now,
+        }
+    }
+
+    fn scores(&self) -> Vec<(u32, u64)> {
+        let mut scores: Vec<_> =
+            self.damage.iter().map(|(&g, &d)| (g, d)).collect();
+        scores.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        scores
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    battle: Option<Battle>,
+    owner: Option<u32>,
+}
+
+/// The guild war, see the module docs for more.
+#[derive(Debug, Default)]
+pub struct GuildWar {
+    window: WarWindow,
+    prize: u32,
+    inner: Mutex<Inner>,
+}
+
+impl GuildWar {
+    pub fn new(window: WarWindow, prize: u32) -> Self {
+        Self {
+            window,
+            prize,
+            inner: Default::default(),
+        }
+    }
+
+    /// Loads the war window and the prize from the environment, see
+    /// [`WarWindow`] and the `GUILD_WAR_PRIZE` environment variable.
+    pub fn from_env() -> Result<Self, Error> {
+        let prize = match dotenvy::var("GUILD_WAR_PRIZE") {
+            Ok(prize) => prize.trim().parse()?,
+            Err(_) => 0,
+        };
+        Ok(Self::new(WarWindow::from_env()?, prize))
+    }
+
+    pub fn window(&self) -> WarWindow { self.window }
+
+    pub fn is_running(&self) -> bool { self.inner.lock().battle.is_some() }
+
+    /// The guild holding the pole.
+    pub fn owner(&self) -> Option<u32> { self.inner.lock().owner }
+
+    pub fn state(&self) -> WarState {
+        let inner = self.inner.lock();
+        WarState {
+            running: inner.battle.is_some(),
+            owner: inner.owner,
+            pole_hp: inner.battle.as_ref().map_or(POLE_HP, |b| b.pole_hp),
+            scores: inner
+                .battle
+                .as_ref()
+                .map(Battle::scores)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Whether members of the given guild could pass the castle gates.
+    pub fn can_pass_gate(&self, syndicate_id: u32) -> bool {
+        self.owner() == Some(syndicate_id)
+    }
+
+    /// Starts the war, returns `false` if it was already running.
+    #[tracing::instrument(skip(self, state))]
+    pub async fn start(&self, state: &State) -> Result<bool, Error> {
+        {
+            let mut inner = self.inner.lock();
+            if inner.battle.is_some() {
+                return Ok(false);
+            }
+            inner.battle = Some(Battle::new(Instant::now()));
+        }
+        tracing::info!("Guild war started");
+        state
+            .broadcast(MsgTalk::announce(
+                "The guild war has begun! Attack the pole to take the castle.",
+            ))
+            .await?;
+        Ok(true)
+    }
+
+    /// Ends the war, returns `None` if it was not running.
+    #[tracing::instrument(skip(self, state))]
+    pub async fn stop(
+        &self,
+        state: &State,
+    ) -> Result<Option<WarResult>, Error> {
+        let winner = {
+            let mut inner = self.inner.lock();
+            if inner.battle.take().is_none() {
+                return Ok(None);
+            }
+            inner.owner
+        };
+        if let Some(guild) = winner.filter(|_| self.prize > 0) {
+            let prize = u64::from(self.prize);
+            let paid =
+                state.guilds().add_funds(state.pool(), guild, prize).await?;
+            if !paid {
+                tracing::warn!(%guild, "The winning guild is gone, no prize");
+            }
+        }
+        let result = WarResult {
+            winner,
+            prize: self.prize,
+        };
+        tracing::info!(?result, "Guild war ended");
+        let msg = match winner {
+            Some(guild) => format!(
+                "The guild war is over! Guild #{guild} holds the castle and wins {} silver.",
+                self.prize
+            ),
+            None => "The guild war is over! Nobody took the castle.".into(),
+        };
+        state.broadcast(MsgTalk::announce(msg)).await?;
+        Ok(Some(result))
+    }
+
+    /// Credits the damage dealt to the pole to the guild of the attacker.
+    ///
+    /// Once the pole falls, the guild that dealt the most damage to it takes
+    /// it over. Returns the health left of the pole, or `None` if the war is
+    /// not running.
+    pub async fn hit_pole(
+        &self,
+        state: &State,
+        syndicate_id: u32,
+        damage: u32,
+    ) -> Result<Option<u32>, Error> {
+        let (hp, new_owner) = {
+            let mut inner = self.inner.lock();
+            let Some(battle) = inner.battle.as_mut() else {
+                return Ok(None);
+            };
+            *battle.damage.entry(syndicate_id).or_default() += damage as u64;
+            battle.pole_hp = battle.pole_hp.saturating_sub(damage);
+            if battle.pole_hp > 0 {
+                (battle.pole_hp, None)
+            } else {
+                let owner = battle.scores().first().map(|&(g, _)| g);
+                battle.pole_hp = POLE_HP;
+                battle.damage.clear();
+                inner.owner = owner;
+                (POLE_HP, owner)
+            }
+        };
+        if let Some(guild) = new_owner {
+            tracing::info!(%guild, "The pole fell");
+            state
+                .broadcast(MsgTalk::announce(format!(
+                    "Guild #{guild} has taken the pole!"
+                )))
+                .await?;
+        }
+        Ok(Some(hp))
+    }
+
+    /// Starts and stops the war following its window, and announces the
+    /// scores on the [`WAR_MAP`] every [`SCORE_INTERVAL`] while it is
+    /// running.
+    pub async fn tick(
+        &self,
+        state: &State,
+        now: Instant,
+        time: NaiveDateTime,
+    ) -> Result<(), Error> {
+        let in_window = self.window.contains(time);
+        match (in_window, self.is_running()) {
+            (true, false) => {
+                self.start(state).await?;
+                return Ok(());
+            },
+            (false, true) => {
+                self.stop(state).await?;
+                return Ok(());
+            },
+            _ => {},
+        }
+        let scores = {
+            let mut inner = self.inner.lock();
+            match inner.battle.as_mut() {
+                Some(battle)
+                    if now.duration_since(battle.last_scores)
+                        >= SCORE_INTERVAL =>
+                {
+                    battle.last_scores = now;
+                    battle.scores()
+                },
+                _ => return Ok(()),
+            }
+        };
+        if scores.is_empty() {
+            return Ok(());
+        }
+        let scores = scores
+            .iter()
+            .take(TOP_GUILDS)
+            .enumerate()
+            .map(|(i, (guild, damage))| {
+                format!("{}. Guild #{guild}: {damage}", i + 1)
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let msg = MsgTalk::bbs(format!("Guild war scores: {scores}"));
+        state.announce_on_map(WAR_MAP, msg).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use chrono::NaiveDate;
+    use futures::FutureExt;
+    use primitives::{Location, Size};
+
+    #[test]
+    fn war_window() {
+        let window: WarWindow = "sat 20:00-21:00".parse().unwrap();
+        assert_eq!(window, WarWindow::default());
+        // A Saturday.
+        let day = NaiveDate::from_ymd_opt(2024, 6, 1).unwrap();
+        assert!(window.contains(day.and_hms_opt(20, 30, 0).unwrap()));
+        assert!(!window.contains(day.and_hms_opt(21, 0, 0).unwrap()));
+        let sunday = day.succ_opt().unwrap();
+        assert!(!window.contains(sunday.and_hms_opt(20, 30, 0).unwrap()));
+        assert!("sat 21:00-20:00".parse::<WarWindow>().is_err());
+        assert!("someday".parse::<WarWindow>().is_err());
+    }
+
+    #[tokio::test]
+    async fn pole_goes_to_top_damage_guild() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(_a, mut a_rx), (_b, mut b_rx)] = actors;
+                let war = GuildWar::new(WarWindow::default(), 1000);
+                let (red, blue) = (1, 2);
+                assert_eq!(war.hit_pole(&state, red, 100).await?, None);
+                assert!(war.start(&state).await?);
+                assert!(!war.start(&state).await?);
+
+                let hp = war.hit_pole(&state, red, POLE_HP / 5).await?;
+                assert_eq!(hp, Some(POLE_HP - POLE_HP / 5));
+                war.hit_pole(&state, blue, POLE_HP / 5 * 3).await?;
+                let snapshot = war.state();
+                assert_eq!(snapshot.scores[0], (blue, POLE_HP as u64 / 5 * 3));
+                assert_eq!(snapshot.pole_hp, POLE_HP / 5);
+                // Red deals the last hit, but blue dealt the most damage.
+                let hp = war.hit_pole(&state, red, POLE_HP / 5).await?;
+                assert_eq!(hp, Some(POLE_HP));
+                assert_eq!(war.owner(), Some(blue));
+                assert!(war.can_pass_gate(blue));
+                assert!(!war.can_pass_gate(red));
+                assert!(war.state().scores.is_empty());
+
+                let result = war.stop(&state).await?;
+                assert_eq!(
+                    result,
+                    Some(WarResult {
+                        winner: Some(blue),
+                        prize: 1000
+                    })
+                );
+                assert!(!war.is_running());
+                assert_eq!(war.owner(), Some(blue));
+                assert_eq!(war.stop(&state).await?, None);
+
+                for rx in [&mut a_rx, &mut b_rx] {
+                    let messages = told(rx);
+                    assert_eq!(messages.len(), 3, "{messages:?}");
+                    assert!(messages[0].contains("has begun"));
+                    assert_eq!(messages[1], "Guild #2 has taken the pole!");
+                    assert!(messages[2].contains("Guild #2 holds the castle"));
+                }
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn winner_gets_the_prize() -> Result<(), Error> {
+        use crate::systems::{
+            create_guild, GUILD_CREATION_FEE, GUILD_CREATION_LEVEL,
+        };
+
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                me.entity().set_level(GUILD_CREATION_LEVEL);
+                me.add_silver(GUILD_CREATION_FEE)?;
+                create_guild(&state, me, "Knights").await?;
+                let guild = state.guilds().of(me.character_id()).unwrap();
+
+                let war = GuildWar::new(WarWindow::default(), 1000);
+                war.start(&state).await?;
+                war.hit_pole(&state, guild.id(), POLE_HP).await?;
+                war.stop(&state).await?;
+                assert_eq!(guild.funds(), GUILD_CREATION_FEE + 1000);
+                let (funds,) = sqlx::query_as::<_, (i64,)>(
+                    "SELECT funds FROM guilds WHERE guild_id = ?;",
+                )
+                .bind(guild.id())
+                .fetch_one(state.pool())
+                .await?;
+                assert_eq!(funds as u64, guild.funds());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn guilds_attack_the_pole() -> Result<(), Error> {
+        use crate::packets::{InteractionType, MsgInteract};
+        use crate::systems::{
+            create_guild, GUILD_CREATION_FEE, GUILD_CREATION_LEVEL,
+        };
+        use tq_network::PacketProcess;
+
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), (b, _)] = actors;
+                let map = state.try_map(WAR_MAP)?;
+                map.load_blank(Size::new(400, 400)).await?;
+                let pole = map.npcs().find(|npc| npc.is_pole()).unwrap();
+                let loc = pole.entity().location();
+                let war = state.guild_war();
+                // Blue hits harder, it deals the most damage to the pole.
+                let fighters = [
+                    (&a, "Red", loc.x - 1, POLE_HP / 5 * 2),
+                    (&b, "Blue", loc.x + 1, POLE_HP / 5 * 3),
+                ];
+                let mut guilds = Vec::new();
+                for (actor, name, x, attack) in fighters {
+                    let entity = actor.entity();
+                    let me = entity.as_character().unwrap();
+                    me.entity().set_level(GUILD_CREATION_LEVEL);
+                    me.add_silver(GUILD_CREATION_FEE)?;
+                    create_guild(&state, me, name).await?;
+                    guilds.push(state.guilds().of(me.character_id()).unwrap());
+                    me.set_equipment_attack(attack);
+                    me.entity()
+                        .set_map_id(WAR_MAP)
+                        .set_location(Location::new(x, loc.y, 0));
+                }
+                let attack = |id| {
+                    MsgInteract::new(
+                        id,
+                        pole.id(),
+                        (loc.x, loc.y),
+                        InteractionType::Attack,
+                        0,
+                    )
+                };
+                let (red, blue) = (guilds[0].id(), guilds[1].id());
+
+                // The pole could not be attacked before the war.
+                attack(a.entity().id()).process(&state, &a).await?;
+                assert_eq!(war.state().pole_hp, POLE_HP);
+                assert!(war.state().scores.is_empty());
+
+                war.start(&state).await?;
+                attack(a.entity().id()).process(&state, &a).await?;
+                let scores = war.state().scores;
+                assert_eq!(scores.len(), 1);
+                assert_eq!(scores[0].0, red);
+                assert!(war.state().pole_hp < POLE_HP);
+                assert_eq!(war.owner(), None);
+                // Blue fells the pole, and takes it over.
+                attack(b.entity().id()).process(&state, &b).await?;
+                assert_eq!(war.owner(), Some(blue));
+                assert_eq!(war.state().pole_hp, POLE_HP);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn gates_let_the_owners_through() -> Result<(), Error> {
+        use crate::systems::{
+            can_step, create_guild, GUILD_CREATION_FEE, GUILD_CREATION_LEVEL,
+        };
+
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), (b, _)] = actors;
+                let (a_entity, b_entity) = (a.entity(), b.entity());
+                let owner = a_entity.as_character().unwrap();
+                let other = b_entity.as_character().unwrap();
+                let map = state.try_map(WAR_MAP)?;
+                map.load_blank(Size::new(400, 400)).await?;
+                let gate = map.npcs().find(|npc| npc.is_gate()).unwrap();
+                let loc = gate.entity().location();
+                let (gate, aside) = ((loc.x, loc.y + 1), (loc.x, loc.y + 5));
+                // Nobody holds the castle yet.
+                assert!(!can_step(&state, owner, &map, gate));
+
+                owner.entity().set_level(GUILD_CREATION_LEVEL);
+                owner.add_silver(GUILD_CREATION_FEE)?;
+                create_guild(&state, owner, "Knights").await?;
+                let guild = state.guilds().of(owner.character_id()).unwrap();
+                let war = state.guild_war();
+                war.start(&state).await?;
+                war.hit_pole(&state, guild.id(), POLE_HP).await?;
+                war.stop(&state).await?;
+
+                assert!(can_step(&state, owner, &map, gate));
+                assert!(!can_step(&state, other, &map, gate));
+                assert!(can_step(&state, other, &map, aside));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn war_follows_its_window() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (_b, mut b_rx)] = actors;
+                let map = state.try_map(WAR_MAP)?;
+                map.load_blank(Size::new(100, 100)).await?;
+                let e = a.entity();
+                e.basic().set_map_id(WAR_MAP);
+                e.basic().set_location(Location::new(50, 50, 0));
+                map.insert_entity(e).await?;
+                let war = GuildWar::new(WarWindow::default(), 0);
+                let day = NaiveDate::from_ymd_opt(2024, 6, 1).unwrap();
+                let now = Instant::now();
+                war.tick(&state, now, day.and_hms_opt(19, 59, 0).unwrap())
+                    .await?;
+                assert!(!war.is_running());
+                war.tick(&state, now, day.and_hms_opt(20, 0, 0).unwrap())
+                    .await?;
+                assert!(war.is_running());
+                war.hit_pole(&state, 7, 10).await?;
+                let later = Instant::now() + SCORE_INTERVAL;
+                war.tick(&state, later, day.and_hms_opt(20, 1, 0).unwrap())
+                    .await?;
+                war.tick(&state, later, day.and_hms_opt(21, 0, 0).unwrap())
+                    .await?;
+                assert!(!war.is_running());
+                let messages = told(&mut a_rx);
+                assert_eq!(messages.len(), 3, "{messages:?}");
+                assert_eq!(messages[1], "Guild war scores: 1. Guild #7: 10");
+                assert!(messages[2].contains("Nobody took the castle"));
+                // The scores are only told to those on the war map.
+                let messages = told(&mut b_rx);
+                assert_eq!(messages.len(), 2, "{messages:?}");
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
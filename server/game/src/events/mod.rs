@@ -0,0 +1,4 @@
+//! Scheduled world events.
+
+pub mod guild_war;
+pub use guild_war::GuildWar;
@@ -2,6 +2,8 @@
 
 pub mod constants;
 pub mod entities;
+pub mod events;
+pub mod sync;
 pub mod systems;
 pub mod utils;
 pub mod world;
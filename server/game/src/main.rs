@@ -7,9 +7,13 @@
 
 use async_trait::async_trait;
 use std::env;
-use tq_network::{Actor, ActorState as _, PacketHandler, Server, TQCipher};
+use std::time::{Duration, Instant};
+use tq_network::{
+    Actor, ActorState as _, PacketHandler, PacketID, Seal, Server, TQCipher,
+};
 
 use game::packets::*;
+use game::systems::{LogFilter, STATUS_EFFECTS_TICK};
 use game::{ActorState, Error, State};
 
 struct GameServer;
@@ -20,6 +24,10 @@ impl Server for GameServer {
     type Cipher = TQCipher;
     type PacketHandler = Handler;
 
+    /// Clients answer pings on their own, it does not mean anyone is there.
+    const PASSIVE_PACKETS: &'static [u16] = &[MsgPing::PACKET_ID];
+    const SEAL: Seal = Seal::None;
+
     /// Get Called right before ending the connection with that client.
     /// good chance to clean up anything related to that actor.
     #[tracing::instrument(skip(state, actor))]
@@ -27,23 +35,31 @@ impl Server for GameServer {
         state: &<Self::PacketHandler as PacketHandler>::State,
         actor: Actor<Self::ActorState>,
     ) -> Result<(), tq_network::Error> {
+        state.audit().disconnected(&actor);
         if let Ok(entity) = actor.try_entity() {
             let me = entity.as_character().ok_or(Error::CharacterNotFound)?;
-            let mymap_id = me.entity().map_id();
-            me.save(state).await?;
-            me.try_screen()?.remove_from_observers().await?;
+            me.leave_world(state).await?;
             ActorState::dispose(&actor, actor.handle()).await?;
-            state.remove_entity(me.id());
-            let mymap = state.try_map(mymap_id)?;
-            mymap.remove_entity(&entity)?;
         }
         let _ = actor.shutdown().await;
         Ok(())
     }
+
+    async fn shutdown_signal(
+        state: &<Self::PacketHandler as PacketHandler>::State,
+    ) {
+        state.restart().stopped().await
+    }
 }
 
 #[derive(Copy, Clone, PacketHandler)]
-#[handle(state = State, actor_state = ActorState)]
+#[handle(
+    state = State,
+    actor_state = ActorState,
+    names = game::packets::name_of,
+    stats = State::invalid_packets,
+    dropped = game::systems::on_dropped_packet
+)]
 pub enum Handler {
     MsgConnect,
     MsgRegister,
@@ -54,6 +70,13 @@ pub enum Handler {
     MsgTransfer,
     MsgNpc,
     MsgTaskDialog,
+    MsgAllot,
+    MsgLogout,
+    MsgPing,
+    MsgGemEmbed,
+    MsgInteract,
+    MsgSyndicate,
+    MsgFriend,
 }
 
 #[tokio::main]
@@ -62,7 +85,7 @@ async fn main() -> Result<(), Error> {
     let log_verbosity = env::var("LOG_VERBOSITY")
         .map(|s| s.parse::<i32>().unwrap_or(2))
         .unwrap_or(2);
-    setup_logger(log_verbosity)?;
+    let log_filter = setup_logger(log_verbosity)?;
     println!(
         r#"
  _____         _____                  
@@ -83,19 +106,89 @@ Copyright 2020 Shady Khalifa (@shekohex)
     tracing::info!("Initializing State ..");
 
     let static_state = {
-        let state = State::init().await?;
+        let mut state = State::init().await?;
+        state.set_log_filter_handle(log_filter);
         Box::leak(Box::new(state)) as *mut State
     };
 
     // SAFETY: We are the only owner of this Box, and we are deref
     // it. This happens only once, so no one else can access.
     let state = unsafe { &*static_state };
+    state.post_init().await?;
+    state.restore_snapshot().await?;
     let realm = tq_db::realm::Realm::by_name(state.pool(), "CoEmu")
         .await?
         .ok_or(Error::RealmNotFound)?;
     let game_port = realm.game_port;
     tracing::info!("Game Server will be available on {}", game_port);
 
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(STATUS_EFFECTS_TICK);
+        loop {
+            interval.tick().await;
+            state.tick_status_effects(Instant::now()).await;
+            state.tick_xp(Instant::now()).await;
+            state.expire_floor_items(Instant::now()).await;
+            let time = chrono::Local::now().naive_local();
+            if let Err(error) =
+                state.guild_war().tick(state, Instant::now(), time).await
+            {
+                tracing::warn!(%error, "Failed to tick the guild war");
+            }
+            if let Err(error) = state.scheduler().tick(state, time).await {
+                tracing::warn!(%error, "Failed to tick the scheduler");
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(
+            state.experience_window().max(Duration::from_millis(10)),
+        );
+        loop {
+            interval.tick().await;
+            state.flush_experience(Instant::now()).await;
+        }
+    });
+
+    tokio::spawn(async move { state.map_loader().run(state).await });
+
+    if !state.movement_window().is_zero() {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(state.movement_window());
+            loop {
+                interval.tick().await;
+                state.flush_movements().await;
+            }
+        });
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PING_INTERVAL);
+        loop {
+            interval.tick().await;
+            state.ping_all(Instant::now()).await;
+        }
+    });
+
+    // A SIGTERM drains the server instead of dropping everyone at once.
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        let Ok(mut terminate) = signal(SignalKind::terminate()) else {
+            tracing::warn!("Failed to listen for SIGTERM");
+            return;
+        };
+        terminate.recv().await;
+        let now = chrono::Local::now().naive_local();
+        let countdown = state.restart().countdown();
+        if let Err(error) =
+            game::systems::start_restart(state, countdown, now).await
+        {
+            tracing::warn!(%error, "Failed to start the restart");
+        }
+    });
+
     GameServer::run(format!("0.0.0.0:{}", game_port), state).await?;
     unsafe {
         // SAFETY: We are the only owner of this Box, and we are dropping
@@ -109,9 +202,12 @@ Copyright 2020 Shady Khalifa (@shekohex)
     Ok(())
 }
 
-fn setup_logger(verbosity: i32) -> Result<(), Error> {
+/// Sets up the logger, the returned filter could change which logs get
+/// through while the server runs.
+fn setup_logger(verbosity: i32) -> Result<LogFilter, Error> {
     use tracing::Level;
     use tracing_subscriber::prelude::*;
+    use tracing_subscriber::reload;
 
     let log_level = match verbosity {
         0 => Level::ERROR,
@@ -141,11 +237,91 @@ fn setup_logger(verbosity: i32) -> Result<(), Error> {
         .with_default_env()
         .spawn();
 
+    let (env_filter, handle) = reload::Layer::new(env_filter);
     let registry = tracing_subscriber::registry().with(env_filter).with(logger);
 
     #[cfg(feature = "console")]
     let registry = registry.with(console_layer);
 
     registry.init();
-    Ok(())
+    Ok(LogFilter::new(handle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use tq_network::{DisconnectReason, DropReason, Message, PacketID};
+
+    #[test]
+    fn every_handled_packet_is_registered() {
+        for id in Handler::PACKET_IDS {
+            assert!(
+                registry().get(*id).is_some_and(|t| t.is_decodable()),
+                "packet {id} is handled but not in the registry"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn dropped_packets_are_counted() -> Result<(), Error> {
+        let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+        sqlx::migrate!("../../migrations")
+            .run(&pool)
+            .await
+            .expect("Failed to migrate database");
+        let state = State::with_pool(pool).await?;
+        let (tx, _rx) = tokio::sync::mpsc::channel(8);
+        let actor = Actor::<ActorState>::new(tx);
+        let addr: std::net::SocketAddr = "10.0.0.1:5816".parse().unwrap();
+        actor.set_peer_addr(addr);
+        let ip = Some(addr.ip());
+
+        let truncated = (MsgWalk::PACKET_ID, Bytes::from_static(&[1, 2]));
+        Handler::handle(truncated.clone(), &state, &actor).await?;
+        Handler::handle(truncated, &state, &actor).await?;
+        Handler::handle((9999, Bytes::new()), &state, &actor).await?;
+
+        let stats = state.invalid_packets();
+        assert_eq!(stats.count(ip, DropReason::Decode), 2);
+        assert_eq!(stats.count(ip, DropReason::UnknownId), 1);
+        assert_eq!(stats.count(None, DropReason::Decode), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn garbage_is_told_once_then_disconnected() -> Result<(), Error> {
+        let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+        sqlx::migrate!("../../migrations")
+            .run(&pool)
+            .await
+            .expect("Failed to migrate database");
+        let state = State::with_pool(pool).await?;
+        let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+        let actor = Actor::<ActorState>::new(tx);
+        let limit = state.invalid_packet_limit() as usize;
+        let messages = |rx: &mut tokio::sync::mpsc::Receiver<Message>| {
+            std::iter::from_fn(|| rx.try_recv().ok()).collect::<Vec<_>>()
+        };
+
+        let truncated = (MsgWalk::PACKET_ID, Bytes::from_static(&[1, 2]));
+        for _ in 0..limit / 2 {
+            Handler::handle(truncated.clone(), &state, &actor).await?;
+        }
+        for _ in limit / 2..limit - 1 {
+            Handler::handle((9999, Bytes::new()), &state, &actor).await?;
+        }
+        let sent = messages(&mut rx);
+        assert_eq!(sent.len(), 1, "{sent:?}");
+        assert!(matches!(sent[0], Message::Packet(MsgTalk::PACKET_ID, _)));
+        assert_eq!(actor.disconnect_reason(), DisconnectReason::ClientClosed);
+
+        Handler::handle((9999, Bytes::new()), &state, &actor).await?;
+        let sent = messages(&mut rx);
+        assert!(matches!(sent[..], [Message::Shutdown]), "{sent:?}");
+        assert_eq!(actor.disconnect_reason(), DisconnectReason::InvalidPackets);
+        let unknown = actor.handle().drops(DropReason::UnknownId);
+        assert_eq!(unknown as usize, limit - limit / 2);
+        Ok(())
+    }
 }
@@ -14,13 +14,13 @@ mod msg_item;
 pub use msg_item::MsgItem;
 
 mod msg_transfer;
-pub use msg_transfer::MsgTransfer;
+pub use msg_transfer::{MsgTransfer, TRANSFER_VERSION};
 
 mod msg_register;
 pub use msg_register::{BaseClass, BodyType, MsgRegister};
 
 mod msg_walk;
-pub use msg_walk::{MovementType, MsgWalk};
+pub use msg_walk::{MovementType, MsgWalk, WalkDirection};
 
 mod msg_player;
 pub use msg_player::MsgPlayer;
@@ -28,6 +28,12 @@ pub use msg_player::MsgPlayer;
 mod msg_item_info;
 pub use msg_item_info::*;
 
+mod msg_item_info_ex;
+pub use msg_item_info_ex::{MsgItemInfoEx, ViewMode};
+
+mod msg_package;
+pub use msg_package::{MsgPackage, PackageAction, PackageKind};
+
 mod msg_data;
 pub use msg_data::MsgData;
 
@@ -45,3 +51,39 @@ pub use msg_npc::MsgNpc;
 
 mod msg_task_dialog;
 pub use msg_task_dialog::MsgTaskDialog;
+
+mod msg_user_attrib;
+pub use msg_user_attrib::{AttributeKind, MsgUserAttrib};
+
+mod msg_allot;
+pub use msg_allot::MsgAllot;
+
+mod msg_map_item;
+pub use msg_map_item::{MapItemAction, MapItemMode, MsgMapItem};
+
+mod msg_logout;
+pub use msg_logout::MsgLogout;
+
+mod msg_gem_embed;
+pub use msg_gem_embed::{GemEmbedAction, MsgGemEmbed};
+
+mod msg_ping;
+pub use msg_ping::{MsgPing, HIGH_LATENCY, PING_INTERVAL};
+
+mod msg_name;
+pub use msg_name::{MsgName, NameAction};
+
+mod msg_interact;
+pub use msg_interact::{InteractionType, MsgInteract};
+
+mod msg_syndicate;
+pub use msg_syndicate::{MsgSyndicate, SyndicateAction};
+
+mod msg_weapon_skill;
+pub use msg_weapon_skill::MsgWeaponSkill;
+
+mod msg_friend;
+pub use msg_friend::{FriendAction, MsgFriend};
+
+mod registry;
+pub use registry::{name_of, registry, PacketType, Registry};
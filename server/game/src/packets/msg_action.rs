@@ -1,13 +1,13 @@
 use super::{MsgTalk, TalkChannel};
-use crate::entities::Character;
-use crate::packets::{MsgMapInfo, MsgWeather};
+use crate::entities::{Character, CharacterState, Flags};
+use crate::packets::{MsgItemInfo, MsgMapInfo, MsgWeaponSkill, MsgWeather};
 use crate::state::State;
-use crate::systems::TileType;
-use crate::{utils, ActorState, Error};
+use crate::{systems, utils, ActorState, Error};
 use async_trait::async_trait;
 use num_enum::{FromPrimitive, IntoPrimitive};
-use primitives::Location;
 use serde::{Deserialize, Serialize};
+use tq_db::item::Item;
+use tq_db::weapon_skill::WeaponSkill;
 use tq_network::{Actor, PacketID, PacketProcess};
 use utils::LoHi;
 
@@ -67,6 +67,34 @@ pub enum ActionType {
     QueryFriendInfo = 140,
     // QueryLeaveWord = 141,
     ChangeFace = 142,
+    /// Triggers the XP skill whose magic type is in `data1`, once the XP
+    /// circle is full.
+    XpSkill = 143,
+    /// Not sent by the stock client, asks for everything in the screen to be
+    /// sent again, see [`Screen::refresh`](crate::systems::Screen::refresh).
+    RefreshScreen = 200,
+}
+
+impl ActionType {
+    /// Whether the action is part of playing the game, rather than of
+    /// loading into it, these are rejected until the client finished
+    /// loading, see [`Character::in_world`].
+    pub fn requires_world(self) -> bool {
+        matches!(
+            self,
+            Self::ChangeFacing
+                | Self::ChangeMap
+                | Self::Teleport
+                | Self::SetKillMode
+                | Self::QueryEntity
+                | Self::CreateBooth
+                | Self::LeaveBooth
+                | Self::Jump
+                | Self::Synchro
+                | Self::XpSkill
+                | Self::RefreshScreen
+        )
+    }
 }
 
 #[derive(Copy, Clone, Debug, Default, FromPrimitive, IntoPrimitive)]
@@ -124,6 +152,9 @@ impl MsgAction {
         Self::new(character_id, data1, data2, details, action_type)
     }
 
+    /// Answers with the map and location the character was saved at, which
+    /// got checked against its map at login, see
+    /// [`systems::check_saved_position`].
     #[tracing::instrument(skip_all)]
     async fn handle_send_location(
         &self,
@@ -134,41 +165,19 @@ impl MsgAction {
         let entity = actor.try_entity()?;
         let character =
             entity.as_character().ok_or(Error::CharacterNotFound)?;
-        let map_id = character.entity().map_id();
-        let location = character.entity().location();
-        match state.try_map(map_id) {
-            Ok(mymap) => {
-                res.data1 = map_id;
-                res.data2 = u32::constract(location.y, location.x);
-                mymap.insert_entity(entity).await?;
-                actor.send(res).await?;
-                actor.send(MsgMapInfo::from_map(mymap)).await?;
-                if !mymap.weather().is_unknwon() {
-                    actor.send(MsgWeather::new(mymap.weather())).await?;
-                }
-                let screen = actor.screen();
-                screen.load_surroundings(state).await?;
-            },
-            Err(_) => {
-                tracing::warn!(
-                    character_id = character.id(),
-                    %map_id,
-                    "map not found",
-                );
-                // Set default map and location.
-                let location = Location::new(430, 378, 0);
-                character.entity().set_map_id(1002).set_location(location);
-                actor
-                    .send(MsgTalk::from_system(
-                        character.id(),
-                        TalkChannel::System,
-                        "Map not found, teleporting to Twin City.".to_string(),
-                    ))
-                    .await?;
-                actor.shutdown().await?;
-                return Ok(());
-            },
-        };
+        let me = character.entity();
+        let mymap = state.try_map(me.map_id())?;
+        let location = me.location();
+        res.data1 = mymap.id();
+        res.data2 = u32::constract(location.y, location.x);
+        mymap.insert_entity(entity).await?;
+        actor.send(res).await?;
+        actor.send(MsgMapInfo::from_map(&mymap)).await?;
+        if !mymap.weather().is_unknwon() {
+            actor.send(MsgWeather::new(mymap.weather())).await?;
+        }
+        let screen = actor.screen();
+        screen.load_surroundings(state).await?;
         Ok(())
     }
 
@@ -191,14 +200,78 @@ impl MsgAction {
         Ok(())
     }
 
+    /// There are no friends yet, only enemies. The echo tells the client the
+    /// lists are complete.
+    #[tracing::instrument(skip_all)]
+    async fn handle_send_associates(
+        &self,
+        state: &State,
+        actor: &Actor<ActorState>,
+    ) -> Result<(), Error> {
+        let entity = actor.try_entity()?;
+        let me = entity.as_character().ok_or(Error::CharacterNotFound)?;
+        systems::send_enemies(state, me).await?;
+        actor.send(self.clone()).await?;
+        Ok(())
+    }
+
+    /// Sends the inventory and equipment of the character, one item at a
+    /// time, followed by this action once they are all sent.
+    #[tracing::instrument(skip_all)]
+    async fn handle_send_items(
+        &self,
+        state: &State,
+        actor: &Actor<ActorState>,
+    ) -> Result<(), Error> {
+        let entity = actor.try_entity()?;
+        let me = entity.as_character().ok_or(Error::CharacterNotFound)?;
+        let pool = state.pool();
+        let inventory = Item::inventory_of(pool, me.character_id()).await?;
+        let equipment = Item::equipment_of(pool, me.character_id()).await?;
+        let items: Vec<_> = inventory
+            .iter()
+            .chain(&equipment)
+            .map(|item| MsgItemInfo::add(me.id(), item))
+            .collect();
+        actor.send_all(items).await?;
+        actor.send(self.clone()).await?;
+        Ok(())
+    }
+
+    /// Sends the weapon proficiencies of the character, followed by this
+    /// action once they are all sent.
+    #[tracing::instrument(skip_all)]
+    async fn handle_send_proficiencies(
+        &self,
+        state: &State,
+        actor: &Actor<ActorState>,
+    ) -> Result<(), Error> {
+        let entity = actor.try_entity()?;
+        let me = entity.as_character().ok_or(Error::CharacterNotFound)?;
+        let skills =
+            WeaponSkill::of_character(state.pool(), me.character_id()).await?;
+        let skills: Vec<_> = skills.iter().map(MsgWeaponSkill::from).collect();
+        actor.send_all(skills).await?;
+        actor.send(self.clone()).await?;
+        Ok(())
+    }
+
+    /// The client is done loading, the character joins the game and gets
+    /// the spawns held back meanwhile.
     #[tracing::instrument(skip_all)]
     async fn handle_login_completed(
         &self,
-        _state: &State,
+        state: &State,
         actor: &Actor<ActorState>,
     ) -> Result<(), Error> {
-        let res = self.clone();
-        actor.send(res).await?;
+        let entity = actor.try_entity()?;
+        let me = entity.as_character().ok_or(Error::CharacterNotFound)?;
+        if !me.enter_world() {
+            let count = actor.screen().refresh().await?;
+            tracing::debug!(id = me.id(), %count, "Entered the world");
+            systems::notify_enemy_status(state, me, true).await?;
+        }
+        actor.send(self.clone()).await?;
         Ok(())
     }
 
@@ -209,12 +282,31 @@ impl MsgAction {
         actor: &Actor<ActorState>,
     ) -> Result<(), Error> {
         // Remove Player from Booth.
+        let entity = actor.try_entity()?;
+        let me = entity.as_character().ok_or(Error::CharacterNotFound)?;
+        systems::close_stall(state, me).await?;
         let myscreen = actor.screen();
         myscreen.clear()?;
         myscreen.load_surroundings(state).await?;
         Ok(())
     }
 
+    #[tracing::instrument(skip_all)]
+    async fn handle_create_booth(
+        &self,
+        actor: &Actor<ActorState>,
+    ) -> Result<(), Error> {
+        let entity = actor.try_entity()?;
+        let me = entity.as_character().ok_or(Error::CharacterNotFound)?;
+        systems::open_stall(me).await?;
+        if me.state() == CharacterState::Vending {
+            let mut msg = self.clone();
+            msg.data1 = me.id();
+            actor.send(msg).await?;
+        }
+        Ok(())
+    }
+
     #[tracing::instrument(skip_all)]
     async fn handle_jump(
         &self,
@@ -227,6 +319,11 @@ impl MsgAction {
         let current_y = self.data2.hi();
         let entity = actor.try_entity()?;
         let me = entity.as_character().ok_or(Error::CharacterNotFound)?;
+        if !me.state().can_move() {
+            tracing::debug!(state = ?me.state(), "Jumping while not able to");
+            me.kick_back().await?;
+            return Ok(());
+        }
         let loc = me.entity().location();
         let mymap_id = me.entity().map_id();
         // Starting to validate this jump.
@@ -256,31 +353,81 @@ impl MsgAction {
 
         let direction =
             tq_math::get_direction_sector((loc.x, loc.y), (new_x, new_y));
-        match mymap.tile(new_x, new_y) {
-            Some(tile) if tile.access > TileType::Npc => {
-                // I guess everything seems to be valid .. send the jump.
-                me.entity()
-                    .set_location(Location::new(new_x, new_y, direction))
-                    .set_action(100);
-                me.set_elevation(tile.elevation);
-                mymap.update_region_for(entity.clone());
-                actor.send(self.clone()).await?;
-                let myscreen = actor.screen();
-                myscreen.send_movement(state, self.clone()).await?;
-            },
-            Some(_) | None => {
-                // Invalid Location move them back
-                let msg = MsgTalk::from_system(
-                    me.id(),
-                    TalkChannel::TopLeft,
-                    String::from("Invalid Location"),
-                );
-                actor.send(msg).await?;
-                me.kick_back().await?;
-                tracing::debug!(id = %me.id(), %loc.x, %loc.y, %new_x, %new_y, "Invalid Location");
-                return Ok(());
-            },
-        };
+        if systems::can_step(state, me, &mymap, (new_x, new_y)) {
+            // I guess everything seems to be valid .. send the jump.
+            me.entity().set_action(100);
+            me.move_to(state, (new_x, new_y), direction, self.clone())
+                .await?;
+        } else {
+            // Invalid Location move them back
+            let msg = MsgTalk::from_system(
+                me.id(),
+                TalkChannel::TopLeft,
+                String::from("Invalid Location"),
+            );
+            actor.send(msg).await?;
+            me.kick_back().await?;
+            tracing::debug!(id = %me.id(), %loc.x, %loc.y, %new_x, %new_y, "Invalid Location");
+            return Ok(());
+        }
+        Ok(())
+    }
+
+    /// Handles a position the client claims to be at, like a `Teleport` or
+    /// a `Synchro` coming from the client. The server owns the character's
+    /// position, so the claim is only accepted if it is a move the character
+    /// could have made from where the server knows it is, in the time since
+    /// its last accepted claim, otherwise the character gets snapped back to
+    /// its last known position.
+    #[tracing::instrument(skip_all)]
+    async fn handle_set_location(
+        &self,
+        state: &State,
+        actor: &Actor<ActorState>,
+    ) -> Result<(), Error> {
+        let new_x = self.data2.lo();
+        let new_y = self.data2.hi();
+        let entity = actor.try_entity()?;
+        let me = entity.as_character().ok_or(Error::CharacterNotFound)?;
+        if !me.state().can_move() {
+            tracing::debug!(state = ?me.state(), "Moving while not able to");
+            me.kick_back().await?;
+            return Ok(());
+        }
+        let loc = me.entity().location();
+        let mymap = state.try_map(me.entity().map_id())?;
+        let (dx, dy) = tq_math::delta((loc.x, loc.y), (new_x, new_y));
+        let plausible = self.character_id == me.id()
+            && self.data1 == mymap.id()
+            && tq_math::in_screen((loc.x, loc.y), (new_x, new_y))
+            && mymap.sample_elevation(
+                (loc.x, loc.y),
+                (new_x, new_y),
+                me.elevation(),
+            )
+            && systems::can_step(state, me, &mymap, (new_x, new_y))
+            && me.try_claim_position(std::time::Instant::now(), dx.max(dy));
+        if plausible {
+            let res = MsgAction::new(
+                me.id(),
+                mymap.id(),
+                u32::constract(new_y, new_x),
+                loc.direction as u16,
+                self.action_type.into(),
+            );
+            me.move_to(state, (new_x, new_y), loc.direction, res)
+                .await?;
+        } else {
+            tracing::debug!(
+                id = %me.id(),
+                %loc.x,
+                %loc.y,
+                %new_x,
+                %new_y,
+                "Implausible Location"
+            );
+            me.kick_back().await?;
+        }
         Ok(())
     }
 
@@ -310,6 +457,26 @@ impl MsgAction {
         Ok(())
     }
 
+    /// Sends everything in the screen again, at most once every
+    /// [`SCREEN_REFRESH_COOLDOWN`](systems::SCREEN_REFRESH_COOLDOWN), the
+    /// requests in between are dropped.
+    #[tracing::instrument(skip_all)]
+    async fn handle_refresh_screen(
+        &self,
+        actor: &Actor<ActorState>,
+    ) -> Result<(), Error> {
+        let entity = actor.try_entity()?;
+        let me = entity.as_character().ok_or(Error::CharacterNotFound)?;
+        let now = std::time::Instant::now();
+        if !me.try_refresh_screen(now, systems::SCREEN_REFRESH_COOLDOWN) {
+            tracing::debug!(me = me.id(), "Screen refresh too soon");
+            return Ok(());
+        }
+        actor.screen().refresh().await?;
+        actor.send(self.clone()).await?;
+        Ok(())
+    }
+
     #[tracing::instrument(skip_all)]
     async fn handle_query_entity(
         &self,
@@ -359,21 +526,42 @@ impl MsgAction {
             me.kick_back().await?;
             return Ok(());
         }
+        let now = tokio::time::Instant::now().into_std();
         let mymap = state.try_map(mymap_id)?;
         let maybe_portal = mymap.portals().iter().find(|p| {
             tq_math::in_circle((loc.x, loc.y, 5), (p.from_x(), p.from_y()))
         });
         match maybe_portal {
+            Some(_)
+                if !systems::ensure_out_of_combat(state, me, now).await? =>
+            {
+                me.kick_back().await?;
+            },
+            Some(portal)
+                if portal.is_city_gate()
+                    && me
+                        .entity()
+                        .flags()
+                        .contains(Flags::BLUE_FLASHING_NAME) =>
+            {
+                let msg = MsgTalk::from_system(
+                    me.id(),
+                    TalkChannel::System,
+                    "The guards will not let you through while your name is flashing.",
+                );
+                actor.send(msg).await?;
+                me.kick_back().await?;
+            },
+            Some(_) if !me.try_use_portal(now, state.portal_cooldown()) => {
+                // Most likely bouncing between two portals.
+                tracing::debug!(%portal_x, %portal_y, "Portal on cooldown");
+                me.kick_back().await?;
+            },
             Some(portal) => {
-                let portal_map = state.try_map(portal.to_map_id())?;
-                mymap.remove_entity(&entity)?;
-                portal_map.insert_entity(entity.clone()).await?;
-                me.teleport(
-                    state,
-                    portal.to_map_id(),
-                    (portal.to_x(), portal.to_y()),
-                )
-                .await?;
+                let to_map_id =
+                    systems::resolve_portal(state, &mymap, portal.to_map_id());
+                me.teleport(state, to_map_id, (portal.to_x(), portal.to_y()))
+                    .await?;
             },
             None => {
                 tracing::debug!(%portal_x, %portal_y, %loc.x, %loc.y, "Portal not found");
@@ -383,6 +571,38 @@ impl MsgAction {
         Ok(())
     }
 
+    /// Deletes the character for good and drops the connection, the
+    /// warehouse has to be unlocked first if it has a password.
+    #[tracing::instrument(skip_all)]
+    async fn handle_delete_role(
+        &self,
+        state: &State,
+        actor: &Actor<ActorState>,
+    ) -> Result<(), Error> {
+        let entity = actor.try_entity()?;
+        let me = entity.as_character().ok_or(Error::CharacterNotFound)?;
+        if !systems::ensure_warehouse_unlocked(state, actor, me).await? {
+            return Ok(());
+        }
+        me.leave_world(state).await?;
+        actor.unbind();
+        let id = me.character_id();
+        tq_db::character::Character::delete(state.pool(), id).await?;
+        tracing::info!(character_id = id, "Character deleted");
+        actor.shutdown().await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all, fields(magic_type = self.data1))]
+    async fn handle_xp_skill(
+        &self,
+        actor: &Actor<ActorState>,
+    ) -> Result<(), Error> {
+        let entity = actor.try_entity()?;
+        let me = entity.as_character().ok_or(Error::CharacterNotFound)?;
+        systems::cast_xp_skill(me, self.data1).await
+    }
+
     #[tracing::instrument(skip_all)]
     async fn handle_set_kill_mode(
         &self,
@@ -420,7 +640,10 @@ impl PacketProcess for MsgAction {
         state: &Self::State,
         actor: &Actor<Self::ActorState>,
     ) -> Result<(), Self::Error> {
-        let ty = self.action_type.into();
+        let ty = ActionType::from(self.action_type);
+        if ty.requires_world() {
+            actor.ensure_in_world()?;
+        }
         match ty {
             ActionType::SendLocation => {
                 self.handle_send_location(state, actor).await
@@ -429,29 +652,19 @@ impl PacketProcess for MsgAction {
             ActionType::SetKillMode => {
                 self.handle_set_kill_mode(state, actor).await
             },
+            ActionType::CreateBooth => self.handle_create_booth(actor).await,
             ActionType::LeaveBooth => {
                 self.handle_leave_booth(state, actor).await
             },
-            ActionType::SendItems => {
-                // TODO: send MsgItemInfo
-                actor.send(self.clone()).await?;
-                Ok(())
-            },
+            ActionType::SendItems => self.handle_send_items(state, actor).await,
             ActionType::SendAssociates => {
-                // Friends.
-                // TODO: send MsgFriend
-                actor.send(self.clone()).await?;
-                Ok(())
+                self.handle_send_associates(state, actor).await
             },
             ActionType::SendProficiencies => {
-                // Skils
-                // TODO: send MsgWeaponSkill
-                actor.send(self.clone()).await?;
-                Ok(())
+                self.handle_send_proficiencies(state, actor).await
             },
             ActionType::SendSpells => {
-                // Magic Spells
-                // TODO: send MsgMagicInfo
+                // There are no spells to learn yet, see `SendAssociates`.
                 actor.send(self.clone()).await?;
                 Ok(())
             },
@@ -465,10 +678,18 @@ impl PacketProcess for MsgAction {
             },
             ActionType::Jump => self.handle_jump(state, actor).await,
             ActionType::ChangeFacing => self.handle_change_facing(actor).await,
+            ActionType::Teleport | ActionType::Synchro => {
+                self.handle_set_location(state, actor).await
+            },
             ActionType::QueryEntity => {
                 self.handle_query_entity(state, actor).await
             },
             ActionType::ChangeMap => self.handle_change_map(state, actor).await,
+            ActionType::XpSkill => self.handle_xp_skill(actor).await,
+            ActionType::RefreshScreen => {
+                self.handle_refresh_screen(actor).await
+            },
+            ActionType::DelRole => self.handle_delete_role(state, actor).await,
             _ => {
                 let p = MsgTalk::from_system(
                     self.character_id,
@@ -490,3 +711,519 @@ impl PacketProcess for MsgAction {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::{MsgMapItem, MsgPlayer};
+    use crate::test_utils::*;
+    use crate::world::Maps;
+    use futures::FutureExt;
+    use primitives::{Location, Size};
+    use tokio::sync::mpsc::Receiver;
+    use tq_network::{Message, PacketDecode};
+
+    /// The ids spawned to the actor, characters and floor items.
+    fn spawned(rx: &mut Receiver<Message>) -> Vec<u32> {
+        let mut ids = Vec::new();
+        while let Ok(msg) = rx.try_recv() {
+            match msg {
+                Message::Packet(MsgPlayer::PACKET_ID, bytes) => {
+                    let msg = MsgPlayer::decode(&bytes).unwrap();
+                    ids.push(msg.character_id as u32);
+                },
+                Message::Packet(MsgMapItem::PACKET_ID, bytes) => {
+                    ids.push(MsgMapItem::decode(&bytes).unwrap().id);
+                },
+                _ => {},
+            }
+        }
+        ids.sort();
+        ids
+    }
+
+    fn claim(id: u32, map_id: u32, x: u16, y: u16) -> MsgAction {
+        let xy = u32::constract(y, x);
+        MsgAction::new(id, map_id, xy, 0, ActionType::Synchro)
+    }
+
+    #[tokio::test]
+    async fn login_enumeration_ends_every_list() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                for position in [Item::INVENTORY, Item::RIGHT_HAND] {
+                    sqlx::query(
+                        "INSERT INTO items (character_id, item_type, position) VALUES (?, 410005, ?);",
+                    )
+                    .bind(me.character_id())
+                    .bind(position)
+                    .execute(state.pool())
+                    .await?;
+                }
+                let blade = WeaponSkill {
+                    character_id: me.character_id(),
+                    skill_type: 410,
+                    level: 3,
+                    experience: 120,
+                };
+                blade.save(state.pool()).await?;
+                let skills =
+                    WeaponSkill::of_character(state.pool(), me.character_id())
+                        .await?;
+                assert_eq!(skills, [blade]);
+
+                let requests = [
+                    ActionType::SendItems,
+                    ActionType::SendAssociates,
+                    ActionType::SendProficiencies,
+                    ActionType::SendSpells,
+                ];
+                for ty in requests {
+                    MsgAction::new(me.id(), 0, 0, 0, ty)
+                        .process(&state, &a)
+                        .await?;
+                }
+                let mut received = Vec::new();
+                while let Ok(msg) = a_rx.try_recv() {
+                    let Message::Packet(id, bytes) = msg else {
+                        continue;
+                    };
+                    let entry = match id {
+                        MsgAction::PACKET_ID => {
+                            let action = MsgAction::decode(&bytes).unwrap();
+                            format!("end {}", action.action_type)
+                        },
+                        MsgItemInfo::PACKET_ID => "item".to_owned(),
+                        MsgWeaponSkill::PACKET_ID => {
+                            let skill = MsgWeaponSkill::decode(&bytes).unwrap();
+                            assert_eq!(
+                                (skill.skill_type, skill.level, skill.experience),
+                                (410, 3, 120)
+                            );
+                            "skill".to_owned()
+                        },
+                        _ => continue,
+                    };
+                    received.push(entry);
+                }
+                assert_eq!(received, [
+                    "item", "item", "end 75", "end 76", "skill", "end 77",
+                    "end 78",
+                ]);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn plausible_position_is_accepted() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let arena = u32::from(Maps::Arena);
+                let map = state.try_map(arena)?;
+                map.load_blank(Size::new(100, 100)).await?;
+                let [(a, mut a_rx), _] = actors;
+                let e = a.entity();
+                e.basic().set_map_id(arena);
+                e.basic().set_location(Location::new(50, 50, 0));
+                map.insert_entity(e.clone()).await?;
+
+                claim(e.id(), arena, 53, 52).process(&state, &a).await?;
+
+                let loc = e.basic().location();
+                assert_eq!((loc.x, loc.y), (53, 52));
+                let sent = packets_of::<MsgAction>(&mut a_rx);
+                assert_eq!(sent.len(), 1);
+                assert!(matches!(
+                    ActionType::from(sent[0].action_type),
+                    ActionType::Synchro
+                ));
+                assert_eq!(sent[0].data2, u32::constract(52, 53));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn implausible_jump_is_snapped_back() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let arena = u32::from(Maps::Arena);
+                let map = state.try_map(arena)?;
+                map.load_blank(Size::new(100, 100)).await?;
+                let [(a, mut a_rx), _] = actors;
+                let e = a.entity();
+                e.basic().set_map_id(arena);
+                e.basic().set_location(Location::new(50, 50, 0));
+                map.insert_entity(e.clone()).await?;
+
+                claim(e.id(), arena, 90, 90).process(&state, &a).await?;
+
+                let loc = e.basic().location();
+                assert_eq!((loc.x, loc.y), (50, 50));
+                let sent = packets_of::<MsgAction>(&mut a_rx);
+                assert_eq!(sent.len(), 1);
+                assert!(matches!(
+                    ActionType::from(sent[0].action_type),
+                    ActionType::Teleport
+                ));
+                assert_eq!(sent[0].data2, u32::constract(50, 50));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn spammed_claims_are_bound_by_time() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let arena = u32::from(Maps::Arena);
+                let map = state.try_map(arena)?;
+                map.load_blank(Size::new(200, 200)).await?;
+                let [(a, mut a_rx), _] = actors;
+                let e = a.entity();
+                e.basic().set_map_id(arena);
+                e.basic().set_location(Location::new(20, 20, 0));
+                map.insert_entity(e.clone()).await?;
+
+                // Every claim is within a screen of the last one, but they
+                // come faster than anyone could move.
+                for step in 1..=5 {
+                    let xy = 20 + step * 15;
+                    claim(e.id(), arena, xy, xy).process(&state, &a).await?;
+                }
+                let loc = e.basic().location();
+                assert_eq!((loc.x, loc.y), (35, 35));
+                let sent = packets_of::<MsgAction>(&mut a_rx);
+                assert_eq!(sent.len(), 5);
+                assert!(sent[1..].iter().all(|action| matches!(
+                    ActionType::from(action.action_type),
+                    ActionType::Teleport
+                )));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn dead_characters_could_not_claim_positions() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let arena = u32::from(Maps::Arena);
+                let map = state.try_map(arena)?;
+                map.load_blank(Size::new(100, 100)).await?;
+                let [(a, mut a_rx), _] = actors;
+                let e = a.entity();
+                e.basic().set_map_id(arena);
+                e.basic().set_location(Location::new(50, 50, 0));
+                map.insert_entity(e.clone()).await?;
+                let me = e.as_character().unwrap();
+                me.try_transition(CharacterState::Dead)?;
+
+                claim(e.id(), arena, 51, 50).process(&state, &a).await?;
+
+                let loc = e.basic().location();
+                assert_eq!((loc.x, loc.y), (50, 50));
+                let sent = packets_of::<MsgAction>(&mut a_rx);
+                assert_eq!(sent.len(), 1);
+                assert!(matches!(
+                    ActionType::from(sent[0].action_type),
+                    ActionType::Teleport
+                ));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    fn use_portal(id: u32, x: u16, y: u16) -> MsgAction {
+        let xy = u32::constract(y, x);
+        MsgAction::new(id, xy, 0, 0, ActionType::ChangeMap)
+    }
+
+    #[tokio::test]
+    async fn portal_cooldown() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                // These two portals lead into each other.
+                let plain = u32::from(Maps::Newplain);
+                let forum = u32::from(Maps::Forum);
+                for map_id in [plain, forum] {
+                    state
+                        .try_map(map_id)?
+                        .load_blank(Size::new(500, 500))
+                        .await?;
+                }
+                let [(a, _), _] = actors;
+                let e = a.entity();
+                e.basic().set_map_id(plain);
+                e.basic().set_location(Location::new(401, 387, 0));
+                state.try_map(plain)?.insert_entity(e.clone()).await?;
+                let me = e.as_character().unwrap();
+
+                use_portal(e.id(), 401, 387).process(&state, &a).await?;
+                assert_eq!(me.entity().map_id(), forum);
+                assert_eq!((me.x(), me.y()), (51, 70));
+
+                // Stepping right into the other one does nothing yet.
+                me.set_position(51, 73);
+                use_portal(e.id(), 51, 73).process(&state, &a).await?;
+                assert_eq!(me.entity().map_id(), forum);
+                assert_eq!((me.x(), me.y()), (51, 73));
+
+                // GMs could still teleport us around.
+                a.set_access(crate::state::Access::new(2, Default::default()));
+                let args = ["tele", "1002", "403", "394"];
+                crate::systems::commands::parse_and_execute(&state, &a, &args)
+                    .await?;
+                assert_eq!(me.entity().map_id(), plain);
+                assert_eq!((me.x(), me.y()), (403, 394));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn no_portals_right_after_a_fight() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let plain = u32::from(Maps::Newplain);
+                let forum = u32::from(Maps::Forum);
+                for map_id in [plain, forum] {
+                    state
+                        .try_map(map_id)?
+                        .load_blank(Size::new(500, 500))
+                        .await?;
+                }
+                let [(a, mut a_rx), _] = actors;
+                let e = a.entity();
+                e.basic().set_map_id(plain);
+                e.basic().set_location(Location::new(401, 387, 0));
+                state.try_map(plain)?.insert_entity(e.clone()).await?;
+                let me = e.as_character().unwrap();
+                let told = |rx: &mut Receiver<Message>| -> Vec<String> {
+                    std::iter::from_fn(|| rx.try_recv().ok())
+                        .filter_map(|msg| match msg {
+                            Message::Packet(MsgTalk::PACKET_ID, bytes) => {
+                                MsgTalk::decode(&bytes).ok()
+                            },
+                            _ => None,
+                        })
+                        .map(|msg| msg.message)
+                        .collect()
+                };
+
+                tokio::time::pause();
+                me.on_attacked(tokio::time::Instant::now().into_std());
+                use_portal(e.id(), 401, 387).process(&state, &a).await?;
+                assert_eq!(me.entity().map_id(), plain);
+                assert_eq!(told(&mut a_rx), ["You cannot leave combat yet."]);
+
+                tokio::time::advance(state.combat_lock()).await;
+                use_portal(e.id(), 401, 387).process(&state, &a).await?;
+                assert_eq!(me.entity().map_id(), forum);
+
+                // GMs could still teleport us out of a fight.
+                me.on_attacked(tokio::time::Instant::now().into_std());
+                a.set_access(crate::state::Access::new(2, Default::default()));
+                let args = ["tele", "1002", "403", "394"];
+                crate::systems::commands::parse_and_execute(&state, &a, &args)
+                    .await?;
+                assert_eq!(me.entity().map_id(), plain);
+                tokio::time::resume();
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    /// Asks for the location of the actor, returns the map and tile it got.
+    async fn send_location(
+        state: &State,
+        actor: &Actor<ActorState>,
+        rx: &mut Receiver<Message>,
+    ) -> Result<(u32, u16, u16), Error> {
+        let id = actor.entity().id();
+        MsgAction::new(id, 0, 0, 0, ActionType::SendLocation)
+            .process(state, actor)
+            .await?;
+        let res = packets_of::<MsgAction>(rx)
+            .into_iter()
+            .find(|a| a.action_type == u16::from(ActionType::SendLocation))
+            .expect("location was sent");
+        Ok((res.data1, res.data2.lo(), res.data2.hi()))
+    }
+
+    #[tokio::test]
+    async fn saved_location_is_sent() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, mut b_rx)] = actors;
+                let map_id = u32::from(Maps::Arena);
+                let map = state.try_map(map_id)?;
+                map.load_blank(Size::new(200, 200)).await?;
+                for (actor, x) in [(&a, 50), (&b, 80)] {
+                    let e = actor.entity();
+                    e.basic().set_map_id(map_id);
+                    e.basic().set_location(Location::new(x, 60, 0));
+                }
+
+                let sent = send_location(&state, &a, &mut a_rx).await?;
+                assert_eq!(sent, (map_id, 50, 60));
+                let sent = send_location(&state, &b, &mut b_rx).await?;
+                assert_eq!(sent, (map_id, 80, 60));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn screen_refresh_resends_what_is_in_view() -> Result<(), Error> {
+        use crate::entities::{FloorItem, FloorItemKind};
+        use crate::state::IdKind;
+
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, _)] = actors;
+                let map_id = u32::from(Maps::Arena);
+                let map = state.try_map(map_id)?;
+                map.load_blank(Size::new(100, 100)).await?;
+                for (actor, x) in [(&a, 40), (&b, 42)] {
+                    let e = actor.entity();
+                    e.basic().set_map_id(map_id);
+                    e.basic().set_location(Location::new(x, 40, 0));
+                    map.insert_entity(e).await?;
+                }
+                let item = FloorItem::new(
+                    state.ids().allocate(IdKind::FloorItem)?,
+                    FloorItemKind::Silver(100),
+                    map_id,
+                    Location::new(41, 41, 0),
+                );
+                let item = map.insert_floor_item(item).await?;
+                a.screen().load_surroundings(&state).await?;
+                let mut in_view = vec![b.entity().id(), item.id()];
+                in_view.sort();
+                assert_eq!(spawned(&mut a_rx), in_view);
+
+                let me = a.entity().id();
+                let refresh =
+                    MsgAction::new(me, 0, 0, 0, ActionType::RefreshScreen);
+                refresh.process(&state, &a).await?;
+                assert_eq!(spawned(&mut a_rx), in_view);
+                // Right after, it is not worth it.
+                refresh.process(&state, &a).await?;
+                assert!(spawned(&mut a_rx).is_empty());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    /// Loads the character of the actor anew onto the arena, next to the
+    /// character of `other`, the way it is right after logging in.
+    async fn log_in_again(
+        state: &State,
+        actor: &Actor<ActorState>,
+        other: &Actor<ActorState>,
+    ) -> Result<(), Error> {
+        let map_id = u32::from(Maps::Arena);
+        let map = state.try_map(map_id)?;
+        map.load_blank(Size::new(100, 100)).await?;
+        let e = other.entity();
+        e.basic().set_map_id(map_id);
+        e.basic().set_location(Location::new(42, 40, 0));
+        map.insert_entity(e).await?;
+
+        let id = actor.entity().as_character().unwrap().character_id();
+        let row = tq_db::character::Character::by_id(state.pool(), id).await?;
+        let me = Character::new(actor.handle(), row);
+        me.entity()
+            .set_map_id(map_id)
+            .set_location(Location::new(40, 40, 0));
+        actor.update(me, crate::systems::Screen::new(actor.handle()));
+        map.insert_entity(actor.entity()).await?;
+        actor.screen().load_surroundings(state).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn login_completed_enters_the_world() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, _)] = actors;
+                log_in_again(&state, &a, &b).await?;
+                // The client is still loading, it is told about nobody yet.
+                assert!(!a.in_world());
+                assert!(spawned(&mut a_rx).is_empty());
+
+                let me = a.entity().id();
+                MsgAction::new(me, 0, 0, 0, ActionType::LoginCompeleted)
+                    .process(&state, &a)
+                    .await?;
+                assert!(a.in_world());
+                assert_eq!(spawned(&mut a_rx), [b.entity().id()]);
+
+                // Playing is allowed from now on.
+                let xy = u32::constract(40, 40);
+                MsgAction::new(me, 0, xy, 2, ActionType::ChangeFacing)
+                    .process(&state, &a)
+                    .await?;
+                assert_eq!(a.entity().basic().location().direction, 2);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn gameplay_before_login_completed_is_rejected() -> Result<(), Error>
+    {
+        use crate::packets::{MovementType, MsgWalk, WalkDirection};
+
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), (b, _)] = actors;
+                log_in_again(&state, &a, &b).await?;
+                let me = a.entity().id();
+                let xy = u32::constract(40, 40);
+                let facing =
+                    MsgAction::new(me, 0, xy, 2, ActionType::ChangeFacing);
+                let res = facing.process(&state, &a).await;
+                assert!(matches!(res, Err(Error::NotInWorld)), "{res:?}");
+                let walk =
+                    MsgWalk::new(me, WalkDirection::North, MovementType::Walk);
+                let res = walk.process(&state, &a).await;
+                assert!(matches!(res, Err(Error::NotInWorld)), "{res:?}");
+                let loc = a.entity().basic().location();
+                assert_eq!((loc.x, loc.y, loc.direction), (40, 40, 0));
+                // Loading goes on meanwhile.
+                MsgAction::new(me, 0, 0, 0, ActionType::SendItems)
+                    .process(&state, &a)
+                    .await?;
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
@@ -0,0 +1,252 @@
+use super::{MsgTalk, TalkChannel};
+use crate::{ActorState, Error, State};
+use serde::{Deserialize, Serialize};
+use tq_network::{Actor, PacketID, PacketProcess};
+
+/// This packet is sent by the client to spend its unspent attribute points,
+/// the fields are the points added to each attribute.
+///
+/// If the character was granted the reallocation service first, either by a
+/// NPC or a GM command, the fields are the new attributes instead, and all of
+/// them get reallocated at once.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PacketID)]
+#[packet(id = 1024)]
+pub struct MsgAllot {
+    pub character_id: u32,
+    pub strength: u16,
+    pub agility: u16,
+    pub vitality: u16,
+    pub spirit: u16,
+}
+
+#[async_trait::async_trait]
+impl PacketProcess for MsgAllot {
+    type ActorState = ActorState;
+    type Error = Error;
+    type State = State;
+
+    async fn process(
+        &self,
+        state: &Self::State,
+        actor: &Actor<Self::ActorState>,
+    ) -> Result<(), Self::Error> {
+        actor.ensure_in_world()?;
+        let entity = actor.entity();
+        let me = entity.as_character().ok_or(Error::CharacterNotFound)?;
+        let attributes =
+            [self.strength, self.agility, self.vitality, self.spirit];
+        if self.character_id != me.id() {
+            tracing::warn!(
+                id = me.id(),
+                character_id = self.character_id,
+                "Attempt to allot the attributes of someone else"
+            );
+            actor
+                .send(MsgTalk::from_system(
+                    me.id(),
+                    TalkChannel::TopLeft,
+                    "You are not allowed to allot these attributes.",
+                ))
+                .await?;
+            return Ok(());
+        }
+        if me.allot_granted() {
+            me.reallot(state, attributes).await?;
+        } else {
+            me.spend_attribute_points(state, attributes).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants;
+    use crate::packets::MsgUserAttrib;
+    use crate::test_utils::*;
+    use futures::FutureExt;
+    use tq_network::PacketEncode;
+
+    #[tokio::test]
+    async fn unspent_points_are_added() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                me.gain_experience(
+                    constants::level_up_experience(me.entity().level())
+                        .unwrap(),
+                    std::time::Instant::now(),
+                )
+                .await?;
+                let points = me.attribute_points();
+                assert!(points >= 3);
+                let before = [me.strength(), me.vitality(), me.spirit()];
+                let max_hp = me.hp().max;
+                while a_rx.try_recv().is_ok() {}
+
+                let msg = MsgAllot {
+                    character_id: me.id(),
+                    strength: 1,
+                    agility: 0,
+                    vitality: 2,
+                    spirit: 0,
+                };
+                msg.process(&state, &a).await?;
+                assert_eq!(me.attribute_points(), points - 3);
+                assert_eq!(me.strength(), before[0] + 1);
+                assert_eq!(me.vitality(), before[1] + 2);
+                assert_eq!(me.spirit(), before[2]);
+                let expected = constants::max_health_points(
+                    me.strength(),
+                    me.agility(),
+                    me.vitality(),
+                    me.spirit(),
+                );
+                assert!(expected > max_hp);
+                assert_eq!(me.hp().max, expected);
+                let Some(tq_network::Message::Packet(id, _)) =
+                    a_rx.try_recv().ok()
+                else {
+                    panic!("the client was not told");
+                };
+                assert_eq!(id, MsgUserAttrib::PACKET_ID);
+
+                // The new attributes are saved.
+                let record = tq_db::character::Character::by_id(
+                    state.pool(),
+                    me.character_id(),
+                )
+                .await?;
+                assert_eq!(record.vitality as u16, me.vitality());
+                assert_eq!(record.attribute_points as u16, points - 3);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn reallot_uses_up_unspent_points() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                me.gain_experience(
+                    constants::level_up_experience(me.entity().level())
+                        .unwrap(),
+                    std::time::Instant::now(),
+                )
+                .await?;
+                let points = me.attribute_points();
+                assert!(points > 0);
+                let total = constants::base_attribute_points(
+                    me.current_class(),
+                    me.entity().level(),
+                ) + me.rebirth_points();
+
+                me.grant_allot();
+                let msg = MsgAllot {
+                    character_id: me.id(),
+                    strength: total,
+                    ..Default::default()
+                };
+                msg.process(&state, &a).await?;
+                assert_eq!(me.strength(), total);
+                assert_eq!(me.attribute_points(), 0);
+
+                // The unspent points went into the reallocation already.
+                let msg = MsgAllot {
+                    character_id: me.id(),
+                    strength: points,
+                    ..Default::default()
+                };
+                let err = msg.process(&state, &a).await.unwrap_err();
+                assert!(matches!(err, Error::NotEnoughAttributePoints(0, _)));
+                assert_eq!(me.strength(), total);
+                let record = tq_db::character::Character::by_id(
+                    state.pool(),
+                    me.character_id(),
+                )
+                .await?;
+                assert_eq!(record.attribute_points, 0);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn wrong_reallot_keeps_the_grant() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let total = constants::base_attribute_points(
+                    me.current_class(),
+                    me.entity().level(),
+                ) + me.rebirth_points();
+                me.grant_allot();
+
+                let msg = MsgAllot {
+                    character_id: me.id(),
+                    strength: total + 1,
+                    ..Default::default()
+                };
+                let err = msg.process(&state, &a).await.unwrap_err();
+                assert!(matches!(err, Error::InvalidAllotment(..)));
+                assert!(me.allot_granted());
+
+                let msg = MsgAllot {
+                    character_id: me.id(),
+                    spirit: total,
+                    ..Default::default()
+                };
+                msg.process(&state, &a).await?;
+                assert_eq!(me.spirit(), total);
+                assert!(!me.allot_granted());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn spending_more_than_unspent_is_rejected() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let points = me.attribute_points();
+                let before = [me.strength(), me.agility()];
+                let msg = MsgAllot {
+                    character_id: me.id(),
+                    strength: points,
+                    agility: 1,
+                    ..Default::default()
+                };
+                let err = msg.process(&state, &a).await.unwrap_err();
+                assert!(matches!(
+                    err,
+                    Error::NotEnoughAttributePoints(left, spent)
+                        if left == points && spent == points + 1
+                ));
+                // The client gets told why.
+                assert!(err.encode().is_ok());
+                assert_eq!(me.attribute_points(), points);
+                assert_eq!([me.strength(), me.agility()], before);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
@@ -1,10 +1,12 @@
-use super::{MsgTalk, MsgUserInfo};
+use super::{MsgTalk, MsgUserInfo, TalkChannel};
 use crate::entities::Character;
 use crate::packets::MsgData;
-use crate::systems::Screen;
+use crate::systems::{self, Locale, Screen, StarterKitGrant};
 use crate::{ActorState, Error, State};
 use serde::{Deserialize, Serialize};
-use tq_network::{Actor, IntoErrorPacket, PacketID, PacketProcess};
+use tq_network::{
+    Actor, DisconnectReason, IntoErrorPacket, PacketID, PacketProcess,
+};
 use tq_serde::String10;
 
 /// Message containing a connection request to the game server. Contains the
@@ -31,33 +33,131 @@ impl PacketProcess for MsgConnect {
         state: &Self::State,
         actor: &Actor<Self::ActorState>,
     ) -> Result<(), Self::Error> {
-        let info = state
-            .remove_login_token(self.token)
-            .map_err(|_| MsgTalk::login_invalid().error_packet())?;
+        let locale = Locale::from_language(&self.language);
+        actor.set_locale(locale);
+        // The token could still be on its way from the account server.
+        let handle = actor.handle();
+        let info = tokio::select! {
+            info = state.wait_login_token(self.token) => info,
+            _ = handle.closed() => {
+                tracing::debug!("Connection closed while waiting for the token");
+                return Ok(());
+            },
+        }
+        .map_err(|_| MsgTalk::login_invalid(locale).error_packet())?;
         actor.generate_keys(self.token).await?;
+        if !state.client_versions().supports(self.build_version) {
+            tracing::debug!(
+                version = self.build_version,
+                account_id = info.account_id,
+                "Unsupported client version"
+            );
+            actor.send(MsgTalk::update_required(locale)).await?;
+            actor.handle().disconnect(DisconnectReason::Kicked).await?;
+            return Ok(());
+        }
+        if state.restart().draining() {
+            tracing::debug!(account_id = info.account_id, "Server restarting");
+            actor.send(MsgTalk::server_restarting(locale)).await?;
+            actor.handle().disconnect(DisconnectReason::Kicked).await?;
+            return Ok(());
+        }
+        if state.login_gate().is_full(state.online_count()) {
+            tracing::debug!(account_id = info.account_id, "Server full");
+            actor.send(MsgTalk::server_full(locale)).await?;
+            actor.handle().disconnect(DisconnectReason::Kicked).await?;
+            return Ok(());
+        }
+        // Loading the character is the heavy part, when everyone comes back
+        // at once after a restart they take turns.
+        let _permit = state.login_gate().admit().await.ok_or_else(|| {
+            tracing::debug!(account_id = info.account_id, "Server busy");
+            MsgTalk::server_busy(locale).error_packet()
+        })?;
         actor.set_id(info.account_id as usize);
+        actor.set_access(info.access);
         let maybe_character = tq_db::character::Character::from_account(
             state.pool(),
             info.account_id,
         )
         .await?;
         match maybe_character {
-            Some(character) => {
+            Some(mut character) => {
+                // Held until the character is in the world, so a login to
+                // the same account from another connection waits its turn.
+                let mine = actor.try_entity().ok().map(|e| e.id());
+                let Some(_reservation) =
+                    state.reserve_login(&character.name, mine)
+                else {
+                    tracing::debug!(
+                        account_id = info.account_id,
+                        character_id = character.character_id,
+                        "Character already online"
+                    );
+                    actor.send(MsgTalk::already_online(locale)).await?;
+                    actor.handle().disconnect(DisconnectReason::Kicked).await?;
+                    return Ok(());
+                };
+                // Left there if the server went down while vending.
+                tq_db::item::Item::return_from_booth(
+                    state.pool(),
+                    character.character_id,
+                    None,
+                )
+                .await?;
+                // Equipment a rebirth had no room for in the inventory.
+                tq_db::item::Item::deliver_mail(
+                    state.pool(),
+                    character.character_id,
+                    crate::constants::INVENTORY_SIZE,
+                )
+                .await?;
+                let kit = state
+                    .starter_kit()
+                    .grant(state.pool(), &mut character)
+                    .await?;
+                let character_id = character.character_id;
                 let me = Character::new(actor.handle(), character);
-                let mymap_id = me.entity().map_id();
+                let mymap =
+                    systems::check_saved_position(state, &me).await.map_err(
+                        |_| MsgTalk::login_invalid(locale).error_packet(),
+                    )?;
                 let screen = Screen::new(actor.handle());
+                let me_id = me.id();
                 let msg = MsgUserInfo::from(&me);
                 actor.update(me, screen);
-                let mymap = state
-                    .try_map(mymap_id)
-                    .map_err(|_| MsgTalk::login_invalid().error_packet())?;
                 mymap.insert_entity(actor.entity()).await?;
                 state.insert_entity(actor.entity());
                 actor.send(MsgTalk::login_ok()).await?;
                 actor.send(msg).await?;
                 actor.send(MsgData::now()).await?;
+                let last_logins =
+                    systems::last_logins(state.pool(), me_id, info.account_id)
+                        .await?;
+                actor.send_all(last_logins).await?;
+                let entity = actor.entity();
+                if let Some(me) = entity.as_character() {
+                    systems::refresh_equipment_stats(state, me).await?;
+                    let now = crate::utils::current_ts() as i64;
+                    systems::deliver_offline_messages(state, me, now).await?;
+                    systems::send_motd(state, me).await?;
+                }
+                state.audit().connected(
+                    actor,
+                    info.account_id,
+                    Some(character_id),
+                );
+                if kit == StarterKitGrant::InventoryFull {
+                    let msg = MsgTalk::from_system(
+                        me_id,
+                        TalkChannel::System,
+                        "Your inventory is full, you will get the starter kit on your next login.",
+                    );
+                    actor.send(msg).await?;
+                }
             },
             None => {
+                state.audit().connected(actor, info.account_id, None);
                 state.store_creation_token(
                     self.token as u32,
                     info.account_id,
@@ -69,3 +169,475 @@ impl PacketProcess for MsgConnect {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::LoginToken;
+    use crate::systems::{ClientVersions, LoginGate};
+    use crate::test_utils::*;
+    use futures::FutureExt;
+    use primitives::Size;
+    use std::time::{Duration, Instant};
+    use tokio::sync::mpsc::Receiver;
+    use tq_db::item::Item;
+    use tq_network::{Message, PacketDecode};
+
+    async fn connect(
+        state: &State,
+        actor: &Actor<ActorState>,
+    ) -> Result<(), Error> {
+        connect_with_version(state, actor, 0).await
+    }
+
+    async fn connect_with_version(
+        state: &State,
+        actor: &Actor<ActorState>,
+        build_version: u16,
+    ) -> Result<(), Error> {
+        let map = state.try_map(1010)?;
+        if !map.loaded() {
+            map.load_blank(Size::new(200, 200)).await?;
+        }
+        let token = state.generate_login_token(1, 1, Default::default())?;
+        let msg = MsgConnect {
+            token: token.token,
+            build_version,
+            ..Default::default()
+        };
+        msg.process(state, actor).await
+    }
+
+    /// The database id of the character of the first test account.
+    async fn character_id(state: &State) -> Result<i32, Error> {
+        let character =
+            tq_db::character::Character::from_account(state.pool(), 1)
+                .await?
+                .ok_or(Error::CharacterNotFound)?;
+        Ok(character.character_id)
+    }
+
+    #[tokio::test]
+    async fn first_login_grants_starter_kit() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let character_id = character_id(&state).await?;
+                let silver = a.entity().as_character().unwrap().silver();
+                let kit = state.starter_kit();
+                let kit_items: usize =
+                    kit.items().iter().map(|(_, q)| *q as usize).sum();
+                assert!(kit_items > 0);
+
+                connect(&state, &a).await?;
+                let items =
+                    Item::inventory_of(state.pool(), character_id).await?;
+                assert_eq!(items.len(), kit_items);
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                assert_eq!(me.silver(), silver + kit.silver() as u64);
+
+                // Logging in again does not grant it twice.
+                connect(&state, &a).await?;
+                let items =
+                    Item::inventory_of(state.pool(), character_id).await?;
+                assert_eq!(items.len(), kit_items);
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                assert_eq!(me.silver(), silver + kit.silver() as u64);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn starter_kit_needs_room_in_inventory() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let character_id = character_id(&state).await?;
+                let silver = a.entity().as_character().unwrap().silver();
+                for _ in 0..crate::constants::INVENTORY_SIZE - 1 {
+                    sqlx::query(
+                        "INSERT INTO items (character_id, item_type) VALUES (?, 1000000);",
+                    )
+                    .bind(character_id)
+                    .execute(state.pool())
+                    .await?;
+                }
+
+                connect(&state, &a).await?;
+                let items = Item::inventory_of(state.pool(), character_id).await?;
+                assert_eq!(items.len(), crate::constants::INVENTORY_SIZE - 1);
+                let mut character =
+                    tq_db::character::Character::by_id(state.pool(), character_id)
+                        .await?;
+                assert!(!character.received_starter_kit);
+                assert_eq!(character.silver as u64, silver);
+
+                // Once there is room, the kit gets granted.
+                sqlx::query("DELETE FROM items WHERE character_id = ?;")
+                    .bind(character_id)
+                    .execute(state.pool())
+                    .await?;
+                let grant = state
+                    .starter_kit()
+                    .grant(state.pool(), &mut character)
+                    .await?;
+                assert_eq!(grant, StarterKitGrant::Granted);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    /// Drains the actor's channel and returns the login messages in it, and
+    /// whether the actor got shut down.
+    fn login_messages(rx: &mut Receiver<Message>) -> (Vec<String>, bool) {
+        let mut messages = Vec::new();
+        let mut shutdown = false;
+        while let Ok(msg) = rx.try_recv() {
+            match msg {
+                Message::Packet(MsgTalk::PACKET_ID, bytes) => {
+                    let msg = MsgTalk::decode(&bytes).unwrap();
+                    if msg.channel == TalkChannel::Login as u16 {
+                        messages.push(msg.message);
+                    }
+                },
+                Message::Shutdown => shutdown = true,
+                _ => continue,
+            }
+        }
+        (messages, shutdown)
+    }
+
+    #[tokio::test]
+    async fn client_version_is_checked() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |mut state, actors| {
+            async move {
+                state.set_client_versions(ClientVersions::new(5017, 5018));
+                let [(a, mut a_rx), (b, mut b_rx)] = actors;
+                let map = state.try_map(1010)?;
+
+                connect_with_version(&state, &a, 5018).await?;
+                let (messages, shutdown) = login_messages(&mut a_rx);
+                assert_eq!(messages, [crate::constants::ANSWER_OK]);
+                assert!(!shutdown);
+
+                let before = map.characters_snapshot().len();
+                connect_with_version(&state, &b, 5016).await?;
+                let (messages, shutdown) = login_messages(&mut b_rx);
+                assert_eq!(
+                    messages,
+                    [MsgTalk::update_required(Locale::English).message]
+                );
+                assert!(shutdown);
+                assert_eq!(b.disconnect_reason(), DisconnectReason::Kicked);
+                assert_eq!(map.characters_snapshot().len(), before);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn late_tokens_are_waited_for() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                state.try_map(1010)?.load_blank(Size::new(200, 200)).await?;
+                let msg = MsgConnect {
+                    token: 42,
+                    ..Default::default()
+                };
+                // The client was faster than the transfer of its token.
+                let transfer = async {
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+                    let login = LoginToken {
+                        account_id: 1,
+                        realm_id: 1,
+                        access: Default::default(),
+                    };
+                    state.store_login_token(42, login);
+                };
+                let (res, _) = tokio::join!(msg.process(&state, &a), transfer);
+                res?;
+                let (messages, shutdown) = login_messages(&mut a_rx);
+                assert_eq!(messages, [crate::constants::ANSWER_OK]);
+                assert!(!shutdown);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn missing_tokens_are_rejected_after_the_wait() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |mut state, actors| {
+            async move {
+                state.set_login_token_wait(Duration::from_millis(200));
+                let [(a, _), (b, b_rx)] = actors;
+                let msg = MsgConnect {
+                    token: 42,
+                    ..Default::default()
+                };
+                let start = Instant::now();
+                assert!(msg.process(&state, &a).await.is_err());
+                assert!(start.elapsed() >= state.login_token_wait());
+
+                // A connection that goes away stops waiting right away.
+                state.set_login_token_wait(Duration::from_secs(60));
+                drop(b_rx);
+                msg.process(&state, &b).await?;
+                assert!(state.remove_login_token(42).is_err());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn logins_take_turns_when_busy() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |mut state, actors| {
+            async move {
+                state.set_login_gate(LoginGate::new(1, 0));
+                let [(a, mut a_rx), _] = actors;
+                let permit = state.login_gate().admit().await;
+                assert!(permit.is_some());
+                // Someone else is logging in, and there is no room to wait.
+                match connect(&state, &a).await {
+                    Err(Error::Msg(msg)) => {
+                        assert_eq!(
+                            msg.message,
+                            MsgTalk::server_busy(Locale::English).message
+                        );
+                    },
+                    other => panic!("expected to be told busy, got {other:?}"),
+                }
+                drop(permit);
+                connect(&state, &a).await?;
+                let (messages, _) = login_messages(&mut a_rx);
+                assert_eq!(messages, [crate::constants::ANSWER_OK]);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn unloadable_map_falls_back_to_newbie_map() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let character_id = character_id(&state).await?;
+                // There are no map files to load it from in the tests.
+                let plain = u32::from(crate::world::Maps::Newplain);
+                sqlx::query(
+                    "UPDATE characters SET map_id = ?, x = 400, y = 350 WHERE character_id = ?;",
+                )
+                .bind(plain)
+                .bind(character_id)
+                .execute(state.pool())
+                .await?;
+
+                connect(&state, &a).await?;
+                let (messages, shutdown) = login_messages(&mut a_rx);
+                assert_eq!(messages, ["ANSWER_OK"]);
+                assert!(!shutdown);
+                let (x, y) = crate::constants::NEWBIE_LOCATION;
+                let entity = a.entity();
+                let loc = entity.basic().location();
+                assert_eq!(entity.basic().map_id(), 1010);
+                assert_eq!((loc.x, loc.y), (x, y));
+                let row =
+                    tq_db::character::Character::by_id(state.pool(), character_id)
+                        .await?;
+                assert_eq!(row.map_id, 1010);
+                assert_eq!((row.x as u16, row.y as u16), (x, y));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    /// Saves the character of the first test account on the arena, at the
+    /// given tile.
+    async fn save_on_arena(
+        state: &State,
+        x: u16,
+        y: u16,
+    ) -> Result<std::sync::Arc<crate::world::Map>, Error> {
+        let arena = state.try_map(u32::from(crate::world::Maps::Arena))?;
+        arena.load_blank(Size::new(100, 100)).await?;
+        sqlx::query(
+            "UPDATE characters SET map_id = ?, x = ?, y = ? WHERE character_id = ?;",
+        )
+        .bind(arena.id())
+        .bind(x)
+        .bind(y)
+        .bind(character_id(state).await?)
+        .execute(state.pool())
+        .await?;
+        Ok(arena)
+    }
+
+    #[tokio::test]
+    async fn logins_resume_where_they_left() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let arena = save_on_arena(&state, 42, 37).await?;
+
+                connect(&state, &a).await?;
+                let entity = a.entity();
+                let loc = entity.basic().location();
+                assert_eq!(entity.basic().map_id(), arena.id());
+                assert_eq!((loc.x, loc.y), (42, 37));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn blocked_saves_are_nudged() -> Result<(), Error> {
+        use crate::systems::{Tile, TileAccess};
+
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let arena = save_on_arena(&state, 42, 37).await?;
+                let wall = Tile {
+                    access: TileAccess::Terrain,
+                    elevation: 0,
+                };
+                arena.set_tile(42, 37, wall);
+
+                connect(&state, &a).await?;
+                let entity = a.entity();
+                let loc = entity.basic().location();
+                assert_eq!(entity.basic().map_id(), arena.id());
+                assert_ne!((loc.x, loc.y), (42, 37));
+                assert!(loc.x.abs_diff(42) <= 1 && loc.y.abs_diff(37) <= 1);
+                let row = tq_db::character::Character::by_id(
+                    state.pool(),
+                    character_id(&state).await?,
+                )
+                .await?;
+                assert_eq!((row.x as u16, row.y as u16), (loc.x, loc.y));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn characters_could_only_be_online_once() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, mut b_rx)] = actors;
+                connect(&state, &a).await?;
+                let (messages, _) = login_messages(&mut a_rx);
+                assert_eq!(messages, [crate::constants::ANSWER_OK]);
+
+                // The same account, from another connection.
+                let online = state.online_count();
+                connect(&state, &b).await?;
+                let (messages, shutdown) = login_messages(&mut b_rx);
+                assert_eq!(
+                    messages,
+                    [MsgTalk::already_online(Locale::English).message]
+                );
+                assert!(shutdown);
+                assert_eq!(b.disconnect_reason(), DisconnectReason::Kicked);
+                assert_eq!(state.online_count(), online);
+                // The one in the world is left alone.
+                assert!(a.try_entity().is_ok());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn concurrent_logins_get_in_once() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                // Neither connection has the character yet.
+                state.remove_entity(a.entity().id());
+                let online = state.online_count();
+                let map = state.try_map(1010)?;
+                map.load_blank(Size::new(200, 200)).await?;
+                let (tx, mut c_rx) = tokio::sync::mpsc::channel(50);
+                let c = Actor::<ActorState>::new(tx);
+                let (tx, mut d_rx) = tokio::sync::mpsc::channel(50);
+                let d = Actor::<ActorState>::new(tx);
+
+                let (c_res, d_res) =
+                    tokio::join!(connect(&state, &c), connect(&state, &d));
+                c_res?;
+                d_res?;
+                let mut results =
+                    [login_messages(&mut c_rx), login_messages(&mut d_rx)];
+                results.sort_by_key(|(_, shutdown)| *shutdown);
+                let [(first, first_shutdown), (second, second_shutdown)] =
+                    results;
+                assert_eq!(first, [crate::constants::ANSWER_OK]);
+                assert!(!first_shutdown);
+                assert_eq!(
+                    second,
+                    [MsgTalk::already_online(Locale::English).message]
+                );
+                assert!(second_shutdown);
+                assert_eq!(state.online_count(), online + 1);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn full_world_turns_logins_away() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |mut state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let online = state.online_count();
+                state.set_login_gate(
+                    LoginGate::default().with_max_online(online),
+                );
+                connect(&state, &a).await?;
+                let (messages, shutdown) = login_messages(&mut a_rx);
+                assert_eq!(
+                    messages,
+                    [MsgTalk::server_full(Locale::English).message]
+                );
+                assert!(shutdown);
+                assert_eq!(a.disconnect_reason(), DisconnectReason::Kicked);
+
+                // Room for one more.
+                state.set_login_gate(
+                    LoginGate::default().with_max_online(online + 1),
+                );
+                connect(&state, &a).await?;
+                let (messages, _) = login_messages(&mut a_rx);
+                assert_eq!(messages, [crate::constants::ANSWER_OK]);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
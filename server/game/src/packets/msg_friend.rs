@@ -0,0 +1,84 @@
+use crate::{systems, ActorState, Error, State};
+use num_enum::{FromPrimitive, IntoPrimitive};
+use serde::{Deserialize, Serialize};
+use tq_network::{Actor, PacketID, PacketProcess};
+use tq_serde::String16;
+
+/// The kind of friend or enemy action in a [`MsgFriend`] packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive, IntoPrimitive)]
+#[repr(u8)]
+pub enum FriendAction {
+    #[num_enum(default)]
+    None = 0,
+    RequestFriend = 10,
+    NewFriend = 11,
+    SetOnlineFriend = 12,
+    SetOfflineFriend = 13,
+    RemoveFriend = 14,
+    AddFriend = 15,
+    SetOnlineEnemy = 16,
+    SetOfflineEnemy = 17,
+    /// Sent by the client to drop someone from its enemies list, and back
+    /// by the server once it is gone.
+    RemoveEnemy = 18,
+    AddEnemy = 19,
+}
+
+/// This packet is used to manage the friends and enemies lists of a
+/// character, and to tell it when someone on them goes online or offline.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PacketID)]
+#[packet(id = 1019)]
+pub struct MsgFriend {
+    /// The id of the character the action is about.
+    pub id: u32,
+    pub action: u8,
+    pub online: bool,
+    reserved0: u16,
+    reserved1: u32,
+    reserved2: u32,
+    pub name: String16,
+}
+
+impl MsgFriend {
+    pub fn new(
+        action: FriendAction,
+        id: u32,
+        name: &str,
+        online: bool,
+    ) -> Self {
+        Self {
+            id,
+            action: action.into(),
+            online,
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl PacketProcess for MsgFriend {
+    type ActorState = ActorState;
+    type Error = Error;
+    type State = State;
+
+    #[tracing::instrument(skip_all, fields(action = self.action))]
+    async fn process(
+        &self,
+        state: &Self::State,
+        actor: &Actor<Self::ActorState>,
+    ) -> Result<(), Self::Error> {
+        actor.ensure_in_world()?;
+        let entity = actor.try_entity()?;
+        let me = entity.as_character().ok_or(Error::CharacterNotFound)?;
+        match FriendAction::from(self.action) {
+            FriendAction::RemoveEnemy => {
+                systems::remove_enemy(state, me, self.id).await?;
+            },
+            action => {
+                tracing::debug!(?action, "Unhandled friend action");
+            },
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use super::msg_item::{inventory_item, tell};
+use super::{MsgItem, MsgItemInfo};
+use crate::entities::NpcKind;
+use crate::{systems, ActorState, Error, State};
+use num_enum::{FromPrimitive, IntoPrimitive};
+use serde::{Deserialize, Serialize};
+use tq_db::item::Item;
+use tq_network::{Actor, PacketID, PacketProcess};
+
+#[derive(
+    Default, Debug, Clone, Copy, PartialEq, Eq, FromPrimitive, IntoPrimitive,
+)]
+#[repr(u16)]
+pub enum GemEmbedAction {
+    #[default]
+    Embed = 0,
+    TakeOff = 1,
+}
+
+/// This packet is sent by the client from the embed dialog of an artisan to
+/// put a gem into one of the sockets of an item.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PacketID)]
+#[packet(id = 1027)]
+pub struct MsgGemEmbed {
+    character_id: u32,
+    item_id: u32,
+    gem_id: u32,
+    position: u16,
+    action: u16,
+}
+
+#[async_trait::async_trait]
+impl PacketProcess for MsgGemEmbed {
+    type ActorState = ActorState;
+    type Error = Error;
+    type State = State;
+
+    #[tracing::instrument(skip_all, fields(item_id = self.item_id, gem_id = self.gem_id))]
+    async fn process(
+        &self,
+        state: &Self::State,
+        actor: &Actor<Self::ActorState>,
+    ) -> Result<(), Self::Error> {
+        actor.ensure_in_world()?;
+        let entity = actor.try_entity()?;
+        let me = entity.as_character().ok_or(Error::CharacterNotFound)?;
+        if GemEmbedAction::from(self.action) != GemEmbedAction::Embed {
+            tracing::debug!(action = self.action, "Unhandled gem embed action");
+            return Ok(());
+        }
+        let map = state.try_map(me.entity().map_id())?;
+        let loc = me.entity().location();
+        let near_artisan = map.npcs().any(|npc| {
+            let at = npc.entity().location();
+            npc.kind() == NpcKind::Embed
+                && tq_math::in_screen((loc.x, loc.y), (at.x, at.y))
+        });
+        if !near_artisan {
+            tracing::warn!("Attempt to embed a gem away from an artisan");
+            return tell(me, "You have to be next to an artisan.").await;
+        }
+        // Equipped items could be socketed too.
+        let target = Item::of_character(
+            state.pool(),
+            self.item_id as _,
+            me.character_id(),
+        )
+        .await?;
+        let gem = inventory_item(state, me, self.gem_id).await?;
+        let (Some(mut target), Some(gem)) = (target, gem) else {
+            return tell(me, "Item not found.").await;
+        };
+        if let Err(rejection) =
+            systems::socket(&mut target, &gem, self.position)
+        {
+            return tell(me, rejection.message()).await;
+        }
+        // Someone else could have used it in the meantime.
+        if !gem.delete(state.pool()).await? {
+            return Ok(());
+        }
+        target.save_upgrades(state.pool()).await?;
+        actor.send(MsgItem::remove(me.id(), self.gem_id)).await?;
+        actor.send(MsgItemInfo::update(me.id(), &target)).await?;
+        if target.position != Item::INVENTORY {
+            // Let the others see the glow of the new gem.
+            let msg = MsgItemInfo::other_player(me.id(), &target);
+            me.try_screen()?.send_message(msg).await?;
+        }
+        Ok(())
+    }
+}
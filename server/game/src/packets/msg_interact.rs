@@ -0,0 +1,221 @@
+use crate::entities::Character;
+use crate::{systems, utils, ActorState, Error, State};
+use num_enum::{FromPrimitive, IntoPrimitive};
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+use tq_network::{Actor, PacketID, PacketProcess};
+
+/// The kind of interaction in a [`MsgInteract`] packet.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, FromPrimitive, IntoPrimitive,
+)]
+#[repr(u32)]
+pub enum InteractionType {
+    #[num_enum(default)]
+    None = 0,
+    Steal = 1,
+    Attack = 2,
+    Heal = 3,
+    Poison = 4,
+    Assassinate = 5,
+    Freeze = 6,
+    Unfreeze = 7,
+    /// Proposes to the target, the server forwards it to the target so its
+    /// client asks whether to accept.
+    Court = 8,
+    /// Answers a proposal, the target is whoever proposed.
+    Marry = 9,
+    Divorce = 10,
+    /// Casts a spell, the data is its magic type.
+    MagicAttack = 24,
+    /// A ranged attack, like shooting an arrow.
+    Shoot = 28,
+}
+
+/// This packet is sent by the client when one entity interacts with another,
+/// like attacking it or proposing to it.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PacketID)]
+#[packet(id = 1022)]
+pub struct MsgInteract {
+    pub timestamp: u32,
+    pub sender_id: u32,
+    pub target_id: u32,
+    pub x: u16,
+    pub y: u16,
+    pub action: u32,
+    /// Depends on the action, for [`InteractionType::Marry`] it is zero when
+    /// the proposal got turned down.
+    pub data: u32,
+}
+
+impl MsgInteract {
+    pub fn new(
+        sender_id: u32,
+        target_id: u32,
+        (x, y): (u16, u16),
+        action: InteractionType,
+        data: u32,
+    ) -> Self {
+        Self {
+            timestamp: utils::current_ts(),
+            sender_id,
+            target_id,
+            x,
+            y,
+            action: action.into(),
+            data,
+        }
+    }
+
+    /// The proposal of `suitor` to `target`.
+    pub fn court(suitor: &Character, target: &Character) -> Self {
+        let loc = suitor.entity().location();
+        Self::new(
+            suitor.id(),
+            target.id(),
+            (loc.x, loc.y),
+            InteractionType::Court,
+            0,
+        )
+    }
+}
+
+#[async_trait::async_trait]
+impl PacketProcess for MsgInteract {
+    type ActorState = ActorState;
+    type Error = Error;
+    type State = State;
+
+    #[tracing::instrument(skip_all, fields(target_id = self.target_id))]
+    async fn process(
+        &self,
+        state: &Self::State,
+        actor: &Actor<Self::ActorState>,
+    ) -> Result<(), Self::Error> {
+        actor.ensure_in_world()?;
+        let entity = actor.try_entity()?;
+        let me = entity.as_character().ok_or(Error::CharacterNotFound)?;
+        match InteractionType::from(self.action) {
+            InteractionType::Attack | InteractionType::Shoot => {
+                let mut rng = state.rng().fork();
+                let (target, now) = (self.target_id, Instant::now());
+                systems::physical_attack(state, me, target, now, &mut rng)
+                    .await?;
+            },
+            InteractionType::MagicAttack => {
+                systems::cast_xp_skill(me, self.data).await?;
+            },
+            InteractionType::Heal => {
+                systems::heal(state, me, self.target_id).await?;
+            },
+            InteractionType::Court => {
+                systems::propose(state, me, self.target_id).await?;
+            },
+            InteractionType::Marry => {
+                let accepted = self.data != 0;
+                systems::answer(state, me, self.target_id, accepted).await?;
+            },
+            action => {
+                tracing::debug!(?action, "Unhandled interaction");
+            },
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use futures::FutureExt;
+    use primitives::{Location, Size};
+
+    /// Puts both characters next to each other on a blank map.
+    async fn side_by_side(
+        state: &State,
+        me: &Character,
+        target: &Character,
+    ) -> Result<(), Error> {
+        let map = state.try_map(1010)?;
+        map.load_blank(Size::new(100, 100)).await?;
+        for (c, x) in [(me, 40), (target, 41)] {
+            c.entity()
+                .set_map_id(1010)
+                .set_location(Location::new(x, 40, 0));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn attack_deals_damage() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, _)] = actors;
+                let (a_entity, b_entity) = (a.entity(), b.entity());
+                let me = a_entity.as_character().unwrap();
+                let target = b_entity.as_character().unwrap();
+                side_by_side(&state, me, target).await?;
+                let hp = target.hp().current();
+
+                let msg = MsgInteract::new(
+                    me.id(),
+                    target.id(),
+                    (41, 40),
+                    InteractionType::Attack,
+                    0,
+                );
+                msg.process(&state, &a).await?;
+                let attacks = packets_of::<MsgInteract>(&mut a_rx);
+                assert_eq!(attacks.len(), 1);
+                assert_eq!(
+                    InteractionType::from(attacks[0].action),
+                    InteractionType::Attack
+                );
+                let damage = attacks[0].data as u16;
+                assert!(damage > 0);
+                assert_eq!(target.hp().current(), hp.saturating_sub(damage));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn unknown_interactions_are_ignored() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, mut b_rx)] = actors;
+                let (a_entity, b_entity) = (a.entity(), b.entity());
+                let me = a_entity.as_character().unwrap();
+                let target = b_entity.as_character().unwrap();
+                side_by_side(&state, me, target).await?;
+                packets_of::<MsgInteract>(&mut a_rx);
+                packets_of::<MsgInteract>(&mut b_rx);
+                let hp = target.hp().current();
+
+                let msg = MsgInteract {
+                    action: 99,
+                    ..MsgInteract::new(
+                        me.id(),
+                        target.id(),
+                        (41, 40),
+                        InteractionType::None,
+                        0,
+                    )
+                };
+                assert_eq!(
+                    InteractionType::from(msg.action),
+                    InteractionType::None
+                );
+                msg.process(&state, &a).await?;
+                assert!(a_rx.try_recv().is_err());
+                assert!(b_rx.try_recv().is_err());
+                assert_eq!(target.hp().current(), hp);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
@@ -0,0 +1,149 @@
+//! The items for sale in the vending stalls, see [`systems::Stall`].
+
+use super::MsgItem;
+use crate::entities::Character;
+use crate::utils::current_ts;
+use crate::{systems, Error, State};
+
+/// Puts the item `param0` of the packet up for sale in the stall of the
+/// character for `param1` silver, the packet is echoed once it is.
+pub(super) async fn list(
+    state: &State,
+    me: &Character,
+    msg: &MsgItem,
+) -> Result<(), Error> {
+    if systems::list_item(state, me, msg.param0, msg.param1).await? {
+        me.owner().send(echo(msg)).await?;
+    }
+    Ok(())
+}
+
+/// Takes the item `param0` of the packet off the stall of the character,
+/// the packet is echoed once it is.
+pub(super) async fn unlist(
+    state: &State,
+    me: &Character,
+    msg: &MsgItem,
+) -> Result<(), Error> {
+    if systems::unlist_item(state, me, msg.param0).await? {
+        me.owner().send(echo(msg)).await?;
+    }
+    Ok(())
+}
+
+/// The packet sent back once it is done, stamped with the server time.
+fn echo(msg: &MsgItem) -> MsgItem {
+    MsgItem {
+        client_timestamp: current_ts(),
+        ..msg.clone()
+    }
+}
+
+/// Shows the character the items for sale in the stall of `seller_id`.
+pub(super) async fn browse(
+    state: &State,
+    me: &Character,
+    seller_id: u32,
+) -> Result<(), Error> {
+    systems::browse(state, me, seller_id).await
+}
+
+/// Buys the item from the stall of `seller_id`.
+pub(super) async fn buy(
+    state: &State,
+    me: &Character,
+    seller_id: u32,
+    item_id: u32,
+) -> Result<(), Error> {
+    systems::buy(state, me, seller_id, item_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::give_item;
+    use super::super::ItemAction;
+    use super::*;
+    use crate::packets::MsgTalk;
+    use crate::test_utils::*;
+    use futures::FutureExt;
+    use tokio::sync::mpsc::Receiver;
+    use tq_network::{Message, PacketDecode, PacketID};
+
+    /// The item actions echoed to the actor, and whether it got told
+    /// anything.
+    fn echoed(rx: &mut Receiver<Message>) -> (Vec<(u32, u32, u32, u32)>, bool) {
+        let mut echoed = Vec::new();
+        let mut told = false;
+        while let Ok(msg) = rx.try_recv() {
+            match msg {
+                Message::Packet(MsgItem::PACKET_ID, bytes) => {
+                    let msg = MsgItem::decode(&bytes).unwrap();
+                    echoed.push((
+                        msg.action_type,
+                        msg.param0,
+                        msg.param1,
+                        msg.client_timestamp,
+                    ));
+                },
+                Message::Packet(MsgTalk::PACKET_ID, _) => told = true,
+                _ => continue,
+            }
+        }
+        (echoed, told)
+    }
+
+    #[tokio::test]
+    async fn listings_are_echoed_once_done() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let item_id =
+                    give_item(&state, me.character_id(), 1000000).await?;
+
+                let add = MsgItem {
+                    param1: 100,
+                    client_timestamp: 42,
+                    ..MsgItem::new(me.id(), item_id, ItemAction::BoothAdd)
+                };
+                let del = MsgItem {
+                    client_timestamp: 43,
+                    ..MsgItem::new(me.id(), item_id, ItemAction::BoothDel)
+                };
+
+                // No stall to put it in yet.
+                list(&state, me, &add).await?;
+                assert_eq!(echoed(&mut a_rx), (vec![], true));
+
+                // Echoed with the time of the server, not of the client.
+                let now = current_ts();
+                systems::open_stall(me).await?;
+                list(&state, me, &add).await?;
+                let booth_add = u32::from(ItemAction::BoothAdd);
+                let (echoes, told) = echoed(&mut a_rx);
+                assert!(!told);
+                let [(action, param0, param1, ts)] = echoes[..] else {
+                    panic!("expected one echo, got {echoes:?}");
+                };
+                assert_eq!((action, param0, param1), (booth_add, item_id, 100));
+                assert!(ts >= now);
+                unlist(&state, me, &del).await?;
+                let booth_del = u32::from(ItemAction::BoothDel);
+                let (echoes, told) = echoed(&mut a_rx);
+                assert!(!told);
+                let [(action, param0, param1, ts)] = echoes[..] else {
+                    panic!("expected one echo, got {echoes:?}");
+                };
+                assert_eq!((action, param0, param1), (booth_del, item_id, 0));
+                assert!(ts >= now);
+                // Not for sale anymore.
+                unlist(&state, me, &del).await?;
+                assert_eq!(echoed(&mut a_rx), (vec![], true));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
@@ -0,0 +1,152 @@
+//! Putting items on and taking them off, and improving them with the minor
+//! items like the +1 stones.
+
+use super::{inventory_item, tell, MsgItem};
+use crate::entities::Character;
+use crate::packets::MsgItemInfo;
+use crate::{systems, Error, State};
+
+/// Puts the item from the inventory on, in the given equipment slot.
+pub(super) async fn equip(
+    state: &State,
+    me: &Character,
+    item_id: u32,
+    position: i16,
+) -> Result<(), Error> {
+    systems::equip(state, me, item_id, position).await
+}
+
+/// Moves the item from its equipment slot back to the inventory.
+pub(super) async fn unequip(
+    state: &State,
+    me: &Character,
+    item_id: u32,
+) -> Result<(), Error> {
+    systems::unequip(state, me, item_id).await
+}
+
+/// Composes the `minor` item into the `target` one, both have to be in the
+/// inventory.
+#[tracing::instrument(skip(state, me), fields(me = me.id()))]
+pub(super) async fn compose(
+    state: &State,
+    me: &Character,
+    target: u32,
+    minor: u32,
+) -> Result<(), Error> {
+    let target_item = inventory_item(state, me, target).await?;
+    let minor_item = inventory_item(state, me, minor).await?;
+    let (Some(mut target_item), Some(minor_item)) = (target_item, minor_item)
+    else {
+        return tell(me, "Item not found.").await;
+    };
+    let composed = systems::compose(
+        &mut target_item,
+        &minor_item,
+        &mut state.rng().fork(),
+    );
+    let composed = match composed {
+        Ok(composed) => composed,
+        Err(rejection) => return tell(me, rejection.message()).await,
+    };
+    // Someone else could have used it in the meantime.
+    if !minor_item.delete(state.pool()).await? {
+        return Ok(());
+    }
+    me.owner().send(MsgItem::remove(me.id(), minor)).await?;
+    if !composed {
+        return tell(me, "The composition failed.").await;
+    }
+    target_item.save_upgrades(state.pool()).await?;
+    me.owner()
+        .send(MsgItemInfo::update(me.id(), &target_item))
+        .await?;
+    tell(me, "The composition succeeded.").await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::give_item;
+    use super::*;
+    use crate::packets::MsgTalk;
+    use crate::test_utils::*;
+    use futures::FutureExt;
+    use tq_db::item::Item;
+    use tq_network::PacketID;
+
+    async fn stored(
+        state: &State,
+        me: &Character,
+        item_id: u32,
+    ) -> Result<Option<Item>, Error> {
+        Item::of_character(state.pool(), item_id as i32, me.character_id())
+            .await
+            .map_err(Into::into)
+    }
+
+    #[tokio::test]
+    async fn equipped_items_come_back_off() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let blade =
+                    give_item(&state, me.character_id(), 410020).await?;
+
+                equip(&state, me, blade, Item::RIGHT_HAND).await?;
+                let item = stored(&state, me, blade).await?.unwrap();
+                assert_eq!(item.position, Item::RIGHT_HAND);
+                unequip(&state, me, blade).await?;
+                let item = stored(&state, me, blade).await?.unwrap();
+                assert_eq!(item.position, Item::INVENTORY);
+                let ids = packet_ids(&mut a_rx);
+                assert!(ids.iter().all(|id| *id != MsgTalk::PACKET_ID));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn compose_persists_and_uses_up_the_minor() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let blade =
+                    give_item(&state, me.character_id(), 410020).await?;
+                let stone =
+                    give_item(&state, me.character_id(), 730001).await?;
+                compose(&state, me, blade, stone).await?;
+
+                // From +0 it always works.
+                assert_eq!(stored(&state, me, blade).await?.unwrap().plus, 1);
+                assert!(stored(&state, me, stone).await?.is_none());
+                let ids = packet_ids(&mut a_rx);
+                assert_eq!(
+                    ids,
+                    [
+                        MsgItem::PACKET_ID,
+                        MsgItemInfo::PACKET_ID,
+                        MsgTalk::PACKET_ID
+                    ]
+                );
+
+                // A +1 stone is no good for a +1 blade, and is kept.
+                let stone =
+                    give_item(&state, me.character_id(), 730001).await?;
+                compose(&state, me, blade, stone).await?;
+                assert_eq!(stored(&state, me, blade).await?.unwrap().plus, 1);
+                assert!(stored(&state, me, stone).await?.is_some());
+                let ids = packet_ids(&mut a_rx);
+                assert_eq!(ids, [MsgTalk::PACKET_ID]);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
@@ -0,0 +1,341 @@
+use super::{MsgTalk, TalkChannel};
+use crate::entities::Character;
+use crate::state::State;
+use crate::{systems, ActorState, Error};
+use async_trait::async_trait;
+use num_enum::{FromPrimitive, IntoPrimitive};
+use serde::{Deserialize, Serialize};
+use tq_db::item::Item;
+use tq_network::{Actor, PacketID, PacketProcess};
+
+mod booth_ops;
+mod equip_ops;
+mod use_ops;
+mod warehouse_ops;
+
+/// Enumeration type for defining item actions that may be requested by the
+/// user, or given to by the server. Allows for action handling as a packet
+/// subtype. Enums should be named by the action they provide to a system in the
+/// context of the player item.
+#[derive(Default, Debug, FromPrimitive, IntoPrimitive, Clone, Copy)]
+#[repr(u32)]
+enum ItemAction {
+    #[default]
+    Unknown,
+    Buy = 1,
+    Sell = 2,
+    Drop = 3,
+    Use = 4,
+    Equip = 5,
+    Unequip = 6,
+    SplitItem = 7,
+    CombineItem = 8,
+    QueryMoneySaved = 9,
+    SaveMoney = 10,
+    DrawMoney = 11,
+    DropMoney = 12,
+    SpendMoney = 13,
+    Repair = 14,
+    RepairAll = 15,
+    Ident = 16,
+    Durability = 17,
+    DropEquipement = 18,
+    Improve = 19,
+    UpLevel = 20,
+    BoothQuery = 21,
+    BoothAdd = 22,
+    BoothDel = 23,
+    BoothBuy = 24,
+    SynchroAmount = 25,
+    Fireworks = 26,
+    Ping = 27,
+    Enchant = 28,
+    BoothAddCPs = 29,
+    /// Sets the warehouse password to `param0` at a warehouseman, `param1`
+    /// is the old one when changing it.
+    SetWarehousePassword = 40,
+    /// Gives the warehouse password `param0` to unlock the warehouse.
+    VerifyWarehousePassword = 41,
+    /// Puts the item `param0` from the inventory in the warehouse of the
+    /// account, at the warehouseman `param1`.
+    WarehouseDeposit = 42,
+    /// Takes the item `param0` out of the warehouse of the account, at the
+    /// warehouseman `param1`.
+    WarehouseWithdraw = 43,
+}
+
+impl ItemAction {
+    /// Whether the action is turned away while the warehouse is locked, see
+    /// [`systems::ensure_warehouse_unlocked`].
+    fn needs_open_warehouse(self) -> bool {
+        matches!(
+            self,
+            Self::QueryMoneySaved
+                | Self::SaveMoney
+                | Self::DrawMoney
+                | Self::WarehouseDeposit
+                | Self::WarehouseWithdraw
+        )
+    }
+}
+
+/// Message containing an item action command. Item actions are usually
+/// performed to manage player equipment, inventory, money, or item shop
+/// purchases and sales. It is serves a second purpose for measuring client
+/// ping.
+///
+/// Every group of actions is handled in a module of its own, `process` only
+/// checks who is asking and passes the parameters on.
+#[derive(Debug, Serialize, Deserialize, Clone, PacketID)]
+#[packet(id = 1009)]
+pub struct MsgItem {
+    character_id: u32,
+    param0: u32,
+    action_type: u32,
+    client_timestamp: u32,
+    param1: u32,
+}
+
+impl MsgItem {
+    fn new(character_id: u32, param0: u32, action: ItemAction) -> Self {
+        Self {
+            character_id,
+            param0,
+            action_type: action.into(),
+            client_timestamp: crate::utils::current_ts(),
+            param1: 0,
+        }
+    }
+
+    /// Removes the item with the given id from the client's inventory.
+    pub fn remove(character_id: u32, item_id: u32) -> Self {
+        // Sent to the client, the drop action removes the item from the
+        // inventory.
+        Self::new(character_id, item_id, ItemAction::Drop)
+    }
+
+    #[cfg(test)]
+    pub fn save_money(character_id: u32, amount: u32) -> Self {
+        Self::new(character_id, amount, ItemAction::SaveMoney)
+    }
+
+    #[cfg(test)]
+    pub fn verify_warehouse_password(character_id: u32, password: u32) -> Self {
+        Self::new(character_id, password, ItemAction::VerifyWarehousePassword)
+    }
+
+    #[cfg(test)]
+    pub fn warehouse_deposit(
+        character_id: u32,
+        item_id: u32,
+        npc_id: u32,
+    ) -> Self {
+        Self {
+            param1: npc_id,
+            ..Self::new(character_id, item_id, ItemAction::WarehouseDeposit)
+        }
+    }
+
+    /// Puts the item from the inventory on, in the given equipment slot.
+    pub fn equip(character_id: u32, item: &Item, position: i16) -> Self {
+        Self {
+            param1: position as u32,
+            ..Self::new(character_id, item.item_id as u32, ItemAction::Equip)
+        }
+    }
+
+    /// Moves the item from its equipment slot back to the inventory.
+    pub fn unequip(character_id: u32, item: &Item, position: i16) -> Self {
+        Self {
+            param1: position as u32,
+            ..Self::new(character_id, item.item_id as u32, ItemAction::Unequip)
+        }
+    }
+
+    /// Answers a ping, see [`ItemAction::Ping`].
+    async fn pong(&self, me: &Character) -> Result<(), Error> {
+        // a bit hacky, just testing it out.
+        // what if we missed with the client timestamp?
+        // does this yield a negative value? let's find out.
+        // lets add 30ms from the client timestamp, so when
+        // the client receives the packet, it can calculate
+        // the round trip time.
+        let msg = MsgItem {
+            client_timestamp: self.client_timestamp + 30,
+            ..self.clone()
+        };
+        // LMFAO, this is so bad. it actually made the ping appear
+        // negative. I'm not sure if this is a bug in the client
+        // or if it's a bug in the server. I'm going to remove this
+        // later, but I'm going to leave it here for now.
+        me.owner().send(msg).await?;
+        Ok(())
+    }
+
+    /// Echoes an action nobody handles yet, and tells the player about it.
+    async fn unhandled(
+        &self,
+        me: &Character,
+        action: ItemAction,
+    ) -> Result<(), Error> {
+        tracing::warn!(
+            ?action,
+            param0 = %self.param0,
+            param1 = %self.param1,
+            action_id = self.action_type,
+            "Missing Item Action Type",
+        );
+        me.owner().send(self.clone()).await?;
+        let msg = MsgTalk::from_system(
+            self.character_id,
+            TalkChannel::Service,
+            format!("Missing Item Action Type {:?}", action),
+        );
+        me.owner().send(msg).await?;
+        Ok(())
+    }
+}
+
+/// Returns the item with the given id if it is in the inventory of the
+/// character.
+pub(super) async fn inventory_item(
+    state: &State,
+    me: &Character,
+    item_id: u32,
+) -> Result<Option<Item>, Error> {
+    let item =
+        Item::of_character(state.pool(), item_id as i32, me.character_id())
+            .await?
+            .filter(|item| item.position == Item::INVENTORY);
+    Ok(item)
+}
+
+/// Tells the character why its item action did nothing.
+pub(super) async fn tell(me: &Character, message: &str) -> Result<(), Error> {
+    let msg = MsgTalk::from_system(me.id(), TalkChannel::TopLeft, message);
+    me.owner().send(msg).await?;
+    Ok(())
+}
+
+#[async_trait]
+impl PacketProcess for MsgItem {
+    type ActorState = ActorState;
+    type Error = Error;
+    type State = State;
+
+    #[tracing::instrument(skip_all, fields(action = self.action_type))]
+    async fn process(
+        &self,
+        state: &Self::State,
+        actor: &Actor<Self::ActorState>,
+    ) -> Result<(), Self::Error> {
+        actor.ensure_in_world()?;
+        let entity = actor.try_entity()?;
+        let me = entity.as_character().ok_or(Error::CharacterNotFound)?;
+        let action = ItemAction::from(self.action_type);
+        if action.needs_open_warehouse()
+            && !systems::ensure_warehouse_unlocked(state, actor, me).await?
+        {
+            return Ok(());
+        }
+        let (param0, param1) = (self.param0, self.param1);
+        match action {
+            ItemAction::Use => use_ops::use_item(state, me, param0).await,
+            ItemAction::Equip => {
+                equip_ops::equip(state, me, param0, param1 as i16).await
+            },
+            ItemAction::Unequip => equip_ops::unequip(state, me, param0).await,
+            ItemAction::Improve => {
+                equip_ops::compose(state, me, param0, param1).await
+            },
+            ItemAction::BoothAdd => booth_ops::list(state, me, self).await,
+            ItemAction::BoothDel => booth_ops::unlist(state, me, self).await,
+            ItemAction::BoothQuery => {
+                booth_ops::browse(state, me, param0).await
+            },
+            ItemAction::BoothBuy => {
+                booth_ops::buy(state, me, param1, param0).await
+            },
+            ItemAction::SetWarehousePassword => {
+                warehouse_ops::set_password(state, actor, me, param0, param1)
+                    .await
+            },
+            ItemAction::VerifyWarehousePassword => {
+                warehouse_ops::verify_password(state, actor, me, param0).await
+            },
+            ItemAction::WarehouseDeposit => {
+                warehouse_ops::deposit(state, me, param0, param1).await
+            },
+            ItemAction::WarehouseWithdraw => {
+                warehouse_ops::withdraw(state, me, param0, param1).await
+            },
+            ItemAction::Ping => self.pong(me).await,
+            _ => self.unhandled(me, action).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use futures::FutureExt;
+    use primitives::Gauge;
+    use tq_network::PacketDecode;
+
+    pub(super) async fn give_item(
+        state: &State,
+        character_id: i32,
+        item_type: u32,
+    ) -> Result<u32, Error> {
+        let (item_id,) = sqlx::query_as::<_, (i32,)>(
+            "INSERT INTO items (character_id, item_type) VALUES (?, ?) RETURNING item_id;",
+        )
+        .bind(character_id)
+        .bind(item_type)
+        .fetch_one(state.pool())
+        .await?;
+        Ok(item_id as u32)
+    }
+
+    #[tokio::test]
+    async fn actions_reach_their_handlers() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let max = me.max_health_points();
+                me.entity().set_hp(Gauge::new(10, max));
+                let item_id =
+                    give_item(&state, me.character_id(), 1000000).await?;
+                MsgItem::new(me.id(), item_id, ItemAction::Use)
+                    .process(&state, &a)
+                    .await?;
+                let item = Item::of_character(
+                    state.pool(),
+                    item_id as i32,
+                    me.character_id(),
+                )
+                .await?;
+                assert!(item.is_none());
+                packets(&mut a_rx);
+
+                // Nothing handles repairs yet, the client gets it back.
+                MsgItem::new(me.id(), item_id, ItemAction::Repair)
+                    .process(&state, &a)
+                    .await?;
+                let sent = packets(&mut a_rx);
+                let ids: Vec<_> = sent.iter().map(|(id, _)| *id).collect();
+                assert_eq!(ids, [MsgItem::PACKET_ID, MsgTalk::PACKET_ID]);
+                let echoed = MsgItem::decode(&sent[0].1).unwrap();
+                assert_eq!(echoed.action_type, u32::from(ItemAction::Repair));
+                let told = MsgTalk::decode(&sent[1].1).unwrap();
+                assert_eq!(told.message, "Missing Item Action Type Repair");
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
@@ -0,0 +1,171 @@
+//! Using up the consumables in the inventory, like potions.
+
+use super::{inventory_item, tell, MsgItem};
+use crate::entities::Character;
+use crate::systems::Consumable;
+use crate::{Error, State};
+
+/// Uses the consumable item from the inventory, applying its effect to the
+/// character and using it up.
+#[tracing::instrument(skip(state, me), fields(me = me.id()))]
+pub(super) async fn use_item(
+    state: &State,
+    me: &Character,
+    item_id: u32,
+) -> Result<(), Error> {
+    let Some(item) = inventory_item(state, me, item_id).await? else {
+        tracing::debug!("Using an item that is not in the inventory");
+        return tell(me, "Item not found.").await;
+    };
+    let Some(consumable) = Consumable::of(item.item_type as u32) else {
+        return tell(me, "This item can not be used.").await;
+    };
+    if !consumable.waste_when_full && me.is_full_for(consumable.effect) {
+        return tell(me, "You do not need to use this item now.").await;
+    }
+    // Someone else could have used it in the meantime.
+    if !item.delete(state.pool()).await? {
+        return Ok(());
+    }
+    let attributes = me.apply_item_effect(consumable.effect).await?;
+    me.owner().send(MsgItem::remove(me.id(), item_id)).await?;
+    if !attributes.is_empty() {
+        // The item is gone for good, so should be what it healed.
+        me.save(state).await?;
+        me.owner().send(attributes).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::give_item;
+    use super::super::ItemAction;
+    use super::*;
+    use crate::packets::{MsgTalk, MsgUserAttrib};
+    use crate::systems::{EffectKind, Stat};
+    use crate::test_utils::*;
+    use futures::FutureExt;
+    use primitives::Gauge;
+    use tq_db::character::Character as DbCharacter;
+    use tq_db::item::Item;
+    use tq_network::{PacketDecode, PacketID};
+
+    #[tokio::test]
+    async fn heal_potion_restores_hp() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let max = me.max_health_points();
+                me.entity().set_hp(Gauge::new(10, max));
+                // Stancher
+                let item_id =
+                    give_item(&state, me.character_id(), 1000000).await?;
+
+                use_item(&state, me, item_id).await?;
+
+                assert_eq!(me.health_points(), (10 + 70).min(max));
+                let saved =
+                    DbCharacter::by_id(state.pool(), me.character_id()).await?;
+                assert_eq!(saved.health_points as u16, me.health_points());
+                let item = Item::of_character(
+                    state.pool(),
+                    item_id as i32,
+                    me.character_id(),
+                )
+                .await?;
+                assert!(item.is_none());
+                let sent = packets(&mut a_rx);
+                let ids: Vec<_> = sent.iter().map(|(id, _)| *id).collect();
+                assert_eq!(ids, [MsgItem::PACKET_ID, MsgUserAttrib::PACKET_ID]);
+                let removed = MsgItem::decode(&sent[0].1).unwrap();
+                assert_eq!(removed.param0, item_id);
+                assert!(matches!(
+                    ItemAction::from(removed.action_type),
+                    ItemAction::Drop
+                ));
+
+                // At full health the potion is kept.
+                me.entity().set_hp(Gauge::full(max));
+                let item_id =
+                    give_item(&state, me.character_id(), 1000000).await?;
+                use_item(&state, me, item_id).await?;
+                let ids = packet_ids(&mut a_rx);
+                assert_eq!(ids, [MsgTalk::PACKET_ID]);
+                let item = Item::of_character(
+                    state.pool(),
+                    item_id as i32,
+                    me.character_id(),
+                )
+                .await?;
+                assert!(item.is_some());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn buff_potion_applies_a_status_effect() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                // Attack Potion
+                let item_id =
+                    give_item(&state, me.character_id(), 1002030).await?;
+
+                use_item(&state, me, item_id).await?;
+
+                let effects = me.status_effects();
+                assert!(effects.is_active(EffectKind::AttackBoost));
+                assert_eq!(effects.fold(Stat::Attack, 100), 110);
+                let item = Item::of_character(
+                    state.pool(),
+                    item_id as i32,
+                    me.character_id(),
+                )
+                .await?;
+                assert!(item.is_none());
+                // The flags of the buff, then the item going away.
+                let ids = packet_ids(&mut a_rx);
+                assert_eq!(ids, [MsgUserAttrib::PACKET_ID, MsgItem::PACKET_ID]);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn missing_item_is_rejected() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, _)] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let max = me.max_health_points();
+                me.entity().set_hp(Gauge::new(10, max));
+
+                use_item(&state, me, 4242).await?;
+                // Nor could we use someone else's item.
+                let other = b.entity();
+                let other = other.as_character().unwrap();
+                let item_id =
+                    give_item(&state, other.character_id(), 1000000).await?;
+                use_item(&state, me, item_id).await?;
+
+                assert_eq!(me.health_points(), 10);
+                let ids = packet_ids(&mut a_rx);
+                assert_eq!(ids, [MsgTalk::PACKET_ID, MsgTalk::PACKET_ID]);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
@@ -0,0 +1,90 @@
+//! The warehouse password, and the items going in and out of the warehouse.
+
+use super::tell;
+use crate::entities::Character;
+use crate::{systems, ActorState, Error, State};
+use std::time::Instant;
+use tq_network::Actor;
+
+/// Sets the warehouse password to `new` at a warehouseman, `old` is the one
+/// it had when changing it.
+pub(super) async fn set_password(
+    state: &State,
+    actor: &Actor<ActorState>,
+    me: &Character,
+    new: u32,
+    old: u32,
+) -> Result<(), Error> {
+    let now = Instant::now();
+    let answer =
+        systems::set_warehouse_password(state, actor, me, old, new, now)
+            .await?;
+    tell(me, &answer.message()).await
+}
+
+/// Gives the warehouse password to unlock the warehouse for the session.
+pub(super) async fn verify_password(
+    state: &State,
+    actor: &Actor<ActorState>,
+    me: &Character,
+    password: u32,
+) -> Result<(), Error> {
+    let now = Instant::now();
+    let answer =
+        systems::verify_warehouse_password(state, actor, me, password, now)
+            .await?;
+    tell(me, &answer.message()).await
+}
+
+/// Puts the item from the inventory in the warehouse, at the warehouseman
+/// `npc_id`.
+pub(super) async fn deposit(
+    state: &State,
+    me: &Character,
+    item_id: u32,
+    npc_id: u32,
+) -> Result<(), Error> {
+    systems::deposit(state, me, npc_id, item_id).await
+}
+
+/// Takes the item out of the warehouse, at the warehouseman `npc_id`.
+pub(super) async fn withdraw(
+    state: &State,
+    me: &Character,
+    item_id: u32,
+    npc_id: u32,
+) -> Result<(), Error> {
+    systems::withdraw(state, me, npc_id, item_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use futures::FutureExt;
+
+    #[tokio::test]
+    async fn password_answers_are_told() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                set_password(&state, &a, me, 1234, 0).await?;
+                assert_eq!(
+                    told(&mut a_rx),
+                    [systems::WarehouseAnswer::NotAtWarehouse.message()]
+                );
+                verify_password(&state, &a, me, 1234).await?;
+                assert_eq!(
+                    told(&mut a_rx),
+                    [systems::WarehouseAnswer::Unlocked.message()]
+                );
+                assert!(a.warehouse_unlocked());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
@@ -1,5 +1,6 @@
 use num_enum::FromPrimitive;
 use serde::Serialize;
+use tq_db::item::Item;
 use tq_network::PacketID;
 
 #[derive(Debug, FromPrimitive)]
@@ -28,8 +29,7 @@ pub struct MsgItemInfo {
     /// Unknown
     reserved0: u8,
     reserved1: u32,
-    gem_one: u8,
-    gem_two: u8,
+    gems: [u8; 2],
     reborn_effect: u8,
     magic: u8,
     plus: u8,
@@ -40,3 +40,64 @@ pub struct MsgItemInfo {
     reserved3: u32,
     reserved4: u32,
 }
+
+impl MsgItemInfo {
+    fn new(character_id: u32, item: &Item, action: ItemInfoAction) -> Self {
+        Self {
+            character_id,
+            item_id: item.item_id as u32,
+            action: action as u8,
+            position: item.position as u8,
+            gems: [item.gem_one as u8, item.gem_two as u8],
+            plus: item.plus as u8,
+            ..Default::default()
+        }
+    }
+
+    /// Adds a new item to the inventory of the character.
+    pub fn add(character_id: u32, item: &Item) -> Self {
+        Self::new(character_id, item, ItemInfoAction::AddItem)
+    }
+
+    /// Updates an item the character already has.
+    pub fn update(character_id: u32, item: &Item) -> Self {
+        Self::new(character_id, item, ItemInfoAction::Update)
+    }
+
+    /// Updates a stack of items, like a pack of arrows, the client shows how
+    /// many are left as its durability.
+    pub fn stack(character_id: u32, item: &Item) -> Self {
+        let amount = item.amount.max(0) as u16;
+        Self {
+            durability: amount,
+            max_durability: amount,
+            ..Self::update(character_id, item)
+        }
+    }
+
+    /// Shows an item the character has equipped to the other players.
+    pub fn other_player(character_id: u32, item: &Item) -> Self {
+        Self::new(character_id, item, ItemInfoAction::OtherPlayerEquipement)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tq_network::PacketEncode;
+
+    #[test]
+    fn gems_are_written_in_place() {
+        let item = Item {
+            item_id: 7,
+            plus: 3,
+            gem_one: 13,
+            gem_two: 255,
+            ..Default::default()
+        };
+        let (_, bytes) = MsgItemInfo::update(1, &item).encode().unwrap();
+        assert_eq!(bytes.len(), 40);
+        assert_eq!(&bytes[20..22], &[13, 255]);
+        assert_eq!(bytes[24], 3);
+    }
+}
@@ -0,0 +1,57 @@
+use serde::Serialize;
+use tq_db::item::Item;
+use tq_network::PacketID;
+
+/// Where the item in a [`MsgItemInfoEx`] is shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum ViewMode {
+    None = 0,
+    /// For sale in a vending stall.
+    Booth = 1,
+    /// Equipped by another player.
+    Equipment = 4,
+}
+
+/// This packet is sent server>client to show an item that belongs to someone
+/// else, like the items for sale in a vending stall.
+#[derive(Debug, Serialize, Clone, PacketID, Default)]
+#[packet(id = 1108)]
+pub struct MsgItemInfoEx {
+    item_id: u32,
+    /// The owner of the item, for booths it is the seller.
+    target_id: u32,
+    /// The price in silver.
+    price: u32,
+    item_type: u32,
+    durability: u16,
+    max_durability: u16,
+    view_mode: u16,
+    position: u16,
+    /// Unknown
+    reserved0: u32,
+    gems: [u8; 2],
+    reborn_effect: u8,
+    magic: u8,
+    plus: u8,
+    bless: u8,
+    enchant: u8,
+    reserved1: u8,
+    restrain: u32,
+}
+
+impl MsgItemInfoEx {
+    /// An item for sale in the stall of `seller_id`.
+    pub fn booth(seller_id: u32, item: &Item, price: u32) -> Self {
+        Self {
+            item_id: item.item_id as u32,
+            target_id: seller_id,
+            price,
+            item_type: item.item_type as u32,
+            view_mode: ViewMode::Booth as u16,
+            gems: [item.gem_one as u8, item.gem_two as u8],
+            plus: item.plus as u8,
+            ..Default::default()
+        }
+    }
+}
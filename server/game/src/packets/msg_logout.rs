@@ -0,0 +1,212 @@
+use super::{MsgTalk, TalkChannel};
+use crate::entities::Character;
+use crate::{systems, ActorState, Error, State};
+use serde::{Deserialize, Serialize};
+use tq_network::{Actor, PacketID, PacketProcess};
+
+/// This packet is sent by the client to log the character out to the
+/// character select screen without closing the connection. The server
+/// answers with the same packet carrying a new login token, which the client
+/// uses in its next [`MsgConnect`](super::MsgConnect) to enter the game again.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PacketID)]
+#[packet(id = 1053)]
+pub struct MsgLogout {
+    pub character_id: u32,
+    pub token: u64,
+}
+
+#[async_trait::async_trait]
+impl PacketProcess for MsgLogout {
+    type ActorState = ActorState;
+    type Error = Error;
+    type State = State;
+
+    async fn process(
+        &self,
+        state: &Self::State,
+        actor: &Actor<Self::ActorState>,
+    ) -> Result<(), Self::Error> {
+        let entity = actor.try_entity()?;
+        let me = entity.as_character().ok_or(Error::CharacterNotFound)?;
+        if me.is_trading() {
+            actor
+                .send(MsgTalk::from_system(
+                    me.id(),
+                    TalkChannel::TopLeft,
+                    "You can not log out while trading.",
+                ))
+                .await?;
+            return Ok(());
+        }
+        let now = tokio::time::Instant::now().into_std();
+        if !systems::ensure_out_of_combat(state, me, now).await? {
+            return Ok(());
+        }
+        Self::to_character_select(state, actor, me).await
+    }
+}
+
+impl MsgLogout {
+    /// Takes the character out of the world and sends the client back to the
+    /// character select screen, with a token to get in again.
+    pub async fn to_character_select(
+        state: &State,
+        actor: &Actor<ActorState>,
+        me: &Character,
+    ) -> Result<(), Error> {
+        me.leave_world(state).await?;
+        actor.unbind();
+        let token = state.generate_login_token(
+            me.account_id(),
+            me.realm_id(),
+            actor.access(),
+        )?;
+        let msg = MsgLogout {
+            character_id: me.id(),
+            token: token.token,
+        };
+        actor.send(msg).await?;
+        tracing::info!(id = me.id(), "Logged out to character select");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::{ActionType, MsgAction};
+    use crate::test_utils::*;
+    use crate::world::Maps;
+    use futures::FutureExt;
+    use primitives::{Location, Size};
+    use tokio::sync::mpsc::Receiver;
+    use tq_network::Message;
+
+    /// Drains the actor's channel and returns the packets in it.
+    fn packets(
+        rx: &mut Receiver<Message>,
+    ) -> impl Iterator<Item = (u16, bytes::Bytes)> {
+        let mut packets = Vec::new();
+        while let Ok(msg) = rx.try_recv() {
+            if let Message::Packet(id, bytes) = msg {
+                packets.push((id, bytes));
+            }
+        }
+        packets.into_iter()
+    }
+
+    #[tokio::test]
+    async fn logout_from_idle() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let arena = u32::from(Maps::Arena);
+                let map = state.try_map(arena)?;
+                map.load_blank(Size::new(100, 100)).await?;
+                let [(a, mut a_rx), (b, mut b_rx)] = actors;
+                for (actor, x) in [(&a, 50), (&b, 52)] {
+                    let e = actor.entity();
+                    e.basic().set_map_id(arena);
+                    e.basic().set_location(Location::new(x, 50, 0));
+                    map.insert_entity(e).await?;
+                }
+                a.screen().load_surroundings(&state).await?;
+
+                let a_id = a.entity().id();
+                let msg = MsgLogout {
+                    character_id: a_id,
+                    token: 0,
+                };
+                msg.process(&state, &a).await?;
+
+                assert!(a.try_entity().is_err());
+                assert!(a.try_screen().is_err());
+                assert!(state.with_entity(a_id, |_| ()).is_none());
+                let leaves: Vec<_> = packets_of::<MsgAction>(&mut b_rx)
+                    .into_iter()
+                    .filter(|msg| {
+                        matches!(
+                            ActionType::from(msg.action_type),
+                            ActionType::LeaveMap
+                        )
+                    })
+                    .collect();
+                assert_eq!(leaves.len(), 1);
+                assert_eq!(leaves[0].character_id, a_id);
+                assert!(b.screen().with_entities(|c| !c.contains_key(&a_id)));
+
+                let answers = packets_of::<MsgLogout>(&mut a_rx);
+                assert_eq!(answers.len(), 1);
+                let answer = &answers[0];
+                assert_eq!(answer.character_id, a_id);
+                // The token lets the client back in.
+                let info = state.remove_login_token(answer.token)?;
+                assert_eq!(info.account_id, 1);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn logout_blocked_during_trade() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, _)] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                me.set_trade_partner(Some(b.entity().id()));
+                let msg = MsgLogout {
+                    character_id: me.id(),
+                    token: 0,
+                };
+                msg.process(&state, &a).await?;
+
+                assert!(a.try_entity().is_ok());
+                assert!(state.with_entity(me.id(), |_| ()).is_some());
+                let ids: Vec<_> =
+                    packets(&mut a_rx).map(|(id, _)| id).collect();
+                assert_eq!(ids, [MsgTalk::PACKET_ID]);
+
+                me.set_trade_partner(None);
+                msg.process(&state, &a).await?;
+                assert!(a.try_entity().is_err());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn logout_blocked_right_after_a_fight() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let msg = MsgLogout {
+                    character_id: me.id(),
+                    token: 0,
+                };
+
+                let now = tokio::time::Instant::now().into_std();
+                me.on_attacked(now);
+                msg.process(&state, &a).await?;
+                assert!(a.try_entity().is_ok());
+                let ids: Vec<_> =
+                    packets(&mut a_rx).map(|(id, _)| id).collect();
+                assert_eq!(ids, [MsgTalk::PACKET_ID]);
+
+                // The fight is as old as the lock now. The clock is not
+                // paused for this, the database pool times out on it.
+                me.on_attacked(now - state.combat_lock());
+                msg.process(&state, &a).await?;
+                assert!(a.try_entity().is_err());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
@@ -0,0 +1,181 @@
+use crate::entities::FloorItem;
+use num_enum::{FromPrimitive, IntoPrimitive};
+use serde::{Deserialize, Serialize};
+use tq_network::PacketID;
+
+/// What to do with the item in a [`MsgMapItem`] packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive, IntoPrimitive)]
+#[repr(u16)]
+pub enum MapItemAction {
+    #[num_enum(default)]
+    Unknown = 0,
+    /// Shows the item on the floor.
+    Create = 1,
+    /// Removes the item from the floor.
+    Delete = 2,
+    /// Sent by the client when trying to pick the item up.
+    Pick = 3,
+}
+
+/// How the client shows the item in a [`MsgMapItem`] packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive, IntoPrimitive)]
+#[repr(u8)]
+pub enum MapItemMode {
+    #[num_enum(default)]
+    Normal = 0,
+    /// Highlighted as the item of the one who gets the packet, while it is
+    /// kept for them.
+    Owned = 1,
+}
+
+/// This packet is used to spawn and remove items lying on the floor of the
+/// map, it is also sent by the client when picking an item up.
+#[derive(Debug, Clone, Serialize, Deserialize, PacketID)]
+#[packet(id = 1101)]
+pub struct MsgMapItem {
+    pub id: u32,
+    pub item_type: u32,
+    pub x: u16,
+    pub y: u16,
+    pub color: u8,
+    pub mode: u8,
+    pub action: u16,
+}
+
+impl MsgMapItem {
+    pub fn new(item: &FloorItem, action: MapItemAction) -> Self {
+        let location = item.entity().location();
+        Self {
+            id: item.id(),
+            item_type: item.item_type(),
+            x: location.x,
+            y: location.y,
+            color: item.color(),
+            mode: MapItemMode::Normal.into(),
+            action: action.into(),
+        }
+    }
+
+    /// Spawns the item to the client.
+    pub fn create(item: &FloorItem) -> Self {
+        Self::new(item, MapItemAction::Create)
+    }
+
+    /// Spawns the item to the client of the given character, highlighted if
+    /// it is kept for them.
+    pub fn create_for(item: &FloorItem, character_id: u32) -> Self {
+        let mut msg = Self::create(item);
+        if item.is_protected_for(character_id) {
+            msg.mode = MapItemMode::Owned.into();
+        }
+        msg
+    }
+
+    /// Removes the item from the client.
+    pub fn delete(item: &FloorItem) -> Self {
+        Self::new(item, MapItemAction::Delete)
+    }
+
+    pub fn action(&self) -> MapItemAction { MapItemAction::from(self.action) }
+
+    pub fn mode(&self) -> MapItemMode { MapItemMode::from(self.mode) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants;
+    use crate::entities::FloorItemKind;
+    use bytes::{BufMut, BytesMut};
+    use primitives::Location;
+    use tq_network::PacketEncode;
+
+    const ID: u32 = 800_001;
+    const OWNER: u32 = 1_000_001;
+
+    fn floor_item(kind: FloorItemKind) -> FloorItem {
+        FloorItem::new(ID, kind, 1002, Location::new(300, 278, 0))
+    }
+
+    fn raw(item_type: u32, color: u8, mode: u8, action: u16) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        buf.put_u32_le(ID);
+        buf.put_u32_le(item_type);
+        buf.put_u16_le(300);
+        buf.put_u16_le(278);
+        buf.put_u8(color);
+        buf.put_u8(mode);
+        buf.put_u16_le(action);
+        buf.to_vec()
+    }
+
+    fn bytes(msg: MsgMapItem) -> Vec<u8> {
+        let (id, bytes) = msg.encode().unwrap();
+        assert_eq!(id, MsgMapItem::PACKET_ID);
+        bytes.to_vec()
+    }
+
+    #[test]
+    fn items_on_the_floor() {
+        let item = floor_item(FloorItemKind::Item(410301));
+        assert_eq!(bytes(MsgMapItem::create(&item)), raw(410301, 0, 0, 1));
+        assert_eq!(bytes(MsgMapItem::delete(&item)), raw(410301, 0, 0, 2));
+    }
+
+    #[test]
+    fn only_the_owner_gets_the_highlight() {
+        let item = floor_item(FloorItemKind::Item(410301)).with_owner(OWNER);
+        assert_eq!(
+            bytes(MsgMapItem::create_for(&item, OWNER)),
+            raw(410301, 0, 1, 1)
+        );
+        assert_eq!(
+            bytes(MsgMapItem::create_for(&item, OWNER + 1)),
+            raw(410301, 0, 0, 1)
+        );
+        let unowned = floor_item(FloorItemKind::Item(410301));
+        assert_eq!(
+            bytes(MsgMapItem::create_for(&unowned, OWNER)),
+            raw(410301, 0, 0, 1)
+        );
+    }
+
+    #[test]
+    fn gems_and_meteors_are_colored() {
+        // A super dragon gem, and a refined phoenix gem.
+        let gem = floor_item(FloorItemKind::Item(700013));
+        assert_eq!(bytes(MsgMapItem::create(&gem)), raw(700013, 3, 0, 1));
+        let gem = floor_item(FloorItemKind::Item(700002));
+        assert_eq!(bytes(MsgMapItem::create(&gem)), raw(700002, 2, 0, 1));
+        let tear = floor_item(FloorItemKind::Item(constants::METEOR_TEAR));
+        assert_eq!(
+            bytes(MsgMapItem::create(&tear)),
+            raw(constants::METEOR_TEAR, 1, 0, 1)
+        );
+        let meteor = floor_item(FloorItemKind::Item(constants::METEOR));
+        assert_eq!(
+            bytes(MsgMapItem::create(&meteor)),
+            raw(constants::METEOR, 0, 0, 1)
+        );
+    }
+
+    #[test]
+    fn money_piles_grow_with_the_amount() {
+        let piles = [
+            (5, 1090000),
+            (50, 1090010),
+            (500, 1090020),
+            (2000, 1091000),
+            (5000, 1091010),
+            (50000, 1091020),
+        ];
+        for (amount, item_type) in piles {
+            let pile = floor_item(FloorItemKind::Silver(amount));
+            assert_eq!(
+                bytes(MsgMapItem::create(&pile)),
+                raw(item_type, 0, 0, 1),
+                "{amount} silver"
+            );
+        }
+    }
+}
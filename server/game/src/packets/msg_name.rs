@@ -0,0 +1,51 @@
+use num_enum::{FromPrimitive, IntoPrimitive};
+use serde::{Deserialize, Serialize};
+use tq_network::PacketID;
+use tq_serde::StringList;
+
+/// What the strings of a [`MsgName`] packet are about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive, IntoPrimitive)]
+#[repr(u8)]
+pub enum NameAction {
+    #[num_enum(default)]
+    None = 0,
+    Fireworks = 1,
+    CreateSyndicate = 2,
+    Syndicate = 3,
+    ChangeTitle = 4,
+    DeleteRole = 5,
+    /// The spouse of the character changed.
+    Spouse = 6,
+    QueryNpc = 7,
+}
+
+/// This packet is used to update one or more names shown by the client,
+/// like the spouse of a character.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PacketID)]
+#[packet(id = 1015)]
+pub struct MsgName {
+    pub data: u32,
+    pub action: u8,
+    pub names: StringList,
+}
+
+impl MsgName {
+    /// Tells the client about the new spouse of the character, the client
+    /// expects `None` for a single character.
+    pub fn spouse(character_id: u32, spouse: &str) -> Self {
+        Self {
+            data: character_id,
+            action: NameAction::Spouse.into(),
+            names: StringList::from(vec![spouse]),
+        }
+    }
+
+    /// Tells the client the name of the guild with the given id.
+    pub fn syndicate(guild_id: u32, name: &str) -> Self {
+        Self {
+            data: guild_id,
+            action: NameAction::Syndicate.into(),
+            names: StringList::from(vec![name]),
+        }
+    }
+}
@@ -4,6 +4,7 @@ use tq_network::{Actor, PacketID, PacketProcess};
 
 use crate::entities::NpcKind;
 use crate::packets::{MsgAction, MsgTalk, MsgTaskDialog};
+use crate::systems;
 
 #[derive(Default, Debug, Clone, Copy, FromPrimitive, IntoPrimitive)]
 #[repr(u16)]
@@ -40,6 +41,7 @@ impl PacketProcess for MsgNpc {
         state: &Self::State,
         actor: &Actor<Self::ActorState>,
     ) -> Result<(), Self::Error> {
+        actor.ensure_in_world()?;
         tracing::debug!(
             npc_id = self.npc_id,
             data = self.data,
@@ -72,6 +74,20 @@ impl PacketProcess for MsgNpc {
             // that are not in the screen.
             return Ok(());
         }
+        mycharacter.set_dialog_npc(Some(npc.id()));
+        if systems::run_npc_script(state, mycharacter, npc.id(), None).await? {
+            return Ok(());
+        }
+        if npc.id() == systems::MATCHMAKER_NPC {
+            actor
+                .send_all(systems::matchmaker_dialog(mycharacter))
+                .await?;
+            return Ok(());
+        }
+        if npc.id() == systems::REBIRTH_NPC {
+            actor.send_all(systems::rebirth_dialog(mycharacter)).await?;
+            return Ok(());
+        }
         // Storage NPCs
         if npc.is_storage() {
             actor
@@ -81,6 +97,10 @@ impl PacketProcess for MsgNpc {
                     super::ActionType::OpenDialog,
                 ))
                 .await?;
+            // A locked warehouse shows nothing until it is unlocked.
+            if systems::warehouse_unlocked(state, actor, mycharacter).await? {
+                systems::show_warehouse(state, mycharacter, npc.id()).await?;
+            }
             return Ok(());
         }
         // For now, lets try sending a dummy dialog
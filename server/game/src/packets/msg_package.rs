@@ -0,0 +1,80 @@
+use serde::Serialize;
+use tq_db::warehouse::WarehouseItem;
+use tq_network::PacketID;
+
+/// What a [`MsgPackage`] is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PackageAction {
+    /// The full list of items.
+    Query = 0,
+}
+
+/// Where the items of a [`MsgPackage`] are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PackageKind {
+    Storage = 10,
+}
+
+/// An item in a [`MsgPackage`], the id is the one to take it out with.
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct PackageItem {
+    item_id: u32,
+    item_type: u32,
+    /// Unknown
+    reserved0: u8,
+    gems: [u8; 2],
+    magic: u8,
+    plus: u8,
+    bless: u8,
+    enchant: u8,
+    /// Unknown
+    reserved1: u8,
+    amount: u16,
+    /// Unknown
+    reserved2: u16,
+}
+
+impl From<&WarehouseItem> for PackageItem {
+    fn from(item: &WarehouseItem) -> Self {
+        Self {
+            item_id: item.warehouse_item_id as u32,
+            item_type: item.item_type as u32,
+            gems: [item.gem_one as u8, item.gem_two as u8],
+            plus: item.plus as u8,
+            amount: item.amount as u16,
+            ..Default::default()
+        }
+    }
+}
+
+/// This packet is sent server>client to show the items kept in a warehouse,
+/// when it is opened and every time an item goes in or out.
+#[derive(Debug, Serialize, Clone, PacketID, Default)]
+#[packet(id = 1102)]
+pub struct MsgPackage {
+    /// The warehouseman the items are kept at.
+    npc_id: u32,
+    action: u8,
+    kind: u8,
+    /// Unknown
+    reserved0: u16,
+    count: u32,
+    items: Vec<PackageItem>,
+}
+
+impl MsgPackage {
+    /// The items in the warehouse of an account, at the warehouseman
+    /// `npc_id`.
+    pub fn warehouse(npc_id: u32, items: &[WarehouseItem]) -> Self {
+        Self {
+            npc_id,
+            action: PackageAction::Query as u8,
+            kind: PackageKind::Storage as u8,
+            count: items.len() as u32,
+            items: items.iter().map(PackageItem::from).collect(),
+            ..Default::default()
+        }
+    }
+}
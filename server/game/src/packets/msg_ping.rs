@@ -0,0 +1,103 @@
+use crate::{ActorState, Error, State};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tq_network::{Actor, ActorHandle, PacketID, PacketProcess};
+
+/// How often every client gets pinged.
+pub const PING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Clients with a round trip time above this get logged.
+pub const HIGH_LATENCY: Duration = Duration::from_millis(400);
+
+/// This packet is used to measure the round trip time to the client. The
+/// server sends it with a token, and the client echoes the same token back.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PacketID)]
+#[packet(id = 1012)]
+pub struct MsgPing {
+    pub character_id: u32,
+    pub token: u32,
+}
+
+impl MsgPing {
+    /// Pings the client behind the given actor.
+    pub async fn send(
+        character_id: u32,
+        to: &ActorHandle,
+        now: Instant,
+    ) -> Result<(), Error> {
+        let token = to.start_ping(now);
+        to.send_now(Self {
+            character_id,
+            token,
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl PacketProcess for MsgPing {
+    type ActorState = ActorState;
+    type Error = Error;
+    type State = State;
+
+    async fn process(
+        &self,
+        state: &Self::State,
+        actor: &Actor<Self::ActorState>,
+    ) -> Result<(), Self::Error> {
+        let now = Instant::now();
+        match actor.handle().record_pong(self.token, now) {
+            Some(rtt) if rtt > HIGH_LATENCY => {
+                tracing::warn!(id = actor.id(), ?rtt, "High latency client");
+            },
+            Some(rtt) => {
+                tracing::trace!(id = actor.id(), ?rtt, "Ping answered");
+            },
+            None => {
+                tracing::debug!(token = self.token, "Unexpected ping answer");
+            },
+        }
+        // Not a sign of life, see `PASSIVE_PACKETS`, but the only packet an
+        // idle client still sends.
+        crate::systems::check_afk(state, actor, now).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use futures::FutureExt;
+    use tq_network::{Message, PacketDecode};
+
+    #[tokio::test]
+    async fn echoed_ping_records_rtt() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, _)] = actors;
+                let sent_at = Instant::now();
+                state.ping_all(sent_at).await;
+                let ping = loop {
+                    match a_rx.try_recv() {
+                        Ok(Message::Packet(MsgPing::PACKET_ID, bytes)) => {
+                            break MsgPing::decode(&bytes)?;
+                        },
+                        Ok(_) => continue,
+                        Err(_) => panic!("no ping was sent"),
+                    }
+                };
+                assert_eq!(ping.character_id, a.entity().id());
+                ping.process(&state, &a).await?;
+                let rtt = a.rtt().expect("rtt should be recorded");
+                assert!(rtt <= sent_at.elapsed());
+                // The other client never answered.
+                assert_eq!(b.rtt(), None);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
@@ -1,4 +1,4 @@
-use crate::entities::Character;
+use crate::entities::{Character, Monster};
 use serde::{Deserialize, Serialize};
 use tq_network::PacketID;
 
@@ -37,8 +37,12 @@ pub struct MsgPlayer {
     nobility_rank: i32,
     character_id2: i32,
     nobility_position: i32,
+    /// Number of Strings to follow
+    /// 1: Character Name
+    /// 2: Spouse Name
     list_count: u8,
     pub character_name: String,
+    pub spouse: String,
 }
 
 impl From<&Character> for MsgPlayer {
@@ -48,18 +52,97 @@ impl From<&Character> for MsgPlayer {
             character_id: c.id() as i32,
             character_id2: c.id() as i32,
             mesh: (c.entity().mesh() + (c.avatar() as u32 * 10_000)) as i32,
-            health_points: c.entity().hp().current(),
+            health_points: c.health_points(),
             hair_style: c.hair_style() as i16,
             level: c.entity().level() as i16,
             level2: c.entity().level() as i16,
             x: loc.x,
             y: loc.y,
             direction: loc.direction,
-            list_count: 1,
-            character_name: c.entity().name().to_owned(),
+            list_count: 2,
+            character_name: c.entity().name().to_string(),
+            spouse: c.spouse(),
             status_flags: c.entity().flags().bits() as i64,
             action: c.entity().action() as u8,
             ..Default::default()
         }
     }
 }
+
+impl MsgPlayer {
+    /// The spawn of a monster, the client tells it apart from a player by its
+    /// id, it has the lookface of its type as the mesh and only its name.
+    pub fn from_monster(m: &Monster) -> Self {
+        let entity = m.entity();
+        let loc = entity.location();
+        Self {
+            character_id: m.id() as i32,
+            character_id2: m.id() as i32,
+            mesh: entity.mesh() as i32,
+            status_flags: entity.flags().bits() as i64,
+            health_points: entity.hp().current(),
+            level: entity.level() as i16,
+            level2: entity.level() as i16,
+            x: loc.x,
+            y: loc.y,
+            direction: loc.direction,
+            action: entity.action() as u8,
+            list_count: 1,
+            character_name: entity.name().to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::{MonsterStats, MonsterType};
+    use bytes::{BufMut, BytesMut};
+    use primitives::Location;
+    use std::sync::Arc;
+    use tq_network::PacketEncode;
+
+    #[test]
+    fn monster_spawn_layout() {
+        let kind = MonsterType::new(1, "Pheasant").with_stats(MonsterStats {
+            lookface: 104,
+            level: 3,
+            life: 33,
+            ..Default::default()
+        });
+        let id = 400_001;
+        let monster =
+            Monster::new(id, Arc::new(kind), 1002, Location::new(300, 278, 5));
+        let (packet_id, bytes) =
+            MsgPlayer::from_monster(&monster).encode().unwrap();
+        assert_eq!(packet_id, MsgPlayer::PACKET_ID);
+
+        let mut raw = BytesMut::new();
+        raw.put_u32_le(id);
+        raw.put_u32_le(104); // mesh
+        raw.put_u64_le(0); // status flags
+        raw.put_u16_le(0); // syndicate
+        raw.put_u8(0);
+        raw.put_u8(0); // syndicate rank
+        raw.put_slice(&[0; 6 * 4]); // equipment
+        raw.put_u16_le(33); // health points
+        raw.put_u16_le(3); // level
+        raw.put_u16_le(300);
+        raw.put_u16_le(278);
+        raw.put_u16_le(0); // hair style
+        raw.put_u8(5); // direction
+        raw.put_u8(100); // action
+        raw.put_u16_le(0); // metempsychosis
+        raw.put_u16_le(3); // level again
+        raw.put_u32_le(0);
+        raw.put_u32_le(0); // nobility rank
+        raw.put_u32_le(id);
+        raw.put_u32_le(0); // nobility position
+        raw.put_u8(1); // one string
+        raw.put_u8(8);
+        raw.put_slice(b"Pheasant");
+        raw.put_u8(0); // no spouse
+        assert_eq!(bytes.to_vec(), raw.to_vec());
+    }
+}
@@ -3,7 +3,7 @@ use crate::entities::Character;
 use crate::systems::Screen;
 use crate::{ActorState, Error, State};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
-use rand::{Rng, SeedableRng};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::convert::TryFrom;
 use tq_network::{Actor, IntoErrorPacket, PacketID, PacketProcess};
@@ -21,31 +21,37 @@ pub struct MsgRegister {
 }
 
 impl MsgRegister {
-    pub fn build_character(
+    pub fn build_character<R: Rng + ?Sized>(
         &self,
         account_id: u32,
         realm_id: u32,
+        rng: &mut R,
     ) -> Result<tq_db::character::Character, Error> {
+        let mesh = BodyType::try_from(self.mesh)
+            .map_err(|_| Error::InvalidBodyType)?;
+        let class =
+            BaseClass::try_from(self.class).map_err(|_| Error::InvalidClass)?;
+        if !mesh.allows(class) {
+            return Err(Error::InvalidClass);
+        }
         Self::build_character_with(
             self.character_name.to_string(),
-            BodyType::try_from(self.mesh)
-                .map_err(|_| Error::InvalidBodyType)?,
-            BaseClass::try_from(self.class).map_err(|_| Error::InvalidClass)?,
+            mesh,
+            class,
             account_id,
             realm_id,
+            rng,
         )
     }
 
-    pub fn build_character_with(
+    pub fn build_character_with<R: Rng + ?Sized>(
         name: String,
         mesh: BodyType,
         class: BaseClass,
         account_id: u32,
         realm_id: u32,
+        rng: &mut R,
     ) -> Result<tq_db::character::Character, Error> {
-        // Some Math for rand characher.
-        let mut rng = rand::rngs::StdRng::from_entropy();
-
         let avatar = match u16::from(mesh) {
             // For Male
             m if m < 1005 => rng.gen_range(1..49),
@@ -56,20 +62,14 @@ impl MsgRegister {
 
         let hair_style = rng.gen_range(3..9) * 100
             + crate::constants::HAIR_STYLES[rng.gen_range(0..12)];
-        let strength = match class {
-            BaseClass::Taoist => 2,
-            _ => 4,
-        };
-        let agility = 6;
-        let vitality = 12;
-        let spirit = match class {
-            BaseClass::Taoist => 10,
-            _ => 0,
-        };
-
-        let health_points =
-            (strength * 3) + (agility * 3) + (spirit * 3) + (vitality * 24);
-        let mana_points = spirit * 5;
+        let [strength, agility, vitality, spirit] =
+            crate::constants::base_attributes(u16::from(class) as u8);
+        let health_points = crate::constants::max_health_points(
+            strength, agility, vitality, spirit,
+        ) as i16;
+        let mana_points = crate::constants::max_mana_points(spirit) as i16;
+        let [strength, agility, vitality, spirit] =
+            [strength, agility, vitality, spirit].map(|v| v as i16);
 
         let c = tq_db::character::Character {
             account_id: account_id as i32,
@@ -81,9 +81,9 @@ impl MsgRegister {
             silver: 1000,
             cps: 0,
             current_class: u16::from(class) as i16,
-            map_id: 1010,
-            x: 61,
-            y: 109,
+            map_id: crate::constants::NEWBIE_MAP_ID as i32,
+            x: crate::constants::NEWBIE_LOCATION.0 as i16,
+            y: crate::constants::NEWBIE_LOCATION.1 as i16,
             virtue: 0,
             strength,
             agility,
@@ -91,13 +91,16 @@ impl MsgRegister {
             spirit,
             health_points,
             mana_points,
+            spouse: tq_db::character::Character::NO_SPOUSE.to_owned(),
             ..Default::default()
         };
         Ok(c)
     }
 }
 
-#[derive(Copy, Clone, Debug, TryFromPrimitive, IntoPrimitive)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, TryFromPrimitive, IntoPrimitive,
+)]
 #[repr(u16)]
 pub enum BodyType {
     AgileMale = 1003,
@@ -106,7 +109,9 @@ pub enum BodyType {
     MuscularFemale = 2002,
 }
 
-#[derive(Copy, Clone, Debug, TryFromPrimitive, IntoPrimitive)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, TryFromPrimitive, IntoPrimitive,
+)]
 #[repr(u16)]
 pub enum BaseClass {
     Trojan = 10,
@@ -115,6 +120,28 @@ pub enum BaseClass {
     Taoist = 100,
 }
 
+/// The classes a new character could start as, by body type. Archers are
+/// kept to the agile bodies.
+const CLASSES_BY_BODY: [(BodyType, &[BaseClass]); 4] = {
+    use BaseClass::*;
+    [
+        (BodyType::AgileMale, &[Trojan, Warrior, Archer, Taoist]),
+        (BodyType::MuscularMale, &[Trojan, Warrior, Taoist]),
+        (BodyType::AgileFemale, &[Trojan, Warrior, Archer, Taoist]),
+        (BodyType::MuscularFemale, &[Trojan, Warrior, Taoist]),
+    ]
+};
+
+impl BodyType {
+    /// Whether a new character with this body could start as `class`.
+    pub fn allows(self, class: BaseClass) -> bool {
+        CLASSES_BY_BODY
+            .iter()
+            .find(|(body, _)| *body == self)
+            .is_some_and(|(_, classes)| classes.contains(&class))
+    }
+}
+
 #[async_trait::async_trait]
 impl PacketProcess for MsgRegister {
     type ActorState = ActorState;
@@ -126,9 +153,19 @@ impl PacketProcess for MsgRegister {
         state: &Self::State,
         actor: &Actor<Self::ActorState>,
     ) -> Result<(), Self::Error> {
+        let locale = actor.locale();
+        // The token is only consumed once the character is in the world, so
+        // the client could try again if anything goes wrong on the way.
         let info = state
-            .remove_creation_token(self.token)
-            .map_err(|_| MsgTalk::register_invalid().error_packet())?;
+            .creation_token(self.token)
+            .map_err(|_| MsgTalk::register_invalid(locale).error_packet())?;
+
+        if let Err(reason) = state.name_filter().check(&self.character_name) {
+            tracing::debug!(name = %self.character_name, ?reason, "Name rejected");
+            return Err(MsgTalk::register_name_not_allowed(locale)
+                .error_packet()
+                .into());
+        }
 
         if tq_db::character::Character::name_taken(
             state.pool(),
@@ -136,33 +173,93 @@ impl PacketProcess for MsgRegister {
         )
         .await?
         {
-            return Err(MsgTalk::register_name_taken().error_packet().into());
+            return Err(MsgTalk::register_name_taken(locale)
+                .error_packet()
+                .into());
         }
 
         // Validate Data.
-        BodyType::try_from(self.mesh)
-            .map_err(|_| MsgTalk::register_invalid().error_packet())?;
-        BaseClass::try_from(self.class)
-            .map_err(|_| MsgTalk::register_invalid().error_packet())?;
+        let mesh = BodyType::try_from(self.mesh)
+            .map_err(|_| MsgTalk::register_invalid(locale).error_packet())?;
+        let class = BaseClass::try_from(self.class)
+            .map_err(|_| MsgTalk::register_invalid(locale).error_packet())?;
+        if !mesh.allows(class) {
+            tracing::debug!(?mesh, ?class, "Invalid body and class");
+            return Err(MsgTalk::register_invalid(locale)
+                .error_packet()
+                .into());
+        }
 
+        // Nothing gets committed until the character passed the checks, if we
+        // fail or the client drops before that, the character is rolled back.
+        let mut tx = state.pool().begin().await?;
         let character_id = self
-            .build_character(info.account_id, info.realm_id)?
-            .save(state.pool())
-            .await?;
+            .build_character(
+                info.account_id,
+                info.realm_id,
+                &mut state.rng().fork(),
+            )?
+            .save(&mut *tx)
+            .await
+            .map_err(|e| match Error::from(e) {
+                // Taken by someone else since we checked.
+                Error::DuplicateName(_) => {
+                    MsgTalk::register_name_taken(locale).error_packet().into()
+                },
+                e => e,
+            })?;
+        // Counted after the insert, which holds the write lock until the
+        // commit, so two clients of the same account creating a character at
+        // once could not both get under the cap.
+        let characters = tq_db::character::Character::count_of_account(
+            &mut *tx,
+            info.account_id,
+        )
+        .await?;
+        if characters > state.max_characters() {
+            tracing::debug!(
+                account_id = info.account_id,
+                %characters,
+                "Too many characters"
+            );
+            return Err(MsgTalk::register_character_limit(locale)
+                .error_packet()
+                .into());
+        }
         let character =
-            tq_db::character::Character::by_id(state.pool(), character_id)
-                .await?;
+            tq_db::character::Character::by_id(&mut *tx, character_id).await?;
+        // Committed before anyone in the world gets to see the character, so
+        // the other writers are not held up by it.
+        tx.commit().await?;
         let map_id = character.map_id;
         let me = Character::new(actor.handle(), character);
+        let me_id = me.id();
         let screen = Screen::new(actor.handle());
         actor.update(me, screen);
         state.insert_entity(actor.entity());
-        // Set player map.
-        state
-            .try_map(map_id as _)
-            .map_err(|_| MsgTalk::register_invalid().error_packet())?
-            .insert_entity(actor.entity())
-            .await?;
+        let entered = async {
+            // Set player map.
+            state
+                .try_map(map_id as _)
+                .map_err(|_| MsgTalk::register_invalid(locale).error_packet())?
+                .insert_entity(actor.entity())
+                .await?;
+            Ok::<_, Error>(())
+        }
+        .await;
+        if let Err(e) = entered {
+            if let Ok(map) = state.try_map(map_id as _) {
+                // It might not be there yet, that's fine.
+                let _ = map.remove_entity(&actor.entity());
+            }
+            state.remove_entity(me_id);
+            actor.unbind();
+            // Undone, so the client could try again with the same name.
+            tq_db::character::Character::delete(state.pool(), character_id)
+                .await?;
+            return Err(e);
+        }
+        state.remove_creation_token(self.token)?;
 
         tracing::info!(
             "Account #{} Created Character #{} with Name {}",
@@ -174,3 +271,220 @@ impl PacketProcess for MsgRegister {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use futures::FutureExt;
+    use primitives::Size;
+    use tq_db::character::Character as DbCharacter;
+
+    #[tokio::test]
+    async fn failed_registration_could_be_retried() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [_, (b, _)] = actors;
+                // Free the second test account, so it could register again.
+                state.remove_entity(b.entity().id());
+                b.unbind();
+                sqlx::query("DELETE FROM characters WHERE account_id = 2;")
+                    .execute(state.pool())
+                    .await?;
+
+                let token = 4242;
+                state.store_creation_token(token, 2, 1)?;
+                let msg = MsgRegister {
+                    character_name: "newbie".into(),
+                    mesh: BodyType::MuscularMale.into(),
+                    class: BaseClass::Trojan.into(),
+                    token,
+                    ..Default::default()
+                };
+                // The spawn map has no map data in the tests, so entering the
+                // world fails right after the character got saved.
+                let map = state.try_map(1010)?;
+                assert!(msg.process(&state, &b).await.is_err());
+                assert!(DbCharacter::from_account(state.pool(), 2)
+                    .await?
+                    .is_none());
+                assert!(b.try_entity().is_err());
+                assert_eq!(state.entities().len(), 1);
+                assert!(state.creation_token(token).is_ok());
+
+                map.load_blank(Size::new(1000, 1000)).await?;
+                msg.process(&state, &b).await?;
+                let character = DbCharacter::from_account(state.pool(), 2)
+                    .await?
+                    .expect("character was saved");
+                assert_eq!(character.name, "newbie");
+                let id = b.entity().id();
+                assert!(state.with_entity(id, |_| ()).is_some());
+                assert!(state.creation_token(token).is_err());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn body_and_class_must_match() -> Result<(), Error> {
+        use crate::systems::Locale;
+
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [_, (b, _)] = actors;
+                state.remove_entity(b.entity().id());
+                b.unbind();
+                sqlx::query("DELETE FROM characters WHERE account_id = 2;")
+                    .execute(state.pool())
+                    .await?;
+                state
+                    .try_map(1010)?
+                    .load_blank(Size::new(1000, 1000))
+                    .await?;
+
+                let token = 4242;
+                state.store_creation_token(token, 2, 1)?;
+                let mut msg = MsgRegister {
+                    character_name: "archer".into(),
+                    mesh: BodyType::MuscularMale.into(),
+                    class: BaseClass::Archer.into(),
+                    token,
+                    ..Default::default()
+                };
+                match msg.process(&state, &b).await {
+                    Err(Error::Msg(talk)) => {
+                        assert_eq!(
+                            talk.message,
+                            MsgTalk::register_invalid(Locale::English).message
+                        );
+                    },
+                    other => panic!("expected to be rejected, got {other:?}"),
+                }
+                assert!(DbCharacter::from_account(state.pool(), 2)
+                    .await?
+                    .is_none());
+                assert!(msg
+                    .build_character(2, 1, &mut rand::thread_rng())
+                    .is_err());
+
+                msg.mesh = BodyType::AgileMale.into();
+                msg.process(&state, &b).await?;
+                let character = DbCharacter::from_account(state.pool(), 2)
+                    .await?
+                    .expect("character was saved");
+                assert_eq!(character.current_class, 40);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn names_not_allowed_are_rejected() -> Result<(), Error> {
+        use crate::systems::Locale;
+
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [_, (b, _)] = actors;
+                state.remove_entity(b.entity().id());
+                b.unbind();
+                sqlx::query("DELETE FROM characters WHERE account_id = 2;")
+                    .execute(state.pool())
+                    .await?;
+                let token = 4242;
+                state.store_creation_token(token, 2, 1)?;
+                for name in ["GM_Bob", "5hitty"] {
+                    let msg = MsgRegister {
+                        character_name: name.into(),
+                        mesh: BodyType::MuscularMale.into(),
+                        class: BaseClass::Trojan.into(),
+                        token,
+                        ..Default::default()
+                    };
+                    match msg.process(&state, &b).await {
+                        Err(Error::Msg(talk)) => {
+                            let expected = MsgTalk::register_name_not_allowed(
+                                Locale::English,
+                            );
+                            assert_eq!(talk.message, expected.message);
+                        },
+                        other => panic!("{name} got through: {other:?}"),
+                    }
+                }
+                assert!(DbCharacter::from_account(state.pool(), 2)
+                    .await?
+                    .is_none());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn characters_per_account_are_capped() -> Result<(), Error> {
+        use crate::systems::Locale;
+
+        /// Registers a new character for the second test account from `b`,
+        /// as if it went back to the character creation.
+        async fn register(
+            state: &State,
+            b: &Actor<ActorState>,
+            name: &str,
+        ) -> Result<(), Error> {
+            if let Ok(entity) = b.try_entity() {
+                state.remove_entity(entity.id());
+                b.unbind();
+            }
+            let token = 4242;
+            state.store_creation_token(token, 2, 1)?;
+            let msg = MsgRegister {
+                character_name: name.into(),
+                mesh: BodyType::MuscularMale.into(),
+                class: BaseClass::Trojan.into(),
+                token,
+                ..Default::default()
+            };
+            msg.process(state, b).await
+        }
+
+        with_test_env(tracing::Level::DEBUG, |mut state, actors| {
+            async move {
+                state.set_max_characters(2);
+                let [_, (b, _)] = actors;
+                state
+                    .try_map(1010)?
+                    .load_blank(Size::new(1000, 1000))
+                    .await?;
+                let count = || DbCharacter::count_of_account(state.pool(), 2);
+                // The test character is the first one.
+                assert_eq!(count().await?, 1);
+                register(&state, &b, "second").await?;
+                assert_eq!(count().await?, 2);
+                let second = b.entity().as_character().unwrap().character_id();
+
+                match register(&state, &b, "third").await {
+                    Err(Error::Msg(talk)) => {
+                        let expected =
+                            MsgTalk::register_character_limit(Locale::English);
+                        assert_eq!(talk.message, expected.message);
+                    },
+                    other => panic!("expected to be rejected, got {other:?}"),
+                }
+                assert_eq!(count().await?, 2);
+
+                // Deleting one frees its slot.
+                DbCharacter::delete(state.pool(), second).await?;
+                register(&state, &b, "third").await?;
+                assert_eq!(count().await?, 2);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
@@ -0,0 +1,97 @@
+use crate::{systems, ActorState, Error, State};
+use num_enum::{FromPrimitive, IntoPrimitive};
+use serde::{Deserialize, Serialize};
+use tq_network::{Actor, PacketID, PacketProcess};
+use tq_serde::StringList;
+
+/// The kind of guild action in a [`MsgSyndicate`] packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive, IntoPrimitive)]
+#[repr(u32)]
+pub enum SyndicateAction {
+    #[num_enum(default)]
+    None = 0,
+    /// Accepts the invitation of the character `data`.
+    ApplyJoin = 1,
+    /// Invites the character `data`, the server forwards it to the invited
+    /// character with `data` being whoever invited it.
+    InviteJoin = 2,
+    Leave = 3,
+    /// Creates a guild named after the first of the names.
+    Create = 101,
+    /// Promotes the character `data` to deputy leader.
+    Promote = 102,
+    Disband = 103,
+}
+
+/// This packet is used to manage guilds, also known as syndicates: creating
+/// them, inviting and promoting members, leaving and disbanding.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PacketID)]
+#[packet(id = 1107)]
+pub struct MsgSyndicate {
+    pub action: u32,
+    /// Depends on the action, mostly the character it is about.
+    pub data: u32,
+    pub names: StringList,
+}
+
+impl MsgSyndicate {
+    pub fn new(action: SyndicateAction, data: u32) -> Self {
+        Self {
+            action: action.into(),
+            data,
+            names: Default::default(),
+        }
+    }
+
+    /// Asks for a guild with the given name.
+    pub fn create(name: &str) -> Self {
+        Self {
+            names: StringList::from(vec![name]),
+            ..Self::new(SyndicateAction::Create, 0)
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl PacketProcess for MsgSyndicate {
+    type ActorState = ActorState;
+    type Error = Error;
+    type State = State;
+
+    #[tracing::instrument(skip_all, fields(action = self.action))]
+    async fn process(
+        &self,
+        state: &Self::State,
+        actor: &Actor<Self::ActorState>,
+    ) -> Result<(), Self::Error> {
+        actor.ensure_in_world()?;
+        let entity = actor.try_entity()?;
+        let me = entity.as_character().ok_or(Error::CharacterNotFound)?;
+        match SyndicateAction::from(self.action) {
+            SyndicateAction::Create => {
+                let name =
+                    self.names.iter().next().cloned().unwrap_or_default();
+                systems::create_guild(state, me, &name).await?;
+            },
+            SyndicateAction::InviteJoin => {
+                systems::invite_to_guild(state, me, self.data).await?;
+            },
+            SyndicateAction::ApplyJoin => {
+                systems::join_guild(state, me, self.data).await?;
+            },
+            SyndicateAction::Leave => {
+                systems::leave_guild(state, me).await?;
+            },
+            SyndicateAction::Promote => {
+                systems::promote_in_guild(state, me, self.data).await?;
+            },
+            SyndicateAction::Disband => {
+                systems::disband_guild(state, me).await?;
+            },
+            action => {
+                tracing::debug!(?action, "Unhandled guild action");
+            },
+        }
+        Ok(())
+    }
+}
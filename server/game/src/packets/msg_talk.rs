@@ -1,10 +1,12 @@
-use crate::constants::{ALL_USERS, SYSTEM};
+use crate::constants::{ALL_USERS, MAX_NAME_LEN, MAX_TXT_LEN, SYSTEM};
 use crate::state::State;
-use crate::systems::commands;
+use crate::systems::{self, commands, Locale, SystemMessage};
+use crate::utils::truncate_str;
 use crate::ActorState;
 use async_trait::async_trait;
 use num_enum::{FromPrimitive, IntoPrimitive};
 use serde::{Deserialize, Serialize};
+use tq_db::item::Item;
 use tq_network::{Actor, PacketID, PacketProcess};
 
 /// Enumeration for defining the channel text is printed to. Can also print to
@@ -82,13 +84,20 @@ pub struct MsgTalk {
 }
 
 impl MsgTalk {
+    /// The color of center-screen announcements.
+    pub const RED: u32 = 0x00FF_0000;
+    /// The color of system messages.
+    pub const WHITE: u32 = 0x00FF_FFFF;
+    /// The color of the messages in the top scrolling bar.
+    pub const YELLOW: u32 = 0x00FF_FF00;
+
     pub fn from_system(
         character_id: u32,
         channel: TalkChannel,
         message: impl Into<String>,
     ) -> Self {
         MsgTalk {
-            color: 0x00FF_FFFF,
+            color: Self::WHITE,
             channel: channel.into(),
             style: TalkStyle::Normal.into(),
             character_id,
@@ -98,20 +107,121 @@ impl MsgTalk {
             sender_name: SYSTEM.to_string(),
             recipient_name: ALL_USERS.to_string(),
             suffix: String::new(),
-            message: message.into(),
+            message: truncate_str(&message.into(), MAX_TXT_LEN).to_owned(),
         }
     }
 
-    pub fn login_invalid() -> Self {
-        Self::from_system(0, TalkChannel::Login, "Login Invalid")
+    /// An announcement shown in the center of the screen of everyone.
+    pub fn announce(message: impl Into<String>) -> Self {
+        Self {
+            color: Self::RED,
+            ..Self::from_system(0, TalkChannel::Center, message)
+        }
     }
 
-    pub fn register_invalid() -> Self {
-        Self::from_system(
-            0,
-            TalkChannel::Register,
-            String::from("Register Invalid"),
-        )
+    /// A world broadcast, shown to everyone along with the name of whoever
+    /// sent it, like the ones sent using a trumpet.
+    pub fn broadcast(sender: &str, message: impl Into<String>) -> Self {
+        Self {
+            sender_name: truncate_str(sender, MAX_NAME_LEN).to_owned(),
+            ..Self::from_system(0, TalkChannel::Broadcast, message)
+        }
+    }
+
+    /// A message scrolling through the bar at the top of the screen.
+    pub fn bbs(message: impl Into<String>) -> Self {
+        Self {
+            color: Self::YELLOW,
+            style: TalkStyle::Scroll.into(),
+            ..Self::from_system(0, TalkChannel::Announce, message)
+        }
+    }
+
+    /// The same message, in another color given as `0x00RRGGBB`.
+    pub fn with_color(self, color: u32) -> Self { Self { color, ..self } }
+
+    /// The same message with a link to `item` at its end, the client shows
+    /// the details of the item when it is clicked.
+    ///
+    /// The text gets shortened if the link would not fit otherwise, the link
+    /// itself is never cut.
+    pub fn with_item_link(self, item: &Item) -> Self {
+        let link = Self::item_link(item);
+        let room = MAX_TXT_LEN.saturating_sub(link.len() + 1);
+        let text = truncate_str(&self.message, room).trim_end();
+        let message = if text.is_empty() {
+            link
+        } else {
+            format!("{text} {link}")
+        };
+        Self { message, ..self }
+    }
+
+    /// The markup of a link to `item`: its id, type and plus.
+    fn item_link(item: &Item) -> String {
+        format!("<{}#{}#{}>", item.item_id, item.item_type, item.plus)
+    }
+
+    /// Told to clients we could not log in, like when their login token is
+    /// unknown or expired.
+    pub fn login_invalid(locale: Locale) -> Self {
+        let message = SystemMessage::LoginInvalid.text(locale);
+        Self::from_system(0, TalkChannel::Login, message)
+    }
+
+    /// Told to clients whose version is not one we support.
+    pub fn update_required(locale: Locale) -> Self {
+        let message = SystemMessage::UpdateRequired.text(locale);
+        Self::from_system(0, TalkChannel::Login, message)
+    }
+
+    /// Told to clients logging in while too many others are.
+    pub fn server_busy(locale: Locale) -> Self {
+        let message = SystemMessage::ServerBusy.text(locale);
+        Self::from_system(0, TalkChannel::Login, message)
+    }
+
+    /// Told to clients logging in while the world has as many players as it
+    /// could take.
+    pub fn server_full(locale: Locale) -> Self {
+        let message = SystemMessage::ServerFull.text(locale);
+        Self::from_system(0, TalkChannel::Login, message)
+    }
+
+    /// Told to clients logging in to a character that is still in the
+    /// world, from another connection.
+    pub fn already_online(locale: Locale) -> Self {
+        let message = SystemMessage::AlreadyOnline.text(locale);
+        Self::from_system(0, TalkChannel::Login, message)
+    }
+
+    /// Told to clients logging in while the server is draining for a
+    /// restart.
+    pub fn server_restarting(locale: Locale) -> Self {
+        let message = SystemMessage::ServerRestarting.text(locale);
+        Self::from_system(0, TalkChannel::Login, message)
+    }
+
+    /// Told once to clients sending packets we could not make sense of.
+    pub fn maybe_unsupported(to: u32, locale: Locale) -> Self {
+        let message = SystemMessage::MaybeUnsupported.text(locale);
+        Self::from_system(to, TalkChannel::TopLeft, message)
+    }
+
+    pub fn register_invalid(locale: Locale) -> Self {
+        let message = SystemMessage::RegisterInvalid.text(locale);
+        Self::from_system(0, TalkChannel::Register, message)
+    }
+
+    pub fn register_name_not_allowed(locale: Locale) -> Self {
+        let message = SystemMessage::RegisterNameNotAllowed.text(locale);
+        Self::from_system(0, TalkChannel::Register, message)
+    }
+
+    /// Told to accounts that already have as many characters as they could.
+    pub fn register_character_limit(locale: Locale) -> Self {
+        let message = SystemMessage::RegisterCharacterLimit.text(locale);
+        Self::from_system(0, TalkChannel::Register, message)
     }
 
     pub fn register_ok() -> Self {
@@ -122,12 +232,9 @@ impl MsgTalk {
         )
     }
 
-    pub fn register_name_taken() -> Self {
-        Self::from_system(
-            0,
-            TalkChannel::Register,
-            String::from("Character name taken, try another one."),
-        )
+    pub fn register_name_taken(locale: Locale) -> Self {
+        let message = SystemMessage::RegisterNameTaken.text(locale);
+        Self::from_system(0, TalkChannel::Register, message)
     }
 
     pub fn login_ok() -> Self {
@@ -160,10 +267,47 @@ impl PacketProcess for MsgTalk {
     ) -> Result<(), Self::Error> {
         if self.message.starts_with('$') {
             // Command Message.
+            actor.ensure_in_world()?;
             let (_, command) = self.message.split_at(1);
             let args: Vec<_> = command.split_whitespace().collect();
             commands::parse_and_execute(state, actor, &args).await?;
         }
+        if matches!(TalkChannel::from(self.channel), TalkChannel::Whisper) {
+            let entity = actor.try_entity()?;
+            let me = entity
+                .as_character()
+                .ok_or(crate::Error::CharacterNotFound)?;
+            let msg = MsgTalk {
+                sender_name: me.entity().name().to_string(),
+                character_id: me.id(),
+                ..self.clone()
+            };
+            let target = state
+                .character_by_name(&self.recipient_name)
+                .and_then(|e| e.owner());
+            if let Some(target) = target {
+                return Ok(target.send(msg).await?);
+            }
+            let now = crate::utils::current_ts() as i64;
+            let name = &self.recipient_name;
+            let queued =
+                systems::queue_offline_message(state, name, &msg, now).await?;
+            let reply = if queued {
+                format!("{name} is offline, they will get it on login.")
+            } else {
+                format!("There is nobody named {name}.")
+            };
+            let reply =
+                MsgTalk::from_system(me.id(), TalkChannel::TopLeft, reply);
+            return Ok(actor.send(reply).await?);
+        }
+        if matches!(TalkChannel::from(self.channel), TalkChannel::Guild) {
+            let entity = actor.try_entity()?;
+            let me = entity
+                .as_character()
+                .ok_or(crate::Error::CharacterNotFound)?;
+            return systems::guild_chat(state, me, self.clone()).await;
+        }
         // For now, we just broadcast the message to all players in our region.
         // TODO: Implement this properly.
         let map_id = actor.entity().basic().map_id();
@@ -176,3 +320,126 @@ impl PacketProcess for MsgTalk {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tq_network::PacketEncode;
+
+    /// The strings part of the packet: their count followed by the sender,
+    /// recipient, suffix and message, each prefixed with its length.
+    fn strings(sender: &str, message: &str) -> Vec<u8> {
+        let mut bytes = vec![4];
+        for s in [sender, "ALLUSERS", "", message] {
+            bytes.push(s.len() as u8);
+            bytes.extend_from_slice(s.as_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn announce_layout() {
+        let (id, bytes) = MsgTalk::announce("Hello").encode().unwrap();
+        assert_eq!(id, 1004);
+        let mut expected = vec![
+            0x00, 0x00, 0xFF, 0x00, // color
+            0xDB, 0x07, // channel (2011, Center)
+            0x00, 0x00, // style (Normal)
+            0x00, 0x00, 0x00, 0x00, // character id
+            0x00, 0x00, 0x00, 0x00, // recipient mesh
+            0x00, 0x00, 0x00, 0x00, // sender mesh
+        ];
+        expected.extend(strings("SYSTEM", "Hello"));
+        assert_eq!(bytes.as_ref(), expected);
+    }
+
+    #[test]
+    fn broadcast_layout() {
+        let msg = MsgTalk::broadcast("Shady", "Selling stuff");
+        let (_, bytes) = msg.encode().unwrap();
+        let mut expected = vec![
+            0xFF, 0xFF, 0xFF, 0x00, // color
+            0xC4, 0x09, // channel (2500, Broadcast)
+            0x00, 0x00, // style (Normal)
+            0x00, 0x00, 0x00, 0x00, // character id
+            0x00, 0x00, 0x00, 0x00, // recipient mesh
+            0x00, 0x00, 0x00, 0x00, // sender mesh
+        ];
+        expected.extend(strings("Shady", "Selling stuff"));
+        assert_eq!(bytes.as_ref(), expected);
+    }
+
+    #[test]
+    fn bbs_layout() {
+        let (_, bytes) = MsgTalk::bbs("Server restart").encode().unwrap();
+        let mut expected = vec![
+            0x00, 0xFF, 0xFF, 0x00, // color
+            0x3F, 0x08, // channel (2111, Announce)
+            0x01, 0x00, // style (Scroll)
+            0x00, 0x00, 0x00, 0x00, // character id
+            0x00, 0x00, 0x00, 0x00, // recipient mesh
+            0x00, 0x00, 0x00, 0x00, // sender mesh
+        ];
+        expected.extend(strings("SYSTEM", "Server restart"));
+        assert_eq!(bytes.as_ref(), expected);
+    }
+
+    #[test]
+    fn colored_layout() {
+        let msg = MsgTalk::from_system(7, TalkChannel::Talk, "Hi")
+            .with_color(0x0012_3456);
+        let (_, bytes) = msg.encode().unwrap();
+        let mut expected = vec![
+            0x56, 0x34, 0x12, 0x00, // color
+            0xD0, 0x07, // channel (2000, Talk)
+            0x00, 0x00, // style (Normal)
+            0x07, 0x00, 0x00, 0x00, // character id
+            0x00, 0x00, 0x00, 0x00, // recipient mesh
+            0x00, 0x00, 0x00, 0x00, // sender mesh
+        ];
+        expected.extend(strings("SYSTEM", "Hi"));
+        assert_eq!(bytes.as_ref(), expected);
+    }
+
+    #[test]
+    fn item_link_layout() {
+        let item = Item {
+            item_id: 42,
+            item_type: 410005,
+            plus: 3,
+            ..Default::default()
+        };
+        let msg = MsgTalk::broadcast("Shady", "Selling").with_item_link(&item);
+        assert_eq!(msg.message, "Selling <42#410005#3>");
+        let (_, bytes) = msg.encode().unwrap();
+        let mut expected = vec![
+            0xFF, 0xFF, 0xFF, 0x00, // color
+            0xC4, 0x09, // channel (2500, Broadcast)
+            0x00, 0x00, // style (Normal)
+            0x00, 0x00, 0x00, 0x00, // character id
+            0x00, 0x00, 0x00, 0x00, // recipient mesh
+            0x00, 0x00, 0x00, 0x00, // sender mesh
+        ];
+        expected.extend(strings("Shady", "Selling <42#410005#3>"));
+        assert_eq!(bytes.as_ref(), expected);
+
+        // The link is kept whole, the text makes room for it.
+        let long = MsgTalk::broadcast("Shady", "x".repeat(MAX_TXT_LEN))
+            .with_item_link(&item);
+        assert!(long.message.len() <= MAX_TXT_LEN);
+        assert!(long.message.ends_with(" <42#410005#3>"));
+    }
+
+    #[test]
+    fn long_messages_are_truncated() {
+        let long = "é".repeat(300);
+        let msg = MsgTalk::broadcast(&"n".repeat(40), long.clone());
+        assert_eq!(msg.sender_name.len(), MAX_NAME_LEN);
+        assert!(msg.message.len() <= MAX_TXT_LEN);
+        assert!(long.starts_with(&msg.message));
+        // Every string still fits its one byte length prefix.
+        let (_, bytes) = msg.encode().unwrap();
+        let expected = 20 + 1 + 4 + MAX_NAME_LEN + 8 + msg.message.len();
+        assert_eq!(bytes.len(), expected);
+    }
+}
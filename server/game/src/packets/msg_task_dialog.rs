@@ -3,7 +3,7 @@ use serde::{Deserialize, Serialize};
 use tq_network::{Actor, PacketID, PacketProcess};
 use tq_serde::StringList;
 
-use crate::constants;
+use crate::{constants, systems};
 
 #[derive(Debug, Clone, Copy, PartialEq, FromPrimitive, IntoPrimitive)]
 #[repr(u8)]
@@ -191,10 +191,56 @@ impl PacketProcess for MsgTaskDialog {
 
     async fn process(
         &self,
-        _state: &Self::State,
-        _actor: &Actor<Self::ActorState>,
+        state: &Self::State,
+        actor: &Actor<Self::ActorState>,
     ) -> Result<(), Self::Error> {
+        actor.ensure_in_world()?;
         tracing::debug!(msg = ?self, "MsgTaskDialog received");
+        if DialogActionKind::from(self.action) != DialogActionKind::Answer {
+            return Ok(());
+        }
+        let entity = actor.try_entity()?;
+        let me = entity
+            .as_character()
+            .ok_or(crate::Error::CharacterNotFound)?;
+        let Some(npc_id) = me.dialog_npc() else {
+            return Ok(());
+        };
+        if self.option_id == u8::MAX {
+            me.set_dialog_npc(None);
+            return Ok(());
+        }
+        let map = state.try_map(me.entity().map_id())?;
+        let Some(npc) = map.npc(npc_id) else {
+            return Ok(());
+        };
+        let my_loc = me.entity().location();
+        let npc_loc = npc.entity().location();
+        if !tq_math::in_screen(my_loc.into(), npc_loc.into()) {
+            me.set_dialog_npc(None);
+            return Ok(());
+        }
+        let option = Some(self.option_id);
+        if systems::run_npc_script(state, me, npc_id, option).await? {
+            return Ok(());
+        }
+        match npc_id {
+            systems::MATCHMAKER_NPC
+                if self.option_id == systems::DIVORCE_OPTION =>
+            {
+                me.set_dialog_npc(None);
+                systems::divorce(state, me).await?;
+            },
+            systems::REBIRTH_NPC => {
+                me.set_dialog_npc(None);
+                if let Some(class) =
+                    systems::RebirthClass::from_option(self.option_id)
+                {
+                    systems::rebirth(state, me, class).await?;
+                }
+            },
+            _ => {},
+        }
         Ok(())
     }
 }
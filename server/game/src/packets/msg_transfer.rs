@@ -1,8 +1,14 @@
+use crate::state::{Access, Privileges};
 use crate::{ActorState, Error, State};
 use async_trait::async_trait;
-use serde::{Deserialize, Serialize};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
 use tq_network::{Actor, PacketID, PacketProcess};
 
+/// The version of [`MsgTransfer`] this server speaks, the account server must
+/// send the same one.
+pub const TRANSFER_VERSION: u16 = 3;
+
 /// Defines account parameters to be transferred from the account server to the
 /// game server. Account information is supplied from the account database, and
 /// used on the game server to transfer authentication and authority level.  
@@ -13,6 +19,46 @@ pub struct MsgTransfer {
     realm_id: u32,
     #[serde(skip_deserializing)]
     token: u64,
+    #[serde(deserialize_with = "transfer_version")]
+    version: u16,
+    gm_level: u8,
+    privileges: u32,
+    /// The [`State::transfer_secret`], the port we get this on is the one
+    /// the players connect to.
+    secret: u64,
+}
+
+/// Older account servers only send the account and realm ids, so reading the
+/// version fails before we get to check it.
+fn transfer_version<'de, D>(deserializer: D) -> Result<u16, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let version = u16::deserialize(deserializer).map_err(|_| {
+        D::Error::custom(format!(
+            "MsgTransfer has no version, the account server is older than v{TRANSFER_VERSION}"
+        ))
+    })?;
+    if version != TRANSFER_VERSION {
+        return Err(D::Error::custom(format!(
+            "MsgTransfer v{version} is not supported, expected v{TRANSFER_VERSION}"
+        )));
+    }
+    Ok(version)
+}
+
+/// Compares the whole secret whatever the first wrong byte, so the time it
+/// takes gives nothing away.
+fn secret_matches(expected: Option<u64>, got: u64) -> bool {
+    let Some(expected) = expected else {
+        return false;
+    };
+    let diff = expected
+        .to_le_bytes()
+        .iter()
+        .zip(got.to_le_bytes())
+        .fold(0, |acc, (a, b)| acc | (a ^ b));
+    std::hint::black_box(diff) == 0
 }
 
 #[async_trait]
@@ -26,12 +72,170 @@ impl PacketProcess for MsgTransfer {
         state: &Self::State,
         actor: &Actor<Self::ActorState>,
     ) -> Result<(), Self::Error> {
-        let generated =
-            state.generate_login_token(self.account_id, self.realm_id)?;
+        if !secret_matches(state.transfer_secret(), self.secret) {
+            tracing::warn!(
+                account_id = self.account_id,
+                "MsgTransfer with a wrong secret"
+            );
+            actor.shutdown().await?;
+            return Ok(());
+        }
+        let access = Access::new(
+            self.gm_level,
+            Privileges::from_bits_truncate(self.privileges),
+        );
+        let generated = state.generate_login_token(
+            self.account_id,
+            self.realm_id,
+            access,
+        )?;
         let mut msg = self.clone();
         msg.token = generated.token;
+        msg.secret = 0;
         actor.send(msg).await?;
         actor.shutdown().await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::{
+        ActionType, MsgAction, MsgConnect, MsgTalk, TalkChannel,
+    };
+    use crate::test_utils::*;
+    use bytes::{BufMut, Bytes, BytesMut};
+    use futures::FutureExt;
+    use primitives::Size;
+    use tokio::sync::mpsc::Receiver;
+    use tq_network::{Message, PacketDecode};
+
+    const SECRET: u64 = 0x5EC2E7;
+
+    fn transfer(gm_level: u8) -> MsgTransfer {
+        MsgTransfer {
+            account_id: 1,
+            realm_id: 1,
+            token: 0,
+            version: TRANSFER_VERSION,
+            gm_level,
+            privileges: Privileges::VIP.bits(),
+            secret: SECRET,
+        }
+    }
+
+    /// The token in the answer to a transfer, we never decode it ourselves.
+    fn answered_token(rx: &mut Receiver<Message>) -> Option<u64> {
+        while let Ok(msg) = rx.try_recv() {
+            if let Message::Packet(MsgTransfer::PACKET_ID, bytes) = msg {
+                let token = bytes[8..16].try_into().unwrap();
+                return Some(u64::from_le_bytes(token));
+            }
+        }
+        None
+    }
+
+    /// Transfers the first test account with the given GM level, connects
+    /// with the token, completes the login and tries to teleport somewhere else
+    /// on the map, then checks where the character ended up.
+    async fn try_teleport(
+        gm_level: u8,
+        expected: (u16, u16),
+    ) -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, move |mut state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                state.set_transfer_secret(SECRET);
+                let map = state.try_map(1010)?;
+                map.load_blank(Size::new(200, 200)).await?;
+                transfer(gm_level).process(&state, &a).await?;
+                let token = answered_token(&mut a_rx).unwrap();
+                let connect = MsgConnect {
+                    token,
+                    ..Default::default()
+                };
+                connect.process(&state, &a).await?;
+                assert_eq!(a.access().gm_level, gm_level);
+                assert_eq!(a.access().privileges, Privileges::VIP);
+                let me = a.entity();
+                let me = me.as_character().unwrap();
+                let msg = MsgTalk::from_system(
+                    me.id(),
+                    TalkChannel::Talk,
+                    "$tele 1010 100 100",
+                );
+                // Commands are only taken once the client is done loading.
+                let res = msg.process(&state, &a).await;
+                assert!(matches!(res, Err(Error::NotInWorld)));
+                MsgAction::new(me.id(), 0, 0, 0, ActionType::LoginCompeleted)
+                    .process(&state, &a)
+                    .await?;
+                me.teleport(&state, 1010, (50, 50)).await?;
+
+                msg.process(&state, &a).await?;
+                assert_eq!((me.x(), me.y()), expected);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn gm_level_comes_from_the_transfer() -> Result<(), Error> {
+        try_teleport(3, (100, 100)).await?;
+        try_teleport(0, (50, 50)).await
+    }
+
+    #[tokio::test]
+    async fn transfers_need_the_secret() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |mut state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, mut b_rx)] = actors;
+                // Nothing gets in before the secret is set.
+                transfer(255).process(&state, &a).await?;
+                assert_eq!(answered_token(&mut a_rx), None);
+
+                state.set_transfer_secret(SECRET);
+                let forged = MsgTransfer {
+                    secret: SECRET + 1,
+                    ..transfer(255)
+                };
+                forged.process(&state, &b).await?;
+                assert_eq!(answered_token(&mut b_rx), None);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[test]
+    fn old_account_server_fails_loudly() {
+        let mut buf = BytesMut::new();
+        buf.put_u32_le(1);
+        buf.put_u32_le(1);
+        let err = MsgTransfer::decode(&buf.freeze()).unwrap_err();
+        assert!(err.to_string().contains("older than v3"), "{err}");
+
+        let mut buf = BytesMut::new();
+        buf.put_u32_le(1);
+        buf.put_u32_le(1);
+        buf.put_u16_le(TRANSFER_VERSION + 1);
+        buf.put_u8(0);
+        buf.put_u32_le(0);
+        let err = MsgTransfer::decode(&buf.freeze()).unwrap_err();
+        assert!(err.to_string().contains("v4 is not supported"), "{err}");
+
+        let mut buf = BytesMut::new();
+        buf.put_u32_le(1);
+        buf.put_u32_le(1);
+        buf.put_u16_le(TRANSFER_VERSION);
+        buf.put_u8(3);
+        buf.put_u32_le(Privileges::PARENTAL_CONTROL.bits());
+        buf.put_u64_le(SECRET);
+        let msg = MsgTransfer::decode(&Bytes::from(buf)).unwrap();
+        assert_eq!(msg.gm_level, 3);
+    }
+}
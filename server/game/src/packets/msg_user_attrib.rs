@@ -0,0 +1,165 @@
+use core::fmt;
+use num_enum::{FromPrimitive, IntoPrimitive};
+use serde::de::{self, DeserializeSeed, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use tq_network::PacketID;
+
+/// The kind of attribute being updated in a [`MsgUserAttrib`] packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive, IntoPrimitive)]
+#[repr(u32)]
+pub enum AttributeKind {
+    Health = 0,
+    MaxHealth = 1,
+    Mana = 2,
+    MaxMana = 3,
+    Silver = 4,
+    Experience = 5,
+    KillPoints = 6,
+    Class = 7,
+    Stamina = 8,
+    AttributePoints = 10,
+    Mesh = 11,
+    Level = 12,
+    Spirit = 13,
+    Vitality = 14,
+    Strength = 15,
+    Agility = 16,
+    Rebirths = 22,
+    Flags = 26,
+    HairStyle = 27,
+    XpCircle = 28,
+    Cps = 30,
+    #[num_enum(default)]
+    Unknown = u32::MAX,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct UserAttribute {
+    kind: u32,
+    value: u64,
+}
+
+/// This packet is sent from the server to the client to update one or more
+/// attributes of an entity, like its health, level or stats, without having
+/// to re-send the full [`super::MsgUserInfo`].
+#[derive(Debug, Clone, Serialize, PacketID)]
+#[packet(id = 1017)]
+pub struct MsgUserAttrib {
+    character_id: u32,
+    count: u32,
+    attributes: Vec<UserAttribute>,
+}
+
+impl MsgUserAttrib {
+    pub fn new(character_id: u32) -> Self {
+        Self {
+            character_id,
+            count: 0,
+            attributes: Vec::new(),
+        }
+    }
+
+    /// Creates a packet with only a single attribute update.
+    pub fn single(character_id: u32, kind: AttributeKind, value: u64) -> Self {
+        Self::new(character_id).with(kind, value)
+    }
+
+    /// Appends an attribute update to this packet.
+    pub fn with(mut self, kind: AttributeKind, value: u64) -> Self {
+        self.attributes.push(UserAttribute {
+            kind: kind.into(),
+            value,
+        });
+        self.count = self.attributes.len() as u32;
+        self
+    }
+
+    /// Returns `true` if there is no attribute updates in this packet.
+    pub fn is_empty(&self) -> bool { self.attributes.is_empty() }
+
+    /// The attribute updates in this packet, in order.
+    pub fn attributes(
+        &self,
+    ) -> impl Iterator<Item = (AttributeKind, u64)> + '_ {
+        self.attributes
+            .iter()
+            .map(|a| (AttributeKind::from(a.kind), a.value))
+    }
+}
+
+/// The attributes are not prefixed with their length, there are as many as
+/// the count before them says.
+impl<'de> Deserialize<'de> for MsgUserAttrib {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        const FIELDS: &[&str] = &["character_id", "count", "attributes"];
+        deserializer.deserialize_struct("MsgUserAttrib", FIELDS, MsgVisitor)
+    }
+}
+
+struct MsgVisitor;
+
+impl<'de> Visitor<'de> for MsgVisitor {
+    type Value = MsgUserAttrib;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a MsgUserAttrib")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(
+        self,
+        mut seq: A,
+    ) -> Result<Self::Value, A::Error> {
+        let character_id = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let count: u32 = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        let attributes = seq
+            .next_element_seed(Attributes(count as usize))?
+            .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+        Ok(MsgUserAttrib {
+            character_id,
+            count,
+            attributes,
+        })
+    }
+}
+
+/// Reads that many attributes.
+struct Attributes(usize);
+
+impl<'de> DeserializeSeed<'de> for Attributes {
+    type Value = Vec<UserAttribute>;
+
+    fn deserialize<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_tuple(self.0, self)
+    }
+}
+
+impl<'de> Visitor<'de> for Attributes {
+    type Value = Vec<UserAttribute>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} attributes", self.0)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(
+        self,
+        mut seq: A,
+    ) -> Result<Self::Value, A::Error> {
+        let mut attributes = Vec::with_capacity(self.0.min(32));
+        for i in 0..self.0 {
+            let attribute = seq
+                .next_element()?
+                .ok_or_else(|| de::Error::invalid_length(i, &self))?;
+            attributes.push(attribute);
+        }
+        Ok(attributes)
+    }
+}
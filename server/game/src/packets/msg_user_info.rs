@@ -94,8 +94,8 @@ impl From<&Character> for MsgUserInfo {
             rebirths: c.rebirths(),
             show_name: true,
             list_count: 2,
-            character_name: c.entity().name().to_owned(),
-            spouse: "None".to_owned(),
+            character_name: c.entity().name().to_string(),
+            spouse: c.spouse(),
         }
     }
 }
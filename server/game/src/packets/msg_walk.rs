@@ -1,10 +1,8 @@
 use crate::constants::{WALK_XCOORDS, WALK_YCOORDS};
 use crate::state::State;
-use crate::systems::TileType;
-use crate::{ActorState, Error};
+use crate::{systems, ActorState, Error};
 use async_trait::async_trait;
-use num_enum::FromPrimitive;
-use primitives::Location;
+use num_enum::{FromPrimitive, IntoPrimitive, TryFromPrimitive};
 use serde::{Deserialize, Serialize};
 use tq_network::{Actor, PacketID, PacketProcess};
 
@@ -20,6 +18,49 @@ pub enum MovementType {
     Unknwon,
 }
 
+/// The eight directions a character could walk or run toward, the client
+/// sends it as a single byte.
+#[derive(
+    Debug,
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+    TryFromPrimitive,
+    IntoPrimitive,
+    Serialize,
+    Deserialize,
+)]
+#[serde(try_from = "u8", into = "u8")]
+#[repr(u8)]
+pub enum WalkDirection {
+    South = 0,
+    SouthWest = 1,
+    West = 2,
+    NorthWest = 3,
+    North = 4,
+    NorthEast = 5,
+    East = 6,
+    SouthEast = 7,
+}
+
+impl WalkDirection {
+    /// Returns the (x, y) offset of a single step in that direction.
+    pub fn delta(self) -> (i8, i8) {
+        let i = u8::from(self) as usize;
+        (WALK_XCOORDS[i], WALK_YCOORDS[i])
+    }
+
+    /// Applies a single step in that direction to the given coordinates.
+    pub fn step(self, x: u16, y: u16) -> (u16, u16) {
+        let (dx, dy) = self.delta();
+        (
+            x.wrapping_add_signed(dx as i16),
+            y.wrapping_add_signed(dy as i16),
+        )
+    }
+}
+
 /// This packet encapsulates a character's ground movement on a map. The
 /// movement packet specifies the type of movement being performed
 /// and the direction the player as it moves on the map. The packet shows
@@ -29,10 +70,24 @@ pub enum MovementType {
 #[packet(id = 1005)]
 pub struct MsgWalk {
     character_id: u32,
-    direction: u8,
+    direction: WalkDirection,
     movement_type: u8,
 }
 
+impl MsgWalk {
+    pub fn new(
+        character_id: u32,
+        direction: WalkDirection,
+        movement_type: MovementType,
+    ) -> Self {
+        Self {
+            character_id,
+            direction,
+            movement_type: movement_type as u8,
+        }
+    }
+}
+
 #[async_trait]
 impl PacketProcess for MsgWalk {
     type ActorState = ActorState;
@@ -48,40 +103,106 @@ impl PacketProcess for MsgWalk {
         state: &Self::State,
         actor: &Actor<Self::ActorState>,
     ) -> Result<(), Self::Error> {
-        let direction = (self.direction % 8) as usize;
-        let entity = actor.entity();
+        actor.ensure_in_world()?;
+        let direction = self.direction;
+        let entity = actor.try_entity()?;
         let me = entity.as_character().ok_or(Error::CharacterNotFound)?;
+        if !me.state().can_move() {
+            tracing::debug!(state = ?me.state(), "Walking while not able to");
+            me.kick_back().await?;
+            return Ok(());
+        }
         let current_location = me.entity().location();
-        let offset = (
-            (WALK_XCOORDS[direction] as u16),
-            (WALK_YCOORDS[direction] as u16),
-        );
-        let x = current_location.x.wrapping_add(offset.0);
-        let y = current_location.y.wrapping_add(offset.1);
+        let (x, y) = direction.step(current_location.x, current_location.y);
         let map = state.try_map(me.entity().map_id())?;
-        match map.tile(x, y) {
-            Some(tile) if tile.access > TileType::Npc => {
-                // The packet is valid. Assign character data:
-                // Send the movement back to the message server and client:
-                me.entity()
-                    .set_location(Location::new(x, y, direction as _));
-                me.set_elevation(tile.elevation);
-                actor.send(self.clone()).await?;
-                map.update_region_for(actor.entity());
-                let myscreen = actor.screen();
-                myscreen.send_movement(state, self.clone()).await?;
-            },
-            Some(_) | None => {
-                let msg = MsgTalk::from_system(
-                    me.id(),
-                    TalkChannel::TopLeft,
-                    "Invalid Location",
-                );
-                actor.send(msg).await?;
-                me.kick_back().await?;
-                return Ok(());
-            },
-        };
+        if systems::can_step(state, me, &map, (x, y)) {
+            // The packet is valid, send the movement back to the client
+            // and to whoever sees us.
+            me.move_to(state, (x, y), direction.into(), self.clone())
+                .await?;
+        } else {
+            let msg = MsgTalk::from_system(
+                me.id(),
+                TalkChannel::TopLeft,
+                "Invalid Location",
+            );
+            actor.send(msg).await?;
+            me.kick_back().await?;
+            return Ok(());
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::CharacterState;
+    use crate::test_utils::*;
+    use bytes::{BufMut, Bytes, BytesMut};
+    use futures::FutureExt;
+    use primitives::Size;
+    use std::convert::TryFrom;
+    use tq_network::PacketDecode;
+
+    fn raw_walk(direction: u8) -> Bytes {
+        let mut buf = BytesMut::new();
+        buf.put_u32_le(1_000_001);
+        buf.put_u8(direction);
+        buf.put_u8(MovementType::Walk as u8);
+        buf.freeze()
+    }
+
+    #[test]
+    fn walk_directions() {
+        use WalkDirection::*;
+        let expected = [
+            South, SouthWest, West, NorthWest, North, NorthEast, East,
+            SouthEast,
+        ];
+        for (byte, direction) in expected.into_iter().enumerate() {
+            assert_eq!(WalkDirection::try_from(byte as u8), Ok(direction));
+            let msg = MsgWalk::decode(&raw_walk(byte as u8)).unwrap();
+            assert_eq!(msg.direction, direction);
+        }
+        assert_eq!(North.step(10, 10), (10, 9));
+        assert_eq!(SouthWest.step(10, 10), (9, 11));
+    }
+
+    #[test]
+    fn invalid_walk_direction() {
+        assert!(WalkDirection::try_from(8).is_err());
+        assert!(MsgWalk::decode(&raw_walk(8)).is_err());
+        assert!(MsgWalk::decode(&raw_walk(255)).is_err());
+    }
+
+    #[tokio::test]
+    async fn the_dead_do_not_walk() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let map = state.try_map(me.entity().map_id())?;
+                map.load_blank(Size::new(200, 200)).await?;
+                me.set_position(50, 50);
+                let msg = MsgWalk {
+                    character_id: me.id(),
+                    direction: WalkDirection::North,
+                    movement_type: MovementType::Walk as u8,
+                };
+
+                me.try_transition(CharacterState::Dead)?;
+                msg.process(&state, &a).await?;
+                assert_eq!((me.x(), me.y()), (50, 50));
+
+                me.try_transition(CharacterState::Alive)?;
+                msg.process(&state, &a).await?;
+                assert_eq!((me.x(), me.y()), (50, 49));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
@@ -0,0 +1,23 @@
+use serde::{Deserialize, Serialize};
+use tq_db::weapon_skill::WeaponSkill;
+use tq_network::PacketID;
+
+/// This packet is sent server>client to tell the client how proficient the
+/// character is with a kind of weapon.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PacketID)]
+#[packet(id = 1025)]
+pub struct MsgWeaponSkill {
+    pub skill_type: u32,
+    pub level: u32,
+    pub experience: u32,
+}
+
+impl From<&WeaponSkill> for MsgWeaponSkill {
+    fn from(skill: &WeaponSkill) -> Self {
+        Self {
+            skill_type: skill.skill_type as u32,
+            level: skill.level as u32,
+            experience: skill.experience as u32,
+        }
+    }
+}
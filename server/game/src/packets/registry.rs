@@ -0,0 +1,233 @@
+use super::*;
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use std::fmt::Debug;
+use tq_network::{PacketDecode, PacketID};
+
+/// A single known packet type.
+#[derive(Debug, Clone, Copy)]
+pub struct PacketType {
+    pub id: u16,
+    pub name: &'static str,
+    decode: Option<fn(&Bytes) -> Option<String>>,
+}
+
+impl PacketType {
+    /// Whether the server knows how to decode this packet, some packets are
+    /// only ever sent to the client.
+    pub fn is_decodable(&self) -> bool { self.decode.is_some() }
+}
+
+/// Maps packet ids to their names and decoders.
+#[derive(Debug)]
+pub struct Registry {
+    types: &'static [PacketType],
+}
+
+impl Registry {
+    pub fn get(&self, id: u16) -> Option<&PacketType> {
+        self.types.iter().find(|t| t.id == id)
+    }
+
+    pub fn name_of(&self, id: u16) -> Option<&'static str> {
+        self.get(id).map(|t| t.name)
+    }
+
+    /// Decodes the packet into its struct and formats it using its `Debug`
+    /// impl, returns `None` if the id is unknown, the packet is encode only or
+    /// the bytes are malformed.
+    pub fn decode_debug(&self, id: u16, bytes: &Bytes) -> Option<String> {
+        self.get(id).and_then(|t| t.decode).and_then(|f| f(bytes))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PacketType> {
+        self.types.iter()
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = u16> + '_ {
+        self.types.iter().map(|t| t.id)
+    }
+}
+
+fn decode_as<T>(bytes: &Bytes) -> Option<String>
+where
+    T: DeserializeOwned + Debug,
+{
+    <T as PacketDecode>::decode(bytes)
+        .ok()
+        .map(|msg| format!("{msg:?}"))
+}
+
+macro_rules! registry {
+    (
+        decode: [$($decode:ident),* $(,)?],
+        encode_only: [$($encode:ident),* $(,)?] $(,)?
+    ) => {
+        static REGISTRY: Registry = Registry {
+            types: &[
+                $(PacketType {
+                    id: <$decode as PacketID>::PACKET_ID,
+                    name: stringify!($decode),
+                    decode: Some(decode_as::<$decode>),
+                },)*
+                $(PacketType {
+                    id: <$encode as PacketID>::PACKET_ID,
+                    name: stringify!($encode),
+                    decode: None,
+                },)*
+            ],
+        };
+    };
+}
+
+registry! {
+    decode: [
+        MsgRegister,
+        MsgTalk,
+        MsgWalk,
+        MsgUserInfo,
+        MsgItem,
+        MsgAction,
+        MsgPing,
+        MsgPlayer,
+        MsgAllot,
+        MsgData,
+        MsgConnect,
+        MsgLogout,
+        MsgMapItem,
+        MsgNpc,
+        MsgTaskDialog,
+        MsgTransfer,
+        MsgGemEmbed,
+        MsgInteract,
+        MsgName,
+        MsgSyndicate,
+        MsgWeaponSkill,
+        MsgFriend,
+    ],
+    encode_only: [
+        MsgItemInfo,
+        MsgItemInfoEx,
+        MsgPackage,
+        MsgWeather,
+        MsgUserAttrib,
+        MsgMapInfo,
+        MsgNpcInfo,
+    ],
+}
+
+/// All the packet types known to the game server.
+pub fn registry() -> &'static Registry { &REGISTRY }
+
+/// Shorthand for `registry().name_of(id)`.
+pub fn name_of(id: u16) -> Option<&'static str> { REGISTRY.name_of(id) }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::{BufMut, BytesMut};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use tq_network::PacketEncode;
+
+    #[test]
+    fn ids_are_unique() {
+        let mut ids: Vec<_> = registry().ids().collect();
+        let len = ids.len();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), len);
+    }
+
+    #[test]
+    fn decode_debug_round_trips_msg_walk() {
+        let mut buf = BytesMut::new();
+        buf.put_u32_le(1_000_001);
+        buf.put_u8(WalkDirection::NorthEast.into());
+        buf.put_u8(MovementType::Run as u8);
+        let bytes = buf.freeze();
+        let msg = MsgWalk::decode(&bytes).unwrap();
+        let (id, encoded) = msg.encode().unwrap();
+        assert_eq!(encoded, bytes);
+        assert_eq!(name_of(id), Some("MsgWalk"));
+        let decoded = registry().decode_debug(id, &encoded).unwrap();
+        assert_eq!(decoded, format!("{msg:?}"));
+        assert!(decoded.contains("NorthEast"));
+    }
+
+    #[test]
+    fn encode_only_packets_are_named_but_not_decoded() {
+        let id = MsgUserAttrib::PACKET_ID;
+        assert_eq!(name_of(id), Some("MsgUserAttrib"));
+        assert!(!registry().get(id).unwrap().is_decodable());
+        assert_eq!(registry().decode_debug(id, &Bytes::new()), None);
+        assert_eq!(name_of(0), None);
+    }
+
+    /// How many random inputs every decoder gets.
+    const FUZZ_ROUNDS: usize = 2_000;
+
+    /// Random bytes, the same ones on every run, mostly short enough to run
+    /// out in the middle of a packet.
+    fn fuzz_bytes(rng: &mut StdRng, max_len: usize) -> Bytes {
+        let len = rng.gen_range(0..=max_len);
+        let mut bytes = vec![0u8; len];
+        rng.fill(&mut bytes[..]);
+        Bytes::from(bytes)
+    }
+
+    /// Decodes the bytes as the packet with the given id, panicking with the
+    /// input so it could be kept as a regression test.
+    fn decode_or_report(id: u16, bytes: &Bytes) {
+        let decoded = std::panic::catch_unwind(|| {
+            registry().decode_debug(id, bytes);
+        });
+        if decoded.is_err() {
+            panic!("{:?} panicked decoding {:02x?}", name_of(id), &bytes[..]);
+        }
+    }
+
+    #[test]
+    fn random_bodies_never_panic() {
+        let mut rng = StdRng::seed_from_u64(0x7c0);
+        for ty in registry().iter().filter(|t| t.is_decodable()) {
+            for _ in 0..FUZZ_ROUNDS {
+                decode_or_report(ty.id, &fuzz_bytes(&mut rng, 96));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn random_frames_never_panic() {
+        use tokio::io::{duplex, AsyncWriteExt};
+        use tokio_stream::StreamExt;
+        use tq_network::{NopCipher, Seal, TQCodec};
+
+        let mut rng = StdRng::seed_from_u64(0x7c1);
+        let ids: Vec<_> = registry().ids().collect();
+        for _ in 0..FUZZ_ROUNDS / 10 {
+            // A few frames in a row, each with a length that may or may not
+            // match what follows it.
+            let mut wire = BytesMut::new();
+            for _ in 0..rng.gen_range(1..=4) {
+                let body = fuzz_bytes(&mut rng, 96);
+                let len = match rng.gen_range(0..4) {
+                    0 => rng.gen_range(0..8),
+                    1 => rng.gen(),
+                    _ => body.len() as u16 + 4,
+                };
+                wire.put_u16_le(len);
+                wire.put_u16_le(ids[rng.gen_range(0..ids.len())]);
+                wire.put_slice(&body);
+            }
+            let (mut client, server) = duplex(wire.len() + 1);
+            client.write_all(&wire).await.unwrap();
+            drop(client);
+            let (_, mut decoder) =
+                TQCodec::new(server, NopCipher, Seal::None).split();
+            while let Some(Ok((id, body))) = decoder.next().await {
+                decode_or_report(id, &body);
+            }
+        }
+    }
+}
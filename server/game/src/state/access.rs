@@ -0,0 +1,30 @@
+bitflags::bitflags! {
+  /// Account flags the account server sends along with the transfer.
+  #[repr(transparent)]
+  #[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+  pub struct Privileges: u32 {
+    const VIP = 1 << 0;
+    const PARENTAL_CONTROL = 1 << 1;
+  }
+}
+
+/// What an account is allowed to do in game, it is decided by the account
+/// server and carried in the login token, so we never look it up ourselves.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Access {
+    /// Zero for players, the higher the level the more commands a GM could
+    /// use.
+    pub gm_level: u8,
+    pub privileges: Privileges,
+}
+
+impl Access {
+    pub fn new(gm_level: u8, privileges: Privileges) -> Self {
+        Self {
+            gm_level,
+            privileges,
+        }
+    }
+
+    pub fn is_gm(&self) -> bool { self.gm_level > 0 }
+}
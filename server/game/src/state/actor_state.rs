@@ -1,15 +1,28 @@
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Weak};
 
 use arc_swap::ArcSwapOption;
+use parking_lot::{Mutex, RwLock};
 
+use super::Access;
 use crate::entities::{Character, GameEntity};
-use crate::systems::Screen;
+use crate::systems::{Locale, Screen};
 use crate::Error;
 
 #[derive(Debug)]
 pub struct ActorState {
     entity: ArcSwapOption<GameEntity>,
     screen: ArcSwapOption<Screen>,
+    access: RwLock<Access>,
+    session_id: Mutex<Option<i64>>,
+    /// Whether the client got told its version may be unsupported.
+    unsupported_notice: AtomicBool,
+    /// Whether the warehouse password was given this session.
+    warehouse_unlocked: AtomicBool,
+    /// Whether the client got told it is away, since it last did something.
+    afk_warned: AtomicBool,
+    /// The language of the client, reported when it connects.
+    locale: RwLock<Locale>,
 }
 
 #[async_trait::async_trait]
@@ -18,6 +31,12 @@ impl tq_network::ActorState for ActorState {
         ActorState {
             entity: Default::default(),
             screen: Default::default(),
+            access: Default::default(),
+            session_id: Default::default(),
+            unsupported_notice: Default::default(),
+            warehouse_unlocked: Default::default(),
+            afk_warned: Default::default(),
+            locale: Default::default(),
         }
     }
 }
@@ -39,6 +58,55 @@ impl ActorState {
         self.screen.store(Some(screen));
     }
 
+    /// Unbinds the character and its screen from this actor, the connection
+    /// stays open but it is no longer in the game world.
+    pub fn unbind(&self) {
+        self.entity.store(None);
+        self.screen.store(None);
+        self.set_warehouse_unlocked(false);
+    }
+
+    /// What the account behind this actor is allowed to do, set once it
+    /// connects with a login token.
+    pub fn access(&self) -> Access { *self.access.read() }
+
+    pub fn set_access(&self, access: Access) { *self.access.write() = access; }
+
+    /// The language system messages are told to the client in.
+    pub fn locale(&self) -> Locale { *self.locale.read() }
+
+    pub fn set_locale(&self, locale: Locale) { *self.locale.write() = locale; }
+
+    /// The connection log session of this actor, see
+    /// [`AuditWriter`](crate::systems::AuditWriter).
+    pub fn session_id(&self) -> Option<i64> { *self.session_id.lock() }
+
+    pub fn set_session_id(&self, session_id: i64) {
+        *self.session_id.lock() = Some(session_id);
+    }
+
+    /// Returns `true` the first time only, the client is told its version
+    /// may be unsupported once.
+    pub fn take_unsupported_notice(&self) -> bool {
+        !self.unsupported_notice.swap(true, Ordering::Relaxed)
+    }
+
+    /// Whether the warehouse password was given this session, see
+    /// [`verify_warehouse_password`](crate::systems::verify_warehouse_password).
+    pub fn warehouse_unlocked(&self) -> bool {
+        self.warehouse_unlocked.load(Ordering::Relaxed)
+    }
+
+    pub fn set_warehouse_unlocked(&self, unlocked: bool) {
+        self.warehouse_unlocked.store(unlocked, Ordering::Relaxed);
+    }
+
+    /// Sets whether the client got told it is away, returning whether it
+    /// already was, see [`check_afk`](crate::systems::check_afk).
+    pub fn set_afk_warned(&self, warned: bool) -> bool {
+        self.afk_warned.swap(warned, Ordering::Relaxed)
+    }
+
     pub fn entity(&self) -> Arc<GameEntity> {
         self.entity.load().clone().expect("state is not empty")
     }
@@ -63,6 +131,23 @@ impl ActorState {
         }
     }
 
+    /// Whether the character of this actor finished loading into the world,
+    /// see [`Character::in_world`].
+    pub fn in_world(&self) -> bool {
+        let entity = self.entity.load();
+        let character = entity.as_deref().and_then(GameEntity::as_character);
+        character.is_some_and(Character::in_world)
+    }
+
+    /// Rejects the gameplay packets of a client still loading.
+    pub fn ensure_in_world(&self) -> Result<(), Error> {
+        if self.in_world() {
+            Ok(())
+        } else {
+            Err(Error::NotInWorld)
+        }
+    }
+
     pub fn screen(&self) -> Arc<Screen> {
         self.screen.load().clone().expect("state is not empty")
     }
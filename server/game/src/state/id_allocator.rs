@@ -0,0 +1,216 @@
+use std::ops::RangeInclusive;
+
+use parking_lot::Mutex;
+
+use crate::{constants, Error};
+
+/// The kinds of entities that get their ids at runtime, each one of them has
+/// its own range of ids so the client can tell them apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdKind {
+    DynamicNpc,
+    Monster,
+    Pet,
+    CallPet,
+    FloorItem,
+}
+
+impl IdKind {
+    const ALL: [Self; 5] = [
+        Self::DynamicNpc,
+        Self::Monster,
+        Self::Pet,
+        Self::CallPet,
+        Self::FloorItem,
+    ];
+
+    /// The range of ids that entities of this kind could use.
+    pub const fn range(self) -> RangeInclusive<u32> {
+        match self {
+            Self::DynamicNpc => {
+                constants::DYN_NPC_ID_MIN..=constants::DYN_NPC_ID_MAX
+            },
+            Self::Monster => {
+                constants::MONSTER_ID_MIN..=constants::MONSTER_ID_MAX
+            },
+            Self::Pet => constants::PET_ID_MIN..=constants::PET_ID_MAX,
+            Self::CallPet => {
+                constants::CALL_PET_ID_MIN..=constants::CALL_PET_ID_MAX
+            },
+            Self::FloorItem => {
+                constants::FLOOR_ITEM_ID_MIN..=constants::FLOOR_ITEM_ID_MAX
+            },
+        }
+    }
+
+    /// Returns the kind that owns the given id, if any.
+    pub fn of(id: u32) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.range().contains(&id))
+    }
+
+    const fn index(self) -> usize {
+        match self {
+            Self::DynamicNpc => 0,
+            Self::Monster => 1,
+            Self::Pet => 2,
+            Self::CallPet => 3,
+            Self::FloorItem => 4,
+        }
+    }
+}
+
+/// Hands out runtime ids for monsters, pets, floor items and dynamic npcs.
+///
+/// Every kind has its own pool, ids are handed out in order until the range
+/// is used up, after that only the ids that got freed are reused. Running out
+/// of ids is an error, we never wrap around into another kind's range.
+#[derive(Debug)]
+pub struct IdAllocator {
+    pools: [Mutex<IdPool>; 5],
+}
+
+impl Default for IdAllocator {
+    fn default() -> Self {
+        Self {
+            pools: IdKind::ALL
+                .map(|kind| Mutex::new(IdPool::new(kind.range()))),
+        }
+    }
+}
+
+impl IdAllocator {
+    pub fn new() -> Self { Self::default() }
+
+    /// Allocates a new id for an entity of the given kind.
+    pub fn allocate(&self, kind: IdKind) -> Result<u32, Error> {
+        self.pools[kind.index()]
+            .lock()
+            .allocate()
+            .ok_or(Error::IdsExhausted(kind))
+    }
+
+    /// Gives the id back, so it could be used again by another entity.
+    ///
+    /// Freeing an id that was never allocated, or freeing it twice, is a bug
+    /// and panics in debug builds.
+    pub fn free(&self, id: u32) {
+        let Some(kind) = IdKind::of(id) else {
+            debug_assert!(false, "id {id} is not a runtime id");
+            return;
+        };
+        self.pools[kind.index()].lock().free(id);
+    }
+}
+
+#[derive(Debug)]
+struct IdPool {
+    range: RangeInclusive<u32>,
+    /// The next id that was never handed out before.
+    next: u32,
+    /// Ids that got freed and could be handed out again.
+    free: Vec<u32>,
+    /// Ids that are currently in use, to catch double frees.
+    #[cfg(debug_assertions)]
+    live: std::collections::HashSet<u32>,
+}
+
+impl IdPool {
+    fn new(range: RangeInclusive<u32>) -> Self {
+        Self {
+            next: *range.start(),
+            range,
+            free: Vec::new(),
+            #[cfg(debug_assertions)]
+            live: Default::default(),
+        }
+    }
+
+    fn allocate(&mut self) -> Option<u32> {
+        let id = match self.free.pop() {
+            Some(id) => id,
+            None if self.next <= *self.range.end() => {
+                let id = self.next;
+                self.next += 1;
+                id
+            },
+            None => return None,
+        };
+        #[cfg(debug_assertions)]
+        self.live.insert(id);
+        Some(id)
+    }
+
+    fn free(&mut self, id: u32) {
+        debug_assert!(self.range.contains(&id), "id {id} is out of range");
+        #[cfg(debug_assertions)]
+        assert!(self.live.remove(&id), "id {id} freed twice");
+        self.free.push(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn churn_stays_in_range() {
+        let ids = IdAllocator::new();
+        let mut live = Vec::new();
+        for round in 0..1000 {
+            for kind in IdKind::ALL {
+                live.push((kind, ids.allocate(kind).unwrap()));
+            }
+            if round % 3 == 0 {
+                for (_, id) in live.drain(..live.len() / 2) {
+                    ids.free(id);
+                }
+            }
+        }
+        for (kind, id) in live {
+            assert!(kind.range().contains(&id));
+            assert_eq!(IdKind::of(id), Some(kind));
+        }
+    }
+
+    #[test]
+    fn freed_ids_are_reused() {
+        let ids = IdAllocator::new();
+        let a = ids.allocate(IdKind::Monster).unwrap();
+        let b = ids.allocate(IdKind::Monster).unwrap();
+        assert_ne!(a, b);
+        ids.free(a);
+        assert_eq!(ids.allocate(IdKind::Monster).unwrap(), a);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "freed twice")]
+    fn double_free_is_caught() {
+        let ids = IdAllocator::new();
+        let id = ids.allocate(IdKind::FloorItem).unwrap();
+        ids.free(id);
+        ids.free(id);
+    }
+
+    #[test]
+    fn exhaustion_is_an_error() {
+        let mut pool = IdPool::new(10..=12);
+        assert_eq!(pool.allocate(), Some(10));
+        assert_eq!(pool.allocate(), Some(11));
+        assert_eq!(pool.allocate(), Some(12));
+        assert_eq!(pool.allocate(), None);
+        pool.free(11);
+        assert_eq!(pool.allocate(), Some(11));
+        assert_eq!(pool.allocate(), None);
+
+        let ids = IdAllocator::new();
+        let kind = IdKind::CallPet;
+        for _ in kind.range() {
+            ids.allocate(kind).unwrap();
+        }
+        let err = ids.allocate(kind).unwrap_err();
+        assert!(matches!(err, Error::IdsExhausted(IdKind::CallPet)));
+    }
+}
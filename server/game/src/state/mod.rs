@@ -1,28 +1,123 @@
 use crate::entities::GameEntity;
-use crate::world::Map;
+use crate::events::GuildWar;
+use crate::packets::MsgPing;
+use crate::systems::{
+    self, Afk, AuditWriter, ClientVersions, GameRng, Guilds, Instances,
+    LogFilter, LoginGate, MapLoader, MonsterTypes, NameFilter, Restart,
+    Scheduler, Scripts, StarterKit, WarehouseAccess, WarehouseLocks,
+};
+use crate::utils::duration_ms_from_env;
+use crate::world::{self, Map, WorldSnapshot};
 use crate::Error;
 use parking_lot::{Mutex, RwLock};
 use sqlx::sqlite::{SqlitePool, SqlitePoolOptions};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::broadcast;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, Notify};
+use tq_network::{InvalidPacketStats, PacketEncode, PacketID};
 use tracing::debug;
 
+/// How long a connection waits for its login token by default, the client
+/// could get the token from the account server before we had it stored.
+pub const LOGIN_TOKEN_WAIT: Duration = Duration::from_secs(2);
+
+/// How many characters an account could have by default, the client only
+/// shows one.
+pub const MAX_CHARACTERS_PER_ACCOUNT: u32 = 1;
+
+/// Loads how many characters an account could have from the
+/// `MAX_CHARACTERS_PER_ACCOUNT` environment variable, falling back to
+/// [`MAX_CHARACTERS_PER_ACCOUNT`].
+pub fn max_characters_from_env() -> Result<u32, Error> {
+    match dotenvy::var("MAX_CHARACTERS_PER_ACCOUNT") {
+        Ok(max) => Ok(max.trim().parse()?),
+        Err(_) => Ok(MAX_CHARACTERS_PER_ACCOUNT),
+    }
+}
+
+mod access;
 mod actor_state;
+mod id_allocator;
 
+pub use access::{Access, Privileges};
 pub use actor_state::ActorState;
+pub use id_allocator::{IdAllocator, IdKind};
 
-type Maps = HashMap<u32, Map>;
+type Maps = RwLock<HashMap<u32, Arc<Map>>>;
 type Entites = RwLock<HashMap<u32, Arc<GameEntity>>>;
+type Names = RwLock<HashMap<String, u32>>;
+type Logins = Mutex<HashSet<String>>;
 type LoginTokens = Mutex<HashMap<u64, LoginToken>>;
+type LoginWaiters = Mutex<HashMap<u64, Arc<Notify>>>;
 type CreationTokens = Mutex<HashMap<u32, CreationToken>>;
 
 #[derive(Debug)]
 pub struct State {
     login_tokens: LoginTokens,
+    /// The connections waiting for a login token that was not stored yet.
+    login_waiters: LoginWaiters,
+    /// How long a connection waits for its login token.
+    login_token_wait: Duration,
+    /// Shared with the account server, a [`MsgTransfer`] without it is
+    /// turned away. `None` turns every transfer away.
+    ///
+    /// [`MsgTransfer`]: crate::packets::MsgTransfer
+    transfer_secret: Option<u64>,
     creation_tokens: CreationTokens,
+    /// How many characters an account could create.
+    max_characters: u32,
     entities: Entites,
+    /// The ids of the characters in the world, by their names.
+    names: Names,
+    /// The names of the characters being loaded into the world.
+    logins: Logins,
     maps: Maps,
+    map_loader: MapLoader,
+    /// The maps made at runtime, like the instances of dungeons.
+    instances: Instances,
+    warehouse_locks: WarehouseLocks,
+    warehouse_access: WarehouseAccess,
+    ids: Arc<IdAllocator>,
+    starter_kit: StarterKit,
+    guild_war: GuildWar,
+    guilds: Guilds,
+    scripts: Scripts,
+    monster_types: MonsterTypes,
+    name_filter: NameFilter,
+    scheduler: Scheduler,
+    audit: AuditWriter,
+    client_versions: ClientVersions,
+    login_gate: LoginGate,
+    restart: Restart,
+    invalid_packets: InvalidPacketStats,
+    /// How many invalid packets a client could send before it gets
+    /// disconnected.
+    invalid_packet_limit: u32,
+    /// How long experience gains get batched before being sent.
+    experience_window: Duration,
+    /// How long the movements seen by a character get batched, zero to send
+    /// them right away.
+    movement_window: Duration,
+    /// How long a character has to wait between two portal uses.
+    portal_cooldown: Duration,
+    /// How much sooner than their interval combat actions are accepted.
+    action_jitter: Duration,
+    /// How long after a fight portals and teleports are off limits.
+    combat_lock: Duration,
+    /// What happens to the players who stay away for too long.
+    afk: Afk,
+    /// Sent to everyone right after they log in, line by line.
+    motd: Vec<String>,
+    /// When the server started.
+    started_at: Instant,
+    rng: GameRng,
+    /// Set once the logger is up, see [`State::set_log_filter`].
+    log_filter: Option<LogFilter>,
+    /// Where the world snapshot is kept, `None` to keep none.
+    snapshot_path: Option<PathBuf>,
     pool: SqlitePool,
 }
 
@@ -40,50 +135,436 @@ impl State {
             .min_connections(4)
             .connect(&db_url)
             .await?;
-        Self::with_pool(pool).await
+        let mut state = Self::with_pool(pool).await?;
+        state.starter_kit = StarterKit::from_env()?;
+        state.guild_war = GuildWar::from_env()?;
+        state.experience_window = duration_ms_from_env(
+            "EXPERIENCE_WINDOW_MS",
+            systems::EXPERIENCE_WINDOW,
+        )?;
+        state.movement_window = duration_ms_from_env(
+            "MOVEMENT_WINDOW_MS",
+            systems::MOVEMENT_WINDOW,
+        )?;
+        state.portal_cooldown =
+            duration_ms_from_env("PORTAL_COOLDOWN_MS", world::PORTAL_COOLDOWN)?;
+        state.action_jitter =
+            duration_ms_from_env("ACTION_JITTER_MS", systems::ACTION_JITTER)?;
+        state.combat_lock =
+            duration_ms_from_env("COMBAT_LOCK_MS", systems::COMBAT_LOCK)?;
+        state.afk = Afk::from_env()?;
+        state.login_token_wait =
+            duration_ms_from_env("LOGIN_TOKEN_WAIT_MS", LOGIN_TOKEN_WAIT)?;
+        state.transfer_secret =
+            Some(dotenvy::var("TRANSFER_SECRET")?.trim().parse()?);
+        state.max_characters = max_characters_from_env()?;
+        state.client_versions = ClientVersions::from_env()?;
+        state.login_gate = LoginGate::from_env()?;
+        state.restart = Restart::from_env()?;
+        state.map_loader = MapLoader::from_env()?;
+        state.invalid_packet_limit = systems::invalid_packet_limit_from_env()?;
+        state.scripts = Scripts::from_env()?;
+        state.monster_types = MonsterTypes::from_env()?;
+        state.name_filter = NameFilter::from_env()?;
+        state.snapshot_path = Some(world::snapshot_path_from_env()?);
+        state.motd = systems::motd_from_env();
+        state.rng = GameRng::from_env()?;
+        tracing::info!(seed = state.rng.seed(), "Seeded the game RNG");
+        systems::register_builtin_jobs(&state.scheduler)?;
+        Ok(state)
     }
 
     pub async fn with_pool(pool: SqlitePool) -> Result<Self, Error> {
         debug!("Loading Maps from Database");
         let db_maps = tq_db::map::Map::load_all(&pool).await?;
         let mut maps = HashMap::with_capacity(db_maps.len());
+        let ids = Arc::new(IdAllocator::new());
         debug!("Loaded #{} Map From Database", db_maps.len());
         for map in db_maps {
             let portals = tq_db::portal::Portal::by_map(&pool, map.id).await?;
             tracing::trace!(%map.id, portals = %portals.len(), "Loaded Portals");
             let npcs = tq_db::npc::Npc::by_map(&pool, map.id).await?;
             tracing::trace!(%map.id, npcs = %npcs.len(), "Loaded Npcs");
-            let map = Map::new(map, portals, npcs);
-            maps.insert(map.id(), map);
+            let map = Map::new(map, portals, npcs, ids.clone());
+            maps.insert(map.id(), Arc::new(map));
         }
+        let guilds = Guilds::load(&pool).await?;
 
         let state = Self {
             login_tokens: Default::default(),
+            login_waiters: Default::default(),
+            login_token_wait: LOGIN_TOKEN_WAIT,
+            transfer_secret: None,
             creation_tokens: Default::default(),
+            max_characters: MAX_CHARACTERS_PER_ACCOUNT,
             entities: Default::default(),
-            maps,
+            names: Default::default(),
+            logins: Default::default(),
+            maps: RwLock::new(maps),
+            map_loader: Default::default(),
+            instances: Default::default(),
+            warehouse_locks: Default::default(),
+            warehouse_access: Default::default(),
+            ids,
+            starter_kit: Default::default(),
+            guild_war: Default::default(),
+            guilds,
+            scripts: Default::default(),
+            monster_types: Default::default(),
+            name_filter: Default::default(),
+            scheduler: Default::default(),
+            audit: AuditWriter::spawn(pool.clone()).await?,
+            client_versions: Default::default(),
+            login_gate: Default::default(),
+            restart: Default::default(),
+            invalid_packets: Default::default(),
+            invalid_packet_limit: systems::INVALID_PACKET_LIMIT,
+            experience_window: systems::EXPERIENCE_WINDOW,
+            movement_window: systems::MOVEMENT_WINDOW,
+            portal_cooldown: world::PORTAL_COOLDOWN,
+            action_jitter: systems::ACTION_JITTER,
+            combat_lock: systems::COMBAT_LOCK,
+            afk: Default::default(),
+            motd: Vec::new(),
+            started_at: Instant::now(),
+            rng: Default::default(),
+            log_filter: None,
+            snapshot_path: None,
             pool,
         };
         Ok(state)
     }
 
+    /// Runs once the state is built and before anyone gets in: loads the
+    /// maps that are always in memory.
+    pub async fn post_init(&self) -> Result<(), Error> {
+        self.map_loader.preload(self).await;
+        Ok(())
+    }
+
     /// Get access to the database pool
     pub fn pool(&self) -> &SqlitePool { &self.pool }
 
-    pub fn maps(&self) -> &Maps { &self.maps }
+    /// The allocator of runtime ids for monsters, floor items and the like.
+    pub fn ids(&self) -> &IdAllocator { &self.ids }
+
+    /// The kit new characters get on their first login.
+    pub fn starter_kit(&self) -> &StarterKit { &self.starter_kit }
+
+    /// The weekly guild war.
+    pub fn guild_war(&self) -> &GuildWar { &self.guild_war }
+
+    pub fn guilds(&self) -> &Guilds { &self.guilds }
+
+    /// The scripts of the NPCs, reloaded with `$reload scripts`.
+    pub fn scripts(&self) -> &Scripts { &self.scripts }
+
+    /// Every monster type by its id, see [`MonsterTypes`].
+    pub fn monster_types(&self) -> &MonsterTypes { &self.monster_types }
+
+    /// The names characters could not have, checked when they get created
+    /// or renamed.
+    pub fn name_filter(&self) -> &NameFilter { &self.name_filter }
+
+    /// The jobs that run at set times, driven by the world tick.
+    pub fn scheduler(&self) -> &Scheduler { &self.scheduler }
+
+    /// The packets dropped from every address, by reason.
+    pub fn invalid_packets(&self) -> &InvalidPacketStats {
+        &self.invalid_packets
+    }
+
+    pub fn invalid_packet_limit(&self) -> u32 { self.invalid_packet_limit }
+
+    /// Writes the connection log and the like in the background.
+    pub fn audit(&self) -> &AuditWriter { &self.audit }
+
+    pub fn experience_window(&self) -> Duration { self.experience_window }
+
+    pub fn movement_window(&self) -> Duration { self.movement_window }
+
+    pub fn set_movement_window(&mut self, window: Duration) {
+        self.movement_window = window;
+    }
+
+    pub fn portal_cooldown(&self) -> Duration { self.portal_cooldown }
+
+    pub fn action_jitter(&self) -> Duration { self.action_jitter }
 
-    pub fn try_map(&self, map_id: u32) -> Result<&Map, Error> {
-        self.maps.get(&map_id).ok_or(Error::MapNotFound)
+    pub fn combat_lock(&self) -> Duration { self.combat_lock }
+
+    /// See [`systems::check_afk`].
+    pub fn afk(&self) -> Afk { self.afk }
+
+    pub fn set_afk(&mut self, afk: Afk) { self.afk = afk; }
+
+    pub fn login_token_wait(&self) -> Duration { self.login_token_wait }
+
+    pub fn set_login_token_wait(&mut self, wait: Duration) {
+        self.login_token_wait = wait;
+    }
+
+    pub fn transfer_secret(&self) -> Option<u64> { self.transfer_secret }
+
+    pub fn set_transfer_secret(&mut self, secret: u64) {
+        self.transfer_secret = Some(secret);
+    }
+
+    pub fn max_characters(&self) -> u32 { self.max_characters }
+
+    pub fn set_max_characters(&mut self, max: u32) {
+        self.max_characters = max;
+    }
+
+    /// The versions of the game client that are allowed to connect.
+    pub fn client_versions(&self) -> &ClientVersions { &self.client_versions }
+
+    pub fn set_client_versions(&mut self, versions: ClientVersions) {
+        self.client_versions = versions;
+    }
+
+    /// Lets the logins into the world a few at a time.
+    pub fn login_gate(&self) -> &LoginGate { &self.login_gate }
+
+    pub fn set_login_gate(&mut self, gate: LoginGate) {
+        self.login_gate = gate;
+    }
+
+    /// Whether the server is draining for a restart, see
+    /// [`systems::start_restart`].
+    pub fn restart(&self) -> &Restart { &self.restart }
+
+    /// The message of the day, see [`systems::send_motd`].
+    pub fn motd(&self) -> &[String] { &self.motd }
+
+    pub fn set_motd(&mut self, motd: Vec<String>) { self.motd = motd; }
+
+    pub fn started_at(&self) -> Instant { self.started_at }
+
+    /// Where the drops, hits and the like draw their randomness from.
+    pub fn rng(&self) -> &GameRng { &self.rng }
+
+    pub fn set_rng(&mut self, rng: GameRng) { self.rng = rng; }
+
+    pub fn set_log_filter_handle(&mut self, filter: LogFilter) {
+        self.log_filter = Some(filter);
+    }
+
+    /// Adds the directives to the log filter while the server runs, see
+    /// [`LogFilter::add`].
+    pub fn set_log_filter(&self, directives: &str) -> Result<(), Error> {
+        let filter = self.log_filter.as_ref().ok_or_else(|| {
+            Error::Other(String::from("The log filter could not be changed"))
+        })?;
+        filter.add(directives)
+    }
+
+    /// How many characters are in the world.
+    pub fn online_count(&self) -> usize { self.names.read().len() }
+
+    pub fn set_snapshot_path(&mut self, path: Option<PathBuf>) {
+        self.snapshot_path = path;
+    }
+
+    /// Writes a snapshot of the world, see [`WorldSnapshot`].
+    pub async fn write_snapshot(&self) -> Result<(), Error> {
+        let Some(path) = &self.snapshot_path else {
+            return Ok(());
+        };
+        WorldSnapshot::take(self, Instant::now()).write(path).await
+    }
+
+    /// Restores the last snapshot of the world, if any. A snapshot that could
+    /// not be read is skipped with a warning.
+    pub async fn restore_snapshot(&self) -> Result<(), Error> {
+        let Some(path) = &self.snapshot_path else {
+            return Ok(());
+        };
+        match WorldSnapshot::read(path).await {
+            Ok(Some(snapshot)) => snapshot.restore(self, Instant::now()).await,
+            Ok(None) => Ok(()),
+            Err(error) => {
+                tracing::warn!(
+                    %error,
+                    path = %path.display(),
+                    "Skipping the world snapshot"
+                );
+                Ok(())
+            },
+        }
+    }
+
+    pub fn try_map(&self, map_id: u32) -> Result<Arc<Map>, Error> {
+        self.map_by_id(map_id).ok_or(Error::MapNotFound)
+    }
+
+    pub fn map_by_id(&self, map_id: u32) -> Option<Arc<Map>> {
+        self.maps.read().get(&map_id).cloned()
+    }
+
+    /// Adds a map made at runtime, like the instance of a dungeon.
+    pub fn insert_map(&self, map: Arc<Map>) {
+        self.maps.write().insert(map.id(), map);
+    }
+
+    /// Takes a map made at runtime out of the world, the ones from the
+    /// database are never removed.
+    pub fn remove_map(&self, map_id: u32) -> Option<Arc<Map>> {
+        let mut maps = self.maps.write();
+        match maps.get(&map_id) {
+            Some(map) if map.is_copy() => maps.remove(&map_id),
+            _ => None,
+        }
+    }
+
+    /// Keeps the resident maps in memory and loads the others ahead of time.
+    pub fn map_loader(&self) -> &MapLoader { &self.map_loader }
+
+    pub fn set_map_loader(&mut self, loader: MapLoader) {
+        self.map_loader = loader;
+    }
+
+    /// The private copies of maps, see [`systems::create_instance`].
+    pub fn instances(&self) -> &Instances { &self.instances }
+
+    /// Who gave the wrong warehouse password lately, see
+    /// [`systems::verify_warehouse_password`].
+    pub fn warehouse_locks(&self) -> &WarehouseLocks { &self.warehouse_locks }
+
+    /// Who is using the warehouse of an account right now, see
+    /// [`systems::deposit`].
+    pub fn warehouse_access(&self) -> &WarehouseAccess {
+        &self.warehouse_access
+    }
+
+    /// Asks for the map to be loaded in the background, see
+    /// [`MapLoader::run`].
+    pub fn request_map_load(&self, map_id: u32) {
+        self.map_loader.request(map_id);
+    }
+
+    /// The maps that are loaded in memory right now.
+    ///
+    /// The handles could be held across awaits, the table of maps is only
+    /// locked while they are collected and none of their regions is locked
+    /// here.
+    pub fn loaded_maps(&self) -> Vec<Arc<Map>> {
+        let maps = self.maps.read();
+        maps.values().filter(|map| map.loaded()).cloned().collect()
+    }
+
+    /// Runs `f` on every map that is loaded in memory, one after another.
+    ///
+    /// The loaded maps are looked up before the first one is visited, a map
+    /// that gets loaded while this runs is visited the next time.
+    pub async fn for_each_loaded_map<F, Fut>(
+        &self,
+        mut f: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(Arc<Map>) -> Fut,
+        Fut: Future<Output = Result<(), Error>>,
+    {
+        for map in self.loaded_maps() {
+            f(map).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends the packet to every character on that map, it is encoded once
+    /// for all of them.
+    #[tracing::instrument(skip(self, packet), fields(packet_id = P::PACKET_ID))]
+    pub async fn announce_on_map<P>(
+        &self,
+        map_id: u32,
+        packet: P,
+    ) -> Result<(), Error>
+    where
+        P: PacketEncode + PacketID,
+        Error: From<P::Error>,
+    {
+        let map = self.try_map(map_id)?;
+        map.broadcast(packet).await?;
+        Ok(())
     }
 
     pub fn insert_entity(&self, entity: Arc<GameEntity>) {
         let mut entities = self.entities.write();
+        if entity.as_character().is_some() {
+            let name = entity.basic().name().to_string();
+            self.names.write().insert(name, entity.id());
+        }
         entities.insert(entity.id(), entity);
     }
 
     pub fn remove_entity(&self, id: u32) {
         let mut entities = self.entities.write();
-        entities.remove(&id);
+        let Some(entity) = entities.remove(&id) else {
+            return;
+        };
+        let mut names = self.names.write();
+        let name = entity.basic().name();
+        if names.get(name.as_str()) == Some(&id) {
+            names.remove(name.as_str());
+        }
+    }
+
+    /// Reserves loading the character named `name` into the world, returns
+    /// `None` if it is being loaded already or it is in the world as another
+    /// entity than `mine`.
+    ///
+    /// The reservation holds until the returned guard is dropped, by then the
+    /// character should be in the world, or it failed to get there.
+    pub fn reserve_login(
+        &self,
+        name: &str,
+        mine: Option<u32>,
+    ) -> Option<LoginReservation<'_>> {
+        let mut logins = self.logins.lock();
+        if logins.contains(name) {
+            return None;
+        }
+        match self.names.read().get(name) {
+            Some(online) if Some(*online) != mine => return None,
+            _ => {},
+        }
+        logins.insert(name.to_owned());
+        Some(LoginReservation {
+            logins: &self.logins,
+            name: name.to_owned(),
+        })
+    }
+
+    /// The character in the world with that name, if any.
+    pub fn character_by_name(&self, name: &str) -> Option<Arc<GameEntity>> {
+        let id = self.names.read().get(name).copied()?;
+        self.entity(id)
+    }
+
+    /// Renames a character in the world, it gets saved with its new name
+    /// along with the rest of it.
+    pub fn rename_character(&self, id: u32, name: &str) -> Result<(), Error> {
+        let entity = self.entity(id).ok_or(Error::CharacterNotFound)?;
+        let me = entity.as_character().ok_or(Error::CharacterNotFound)?;
+        if let Err(reason) = self.name_filter.check(name) {
+            tracing::debug!(%id, %name, ?reason, "Rename rejected");
+            return Err(Error::NameNotAllowed(name.to_owned()));
+        }
+        let mut names = self.names.write();
+        match names.get(name) {
+            Some(other) if *other != id => {
+                return Err(Error::DuplicateName(name.to_owned()))
+            },
+            _ => {},
+        }
+        names.remove(me.entity().name().as_str());
+        names.insert(name.to_owned(), id);
+        me.entity().set_name(name.to_owned());
+        Ok(())
+    }
+
+    pub fn entity(&self, id: u32) -> Option<Arc<GameEntity>> {
+        self.entities.read().get(&id).cloned()
     }
 
     pub fn with_entity<F, R>(&self, id: u32, f: F) -> Option<R>
@@ -94,6 +575,143 @@ impl State {
         entities.get(&id).map(|v| f(v))
     }
 
+    /// Sends the packet to every character in the world, it is encoded once
+    /// for all of them, see [`systems::SharedPacket`].
+    #[tracing::instrument(skip(self, packet), fields(packet_id = P::PACKET_ID))]
+    pub async fn broadcast<P>(&self, packet: P) -> Result<(), Error>
+    where
+        P: PacketEncode + PacketID,
+        Error: From<P::Error>,
+    {
+        let mut shared = systems::SharedPacket::new(&packet);
+        let mut sends = Vec::new();
+        for e in self.entities() {
+            let Some(owner) = e.owner() else {
+                continue;
+            };
+            let msg = shared.encode_for(&owner)?;
+            sends.push((
+                e.id(),
+                async move { owner.try_send_encoded(msg).await },
+            ));
+        }
+        // The dead ones get removed from the world when their connection
+        // gets cleaned up.
+        systems::fan_out(sends).await;
+        Ok(())
+    }
+
+    /// Expires the status effects of every character in the world.
+    pub async fn tick_status_effects(&self, now: Instant) {
+        for entity in self.entities() {
+            let Some(character) = entity.as_character() else {
+                continue;
+            };
+            if !character.in_world() {
+                continue;
+            }
+            if let Err(error) = character.tick_status_effects(now).await {
+                tracing::warn!(
+                    %error,
+                    id = character.id(),
+                    "Failed to tick status effects"
+                );
+            }
+        }
+    }
+
+    /// Fills or empties the XP circle of every character in the world.
+    pub async fn tick_xp(&self, now: Instant) {
+        for entity in self.entities() {
+            let Some(character) = entity.as_character() else {
+                continue;
+            };
+            if !character.in_world() {
+                continue;
+            }
+            if let Err(error) = character.tick_xp(now).await {
+                tracing::warn!(
+                    %error,
+                    id = character.id(),
+                    "Failed to tick the XP circle"
+                );
+            }
+        }
+    }
+
+    /// Removes the expired floor items of every loaded map.
+    pub async fn expire_floor_items(&self, now: Instant) {
+        for map in self.loaded_maps() {
+            if let Err(error) = map.expire_floor_items(now).await {
+                tracing::warn!(
+                    %error,
+                    map_id = map.id(),
+                    "Failed to expire floor items"
+                );
+            }
+        }
+    }
+
+    /// Sends the movements held back on every loaded map to their
+    /// observers.
+    pub async fn flush_movements(&self) {
+        for map in self.loaded_maps() {
+            map.movements().flush().await;
+        }
+    }
+
+    /// Sends the batched experience gains of every character whose window
+    /// is over.
+    pub async fn flush_experience(&self, now: Instant) {
+        for entity in self.entities() {
+            let Some(character) = entity.as_character() else {
+                continue;
+            };
+            if !character.in_world() {
+                continue;
+            }
+            let window = self.experience_window;
+            if let Err(error) = character.flush_experience(now, window).await {
+                tracing::warn!(
+                    %error,
+                    id = character.id(),
+                    "Failed to flush experience"
+                );
+            }
+        }
+    }
+
+    /// Pings every character in the world to measure their latency.
+    pub async fn ping_all(&self, now: Instant) {
+        for entity in self.entities() {
+            let Some(character) = entity.as_character() else {
+                continue;
+            };
+            let owner = character.owner();
+            if let Err(error) = MsgPing::send(character.id(), &owner, now).await
+            {
+                tracing::debug!(%error, id = character.id(), "Failed to ping");
+            }
+        }
+    }
+
+    /// Saves every character in the world.
+    pub async fn save_all(&self) -> Result<(), Error> {
+        for entity in self.entities() {
+            let Some(character) = entity.as_character() else {
+                continue;
+            };
+            if let Err(error) = character.save(self).await {
+                tracing::warn!(
+                    %error,
+                    id = character.id(),
+                    "Failed to save character"
+                );
+            }
+        }
+        Ok(())
+    }
+
     pub fn entities(&self) -> Vec<Arc<GameEntity>> {
         let lock = self.entities.read();
         let values = lock.values();
@@ -108,18 +726,28 @@ impl State {
         &self,
         account_id: u32,
         realm_id: u32,
+        access: Access,
     ) -> Result<GeneratedLoginToken, crate::Error> {
         let token = rand::random();
-        self.login_tokens.lock().insert(
+        self.store_login_token(
             token,
             LoginToken {
                 account_id,
                 realm_id,
+                access,
             },
         );
         Ok(GeneratedLoginToken { token })
     }
 
+    /// Stores a Login Token, waking up the connection waiting for it if any.
+    pub fn store_login_token(&self, token: u64, login: LoginToken) {
+        self.login_tokens.lock().insert(token, login);
+        if let Some(waiter) = self.login_waiters.lock().remove(&token) {
+            waiter.notify_one();
+        }
+    }
+
     /// Remove a Login Token.
     pub fn remove_login_token(
         &self,
@@ -131,6 +759,37 @@ impl State {
             .ok_or(crate::Error::LoginTokenNotFound)
     }
 
+    /// Removes a Login Token, waiting up to [`State::login_token_wait`] for
+    /// it to be stored if it is not there yet.
+    ///
+    /// No lock is held while waiting, and dropping the future stops the wait.
+    pub async fn wait_login_token(
+        &self,
+        token: u64,
+    ) -> Result<LoginToken, crate::Error> {
+        if let Ok(login) = self.remove_login_token(token) {
+            return Ok(login);
+        }
+        let waiter =
+            self.login_waiters.lock().entry(token).or_default().clone();
+        let _guard = LoginWaiterGuard {
+            waiters: &self.login_waiters,
+            token,
+        };
+        let wait = async {
+            loop {
+                // Stored between the first try and the registration.
+                if let Ok(login) = self.remove_login_token(token) {
+                    return login;
+                }
+                waiter.notified().await;
+            }
+        };
+        tokio::time::timeout(self.login_token_wait, wait)
+            .await
+            .map_err(|_| crate::Error::LoginTokenNotFound)
+    }
+
     /// Store a new CreationToken.
     /// The token will be stored internally, and can be later removed by calling
     /// [`TokenStore::remove_creation_token`].
@@ -150,6 +809,18 @@ impl State {
         Ok(())
     }
 
+    /// Get a CreationToken without removing it.
+    pub fn creation_token(
+        &self,
+        token: u32,
+    ) -> Result<CreationToken, crate::Error> {
+        self.creation_tokens
+            .lock()
+            .get(&token)
+            .cloned()
+            .ok_or(crate::Error::CreationTokenNotFound)
+    }
+
     /// Remove a CreationToken.
     pub fn remove_creation_token(
         &self,
@@ -163,6 +834,7 @@ impl State {
 
     fn drain_entities(&self) -> Vec<Arc<GameEntity>> {
         let mut entities = self.entities.write();
+        self.names.write().clear();
         let values = entities.drain();
         values.map(|(_, v)| v).collect()
     }
@@ -170,6 +842,9 @@ impl State {
     /// Cleanup the state, close all connections and updating the database.
     pub async fn clean_up(self) -> Result<(), Error> {
         debug!("Clean up ..");
+        if let Err(error) = self.write_snapshot().await {
+            tracing::warn!(%error, "Failed to write the world snapshot");
+        }
         debug!("Saving Entities data ..");
         let entities = self.drain_entities();
         for e in entities {
@@ -177,7 +852,9 @@ impl State {
                 GameEntity::Character(character) => {
                     character.save(&self).await?
                 },
-                GameEntity::Npc(_) => {
+                GameEntity::Npc(_)
+                | GameEntity::FloorItem(_)
+                | GameEntity::Monster(_) => {
                     // Do nothing for now
                 },
             }
@@ -192,6 +869,28 @@ impl State {
 pub struct LoginToken {
     pub account_id: u32,
     pub realm_id: u32,
+    pub access: Access,
+}
+
+/// Forgets the waiter of a token once its connection stops waiting.
+struct LoginWaiterGuard<'a> {
+    waiters: &'a LoginWaiters,
+    token: u64,
+}
+
+impl Drop for LoginWaiterGuard<'_> {
+    fn drop(&mut self) { self.waiters.lock().remove(&self.token); }
+}
+
+/// Keeps other connections from loading a character into the world, see
+/// [`State::reserve_login`].
+pub struct LoginReservation<'a> {
+    logins: &'a Logins,
+    name: String,
+}
+
+impl Drop for LoginReservation<'_> {
+    fn drop(&mut self) { self.logins.lock().remove(&self.name); }
 }
 
 #[derive(Clone, Debug)]
@@ -247,3 +946,256 @@ impl Shutdown {
         self.shutdown = true;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::{FloorItem, FloorItemKind};
+    use crate::packets::MsgTalk;
+    use crate::test_utils::*;
+    use crate::world::Maps;
+    use futures::FutureExt;
+    use primitives::{Location, Size};
+    use tokio::sync::mpsc::Receiver;
+    use tq_network::Message;
+
+    fn talks(rx: &mut Receiver<Message>) -> usize {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .filter(|msg| matches!(msg, Message::Packet(MsgTalk::PACKET_ID, _)))
+            .count()
+    }
+
+    #[tokio::test]
+    async fn announcements_stay_on_their_map() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, mut b_rx)] = actors;
+                let (arena, plain) =
+                    (u32::from(Maps::Arena), u32::from(Maps::Newplain));
+                for (actor, map_id) in [(&a, arena), (&b, plain)] {
+                    let map = state.try_map(map_id)?;
+                    map.load_blank(Size::new(100, 100)).await?;
+                    let e = actor.entity();
+                    e.basic().set_map_id(map_id);
+                    e.basic().set_location(Location::new(50, 50, 0));
+                    map.insert_entity(e).await?;
+                }
+                talks(&mut a_rx);
+                talks(&mut b_rx);
+
+                state
+                    .announce_on_map(arena, MsgTalk::announce("Hi"))
+                    .await?;
+                assert_eq!(talks(&mut a_rx), 1);
+                assert_eq!(talks(&mut b_rx), 0);
+                assert!(state.map_by_id(0).is_none());
+                let unknown = state.announce_on_map(0, MsgTalk::announce("Hi"));
+                assert!(matches!(unknown.await, Err(Error::MapNotFound)));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn broadcasts_follow_the_codec_of_each_client() -> Result<(), Error> {
+        use tq_network::{Codec, IntoErrorPacket, PacketDecode};
+
+        /// The messages of the talks sent to the actor, decoded with `codec`.
+        fn told(rx: &mut Receiver<Message>, codec: Codec) -> Vec<String> {
+            std::iter::from_fn(|| rx.try_recv().ok())
+                .filter_map(|msg| match msg {
+                    Message::Packet(MsgTalk::PACKET_ID, bytes) => Some(
+                        MsgTalk::decode_with(&bytes, codec).unwrap().message,
+                    ),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let account_id = sqlx::query(
+                    "INSERT INTO accounts (username, password) VALUES ('json', '');",
+                )
+                .execute(state.pool())
+                .await?
+                .last_insert_rowid();
+                let (j, mut j_rx) =
+                    make_test_actor_with(&state, account_id as _, Codec::Json)
+                        .await?;
+                let arena = u32::from(Maps::Arena);
+                let map = state.try_map(arena)?;
+                map.load_blank(Size::new(100, 100)).await?;
+                for (actor, x) in [(&a, 50), (&j, 51)] {
+                    let e = actor.entity();
+                    e.basic().set_map_id(arena);
+                    e.basic().set_location(Location::new(x, 50, 0));
+                    map.insert_entity(e).await?;
+                }
+                told(&mut a_rx, Codec::Binary);
+                told(&mut j_rx, Codec::Json);
+
+                state.broadcast(MsgTalk::announce("World")).await?;
+                map.broadcast(MsgTalk::announce("Map")).await?;
+                let error = Error::from(MsgTalk::announce("Oops").error_packet());
+                j.send(error).await?;
+                assert_eq!(told(&mut a_rx, Codec::Binary), ["World", "Map"]);
+                assert_eq!(told(&mut j_rx, Codec::Json), ["World", "Map", "Oops"]);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn characters_are_found_by_name() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), (b, _)] = actors;
+                let a_name = a.entity().basic().name();
+                let b_name = b.entity().basic().name();
+                let found = state.character_by_name(&a_name).unwrap();
+                assert_eq!(found.id(), a.entity().id());
+                assert!(state.character_by_name("nobody").is_none());
+
+                // Renamed, it is only found under its new name.
+                state.rename_character(a.entity().id(), "Renamed")?;
+                assert!(state.character_by_name(&a_name).is_none());
+                let found = state.character_by_name("Renamed").unwrap();
+                assert_eq!(found.id(), a.entity().id());
+                assert_eq!(found.basic().name().as_str(), "Renamed");
+                // Nor could it take a name in use.
+                let e = state
+                    .rename_character(a.entity().id(), &b_name)
+                    .unwrap_err();
+                assert!(matches!(e, Error::DuplicateName(_)), "{e:?}");
+                assert_eq!(
+                    state.character_by_name(&b_name).unwrap().id(),
+                    b.entity().id()
+                );
+                // Nor a name that is not allowed.
+                let e = state
+                    .rename_character(a.entity().id(), "[GM]Renamed")
+                    .unwrap_err();
+                assert!(matches!(e, Error::NameNotAllowed(_)), "{e:?}");
+
+                // Gone once it leaves the world.
+                state.remove_entity(a.entity().id());
+                assert!(state.character_by_name("Renamed").is_none());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn loaded_maps_are_looked_up_before_visiting() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, _| {
+            async move {
+                let arena = u32::from(Maps::Arena);
+                state
+                    .try_map(arena)?
+                    .load_blank(Size::new(100, 100))
+                    .await?;
+                let mut visited = Vec::new();
+                state
+                    .for_each_loaded_map(|map| {
+                        visited.push(map.id());
+                        let state = &state;
+                        async move {
+                            // Reaching back into the state and loading
+                            // another map while visiting holds nothing up.
+                            let plain = u32::from(Maps::Newplain);
+                            let other = state.try_map(plain)?;
+                            other.load_blank(Size::new(100, 100)).await?;
+                            let msg = MsgTalk::announce("Hi");
+                            state.announce_on_map(map.id(), msg).await
+                        }
+                    })
+                    .await?;
+                assert_eq!(visited, [arena]);
+                assert_eq!(state.loaded_maps().len(), 2);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn announcing_while_characters_move() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, mut b_rx)] = actors;
+                let (arena, plain) =
+                    (u32::from(Maps::Arena), u32::from(Maps::Newplain));
+                let (from, to) = (state.try_map(arena)?, state.try_map(plain)?);
+                let (from, to) = (&*from, &*to);
+                for map in [from, to] {
+                    map.load_blank(Size::new(100, 100)).await?;
+                }
+                for actor in [&a, &b] {
+                    let e = actor.entity();
+                    e.basic().set_map_id(arena);
+                    e.basic().set_location(Location::new(50, 50, 0));
+                    from.insert_entity(e).await?;
+                }
+                // Something stays on either map, so none of them gets
+                // unloaded.
+                let id = state.ids().allocate(IdKind::FloorItem)?;
+                let kind = FloorItemKind::Silver(1);
+                let location = Location::new(10, 10, 0);
+                let item = FloorItem::new(id, kind, plain, location);
+                to.insert_floor_item(item).await?;
+                const ROUNDS: usize = 200;
+                let announce = async {
+                    for _ in 0..ROUNDS {
+                        let msg = MsgTalk::announce("Hi");
+                        state.announce_on_map(arena, msg).await?;
+                        tokio::task::yield_now().await;
+                    }
+                    Ok::<_, Error>(())
+                };
+                let moving = async {
+                    let e = b.entity();
+                    let location = e.basic().location();
+                    for i in 0..ROUNDS {
+                        let (from, to) =
+                            if i % 2 == 0 { (from, to) } else { (to, from) };
+                        e.basic().set_map_id(to.id());
+                        Map::transfer_entity(from, to, e.clone(), location)
+                            .await?;
+                        tokio::task::yield_now().await;
+                    }
+                    Ok::<_, Error>(())
+                };
+                // Keeps the channels from filling up, the announcements
+                // would wait on them otherwise.
+                let mut received = 0;
+                let draining = async {
+                    while received < ROUNDS {
+                        received += talks(&mut a_rx);
+                        talks(&mut b_rx);
+                        tokio::task::yield_now().await;
+                    }
+                };
+                let all = async { tokio::join!(announce, moving, draining) };
+                let (announced, moved, ()) =
+                    tokio::time::timeout(Duration::from_secs(10), all)
+                        .await
+                        .expect("the announcements got stuck");
+                announced?;
+                moved?;
+                assert_eq!(received, ROUNDS);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
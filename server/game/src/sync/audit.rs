@@ -0,0 +1,347 @@
+//! Instrumented locks to track down deadlocks.
+//!
+//! Every lock belongs to a class, the place in the code where it got created,
+//! so all the regions of all maps share the same class for example. Whenever
+//! a lock is taken while the same task holds a lock of another class, we
+//! remember the order they were taken in, along with where that happened. If
+//! we ever see the opposite order somewhere else, the two code paths could
+//! deadlock each other if they run at the same time, so we warn with both of
+//! their call sites.
+//!
+//! Locks held for longer than [`HELD_TOO_LONG`] get reported too. Holding a
+//! lock that long is most likely a lock held across an `.await`, but that is
+//! only a guess from the time it was held: the audit does not see the
+//! `.await`s themselves, a short one goes unnoticed and a slow computation
+//! under a lock gets reported all the same.
+//!
+//! All of this only exists in debug builds with the `lock-audit` feature,
+//! otherwise [`crate::sync::RwLock`] is the plain [`parking_lot::RwLock`].
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::panic::Location;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+/// A place in the code.
+pub type Site = &'static Location<'static>;
+
+/// Locks held longer than this get reported.
+pub const HELD_TOO_LONG: Duration = Duration::from_millis(100);
+
+/// Something suspicious the audit found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Finding {
+    /// Locks of two classes were taken in opposite orders at two places.
+    Inversion {
+        /// The classes of the two locks.
+        classes: (Site, Site),
+        /// Where the first lock was taken, then where the second was taken
+        /// while holding it.
+        first: (Site, Site),
+        /// Where the second lock was taken, then where the first was taken
+        /// while holding it.
+        second: (Site, Site),
+    },
+    /// A lock taken at `site` was held for longer than [`HELD_TOO_LONG`].
+    HeldTooLong { site: Site, held: Duration },
+}
+
+/// The order locks were taken in so far, `(a, b) => (x, y)` means a lock of
+/// class `b` was taken at `y` while holding a lock of class `a` taken at `x`.
+type Order = HashMap<(Site, Site), (Site, Site)>;
+
+static ORDER: Lazy<Mutex<Order>> = Lazy::new(Default::default);
+
+static FINDINGS: Mutex<Vec<Finding>> = Mutex::new(Vec::new());
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The locks held right now, by whoever holds them. A task could move to
+/// another thread while holding a lock, so this is shared by all threads.
+static HELD: Lazy<Mutex<HashMap<Holder, Vec<Held>>>> =
+    Lazy::new(Default::default);
+
+/// Who holds a lock, the task taking it, or the thread when it is not taken
+/// from a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Holder {
+    Task(tokio::task::Id),
+    Thread(ThreadId),
+}
+
+impl Holder {
+    fn current() -> Self {
+        match tokio::task::try_id() {
+            Some(id) => Self::Task(id),
+            None => Self::Thread(thread::current().id()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Held {
+    id: u64,
+    class: Site,
+    site: Site,
+}
+
+/// Returns everything the audit found so far.
+pub fn findings() -> Vec<Finding> { FINDINGS.lock().clone() }
+
+fn report(finding: Finding) {
+    let mut findings = FINDINGS.lock();
+    let seen = findings.iter().any(|f| match (f, &finding) {
+        (
+            Finding::Inversion { classes: a, .. },
+            Finding::Inversion { classes: b, .. },
+        ) => a == b,
+        (
+            Finding::HeldTooLong { site: a, .. },
+            Finding::HeldTooLong { site: b, .. },
+        ) => a == b,
+        _ => false,
+    });
+    if seen {
+        return;
+    }
+    match &finding {
+        Finding::Inversion { first, second, .. } => {
+            tracing::warn!(
+                first.held = %first.0,
+                first.taking = %first.1,
+                second.held = %second.0,
+                second.taking = %second.1,
+                "Locks taken in opposite order, potential deadlock"
+            );
+        },
+        Finding::HeldTooLong { site, held } => {
+            tracing::warn!(%site, ?held, "Lock held for too long, is it held across an .await?");
+        },
+    }
+    findings.push(finding);
+}
+
+/// Records that a lock of `class` is about to be taken at `site`.
+fn acquire(class: Site, site: Site) -> Token {
+    let holder = Holder::current();
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut held = HELD.lock();
+    let stack = held.entry(holder).or_default();
+    for h in stack.iter().filter(|h| h.class != class) {
+        let mut order = ORDER.lock();
+        order.entry((h.class, class)).or_insert((h.site, site));
+        if let Some(&second) = order.get(&(class, h.class)) {
+            report(Finding::Inversion {
+                classes: (h.class, class),
+                first: (h.site, site),
+                second,
+            });
+        }
+    }
+    stack.push(Held { id, class, site });
+    drop(held);
+    Token {
+        id,
+        holder,
+        site,
+        since: Instant::now(),
+    }
+}
+
+/// Tracks a held lock, until it gets dropped.
+struct Token {
+    id: u64,
+    holder: Holder,
+    site: Site,
+    since: Instant,
+}
+
+impl Drop for Token {
+    fn drop(&mut self) {
+        let mut held = HELD.lock();
+        if let Some(stack) = held.get_mut(&self.holder) {
+            stack.retain(|h| h.id != self.id);
+            if stack.is_empty() {
+                held.remove(&self.holder);
+            }
+        }
+        drop(held);
+        let elapsed = self.since.elapsed();
+        if elapsed > HELD_TOO_LONG {
+            report(Finding::HeldTooLong {
+                site: self.site,
+                held: elapsed,
+            });
+        }
+    }
+}
+
+/// A [`parking_lot::RwLock`] that reports how it is used, see the module
+/// docs for more.
+pub struct RwLock<T: ?Sized> {
+    class: Site,
+    inner: parking_lot::RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    #[track_caller]
+    pub fn new(value: T) -> Self {
+        Self {
+            class: Location::caller(),
+            inner: parking_lot::RwLock::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Where this lock was created, which is its class.
+    pub fn class(&self) -> Site { self.class }
+
+    #[track_caller]
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        let token = acquire(self.class, Location::caller());
+        RwLockReadGuard {
+            guard: self.inner.read(),
+            _token: token,
+        }
+    }
+
+    #[track_caller]
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let token = acquire(self.class, Location::caller());
+        RwLockWriteGuard {
+            guard: self.inner.write(),
+            _token: token,
+        }
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    #[track_caller]
+    fn default() -> Self { Self::new(T::default()) }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    guard: parking_lot::RwLockReadGuard<'a, T>,
+    _token: Token,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T { &self.guard }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    guard: parking_lot::RwLockWriteGuard<'a, T>,
+    _token: Token,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T { &self.guard }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T { &mut self.guard }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inversion_is_detected() {
+        let floor = RwLock::new(0);
+        let characters = RwLock::new(0);
+        {
+            let _floor = floor.read();
+            let _characters = characters.write();
+        }
+        let found = |f: &Finding| matches!(f, Finding::Inversion { classes, .. } if *classes == (characters.class(), floor.class()));
+        assert!(!findings().iter().any(found));
+        {
+            let _characters = characters.read();
+            let _floor = floor.write();
+        }
+        let findings = findings();
+        let Some(Finding::Inversion { first, second, .. }) =
+            findings.iter().find(|f| found(f))
+        else {
+            panic!("inversion was not detected: {findings:?}");
+        };
+        assert_eq!(first.0.line() + 1, first.1.line());
+        assert_eq!(second.0.line() + 1, second.1.line());
+        assert_ne!(first.0, second.1);
+    }
+
+    #[test]
+    fn same_order_is_fine() {
+        let a = RwLock::new(0);
+        let b = RwLock::new(0);
+        for _ in 0..2 {
+            let _a = a.write();
+            let _b = b.read();
+        }
+        assert!(!findings().iter().any(|f| matches!(
+            f,
+            Finding::Inversion { classes, .. }
+                if *classes == (a.class(), b.class())
+                    || *classes == (b.class(), a.class())
+        )));
+    }
+
+    #[test]
+    fn locks_held_by_others_do_not_count() {
+        let a = RwLock::new(0);
+        let b = RwLock::new(0);
+        let (held, release) =
+            (std::sync::Barrier::new(2), std::sync::Barrier::new(2));
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let _a = a.read();
+                held.wait();
+                release.wait();
+            });
+            held.wait();
+            // The other thread holds `a`, not us.
+            drop(b.read());
+            release.wait();
+        });
+        {
+            let _b = b.read();
+            let _a = a.read();
+        }
+        assert!(!findings().iter().any(|f| matches!(
+            f,
+            Finding::Inversion { classes, .. }
+                if *classes == (a.class(), b.class())
+                    || *classes == (b.class(), a.class())
+        )));
+    }
+
+    #[test]
+    fn long_held_lock_is_reported() {
+        let lock = RwLock::new(0);
+        let site = {
+            let guard = lock.write();
+            std::thread::sleep(HELD_TOO_LONG + Duration::from_millis(10));
+            guard._token.site
+        };
+        assert!(findings().iter().any(
+            |f| matches!(f, Finding::HeldTooLong { site: s, .. } if *s == site)
+        ));
+    }
+}
@@ -0,0 +1,15 @@
+//! Synchronization primitives used by the world.
+//!
+//! By default these are just the [`parking_lot`] locks. Building a debug
+//! build with the `lock-audit` feature swaps them with instrumented locks that
+//! watch the order locks get taken in, and warn about lock order inversions
+//! and locks that are held for too long, see [`audit`] for more.
+
+#[cfg(all(feature = "lock-audit", debug_assertions))]
+pub mod audit;
+
+#[cfg(all(feature = "lock-audit", debug_assertions))]
+pub use audit::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+#[cfg(not(all(feature = "lock-audit", debug_assertions)))]
+pub use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
@@ -0,0 +1,204 @@
+//! What happens to players who stay away from their keyboard for too long.
+//!
+//! Every packet a client sends, but the answers to pings, counts as activity,
+//! see [`ActorHandle::last_activity`](tq_network::ActorHandle::last_activity).
+//! The answers to pings are the only packets an idle client still sends, so
+//! the check runs on them, with the actor at hand.
+
+use crate::entities::Character;
+use crate::packets::{MsgLogout, MsgTalk, TalkChannel};
+use crate::{ActorState, Error, State};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tq_network::{Actor, DisconnectReason};
+
+/// How long a player could do nothing by default before the [`AfkPolicy`]
+/// kicks in.
+pub const AFK_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// What happens to a player who did nothing for too long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AfkPolicy {
+    /// They are told they are away, once until they do something again.
+    Warn,
+    /// They are sent back to the character select screen.
+    #[default]
+    Select,
+    /// They are disconnected.
+    Disconnect,
+}
+
+impl FromStr for AfkPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "warn" => Ok(Self::Warn),
+            "select" => Ok(Self::Select),
+            "disconnect" => Ok(Self::Disconnect),
+            _ => Err(Error::Other(format!(
+                "Invalid AFK policy: {s:?}, expected warn|select|disconnect"
+            ))),
+        }
+    }
+}
+
+/// How long players could stay away and what happens to them after.
+///
+/// It could be configured using the `AFK_TIMEOUT_SECS` and `AFK_POLICY`
+/// environment variables, a timeout of zero turns it off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Afk {
+    pub timeout: Duration,
+    pub policy: AfkPolicy,
+}
+
+impl Default for Afk {
+    fn default() -> Self {
+        Self {
+            timeout: AFK_TIMEOUT,
+            policy: AfkPolicy::default(),
+        }
+    }
+}
+
+impl Afk {
+    /// Loads the timeout and the policy from the environment, falling back
+    /// to the defaults for any of them that is not configured.
+    pub fn from_env() -> Result<Self, Error> {
+        let mut afk = Self::default();
+        if let Ok(v) = dotenvy::var("AFK_TIMEOUT_SECS") {
+            afk.timeout = Duration::from_secs(v.trim().parse()?);
+        }
+        if let Ok(v) = dotenvy::var("AFK_POLICY") {
+            afk.policy = v.parse()?;
+        }
+        Ok(afk)
+    }
+}
+
+/// Applies the [`AfkPolicy`] to the character of the actor if it did nothing
+/// for too long as of `now`, returns whether it did.
+///
+/// Fighting or trading counts as doing something, it could be the other side
+/// keeping them busy.
+#[tracing::instrument(skip(state, actor))]
+pub async fn check_afk(
+    state: &State,
+    actor: &Actor<ActorState>,
+    now: Instant,
+) -> Result<bool, Error> {
+    let afk = state.afk();
+    if afk.timeout.is_zero() {
+        return Ok(false);
+    }
+    let Ok(entity) = actor.try_entity() else {
+        return Ok(false);
+    };
+    let Some(me) = entity.as_character() else {
+        return Ok(false);
+    };
+    let handle = actor.handle();
+    if me.is_trading() || me.in_combat(now, state.combat_lock()) {
+        handle.touch(now);
+    }
+    if handle.idle_for(now) < afk.timeout {
+        actor.set_afk_warned(false);
+        return Ok(false);
+    }
+    tracing::debug!(id = me.id(), policy = ?afk.policy, "Away for too long");
+    match afk.policy {
+        AfkPolicy::Warn => {
+            if !actor.set_afk_warned(true) {
+                tell(me, "You have been away for a while.").await?;
+            }
+        },
+        AfkPolicy::Select => {
+            MsgLogout::to_character_select(state, actor, me).await?;
+        },
+        AfkPolicy::Disconnect => {
+            tell(me, "You got disconnected for being away too long.").await?;
+            handle.disconnect(DisconnectReason::Kicked).await?;
+        },
+    }
+    Ok(true)
+}
+
+async fn tell(me: &Character, message: &str) -> Result<(), Error> {
+    let msg = MsgTalk::from_system(me.id(), TalkChannel::System, message);
+    me.owner().send(msg).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use futures::FutureExt;
+    use tq_network::{Message, PacketID};
+
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    #[tokio::test]
+    async fn idle_player_goes_back_to_select() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |mut state, actors| {
+            state.set_afk(Afk {
+                timeout: TIMEOUT,
+                policy: AfkPolicy::Select,
+            });
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let start = Instant::now();
+                a.handle().touch(start);
+
+                assert!(!check_afk(&state, &a, start + TIMEOUT / 2).await?);
+                assert!(a.try_entity().is_ok());
+
+                assert!(check_afk(&state, &a, start + TIMEOUT).await?);
+                assert!(a.try_entity().is_err());
+                let mut logouts = 0;
+                while let Ok(msg) = a_rx.try_recv() {
+                    if let Message::Packet(MsgLogout::PACKET_ID, _) = msg {
+                        logouts += 1;
+                    }
+                }
+                assert_eq!(logouts, 1);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn active_player_never_goes_away() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |mut state, actors| {
+            state.set_afk(Afk {
+                timeout: TIMEOUT,
+                policy: AfkPolicy::Disconnect,
+            });
+            async move {
+                let [(a, _), (b, _)] = actors;
+                let mut now = Instant::now();
+                for _ in 0..5 {
+                    a.handle().touch(now);
+                    now += TIMEOUT - Duration::from_secs(1);
+                    assert!(!check_afk(&state, &a, now).await?);
+                }
+                // Trading keeps the timer from running out, and resets it.
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                me.set_trade_partner(Some(b.entity().id()));
+                now += TIMEOUT * 2;
+                assert!(!check_afk(&state, &a, now).await?);
+                me.set_trade_partner(None);
+                assert!(!check_afk(&state, &a, now).await?);
+                assert_ne!(a.disconnect_reason(), DisconnectReason::Kicked);
+                assert!(a.try_entity().is_ok());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
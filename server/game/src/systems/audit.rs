@@ -0,0 +1,298 @@
+use crate::packets::{MsgTalk, TalkChannel};
+use crate::{ActorState, Error};
+use sqlx::SqlitePool;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use tq_db::cheat_log::CheatLog;
+use tq_db::connection_log::ConnectionLog;
+use tq_network::{Actor, DisconnectReason};
+
+/// How many of the latest logins a player sees when logging in.
+pub const LAST_LOGINS_SHOWN: i64 = 5;
+
+#[derive(Debug)]
+enum AuditRecord {
+    Connected(ConnectionLog),
+    Disconnected {
+        session_id: i64,
+        at: i64,
+        reason: DisconnectReason,
+    },
+    PossibleCheat(CheatLog),
+    /// Answers once everything sent before it got written.
+    Flush(oneshot::Sender<()>),
+}
+
+/// Writes the audit records to the database in the background, so logging
+/// in or out never waits on it.
+#[derive(Debug, Clone)]
+pub struct AuditWriter {
+    tx: mpsc::UnboundedSender<AuditRecord>,
+    /// The last session id handed out, counting up from the highest one in
+    /// the database so no two sessions ever share one.
+    last_session_id: Arc<AtomicI64>,
+}
+
+impl AuditWriter {
+    /// Starts the writer task, it runs until every copy of the writer is
+    /// dropped.
+    pub async fn spawn(pool: SqlitePool) -> Result<Self, Error> {
+        let last_session_id = ConnectionLog::last_session_id(&pool).await?;
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(record) = rx.recv().await {
+                let result = match record {
+                    AuditRecord::Connected(log) => log.insert(&pool).await,
+                    AuditRecord::Disconnected {
+                        session_id,
+                        at,
+                        reason,
+                    } => {
+                        ConnectionLog::close(
+                            &pool,
+                            session_id,
+                            at,
+                            reason.as_str(),
+                        )
+                        .await
+                    },
+                    AuditRecord::PossibleCheat(log) => log.insert(&pool).await,
+                    AuditRecord::Flush(done) => {
+                        let _ = done.send(());
+                        Ok(())
+                    },
+                };
+                if let Err(e) = result {
+                    tracing::error!(error = ?e, "Failed to write audit record");
+                }
+            }
+        });
+        Ok(Self {
+            tx,
+            last_session_id: Arc::new(AtomicI64::new(last_session_id)),
+        })
+    }
+
+    fn write(&self, record: AuditRecord) {
+        if self.tx.send(record).is_err() {
+            tracing::warn!("Audit writer is gone, dropping record");
+        }
+    }
+
+    /// Starts a new session for the actor, and remembers it so we could close
+    /// it on disconnect.
+    pub fn connected(
+        &self,
+        actor: &Actor<ActorState>,
+        account_id: u32,
+        character_id: Option<i32>,
+    ) {
+        let session_id =
+            self.last_session_id.fetch_add(1, Ordering::Relaxed) + 1;
+        actor.set_session_id(session_id);
+        self.write(AuditRecord::Connected(ConnectionLog {
+            session_id,
+            account_id: account_id as i32,
+            character_id,
+            ip: actor.peer_addr().map(|addr| addr.ip().to_string()),
+            connected_at: chrono::Utc::now().timestamp(),
+            ..Default::default()
+        }));
+    }
+
+    /// Closes the session of the actor, if it ever started one.
+    pub fn disconnected(&self, actor: &Actor<ActorState>) {
+        if let Some(session_id) = actor.session_id() {
+            self.write(AuditRecord::Disconnected {
+                session_id,
+                at: chrono::Utc::now().timestamp(),
+                reason: actor.disconnect_reason(),
+            });
+        }
+    }
+
+    /// Records something the character did that its client could only do if
+    /// it was tampered with.
+    pub fn possible_cheat(
+        &self,
+        character_id: i32,
+        kind: &str,
+        detail: impl Into<String>,
+    ) {
+        let detail = detail.into();
+        tracing::warn!(%character_id, %kind, %detail, "Possible cheat");
+        self.write(AuditRecord::PossibleCheat(CheatLog {
+            character_id,
+            kind: kind.to_owned(),
+            detail,
+            caught_at: chrono::Utc::now().timestamp(),
+            ..Default::default()
+        }));
+    }
+
+    /// Waits until everything sent so far got written.
+    pub async fn flush(&self) {
+        let (tx, rx) = oneshot::channel();
+        self.write(AuditRecord::Flush(tx));
+        let _ = rx.await;
+    }
+}
+
+/// The latest logins of the account, as lines to show to the player.
+pub async fn last_logins(
+    pool: &SqlitePool,
+    character_id: u32,
+    account_id: u32,
+) -> Result<Vec<MsgTalk>, Error> {
+    let logs = ConnectionLog::latest_of_account(
+        pool,
+        account_id as i32,
+        LAST_LOGINS_SHOWN,
+    )
+    .await?;
+    let lines = logs
+        .into_iter()
+        .map(|log| {
+            let ip = log.ip.as_deref().unwrap_or("unknown");
+            let at =
+                chrono::NaiveDateTime::from_timestamp_opt(log.connected_at, 0)
+                    .map(|at| at.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+                    .unwrap_or_default();
+            MsgTalk::from_system(
+                character_id,
+                TalkChannel::System,
+                format!("Last login from {ip} at {at}"),
+            )
+        })
+        .collect();
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::MsgConnect;
+    use crate::test_utils::*;
+    use futures::FutureExt;
+    use primitives::Size;
+    use tq_network::{Message, PacketDecode, PacketID, PacketProcess};
+
+    async fn connect(
+        state: &crate::State,
+        actor: &Actor<ActorState>,
+        account_id: u32,
+    ) -> Result<(), Error> {
+        let map = state.try_map(1010)?;
+        if !map.loaded() {
+            map.load_blank(Size::new(200, 200)).await?;
+        }
+        let token =
+            state.generate_login_token(account_id, 1, Default::default())?;
+        let msg = MsgConnect {
+            token: token.token,
+            ..Default::default()
+        };
+        msg.process(state, actor).await
+    }
+
+    async fn session(
+        state: &crate::State,
+        actor: &Actor<ActorState>,
+    ) -> Result<ConnectionLog, Error> {
+        state.audit().flush().await;
+        let session_id = actor.session_id().expect("no session");
+        let log = ConnectionLog::by_session(state.pool(), session_id).await?;
+        Ok(log.expect("session was not written"))
+    }
+
+    #[tokio::test]
+    async fn kicks_and_closes_are_told_apart() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), (b, _)] = actors;
+                a.set_peer_addr("1.2.3.4:5816".parse().unwrap());
+                connect(&state, &a, 1).await?;
+                connect(&state, &b, 2).await?;
+                let log = session(&state, &a).await?;
+                assert_eq!(log.ip.as_deref(), Some("1.2.3.4"));
+                assert_eq!(log.disconnected_at, None);
+
+                a.handle().disconnect(DisconnectReason::Kicked).await?;
+                state.audit().disconnected(&a);
+                state.audit().disconnected(&b);
+                let kicked = session(&state, &a).await?;
+                let closed = session(&state, &b).await?;
+                assert!(kicked.disconnected_at.is_some());
+                assert_eq!(kicked.disconnect_reason.as_deref(), Some("kicked"));
+                assert_eq!(
+                    closed.disconnect_reason.as_deref(),
+                    Some("client_closed")
+                );
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn sessions_never_share_an_id() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), (b, _)] = actors;
+                connect(&state, &a, 1).await?;
+                connect(&state, &b, 2).await?;
+                let (first, second) =
+                    (session(&state, &a).await?, session(&state, &b).await?);
+                assert_eq!(second.session_id, first.session_id + 1);
+
+                // After a restart they go on from the last one.
+                let restarted =
+                    AuditWriter::spawn(state.pool().clone()).await?;
+                restarted.connected(&a, 1, None);
+                restarted.flush().await;
+                let session_id = a.session_id().unwrap();
+                assert_eq!(session_id, second.session_id + 1);
+                let log =
+                    ConnectionLog::by_session(state.pool(), session_id).await?;
+                assert_eq!(log.map(|l| l.account_id), Some(1));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn last_logins_are_shown_on_login() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                a.set_peer_addr("1.2.3.4:5816".parse().unwrap());
+                // The first login has none, then one more every time up to
+                // the limit.
+                for n in 0..LAST_LOGINS_SHOWN + 2 {
+                    connect(&state, &a, 1).await?;
+                    state.audit().flush().await;
+                    let mut lines = Vec::new();
+                    while let Ok(msg) = a_rx.try_recv() {
+                        if let Message::Packet(MsgTalk::PACKET_ID, bytes) = msg {
+                            let msg = MsgTalk::decode(&bytes)?;
+                            if msg.message.starts_with("Last login") {
+                                lines.push(msg.message);
+                            }
+                        }
+                    }
+                    assert_eq!(lines.len() as i64, n.min(LAST_LOGINS_SHOWN));
+                    for line in lines {
+                        assert!(line.starts_with("Last login from 1.2.3.4 at "));
+                    }
+                }
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
@@ -0,0 +1,87 @@
+use crate::packets::MsgTalk;
+use crate::{ActorState, Error, State};
+use std::ops::RangeInclusive;
+use tq_network::{Actor, DisconnectReason, DropReason};
+
+/// How many packets we could not make sense of a client could send before it
+/// gets disconnected.
+pub const INVALID_PACKET_LIMIT: u32 = 20;
+
+/// Loads the limit from the `INVALID_PACKET_LIMIT` environment variable,
+/// falling back to [`INVALID_PACKET_LIMIT`].
+pub fn invalid_packet_limit_from_env() -> Result<u32, Error> {
+    match dotenvy::var("INVALID_PACKET_LIMIT") {
+        Ok(limit) => Ok(limit.trim().parse()?),
+        Err(_) => Ok(INVALID_PACKET_LIMIT),
+    }
+}
+
+/// The versions of the game client the server could talk to, clients outside
+/// of it are told to update and get disconnected on connect.
+///
+/// It could be configured using the `CLIENT_VERSION_MIN` and
+/// `CLIENT_VERSION_MAX` environment variables, any version is supported by
+/// default.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientVersions {
+    range: RangeInclusive<u16>,
+}
+
+impl Default for ClientVersions {
+    fn default() -> Self { Self::new(u16::MIN, u16::MAX) }
+}
+
+impl ClientVersions {
+    pub fn new(min: u16, max: u16) -> Self { Self { range: min..=max } }
+
+    /// Loads the supported versions from the environment, falling back to the
+    /// default for any bound that is not configured.
+    pub fn from_env() -> Result<Self, Error> {
+        let mut min = u16::MIN;
+        let mut max = u16::MAX;
+        if let Ok(v) = dotenvy::var("CLIENT_VERSION_MIN") {
+            min = v.trim().parse()?;
+        }
+        if let Ok(v) = dotenvy::var("CLIENT_VERSION_MAX") {
+            max = v.trim().parse()?;
+        }
+        Ok(Self::new(min, max))
+    }
+
+    pub fn min(&self) -> u16 { *self.range.start() }
+
+    pub fn max(&self) -> u16 { *self.range.end() }
+
+    pub fn supports(&self, version: u16) -> bool {
+        self.range.contains(&version)
+    }
+}
+
+/// Called for every packet of the actor that got dropped. A client sending
+/// packets we could not decode, or do not know, is likely a version we do not
+/// support: it is told so the first time, and disconnected once it sent
+/// [`State::invalid_packet_limit`] of them so it does not hold on to its slot.
+#[tracing::instrument(skip(state, actor), fields(actor = actor.id()))]
+pub async fn on_dropped_packet(
+    state: &State,
+    actor: &Actor<ActorState>,
+    reason: DropReason,
+) -> Result<(), Error> {
+    if reason == DropReason::RateLimited {
+        return Ok(());
+    }
+    let handle = actor.handle();
+    handle.record_drop(reason);
+    let invalid =
+        handle.drops(DropReason::Decode) + handle.drops(DropReason::UnknownId);
+    if invalid == state.invalid_packet_limit() {
+        tracing::warn!(%invalid, "Too many invalid packets, disconnecting");
+        handle.disconnect(DisconnectReason::InvalidPackets).await?;
+    } else if actor.take_unsupported_notice() {
+        let to = actor.try_entity().map_or(0, |e| e.id());
+        actor
+            .send(MsgTalk::maybe_unsupported(to, actor.locale()))
+            .await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,635 @@
+use crate::entities::{Character, CharacterState, Entity, Monster, Npc};
+use crate::packets::{
+    AttributeKind, InteractionType, MsgInteract, MsgItem, MsgItemInfo, MsgTalk,
+    MsgUserAttrib, TalkChannel,
+};
+use crate::systems::{attack_interval, Stat};
+use crate::world::Map;
+use crate::{Error, State};
+use rand::Rng;
+use std::time::{Duration, Instant};
+use tq_db::item::Item;
+
+/// How far a bow could shoot.
+pub const ARCHER_RANGE: u16 = 18;
+
+/// How close a character has to stand to hit its target with a melee weapon.
+pub const MELEE_RANGE: u16 = 2;
+
+/// How long after taking or dealing damage a character could not go through
+/// a portal or teleport away by default.
+pub const COMBAT_LOCK: Duration = Duration::from_secs(5);
+
+/// Tells the character it could not leave yet if it fought too recently,
+/// see [`COMBAT_LOCK`], and returns whether it is free to go.
+pub async fn ensure_out_of_combat(
+    state: &State,
+    me: &Character,
+    now: Instant,
+) -> Result<bool, Error> {
+    if !me.in_combat(now, state.combat_lock()) {
+        return Ok(true);
+    }
+    let msg = MsgTalk::from_system(
+        me.id(),
+        TalkChannel::System,
+        "You cannot leave combat yet.",
+    );
+    me.owner().send(msg).await?;
+    Ok(false)
+}
+
+/// Why an attack did not happen, nothing gets used up when it does not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttackRejection {
+    TargetNotFound,
+    OutOfRange,
+    OutOfSight,
+    NoArrows,
+    /// The attacker is dead, frozen or busy with something else.
+    CannotAttack,
+    /// The target is dead, or busy trading or vending, or it is the pole and
+    /// the attacker has no guild or the guild war is not running.
+    NotAttackable,
+    /// The last attack was too recent for the weapon in hand.
+    TooFast,
+    /// Players could not attack each other on that map.
+    PkDisabled,
+}
+
+impl AttackRejection {
+    /// What the player gets told.
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::TargetNotFound => "Target not found.",
+            Self::OutOfRange => "The target is too far away.",
+            Self::OutOfSight => "Something is in the way.",
+            Self::NoArrows => "You have no arrows left.",
+            Self::CannotAttack => "You can not attack right now.",
+            Self::NotAttackable => "The target can not be attacked.",
+            Self::TooFast => "You are attacking too fast.",
+            Self::PkDisabled => "PK is not allowed on this map.",
+        }
+    }
+}
+
+/// Whether the item type is a bow.
+pub fn is_bow(item_type: u32) -> bool { item_type / 1000 == 500 }
+
+/// Whether the item type is a pack of arrows.
+pub fn is_arrow(item_type: u32) -> bool { item_type / 1000 == 1050 }
+
+/// Whoever gets hit by an attack.
+#[derive(Clone, Copy)]
+enum Target<'a> {
+    Character(&'a Character),
+    Monster(&'a Monster),
+    /// The pole of the guild war, its health is kept by the war, see
+    /// [`GuildWar::hit_pole`](crate::events::GuildWar::hit_pole).
+    Pole(&'a Npc),
+}
+
+impl Target<'_> {
+    fn id(&self) -> u32 { self.entity().id() }
+
+    fn entity(&self) -> &Entity {
+        match self {
+            Self::Character(c) => c.entity(),
+            Self::Monster(m) => m.entity(),
+            Self::Pole(p) => p.entity(),
+        }
+    }
+
+    /// Monsters and the pole have no agility of their own, nothing makes
+    /// them dodge.
+    fn agility(&self) -> u16 {
+        match self {
+            Self::Character(c) => c.agility(),
+            Self::Monster(_) | Self::Pole(_) => 0,
+        }
+    }
+
+    fn can_be_attacked(&self) -> bool {
+        match self {
+            Self::Character(c) => c.state().can_be_attacked(),
+            Self::Monster(m) => m.entity().hp().current() > 0,
+            Self::Pole(_) => true,
+        }
+    }
+}
+
+/// Attacks `target_id` with whatever weapon `me` has equipped. Archers shoot
+/// from afar using up an arrow for every shot, everyone else has to stand
+/// next to the target. The target is either a character or a monster on the
+/// same map, a monster killed this way drops its loot where it died, or the
+/// pole of the guild war, which only members of a guild could attack while
+/// the war is running.
+///
+/// Attacks coming faster than the weapon allows, see [`attack_interval`],
+/// are turned down.
+///
+/// The attack is shown to everyone around, with a zero damage if it missed.
+#[tracing::instrument(skip(state, me, now, rng), fields(me = me.id()))]
+pub async fn physical_attack<R: Rng + Send>(
+    state: &State,
+    me: &Character,
+    target_id: u32,
+    now: Instant,
+    rng: &mut R,
+) -> Result<(), Error> {
+    let map = state.try_map(me.entity().map_id())?;
+    let character = state
+        .entity(target_id)
+        .filter(|e| e.basic().map_id() == map.id());
+    let monster = map.monster(target_id);
+    let target = match (
+        character.as_ref().and_then(|e| e.as_character()),
+        monster.as_ref().and_then(|e| e.as_monster()),
+    ) {
+        (Some(c), _) => Target::Character(c),
+        (None, Some(m)) => Target::Monster(m),
+        (None, None) => match map.npc(target_id).filter(|npc| npc.is_pole()) {
+            Some(pole) => Target::Pole(pole),
+            None => return tell(me, AttackRejection::TargetNotFound).await,
+        },
+    };
+    if matches!(target, Target::Character(_)) && !map.pk_allowed() {
+        return tell(me, AttackRejection::PkDisabled).await;
+    }
+    // The damage dealt to the pole goes to the guild of the attacker.
+    let guild = match target {
+        Target::Pole(_) => match state.guilds().of(me.character_id()) {
+            Some(guild) if state.guild_war().is_running() => Some(guild.id()),
+            _ => return tell(me, AttackRejection::NotAttackable).await,
+        },
+        _ => None,
+    };
+    if !me.state().can_attack() {
+        return tell(me, AttackRejection::CannotAttack).await;
+    }
+    if !target.can_be_attacked() {
+        return tell(me, AttackRejection::NotAttackable).await;
+    }
+    let pool = state.pool();
+    let weapon =
+        Item::equipped(pool, me.character_id(), Item::RIGHT_HAND).await?;
+    let weapon_type = weapon.as_ref().map(|item| item.item_type as u32);
+    let action = match weapon_type {
+        Some(item_type) if is_bow(item_type) => InteractionType::Shoot,
+        _ => InteractionType::Attack,
+    };
+    let interval = attack_interval(weapon_type);
+    if !me
+        .cooldowns()
+        .try_act(action, interval, state.action_jitter(), now)
+    {
+        tracing::debug!(?action, "Attacking too fast");
+        return tell(me, AttackRejection::TooFast).await;
+    }
+    let attack = me.strength() as u32 + me.equipment_attack();
+    let attack = me.status_effects().fold(Stat::Attack, attack);
+    let (a, b) = (me.entity().location(), target.entity().location());
+    let (from, to) = ((a.x, a.y), (b.x, b.y));
+    let damage = if action == InteractionType::Shoot {
+        if !tq_math::in_range(from, to, ARCHER_RANGE) {
+            return tell(me, AttackRejection::OutOfRange).await;
+        }
+        if !map.in_sight(from, to) {
+            return tell(me, AttackRejection::OutOfSight).await;
+        }
+        let arrows = Item::equipped(pool, me.character_id(), Item::LEFT_HAND)
+            .await?
+            .filter(|item| is_arrow(item.item_type as u32) && item.amount > 0);
+        let Some(mut arrows) = arrows else {
+            return tell(me, AttackRejection::NoArrows).await;
+        };
+        // Someone else could have used it in the meantime.
+        if !arrows.use_one(pool).await? {
+            return tell(me, AttackRejection::NoArrows).await;
+        }
+        if arrows.amount == 0 {
+            arrows.unequip(pool).await?;
+            let msg = MsgItem::unequip(me.id(), &arrows, Item::LEFT_HAND);
+            me.owner().send(msg).await?;
+        } else {
+            me.owner()
+                .send(MsgItemInfo::stack(me.id(), &arrows))
+                .await?;
+        }
+        let chance = tq_math::archer_hit_chance(me.agility(), target.agility());
+        if rng.gen_range(0..100) < chance {
+            tq_math::archer_damage(attack, target.agility())
+        } else {
+            0
+        }
+    } else {
+        if !tq_math::in_range(from, to, MELEE_RANGE) {
+            return tell(me, AttackRejection::OutOfRange).await;
+        }
+        attack
+    };
+    let mut slain = None;
+    if let Some(guild) = guild.filter(|_| damage > 0) {
+        state.guild_war().hit_pole(state, guild, damage).await?;
+    } else if damage > 0 {
+        let mut hp = target.entity().hp();
+        hp.decrement(damage.min(u16::MAX as u32) as u16);
+        target.entity().set_hp(hp);
+        match target {
+            Target::Character(target) => {
+                // Whoever got there first killed it.
+                let killed = hp.current() == 0
+                    && target.try_transition(CharacterState::Dead).is_ok();
+                if killed {
+                    tracing::debug!(target = target.id(), "Killed");
+                    let now = crate::utils::current_ts() as i64;
+                    super::record_enemy(state, target, me, now).await?;
+                }
+                let msg = MsgUserAttrib::single(
+                    target.id(),
+                    AttributeKind::Health,
+                    hp.current() as u64,
+                );
+                target.owner().send(msg).await?;
+            },
+            Target::Monster(monster) if hp.current() == 0 => {
+                slain = Some(monster);
+            },
+            Target::Monster(_) | Target::Pole(_) => {},
+        }
+    }
+    me.on_attack(now).await?;
+    if let Target::Character(target) = target {
+        target.on_attacked(now);
+    }
+    tracing::trace!(?action, %damage, target = target.id(), "Attacked");
+    let msg = MsgInteract::new(me.id(), target.id(), to, action, damage);
+    me.owner().send(msg.clone()).await?;
+    me.try_screen()?.send_message(msg).await?;
+    if let Some(monster) = slain {
+        kill_monster(&map, me, monster, rng).await?;
+    }
+    Ok(())
+}
+
+/// Takes the monster `me` killed off the map, and drops its loot where it
+/// died, kept for `me` for a while.
+async fn kill_monster<R: Rng + Send>(
+    map: &Map,
+    me: &Character,
+    monster: &Monster,
+    rng: &mut R,
+) -> Result<(), Error> {
+    // Whoever got there first killed it.
+    if map.remove_monster(monster.id()).await?.is_none() {
+        return Ok(());
+    }
+    tracing::debug!(target = monster.id(), "Killed");
+    let location = monster.entity().location();
+    map.on_monster_killed(monster.kind(), location, Some(me.id()), rng)
+        .await?;
+    Ok(())
+}
+
+async fn tell(me: &Character, rejection: AttackRejection) -> Result<(), Error> {
+    let msg = MsgTalk::from_system(
+        me.id(),
+        TalkChannel::TopLeft,
+        rejection.message(),
+    );
+    me.owner().send(msg).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::MonsterType;
+    use crate::systems::{DropTable, Tile, TileAccess, BOW_INTERVAL};
+    use crate::test_utils::*;
+    use crate::world::MapAttributes;
+    use futures::FutureExt;
+    use primitives::{Location, Size};
+    use rand::SeedableRng;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// A bow and a pack of arrows.
+    const BOW: i32 = 500_005;
+    const ARROWS: i32 = 1_050_000;
+
+    async fn equip(
+        state: &State,
+        character_id: i32,
+        item_type: i32,
+        position: i16,
+        amount: i16,
+    ) -> Result<(), Error> {
+        sqlx::query(
+            "INSERT INTO items (character_id, item_type, position, amount) VALUES (?, ?, ?, ?);",
+        )
+        .bind(character_id)
+        .bind(item_type)
+        .bind(position)
+        .bind(amount)
+        .execute(state.pool())
+        .await?;
+        Ok(())
+    }
+
+    /// Puts both characters on a blank map, ten tiles away from each other.
+    async fn face_off(
+        state: &State,
+        me: &Character,
+        target: &Character,
+    ) -> Result<(), Error> {
+        let map = state.try_map(1010)?;
+        map.load_blank(Size::new(100, 100)).await?;
+        for (c, x) in [(me, 40), (target, 50)] {
+            c.entity()
+                .set_map_id(1010)
+                .set_location(Location::new(x, 40, 0));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shooting_without_arrows_is_blocked() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, mut b_rx)] = actors;
+                let (a_entity, b_entity) = (a.entity(), b.entity());
+                let me = a_entity.as_character().unwrap();
+                let target = b_entity.as_character().unwrap();
+                face_off(&state, me, target).await?;
+                equip(&state, me.character_id(), BOW, Item::RIGHT_HAND, 1)
+                    .await?;
+                let hp = target.hp().current();
+
+                let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+                let now = Instant::now();
+                physical_attack(&state, me, target.id(), now, &mut rng).await?;
+                let told = packets_of::<MsgTalk>(&mut a_rx);
+                assert_eq!(
+                    told[0].message,
+                    AttackRejection::NoArrows.message()
+                );
+                assert!(packets_of::<MsgInteract>(&mut b_rx).is_empty());
+                assert_eq!(target.hp().current(), hp);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn walls_block_the_line_of_sight() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, _)] = actors;
+                let (a_entity, b_entity) = (a.entity(), b.entity());
+                let me = a_entity.as_character().unwrap();
+                let target = b_entity.as_character().unwrap();
+                face_off(&state, me, target).await?;
+                equip(&state, me.character_id(), BOW, Item::RIGHT_HAND, 1)
+                    .await?;
+                equip(&state, me.character_id(), ARROWS, Item::LEFT_HAND, 5)
+                    .await?;
+                let wall = Tile {
+                    access: TileAccess::Terrain,
+                    elevation: 0,
+                };
+                state.try_map(1010)?.set_tile(45, 40, wall);
+
+                let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+                let now = Instant::now();
+                physical_attack(&state, me, target.id(), now, &mut rng).await?;
+                let told = packets_of::<MsgTalk>(&mut a_rx);
+                assert_eq!(
+                    told[0].message,
+                    AttackRejection::OutOfSight.message()
+                );
+                let arrows = Item::equipped(
+                    state.pool(),
+                    me.character_id(),
+                    Item::LEFT_HAND,
+                )
+                .await?
+                .unwrap();
+                assert_eq!(arrows.amount, 5);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn arrows_are_used_up() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, _)] = actors;
+                let (a_entity, b_entity) = (a.entity(), b.entity());
+                let me = a_entity.as_character().unwrap();
+                let target = b_entity.as_character().unwrap();
+                face_off(&state, me, target).await?;
+                equip(&state, me.character_id(), BOW, Item::RIGHT_HAND, 1)
+                    .await?;
+                equip(&state, me.character_id(), ARROWS, Item::LEFT_HAND, 2)
+                    .await?;
+                let pool = state.pool();
+                let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+                let now = Instant::now();
+
+                physical_attack(&state, me, target.id(), now, &mut rng).await?;
+                let arrows =
+                    Item::equipped(pool, me.character_id(), Item::LEFT_HAND)
+                        .await?
+                        .unwrap();
+                assert_eq!(arrows.amount, 1);
+                let shots = packets_of::<MsgInteract>(&mut a_rx);
+                assert_eq!(shots.len(), 1);
+                assert_eq!(
+                    InteractionType::from(shots[0].action),
+                    InteractionType::Shoot
+                );
+
+                // The last arrow goes back to the inventory as an empty pack.
+                physical_attack(
+                    &state,
+                    me,
+                    target.id(),
+                    now + BOW_INTERVAL,
+                    &mut rng,
+                )
+                .await?;
+                let equipped =
+                    Item::equipped(pool, me.character_id(), Item::LEFT_HAND)
+                        .await?;
+                assert!(equipped.is_none());
+                let inventory =
+                    Item::inventory_of(pool, me.character_id()).await?;
+                assert_eq!(inventory.len(), 1);
+                assert_eq!(inventory[0].amount, 0);
+
+                physical_attack(
+                    &state,
+                    me,
+                    target.id(),
+                    now + BOW_INTERVAL * 2,
+                    &mut rng,
+                )
+                .await?;
+                let told = packets_of::<MsgTalk>(&mut a_rx);
+                assert_eq!(
+                    told.last().unwrap().message,
+                    AttackRejection::NoArrows.message()
+                );
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn attacks_follow_the_weapon_speed() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, _)] = actors;
+                let (a_entity, b_entity) = (a.entity(), b.entity());
+                let me = a_entity.as_character().unwrap();
+                let target = b_entity.as_character().unwrap();
+                face_off(&state, me, target).await?;
+                equip(&state, me.character_id(), BOW, Item::RIGHT_HAND, 1)
+                    .await?;
+                equip(&state, me.character_id(), ARROWS, Item::LEFT_HAND, 10)
+                    .await?;
+                let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+                let start = Instant::now();
+
+                // Shooting as fast as the bow allows, give or take the
+                // jitter of the network.
+                let jitter = state.action_jitter() / 2;
+                for at in [start, start + BOW_INTERVAL - jitter] {
+                    physical_attack(&state, me, target.id(), at, &mut rng)
+                        .await?;
+                }
+                assert_eq!(packets_of::<MsgInteract>(&mut a_rx).len(), 2);
+                assert!(packets_of::<MsgTalk>(&mut a_rx).is_empty());
+
+                // Then shooting twice as fast.
+                let next = start + BOW_INTERVAL * 2;
+                for i in 0..4 {
+                    let at = next + BOW_INTERVAL / 2 * i;
+                    physical_attack(&state, me, target.id(), at, &mut rng)
+                        .await?;
+                }
+                assert_eq!(packets_of::<MsgInteract>(&mut a_rx).len(), 2);
+                let at = next + BOW_INTERVAL * 2 - BOW_INTERVAL / 2;
+                physical_attack(&state, me, target.id(), at, &mut rng).await?;
+                let told = packets_of::<MsgTalk>(&mut a_rx);
+                assert_eq!(told[0].message, AttackRejection::TooFast.message());
+                let arrows = Item::equipped(
+                    state.pool(),
+                    me.character_id(),
+                    Item::LEFT_HAND,
+                )
+                .await?
+                .unwrap();
+                assert_eq!(arrows.amount, 6);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn killed_monsters_drop_their_loot() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, _)] = actors;
+                let (a_entity, b_entity) = (a.entity(), b.entity());
+                let me = a_entity.as_character().unwrap();
+                let target = b_entity.as_character().unwrap();
+                face_off(&state, me, target).await?;
+                let map = state.try_map(1010)?;
+                let drops = DropTable::new().with_silver(10..=20, 1);
+                let kind = MonsterType::new(1, "Pheasant").with_drops(drops);
+                let monster = map
+                    .spawn_monster(Arc::new(kind), Location::new(41, 40, 0))
+                    .await?;
+                assert!(map.floor_items().is_empty());
+
+                let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+                let now = Instant::now();
+                physical_attack(&state, me, monster.id(), now, &mut rng)
+                    .await?;
+                let hits = packets_of::<MsgInteract>(&mut a_rx);
+                assert_eq!(hits.len(), 1);
+                assert_eq!(hits[0].target_id, monster.id());
+                assert!(map.monster(monster.id()).is_none());
+                let items = map.floor_items();
+                assert_eq!(items.len(), 1);
+                let item = items[0].as_floor_item().unwrap();
+                assert!((10..=20).contains(&item.money()));
+                assert!(item.is_protected_for(me.id()));
+                let loc = item.location();
+                assert!(loc.x.abs_diff(41) <= 1 && loc.y.abs_diff(40) <= 1);
+
+                // Gone, there is nothing left to hit.
+                let at = now + Duration::from_secs(2);
+                physical_attack(&state, me, monster.id(), at, &mut rng).await?;
+                let told = packets_of::<MsgTalk>(&mut a_rx);
+                assert_eq!(
+                    told.last().unwrap().message,
+                    AttackRejection::TargetNotFound.message()
+                );
+                assert_eq!(map.floor_items().len(), 1);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn pk_could_be_turned_off_on_a_map() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, _)] = actors;
+                let (a_entity, b_entity) = (a.entity(), b.entity());
+                let me = a_entity.as_character().unwrap();
+                let target = b_entity.as_character().unwrap();
+                face_off(&state, me, target).await?;
+                target.entity().set_location(Location::new(41, 40, 0));
+                let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+                let start = Instant::now();
+
+                // Turned off somewhere else, it goes on here.
+                let mut no_pk = MapAttributes::default();
+                no_pk.set("pk", "off")?;
+                state.try_map(1005)?.set_attributes(no_pk).await?;
+                physical_attack(&state, me, target.id(), start, &mut rng)
+                    .await?;
+                assert_eq!(packets_of::<MsgInteract>(&mut a_rx).len(), 1);
+
+                state.try_map(1010)?.set_attributes(no_pk).await?;
+                let hp = target.hp().current();
+                let at = start + Duration::from_secs(2);
+                physical_attack(&state, me, target.id(), at, &mut rng).await?;
+                let told = packets_of::<MsgTalk>(&mut a_rx);
+                assert_eq!(
+                    told[0].message,
+                    AttackRejection::PkDisabled.message()
+                );
+                assert_eq!(target.hp().current(), hp);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
@@ -1,8 +1,9 @@
 use crate::packets::{MsgTalk, TalkChannel};
-use crate::world::Maps;
+use crate::world::{self, Maps};
 use crate::{ActorState, Error};
 use argh::FromArgs;
-use tq_network::Actor;
+use std::time::Duration;
+use tq_network::{Actor, DisconnectReason, DropReason};
 
 pub async fn parse_and_execute(
     state: &crate::State,
@@ -31,17 +32,43 @@ pub async fn parse_and_execute(
             return Ok(());
         },
     };
+    if c.commands.gm_level() > actor.access().gm_level {
+        tracing::warn!(
+            account_id = actor.id(),
+            command = ?c.commands,
+            "Tried to use a command above their GM level"
+        );
+        actor
+            .send(MsgTalk::from_system(
+                me.id(),
+                TalkChannel::System,
+                "You are not allowed to use this command.",
+            ))
+            .await?;
+        return Ok(());
+    }
     match c.commands {
         SubCommands::Dc(_) => {
             actor.shutdown().await?;
             Ok(())
         },
+        SubCommands::Kick(cmd) => {
+            let target =
+                state.character_by_name(&cmd.name).and_then(|e| e.owner());
+            let reply = match target {
+                Some(owner) => {
+                    owner.disconnect(DisconnectReason::Kicked).await?;
+                    format!("{} got kicked.", cmd.name)
+                },
+                None => format!("{} is not online.", cmd.name),
+            };
+            actor
+                .send(MsgTalk::from_system(me.id(), TalkChannel::System, reply))
+                .await?;
+            Ok(())
+        },
         SubCommands::Teleport(info) => {
-            let old_map = state.try_map(me.entity().map_id())?;
-            let map = state.try_map(info.map_id)?;
             me.teleport(state, info.map_id, (info.x, info.y)).await?;
-            map.insert_entity(actor.entity()).await?;
-            old_map.remove_entity(&actor.entity())?;
             if info.all {
                 // TODO: teleport all
             }
@@ -64,6 +91,15 @@ pub async fn parse_and_execute(
             }
             Ok(())
         },
+        SubCommands::ServerInfo(_) => {
+            let now = std::time::Instant::now();
+            let info = super::ServerInfo::gather(state, me, now).await;
+            let lines = info.lines().map(|line| {
+                MsgTalk::from_system(me.id(), TalkChannel::System, line)
+            });
+            actor.send_all(lines).await?;
+            Ok(())
+        },
         SubCommands::JumpBack(_) => {
             me.kick_back().await?;
             Ok(())
@@ -73,6 +109,201 @@ pub async fn parse_and_execute(
             map.change_weather(weather.kind.into()).await?;
             Ok(())
         },
+        SubCommands::MapAttr(cmd) => {
+            let map = state.try_map(me.entity().map_id())?;
+            let reply = if cmd.key == "reset" {
+                map.reset_attributes().await?;
+                world::cancel_attributes_revert(state, map.id());
+                String::from("The map is back to normal.")
+            } else {
+                let value = cmd.value.unwrap_or_default();
+                let mut attributes = map.attributes();
+                match attributes.set(&cmd.key, &value) {
+                    Ok(()) => {
+                        map.set_attributes(attributes).await?;
+                        match cmd.minutes {
+                            Some(minutes) => {
+                                let at = chrono::Local::now().naive_local()
+                                    + chrono::Duration::minutes(minutes.into());
+                                world::schedule_attributes_revert(
+                                    state,
+                                    map.id(),
+                                    at,
+                                );
+                                format!(
+                                    "Map {} set to {value} for {minutes} minutes.",
+                                    cmd.key
+                                )
+                            },
+                            None => format!("Map {} set to {value}.", cmd.key),
+                        }
+                    },
+                    Err(e) => e.to_string(),
+                }
+            };
+            actor
+                .send(MsgTalk::from_system(me.id(), TalkChannel::System, reply))
+                .await?;
+            Ok(())
+        },
+        SubCommands::Allot(_) => {
+            me.grant_allot();
+            actor
+                .send(MsgTalk::from_system(
+                    me.id(),
+                    TalkChannel::System,
+                    "You can now reallocate your attributes once.",
+                ))
+                .await?;
+            Ok(())
+        },
+        SubCommands::Broadcast(cmd) => {
+            let msg =
+                MsgTalk::broadcast(&me.entity().name(), cmd.message.join(" "));
+            state.broadcast(msg).await
+        },
+        SubCommands::Announce(cmd) => {
+            state
+                .broadcast(MsgTalk::announce(cmd.message.join(" ")))
+                .await
+        },
+        SubCommands::GuildWar(cmd) => {
+            let war = state.guild_war();
+            let reply = match cmd.action {
+                GuildWarAction::Start if war.start(state).await? => {
+                    "Guild war started.".to_owned()
+                },
+                GuildWarAction::Stop if war.stop(state).await?.is_some() => {
+                    "Guild war stopped.".to_owned()
+                },
+                GuildWarAction::Start | GuildWarAction::Stop => {
+                    "Nothing to do.".to_owned()
+                },
+                GuildWarAction::State => format!("{:?}", war.state()),
+            };
+            actor
+                .send(MsgTalk::from_system(me.id(), TalkChannel::System, reply))
+                .await?;
+            Ok(())
+        },
+        SubCommands::Drops(DropsCmd {
+            name: Some(name), ..
+        }) => {
+            let target = state.character_by_name(&name).and_then(|e| e.owner());
+            let reply = match target {
+                Some(owner) => {
+                    let reasons = [
+                        DropReason::Decode,
+                        DropReason::UnknownId,
+                        DropReason::RateLimited,
+                    ];
+                    let counts = reasons
+                        .map(|r| format!("{}: {}", r.as_str(), owner.drops(r)))
+                        .join(", ");
+                    format!("{name} {counts}")
+                },
+                None => format!("{name} is not online."),
+            };
+            actor
+                .send(MsgTalk::from_system(me.id(), TalkChannel::System, reply))
+                .await?;
+            Ok(())
+        },
+        SubCommands::Drops(cmd) => {
+            let snapshot = state.invalid_packets().snapshot();
+            if snapshot.is_empty() {
+                let msg = MsgTalk::from_system(
+                    me.id(),
+                    TalkChannel::System,
+                    "No packets were dropped.",
+                );
+                actor.send(msg).await?;
+            }
+            for (ip, reason, count) in snapshot.into_iter().take(cmd.top) {
+                let ip = ip
+                    .map_or_else(|| "unknown".to_owned(), |ip| ip.to_string());
+                let line = format!("{ip} {}: {count}", reason.as_str());
+                actor
+                    .send(MsgTalk::from_system(
+                        me.id(),
+                        TalkChannel::System,
+                        line,
+                    ))
+                    .await?;
+            }
+            Ok(())
+        },
+        SubCommands::Reload(cmd) => {
+            let reply = match cmd.target {
+                ReloadTarget::Scripts => {
+                    let count = state.scripts().reload()?;
+                    format!("Reloaded {count} scripts.")
+                },
+            };
+            actor
+                .send(MsgTalk::from_system(me.id(), TalkChannel::System, reply))
+                .await?;
+            Ok(())
+        },
+        SubCommands::Restart(cmd) => {
+            let reply = match cmd.minutes.as_deref() {
+                Some("cancel") if super::cancel_restart(state) => {
+                    state
+                        .broadcast(MsgTalk::announce(
+                            "The server restart was cancelled.",
+                        ))
+                        .await?;
+                    String::from("Restart cancelled.")
+                },
+                Some("cancel") => String::from("Nothing to cancel."),
+                _ if state.restart().draining() => {
+                    String::from("The server is already restarting.")
+                },
+                minutes => {
+                    let countdown = match minutes {
+                        Some(m) => match m.parse::<u64>() {
+                            Ok(m) => Duration::from_secs(m * 60),
+                            Err(_) => {
+                                let reply = format!("Invalid minutes: {m:?}");
+                                actor
+                                    .send(MsgTalk::from_system(
+                                        me.id(),
+                                        TalkChannel::System,
+                                        reply,
+                                    ))
+                                    .await?;
+                                return Ok(());
+                            },
+                        },
+                        None => state.restart().countdown(),
+                    };
+                    let now = chrono::Local::now().naive_local();
+                    super::start_restart(state, countdown, now).await?;
+                    String::from("Restart started.")
+                },
+            };
+            actor
+                .send(MsgTalk::from_system(me.id(), TalkChannel::System, reply))
+                .await?;
+            Ok(())
+        },
+        SubCommands::LogLevel(cmd) => {
+            // Logged before the change, the new filter could hide it.
+            tracing::warn!(
+                account_id = actor.id(),
+                name = %me.entity().name(),
+                directives = %cmd.directives,
+                "Changing the log filter"
+            );
+            let reply = match state.set_log_filter(&cmd.directives) {
+                Ok(()) => format!("Log filter set to {}.", cmd.directives),
+                Err(e) => e.to_string(),
+            };
+            actor
+                .send(MsgTalk::from_system(me.id(), TalkChannel::System, reply))
+                .await?;
+            Ok(())
+        },
     }
 }
 
@@ -87,10 +318,45 @@ struct Command {
 #[argh(subcommand)]
 enum SubCommands {
     Dc(DcCmd),
+    Kick(KickCmd),
     Which(WhichCmd),
+    ServerInfo(ServerInfoCmd),
     Teleport(TeleportCmd),
     JumpBack(JumpBackCmd),
     Weather(WeatherCmd),
+    MapAttr(MapAttrCmd),
+    Allot(AllotCmd),
+    Broadcast(BroadcastCmd),
+    Announce(AnnounceCmd),
+    GuildWar(GuildWarCmd),
+    Reload(ReloadCmd),
+    Drops(DropsCmd),
+    Restart(RestartCmd),
+    LogLevel(LogLevelCmd),
+}
+
+impl SubCommands {
+    /// The GM level needed to use this command, zero means anyone could.
+    fn gm_level(&self) -> u8 {
+        match self {
+            Self::Dc(_) | Self::Which(_) | Self::ServerInfo(_) => 0,
+            Self::JumpBack(_) | Self::Broadcast(_) => 1,
+            Self::Kick(_) => 2,
+            Self::Teleport(_) | Self::Weather(_) | Self::Allot(_) => 2,
+            Self::Announce(_) | Self::GuildWar(_) | Self::Reload(_) => 3,
+            Self::Drops(_) | Self::MapAttr(_) | Self::Restart(_) => 3,
+            Self::LogLevel(_) => 3,
+        }
+    }
+}
+
+/// Change which logs get through, like tq_network=trace
+#[derive(Debug, Clone, PartialEq, FromArgs)]
+#[argh(subcommand, name = "loglevel")]
+struct LogLevelCmd {
+    /// the directives, in the syntax of RUST_LOG
+    #[argh(positional)]
+    directives: String,
 }
 
 /// Disconnect From Server
@@ -98,6 +364,15 @@ enum SubCommands {
 #[argh(subcommand, name = "dc")]
 struct DcCmd {}
 
+/// Disconnect a player from the server
+#[derive(Debug, Clone, PartialEq, FromArgs)]
+#[argh(subcommand, name = "kick")]
+struct KickCmd {
+    /// the name of the character
+    #[argh(positional)]
+    name: String,
+}
+
 /// Jump Back to prev location
 #[derive(Debug, Clone, PartialEq, FromArgs)]
 #[argh(subcommand, name = "jump-back")]
@@ -112,6 +387,11 @@ struct WhichCmd {
     map: bool,
 }
 
+/// Show the uptime, how many are online and the like
+#[derive(Debug, Clone, PartialEq, FromArgs)]
+#[argh(subcommand, name = "serverinfo")]
+struct ServerInfoCmd {}
+
 /// Teleport to other map at specific location
 #[derive(Debug, Clone, PartialEq, FromArgs)]
 #[argh(subcommand, name = "tele")]
@@ -135,3 +415,116 @@ struct WeatherCmd {
     #[argh(positional)]
     kind: u32,
 }
+
+/// Override an attribute of the current map for an event
+#[derive(Debug, Clone, PartialEq, FromArgs)]
+#[argh(subcommand, name = "mapattr")]
+struct MapAttrCmd {
+    /// pk (on/off), weather (kind), argb (hex), exp (bonus %) or reset
+    #[argh(positional)]
+    key: String,
+    /// the new value
+    #[argh(positional)]
+    value: Option<String>,
+    /// put the map back after that many minutes
+    #[argh(option)]
+    minutes: Option<u32>,
+}
+
+/// Allow reallocating your attributes once
+#[derive(Debug, Clone, PartialEq, FromArgs)]
+#[argh(subcommand, name = "allot")]
+struct AllotCmd {}
+
+/// Send a message to everyone in the world
+#[derive(Debug, Clone, PartialEq, FromArgs)]
+#[argh(subcommand, name = "broadcast")]
+struct BroadcastCmd {
+    #[argh(positional)]
+    message: Vec<String>,
+}
+
+/// Announce something in the center of everyone's screen
+#[derive(Debug, Clone, PartialEq, FromArgs)]
+#[argh(subcommand, name = "announce")]
+struct AnnounceCmd {
+    #[argh(positional)]
+    message: Vec<String>,
+}
+
+/// Start, stop or check the guild war
+#[derive(Debug, Clone, PartialEq, FromArgs)]
+#[argh(subcommand, name = "gw")]
+struct GuildWarCmd {
+    /// start, stop or state
+    #[argh(positional)]
+    action: GuildWarAction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum GuildWarAction {
+    Start,
+    Stop,
+    State,
+}
+
+impl std::str::FromStr for GuildWarAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "start" => Ok(Self::Start),
+            "stop" => Ok(Self::Stop),
+            "state" => Ok(Self::State),
+            _ => {
+                Err(format!("unknown action {s:?}, expected start|stop|state"))
+            },
+        }
+    }
+}
+
+/// Show the addresses that sent the most dropped packets
+#[derive(Debug, Clone, PartialEq, FromArgs)]
+#[argh(subcommand, name = "drops")]
+struct DropsCmd {
+    /// how many to show
+    #[argh(option, default = "10")]
+    top: usize,
+    /// show the packets dropped from that player instead
+    #[argh(option)]
+    name: Option<String>,
+}
+
+/// Reload things from the disk without restarting the server
+#[derive(Debug, Clone, PartialEq, FromArgs)]
+#[argh(subcommand, name = "reload")]
+struct ReloadCmd {
+    /// what to reload, only scripts for now
+    #[argh(positional)]
+    target: ReloadTarget,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ReloadTarget {
+    Scripts,
+}
+
+impl std::str::FromStr for ReloadTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "scripts" => Ok(Self::Scripts),
+            _ => Err(format!("unknown target {s:?}, expected scripts")),
+        }
+    }
+}
+
+/// Drain the server and restart it after a countdown
+#[derive(Debug, Clone, PartialEq, FromArgs)]
+#[argh(subcommand, name = "restart")]
+struct RestartCmd {
+    /// minutes before the restart, or cancel
+    #[argh(positional)]
+    minutes: Option<String>,
+}
@@ -0,0 +1,76 @@
+use super::EffectKind;
+use std::time::Duration;
+
+/// What happens when a consumable item gets used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ItemEffect {
+    /// Restores the given amount of health and mana points.
+    Heal { hp: u16, mp: u16 },
+    /// Puts the character under a status effect for a while, see
+    /// [`StatusEffects`](super::StatusEffects).
+    Buff {
+        kind: EffectKind,
+        power: u16,
+        duration: Duration,
+    },
+}
+
+/// An item that gets used up once the character uses it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Consumable {
+    pub effect: ItemEffect,
+    /// Whether the item could still be used when the effect would do nothing,
+    /// like drinking a health potion at full health, wasting the item.
+    pub waste_when_full: bool,
+}
+
+impl Consumable {
+    const fn heal(hp: u16, mp: u16) -> Self {
+        Self {
+            effect: ItemEffect::Heal { hp, mp },
+            waste_when_full: false,
+        }
+    }
+
+    const fn buff(kind: EffectKind, power: u16, secs: u64) -> Self {
+        Self {
+            effect: ItemEffect::Buff {
+                kind,
+                power,
+                duration: Duration::from_secs(secs),
+            },
+            waste_when_full: false,
+        }
+    }
+
+    /// Returns the consumable of the given item type, or `None` if that item
+    /// could not be used.
+    pub const fn of(item_type: u32) -> Option<Self> {
+        let consumable = match item_type {
+            // Stancher
+            1000000 => Self::heal(70, 0),
+            // Resolutive
+            1000010 => Self::heal(100, 0),
+            // Painkiller
+            1000020 => Self::heal(250, 0),
+            // Amrita
+            1000030 => Self::heal(500, 0),
+            // Panacea
+            1000040 => Self::heal(1000, 0),
+            // Agrypnotic
+            1001000 => Self::heal(0, 70),
+            // Tonic
+            1001010 => Self::heal(0, 200),
+            // Recuperate
+            1001020 => Self::heal(0, 450),
+            // Soul Pill
+            1001030 => Self::heal(0, 1000),
+            // Attack Potion
+            1002030 => Self::buff(EffectKind::AttackBoost, 10, 60),
+            // Defense Potion
+            1002040 => Self::buff(EffectKind::DefenseBoost, 10, 60),
+            _ => return None,
+        };
+        Some(consumable)
+    }
+}
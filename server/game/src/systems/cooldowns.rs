@@ -0,0 +1,116 @@
+use crate::packets::InteractionType;
+use crate::systems::is_bow;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How much sooner than its interval an action could arrive and still be
+/// accepted, to make up for the jitter of the network.
+pub const ACTION_JITTER: Duration = Duration::from_millis(100);
+
+/// The interval between two blows with bare hands or a one handed weapon.
+pub const ONE_HANDED_INTERVAL: Duration = Duration::from_millis(800);
+
+/// The interval between two blows with a two handed weapon.
+pub const TWO_HANDED_INTERVAL: Duration = Duration::from_millis(1200);
+
+/// The interval between two shots with a bow.
+pub const BOW_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// Whether the item type is a weapon held with both hands.
+pub fn is_two_handed(item_type: u32) -> bool {
+    (510..600).contains(&(item_type / 1000)) && !is_bow(item_type)
+}
+
+/// How long a character has to wait between two attacks with that weapon in
+/// its right hand, `None` for bare hands.
+pub fn attack_interval(weapon: Option<u32>) -> Duration {
+    match weapon {
+        Some(item_type) if is_bow(item_type) => BOW_INTERVAL,
+        Some(item_type) if is_two_handed(item_type) => TWO_HANDED_INTERVAL,
+        _ => ONE_HANDED_INTERVAL,
+    }
+}
+
+/// When a character did every kind of action last, to hold back the clients
+/// that act faster than they should.
+#[derive(Debug, Default)]
+pub struct ActionCooldowns {
+    last: Mutex<HashMap<InteractionType, Instant>>,
+}
+
+impl ActionCooldowns {
+    pub fn new() -> Self { Self::default() }
+
+    /// Marks the action done at `now`, unless the last one of that kind was
+    /// less than `interval - jitter` ago, then returns `false` and nothing
+    /// changes.
+    pub fn try_act(
+        &self,
+        action: InteractionType,
+        interval: Duration,
+        jitter: Duration,
+        now: Instant,
+    ) -> bool {
+        let mut last = self.last.lock();
+        let min = interval.saturating_sub(jitter);
+        match last.get(&action) {
+            Some(&t) if now.saturating_duration_since(t) < min => false,
+            _ => {
+                last.insert(action, now);
+                true
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intervals_follow_the_weapon() {
+        assert_eq!(attack_interval(None), ONE_HANDED_INTERVAL);
+        assert_eq!(attack_interval(Some(410_005)), ONE_HANDED_INTERVAL);
+        assert_eq!(attack_interval(Some(560_005)), TWO_HANDED_INTERVAL);
+        assert_eq!(attack_interval(Some(500_005)), BOW_INTERVAL);
+    }
+
+    #[test]
+    fn paced_actions_are_accepted() {
+        let cooldowns = ActionCooldowns::new();
+        let interval = ONE_HANDED_INTERVAL;
+        let start = Instant::now();
+        let attack = InteractionType::Attack;
+        assert!(cooldowns.try_act(attack, interval, ACTION_JITTER, start));
+        // The second one is a little early, it got held up less than the
+        // first one on its way.
+        let early = start + interval - ACTION_JITTER / 2;
+        assert!(cooldowns.try_act(attack, interval, ACTION_JITTER, early));
+        for i in 1..5 {
+            let now = early + interval * i;
+            assert!(cooldowns.try_act(attack, interval, ACTION_JITTER, now));
+        }
+    }
+
+    #[test]
+    fn fast_actions_are_throttled() {
+        let cooldowns = ActionCooldowns::new();
+        let interval = TWO_HANDED_INTERVAL;
+        let start = Instant::now();
+        let attack = InteractionType::Attack;
+        assert!(cooldowns.try_act(attack, interval, ACTION_JITTER, start));
+        let mut throttled = 0;
+        for i in 1..10 {
+            let now = start + interval / 4 * i;
+            if !cooldowns.try_act(attack, interval, ACTION_JITTER, now) {
+                throttled += 1;
+            }
+        }
+        // Only every fourth one made it through.
+        assert_eq!(throttled, 7);
+        // Other kinds of actions are kept apart.
+        let shoot = InteractionType::Shoot;
+        assert!(cooldowns.try_act(shoot, interval, ACTION_JITTER, start));
+    }
+}
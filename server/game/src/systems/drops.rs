@@ -0,0 +1,184 @@
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::Rng;
+use std::ops::RangeInclusive;
+
+/// A single entry in a [`DropTable`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DropEntry {
+    /// Drops the item with the given item type.
+    Item(u32),
+    /// Drops a random amount of silver in that range.
+    Silver(RangeInclusive<u32>),
+}
+
+/// The result of rolling a [`DropTable`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drop {
+    Item(u32),
+    Silver(u32),
+}
+
+/// A weighted list of items and silver ranges that could be dropped when a
+/// monster gets killed.
+///
+/// Every roll draws `rolls` times from the table, each draw picks one entry
+/// (or nothing) with a probability proportional to its weight.
+#[derive(Debug, Clone)]
+pub struct DropTable {
+    entries: Vec<(DropEntry, u32)>,
+    /// The weight of not dropping anything.
+    nothing: u32,
+    /// How many times we draw from the table on every roll.
+    rolls: u8,
+}
+
+impl Default for DropTable {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+            nothing: 0,
+            rolls: 1,
+        }
+    }
+}
+
+impl DropTable {
+    pub fn new() -> Self { Self::default() }
+
+    /// Adds an item with the given weight to the table.
+    pub fn with_item(mut self, item_type: u32, weight: u32) -> Self {
+        self.entries.push((DropEntry::Item(item_type), weight));
+        self
+    }
+
+    /// Adds a silver range with the given weight to the table.
+    pub fn with_silver(
+        mut self,
+        amount: RangeInclusive<u32>,
+        weight: u32,
+    ) -> Self {
+        self.entries.push((DropEntry::Silver(amount), weight));
+        self
+    }
+
+    /// Sets the weight of dropping nothing at all.
+    pub fn with_nothing(mut self, weight: u32) -> Self {
+        self.nothing = weight;
+        self
+    }
+
+    /// Sets how many draws happen on every roll.
+    pub fn with_rolls(mut self, rolls: u8) -> Self {
+        self.rolls = rolls;
+        self
+    }
+
+    pub fn entries(&self) -> &[(DropEntry, u32)] { &self.entries }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(|(_, weight)| *weight == 0)
+    }
+
+    /// Rolls the table using the given random number generator.
+    ///
+    /// The result only depends on the state of `rng`, so using a seeded
+    /// generator always produces the same drops.
+    pub fn roll<R: Rng + ?Sized>(&self, rng: &mut R) -> Vec<Drop> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        // The last index is reserved for the "nothing" entry.
+        let weights = self
+            .entries
+            .iter()
+            .map(|(_, weight)| *weight)
+            .chain(std::iter::once(self.nothing));
+        let dist = match WeightedIndex::new(weights) {
+            Ok(dist) => dist,
+            Err(e) => {
+                tracing::warn!(error = ?e, "Invalid drop table weights");
+                return Vec::new();
+            },
+        };
+        let mut drops = Vec::with_capacity(self.rolls as usize);
+        for _ in 0..self.rolls {
+            let Some((entry, _)) = self.entries.get(dist.sample(rng)) else {
+                continue;
+            };
+            let drop = match entry {
+                DropEntry::Item(item_type) => Drop::Item(*item_type),
+                DropEntry::Silver(range) => {
+                    Drop::Silver(rng.gen_range(range.clone()))
+                },
+            };
+            drops.push(drop);
+        }
+        drops
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn empty_table_drops_nothing() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let table = DropTable::new().with_rolls(5);
+        for _ in 0..100 {
+            assert!(table.roll(&mut rng).is_empty());
+        }
+    }
+
+    #[test]
+    fn same_seed_same_drops() {
+        let table = DropTable::new()
+            .with_item(1, 1)
+            .with_item(2, 1)
+            .with_silver(10..=100, 1)
+            .with_rolls(3);
+        let a: Vec<_> = {
+            let mut rng = StdRng::seed_from_u64(7);
+            (0..50).map(|_| table.roll(&mut rng)).collect()
+        };
+        let b: Vec<_> = {
+            let mut rng = StdRng::seed_from_u64(7);
+            (0..50).map(|_| table.roll(&mut rng)).collect()
+        };
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn distribution_matches_weights() {
+        const ROLLS: usize = 100_000;
+        let mut rng = StdRng::seed_from_u64(42);
+        let table = DropTable::new()
+            .with_item(1, 1)
+            .with_item(2, 3)
+            .with_silver(1..=10, 2)
+            .with_nothing(4);
+        let mut counts = [0usize; 4];
+        for _ in 0..ROLLS {
+            match table.roll(&mut rng).as_slice() {
+                [Drop::Item(1)] => counts[0] += 1,
+                [Drop::Item(2)] => counts[1] += 1,
+                [Drop::Silver(amount)] => {
+                    assert!((1..=10).contains(amount));
+                    counts[2] += 1;
+                },
+                [] => counts[3] += 1,
+                other => panic!("unexpected drops: {other:?}"),
+            }
+        }
+        let expected = [0.1, 0.3, 0.2, 0.4];
+        for (count, expected) in counts.iter().zip(expected) {
+            let ratio = *count as f64 / ROLLS as f64;
+            assert!(
+                (ratio - expected).abs() < 0.01,
+                "expected {expected} got {ratio}"
+            );
+        }
+    }
+}
@@ -0,0 +1,271 @@
+//! The enemies list of a character, the last players who killed it, kept in
+//! the database and shown next to the friends list in the client.
+
+use crate::entities::Character;
+use crate::packets::{FriendAction, MsgFriend};
+use crate::systems::fan_out;
+use crate::{constants, Error, State};
+use tq_db::enemy::Enemy;
+
+/// How many enemies a character keeps, the oldest ones are dropped to make
+/// room for the new ones.
+pub const ENEMY_CAP: i64 = 10;
+
+/// The id the client knows the character by.
+fn entity_id(character_id: i32) -> u32 {
+    character_id as u32 + constants::CHARACTER_ID_MIN
+}
+
+/// Records `killer` as the most recent enemy of `victim`, and tells the
+/// victim about it along with whoever got dropped from its list.
+#[tracing::instrument(skip_all, fields(victim = victim.id(), killer = killer.id()))]
+pub async fn record_enemy(
+    state: &State,
+    victim: &Character,
+    killer: &Character,
+    now: i64,
+) -> Result<(), Error> {
+    let name = killer.entity().name();
+    let enemy = Enemy {
+        character_id: victim.character_id(),
+        enemy_id: killer.character_id(),
+        enemy_name: name.to_string(),
+        killed_at: now,
+    };
+    let evicted = enemy.record(state.pool(), ENEMY_CAP).await?;
+    let mut packets: Vec<_> = evicted
+        .into_iter()
+        .map(|id| {
+            MsgFriend::new(FriendAction::RemoveEnemy, entity_id(id), "", false)
+        })
+        .collect();
+    packets.push(MsgFriend::new(
+        FriendAction::AddEnemy,
+        killer.id(),
+        &name,
+        true,
+    ));
+    victim.owner().send_all(packets).await?;
+    Ok(())
+}
+
+/// Sends the character its enemies list, each one marked as online or not,
+/// and returns how many there were.
+pub async fn send_enemies(
+    state: &State,
+    me: &Character,
+) -> Result<usize, Error> {
+    let enemies = Enemy::of_character(state.pool(), me.character_id()).await?;
+    let packets: Vec<_> = enemies
+        .iter()
+        .map(|enemy| {
+            let id = entity_id(enemy.enemy_id);
+            let online = state.entity(id).is_some();
+            MsgFriend::new(
+                FriendAction::AddEnemy,
+                id,
+                &enemy.enemy_name,
+                online,
+            )
+        })
+        .collect();
+    let count = packets.len();
+    me.owner().send_all(packets).await?;
+    Ok(count)
+}
+
+/// Drops the character with the given id from the enemies list, the client
+/// gets the removal echoed back once it is gone.
+pub async fn remove_enemy(
+    state: &State,
+    me: &Character,
+    enemy: u32,
+) -> Result<(), Error> {
+    if !constants::is_character(enemy) {
+        return Ok(());
+    }
+    let enemy_id = (enemy - constants::CHARACTER_ID_MIN) as i32;
+    if Enemy::remove(state.pool(), me.character_id(), enemy_id).await? {
+        let msg = MsgFriend::new(FriendAction::RemoveEnemy, enemy, "", false);
+        me.owner().send(msg).await?;
+    }
+    Ok(())
+}
+
+/// Tells everyone online that has the character as an enemy that it went
+/// online or offline.
+pub async fn notify_enemy_status(
+    state: &State,
+    me: &Character,
+    online: bool,
+) -> Result<(), Error> {
+    let action = if online {
+        FriendAction::SetOnlineEnemy
+    } else {
+        FriendAction::SetOfflineEnemy
+    };
+    let name = me.entity().name();
+    let msg = MsgFriend::new(action, me.id(), &name, online);
+    let holders = Enemy::held_by(state.pool(), me.character_id()).await?;
+    let sends = holders.into_iter().filter_map(|id| {
+        let entity = state.entity(entity_id(id))?;
+        let owner = entity.as_character()?.owner();
+        let msg = msg.clone();
+        Some((entity.id(), async move { owner.try_send(msg).await }))
+    });
+    fan_out(sends).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::{ActionType, MsgAction};
+    use crate::test_utils::*;
+    use futures::FutureExt;
+    use tokio::sync::mpsc::Receiver;
+    use tq_network::{Message, PacketDecode, PacketID, PacketProcess};
+
+    /// The friend packets sent to the actor, leaving out anything else.
+    fn friends(rx: &mut Receiver<Message>) -> Vec<(FriendAction, u32, bool)> {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .filter_map(|msg| match msg {
+                Message::Packet(MsgFriend::PACKET_ID, bytes) => {
+                    let msg = MsgFriend::decode(&bytes).ok()?;
+                    Some((msg.action.into(), msg.id, msg.online))
+                },
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn the_oldest_enemy_is_dropped() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let mut killers = Vec::new();
+                for id in 3..=(ENEMY_CAP as usize + 3) {
+                    let (killer, _) =
+                        make_extra_actor(&state, &format!("killer{id}"))
+                            .await?;
+                    killers.push(killer.entity());
+                }
+                for (now, killer) in killers.iter().enumerate() {
+                    let killer = killer.as_character().unwrap();
+                    record_enemy(&state, me, killer, now as i64).await?;
+                }
+                let first = killers[0].id();
+                let last = killers.last().unwrap().id();
+                let received = friends(&mut a_rx);
+                assert_eq!(received.len(), killers.len() + 1);
+                assert_eq!(
+                    received[ENEMY_CAP as usize..],
+                    [
+                        (FriendAction::RemoveEnemy, first, false),
+                        (FriendAction::AddEnemy, last, true),
+                    ]
+                );
+
+                let enemies =
+                    Enemy::of_character(state.pool(), me.character_id())
+                        .await?;
+                assert_eq!(enemies.len(), ENEMY_CAP as usize);
+                assert_eq!(entity_id(enemies[0].enemy_id), last);
+                assert!(enemies.iter().all(|e| entity_id(e.enemy_id) != first));
+
+                // Killing again makes it the most recent, not a new one.
+                let again = killers[1].as_character().unwrap();
+                record_enemy(&state, me, again, 100).await?;
+                let enemies =
+                    Enemy::of_character(state.pool(), me.character_id())
+                        .await?;
+                assert_eq!(enemies.len(), ENEMY_CAP as usize);
+                assert_eq!(entity_id(enemies[0].enemy_id), again.id());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn removed_enemies_stay_removed() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, _)] = actors;
+                let (a_entity, b_entity) = (a.entity(), b.entity());
+                let me = a_entity.as_character().unwrap();
+                let killer = b_entity.as_character().unwrap();
+                record_enemy(&state, me, killer, 1).await?;
+                friends(&mut a_rx);
+
+                let msg = MsgFriend::new(
+                    FriendAction::RemoveEnemy,
+                    killer.id(),
+                    "",
+                    false,
+                );
+                msg.process(&state, &a).await?;
+                assert_eq!(
+                    friends(&mut a_rx),
+                    [(FriendAction::RemoveEnemy, killer.id(), false)]
+                );
+                let enemies =
+                    Enemy::of_character(state.pool(), me.character_id())
+                        .await?;
+                assert!(enemies.is_empty());
+                // Nothing to echo once it is gone.
+                msg.process(&state, &a).await?;
+                assert!(friends(&mut a_rx).is_empty());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn enemies_are_sent_at_login() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, _)] = actors;
+                let (a_entity, b_entity) = (a.entity(), b.entity());
+                let me = a_entity.as_character().unwrap();
+                let killer = b_entity.as_character().unwrap();
+                record_enemy(&state, me, killer, 1).await?;
+                friends(&mut a_rx);
+
+                let associates = MsgAction::new(
+                    me.id(),
+                    0,
+                    0,
+                    0,
+                    ActionType::SendAssociates,
+                );
+                associates.process(&state, &a).await?;
+                assert_eq!(
+                    friends(&mut a_rx),
+                    [(FriendAction::AddEnemy, killer.id(), true)]
+                );
+
+                // The enemy going offline is told like a friend would be.
+                killer.leave_world(&state).await?;
+                assert_eq!(
+                    friends(&mut a_rx),
+                    [(FriendAction::SetOfflineEnemy, killer.id(), false)]
+                );
+                associates.process(&state, &a).await?;
+                assert_eq!(
+                    friends(&mut a_rx),
+                    [(FriendAction::AddEnemy, killer.id(), false)]
+                );
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
@@ -0,0 +1,496 @@
+//! Putting on and taking off equipment, with the slot rules: a weapon held
+//! with both hands leaves no room for a shield, and the other way around.
+
+use crate::entities::Character;
+use crate::packets::{MsgItem, MsgTalk, TalkChannel};
+use crate::systems::{could_wear, is_arrow, is_bow, is_two_handed};
+use crate::{constants, Error, State};
+use tq_db::item::Item;
+
+/// How much attack each plus level of a weapon adds, until the item stats
+/// get loaded from the client data.
+pub const ATTACK_PER_PLUS: u32 = 10;
+
+/// Why equipping or unequipping an item was refused, nothing moves when it
+/// is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EquipRejection {
+    NotInInventory,
+    NotEquipped,
+    CannotWear,
+    /// The client asked for a slot the item never goes to.
+    WrongSlot,
+    NoBow,
+    InventoryFull,
+}
+
+impl EquipRejection {
+    /// What the player gets told.
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::NotInInventory | Self::NotEquipped => "Item not found.",
+            Self::CannotWear => "You can not wear this item.",
+            Self::WrongSlot => "You can not wear this item there.",
+            Self::NoBow => "Arrows could only be equipped along with a bow.",
+            Self::InventoryFull => "Your inventory is full.",
+        }
+    }
+}
+
+/// The equipment slot the item goes to, `None` if it could not be worn.
+pub fn slot_of(item_type: u32) -> Option<i16> {
+    let slot = match item_type / 10_000 {
+        // Helmets, earrings and caps.
+        11 => 1,
+        // Necklaces and bags.
+        12 => 2,
+        13 => 3,
+        // Rings and bracelets.
+        15 => Item::RING,
+        16 => 8,
+        18 => 9,
+        210 => 7,
+        40..=59 => Item::RIGHT_HAND,
+        90 | 105 => Item::LEFT_HAND,
+        _ => return None,
+    };
+    Some(slot)
+}
+
+/// The slots the item could be equipped at by a character of `class`,
+/// worked out from its type alone, its own slot first. Empty if it could not
+/// be worn.
+///
+/// Rings go on either hand, and warriors could hold a one handed weapon in
+/// each.
+pub fn legal_slots(item_type: u32, class: u8) -> Vec<i16> {
+    let Some(slot) = slot_of(item_type) else {
+        return Vec::new();
+    };
+    if slot == Item::RING {
+        vec![slot, Item::LEFT_RING]
+    } else if is_one_handed(item_type) && is_warrior(class) {
+        vec![slot, Item::LEFT_HAND]
+    } else {
+        vec![slot]
+    }
+}
+
+/// Whether the class is a warrior, or any of its promotions.
+fn is_warrior(class: u8) -> bool { matches!(class, 20..=29) }
+
+/// Whether the item type is a weapon held with one hand, which warriors
+/// could hold in either hand.
+pub fn is_one_handed(item_type: u32) -> bool { item_type / 100_000 == 4 }
+
+/// Whether a weapon of that type leaves the left hand free for nothing but
+/// arrows.
+fn takes_both_hands(item_type: u32) -> bool {
+    is_two_handed(item_type) || is_bow(item_type)
+}
+
+/// Where the item goes when equipped at `position`, zero for its own slot,
+/// and which of the `equipment` have to make room for it.
+pub fn plan_equip(
+    item_type: u32,
+    position: i16,
+    class: u8,
+    equipment: &[Item],
+) -> Result<(i16, Vec<Item>), EquipRejection> {
+    let slots = legal_slots(item_type, class);
+    let slot = *slots.first().ok_or(EquipRejection::CannotWear)?;
+    // The client only gets to pick among the slots the item could go to.
+    let position = match position {
+        Item::INVENTORY => slot,
+        p => p,
+    };
+    if !slots.contains(&position) {
+        return Err(EquipRejection::WrongSlot);
+    }
+    if !could_wear(item_type, class) {
+        return Err(EquipRejection::CannotWear);
+    }
+    let in_slot = |position| equipment.iter().find(|i| i.position == position);
+    let right = in_slot(Item::RIGHT_HAND).map(|i| i.item_type as u32);
+    let left = in_slot(Item::LEFT_HAND).map(|i| i.item_type as u32);
+    if is_arrow(item_type) && !right.is_some_and(is_bow) {
+        return Err(EquipRejection::NoBow);
+    }
+    let mut displaced: Vec<_> = in_slot(position).into_iter().collect();
+    if position == Item::RIGHT_HAND {
+        let keeps_left = match left {
+            Some(left) if is_arrow(left) => is_bow(item_type),
+            Some(_) => !takes_both_hands(item_type),
+            None => true,
+        };
+        if !keeps_left {
+            displaced.extend(in_slot(Item::LEFT_HAND));
+        }
+    } else if position == Item::LEFT_HAND
+        && !is_arrow(item_type)
+        && right.is_some_and(takes_both_hands)
+    {
+        displaced.extend(in_slot(Item::RIGHT_HAND));
+    }
+    Ok((position, displaced.into_iter().cloned().collect()))
+}
+
+/// Equips the item `item_id` from the inventory at `position`, zero for its
+/// own slot, moving whatever has to make room for it back to the inventory.
+#[tracing::instrument(skip(state, me), fields(me = me.id()))]
+pub async fn equip(
+    state: &State,
+    me: &Character,
+    item_id: u32,
+    position: i16,
+) -> Result<(), Error> {
+    let pool = state.pool();
+    let item = Item::of_character(pool, item_id as i32, me.character_id())
+        .await?
+        .filter(|item| item.position == Item::INVENTORY);
+    let Some(mut item) = item else {
+        return tell(me, EquipRejection::NotInInventory).await;
+    };
+    let equipment = Item::equipment_of(pool, me.character_id()).await?;
+    let planned = plan_equip(
+        item.item_type as u32,
+        position,
+        me.current_class(),
+        &equipment,
+    );
+    let (position, displaced) = match planned {
+        Ok(planned) => planned,
+        Err(EquipRejection::WrongSlot) => {
+            // The client never offers such a slot on its own.
+            state.audit().possible_cheat(
+                me.character_id(),
+                "equip_position",
+                format!("item type {} at position {position}", item.item_type),
+            );
+            return tell(me, EquipRejection::WrongSlot).await;
+        },
+        Err(rejection) => return tell(me, rejection).await,
+    };
+    // The item leaves the inventory as the displaced ones come in.
+    let in_inventory = Item::inventory_of(pool, me.character_id()).await?.len();
+    if in_inventory - 1 + displaced.len() > constants::INVENTORY_SIZE {
+        return tell(me, EquipRejection::InventoryFull).await;
+    }
+    let ids: Vec<_> = displaced.iter().map(|item| item.item_id).collect();
+    // Someone else could have moved it in the meantime.
+    if !item.equip(pool, position, &ids).await? {
+        return tell(me, EquipRejection::NotInInventory).await;
+    }
+    let mut msgs: Vec<_> = displaced
+        .iter()
+        .map(|d| MsgItem::unequip(me.id(), d, d.position))
+        .collect();
+    msgs.push(MsgItem::equip(me.id(), &item, position));
+    me.owner().send_all(msgs).await?;
+    refresh_equipment_stats(state, me).await
+}
+
+/// Moves the equipped item `item_id` back to the inventory.
+#[tracing::instrument(skip(state, me), fields(me = me.id()))]
+pub async fn unequip(
+    state: &State,
+    me: &Character,
+    item_id: u32,
+) -> Result<(), Error> {
+    let pool = state.pool();
+    let item = Item::of_character(pool, item_id as i32, me.character_id())
+        .await?
+        .filter(|item| Item::EQUIPMENT.contains(&item.position));
+    let Some(mut item) = item else {
+        return tell(me, EquipRejection::NotEquipped).await;
+    };
+    let in_inventory = Item::inventory_of(pool, me.character_id()).await?.len();
+    if in_inventory >= constants::INVENTORY_SIZE {
+        return tell(me, EquipRejection::InventoryFull).await;
+    }
+    let position = item.position;
+    if !item.unequip(pool).await? {
+        return tell(me, EquipRejection::NotEquipped).await;
+    }
+    let msg = MsgItem::unequip(me.id(), &item, position);
+    me.owner().send(msg).await?;
+    refresh_equipment_stats(state, me).await
+}
+
+/// Works out again what the equipment adds to the stats of the character,
+/// after it changed or on login.
+pub async fn refresh_equipment_stats(
+    state: &State,
+    me: &Character,
+) -> Result<(), Error> {
+    let equipment = Item::equipment_of(state.pool(), me.character_id()).await?;
+    let attack = equipment
+        .iter()
+        .filter(|item| {
+            matches!(item.position, Item::RIGHT_HAND | Item::LEFT_HAND)
+        })
+        .filter(|item| (4..=5).contains(&(item.item_type / 100_000)))
+        .map(|item| item.plus as u32 * ATTACK_PER_PLUS)
+        .sum();
+    me.set_equipment_attack(attack);
+    Ok(())
+}
+
+async fn tell(me: &Character, rejection: EquipRejection) -> Result<(), Error> {
+    let msg = MsgTalk::from_system(
+        me.id(),
+        TalkChannel::TopLeft,
+        rejection.message(),
+    );
+    me.owner().send(msg).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use futures::FutureExt;
+
+    const BLADE: i32 = 410005;
+    const GLAIVE: i32 = 510005;
+    const SHIELD: i32 = 900005;
+    const BOW: i32 = 500005;
+    const ARROWS: i32 = 1050000;
+    const RING: i32 = 150005;
+
+    async fn give(
+        state: &State,
+        character_id: i32,
+        item_type: i32,
+        position: i16,
+        plus: i16,
+    ) -> Result<u32, Error> {
+        let (item_id,) = sqlx::query_as::<_, (i32,)>(
+            "INSERT INTO items (character_id, item_type, position, plus) VALUES (?, ?, ?, ?) RETURNING item_id;",
+        )
+        .bind(character_id)
+        .bind(item_type)
+        .bind(position)
+        .bind(plus)
+        .fetch_one(state.pool())
+        .await?;
+        Ok(item_id as u32)
+    }
+
+    fn equipped(item_type: i32, position: i16) -> Item {
+        Item {
+            item_type,
+            position,
+            ..Default::default()
+        }
+    }
+
+    fn displaced(
+        item_type: i32,
+        position: i16,
+        class: u8,
+        equipment: &[Item],
+    ) -> Result<Vec<i32>, EquipRejection> {
+        let (_, displaced) =
+            plan_equip(item_type as u32, position, class, equipment)?;
+        Ok(displaced.iter().map(|item| item.item_type).collect())
+    }
+
+    #[test]
+    fn hands_make_room_for_each_other() {
+        const WARRIOR: u8 = 21;
+        let shield = [equipped(SHIELD, Item::LEFT_HAND)];
+        assert_eq!(displaced(GLAIVE, 0, WARRIOR, &shield), Ok(vec![SHIELD]));
+        // One hand for each.
+        assert_eq!(displaced(BLADE, 0, WARRIOR, &shield), Ok(vec![]));
+        let glaive = [equipped(GLAIVE, Item::RIGHT_HAND)];
+        assert_eq!(displaced(SHIELD, 0, WARRIOR, &glaive), Ok(vec![GLAIVE]));
+        assert_eq!(
+            displaced(BLADE, Item::LEFT_HAND, WARRIOR, &glaive),
+            Ok(vec![GLAIVE])
+        );
+        assert_eq!(
+            displaced(SHIELD, Item::RIGHT_HAND, WARRIOR, &[]),
+            Err(EquipRejection::WrongSlot)
+        );
+
+        const ARCHER: u8 = 41;
+        let bow = [
+            equipped(BOW, Item::RIGHT_HAND),
+            equipped(ARROWS, Item::LEFT_HAND),
+        ];
+        assert_eq!(displaced(BOW, 0, ARCHER, &bow), Ok(vec![BOW]));
+        assert_eq!(displaced(BLADE, 0, ARCHER, &bow), Ok(vec![BOW, ARROWS]));
+        assert_eq!(
+            displaced(ARROWS, 0, ARCHER, &[]),
+            Err(EquipRejection::NoBow)
+        );
+    }
+
+    #[tokio::test]
+    async fn two_handed_weapon_takes_the_shield_off() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let id = me.character_id();
+                let shield =
+                    give(&state, id, SHIELD, Item::LEFT_HAND, 0).await?;
+                let glaive =
+                    give(&state, id, GLAIVE, Item::INVENTORY, 3).await?;
+
+                equip(&state, me, glaive, 0).await?;
+                let pool = state.pool();
+                let right = Item::equipped(pool, id, Item::RIGHT_HAND).await?;
+                assert_eq!(right.map(|i| i.item_id as u32), Some(glaive));
+                assert!(Item::equipped(pool, id, Item::LEFT_HAND)
+                    .await?
+                    .is_none());
+                let inventory = Item::inventory_of(pool, id).await?;
+                let ids: Vec<_> =
+                    inventory.iter().map(|i| i.item_id as u32).collect();
+                assert_eq!(ids, [shield]);
+                assert_eq!(me.equipment_attack(), 3 * ATTACK_PER_PLUS);
+
+                unequip(&state, me, glaive).await?;
+                assert_eq!(Item::inventory_of(pool, id).await?.len(), 2);
+                assert_eq!(me.equipment_attack(), 0);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn displaced_items_need_room_in_the_inventory() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let id = me.character_id();
+                give(&state, id, BLADE, Item::RIGHT_HAND, 0).await?;
+                give(&state, id, SHIELD, Item::LEFT_HAND, 0).await?;
+                let glaive =
+                    give(&state, id, GLAIVE, Item::INVENTORY, 0).await?;
+                for _ in 1..constants::INVENTORY_SIZE {
+                    give(&state, id, BLADE, Item::INVENTORY, 0).await?;
+                }
+
+                // Taking the glaive out leaves room for one of the two.
+                equip(&state, me, glaive, 0).await?;
+                let pool = state.pool();
+                let equipment = Item::equipment_of(pool, id).await?;
+                let mut types: Vec<_> =
+                    equipment.iter().map(|i| i.item_type).collect();
+                types.sort();
+                assert_eq!(types, [BLADE, SHIELD]);
+                assert_eq!(
+                    Item::inventory_of(pool, id).await?.len(),
+                    constants::INVENTORY_SIZE
+                );
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn claimed_positions_are_checked() -> Result<(), Error> {
+        use crate::packets::MsgItem;
+        use tq_db::cheat_log::CheatLog;
+        use tq_network::{Message, PacketDecode, PacketID, PacketProcess};
+
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let id = me.character_id();
+                let pool = state.pool();
+                let blade = give(&state, id, BLADE, Item::INVENTORY, 0).await?;
+                let shield =
+                    give(&state, id, SHIELD, Item::INVENTORY, 0).await?;
+                // A weapon as an armor, and a shield in the weapon hand.
+                for (item_id, position) in
+                    [(blade, 3), (shield, Item::RIGHT_HAND)]
+                {
+                    let item = Item::of_character(pool, item_id as i32, id)
+                        .await?
+                        .unwrap();
+                    MsgItem::equip(me.id(), &item, position)
+                        .process(&state, &a)
+                        .await?;
+                }
+                let told: Vec<_> = std::iter::from_fn(|| a_rx.try_recv().ok())
+                    .filter_map(|msg| match msg {
+                        Message::Packet(MsgTalk::PACKET_ID, bytes) => {
+                            MsgTalk::decode(&bytes).ok().map(|m| m.message)
+                        },
+                        _ => None,
+                    })
+                    .collect();
+                assert_eq!(told, [EquipRejection::WrongSlot.message(); 2]);
+                assert!(Item::equipment_of(pool, id).await?.is_empty());
+                state.audit().flush().await;
+                let caught = CheatLog::of_character(pool, id).await?;
+                let caught: Vec<_> = caught
+                    .iter()
+                    .map(|log| (log.kind.as_str(), log.detail.as_str()))
+                    .collect();
+                assert_eq!(
+                    caught,
+                    [
+                        ("equip_position", "item type 410005 at position 3"),
+                        ("equip_position", "item type 900005 at position 4"),
+                    ]
+                );
+
+                // Only warriors hold a one handed weapon in the other hand.
+                let (state, a) = (&state, &a);
+                let equip_at = |item_id: u32, position| async move {
+                    let item = Item::of_character(pool, item_id as i32, id)
+                        .await?
+                        .unwrap();
+                    MsgItem::equip(me.id(), &item, position)
+                        .process(state, a)
+                        .await
+                };
+                equip_at(blade, Item::LEFT_HAND).await?;
+                assert!(Item::equipment_of(pool, id).await?.is_empty());
+                // As if it was reborn a warrior.
+                let mut warrior = me.snapshot();
+                warrior.current_class = 21;
+                me.apply_rebirth(&warrior);
+                equip_at(blade, Item::LEFT_HAND).await?;
+                let left = Item::equipped(pool, id, Item::LEFT_HAND).await?;
+                assert_eq!(left.map(|i| i.item_id as u32), Some(blade));
+
+                // Rings go on either hand.
+                let rings = [
+                    give(state, id, RING, Item::INVENTORY, 0).await?,
+                    give(state, id, RING, Item::INVENTORY, 0).await?,
+                ];
+                equip_at(rings[0], Item::LEFT_RING).await?;
+                equip_at(rings[1], Item::INVENTORY).await?;
+                for (ring, position) in
+                    rings.into_iter().zip([Item::LEFT_RING, Item::RING])
+                {
+                    let worn = Item::equipped(pool, id, position).await?;
+                    assert_eq!(worn.map(|i| i.item_id as u32), Some(ring));
+                }
+                state.audit().flush().await;
+                let caught = CheatLog::of_character(pool, id).await?;
+                assert_eq!(caught.len(), 3);
+                assert_eq!(caught[2].detail, "item type 410005 at position 5");
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
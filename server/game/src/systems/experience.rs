@@ -0,0 +1,59 @@
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long experience gains get batched by default before the client gets
+/// told about them.
+pub const EXPERIENCE_WINDOW: Duration = Duration::from_millis(500);
+
+/// Tracks experience that was gained but not sent to the client yet, so a
+/// burst of small gains, like while killing a pack of monsters, ends up as a
+/// single update instead of one for every kill.
+#[derive(Debug, Default)]
+pub struct ExperienceBatch {
+    /// When the oldest unsent gain happened.
+    since: Mutex<Option<Instant>>,
+}
+
+impl ExperienceBatch {
+    pub fn new() -> Self { Self::default() }
+
+    /// Records a gain that happened at `now`.
+    pub fn mark(&self, now: Instant) { self.since.lock().get_or_insert(now); }
+
+    /// Whether there is anything unsent.
+    pub fn is_pending(&self) -> bool { self.since.lock().is_some() }
+
+    /// Clears the batch, returns `true` if there was anything unsent.
+    pub fn clear(&self) -> bool { self.since.lock().take().is_some() }
+
+    /// Clears the batch if its window is over by `now`, returns `true` if it
+    /// should be flushed.
+    pub fn take_due(&self, now: Instant, window: Duration) -> bool {
+        let mut since = self.since.lock();
+        match *since {
+            Some(t) if now.saturating_duration_since(t) >= window => {
+                *since = None;
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_is_due_after_window() {
+        let batch = ExperienceBatch::new();
+        let now = Instant::now();
+        assert!(!batch.take_due(now + EXPERIENCE_WINDOW, EXPERIENCE_WINDOW));
+        batch.mark(now);
+        // Later gains do not push the flush back.
+        batch.mark(now + EXPERIENCE_WINDOW / 2);
+        assert!(!batch.take_due(now + EXPERIENCE_WINDOW / 2, EXPERIENCE_WINDOW));
+        assert!(batch.take_due(now + EXPERIENCE_WINDOW, EXPERIENCE_WINDOW));
+        assert!(!batch.is_pending());
+    }
+}
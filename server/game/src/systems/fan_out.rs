@@ -0,0 +1,73 @@
+//! Sending packets to many clients at once, like the observers in a screen or
+//! the members of a guild.
+//!
+//! A client could disconnect while a packet is on its way to everyone, that
+//! must not keep the packet from the others. The ids of the dead ones are
+//! handed back so whoever keeps them around could forget about them.
+
+use bytes::Bytes;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use std::fmt::Debug;
+use std::future::Future;
+use tq_network::{ActorHandle, Codec, PacketEncode, SendOutcome};
+
+/// A packet going out to many clients, encoded once for every codec they
+/// use, the same way [`ActorHandle::send`] would encode it for each of them.
+pub struct SharedPacket<'a, P> {
+    packet: &'a P,
+    encoded: Vec<(Codec, (u16, Bytes))>,
+}
+
+impl<'a, P: PacketEncode> SharedPacket<'a, P> {
+    pub fn new(packet: &'a P) -> Self {
+        Self {
+            packet,
+            encoded: Vec::with_capacity(1),
+        }
+    }
+
+    /// The packet, encoded with the codec of `to`.
+    pub fn encode_for(
+        &mut self,
+        to: &ActorHandle,
+    ) -> Result<(u16, Bytes), P::Error> {
+        let codec = to.codec();
+        if let Some((_, msg)) = self.encoded.iter().find(|(c, _)| *c == codec) {
+            return Ok(msg.clone());
+        }
+        let msg = self.packet.encode_with(codec)?;
+        self.encoded.push((codec, msg.clone()));
+        Ok(msg)
+    }
+}
+
+/// Runs the sends all at once, each one tagged with the id of the entity it
+/// goes to, and returns the ids of the entities whose client is gone. Any
+/// other failure is only logged.
+pub async fn fan_out<I, F, E>(sends: I) -> Vec<u32>
+where
+    I: IntoIterator<Item = (u32, F)>,
+    F: Future<Output = SendOutcome<E>>,
+    E: Debug,
+{
+    let futs: FuturesUnordered<_> = sends
+        .into_iter()
+        .map(|(id, send)| async move { (id, send.await) })
+        .collect();
+    futs.filter_map(|(id, outcome)| async move {
+        match outcome {
+            SendOutcome::Sent => None,
+            SendOutcome::Dead => {
+                tracing::debug!(id, "Skipped a dead actor");
+                Some(id)
+            },
+            SendOutcome::Failed(error) => {
+                tracing::error!(id, ?error, "Failed to send packet");
+                None
+            },
+        }
+    })
+    .collect()
+    .await
+}
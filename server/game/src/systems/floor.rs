@@ -1,8 +1,8 @@
+use crate::sync::RwLock;
 use crate::Error;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use io::{AsyncReadExt, AsyncWriteExt};
 use num_enum::FromPrimitive;
-use parking_lot::RwLock;
 use primitives::{Point, Size};
 use std::env;
 use std::path::PathBuf;
@@ -57,6 +57,61 @@ impl Floor {
         self.with_coordinates(|c| c.get(i as usize).cloned())
     }
 
+    /// Whether the given kind of entity can step on the tile, tiles outside
+    /// of the map are never walkable.
+    pub fn is_walkable(&self, x: u16, y: u16, by: EntityKind) -> bool {
+        self.tile(x, y).is_some_and(|t| t.access.walkable_by(by))
+    }
+
+    /// The tiles no further than `radius` tiles away from `center` on either
+    /// axis that `accept` takes, at most `limit` of them. They are tried
+    /// nearest first, ring by ring, always in the same order.
+    ///
+    /// `accept` gets `None` for the tiles outside of the grid, all of them
+    /// when the floor is not loaded. The grid stays locked for reading until
+    /// it returns.
+    pub fn tiles_around<F>(
+        &self,
+        center: (u16, u16),
+        radius: u16,
+        limit: usize,
+        mut accept: F,
+    ) -> Vec<(u16, u16)>
+    where
+        F: FnMut((u16, u16), Option<&Tile>) -> bool,
+    {
+        let boundaries = self.boundaries();
+        let coordinates = self.coordinates.read();
+        let mut found = Vec::new();
+        for (x, y) in spiral(center, radius) {
+            if found.len() >= limit {
+                break;
+            }
+            let inside =
+                (x as i32) < boundaries.width && (y as i32) < boundaries.height;
+            let tile = inside
+                .then(|| x as i32 * boundaries.width + y as i32)
+                .and_then(|i| coordinates.get(i as usize));
+            if accept((x, y), tile) {
+                found.push((x, y));
+            }
+        }
+        found
+    }
+
+    /// The first tile [`Floor::tiles_around`] finds.
+    pub fn find_tile<F>(
+        &self,
+        center: (u16, u16),
+        radius: u16,
+        accept: F,
+    ) -> Option<(u16, u16)>
+    where
+        F: FnMut((u16, u16), Option<&Tile>) -> bool,
+    {
+        self.tiles_around(center, radius, 1, accept).pop()
+    }
+
     /// This method loads a compressed map from the server's flat file database.
     /// If the file does not exist, the server will make an attempt to find
     /// and convert a dmap version of the map into a compressed map file.
@@ -103,6 +158,30 @@ impl Floor {
         Ok(())
     }
 
+    /// Loads an empty, fully walkable grid of the given size, used by tests
+    /// that do not have the map files around.
+    #[cfg(test)]
+    pub fn load_blank(&self, boundaries: Size<i32>) {
+        let tile = Tile {
+            access: TileAccess::Available,
+            elevation: 0,
+        };
+        *self.boundaries.write() = boundaries;
+        *self.coordinates.write() = vec![tile; boundaries.area() as usize];
+        self.loaded
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Changes a single tile, used by tests to put walls on a blank grid.
+    #[cfg(test)]
+    pub fn set_tile(&self, x: u16, y: u16, tile: Tile) {
+        let boundaries = self.boundaries();
+        let i = (x as i32 * boundaries.width) + y as i32;
+        if let Some(t) = self.coordinates.write().get_mut(i as usize) {
+            *t = tile;
+        }
+    }
+
     /// This method unloads the map from memory .. useful when there is no one
     /// on that map. it should get loaded again once needed by calling
     /// [`Self::load`].
@@ -140,15 +219,15 @@ impl Floor {
         for y in 0..height {
             for x in 0..width {
                 let mut access = if buffer.get_u16_le() == 0 {
-                    TileType::Available
+                    TileAccess::Available
                 } else {
-                    TileType::Terrain
+                    TileAccess::Terrain
                 };
                 let surface = buffer.get_u16_le();
                 let elevation = buffer.get_u16_le();
                 // Edit the access type and save to the coordinate system:
                 if surface == 16 {
-                    access = TileType::MarketSpot;
+                    access = TileAccess::MarketSpot;
                 }
                 let i = (x * boundaries.width) + y;
                 coordinates[i as usize] = Tile { access, elevation };
@@ -169,7 +248,7 @@ impl Floor {
                 for y in 0..3 {
                     if py + y < height && px + x < width {
                         let i = ((px + x) * boundaries.width) + (py + y);
-                        coordinates[i as usize].access = TileType::Portal;
+                        coordinates[i as usize].access = TileAccess::Portal;
                     }
                 }
             }
@@ -230,9 +309,9 @@ impl Floor {
                                 let py = location.y + start_location.y - y;
                                 let p = Point::new(px, py);
                                 let access = if scene_buffer.get_i32_le() == 0 {
-                                    TileType::Available
+                                    TileAccess::Available
                                 } else {
-                                    TileType::Terrain
+                                    TileAccess::Terrain
                                 };
                                 let i = (p.x * boundaries.width) + p.y;
                                 coordinates[i as usize].access = access;
@@ -307,14 +386,16 @@ impl Floor {
 /// map's coordinate grid is composed of these tiles.
 #[derive(Debug, Copy, Clone, Default)]
 pub struct Tile {
-    pub access: TileType,
+    pub access: TileAccess,
     pub elevation: u16,
 }
 
 /// This enumeration type defines the access types for tiles.
-#[derive(Debug, Copy, Clone, FromPrimitive, Eq, PartialEq, Ord, PartialOrd)]
+#[derive(
+    Debug, Default, Copy, Clone, FromPrimitive, Eq, PartialEq, Ord, PartialOrd,
+)]
 #[repr(u8)]
-pub enum TileType {
+pub enum TileAccess {
     Terrain = 0,
     Npc = 1,
     Monster = 2,
@@ -322,12 +403,31 @@ pub enum TileType {
     Item = 4,
     MarketSpot = 5,
     Available = 6,
-    #[num_enum(default)]
+    #[default]
     Unknown = u8::MAX,
 }
 
-impl Default for TileType {
-    fn default() -> Self { Self::Unknown }
+impl TileAccess {
+    /// Whether the given kind of entity can step on a tile with this access.
+    ///
+    /// Monsters keep to their own tiles and the open ground, while portals
+    /// and market spots are only for players.
+    pub fn walkable_by(self, by: EntityKind) -> bool {
+        match self {
+            Self::Terrain | Self::Npc | Self::Unknown => false,
+            Self::Monster => by == EntityKind::Monster,
+            Self::Portal | Self::MarketSpot => by == EntityKind::Player,
+            Self::Item | Self::Available => true,
+        }
+    }
+}
+
+/// Who is moving on the floor, since monsters and players do not walk the
+/// same tiles.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EntityKind {
+    Player,
+    Monster,
 }
 
 /// This enumeration type defines the types of scenery files used by the client.
@@ -341,3 +441,59 @@ pub enum SceneryType {
     #[num_enum(default)]
     Unknown = u8::MAX,
 }
+
+/// The tiles around `center`, ring by ring up to `radius`, each ring from
+/// its top left corner, column by column.
+fn spiral((x, y): (u16, u16), radius: u16) -> impl Iterator<Item = (u16, u16)> {
+    (0..=radius as i32).flat_map(move |r| {
+        (-r..=r)
+            .flat_map(move |dx| {
+                (-r..=r)
+                    .filter(move |dy| dx.abs() == r || dy.abs() == r)
+                    .map(move |dy| (dx, dy))
+            })
+            .filter_map(move |(dx, dy)| {
+                let x = u16::try_from(x as i32 + dx).ok()?;
+                let y = u16::try_from(y as i32 + dy).ok()?;
+                Some((x, y))
+            })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(access: TileAccess) -> Tile {
+        Tile {
+            access,
+            elevation: 0,
+        }
+    }
+
+    #[test]
+    fn walkable_by_kind() {
+        let floor = Floor::new("blank");
+        floor.load_blank(Size::new(8, 8));
+        floor.set_tile(1, 1, tile(TileAccess::Portal));
+        floor.set_tile(2, 2, tile(TileAccess::MarketSpot));
+        floor.set_tile(3, 3, tile(TileAccess::Monster));
+        floor.set_tile(4, 4, tile(TileAccess::Terrain));
+
+        // Players only.
+        assert!(floor.is_walkable(1, 1, EntityKind::Player));
+        assert!(!floor.is_walkable(1, 1, EntityKind::Monster));
+        assert!(floor.is_walkable(2, 2, EntityKind::Player));
+        assert!(!floor.is_walkable(2, 2, EntityKind::Monster));
+        // Monsters only.
+        assert!(floor.is_walkable(3, 3, EntityKind::Monster));
+        assert!(!floor.is_walkable(3, 3, EntityKind::Player));
+        // Nobody, not even outside of the map.
+        assert!(!floor.is_walkable(4, 4, EntityKind::Player));
+        assert!(!floor.is_walkable(4, 4, EntityKind::Monster));
+        assert!(!floor.is_walkable(8, 8, EntityKind::Player));
+        // Everyone.
+        assert!(floor.is_walkable(5, 5, EntityKind::Player));
+        assert!(floor.is_walkable(5, 5, EntityKind::Monster));
+    }
+}
@@ -0,0 +1,796 @@
+use crate::entities::{Character, GameEntity};
+use crate::packets::{
+    AttributeKind, MsgName, MsgSyndicate, MsgTalk, MsgUserAttrib,
+    SyndicateAction, TalkChannel,
+};
+use crate::systems::fan_out;
+use crate::{constants, Error, State};
+use num_enum::{FromPrimitive, IntoPrimitive};
+use parking_lot::{Mutex, RwLock};
+use sqlx::SqlitePool;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tq_db::guild::{Guild as GuildRow, GuildMember as GuildMemberRow};
+
+/// The level a character has to reach before it could create a guild.
+pub const GUILD_CREATION_LEVEL: u16 = 90;
+
+/// The silver it takes to create a guild, it all goes to the funds of the
+/// new guild.
+pub const GUILD_CREATION_FEE: u64 = 1_000_000;
+
+/// The rank of a guild member, it decides what the member is allowed to do.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    FromPrimitive,
+    IntoPrimitive,
+)]
+#[repr(u8)]
+pub enum GuildRank {
+    #[num_enum(default)]
+    Member = 50,
+    DeputyLeader = 90,
+    Leader = 100,
+}
+
+impl GuildRank {
+    pub const fn can_invite(self) -> bool {
+        matches!(self, Self::Leader | Self::DeputyLeader)
+    }
+
+    /// Only the leader could promote members or disband the guild.
+    pub const fn is_leader(self) -> bool { matches!(self, Self::Leader) }
+}
+
+/// Why a guild action did nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuildRejection {
+    InvalidName,
+    NameTaken,
+    UnderLevel,
+    NotEnoughSilver,
+    AlreadyInGuild,
+    NotInGuild,
+    TargetNotFound,
+    TargetInGuild,
+    NotInvited,
+    /// The rank of the character does not allow it.
+    NotAllowed,
+    /// The leader has to disband the guild instead.
+    LeaderCannotLeave,
+    AlreadyPromoted,
+}
+
+impl GuildRejection {
+    /// What the player gets told.
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::InvalidName => "Invalid guild name.",
+            Self::NameTaken => "That guild name is taken.",
+            Self::UnderLevel => "You have to reach level 90 to create a guild.",
+            Self::NotEnoughSilver => {
+                "You need 1,000,000 silver to create a guild."
+            },
+            Self::AlreadyInGuild => "You are already in a guild.",
+            Self::NotInGuild => "You are not in a guild.",
+            Self::TargetNotFound => "That player is not around.",
+            Self::TargetInGuild => "That player is already in a guild.",
+            Self::NotInvited => "You were not invited to that guild.",
+            Self::NotAllowed => "Your rank does not allow it.",
+            Self::LeaderCannotLeave => {
+                "The leader could only disband the guild."
+            },
+            Self::AlreadyPromoted => "That member is already a deputy leader.",
+        }
+    }
+}
+
+/// A member of a [`Guild`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub name: String,
+    pub rank: GuildRank,
+}
+
+/// A guild, also known as a syndicate, as cached in the [`State`].
+#[derive(Debug)]
+pub struct Guild {
+    id: u32,
+    name: String,
+    /// The character id of the leader.
+    leader_id: i32,
+    funds: AtomicU64,
+    /// The members by character id, the leader included.
+    members: RwLock<BTreeMap<i32, Member>>,
+}
+
+impl Guild {
+    fn from_row(row: GuildRow) -> Self {
+        Self {
+            id: row.guild_id as u32,
+            name: row.name,
+            leader_id: row.leader_id,
+            funds: AtomicU64::new(row.funds as u64),
+            members: Default::default(),
+        }
+    }
+
+    pub fn id(&self) -> u32 { self.id }
+
+    pub fn name(&self) -> &str { &self.name }
+
+    pub fn leader_id(&self) -> i32 { self.leader_id }
+
+    /// The silver in the funds of the guild.
+    pub fn funds(&self) -> u64 { self.funds.load(Ordering::Relaxed) }
+
+    pub fn member(&self, character_id: i32) -> Option<Member> {
+        self.members.read().get(&character_id).cloned()
+    }
+
+    pub fn rank_of(&self, character_id: i32) -> Option<GuildRank> {
+        self.members.read().get(&character_id).map(|m| m.rank)
+    }
+
+    pub fn member_count(&self) -> usize { self.members.read().len() }
+
+    fn set_rank(&self, character_id: i32, rank: GuildRank) {
+        if let Some(member) = self.members.write().get_mut(&character_id) {
+            member.rank = rank;
+        }
+    }
+
+    /// The members of the guild that are online.
+    fn online_members(&self, state: &State) -> Vec<Arc<GameEntity>> {
+        let members = self.members.read();
+        state
+            .entities()
+            .into_iter()
+            .filter(|e| {
+                e.as_character()
+                    .is_some_and(|c| members.contains_key(&c.character_id()))
+            })
+            .collect()
+    }
+}
+
+/// All the guilds of the server, cached in the [`State`] and kept in sync
+/// with the database.
+#[derive(Debug, Default)]
+pub struct Guilds {
+    guilds: RwLock<HashMap<u32, Arc<Guild>>>,
+    /// The guild of every member, by character id.
+    membership: RwLock<HashMap<i32, u32>>,
+    /// The guild every invited character got invited to, by character id.
+    invitations: Mutex<HashMap<i32, u32>>,
+}
+
+impl Guilds {
+    /// Loads the guilds and their members from the database.
+    pub async fn load(pool: &SqlitePool) -> Result<Self, Error> {
+        let guilds = Self::default();
+        for row in GuildRow::load_all(pool).await? {
+            guilds.insert(Guild::from_row(row));
+        }
+        for row in GuildMemberRow::load_all(pool).await? {
+            let Some(guild) = guilds.get(row.guild_id as u32) else {
+                continue;
+            };
+            let member = Member {
+                name: row.name,
+                rank: GuildRank::from(row.rank as u8),
+            };
+            guilds.add_member(&guild, row.character_id, member);
+        }
+        tracing::debug!(count = guilds.guilds.read().len(), "Loaded Guilds");
+        Ok(guilds)
+    }
+
+    pub fn get(&self, guild_id: u32) -> Option<Arc<Guild>> {
+        self.guilds.read().get(&guild_id).cloned()
+    }
+
+    /// The guild the character is in, if any.
+    pub fn of(&self, character_id: i32) -> Option<Arc<Guild>> {
+        let guild_id = self.membership.read().get(&character_id).copied()?;
+        self.get(guild_id)
+    }
+
+    /// Adds `amount` silver to the funds of the guild, returns `false` if
+    /// there is no such guild.
+    pub async fn add_funds(
+        &self,
+        pool: &SqlitePool,
+        guild_id: u32,
+        amount: u64,
+    ) -> Result<bool, Error> {
+        let Some(guild) = self.get(guild_id) else {
+            return Ok(false);
+        };
+        if !GuildRow::add_funds(pool, guild_id as i32, amount as i64).await? {
+            return Ok(false);
+        }
+        guild.funds.fetch_add(amount, Ordering::Relaxed);
+        Ok(true)
+    }
+
+    fn insert(&self, guild: Guild) -> Arc<Guild> {
+        let guild = Arc::new(guild);
+        self.guilds.write().insert(guild.id, guild.clone());
+        guild
+    }
+
+    fn remove(&self, guild: &Guild) {
+        self.guilds.write().remove(&guild.id);
+        let members = std::mem::take(&mut *guild.members.write());
+        let mut membership = self.membership.write();
+        for character_id in members.keys() {
+            membership.remove(character_id);
+        }
+    }
+
+    fn add_member(&self, guild: &Guild, character_id: i32, member: Member) {
+        guild.members.write().insert(character_id, member);
+        self.membership.write().insert(character_id, guild.id);
+    }
+
+    fn remove_member(&self, guild: &Guild, character_id: i32) {
+        guild.members.write().remove(&character_id);
+        self.membership.write().remove(&character_id);
+    }
+
+    fn invite(&self, character_id: i32, guild_id: u32) {
+        self.invitations.lock().insert(character_id, guild_id);
+    }
+
+    fn take_invitation(&self, character_id: i32) -> Option<u32> {
+        self.invitations.lock().remove(&character_id)
+    }
+}
+
+/// Creates a guild named `name` led by `me`, paying the
+/// [`GUILD_CREATION_FEE`] into its funds.
+#[tracing::instrument(skip(state, me), fields(me = me.id()))]
+pub async fn create_guild(
+    state: &State,
+    me: &Character,
+    name: &str,
+) -> Result<(), Error> {
+    let valid = !name.is_empty()
+        && name.len() <= constants::MAX_NAME_LEN
+        && name.chars().all(|c| c.is_ascii_alphanumeric());
+    if !valid {
+        return tell(me, GuildRejection::InvalidName.message()).await;
+    }
+    let guilds = state.guilds();
+    if guilds.of(me.character_id()).is_some() {
+        return tell(me, GuildRejection::AlreadyInGuild.message()).await;
+    }
+    if me.entity().level() < GUILD_CREATION_LEVEL {
+        return tell(me, GuildRejection::UnderLevel.message()).await;
+    }
+    if me.spend_silver(GUILD_CREATION_FEE).is_err() {
+        return tell(me, GuildRejection::NotEnoughSilver.message()).await;
+    }
+    let leader = GuildRank::Leader;
+    let row = GuildRow::create(
+        state.pool(),
+        name,
+        me.character_id(),
+        u8::from(leader) as i16,
+        GUILD_CREATION_FEE as i64,
+    )
+    .await?;
+    let Some(row) = row else {
+        me.add_silver(GUILD_CREATION_FEE)?;
+        return tell(me, GuildRejection::NameTaken.message()).await;
+    };
+    me.save(state).await?;
+    let msg =
+        MsgUserAttrib::single(me.id(), AttributeKind::Silver, me.silver());
+    me.owner().send(msg).await?;
+    let guild = guilds.insert(Guild::from_row(row));
+    let member = Member {
+        name: me.entity().name().to_string(),
+        rank: leader,
+    };
+    guilds.add_member(&guild, me.character_id(), member);
+    me.owner()
+        .send(MsgName::syndicate(guild.id(), guild.name()))
+        .await?;
+    tracing::info!(guild = guild.name(), "Guild created");
+    let msg = format!("{} has created the guild {}.", me.entity().name(), name);
+    state.broadcast(MsgTalk::announce(msg)).await?;
+    Ok(())
+}
+
+/// Invites the character `target_id` to the guild of `me`, the invitation
+/// stands until the target answers it.
+#[tracing::instrument(skip(state, me), fields(me = me.id()))]
+pub async fn invite_to_guild(
+    state: &State,
+    me: &Character,
+    target_id: u32,
+) -> Result<(), Error> {
+    let guilds = state.guilds();
+    let Some(guild) = guilds.of(me.character_id()) else {
+        return tell(me, GuildRejection::NotInGuild.message()).await;
+    };
+    let can_invite = guild
+        .rank_of(me.character_id())
+        .is_some_and(GuildRank::can_invite);
+    if !can_invite {
+        return tell(me, GuildRejection::NotAllowed.message()).await;
+    }
+    let target = nearby(state, me, target_id);
+    let Some(target) = target.as_ref().and_then(|e| e.as_character()) else {
+        return tell(me, GuildRejection::TargetNotFound.message()).await;
+    };
+    if guilds.of(target.character_id()).is_some() {
+        return tell(me, GuildRejection::TargetInGuild.message()).await;
+    }
+    guilds.invite(target.character_id(), guild.id());
+    let msg = MsgSyndicate::new(SyndicateAction::InviteJoin, me.id());
+    target.owner().send(msg).await?;
+    Ok(())
+}
+
+/// Accepts the invitation of the character `inviter_id`, joining its guild
+/// as a [`GuildRank::Member`].
+#[tracing::instrument(skip(state, me), fields(me = me.id()))]
+pub async fn join_guild(
+    state: &State,
+    me: &Character,
+    inviter_id: u32,
+) -> Result<(), Error> {
+    let guilds = state.guilds();
+    if guilds.of(me.character_id()).is_some() {
+        return tell(me, GuildRejection::AlreadyInGuild.message()).await;
+    }
+    let invitation = guilds.take_invitation(me.character_id());
+    let inviter = state.entity(inviter_id);
+    let guild = inviter
+        .as_ref()
+        .and_then(|e| e.as_character())
+        .and_then(|c| guilds.of(c.character_id()))
+        .filter(|g| Some(g.id()) == invitation);
+    let Some(guild) = guild else {
+        return tell(me, GuildRejection::NotInvited.message()).await;
+    };
+    let rank = GuildRank::Member;
+    let joined = GuildMemberRow::join(
+        state.pool(),
+        guild.id() as i32,
+        me.character_id(),
+        u8::from(rank) as i16,
+    )
+    .await?;
+    if !joined {
+        return tell(me, GuildRejection::AlreadyInGuild.message()).await;
+    }
+    let member = Member {
+        name: me.entity().name().to_string(),
+        rank,
+    };
+    guilds.add_member(&guild, me.character_id(), member);
+    me.owner()
+        .send(MsgName::syndicate(guild.id(), guild.name()))
+        .await?;
+    let msg = format!("{} joined the guild.", me.entity().name());
+    tell_guild(state, &guild, &msg).await?;
+    Ok(())
+}
+
+/// Leaves the guild of `me`, the leader could not leave its own guild.
+#[tracing::instrument(skip(state, me), fields(me = me.id()))]
+pub async fn leave_guild(state: &State, me: &Character) -> Result<(), Error> {
+    let guilds = state.guilds();
+    let Some(guild) = guilds.of(me.character_id()) else {
+        return tell(me, GuildRejection::NotInGuild.message()).await;
+    };
+    if guild.leader_id() == me.character_id() {
+        return tell(me, GuildRejection::LeaderCannotLeave.message()).await;
+    }
+    GuildMemberRow::leave(state.pool(), me.character_id()).await?;
+    guilds.remove_member(&guild, me.character_id());
+    tell(me, "You left the guild.").await?;
+    let msg = format!("{} left the guild.", me.entity().name());
+    tell_guild(state, &guild, &msg).await?;
+    Ok(())
+}
+
+/// Promotes the member `target_id` to [`GuildRank::DeputyLeader`], only the
+/// leader could promote.
+#[tracing::instrument(skip(state, me), fields(me = me.id()))]
+pub async fn promote_in_guild(
+    state: &State,
+    me: &Character,
+    target_id: u32,
+) -> Result<(), Error> {
+    let guilds = state.guilds();
+    let Some(guild) = guilds.of(me.character_id()) else {
+        return tell(me, GuildRejection::NotInGuild.message()).await;
+    };
+    let is_leader = guild
+        .rank_of(me.character_id())
+        .is_some_and(GuildRank::is_leader);
+    if !is_leader {
+        return tell(me, GuildRejection::NotAllowed.message()).await;
+    }
+    let target = state.entity(target_id);
+    let member = target
+        .as_ref()
+        .and_then(|e| e.as_character())
+        .and_then(|c| Some((c, guild.member(c.character_id())?)));
+    let Some((target, member)) = member else {
+        return tell(me, GuildRejection::TargetNotFound.message()).await;
+    };
+    if member.rank >= GuildRank::DeputyLeader {
+        return tell(me, GuildRejection::AlreadyPromoted.message()).await;
+    }
+    let rank = GuildRank::DeputyLeader;
+    GuildMemberRow::set_rank(
+        state.pool(),
+        target.character_id(),
+        u8::from(rank) as i16,
+    )
+    .await?;
+    guild.set_rank(target.character_id(), rank);
+    let msg = format!("{} is now a deputy leader.", member.name);
+    tell_guild(state, &guild, &msg).await?;
+    Ok(())
+}
+
+/// Disbands the guild of `me`, only the leader could disband it.
+#[tracing::instrument(skip(state, me), fields(me = me.id()))]
+pub async fn disband_guild(state: &State, me: &Character) -> Result<(), Error> {
+    let guilds = state.guilds();
+    let Some(guild) = guilds.of(me.character_id()) else {
+        return tell(me, GuildRejection::NotInGuild.message()).await;
+    };
+    let is_leader = guild
+        .rank_of(me.character_id())
+        .is_some_and(GuildRank::is_leader);
+    if !is_leader {
+        return tell(me, GuildRejection::NotAllowed.message()).await;
+    }
+    GuildRow::disband(state.pool(), guild.id() as i32).await?;
+    let online = guild.online_members(state);
+    guilds.remove(&guild);
+    let msg = format!("The guild {} has been disbanded.", guild.name());
+    for c in online.iter().filter_map(|e| e.as_character()) {
+        tell(c, &msg).await?;
+    }
+    tracing::info!(guild = guild.name(), "Guild disbanded");
+    Ok(())
+}
+
+/// Routes a message of the guild channel to the other online members of the
+/// guild of `me`.
+#[tracing::instrument(skip(state, me, msg), fields(me = me.id()))]
+pub async fn guild_chat(
+    state: &State,
+    me: &Character,
+    mut msg: MsgTalk,
+) -> Result<(), Error> {
+    let Some(guild) = state.guilds().of(me.character_id()) else {
+        return tell(me, GuildRejection::NotInGuild.message()).await;
+    };
+    msg.sender_name = me.entity().name().to_string();
+    let sends = guild.online_members(state).into_iter().filter_map(|e| {
+        let owner = e.owner().filter(|_| e.id() != me.id())?;
+        let msg = msg.clone();
+        Some((e.id(), async move { owner.try_send(msg).await }))
+    });
+    fan_out(sends).await;
+    Ok(())
+}
+
+/// The character `id` if it is on the same map as `me` and in its screen.
+fn nearby(state: &State, me: &Character, id: u32) -> Option<Arc<GameEntity>> {
+    let map_id = me.entity().map_id();
+    let loc = me.entity().location();
+    state.entity(id).filter(|e| {
+        e.id() != me.id()
+            && e.basic().map_id() == map_id
+            && tq_math::in_screen(loc.into(), e.basic().location().into())
+    })
+}
+
+/// Tells every online member of the guild.
+async fn tell_guild(
+    state: &State,
+    guild: &Guild,
+    message: &str,
+) -> Result<(), Error> {
+    let sends = guild.online_members(state).into_iter().filter_map(|e| {
+        let c = e.as_character()?;
+        let msg = MsgTalk::from_system(c.id(), TalkChannel::Guild, message);
+        let owner = c.owner();
+        Some((c.id(), async move { owner.try_send(msg).await }))
+    });
+    fan_out(sends).await;
+    Ok(())
+}
+
+async fn tell(me: &Character, message: &str) -> Result<(), Error> {
+    let msg = MsgTalk::from_system(me.id(), TalkChannel::TopLeft, message);
+    me.owner().send(msg).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use crate::ActorState;
+    use futures::FutureExt;
+    use tq_network::{Actor, PacketProcess};
+
+    async fn send(
+        state: &State,
+        actor: &Actor<ActorState>,
+        action: SyndicateAction,
+        data: u32,
+    ) -> Result<(), Error> {
+        MsgSyndicate::new(action, data).process(state, actor).await
+    }
+
+    /// Makes the actor the leader of a guild named `name`.
+    async fn found(
+        state: &State,
+        actor: &Actor<ActorState>,
+        name: &str,
+    ) -> Result<Arc<Guild>, Error> {
+        let entity = actor.entity();
+        let me = entity.as_character().unwrap();
+        me.entity().set_level(GUILD_CREATION_LEVEL);
+        me.add_silver(GUILD_CREATION_FEE)?;
+        MsgSyndicate::create(name).process(state, actor).await?;
+        Ok(state.guilds().of(me.character_id()).unwrap())
+    }
+
+    /// Makes the actor `b` join the guild of `a`.
+    async fn recruit(
+        state: &State,
+        a: &Actor<ActorState>,
+        b: &Actor<ActorState>,
+    ) -> Result<(), Error> {
+        let (a_id, b_id) = (a.entity().id(), b.entity().id());
+        send(state, a, SyndicateAction::InviteJoin, b_id).await?;
+        send(state, b, SyndicateAction::ApplyJoin, a_id).await
+    }
+
+    async fn rank_in_db(state: &State, character_id: i32) -> Option<i16> {
+        sqlx::query_as::<_, (i16,)>(
+            "SELECT rank FROM guild_members WHERE character_id = ?;",
+        )
+        .bind(character_id)
+        .fetch_optional(state.pool())
+        .await
+        .unwrap()
+        .map(|(rank,)| rank)
+    }
+
+    #[tokio::test]
+    async fn creating_a_guild() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, mut b_rx)] = actors;
+                let (a_entity, b_entity) = (a.entity(), b.entity());
+                let me = a_entity.as_character().unwrap();
+                let other = b_entity.as_character().unwrap();
+
+                // Not without the level nor the silver.
+                MsgSyndicate::create("Knights").process(&state, &a).await?;
+                let told = packets_of::<MsgTalk>(&mut a_rx);
+                assert_eq!(
+                    told[0].message,
+                    GuildRejection::UnderLevel.message()
+                );
+
+                let guild = found(&state, &a, "Knights").await?;
+                assert_eq!(guild.name(), "Knights");
+                assert_eq!(guild.leader_id(), me.character_id());
+                assert_eq!(guild.funds(), GUILD_CREATION_FEE);
+                assert_eq!(
+                    guild.rank_of(me.character_id()),
+                    Some(GuildRank::Leader)
+                );
+                assert_eq!(me.silver(), 1000);
+                let names = packets_of::<MsgName>(&mut a_rx);
+                assert_eq!(names[0].data, guild.id());
+                assert_eq!(names[0].names.as_vec(), &["Knights"]);
+                assert_eq!(
+                    rank_in_db(&state, me.character_id()).await,
+                    Some(100)
+                );
+
+                // Names are unique, and the fee is given back.
+                other.entity().set_level(GUILD_CREATION_LEVEL);
+                other.add_silver(GUILD_CREATION_FEE)?;
+                MsgSyndicate::create("Knights").process(&state, &b).await?;
+                let told = packets_of::<MsgTalk>(&mut b_rx);
+                assert_eq!(
+                    told.last().unwrap().message,
+                    GuildRejection::NameTaken.message()
+                );
+                assert_eq!(other.silver(), 1000 + GUILD_CREATION_FEE);
+                assert!(state.guilds().of(other.character_id()).is_none());
+
+                // The guilds are loaded back from the database.
+                let guilds = Guilds::load(state.pool()).await?;
+                let loaded = guilds.of(me.character_id()).unwrap();
+                assert_eq!(loaded.name(), "Knights");
+                assert_eq!(loaded.member_count(), 1);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn joining_a_guild() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, mut b_rx)] = actors;
+                let (a_entity, b_entity) = (a.entity(), b.entity());
+                let me = a_entity.as_character().unwrap();
+                let other = b_entity.as_character().unwrap();
+                let guild = found(&state, &a, "Knights").await?;
+                // Everyone heard about the new guild.
+                let told = packets_of::<MsgTalk>(&mut b_rx);
+                assert!(told[0].message.contains("created the guild Knights"));
+
+                // Joining takes an invitation.
+                send(&state, &b, SyndicateAction::ApplyJoin, me.id()).await?;
+                let told = packets_of::<MsgTalk>(&mut b_rx);
+                assert_eq!(
+                    told[0].message,
+                    GuildRejection::NotInvited.message()
+                );
+
+                send(&state, &a, SyndicateAction::InviteJoin, other.id())
+                    .await?;
+                let invites = packets_of::<MsgSyndicate>(&mut b_rx);
+                assert_eq!(invites.len(), 1);
+                assert_eq!(invites[0].data, me.id());
+                send(&state, &b, SyndicateAction::ApplyJoin, me.id()).await?;
+                assert_eq!(
+                    guild.rank_of(other.character_id()),
+                    Some(GuildRank::Member)
+                );
+                assert_eq!(
+                    rank_in_db(&state, other.character_id()).await,
+                    Some(50)
+                );
+
+                // Both heard about it, then chat through the guild channel.
+                for rx in [&mut a_rx, &mut b_rx] {
+                    let told = packets_of::<MsgTalk>(rx);
+                    assert!(told.last().unwrap().message.contains("joined"));
+                }
+                let msg =
+                    MsgTalk::from_system(other.id(), TalkChannel::Guild, "Hi");
+                msg.process(&state, &b).await?;
+                let heard = packets_of::<MsgTalk>(&mut a_rx);
+                assert_eq!(heard.len(), 1);
+                assert_eq!(heard[0].message, "Hi");
+                assert_eq!(heard[0].sender_name, "test2");
+                assert!(packets_of::<MsgTalk>(&mut b_rx).is_empty());
+
+                // Members could leave, but not the leader.
+                send(&state, &a, SyndicateAction::Leave, 0).await?;
+                let told = packets_of::<MsgTalk>(&mut a_rx);
+                assert_eq!(
+                    told[0].message,
+                    GuildRejection::LeaderCannotLeave.message()
+                );
+                send(&state, &b, SyndicateAction::Leave, 0).await?;
+                assert!(state.guilds().of(other.character_id()).is_none());
+                assert_eq!(
+                    rank_in_db(&state, other.character_id()).await,
+                    None
+                );
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn rank_promotion() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, mut b_rx)] = actors;
+                let b_entity = b.entity();
+                let other = b_entity.as_character().unwrap();
+                let guild = found(&state, &a, "Knights").await?;
+                recruit(&state, &a, &b).await?;
+
+                // Members could not promote, not even themselves.
+                send(&state, &b, SyndicateAction::Promote, other.id()).await?;
+                let told = packets_of::<MsgTalk>(&mut b_rx);
+                assert_eq!(
+                    told.last().unwrap().message,
+                    GuildRejection::NotAllowed.message()
+                );
+                assert_eq!(
+                    guild.rank_of(other.character_id()),
+                    Some(GuildRank::Member)
+                );
+
+                send(&state, &a, SyndicateAction::Promote, other.id()).await?;
+                assert_eq!(
+                    guild.rank_of(other.character_id()),
+                    Some(GuildRank::DeputyLeader)
+                );
+                assert_eq!(
+                    rank_in_db(&state, other.character_id()).await,
+                    Some(90)
+                );
+                packets_of::<MsgTalk>(&mut a_rx);
+                send(&state, &a, SyndicateAction::Promote, other.id()).await?;
+                let told = packets_of::<MsgTalk>(&mut a_rx);
+                assert_eq!(
+                    told[0].message,
+                    GuildRejection::AlreadyPromoted.message()
+                );
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn only_the_leader_disbands() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, mut b_rx)] = actors;
+                let (a_entity, b_entity) = (a.entity(), b.entity());
+                let me = a_entity.as_character().unwrap();
+                let other = b_entity.as_character().unwrap();
+                let guild = found(&state, &a, "Knights").await?;
+                recruit(&state, &a, &b).await?;
+                send(&state, &a, SyndicateAction::Promote, other.id()).await?;
+
+                // Not even a deputy leader.
+                packets_of::<MsgTalk>(&mut b_rx);
+                send(&state, &b, SyndicateAction::Disband, 0).await?;
+                let told = packets_of::<MsgTalk>(&mut b_rx);
+                assert_eq!(
+                    told[0].message,
+                    GuildRejection::NotAllowed.message()
+                );
+                assert!(state.guilds().get(guild.id()).is_some());
+
+                send(&state, &a, SyndicateAction::Disband, 0).await?;
+                assert!(state.guilds().get(guild.id()).is_none());
+                assert!(state.guilds().of(me.character_id()).is_none());
+                assert!(state.guilds().of(other.character_id()).is_none());
+                assert_eq!(rank_in_db(&state, me.character_id()).await, None);
+                assert_eq!(
+                    rank_in_db(&state, other.character_id()).await,
+                    None
+                );
+                for rx in [&mut a_rx, &mut b_rx] {
+                    let told = packets_of::<MsgTalk>(rx);
+                    assert!(told.last().unwrap().message.contains("disbanded"));
+                }
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
@@ -0,0 +1,319 @@
+//! Private copies of a map, like a dungeon every team gets for itself.
+//!
+//! An instance is a [`Map`] made from a template at runtime, under an id of
+//! its own and keyed by whoever owns it, a team or anything else. It lives in
+//! the state along with the maps from the database, so everything that looks
+//! maps up by id works in it, and it is gone once the last character leaves.
+
+use crate::entities::Character;
+use crate::world::Map;
+use crate::{Error, State};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// Where the ids of the instances start, far above the maps from the
+/// database.
+pub const INSTANCE_MAP_ID_MIN: u32 = 1_000_000;
+
+/// Who owns an instance, and what it is a copy of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct InstanceOf {
+    key: u32,
+    template: u32,
+}
+
+/// The instances in the world.
+#[derive(Debug)]
+pub struct Instances {
+    next_id: AtomicU32,
+    /// The instances by their map ids.
+    maps: Mutex<HashMap<u32, InstanceOf>>,
+}
+
+impl Default for Instances {
+    fn default() -> Self {
+        Self {
+            next_id: AtomicU32::new(INSTANCE_MAP_ID_MIN),
+            maps: Default::default(),
+        }
+    }
+}
+
+impl Instances {
+    /// The id of the instance of the template owned by `key`, if any.
+    pub fn find(&self, key: u32, template: u32) -> Option<u32> {
+        let wanted = InstanceOf { key, template };
+        let maps = self.maps.lock();
+        maps.iter()
+            .find(|(_, of)| **of == wanted)
+            .map(|(id, _)| *id)
+    }
+
+    /// Who owns the instance, `None` if the map is not one.
+    pub fn key_of(&self, map_id: u32) -> Option<u32> {
+        self.maps.lock().get(&map_id).map(|of| of.key)
+    }
+
+    /// How many instances there are.
+    pub fn len(&self) -> usize { self.maps.lock().len() }
+
+    pub fn is_empty(&self) -> bool { self.maps.lock().is_empty() }
+}
+
+/// The instance of the template owned by `key`, made if there is none yet.
+#[tracing::instrument(skip(state))]
+pub fn create_instance(
+    state: &State,
+    template: u32,
+    key: u32,
+) -> Result<Arc<Map>, Error> {
+    let instances = state.instances();
+    if let Some(map) = instances
+        .find(key, template)
+        .and_then(|id| state.map_by_id(id))
+    {
+        return Ok(map);
+    }
+    let template_map = state.try_map(template)?;
+    if template_map.is_copy() {
+        return Err(Error::Other(format!(
+            "Map {template} is an instance, not a template"
+        )));
+    }
+    let id = instances.next_id.fetch_add(1, Ordering::Relaxed);
+    let map = Arc::new(template_map.instantiate(id));
+    instances
+        .maps
+        .lock()
+        .insert(id, InstanceOf { key, template });
+    state.insert_map(map.clone());
+    tracing::debug!(map_id = id, "Instance created");
+    Ok(map)
+}
+
+/// Teleports the team into its instance of the template, made if needed, and
+/// returns the instance.
+pub async fn enter_instance(
+    state: &State,
+    template: u32,
+    key: u32,
+    team: &[&Character],
+    (x, y): (u16, u16),
+) -> Result<Arc<Map>, Error> {
+    let map = create_instance(state, template, key)?;
+    for member in team {
+        member.teleport(state, map.id(), (x, y)).await?;
+    }
+    Ok(map)
+}
+
+/// Takes the instance out of the world, `false` if the map is not an
+/// instance.
+#[tracing::instrument(skip(state))]
+pub fn destroy_instance(state: &State, map_id: u32) -> Result<bool, Error> {
+    if state.instances().maps.lock().remove(&map_id).is_none() {
+        return Ok(false);
+    }
+    if let Some(map) = state.remove_map(map_id) {
+        map.unload()?;
+    }
+    tracing::debug!("Instance destroyed");
+    Ok(true)
+}
+
+/// Destroys the instance once no character is left in it, it does nothing
+/// for the other maps.
+pub fn destroy_instance_if_empty(
+    state: &State,
+    map_id: u32,
+) -> Result<bool, Error> {
+    if state.instances().key_of(map_id).is_none() {
+        return Ok(false);
+    }
+    let empty = state
+        .map_by_id(map_id)
+        .is_none_or(|map| map.characters_snapshot().is_empty());
+    if !empty {
+        return Ok(false);
+    }
+    destroy_instance(state, map_id)
+}
+
+/// The map a portal on `from` leading to `to_map_id` takes the character to.
+///
+/// Within an instance, a portal into the template leads back into the
+/// instance, and a portal into another template leads into the instance of
+/// that template owned by the same key if there is one. Everything else
+/// leads to the map from the database.
+pub fn resolve_portal(state: &State, from: &Map, to_map_id: u32) -> u32 {
+    let Some(key) = state.instances().key_of(from.id()) else {
+        return to_map_id;
+    };
+    if to_map_id == from.map_id() {
+        return from.id();
+    }
+    state.instances().find(key, to_map_id).unwrap_or(to_map_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::{ActionType, MsgAction};
+    use crate::test_utils::*;
+    use crate::utils::LoHi;
+    use crate::world::Maps;
+    use futures::FutureExt;
+    use primitives::{Location, Size};
+    use tq_network::PacketProcess;
+
+    /// Puts the actors on a blank arena, where they start from.
+    async fn on_arena(
+        state: &State,
+        actors: &[&TestActor],
+    ) -> Result<(), Error> {
+        let arena = u32::from(Maps::Arena);
+        let map = state.try_map(arena)?;
+        map.load_blank(Size::new(100, 100)).await?;
+        for (actor, _) in actors {
+            let e = actor.entity();
+            e.basic().set_map_id(arena);
+            e.basic().set_location(Location::new(50, 50, 0));
+            map.insert_entity(e).await?;
+        }
+        Ok(())
+    }
+
+    /// Makes the instance and loads it as a blank map.
+    async fn blank_instance(
+        state: &State,
+        template: u32,
+        key: u32,
+    ) -> Result<Arc<Map>, Error> {
+        let map = create_instance(state, template, key)?;
+        map.load_blank(Size::new(500, 500)).await?;
+        Ok(map)
+    }
+
+    #[tokio::test]
+    async fn teams_get_their_own_copies() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                on_arena(&state, &[&actors[0], &actors[1]]).await?;
+                let [(a, _), (b, _)] = actors;
+                let dungeon = u32::from(Maps::Newplain);
+                let first = blank_instance(&state, dungeon, 1).await?;
+                let second = blank_instance(&state, dungeon, 2).await?;
+                assert_ne!(first.id(), second.id());
+                for map in [&first, &second] {
+                    assert!(map.is_copy());
+                    assert_eq!(map.map_id(), dungeon);
+                }
+                // The same team gets the same copy.
+                assert_eq!(
+                    create_instance(&state, dungeon, 1)?.id(),
+                    first.id()
+                );
+                assert_eq!(state.instances().len(), 2);
+
+                let (ea, eb) = (a.entity(), b.entity());
+                let (alice, bob) =
+                    (ea.as_character().unwrap(), eb.as_character().unwrap());
+                enter_instance(&state, dungeon, 1, &[alice], (60, 60)).await?;
+                enter_instance(&state, dungeon, 2, &[bob], (60, 60)).await?;
+                assert_eq!(alice.entity().map_id(), first.id());
+                assert_eq!(bob.entity().map_id(), second.id());
+                let ids = |map: &Map| -> Vec<u32> {
+                    map.characters_snapshot().iter().map(|e| e.id()).collect()
+                };
+                assert_eq!(ids(&first), [alice.id()]);
+                assert_eq!(ids(&second), [bob.id()]);
+                // Nobody shows up on the template either.
+                assert!(state
+                    .try_map(dungeon)?
+                    .characters_snapshot()
+                    .is_empty());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn portals_inside_an_instance_lead_into_it() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                on_arena(&state, &[&actors[0]]).await?;
+                let [(a, _), _] = actors;
+                // The portal at (401, 387) leads into the forum.
+                let plain = u32::from(Maps::Newplain);
+                let forum = u32::from(Maps::Forum);
+                let upper = blank_instance(&state, plain, 7).await?;
+                assert_eq!(resolve_portal(&state, &upper, plain), upper.id());
+                // No copy of the forum for that team yet.
+                assert_eq!(resolve_portal(&state, &upper, forum), forum);
+                let lower = blank_instance(&state, forum, 7).await?;
+                assert_eq!(resolve_portal(&state, &upper, forum), lower.id());
+                // Another team's copy is none of our business.
+                let other = blank_instance(&state, plain, 8).await?;
+                assert_eq!(resolve_portal(&state, &other, forum), forum);
+
+                let e = a.entity();
+                let me = e.as_character().unwrap();
+                enter_instance(&state, plain, 7, &[me], (401, 387)).await?;
+                let xy = u32::constract(387, 401);
+                MsgAction::new(me.id(), xy, 0, 0, ActionType::ChangeMap)
+                    .process(&state, &a)
+                    .await?;
+                assert_eq!(me.entity().map_id(), lower.id());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn empty_instances_are_destroyed() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                on_arena(&state, &[&actors[0], &actors[1]]).await?;
+                let [(a, _), (b, _)] = actors;
+                let dungeon = u32::from(Maps::Newplain);
+                let arena = u32::from(Maps::Arena);
+                let map = blank_instance(&state, dungeon, 1).await?;
+                let id = map.id();
+                drop(map);
+                let (ea, eb) = (a.entity(), b.entity());
+                let (alice, bob) =
+                    (ea.as_character().unwrap(), eb.as_character().unwrap());
+                enter_instance(&state, dungeon, 1, &[alice, bob], (60, 60))
+                    .await?;
+
+                // Still someone in there. The arena got unloaded when they
+                // both left it.
+                let arena_map = state.try_map(arena)?;
+                arena_map.load_blank(Size::new(100, 100)).await?;
+                alice.teleport(&state, arena, (50, 50)).await?;
+                assert!(state.map_by_id(id).is_some());
+
+                // The last one logs out in there, and comes back to the
+                // template.
+                bob.leave_world(&state).await?;
+                assert!(state.map_by_id(id).is_none());
+                assert!(state.instances().is_empty());
+                let saved =
+                    tq_db::character::Character::from_account(state.pool(), 2)
+                        .await?
+                        .expect("character");
+                assert_eq!(saved.map_id as u32, dungeon);
+                assert!(!destroy_instance(&state, id)?);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
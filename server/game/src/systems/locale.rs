@@ -0,0 +1,146 @@
+//! The languages system messages could be told in, picked from the language
+//! the client reports in [`MsgConnect`](crate::packets::MsgConnect).
+
+/// A language the system messages are translated to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Locale {
+    #[default]
+    English,
+    Spanish,
+    Portuguese,
+    French,
+}
+
+impl Locale {
+    /// The locale of the language reported by the client, like `En` or
+    /// `es-ES`, English for any language we have no translations for.
+    pub fn from_language(language: &str) -> Self {
+        let code = language.trim().get(..2).unwrap_or_default();
+        match code.to_ascii_lowercase().as_str() {
+            "es" => Self::Spanish,
+            "pt" => Self::Portuguese,
+            "fr" => Self::French,
+            _ => Self::English,
+        }
+    }
+}
+
+/// The system messages that get told in the language of the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SystemMessage {
+    LoginInvalid,
+    UpdateRequired,
+    ServerBusy,
+    ServerFull,
+    AlreadyOnline,
+    ServerRestarting,
+    MaybeUnsupported,
+    RegisterInvalid,
+    RegisterNameTaken,
+    RegisterNameNotAllowed,
+    RegisterCharacterLimit,
+}
+
+impl SystemMessage {
+    /// The message in the given locale.
+    pub fn text(self, locale: Locale) -> &'static str {
+        // One row per message, in the order of the `Locale` variants.
+        let row = match self {
+            Self::LoginInvalid => [
+                "Login Invalid",
+                "Inicio de sesión inválido",
+                "Login inválido",
+                "Connexion invalide",
+            ],
+            Self::UpdateRequired => [
+                "Your client is not supported, please update it.",
+                "Tu cliente no es compatible, por favor actualízalo.",
+                "Seu cliente não é suportado, por favor atualize-o.",
+                "Votre client n'est pas pris en charge, veuillez le mettre à jour.",
+            ],
+            Self::ServerBusy => [
+                "The server is busy, please try again in a moment.",
+                "El servidor está ocupado, inténtalo de nuevo en un momento.",
+                "O servidor está ocupado, tente novamente em instantes.",
+                "Le serveur est occupé, veuillez réessayer dans un instant.",
+            ],
+            Self::ServerFull => [
+                "The server is full, please try again later.",
+                "El servidor está lleno, inténtalo de nuevo más tarde.",
+                "O servidor está cheio, tente novamente mais tarde.",
+                "Le serveur est plein, veuillez réessayer plus tard.",
+            ],
+            Self::AlreadyOnline => [
+                "This character is already online.",
+                "Este personaje ya está conectado.",
+                "Este personagem já está conectado.",
+                "Ce personnage est déjà connecté.",
+            ],
+            Self::ServerRestarting => [
+                "The server is restarting, please try again in a few minutes.",
+                "El servidor se está reiniciando, inténtalo de nuevo en unos minutos.",
+                "O servidor está reiniciando, tente novamente em alguns minutos.",
+                "Le serveur redémarre, veuillez réessayer dans quelques minutes.",
+            ],
+            Self::MaybeUnsupported => [
+                "Your client version may be unsupported, please update it.",
+                "Puede que tu versión del cliente no sea compatible, por favor actualízala.",
+                "Sua versão do cliente pode não ser suportada, por favor atualize-a.",
+                "Votre version du client n'est peut-être pas prise en charge, veuillez la mettre à jour.",
+            ],
+            Self::RegisterInvalid => [
+                "Register Invalid",
+                "Registro inválido",
+                "Registro inválido",
+                "Inscription invalide",
+            ],
+            Self::RegisterNameTaken => [
+                "Character name taken, try another one.",
+                "El nombre ya está en uso, prueba con otro.",
+                "Nome de personagem em uso, tente outro.",
+                "Ce nom est déjà pris, essayez-en un autre.",
+            ],
+            Self::RegisterNameNotAllowed => [
+                "This name is not allowed, try another one.",
+                "Este nombre no está permitido, prueba con otro.",
+                "Este nome não é permitido, tente outro.",
+                "Ce nom n'est pas autorisé, essayez-en un autre.",
+            ],
+            Self::RegisterCharacterLimit => [
+                "This account can not have any more characters.",
+                "Esta cuenta no puede tener más personajes.",
+                "Esta conta não pode ter mais personagens.",
+                "Ce compte ne peut pas avoir plus de personnages.",
+            ],
+        };
+        row[locale as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_locale_is_translated() {
+        let locale = Locale::from_language("Es");
+        assert_eq!(locale, Locale::Spanish);
+        assert_eq!(
+            SystemMessage::RegisterNameTaken.text(locale),
+            "El nombre ya está en uso, prueba con otro."
+        );
+        assert_eq!(Locale::from_language("pt-BR"), Locale::Portuguese);
+    }
+
+    #[test]
+    fn unknown_locale_falls_back_to_english() {
+        for language in ["Zh", "", "x"] {
+            let locale = Locale::from_language(language);
+            assert_eq!(locale, Locale::English);
+            assert_eq!(
+                SystemMessage::LoginInvalid.text(locale),
+                "Login Invalid"
+            );
+        }
+    }
+}
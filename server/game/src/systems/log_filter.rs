@@ -0,0 +1,90 @@
+use crate::Error;
+use tracing_subscriber::filter::EnvFilter;
+use tracing_subscriber::{reload, Registry};
+
+/// Changes which logs get through while the server runs, so chasing a bug
+/// does not need a restart.
+///
+/// New directives go on top of the current ones, using the same syntax as
+/// `RUST_LOG`, like `tq_network=trace`.
+#[derive(Debug, Clone)]
+pub struct LogFilter {
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogFilter {
+    pub fn new(handle: reload::Handle<EnvFilter, Registry>) -> Self {
+        Self { handle }
+    }
+
+    /// The directives in use right now.
+    pub fn current(&self) -> Result<String, Error> {
+        self.handle
+            .with_current(|filter| filter.to_string())
+            .map_err(|e| Error::Other(e.to_string()))
+    }
+
+    /// Adds the directives to the filter, invalid ones are rejected and the
+    /// filter stays as it was.
+    pub fn add(&self, directives: &str) -> Result<(), Error> {
+        let invalid = |e| Error::Other(format!("Invalid log filter: {e}"));
+        EnvFilter::try_new(directives).map_err(invalid)?;
+        let merged = format!("{},{directives}", self.current()?);
+        let filter = EnvFilter::try_new(merged).map_err(invalid)?;
+        self.handle
+            .reload(filter)
+            .map_err(|e| Error::Other(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::prelude::*;
+
+    /// Keeps whatever gets logged, so the test could look at it.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    impl Captured {
+        fn contains(&self, s: &str) -> bool {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).contains(s)
+        }
+    }
+
+    #[test]
+    fn filter_changes_at_runtime() {
+        let (layer, handle) = reload::Layer::new(EnvFilter::new("info"));
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::registry().with(layer).with(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(move || writer.clone()),
+        );
+        let filter = LogFilter::new(handle);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::debug!(target: "tq_network", "before the change");
+            assert!(filter.add("tq_network=debug=oops").is_err());
+            tracing::debug!(target: "tq_network", "after a bad change");
+            filter.add("tq_network=debug").unwrap();
+            tracing::debug!(target: "tq_network", "after the change");
+            tracing::debug!(target: "tq_db", "somewhere else");
+        });
+        assert!(!captured.contains("before the change"));
+        assert!(!captured.contains("after a bad change"));
+        assert!(captured.contains("after the change"));
+        assert!(!captured.contains("somewhere else"));
+    }
+}
@@ -0,0 +1,169 @@
+use crate::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// How many logins load their character at the same time by default.
+pub const LOGIN_CONCURRENCY: usize = 32;
+
+/// How many logins could wait for their turn by default, the ones after them
+/// are told the server is busy.
+pub const LOGIN_QUEUE_LIMIT: usize = 512;
+
+/// How long a login waits for its turn before it is told the server is busy.
+pub const LOGIN_QUEUE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Admits the logins into the world a few at a time, so the database does not
+/// get flooded when everyone reconnects after a restart, and turns them away
+/// once the world is full.
+///
+/// It could be configured using the `LOGIN_CONCURRENCY`, `LOGIN_QUEUE_LIMIT`
+/// and `MAX_ONLINE` environment variables.
+#[derive(Debug)]
+pub struct LoginGate {
+    permits: Semaphore,
+    /// How many logins wait for their turn.
+    waiting: AtomicUsize,
+    queue_limit: usize,
+    timeout: Duration,
+    /// How many players could be online at once, `None` for no limit.
+    max_online: Option<usize>,
+}
+
+impl Default for LoginGate {
+    fn default() -> Self { Self::new(LOGIN_CONCURRENCY, LOGIN_QUEUE_LIMIT) }
+}
+
+impl LoginGate {
+    pub fn new(concurrency: usize, queue_limit: usize) -> Self {
+        Self {
+            permits: Semaphore::new(concurrency),
+            waiting: AtomicUsize::new(0),
+            queue_limit,
+            timeout: LOGIN_QUEUE_TIMEOUT,
+            max_online: None,
+        }
+    }
+
+    /// Loads the limits from the environment, falling back to the defaults
+    /// for any of them that is not configured.
+    pub fn from_env() -> Result<Self, Error> {
+        let mut concurrency = LOGIN_CONCURRENCY;
+        let mut queue_limit = LOGIN_QUEUE_LIMIT;
+        if let Ok(v) = dotenvy::var("LOGIN_CONCURRENCY") {
+            concurrency = v.trim().parse()?;
+        }
+        if let Ok(v) = dotenvy::var("LOGIN_QUEUE_LIMIT") {
+            queue_limit = v.trim().parse()?;
+        }
+        let mut gate = Self::new(concurrency, queue_limit);
+        if let Ok(v) = dotenvy::var("MAX_ONLINE") {
+            gate.max_online = Some(v.trim().parse()?);
+        }
+        Ok(gate)
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_max_online(mut self, max_online: usize) -> Self {
+        self.max_online = Some(max_online);
+        self
+    }
+
+    /// Whether there is no room left for another player, with `online` of
+    /// them in the world already.
+    pub fn is_full(&self, online: usize) -> bool {
+        self.max_online.is_some_and(|max| online >= max)
+    }
+
+    /// How many logins wait for their turn.
+    pub fn waiting(&self) -> usize { self.waiting.load(Ordering::Relaxed) }
+
+    /// Waits for the turn of a login, `None` if the queue is full or the turn
+    /// did not come in time. The login goes on as long as the permit is held.
+    pub async fn admit(&self) -> Option<SemaphorePermit<'_>> {
+        if let Ok(permit) = self.permits.try_acquire() {
+            return Some(permit);
+        }
+        let queued = Queued::join(&self.waiting);
+        if queued.ahead >= self.queue_limit {
+            return None;
+        }
+        tokio::time::timeout(self.timeout, self.permits.acquire())
+            .await
+            .ok()?
+            .ok()
+    }
+}
+
+/// A place in the queue, left once dropped.
+struct Queued<'a> {
+    waiting: &'a AtomicUsize,
+    /// How many were waiting before this one.
+    ahead: usize,
+}
+
+impl<'a> Queued<'a> {
+    fn join(waiting: &'a AtomicUsize) -> Self {
+        let ahead = waiting.fetch_add(1, Ordering::Relaxed);
+        Self { waiting, ahead }
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) { self.waiting.fetch_sub(1, Ordering::Relaxed); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[tokio::test]
+    async fn logins_within_the_cap_go_right_in() {
+        let gate = LoginGate::new(2, 0);
+        let first = gate.admit().now_or_never().flatten();
+        let second = gate.admit().now_or_never().flatten();
+        assert!(first.is_some() && second.is_some());
+        drop(first);
+        assert!(gate.admit().now_or_never().flatten().is_some());
+    }
+
+    #[tokio::test]
+    async fn excess_logins_are_queued_then_turned_away() {
+        let gate = LoginGate::new(1, 1);
+        let first = gate.admit().await;
+        assert!(first.is_some());
+
+        // The second one waits for the first to be done.
+        let mut second = Box::pin(gate.admit());
+        assert!((&mut second).now_or_never().is_none());
+        assert_eq!(gate.waiting(), 1);
+        // There is no room left in the queue for a third.
+        assert!(gate.admit().await.is_none());
+        assert_eq!(gate.waiting(), 1);
+
+        drop(first);
+        assert!(second.await.is_some());
+        assert_eq!(gate.waiting(), 0);
+    }
+
+    #[test]
+    fn full_world_is_only_with_a_limit() {
+        assert!(!LoginGate::default().is_full(usize::MAX));
+        let gate = LoginGate::default().with_max_online(2);
+        assert!(!gate.is_full(1));
+        assert!(gate.is_full(2));
+    }
+
+    #[tokio::test]
+    async fn queued_logins_give_up_in_time() {
+        let gate = LoginGate::new(1, 8).with_timeout(Duration::from_millis(50));
+        let _first = gate.admit().await;
+        assert!(gate.admit().await.is_none());
+        assert_eq!(gate.waiting(), 0);
+    }
+}
@@ -0,0 +1,211 @@
+use crate::entities::Character;
+use crate::packets::{
+    ActionType, AttributeKind, InteractionType, MsgAction, MsgInteract,
+    MsgTalk, MsgUserAttrib, TalkChannel,
+};
+use crate::systems::XpSkill;
+use crate::{Error, State};
+use std::time::Instant;
+
+/// How far a spell could reach.
+pub const MAGIC_RANGE: u16 = 18;
+
+/// The health points a heal restores.
+pub const HEAL_AMOUNT: u16 = 100;
+
+/// The mana points a heal costs.
+pub const HEAL_MANA_COST: u16 = 30;
+
+/// Why a spell did not get cast, nothing gets used up when it does not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MagicRejection {
+    TargetNotFound,
+    OutOfRange,
+    NotEnoughMana,
+    /// The XP circle is not full yet.
+    NotEnoughXp,
+    /// The caster is dead, frozen or busy with something else.
+    CannotCast,
+    /// The target is dead, or busy trading or vending.
+    InvalidTarget,
+}
+
+impl MagicRejection {
+    /// What the player gets told.
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::TargetNotFound => "Target not found.",
+            Self::OutOfRange => "The target is too far away.",
+            Self::NotEnoughMana => "You do not have enough mana.",
+            Self::NotEnoughXp => "Your XP circle is not full yet.",
+            Self::CannotCast => "You can not cast spells right now.",
+            Self::InvalidTarget => "The spell can not be cast on the target.",
+        }
+    }
+}
+
+/// Casts the XP skill with that magic type on `me`, using up the full XP
+/// circle.
+///
+/// Unknown magic types are ignored, there are no other skills yet.
+#[tracing::instrument(skip(me), fields(me = me.id()))]
+pub async fn cast_xp_skill(
+    me: &Character,
+    magic_type: u32,
+) -> Result<(), Error> {
+    let Some(skill) = XpSkill::from_magic_type(magic_type) else {
+        tracing::debug!("Unknown magic type");
+        return Ok(());
+    };
+    if !me.state().can_attack() {
+        return tell(me, MagicRejection::CannotCast).await;
+    }
+    if !me.use_xp_skill(skill, Instant::now()).await? {
+        return tell(me, MagicRejection::NotEnoughXp).await;
+    }
+    let msg = MsgAction::new(me.id(), 0, 0, 0, ActionType::XpClear);
+    me.owner().send(msg).await?;
+    Ok(())
+}
+
+/// Heals `target_id` for [`HEAL_AMOUNT`] health points, for
+/// [`HEAL_MANA_COST`] mana points of `me`. Characters could heal themselves.
+///
+/// The heal is shown to everyone around.
+#[tracing::instrument(skip(state, me), fields(me = me.id()))]
+pub async fn heal(
+    state: &State,
+    me: &Character,
+    target_id: u32,
+) -> Result<(), Error> {
+    let target = state
+        .entity(target_id)
+        .filter(|e| e.basic().map_id() == me.entity().map_id());
+    let Some(target) = target.as_ref().and_then(|e| e.as_character()) else {
+        return tell(me, MagicRejection::TargetNotFound).await;
+    };
+    if !me.state().can_attack() {
+        return tell(me, MagicRejection::CannotCast).await;
+    }
+    if !target.state().can_be_attacked() {
+        return tell(me, MagicRejection::InvalidTarget).await;
+    }
+    let (a, b) = (me.entity().location(), target.entity().location());
+    let (from, to) = ((a.x, a.y), (b.x, b.y));
+    if !tq_math::in_range(from, to, MAGIC_RANGE) {
+        return tell(me, MagicRejection::OutOfRange).await;
+    }
+    let Some(mana) = me.spend_mana(HEAL_MANA_COST) else {
+        return tell(me, MagicRejection::NotEnoughMana).await;
+    };
+    let msg = MsgUserAttrib::single(me.id(), AttributeKind::Mana, mana as u64);
+    me.owner().send(msg).await?;
+
+    let mut hp = target.hp();
+    let before = hp.current();
+    hp.increment(HEAL_AMOUNT);
+    target.entity().set_hp(hp);
+    let healed = hp.current() - before;
+    let msg = MsgUserAttrib::single(
+        target.id(),
+        AttributeKind::Health,
+        hp.current() as u64,
+    );
+    target.owner().send(msg).await?;
+    tracing::trace!(%healed, target = target.id(), "Healed");
+    let msg = MsgInteract::new(
+        me.id(),
+        target.id(),
+        to,
+        InteractionType::Heal,
+        healed as u32,
+    );
+    me.owner().send(msg.clone()).await?;
+    me.try_screen()?.send_message(msg).await?;
+    Ok(())
+}
+
+async fn tell(me: &Character, rejection: MagicRejection) -> Result<(), Error> {
+    let msg = MsgTalk::from_system(
+        me.id(),
+        TalkChannel::TopLeft,
+        rejection.message(),
+    );
+    me.owner().send(msg).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants;
+    use crate::systems::ItemEffect;
+    use crate::test_utils::*;
+    use futures::FutureExt;
+    use primitives::{Location, Size};
+
+    #[tokio::test]
+    async fn heal_costs_mana() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, _)] = actors;
+                let (a_entity, b_entity) = (a.entity(), b.entity());
+                let me = a_entity.as_character().unwrap();
+                let target = b_entity.as_character().unwrap();
+                let map = state.try_map(1010)?;
+                map.load_blank(Size::new(100, 100)).await?;
+                for (c, x) in [(me, 20), (target, 50)] {
+                    c.entity()
+                        .set_map_id(1010)
+                        .set_location(Location::new(x, 40, 0));
+                }
+                let mut hp = target.hp();
+                hp.set(1);
+                target.entity().set_hp(hp);
+
+                heal(&state, me, target.id()).await?;
+                let told = packets_of::<MsgTalk>(&mut a_rx);
+                assert_eq!(
+                    told[0].message,
+                    MagicRejection::OutOfRange.message()
+                );
+
+                target.entity().set_location(Location::new(30, 40, 0));
+                heal(&state, me, target.id()).await?;
+                let told = packets_of::<MsgTalk>(&mut a_rx);
+                assert_eq!(
+                    told[0].message,
+                    MagicRejection::NotEnoughMana.message()
+                );
+                assert_eq!(target.hp().current(), 1);
+
+                me.entity().set_level(50);
+                let points =
+                    constants::base_attribute_points(me.current_class(), 50)
+                        + me.rebirth_points();
+                me.apply_allotment([0, 0, 0, points])?;
+                me.apply_item_effect(ItemEffect::Heal {
+                    hp: 0,
+                    mp: u16::MAX,
+                })
+                .await?;
+                let mana = me.mana_points();
+                assert!(mana >= HEAL_MANA_COST);
+
+                heal(&state, me, target.id()).await?;
+                assert_eq!(me.mana_points(), mana - HEAL_MANA_COST);
+                let expected = (1 + HEAL_AMOUNT).min(target.hp().max);
+                assert_eq!(target.hp().current(), expected);
+                let heals = packets_of::<MsgInteract>(&mut a_rx);
+                assert_eq!(
+                    InteractionType::from(heals[0].action),
+                    InteractionType::Heal
+                );
+                assert_eq!(heals[0].data, (expected - 1) as u32);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
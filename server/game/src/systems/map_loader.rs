@@ -0,0 +1,289 @@
+//! Loading the maps ahead of the players, so the first one into a big map does
+//! not wait for it to be parsed under their login or their step through a
+//! portal.
+
+use crate::world::Map;
+use crate::{Error, State};
+use futures::stream::{self, StreamExt};
+use parking_lot::Mutex;
+use std::collections::BTreeSet;
+use tokio::sync::Notify;
+
+/// The maps that are always in memory by default: Twin City, the market and
+/// the newbie village.
+pub const RESIDENT_MAPS: [u32; 3] = [1002, 1036, 1010];
+
+/// How many maps get loaded at the same time on startup by default.
+pub const MAP_PRELOAD_CONCURRENCY: usize = 4;
+
+/// How close to a portal a character gets before the map on the other side
+/// starts loading.
+pub const PORTAL_PREWARM_DISTANCE: u16 = 6;
+
+/// Keeps the resident maps in memory and loads the others in the background
+/// when asked to, see [`State::request_map_load`].
+///
+/// The resident maps and how many of them load at once could be configured
+/// using the `RESIDENT_MAPS` and `MAP_PRELOAD_CONCURRENCY` environment
+/// variables.
+#[derive(Debug)]
+pub struct MapLoader {
+    resident: Vec<u32>,
+    concurrency: usize,
+    /// The maps asked for and not loaded yet.
+    requested: Mutex<BTreeSet<u32>>,
+    wakeup: Notify,
+}
+
+impl Default for MapLoader {
+    fn default() -> Self {
+        Self::new(RESIDENT_MAPS.to_vec(), MAP_PRELOAD_CONCURRENCY)
+    }
+}
+
+impl MapLoader {
+    pub fn new(resident: Vec<u32>, concurrency: usize) -> Self {
+        Self {
+            resident,
+            concurrency: concurrency.max(1),
+            requested: Default::default(),
+            wakeup: Notify::new(),
+        }
+    }
+
+    /// Loads the configuration from the environment, `RESIDENT_MAPS` is a
+    /// comma separated list of map ids, empty to keep none in memory.
+    pub fn from_env() -> Result<Self, Error> {
+        let mut resident = RESIDENT_MAPS.to_vec();
+        let mut concurrency = MAP_PRELOAD_CONCURRENCY;
+        if let Ok(v) = dotenvy::var("RESIDENT_MAPS") {
+            resident = v
+                .split(',')
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(str::parse)
+                .collect::<Result<_, _>>()?;
+        }
+        if let Ok(v) = dotenvy::var("MAP_PRELOAD_CONCURRENCY") {
+            concurrency = v.trim().parse()?;
+        }
+        Ok(Self::new(resident, concurrency))
+    }
+
+    /// The maps that are always in memory.
+    pub fn resident(&self) -> &[u32] { &self.resident }
+
+    /// Asks for the map to be loaded in the background, it does nothing if
+    /// the map is already asked for.
+    pub fn request(&self, map_id: u32) {
+        if self.requested.lock().insert(map_id) {
+            self.wakeup.notify_one();
+        }
+    }
+
+    /// Loads the resident maps, a few at a time, and keeps them in memory
+    /// from now on. A map that fails to load is skipped with a warning.
+    #[tracing::instrument(skip_all)]
+    pub async fn preload(&self, state: &State) {
+        let maps = self.resident.iter().filter_map(|id| {
+            let map = state.map_by_id(*id);
+            if map.is_none() {
+                tracing::warn!(map_id = %id, "Unknown resident map");
+            }
+            map
+        });
+        stream::iter(maps)
+            .for_each_concurrent(self.concurrency, |map| async move {
+                map.set_resident(true);
+                load(&map).await;
+            })
+            .await;
+        tracing::info!(maps = self.resident.len(), "Resident maps loaded");
+    }
+
+    /// Loads the maps asked for so far, and returns how many there were.
+    pub async fn load_requested(&self, state: &State) -> usize {
+        let requested = std::mem::take(&mut *self.requested.lock());
+        let maps = requested.iter().filter_map(|id| state.map_by_id(*id));
+        stream::iter(maps)
+            .for_each_concurrent(self.concurrency, |map| async move {
+                load(&map).await
+            })
+            .await;
+        requested.len()
+    }
+
+    /// Loads the maps as they are asked for, forever.
+    pub async fn run(&self, state: &State) {
+        loop {
+            self.wakeup.notified().await;
+            self.load_requested(state).await;
+        }
+    }
+}
+
+async fn load(map: &Map) {
+    if let Err(error) = map.load().await {
+        tracing::warn!(%error, map_id = map.id(), "Failed to load the map");
+    }
+}
+
+/// Asks for the maps behind the portals close to `(x, y)` to be loaded, so
+/// they are ready by the time the character steps through.
+pub fn prewarm_portals(state: &State, map: &Map, (x, y): (u16, u16)) {
+    let near = map.portals().iter().filter(|p| {
+        tq_math::in_range(
+            (x, y),
+            (p.from_x(), p.from_y()),
+            PORTAL_PREWARM_DISTANCE,
+        )
+    });
+    for portal in near {
+        let to_map_id = super::resolve_portal(state, map, portal.to_map_id());
+        let unloaded =
+            state.map_by_id(to_map_id).is_some_and(|map| !map.loaded());
+        if unloaded {
+            state.request_map_load(to_map_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::{ActionType, MsgAction};
+    use crate::systems::TileAccess;
+    use crate::test_utils::*;
+    use crate::utils::LoHi;
+    use crate::world::Maps;
+    use futures::FutureExt;
+    use primitives::{Location, Size};
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use tq_network::PacketProcess;
+
+    /// Writes a walkable compressed map of the given size, and points the map
+    /// at it in the database.
+    async fn write_map(
+        state: &State,
+        map_id: u32,
+        size: Size<i32>,
+    ) -> Result<PathBuf, Error> {
+        let name = format!("coemu-{map_id}-{}.cmap", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        let mut bytes = Vec::new();
+        bytes.extend(size.width.to_le_bytes());
+        bytes.extend(size.height.to_le_bytes());
+        for _ in 0..size.area() {
+            bytes.push(TileAccess::Available as u8);
+            bytes.extend(0u16.to_le_bytes());
+        }
+        tokio::fs::write(&path, bytes).await?;
+        sqlx::query("UPDATE maps SET path = ? WHERE id = ?;")
+            .bind(path.display().to_string())
+            .bind(map_id as i32)
+            .execute(state.pool())
+            .await?;
+        Ok(path)
+    }
+
+    #[tokio::test]
+    async fn resident_maps_are_loaded_after_init() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, _| {
+            async move {
+                let resident =
+                    [u32::from(Maps::Newplain), u32::from(Maps::Street)];
+                let mut files = Vec::new();
+                for map_id in resident {
+                    files.push(
+                        write_map(&state, map_id, Size::new(100, 100)).await?,
+                    );
+                }
+                // Starts again with the maps pointing at the files.
+                let mut state = State::with_pool(state.pool().clone()).await?;
+                state.set_map_loader(MapLoader::new(resident.to_vec(), 2));
+                state.post_init().await?;
+                for map_id in resident {
+                    let map = state.try_map(map_id)?;
+                    assert!(map.loaded() && map.resident());
+                    // Still there once the last one on it leaves.
+                    map.remove_entity_by_id_and_location(
+                        42,
+                        Location::new(50, 50, 0),
+                    )?;
+                    assert!(map.loaded());
+                }
+                assert!(!state.try_map(u32::from(Maps::Forum))?.loaded());
+                for path in files {
+                    let _ = tokio::fs::remove_file(path).await;
+                }
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn approaching_a_portal_loads_the_map_behind_it() -> Result<(), Error>
+    {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                // The portal at (401, 387) leads into the forum.
+                let plain = u32::from(Maps::Newplain);
+                let forum = u32::from(Maps::Forum);
+                let file =
+                    write_map(&state, forum, Size::new(100, 100)).await?;
+                let state = State::with_pool(state.pool().clone()).await?;
+                state
+                    .try_map(plain)?
+                    .load_blank(Size::new(500, 500))
+                    .await?;
+                let [(a, _), _] = actors;
+                let e = a.entity();
+                e.basic().set_map_id(plain);
+                e.basic().set_location(Location::new(401, 370, 0));
+                state.insert_entity(e.clone());
+                state.try_map(plain)?.insert_entity(e.clone()).await?;
+                let me = e.as_character().unwrap();
+                let step = |x: u16, y: u16| {
+                    let xy = u32::constract(y, x);
+                    MsgAction::new(me.id(), xy, xy, 0, ActionType::Jump)
+                };
+
+                // Too far to bother yet.
+                me.move_to(&state, (401, 378), 0, step(401, 378)).await?;
+                assert_eq!(state.map_loader().load_requested(&state).await, 0);
+
+                me.move_to(&state, (401, 382), 0, step(401, 382)).await?;
+                let map = state.try_map(forum)?;
+                let loaded = async {
+                    while !map.loaded() {
+                        tokio::time::sleep(Duration::from_millis(5)).await;
+                    }
+                };
+                let loader = state.map_loader().run(&state);
+                let waited =
+                    tokio::time::timeout(Duration::from_secs(5), async {
+                        tokio::select! {
+                            _ = loader => {},
+                            _ = loaded => {},
+                        }
+                    });
+                waited.await.expect("the forum was not loaded in time");
+
+                // Then the step through the portal lands on a loaded map.
+                me.set_position(401, 387);
+                let xy = u32::constract(387, 401);
+                MsgAction::new(me.id(), xy, 0, 0, ActionType::ChangeMap)
+                    .process(&state, &a)
+                    .await?;
+                assert_eq!(me.entity().map_id(), forum);
+                let _ = tokio::fs::remove_file(file).await;
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
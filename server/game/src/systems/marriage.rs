@@ -0,0 +1,341 @@
+use crate::entities::{with_two_characters, Character};
+use crate::packets::{
+    MsgInteract, MsgName, MsgTalk, MsgTaskDialog, TalkChannel,
+};
+use crate::{Error, State};
+use tq_db::character::Character as CharacterRow;
+
+/// How close two characters have to stand for one to propose to the other.
+pub const MARRIAGE_RANGE: u16 = 5;
+
+/// The Matchmaker, the NPC that divorces married characters.
+pub const MATCHMAKER_NPC: u32 = 30000;
+
+/// The dialog option of the Matchmaker that asks for a divorce.
+pub const DIVORCE_OPTION: u8 = 1;
+
+/// Why a marriage proposal was refused, nothing changes when it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarriageRejection {
+    TargetNotFound,
+    SameCharacter,
+    AlreadyMarried,
+    TargetMarried,
+    TooFar,
+    NoProposal,
+}
+
+impl MarriageRejection {
+    /// What the player gets told.
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::TargetNotFound => "That player is not around.",
+            Self::SameCharacter => "You can not marry yourself.",
+            Self::AlreadyMarried => "You are already married.",
+            Self::TargetMarried => "That player is already married.",
+            Self::TooFar => "You have to stand closer to propose.",
+            Self::NoProposal => "Nobody proposed to you.",
+        }
+    }
+}
+
+/// Checks whether `me` could marry `target` right now.
+pub fn can_marry(
+    me: &Character,
+    target: &Character,
+) -> Result<(), MarriageRejection> {
+    if me.id() == target.id() {
+        return Err(MarriageRejection::SameCharacter);
+    }
+    if me.is_married() {
+        return Err(MarriageRejection::AlreadyMarried);
+    }
+    if target.is_married() {
+        return Err(MarriageRejection::TargetMarried);
+    }
+    let (mine, theirs) = (me.entity(), target.entity());
+    let (a, b) = (mine.location(), theirs.location());
+    if mine.map_id() != theirs.map_id()
+        || !tq_math::in_range((a.x, a.y), (b.x, b.y), MARRIAGE_RANGE)
+    {
+        return Err(MarriageRejection::TooFar);
+    }
+    Ok(())
+}
+
+/// Sends `target_id` a proposal from `me`, their client asks them whether
+/// they accept it.
+#[tracing::instrument(skip(state, me), fields(me = me.id()))]
+pub async fn propose(
+    state: &State,
+    me: &Character,
+    target_id: u32,
+) -> Result<(), Error> {
+    let Some(entity) = state.entity(target_id) else {
+        return tell(me, MarriageRejection::TargetNotFound.message()).await;
+    };
+    let Some(target) = entity.as_character() else {
+        return tell(me, MarriageRejection::TargetNotFound.message()).await;
+    };
+    if let Err(rejection) = can_marry(me, target) {
+        return tell(me, rejection.message()).await;
+    }
+    target.set_suitor(me.id());
+    target.owner().send(MsgInteract::court(me, target)).await?;
+    Ok(())
+}
+
+/// Answers the proposal `suitor_id` made to `me`, marrying both if it got
+/// accepted.
+#[tracing::instrument(skip(state, me), fields(me = me.id()))]
+pub async fn answer(
+    state: &State,
+    me: &Character,
+    suitor_id: u32,
+    accepted: bool,
+) -> Result<(), Error> {
+    if me.take_suitor() != Some(suitor_id) {
+        tracing::warn!("Answered a proposal that was never made");
+        return tell(me, MarriageRejection::NoProposal.message()).await;
+    }
+    let Some(entity) = state.entity(suitor_id) else {
+        return tell(me, MarriageRejection::TargetNotFound.message()).await;
+    };
+    let Some(suitor) = entity.as_character() else {
+        return tell(me, MarriageRejection::TargetNotFound.message()).await;
+    };
+    if !accepted {
+        let msg = format!("{} turned your proposal down.", me.entity().name());
+        return tell(suitor, msg).await;
+    }
+    // Things could have changed since the proposal.
+    if let Err(rejection) = can_marry(me, suitor) {
+        return tell(me, rejection.message()).await;
+    }
+    let my_name = me.entity().name().to_string();
+    let suitor_name = suitor.entity().name().to_string();
+    let married = CharacterRow::marry(
+        state.pool(),
+        (me.character_id(), &my_name),
+        (suitor.character_id(), &suitor_name),
+    )
+    .await?;
+    if !married {
+        return tell(me, MarriageRejection::TargetMarried.message()).await;
+    }
+    with_two_characters(me, suitor, |mine, theirs| {
+        mine.spouse = suitor_name.clone();
+        theirs.spouse = my_name.clone();
+    });
+    tracing::info!(%my_name, %suitor_name, "Characters got married");
+    show_spouse(me).await?;
+    show_spouse(suitor).await?;
+    Ok(())
+}
+
+/// The dialog the Matchmaker greets `me` with.
+pub fn matchmaker_dialog(me: &Character) -> Vec<MsgTaskDialog> {
+    let builder = MsgTaskDialog::builder();
+    let builder = if me.is_married() {
+        builder
+            .text(format!(
+                "You are married to {}, are you sure you want a divorce?",
+                me.spouse()
+            ))
+            .with_option(DIVORCE_OPTION, "Yes, I want a divorce.")
+            .with_option(u8::MAX, "No, I changed my mind.")
+    } else {
+        builder
+            .text("Love is in the air, come back when you are married.")
+            .with_option(u8::MAX, "Thanks.")
+    };
+    builder.and().with_avatar(47).build()
+}
+
+/// Divorces `me` from its spouse, clearing both sides whether the spouse is
+/// online or not.
+#[tracing::instrument(skip(state, me), fields(me = me.id()))]
+pub async fn divorce(state: &State, me: &Character) -> Result<(), Error> {
+    let Some(former) =
+        CharacterRow::divorce(state.pool(), me.character_id()).await?
+    else {
+        return tell(me, "You are not married.").await;
+    };
+    me.set_spouse(CharacterRow::NO_SPOUSE);
+    show_spouse(me).await?;
+    let online = state.character_by_name(&former);
+    let spouse = online.as_ref().and_then(|e| e.as_character());
+    if let Some(c) = spouse.filter(|c| c.is_married()) {
+        c.set_spouse(CharacterRow::NO_SPOUSE);
+        show_spouse(c).await?;
+        let msg = format!("{} divorced you.", me.entity().name());
+        tell(c, msg).await?;
+    }
+    tracing::info!(%former, "Characters got divorced");
+    Ok(())
+}
+
+/// Shows the current spouse of the character to it and everyone around.
+async fn show_spouse(c: &Character) -> Result<(), Error> {
+    let msg = MsgName::spouse(c.id(), &c.spouse());
+    c.owner().send(msg.clone()).await?;
+    if let Ok(screen) = c.try_screen() {
+        screen.send_message(msg).await?;
+    }
+    Ok(())
+}
+
+async fn tell(c: &Character, message: impl Into<String>) -> Result<(), Error> {
+    let msg = MsgTalk::from_system(c.id(), TalkChannel::TopLeft, message);
+    c.owner().send(msg).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::InteractionType;
+    use crate::test_utils::*;
+    use crate::ActorState;
+    use futures::FutureExt;
+    use tq_network::{Actor, PacketProcess};
+
+    async fn interact(
+        state: &State,
+        actor: &Actor<ActorState>,
+        target: &Actor<ActorState>,
+        action: InteractionType,
+        data: u32,
+    ) -> Result<(), Error> {
+        let msg = MsgInteract::new(
+            actor.entity().id(),
+            target.entity().id(),
+            (0, 0),
+            action,
+            data,
+        );
+        msg.process(state, actor).await
+    }
+
+    async fn spouse_in_db(state: &State, c: &Character) -> String {
+        CharacterRow::by_id(state.pool(), c.character_id())
+            .await
+            .unwrap()
+            .spouse
+    }
+
+    #[tokio::test]
+    async fn proposal_handshake() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, mut b_rx)] = actors;
+                let (a_entity, b_entity) = (a.entity(), b.entity());
+                let me = a_entity.as_character().unwrap();
+                let other = b_entity.as_character().unwrap();
+
+                interact(&state, &a, &b, InteractionType::Court, 0).await?;
+                let proposals = packets_of::<MsgInteract>(&mut b_rx);
+                assert_eq!(proposals.len(), 1);
+                assert_eq!(proposals[0].sender_id, me.id());
+                assert_eq!(
+                    InteractionType::from(proposals[0].action),
+                    InteractionType::Court
+                );
+
+                interact(&state, &b, &a, InteractionType::Marry, 1).await?;
+                assert_eq!(me.spouse(), "test2");
+                assert_eq!(other.spouse(), "test1");
+                assert_eq!(spouse_in_db(&state, me).await, "test2");
+                assert_eq!(spouse_in_db(&state, other).await, "test1");
+                for (rx, id, spouse) in [
+                    (&mut a_rx, me.id(), "test2"),
+                    (&mut b_rx, other.id(), "test1"),
+                ] {
+                    let names = packets_of::<MsgName>(rx);
+                    assert_eq!(names.len(), 1);
+                    assert_eq!(names[0].data, id);
+                    assert_eq!(names[0].names.as_vec(), &[spouse]);
+                }
+
+                // Married characters could not propose anymore.
+                interact(&state, &b, &a, InteractionType::Court, 0).await?;
+                assert!(packets_of::<MsgInteract>(&mut a_rx).is_empty());
+                let told = packets_of::<MsgTalk>(&mut b_rx);
+                assert_eq!(
+                    told[0].message,
+                    MarriageRejection::AlreadyMarried.message()
+                );
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn refused_proposal() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, _b_rx)] = actors;
+                let (a_entity, b_entity) = (a.entity(), b.entity());
+                let me = a_entity.as_character().unwrap();
+                let other = b_entity.as_character().unwrap();
+
+                // Proposing to ourselves does nothing.
+                interact(&state, &a, &a, InteractionType::Court, 0).await?;
+                assert!(packets_of::<MsgInteract>(&mut a_rx).is_empty());
+
+                interact(&state, &a, &b, InteractionType::Court, 0).await?;
+                interact(&state, &b, &a, InteractionType::Marry, 0).await?;
+                let told = packets_of::<MsgTalk>(&mut a_rx);
+                assert_eq!(told.len(), 1);
+                assert!(told[0].message.contains("turned your proposal down"));
+                assert!(!me.is_married() && !other.is_married());
+                assert_eq!(spouse_in_db(&state, me).await, "None");
+
+                // The proposal is gone once answered.
+                interact(&state, &b, &a, InteractionType::Marry, 1).await?;
+                assert!(!me.is_married() && !other.is_married());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn divorce_clears_both_sides() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, _b_rx)] = actors;
+                let (a_entity, b_entity) = (a.entity(), b.entity());
+                let me = a_entity.as_character().unwrap();
+                let other = b_entity.as_character().unwrap();
+                interact(&state, &a, &b, InteractionType::Court, 0).await?;
+                interact(&state, &b, &a, InteractionType::Marry, 1).await?;
+                assert!(me.is_married());
+                packets_of::<MsgName>(&mut a_rx);
+
+                // The spouse logged out.
+                state.remove_entity(other.id());
+                divorce(&state, me).await?;
+                assert!(!me.is_married());
+                assert_eq!(spouse_in_db(&state, me).await, "None");
+                assert_eq!(spouse_in_db(&state, other).await, "None");
+                let names = packets_of::<MsgName>(&mut a_rx);
+                assert_eq!(names.len(), 1);
+                assert_eq!(names[0].names.as_vec(), &["None"]);
+
+                // Nothing left to divorce.
+                assert_eq!(
+                    CharacterRow::divorce(state.pool(), me.character_id())
+                        .await?,
+                    None
+                );
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
@@ -4,4 +4,118 @@ pub use floor::*;
 mod screen;
 pub use screen::*;
 
+mod fan_out;
+pub use fan_out::*;
+
+mod drops;
+pub use drops::*;
+
+mod consumables;
+pub use consumables::*;
+
+mod experience;
+pub use experience::*;
+
+mod movement;
+pub use movement::*;
+
+mod xp;
+pub use xp::*;
+
+mod status_effects;
+pub use status_effects::*;
+
+mod starter_kit;
+pub use starter_kit::*;
+
+mod upgrades;
+pub use upgrades::*;
+
+mod combat;
+pub use combat::*;
+
+mod cooldowns;
+pub use cooldowns::*;
+
+mod vending;
+pub use vending::*;
+
+mod marriage;
+pub use marriage::*;
+
+mod rebirth;
+pub use rebirth::*;
+
+mod guild;
+pub use guild::*;
+
+mod scripts;
+pub use scripts::*;
+
+mod magic;
+pub use magic::*;
+
+mod client_versions;
+pub use client_versions::*;
+
+mod login_gate;
+pub use login_gate::*;
+
+mod audit;
+pub use audit::*;
+
+mod scheduler;
+pub use scheduler::*;
+
+mod restart;
+pub use restart::*;
+
+mod offline_messages;
+pub use offline_messages::*;
+
+mod enemies;
+pub use enemies::*;
+
+mod map_loader;
+pub use map_loader::*;
+
+mod instances;
+pub use instances::*;
+
+mod server_info;
+pub use server_info::*;
+
+mod rng;
+pub use rng::*;
+
+mod warehouse;
+pub use warehouse::*;
+
+mod storage;
+pub use storage::*;
+
+mod placement;
+pub use placement::*;
+
+mod equipment;
+pub use equipment::*;
+
+mod weight;
+pub use weight::*;
+
+mod afk;
+pub use afk::*;
+
+mod log_filter;
+pub use log_filter::*;
+
+mod locale;
+pub use locale::*;
+
+mod monster_types;
+pub use monster_types::*;
+
+mod name_filter;
+pub use name_filter::*;
+
 pub mod commands;
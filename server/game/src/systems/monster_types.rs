@@ -0,0 +1,266 @@
+//! The table of monster types, read from the `MonsterTypes.ini` file in the
+//! data directory.
+//!
+//! Every type is a section named after its id, with its stats as `key=value`
+//! lines:
+//!
+//! ```ini
+//! [1]
+//! Name=Pheasant
+//! Lookface=104
+//! Level=1
+//! Life=33
+//! AttackRange=1
+//! MoveSpeed=1000
+//! ViewRange=10
+//! ```
+//!
+//! `Name`, `Lookface`, `Level` and `Life` are required, types missing any of
+//! them are skipped. The others fall back to the [`MonsterStats`] defaults.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::RwLock;
+
+use crate::entities::{MonsterStats, MonsterType};
+use crate::Error;
+
+/// Every monster type by its id.
+#[derive(Debug, Default)]
+pub struct MonsterTypes {
+    types: RwLock<HashMap<u32, Arc<MonsterType>>>,
+}
+
+impl MonsterTypes {
+    /// Loads the types from the `MONSTER_TYPES_LOCATION` file,
+    /// `MonsterTypes.ini` in the data directory by default. A missing file
+    /// leaves the table empty.
+    pub fn from_env() -> Result<Self, Error> {
+        let path = match dotenvy::var("MONSTER_TYPES_LOCATION") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => {
+                let data_dir = dotenvy::var("DATA_LOCATION")?;
+                PathBuf::from(data_dir).join("MonsterTypes.ini")
+            },
+        };
+        let types = Self::default();
+        types.load(&path)?;
+        Ok(types)
+    }
+
+    /// Reads the types from the file at `path`, replacing the loaded ones.
+    ///
+    /// Returns how many got loaded.
+    pub fn load(&self, path: &Path) -> Result<usize, Error> {
+        let source = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tracing::warn!(path = %path.display(), "No monster types");
+                return Ok(0);
+            },
+            Err(e) => return Err(e.into()),
+        };
+        let types = parse_monster_types(&source);
+        let count = types.len();
+        *self.types.write() = types;
+        tracing::info!(%count, "Loaded monster types");
+        Ok(count)
+    }
+
+    pub fn get(&self, id: u32) -> Option<Arc<MonsterType>> {
+        self.types.read().get(&id).cloned()
+    }
+
+    pub fn insert(&self, kind: MonsterType) {
+        self.types.write().insert(kind.id(), Arc::new(kind));
+    }
+
+    pub fn len(&self) -> usize { self.types.read().len() }
+
+    pub fn is_empty(&self) -> bool { self.types.read().is_empty() }
+}
+
+/// Parses the monster types in `source`, logging a warning for every one
+/// that could not be used.
+pub fn parse_monster_types(source: &str) -> HashMap<u32, Arc<MonsterType>> {
+    let mut types = HashMap::new();
+    let mut section: Option<(u32, HashMap<&str, &str>)> = None;
+    let mut flush = |section: Option<(u32, HashMap<&str, &str>)>| {
+        let Some((id, fields)) = section else {
+            return;
+        };
+        match monster_type(id, &fields) {
+            Ok(kind) => {
+                if types.insert(id, Arc::new(kind)).is_some() {
+                    tracing::warn!(%id, "Duplicate monster type");
+                }
+            },
+            Err(error) => {
+                tracing::warn!(%id, %error, "Skipped monster type");
+            },
+        }
+    };
+    for (n, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[') {
+            flush(section.take());
+            let id = header.strip_suffix(']').and_then(|id| id.parse().ok());
+            match id {
+                Some(id) => section = Some((id, HashMap::new())),
+                None => {
+                    tracing::warn!(line = n + 1, %line, "Invalid monster type");
+                },
+            }
+            continue;
+        }
+        let Some((_, fields)) = section.as_mut() else {
+            continue;
+        };
+        match line.split_once('=') {
+            Some((key, value)) => {
+                fields.insert(key.trim(), value.trim());
+            },
+            None => {
+                tracing::warn!(line = n + 1, %line, "Invalid monster type line");
+            },
+        }
+    }
+    flush(section);
+    types
+}
+
+fn monster_type(
+    id: u32,
+    fields: &HashMap<&str, &str>,
+) -> Result<MonsterType, Error> {
+    let name = fields
+        .get("Name")
+        .ok_or_else(|| Error::Other("Missing Name".into()))?;
+    let defaults = MonsterStats::default();
+    let move_speed = defaults.move_speed.as_millis() as u64;
+    let stats = MonsterStats {
+        lookface: required(fields, "Lookface")?,
+        level: required(fields, "Level")?,
+        life: required(fields, "Life")?,
+        attack_range: optional(
+            id,
+            fields,
+            "AttackRange",
+            defaults.attack_range,
+        )?,
+        move_speed: Duration::from_millis(optional(
+            id,
+            fields,
+            "MoveSpeed",
+            move_speed,
+        )?),
+        view_range: optional(id, fields, "ViewRange", defaults.view_range)?,
+    };
+    Ok(MonsterType::new(id, *name).with_stats(stats))
+}
+
+fn required<T: FromStr>(
+    fields: &HashMap<&str, &str>,
+    key: &str,
+) -> Result<T, Error> {
+    match fields.get(key) {
+        Some(value) => number(key, value),
+        None => Err(Error::Other(format!("Missing {key}"))),
+    }
+}
+
+/// Like [`required`], but a missing field only gets a warning and takes the
+/// `default`.
+fn optional<T: FromStr + Display>(
+    id: u32,
+    fields: &HashMap<&str, &str>,
+    key: &str,
+    default: T,
+) -> Result<T, Error> {
+    match fields.get(key) {
+        Some(value) => number(key, value),
+        None => {
+            tracing::warn!(%id, %key, %default, "Missing monster type field");
+            Ok(default)
+        },
+    }
+}
+
+fn number<T: FromStr>(key: &str, value: &str) -> Result<T, Error> {
+    value
+        .parse()
+        .map_err(|_| Error::Other(format!("Invalid {key}: {value:?}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = "
+; A few monsters of the twin city.
+[1]
+Name=Pheasant
+Lookface=104
+Level=1
+Life=33
+AttackRange=1
+MoveSpeed=1000
+ViewRange=10
+
+[2]
+Name=Turtledove
+Lookface=107
+Level=7
+Life=104
+
+[3]
+Name=Robin
+Level=12
+Life=148
+
+[4]
+Name=Apparition
+Lookface=111
+Level=70
+Life=100000
+";
+
+    #[test]
+    fn known_monster_stats() {
+        let types = parse_monster_types(FIXTURE);
+        let pheasant = &types[&1];
+        assert_eq!(pheasant.name(), "Pheasant");
+        assert_eq!(
+            *pheasant.stats(),
+            MonsterStats {
+                lookface: 104,
+                level: 1,
+                life: 33,
+                attack_range: 1,
+                move_speed: Duration::from_millis(1000),
+                view_range: 10,
+            }
+        );
+        // Missing optional fields take the defaults.
+        let turtledove = &types[&2];
+        assert_eq!(turtledove.stats().life, 104);
+        assert_eq!(turtledove.stats().view_range, 15);
+    }
+
+    #[test]
+    fn invalid_monsters_are_skipped() {
+        let types = parse_monster_types(FIXTURE);
+        // Robin has no lookface, and the life of the apparition is too big.
+        assert_eq!(types.len(), 2);
+        assert!(!types.contains_key(&3));
+        assert!(!types.contains_key(&4));
+    }
+}
@@ -0,0 +1,278 @@
+use crate::entities::Character;
+use crate::systems::EntityKind;
+use crate::world::Map;
+use crate::{Error, State};
+use bytes::Bytes;
+use futures::stream::{FuturesUnordered, StreamExt};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::Duration;
+use tq_network::ActorHandle;
+
+/// How long the movements seen by a character get batched by default, zero
+/// sends every movement right away.
+pub const MOVEMENT_WINDOW: Duration = Duration::ZERO;
+
+/// How many tiles a character could cover in a second, bounds how far the
+/// positions the client claims could be from the last one accepted.
+pub const CLAIM_TILES_PER_SECOND: u128 = 20;
+
+/// How far from its last claimed position a character could be after
+/// `elapsed`, never more than a screen away.
+pub fn claim_reach(elapsed: Duration) -> u16 {
+    let tiles = elapsed.as_millis() * CLAIM_TILES_PER_SECOND / 1000;
+    tiles.min(tq_math::SCREEN_DISTANCE.into()) as u16
+}
+
+/// Whether `me` could step on the tile at `(x, y)` of `map`: it has to be
+/// walkable, and the castle gates only let the guild holding the castle
+/// through, see
+/// [`GuildWar::can_pass_gate`](crate::events::GuildWar::can_pass_gate).
+pub fn can_step(
+    state: &State,
+    me: &Character,
+    map: &Map,
+    (x, y): (u16, u16),
+) -> bool {
+    if !map.is_walkable(x, y, EntityKind::Player) {
+        return false;
+    }
+    !map.is_gate(x, y)
+        || state
+            .guilds()
+            .of(me.character_id())
+            .is_some_and(|guild| state.guild_war().can_pass_gate(guild.id()))
+}
+
+/// The packets held back for an observer, and where they go.
+type Pending = HashMap<u32, (ActorHandle, Vec<(u16, Bytes)>)>;
+
+/// The movements of the characters on a map that were not sent to their
+/// observers yet, by observer. On a crowded map, every observer gets a single
+/// batch for each window instead of a packet for every step of everyone
+/// around.
+///
+/// The packets of an observer are always sent in the order they were pushed,
+/// anything sent to it right away, like spawn packets, should be preceded by
+/// a [`MovementBatch::flush_observer`].
+#[derive(Debug, Default)]
+pub struct MovementBatch {
+    pending: Mutex<Pending>,
+}
+
+impl MovementBatch {
+    pub fn new() -> Self { Self::default() }
+
+    /// Holds an encoded packet back for the observer.
+    pub fn push(
+        &self,
+        observer: u32,
+        owner: &ActorHandle,
+        packet: (u16, Bytes),
+    ) {
+        let mut pending = self.pending.lock();
+        pending
+            .entry(observer)
+            .or_insert_with(|| (owner.clone(), Vec::new()))
+            .1
+            .push(packet);
+    }
+
+    /// How many packets are held back for the observer.
+    pub fn pending_for(&self, observer: u32) -> usize {
+        self.pending
+            .lock()
+            .get(&observer)
+            .map_or(0, |(_, p)| p.len())
+    }
+
+    /// Sends the observer whatever is held back for it.
+    pub async fn flush_observer(&self, observer: u32) -> Result<(), Error> {
+        let batch = self.pending.lock().remove(&observer);
+        if let Some((owner, packets)) = batch {
+            owner.send_batch(packets).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends every observer whatever is held back for it, as a single batch.
+    #[tracing::instrument(skip(self))]
+    pub async fn flush(&self) {
+        let pending = std::mem::take(&mut *self.pending.lock());
+        let futs: FuturesUnordered<_> = pending
+            .into_iter()
+            .map(|(observer, (owner, packets))| async move {
+                (observer, owner.send_batch(packets).await)
+            })
+            .collect();
+        futs.for_each_concurrent(None, |(observer, res)| async move {
+            if let Err(error) = res {
+                tracing::debug!(%error, %observer, "Failed to send movements");
+            }
+        })
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::{MovementType, MsgWalk, WalkDirection};
+    use crate::test_utils::*;
+    use crate::world::Maps;
+    use crate::State;
+    use futures::FutureExt;
+    use primitives::{Location, Size};
+    use tokio::sync::mpsc::Receiver;
+    use tq_network::{Message, PacketID};
+
+    /// How many characters crowd the map.
+    const CROWD: usize = 6;
+
+    /// The messages waiting in the channel, and how many walks are in them.
+    fn received(rx: &mut Receiver<Message>) -> (usize, usize) {
+        let (mut messages, mut walks) = (0, 0);
+        while let Ok(msg) = rx.try_recv() {
+            messages += 1;
+            walks += match msg {
+                Message::Packet(MsgWalk::PACKET_ID, _) => 1,
+                Message::Batch(packets) => packets
+                    .iter()
+                    .filter(|(id, _)| *id == MsgWalk::PACKET_ID)
+                    .count(),
+                _ => 0,
+            };
+        }
+        (messages, walks)
+    }
+
+    /// Puts a crowd of characters next to each other on a blank map, each
+    /// one already seeing all the others.
+    async fn crowd(
+        state: &State,
+        actors: [TestActor; 2],
+    ) -> Result<Vec<TestActor>, Error> {
+        let map_id = u32::from(Maps::Arena);
+        let map = state.try_map(map_id)?;
+        map.load_blank(Size::new(100, 100)).await?;
+        let mut crowd = Vec::from(actors);
+        for i in crowd.len()..CROWD {
+            crowd.push(make_extra_actor(state, &format!("crowd{i}")).await?);
+        }
+        for (i, (actor, _)) in crowd.iter().enumerate() {
+            let e = actor.entity();
+            e.basic().set_map_id(map_id);
+            e.basic().set_location(Location::new(40 + i as u16, 40, 0));
+            map.insert_entity(e).await?;
+        }
+        for (actor, _) in &crowd {
+            actor.screen().load_surroundings(state).await?;
+        }
+        for (_, rx) in &mut crowd {
+            received(rx);
+        }
+        Ok(crowd)
+    }
+
+    /// Everyone in the crowd takes a step south.
+    async fn step(state: &State, crowd: &[TestActor]) -> Result<(), Error> {
+        for (actor, _) in crowd {
+            let e = actor.entity();
+            let loc = e.basic().location();
+            e.basic().set_location(Location::new(loc.x, loc.y + 1, 0));
+            let msg =
+                MsgWalk::new(e.id(), WalkDirection::South, MovementType::Walk);
+            actor.screen().send_movement(state, msg).await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn movements_are_sent_one_by_one() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let mut crowd = crowd(&state, actors).await?;
+                step(&state, &crowd).await?;
+                for (_, rx) in &mut crowd {
+                    assert_eq!(received(rx), (CROWD - 1, CROWD - 1));
+                }
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn movements_are_batched_by_observer() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |mut state, actors| {
+            async move {
+                state.set_movement_window(Duration::from_millis(50));
+                let mut crowd = crowd(&state, actors).await?;
+                step(&state, &crowd).await?;
+                step(&state, &crowd).await?;
+                for (_, rx) in &mut crowd {
+                    assert_eq!(received(rx), (0, 0));
+                }
+                state.flush_movements().await;
+                // A single message for all the steps of everyone around.
+                for (_, rx) in &mut crowd {
+                    assert_eq!(received(rx), (1, 2 * (CROWD - 1)));
+                }
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn held_back_movements_go_before_spawns() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |mut state, actors| {
+            async move {
+                state.set_movement_window(Duration::from_millis(50));
+                let mut crowd = crowd(&state, actors).await?;
+                let map = state.try_map(u32::from(Maps::Arena))?;
+                let [(a, a_rx), (b, _), ..] = &mut crowd[..] else {
+                    unreachable!()
+                };
+                let a_id = a.entity().id();
+                // `b` walks out of the sight of `a`, the step that took it
+                // out waits behind the one before.
+                let e = b.entity();
+                for y in [41, 60] {
+                    e.basic().set_location(Location::new(41, y, 0));
+                    map.update_region_for(e.clone());
+                    let msg = MsgWalk::new(
+                        e.id(),
+                        WalkDirection::South,
+                        MovementType::Walk,
+                    );
+                    b.screen().send_movement(&state, msg).await?;
+                }
+                assert_eq!(map.movements().pending_for(a_id), 2);
+                // Then right back in, what `a` saw before the spawn goes
+                // first.
+                e.basic().set_location(Location::new(41, 42, 0));
+                map.update_region_for(e.clone());
+                let msg = MsgWalk::new(
+                    e.id(),
+                    WalkDirection::South,
+                    MovementType::Walk,
+                );
+                b.screen().send_movement(&state, msg).await?;
+                assert_eq!(map.movements().pending_for(a_id), 0);
+                match a_rx.try_recv() {
+                    Ok(Message::Batch(packets)) => assert_eq!(packets.len(), 2),
+                    other => {
+                        panic!("expected the held back walks, got {other:?}")
+                    },
+                }
+                assert!(matches!(a_rx.try_recv(), Ok(Message::Packet(..))));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
@@ -0,0 +1,122 @@
+//! Keeps reserved and offensive names away from characters, both when they
+//! get created and when they get renamed.
+
+use crate::Error;
+
+/// The names only the staff could look like they have, by default.
+pub const RESERVED_NAMES: &[&str] = &["gm", "pm", "admin", "system"];
+
+/// The words no name could have in it, by default.
+pub const PROFANE_WORDS: &[&str] =
+    &["fuck", "shit", "bitch", "cunt", "whore", "pussy", "dick"];
+
+/// Why a name got rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameRejection {
+    /// The name looks like one of the staff.
+    Reserved,
+    /// The name has a profane word in it.
+    Profane,
+}
+
+/// The reserved names and the profane words names are checked against.
+///
+/// Names are compared in lowercase, with the look-alike digits and symbols
+/// read as the letters they stand for, and anything else that is not a
+/// letter or a digit left out, so `[GM]Bob` and `Sh1t` are both caught.
+/// A reserved name has to be the whole name, or where it starts or ends,
+/// while a profane word is caught anywhere in it.
+///
+/// The lists could be replaced using the `RESERVED_NAMES` and
+/// `PROFANE_WORDS` environment variables, comma separated.
+#[derive(Debug, Clone)]
+pub struct NameFilter {
+    reserved: Vec<String>,
+    profane: Vec<String>,
+}
+
+impl Default for NameFilter {
+    fn default() -> Self { Self::new(RESERVED_NAMES, PROFANE_WORDS) }
+}
+
+impl NameFilter {
+    pub fn new<S: AsRef<str>>(reserved: &[S], profane: &[S]) -> Self {
+        let normalized = |words: &[S]| {
+            words
+                .iter()
+                .map(|w| normalize(w.as_ref()))
+                .filter(|w| !w.is_empty())
+                .collect()
+        };
+        Self {
+            reserved: normalized(reserved),
+            profane: normalized(profane),
+        }
+    }
+
+    /// Loads the lists from the environment, falling back to the defaults
+    /// for any of them that is not configured.
+    pub fn from_env() -> Result<Self, Error> {
+        let list = |var: &str, default: &[&str]| match dotenvy::var(var) {
+            Ok(v) => v.split(',').map(str::to_owned).collect(),
+            Err(_) => default.iter().map(|w| w.to_string()).collect(),
+        };
+        let reserved: Vec<String> = list("RESERVED_NAMES", RESERVED_NAMES);
+        let profane: Vec<String> = list("PROFANE_WORDS", PROFANE_WORDS);
+        Ok(Self::new(&reserved, &profane))
+    }
+
+    /// Checks the name against both lists.
+    pub fn check(&self, name: &str) -> Result<(), NameRejection> {
+        let name = normalize(name);
+        let reserved = self.reserved.iter().any(|r| {
+            name.starts_with(r.as_str()) || name.ends_with(r.as_str())
+        });
+        if reserved {
+            return Err(NameRejection::Reserved);
+        }
+        if self.profane.iter().any(|p| name.contains(p.as_str())) {
+            return Err(NameRejection::Profane);
+        }
+        Ok(())
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '0' => 'o',
+            '1' | '!' | '|' => 'i',
+            '3' => 'e',
+            '4' | '@' => 'a',
+            '5' | '$' => 's',
+            '7' => 't',
+            c => c.to_ascii_lowercase(),
+        })
+        .filter(char::is_ascii_alphanumeric)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserved_and_profane_names_are_rejected() {
+        let filter = NameFilter::default();
+        for name in ["GM", "[GM]Bob", "Bob[pm]", "Adm1n", "SYSTEM"] {
+            assert_eq!(filter.check(name), Err(NameRejection::Reserved));
+        }
+        for name in ["BigSh1t", "fUcK", "Bi7ch_"] {
+            assert_eq!(filter.check(name), Err(NameRejection::Profane));
+        }
+    }
+
+    #[test]
+    fn clean_names_pass() {
+        let filter = NameFilter::default();
+        for name in ["Bob", "Pigment", "Gemma", "Hero42"] {
+            assert_eq!(filter.check(name), Ok(()), "{name}");
+        }
+    }
+}
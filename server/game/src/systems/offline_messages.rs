@@ -0,0 +1,237 @@
+//! Messages sent to characters while they are offline, kept in the database
+//! until their next login.
+
+use crate::entities::Character;
+use crate::packets::{MsgTalk, TalkChannel};
+use crate::{Error, State};
+use std::time::Duration;
+use tq_db::character::Character as CharacterRow;
+use tq_db::offline_message::OfflineMessage;
+
+/// How many messages could wait for a character, the oldest ones are dropped
+/// to make room for the new ones.
+pub const OFFLINE_MESSAGE_CAP: i64 = 20;
+
+/// How long a message waits for its recipient before it is dropped.
+pub const OFFLINE_MESSAGE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Keeps the message for the character named `recipient` until it logs in,
+/// `false` if no character has that name.
+pub async fn queue_offline_message(
+    state: &State,
+    recipient: &str,
+    msg: &MsgTalk,
+    now: i64,
+) -> Result<bool, Error> {
+    let pool = state.pool();
+    let Some(character_id) = CharacterRow::id_by_name(pool, recipient).await?
+    else {
+        return Ok(false);
+    };
+    let offline = OfflineMessage {
+        character_id,
+        sender_name: msg.sender_name.clone(),
+        channel: msg.channel.into(),
+        message: msg.message.clone(),
+        sent_at: now,
+        ..Default::default()
+    };
+    offline.queue(pool, OFFLINE_MESSAGE_CAP).await?;
+    tracing::debug!(%character_id, "Queued an offline message");
+    Ok(true)
+}
+
+/// Keeps a notice from the system for the character until its next login.
+pub async fn queue_offline_notice(
+    state: &State,
+    character_id: i32,
+    message: impl Into<String>,
+    now: i64,
+) -> Result<(), Error> {
+    let msg = MsgTalk::from_system(0, TalkChannel::System, message);
+    let offline = OfflineMessage {
+        character_id,
+        sender_name: msg.sender_name,
+        channel: msg.channel.into(),
+        message: msg.message,
+        sent_at: now,
+        ..Default::default()
+    };
+    offline.queue(state.pool(), OFFLINE_MESSAGE_CAP).await?;
+    Ok(())
+}
+
+/// Sends the character the messages it got while it was offline, oldest
+/// first, and returns how many there were. The ones that waited longer than
+/// [`OFFLINE_MESSAGE_TTL`] are dropped.
+#[tracing::instrument(skip(state, me), fields(me = me.id()))]
+pub async fn deliver_offline_messages(
+    state: &State,
+    me: &Character,
+    now: i64,
+) -> Result<usize, Error> {
+    let not_before = now - OFFLINE_MESSAGE_TTL.as_secs() as i64;
+    let messages =
+        OfflineMessage::take(state.pool(), me.character_id(), not_before)
+            .await?;
+    let name = me.entity().name();
+    let packets: Vec<_> = messages
+        .into_iter()
+        .map(|m| {
+            let channel = TalkChannel::from(m.channel as u16);
+            MsgTalk {
+                sender_name: m.sender_name,
+                recipient_name: name.to_string(),
+                ..MsgTalk::from_system(me.id(), channel, m.message)
+            }
+        })
+        .collect();
+    let count = packets.len();
+    me.owner().send_all(packets).await?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use futures::FutureExt;
+    use tq_network::PacketProcess;
+
+    #[tokio::test]
+    async fn whispers_wait_for_the_next_login() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, mut b_rx)] = actors;
+                let entity = b.entity();
+                let bob = entity.as_character().unwrap();
+                let bob_name = bob.entity().name().to_string();
+                bob.leave_world(&state).await?;
+                packets_of::<MsgTalk>(&mut b_rx);
+
+                for text in ["first", "second"] {
+                    let whisper = MsgTalk {
+                        recipient_name: bob_name.clone(),
+                        ..MsgTalk::from_system(0, TalkChannel::Whisper, text)
+                    };
+                    whisper.process(&state, &a).await?;
+                }
+                let replies = packets_of::<MsgTalk>(&mut a_rx);
+                assert_eq!(replies.len(), 2);
+                assert!(replies[0].message.contains("offline"));
+                let waiting =
+                    OfflineMessage::count(state.pool(), bob.character_id())
+                        .await?;
+                assert_eq!(waiting, 2);
+
+                // What logging in does once the character is loaded.
+                let now = crate::utils::current_ts() as i64;
+                assert_eq!(
+                    deliver_offline_messages(&state, bob, now).await?,
+                    2
+                );
+                let whispers: Vec<_> = packets_of::<MsgTalk>(&mut b_rx)
+                    .into_iter()
+                    .filter(|m| m.channel == TalkChannel::Whisper as u16)
+                    .collect();
+                let texts: Vec<_> =
+                    whispers.iter().map(|m| m.message.as_str()).collect();
+                assert_eq!(texts, ["first", "second"]);
+                let alice = a.entity();
+                assert_eq!(
+                    whispers[0].sender_name,
+                    alice.basic().name().as_str()
+                );
+                let waiting =
+                    OfflineMessage::count(state.pool(), bob.character_id())
+                        .await?;
+                assert_eq!(waiting, 0);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn whispers_to_nobody_are_not_kept() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let whisper = MsgTalk {
+                    recipient_name: String::from("Nobody"),
+                    ..MsgTalk::from_system(0, TalkChannel::Whisper, "hello?")
+                };
+                whisper.process(&state, &a).await?;
+                let replies = packets_of::<MsgTalk>(&mut a_rx);
+                assert_eq!(replies[0].message, "There is nobody named Nobody.");
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn old_and_excess_messages_are_dropped() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let now = 1_700_000_000;
+                let ttl = OFFLINE_MESSAGE_TTL.as_secs() as i64;
+                // Expired by the time we log in.
+                queue_offline_notice(
+                    &state,
+                    me.character_id(),
+                    "stale",
+                    now - ttl - 1,
+                )
+                .await?;
+                for i in 0..OFFLINE_MESSAGE_CAP + 2 {
+                    queue_offline_notice(
+                        &state,
+                        me.character_id(),
+                        format!("#{i}"),
+                        now,
+                    )
+                    .await?;
+                }
+                let waiting =
+                    OfflineMessage::count(state.pool(), me.character_id())
+                        .await?;
+                assert_eq!(waiting, OFFLINE_MESSAGE_CAP);
+
+                let delivered =
+                    deliver_offline_messages(&state, me, now).await?;
+                assert_eq!(delivered as i64, OFFLINE_MESSAGE_CAP);
+                let texts: Vec<_> = packets_of::<MsgTalk>(&mut a_rx)
+                    .into_iter()
+                    .map(|m| m.message)
+                    .collect();
+                // The oldest ones made room for the newest.
+                assert_eq!(texts.first().map(String::as_str), Some("#2"));
+                let last = format!("#{}", OFFLINE_MESSAGE_CAP + 1);
+                assert_eq!(texts.last(), Some(&last));
+
+                // Only the stale one left, it is not delivered.
+                queue_offline_notice(
+                    &state,
+                    me.character_id(),
+                    "stale",
+                    now - ttl - 1,
+                )
+                .await?;
+                assert_eq!(deliver_offline_messages(&state, me, now).await?, 0);
+                let waiting =
+                    OfflineMessage::count(state.pool(), me.character_id())
+                        .await?;
+                assert_eq!(waiting, 0);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
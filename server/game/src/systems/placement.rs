@@ -0,0 +1,249 @@
+//! Where a character comes back to when it logs in.
+//!
+//! A save could point outside of its map, or at a tile the map data blocked
+//! since. Such a character gets moved somewhere it could stand before it
+//! enters the world, instead of getting stuck there.
+
+use crate::entities::Character;
+use crate::systems::EntityKind;
+use crate::world::{Map, PLACEMENT_RADIUS};
+use crate::{constants, Error, State};
+use primitives::Location;
+use std::sync::Arc;
+
+/// Checks the saved position of the character against its map, and moves
+/// it when it could not stand there. A blocked tile gets nudged to the
+/// closest one the character could stand on. Failing that, or if the save is
+/// outside of the map, the character goes to the revive point of the map.
+/// It ends up on the newbie map if the map is gone, fails to load, or has no
+/// room at the revive point either. The correction is saved right away.
+///
+/// Returns the map the character is on, loaded.
+#[tracing::instrument(skip_all, fields(me = me.id()))]
+pub async fn check_saved_position(
+    state: &State,
+    me: &Character,
+) -> Result<Arc<Map>, Error> {
+    let entity = me.entity();
+    let (map_id, saved) = (entity.map_id(), entity.location());
+    if let Some(map) = loaded_map(state, map_id).await {
+        if could_stand(&map, saved.x, saved.y) {
+            return Ok(map);
+        }
+        let nudged = map
+            .contains(saved.x, saved.y)
+            .then(|| {
+                map.find_available_tile((saved.x, saved.y), PLACEMENT_RADIUS)
+            })
+            .flatten();
+        let (x, y) = nudged.unwrap_or_else(|| map.revive_tile());
+        if could_stand(&map, x, y) {
+            tracing::warn!(
+                map_id,
+                x = saved.x,
+                y = saved.y,
+                to = ?(x, y),
+                nudged = nudged.is_some(),
+                "Invalid saved position, moved",
+            );
+            entity.set_location(Location::new(x, y, saved.direction));
+            me.save(state).await?;
+            return Ok(map);
+        }
+    }
+    let map = state.try_map(constants::NEWBIE_MAP_ID)?;
+    map.load().await?;
+    let spawn = constants::NEWBIE_LOCATION;
+    let (x, y) = map
+        .find_available_tile(spawn, PLACEMENT_RADIUS)
+        .unwrap_or(spawn);
+    tracing::warn!(
+        map_id,
+        x = saved.x,
+        y = saved.y,
+        to = ?(x, y),
+        "Invalid saved position, moved to the newbie map",
+    );
+    entity
+        .set_map_id(constants::NEWBIE_MAP_ID)
+        .set_location(Location::new(x, y, saved.direction));
+    me.save(state).await?;
+    Ok(map)
+}
+
+/// The map with that id, loaded, `None` if it is gone or fails to load.
+async fn loaded_map(state: &State, map_id: u32) -> Option<Arc<Map>> {
+    let map = state.map_by_id(map_id)?;
+    match map.load().await {
+        Ok(()) => Some(map),
+        Err(e) => {
+            tracing::warn!(map_id, error = %e, "Saved map failed to load");
+            None
+        },
+    }
+}
+
+/// Whether a player could stand on that tile of the map.
+fn could_stand(map: &Map, x: u16, y: u16) -> bool {
+    map.contains(x, y) && map.is_walkable(x, y, EntityKind::Player)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systems::{Tile, TileAccess};
+    use crate::test_utils::*;
+    use crate::world::Maps;
+    use futures::FutureExt;
+    use primitives::Size;
+    use tq_db::character::Character as DbCharacter;
+
+    #[tokio::test]
+    async fn invalid_saves_are_corrected() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let arena = state.try_map(u32::from(Maps::Arena))?;
+                arena.load_blank(Size::new(100, 100)).await?;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                me.entity()
+                    .set_map_id(arena.id())
+                    .set_location(Location::new(300, 40, 0));
+
+                check_saved_position(&state, me).await?;
+                let revive = arena.revive_tile();
+                let loc = me.entity().location();
+                assert_eq!((loc.x, loc.y), revive);
+                let row =
+                    DbCharacter::by_id(state.pool(), me.character_id()).await?;
+                assert_eq!(row.map_id as u32, arena.id());
+                assert_eq!((row.x as u16, row.y as u16), revive);
+
+                // A wall put where the character was saved, it gets
+                // nudged next to it.
+                let wall = Tile {
+                    access: TileAccess::Terrain,
+                    elevation: 0,
+                };
+                arena.set_tile(20, 20, wall);
+                me.entity().set_location(Location::new(20, 20, 0));
+                check_saved_position(&state, me).await?;
+                let loc = me.entity().location();
+                assert_ne!((loc.x, loc.y), (20, 20));
+                assert!(loc.x.abs_diff(20) <= 1 && loc.y.abs_diff(20) <= 1);
+                let row =
+                    DbCharacter::by_id(state.pool(), me.character_id()).await?;
+                assert_eq!((row.x as u16, row.y as u16), (loc.x, loc.y));
+
+                // Walled in with nowhere to go, back to the revive point.
+                let r = PLACEMENT_RADIUS as i32;
+                for dx in -r..=r {
+                    for dy in -r..=r {
+                        let (x, y) = ((30 + dx) as u16, (30 + dy) as u16);
+                        arena.set_tile(x, y, wall);
+                    }
+                }
+                me.entity().set_location(Location::new(30, 30, 0));
+                check_saved_position(&state, me).await?;
+                let loc = me.entity().location();
+                assert_eq!((loc.x, loc.y), revive);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn valid_saves_are_left_alone() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let arena = state.try_map(u32::from(Maps::Arena))?;
+                arena.load_blank(Size::new(100, 100)).await?;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let before =
+                    DbCharacter::by_id(state.pool(), me.character_id()).await?;
+                me.entity()
+                    .set_map_id(arena.id())
+                    .set_location(Location::new(40, 40, 0));
+
+                let map = check_saved_position(&state, me).await?;
+                assert_eq!(map.id(), arena.id());
+                let loc = me.entity().location();
+                assert_eq!((loc.x, loc.y), (40, 40));
+                // Nothing got saved.
+                let after =
+                    DbCharacter::by_id(state.pool(), me.character_id()).await?;
+                assert_eq!(after.map_id, before.map_id);
+                assert_eq!((after.x, after.y), (before.x, before.y));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn missing_map_falls_back_to_newbie_map() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let newbie_map = state.try_map(constants::NEWBIE_MAP_ID)?;
+                newbie_map.load_blank(Size::new(200, 200)).await?;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                me.entity()
+                    .set_map_id(424242)
+                    .set_location(Location::new(10, 10, 0));
+
+                let map = check_saved_position(&state, me).await?;
+                assert_eq!(map.id(), constants::NEWBIE_MAP_ID);
+                let (x, y) = constants::NEWBIE_LOCATION;
+                let loc = me.entity().location();
+                assert_eq!((loc.x, loc.y), (x, y));
+                let row =
+                    DbCharacter::by_id(state.pool(), me.character_id()).await?;
+                assert_eq!(row.map_id as u32, constants::NEWBIE_MAP_ID);
+                assert_eq!((row.x as u16, row.y as u16), (x, y));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn map_failing_to_load_falls_back_to_newbie_map() -> Result<(), Error>
+    {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let newbie_map = state.try_map(constants::NEWBIE_MAP_ID)?;
+                newbie_map.load_blank(Size::new(200, 200)).await?;
+                // The arena is there, but its cmap is not in the test data.
+                let arena = state.try_map(u32::from(Maps::Arena))?;
+                assert!(arena.load().await.is_err());
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                me.entity()
+                    .set_map_id(arena.id())
+                    .set_location(Location::new(10, 10, 0));
+
+                let map = check_saved_position(&state, me).await?;
+                assert_eq!(map.id(), constants::NEWBIE_MAP_ID);
+                let (x, y) = constants::NEWBIE_LOCATION;
+                let loc = me.entity().location();
+                assert_eq!((loc.x, loc.y), (x, y));
+                let row =
+                    DbCharacter::by_id(state.pool(), me.character_id()).await?;
+                assert_eq!(row.map_id as u32, constants::NEWBIE_MAP_ID);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
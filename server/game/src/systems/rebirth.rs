@@ -0,0 +1,493 @@
+use crate::entities::{Character, CharacterState};
+use crate::packets::{
+    AttributeKind, MsgItem, MsgTalk, MsgTaskDialog, MsgUserAttrib, TalkChannel,
+};
+use crate::systems::{is_arrow, is_bow};
+use crate::{constants, Error, State};
+use tq_db::character::Character as CharacterRow;
+use tq_db::item::Item;
+
+/// Eternity, the NPC that reborns characters.
+pub const REBIRTH_NPC: u32 = 300500;
+
+/// The level a character has to reach before it could be reborn.
+pub const REBIRTH_LEVEL: u16 = 120;
+
+/// The level a Water Taoist has to reach before it could be reborn, they
+/// level up slower than everyone else.
+pub const WATER_REBIRTH_LEVEL: u16 = 110;
+
+/// The level a character starts over at once reborn.
+pub const REBORN_LEVEL: u16 = 15;
+
+/// The item type of the Celestial Stone, used up by a rebirth.
+pub const CELESTIAL_STONE: i32 = 721259;
+
+/// The classes a character could be reborn into, as offered by
+/// [`REBIRTH_NPC`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebirthClass {
+    Trojan,
+    Warrior,
+    Archer,
+    Taoist,
+}
+
+impl RebirthClass {
+    /// The class picked with that dialog option.
+    pub fn from_option(option: u8) -> Option<Self> {
+        match option {
+            1 => Some(Self::Trojan),
+            2 => Some(Self::Warrior),
+            3 => Some(Self::Archer),
+            4 => Some(Self::Taoist),
+            _ => None,
+        }
+    }
+
+    /// The dialog option that picks this class.
+    pub const fn option(self) -> u8 {
+        match self {
+            Self::Trojan => 1,
+            Self::Warrior => 2,
+            Self::Archer => 3,
+            Self::Taoist => 4,
+        }
+    }
+
+    /// The class profession the character gets, the first promotion of the
+    /// class.
+    pub const fn class_id(self) -> u8 {
+        match self {
+            Self::Trojan => 11,
+            Self::Warrior => 21,
+            Self::Archer => 41,
+            Self::Taoist => 101,
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Trojan => "Trojan",
+            Self::Warrior => "Warrior",
+            Self::Archer => "Archer",
+            Self::Taoist => "Taoist",
+        }
+    }
+}
+
+/// Why a rebirth did not happen, nothing changes when it does not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebirthRejection {
+    UnderLevel,
+    AlreadyReborn,
+    /// The character is dead, vending or busy with something else.
+    Busy,
+    NoCelestialStone,
+}
+
+impl RebirthRejection {
+    /// What the player gets told.
+    pub fn message(&self) -> String {
+        match self {
+            Self::UnderLevel => format!(
+                "You have to reach level {REBIRTH_LEVEL} to be reborn, or \
+                 level {WATER_REBIRTH_LEVEL} as a Water Taoist."
+            ),
+            Self::AlreadyReborn => "You have already been reborn.".into(),
+            Self::Busy => "You can not be reborn right now.".into(),
+            Self::NoCelestialStone => "You need a Celestial Stone.".into(),
+        }
+    }
+}
+
+/// The level a character of that class profession has to reach before it
+/// could be reborn.
+pub const fn rebirth_level(class: u8) -> u16 {
+    match class {
+        130..=139 => WATER_REBIRTH_LEVEL,
+        _ => REBIRTH_LEVEL,
+    }
+}
+
+/// The bonus attribute points for being reborn at the given level, kept on
+/// top of the points earned by leveling from then on.
+///
+/// This is an approximation of the client's table, which also depends on the
+/// class at rebirth: one point for every level past 100.
+pub const fn rebirth_bonus_points(level: u16) -> u16 {
+    level.saturating_sub(100)
+}
+
+/// Whether a character of that class could wear the item, the class could
+/// be any promotion of the base class.
+///
+/// Only the class restricted weapons are checked, until the item types get
+/// loaded from the client data: bows and arrows are for archers, shields
+/// for warriors and backswords for taoists.
+pub fn could_wear(item_type: u32, class: u8) -> bool {
+    let item_type_class = item_type / 1000;
+    if is_bow(item_type) || is_arrow(item_type) {
+        matches!(class, 40..=49)
+    } else if item_type_class == 900 {
+        matches!(class, 20..=29)
+    } else if item_type_class == 421 {
+        matches!(class, 100..=199)
+    } else {
+        true
+    }
+}
+
+/// The dialog of [`REBIRTH_NPC`], offering the classes to be reborn into.
+pub fn rebirth_dialog(me: &Character) -> Vec<MsgTaskDialog> {
+    let builder = MsgTaskDialog::builder();
+    let builder = match check(me) {
+        Ok(()) => {
+            let builder = builder.text(
+                "Bring me a Celestial Stone and you could start over in a \
+                 new class, what will it be?",
+            );
+            [
+                RebirthClass::Trojan,
+                RebirthClass::Warrior,
+                RebirthClass::Archer,
+                RebirthClass::Taoist,
+            ]
+            .into_iter()
+            .fold(builder, |b, class| {
+                b.with_option(class.option(), class.name())
+            })
+            .with_option(u8::MAX, "Not now.")
+        },
+        Err(rejection) => builder
+            .text(rejection.message())
+            .with_option(u8::MAX, "I see."),
+    };
+    builder.and().with_avatar(47).build()
+}
+
+/// Checks whether `me` could be reborn right now, except for the Celestial
+/// Stone.
+fn check(me: &Character) -> Result<(), RebirthRejection> {
+    if me.rebirths() > 0 {
+        Err(RebirthRejection::AlreadyReborn)
+    } else if me.entity().level() < rebirth_level(me.current_class()) {
+        Err(RebirthRejection::UnderLevel)
+    } else if me.state() != CharacterState::Alive {
+        Err(RebirthRejection::Busy)
+    } else {
+        Ok(())
+    }
+}
+
+/// Reborns `me` into `class`, using up a Celestial Stone from its
+/// inventory.
+///
+/// The character starts over at [`REBORN_LEVEL`] with the base attributes
+/// of its new class, all the points of its new level left to distribute
+/// and the [`rebirth_bonus_points`] of the level it was reborn at. The
+/// equipment the new class could not wear goes back to the inventory, or to
+/// the mailbox once the inventory is full, to be delivered on a later
+/// login.
+#[tracing::instrument(skip(state, me), fields(me = me.id()))]
+pub async fn rebirth(
+    state: &State,
+    me: &Character,
+    class: RebirthClass,
+) -> Result<(), Error> {
+    if let Err(rejection) = check(me) {
+        return tell(me, &rejection.message()).await;
+    }
+    let pool = state.pool();
+    let stone = Item::inventory_of(pool, me.character_id())
+        .await?
+        .into_iter()
+        .find(|item| item.item_type == CELESTIAL_STONE);
+    let Some(stone) = stone else {
+        return tell(me, &RebirthRejection::NoCelestialStone.message()).await;
+    };
+    let class_id = class.class_id();
+    let unequip: Vec<_> = Item::equipment_of(pool, me.character_id())
+        .await?
+        .into_iter()
+        .filter(|item| !could_wear(item.item_type as u32, class_id))
+        .collect();
+    let ids: Vec<_> = unequip.iter().map(|item| item.item_id).collect();
+
+    let level = me.entity().level();
+    let bonus = rebirth_bonus_points(level);
+    let base = constants::base_attributes(class_id);
+    let earned = constants::base_attribute_points(class_id, REBORN_LEVEL)
+        - base.iter().sum::<u16>();
+    let mut reborn = me.snapshot();
+    reborn.previous_class = reborn.current_class;
+    reborn.current_class = class_id as i16;
+    reborn.rebirths += 1;
+    reborn.level = REBORN_LEVEL as i16;
+    reborn.experience = 0;
+    [
+        reborn.strength,
+        reborn.agility,
+        reborn.vitality,
+        reborn.spirit,
+    ] = base.map(|v| v as i16);
+    reborn.attribute_points = (earned + bonus) as i16;
+    reborn.rebirth_points = bonus as i16;
+    let [strength, agility, vitality, spirit] = base;
+    reborn.health_points =
+        constants::max_health_points(strength, agility, vitality, spirit)
+            as i16;
+    reborn.mana_points = constants::max_mana_points(spirit) as i16;
+
+    let row = CharacterRow::from(reborn.clone());
+    let mailed = row
+        .rebirth(pool, stone.item_id, &ids, constants::INVENTORY_SIZE)
+        .await?;
+    // Someone else could have used the stone in the meantime.
+    let Some(mailed) = mailed else {
+        return tell(me, &RebirthRejection::NoCelestialStone.message()).await;
+    };
+    me.owner()
+        .send(MsgItem::remove(me.id(), stone.item_id as u32))
+        .await?;
+    let in_inventory = ids.len().saturating_sub(mailed);
+    for (i, item) in unequip.iter().enumerate() {
+        let msg = MsgItem::unequip(me.id(), item, item.position);
+        me.owner().send(msg).await?;
+        if i >= in_inventory {
+            let msg = MsgItem::remove(me.id(), item.item_id as u32);
+            me.owner().send(msg).await?;
+        }
+    }
+
+    let msg = me.apply_rebirth(&reborn);
+    me.owner().send(msg).await?;
+    // Everyone around gets to see the reborn aura.
+    let msg = MsgUserAttrib::new(me.id())
+        .with(AttributeKind::Rebirths, reborn.rebirths as u64)
+        .with(AttributeKind::Level, REBORN_LEVEL as u64);
+    if let Ok(screen) = me.try_screen() {
+        screen.send_message(msg).await?;
+    }
+    if mailed > 0 {
+        let msg = "Some of your equipment did not fit in your inventory, it \
+                   will be delivered on your next login.";
+        tell(me, msg).await?;
+    }
+    tracing::info!(%level, class = class.name(), %bonus, "Reborn");
+    let msg = format!(
+        "Congratulations! {} has been reborn as a {}.",
+        me.entity().name(),
+        class.name()
+    );
+    state.broadcast(MsgTalk::announce(msg)).await?;
+    Ok(())
+}
+
+async fn tell(me: &Character, message: &str) -> Result<(), Error> {
+    let msg = MsgTalk::from_system(me.id(), TalkChannel::TopLeft, message);
+    me.owner().send(msg).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use futures::FutureExt;
+
+    const BOW: i32 = 500_005;
+    const ARROWS: i32 = 1_050_000;
+    const BLADE: i32 = 410_005;
+
+    async fn give(
+        state: &State,
+        character_id: i32,
+        item_type: i32,
+        position: i16,
+    ) -> Result<(), Error> {
+        sqlx::query(
+            "INSERT INTO items (character_id, item_type, position, amount) VALUES (?, ?, ?, 1);",
+        )
+        .bind(character_id)
+        .bind(item_type)
+        .bind(position)
+        .execute(state.pool())
+        .await?;
+        Ok(())
+    }
+
+    #[test]
+    fn bonus_points_grow_with_the_level() {
+        assert_eq!(rebirth_bonus_points(110), 10);
+        assert_eq!(rebirth_bonus_points(115), 15);
+        assert_eq!(rebirth_bonus_points(120), 20);
+        assert_eq!(rebirth_bonus_points(130), 30);
+    }
+
+    #[test]
+    fn water_taoists_are_reborn_earlier() {
+        assert_eq!(rebirth_level(15), REBIRTH_LEVEL);
+        assert_eq!(rebirth_level(101), REBIRTH_LEVEL);
+        assert_eq!(rebirth_level(145), REBIRTH_LEVEL);
+        assert_eq!(rebirth_level(135), WATER_REBIRTH_LEVEL);
+        assert_eq!(rebirth_level(132), WATER_REBIRTH_LEVEL);
+    }
+
+    #[tokio::test]
+    async fn under_level_is_rejected() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                give(
+                    &state,
+                    me.character_id(),
+                    CELESTIAL_STONE,
+                    Item::INVENTORY,
+                )
+                .await?;
+                me.entity().set_level(REBIRTH_LEVEL - 1);
+
+                rebirth(&state, me, RebirthClass::Warrior).await?;
+                let told = packets_of::<MsgTalk>(&mut a_rx);
+                assert_eq!(
+                    told[0].message,
+                    RebirthRejection::UnderLevel.message()
+                );
+                assert_eq!(me.current_class(), 10);
+                assert_eq!(me.rebirths(), 0);
+                assert_eq!(me.entity().level(), REBIRTH_LEVEL - 1);
+                let inventory =
+                    Item::inventory_of(state.pool(), me.character_id()).await?;
+                assert_eq!(inventory.len(), 1);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn rebirth_keeps_the_bonus_points() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (_b, mut b_rx)] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                me.entity().set_level(120);
+                rebirth(&state, me, RebirthClass::Warrior).await?;
+                let told = packets_of::<MsgTalk>(&mut a_rx);
+                assert_eq!(
+                    told[0].message,
+                    RebirthRejection::NoCelestialStone.message()
+                );
+
+                give(
+                    &state,
+                    me.character_id(),
+                    CELESTIAL_STONE,
+                    Item::INVENTORY,
+                )
+                .await?;
+                rebirth(&state, me, RebirthClass::Warrior).await?;
+                assert_eq!(me.current_class(), 21);
+                assert_eq!(me.previous_class(), 10);
+                assert_eq!(me.rebirths(), 1);
+                assert_eq!(me.entity().level(), REBORN_LEVEL);
+                assert_eq!(me.experience(), 0);
+                assert_eq!(me.rebirth_points(), 20);
+                assert_eq!(me.attribute_points(), 14 * 3 + 20);
+                assert_eq!(me.hp().current(), me.hp().max);
+                let inventory =
+                    Item::inventory_of(state.pool(), me.character_id()).await?;
+                assert!(inventory.is_empty());
+                let row = CharacterRow::by_id(state.pool(), me.character_id())
+                    .await?;
+                assert_eq!((row.current_class, row.previous_class), (21, 10));
+                assert_eq!(
+                    (row.level, row.rebirths, row.rebirth_points),
+                    (15, 1, 20)
+                );
+                // Everyone hears about it.
+                let announced = packets_of::<MsgTalk>(&mut b_rx);
+                assert!(announced[0].message.contains("reborn as a Warrior"));
+
+                // The bonus counts when redistributing the attributes.
+                let base = constants::base_attribute_points(21, REBORN_LEVEL);
+                assert!(me.apply_allotment([base + 20, 0, 0, 0]).is_ok());
+                assert!(me.apply_allotment([base, 0, 0, 0]).is_err());
+
+                rebirth(&state, me, RebirthClass::Trojan).await?;
+                let told = packets_of::<MsgTalk>(&mut a_rx);
+                assert_eq!(
+                    told.last().unwrap().message,
+                    RebirthRejection::AlreadyReborn.message()
+                );
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn invalid_equipment_is_mailed_when_inventory_full(
+    ) -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let pool = state.pool();
+                let id = me.character_id();
+                me.entity().set_level(REBIRTH_LEVEL);
+                give(&state, id, BOW, Item::RIGHT_HAND).await?;
+                give(&state, id, ARROWS, Item::LEFT_HAND).await?;
+                give(&state, id, BLADE, 3).await?;
+                give(&state, id, CELESTIAL_STONE, Item::INVENTORY).await?;
+                for _ in 1..constants::INVENTORY_SIZE {
+                    give(&state, id, BLADE, Item::INVENTORY).await?;
+                }
+
+                rebirth(&state, me, RebirthClass::Warrior).await?;
+                assert_eq!(me.rebirths(), 1);
+                // The stone left room for the bow only.
+                let inventory = Item::inventory_of(pool, id).await?;
+                assert_eq!(inventory.len(), constants::INVENTORY_SIZE);
+                assert!(inventory.iter().any(|item| item.item_type == BOW));
+                let equipment = Item::equipment_of(pool, id).await?;
+                assert_eq!(equipment.len(), 1);
+                assert_eq!(equipment[0].item_type, BLADE);
+                let told = packets_of::<MsgTalk>(&mut a_rx);
+                assert!(told.iter().any(|t| t.message.contains("delivered")));
+
+                // Nothing gets delivered while the inventory is still full.
+                assert_eq!(
+                    Item::deliver_mail(pool, id, constants::INVENTORY_SIZE)
+                        .await?,
+                    0
+                );
+                let blade = &inventory
+                    .iter()
+                    .find(|item| item.item_type == BLADE)
+                    .unwrap();
+                sqlx::query("DELETE FROM items WHERE item_id = ?;")
+                    .bind(blade.item_id)
+                    .execute(pool)
+                    .await?;
+                assert_eq!(
+                    Item::deliver_mail(pool, id, constants::INVENTORY_SIZE)
+                        .await?,
+                    1
+                );
+                let inventory = Item::inventory_of(pool, id).await?;
+                assert!(inventory.iter().any(|item| item.item_type == ARROWS));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
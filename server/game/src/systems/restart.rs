@@ -0,0 +1,270 @@
+//! Restarting the server without kicking everyone out of the blue: once
+//! draining, nobody new gets in, everyone is told how long they have left,
+//! and when the time is up everyone gets saved and the server shuts down.
+
+use crate::packets::MsgTalk;
+use crate::systems::{Job, Schedule};
+use crate::{Error, State};
+use chrono::NaiveDateTime;
+use futures::FutureExt;
+use parking_lot::Mutex;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// How long a restart counts down before the server shuts down, unless
+/// `RESTART_COUNTDOWN_MINUTES` says otherwise.
+pub const RESTART_COUNTDOWN: Duration = Duration::from_secs(10 * 60);
+
+/// How many minutes before the shutdown everyone gets reminded.
+const RESTART_REMINDERS: [u32; 3] = [10, 5, 1];
+
+/// The name of the job that shuts the server down.
+const SHUTDOWN_JOB: &str = "restart-shutdown";
+
+/// Whether the server is on its way down, and the signal for when it should
+/// stop.
+#[derive(Debug)]
+pub struct Restart {
+    /// When the server goes down, once it is draining.
+    at: Mutex<Option<NaiveDateTime>>,
+    stopping: watch::Sender<bool>,
+    countdown: Duration,
+}
+
+impl Default for Restart {
+    fn default() -> Self { Self::new(RESTART_COUNTDOWN) }
+}
+
+impl Restart {
+    pub fn new(countdown: Duration) -> Self {
+        Self {
+            at: Mutex::new(None),
+            stopping: watch::channel(false).0,
+            countdown,
+        }
+    }
+
+    /// Loads the countdown from `RESTART_COUNTDOWN_MINUTES`, falling back to
+    /// [`RESTART_COUNTDOWN`].
+    pub fn from_env() -> Result<Self, Error> {
+        match dotenvy::var("RESTART_COUNTDOWN_MINUTES") {
+            Ok(v) => {
+                let minutes: u64 = v.trim().parse()?;
+                Ok(Self::new(Duration::from_secs(minutes * 60)))
+            },
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// How long a restart started by a SIGTERM counts down.
+    pub fn countdown(&self) -> Duration { self.countdown }
+
+    /// Whether the server stopped letting new players in.
+    pub fn draining(&self) -> bool { self.at.lock().is_some() }
+
+    /// When the server goes down, if it is draining.
+    pub fn at(&self) -> Option<NaiveDateTime> { *self.at.lock() }
+
+    /// Tells the server to shut down now.
+    pub fn stop(&self) { self.stopping.send_replace(true); }
+
+    /// Resolves once the server should shut down.
+    pub async fn stopped(&self) {
+        let mut stopping = self.stopping.subscribe();
+        // The sender lives as long as we do.
+        let _ = stopping.wait_for(|stopping| *stopping).await;
+    }
+}
+
+fn reminder(minutes: u32) -> String {
+    match minutes {
+        1 => String::from("The server is restarting in 1 minute."),
+        _ => format!("The server is restarting in {minutes} minutes."),
+    }
+}
+
+fn reminder_job(minutes: u32) -> String { format!("restart-in-{minutes}m") }
+
+/// Starts draining the server at `now`: nobody new gets in, everyone is told
+/// the server is restarting and reminded 10, 5 and 1 minutes before it does,
+/// and after `countdown` everyone gets saved and the server shuts down.
+pub async fn start_restart(
+    state: &State,
+    countdown: Duration,
+    now: NaiveDateTime,
+) -> Result<(), Error> {
+    let minutes = countdown.as_secs().div_ceil(60) as u32;
+    let at = now + chrono::Duration::seconds(countdown.as_secs() as i64);
+    *state.restart().at.lock() = Some(at);
+    tracing::info!(%at, "Draining the server for a restart");
+    state
+        .broadcast(MsgTalk::announce(reminder(minutes)))
+        .await?;
+    for left in RESTART_REMINDERS.into_iter().filter(|m| *m < minutes) {
+        let due = at - chrono::Duration::minutes(left.into());
+        let job = Job::new(reminder_job(left), Schedule::Once(due), move |s| {
+            s.broadcast(MsgTalk::announce(reminder(left))).boxed()
+        });
+        state.scheduler().register(job);
+    }
+    let shutdown = Job::new(SHUTDOWN_JOB, Schedule::Once(at), |state| {
+        async move {
+            state.save_all().await?;
+            state.restart().stop();
+            Ok(())
+        }
+        .boxed()
+    });
+    state.scheduler().register(shutdown);
+    Ok(())
+}
+
+/// Lets players in again and drops the countdown, `false` if the server was
+/// not draining.
+pub fn cancel_restart(state: &State) -> bool {
+    if state.restart().at.lock().take().is_none() {
+        return false;
+    }
+    for left in RESTART_REMINDERS {
+        state.scheduler().unregister(&reminder_job(left));
+    }
+    state.scheduler().unregister(SHUTDOWN_JOB);
+    tracing::info!("Restart cancelled");
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::{MsgConnect, MsgPing, TalkChannel};
+    use crate::systems::Locale;
+    use crate::test_utils::*;
+    use chrono::Duration as Minutes;
+    use futures::FutureExt;
+    use std::time::Instant;
+    use tokio::sync::mpsc::Receiver;
+    use tq_network::{Message, PacketDecode, PacketID, PacketProcess};
+
+    /// Drains the actor's channel and returns the messages told to it on the
+    /// given channel, and whether the actor got shut down.
+    fn told(
+        rx: &mut Receiver<Message>,
+        channel: TalkChannel,
+    ) -> (Vec<String>, bool) {
+        let mut messages = Vec::new();
+        let mut shutdown = false;
+        while let Ok(msg) = rx.try_recv() {
+            match msg {
+                Message::Packet(MsgTalk::PACKET_ID, bytes) => {
+                    let msg = MsgTalk::decode(&bytes).unwrap();
+                    if msg.channel == channel as u16 {
+                        messages.push(msg.message);
+                    }
+                },
+                Message::Shutdown => shutdown = true,
+                _ => continue,
+            }
+        }
+        (messages, shutdown)
+    }
+
+    #[tokio::test]
+    async fn draining_keeps_players_and_refuses_new_ones() -> Result<(), Error>
+    {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, mut b_rx)] = actors;
+                let now = at("2024-01-01 03:50");
+                start_restart(&state, Duration::from_secs(10 * 60), now)
+                    .await?;
+                assert!(state.restart().draining());
+                let (center, _) = told(&mut a_rx, TalkChannel::Center);
+                assert_eq!(center, [reminder(10)]);
+
+                // Whoever logs in now is told to come back later.
+                let token =
+                    state.generate_login_token(2, 1, Default::default())?;
+                let connect = MsgConnect {
+                    token: token.token,
+                    ..Default::default()
+                };
+                connect.process(&state, &b).await?;
+                let (login, shutdown) = told(&mut b_rx, TalkChannel::Login);
+                assert_eq!(
+                    login,
+                    [MsgTalk::server_restarting(Locale::English).message]
+                );
+                assert!(shutdown);
+
+                // Those already in keep playing.
+                let sent_at = Instant::now();
+                state.ping_all(sent_at).await;
+                let ping = std::iter::from_fn(|| a_rx.try_recv().ok())
+                    .find_map(|msg| match msg {
+                        Message::Packet(MsgPing::PACKET_ID, bytes) => {
+                            MsgPing::decode(&bytes).ok()
+                        },
+                        _ => None,
+                    })
+                    .expect("no ping was sent");
+                ping.process(&state, &a).await?;
+                assert!(a.rtt().is_some());
+
+                // Reminded 5 then 1 minute before.
+                for (minutes, left) in [(5, 5), (9, 1)] {
+                    state.scheduler().tick(&state, now).await?;
+                    state
+                        .scheduler()
+                        .tick(&state, now + Minutes::minutes(minutes))
+                        .await?;
+                    let (center, _) = told(&mut a_rx, TalkChannel::Center);
+                    assert_eq!(center, [reminder(left)]);
+                }
+                assert!(cancel_restart(&state));
+                assert!(!state.restart().draining());
+                assert!(!cancel_restart(&state));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn restart_saves_everyone_then_stops() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                me.add_silver(4242)?;
+                let silver = me.silver();
+                let now = at("2024-01-01 03:50");
+                start_restart(&state, Duration::from_secs(60), now).await?;
+                let stopped = state.restart().stopped();
+                tokio::pin!(stopped);
+                state.scheduler().tick(&state, now).await?;
+                assert!((&mut stopped).now_or_never().is_none());
+
+                state
+                    .scheduler()
+                    .tick(&state, now + Minutes::minutes(1))
+                    .await?;
+                assert!(stopped.now_or_never().is_some());
+                let saved =
+                    tq_db::character::Character::from_account(state.pool(), 1)
+                        .await?
+                        .expect("character");
+                assert_eq!(saved.silver as u64, silver);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    /// 2024-01-01 is a Monday.
+    fn at(time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap()
+    }
+}
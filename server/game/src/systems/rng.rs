@@ -0,0 +1,109 @@
+use crate::Error;
+use parking_lot::Mutex;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Where the randomness of the game comes from: the looks of new characters,
+/// the drops, the hits and misses and the like.
+///
+/// It is seeded once, from the `RNG_SEED` environment variable or from
+/// entropy, and the seed is logged at startup, so the same seed gives the
+/// same outcomes for the same actions in the same order.
+#[derive(Debug)]
+pub struct GameRng {
+    seed: u64,
+    inner: Mutex<StdRng>,
+}
+
+impl Default for GameRng {
+    fn default() -> Self { Self::seeded(rand::random()) }
+}
+
+impl GameRng {
+    pub fn seeded(seed: u64) -> Self {
+        Self {
+            seed,
+            inner: Mutex::new(StdRng::seed_from_u64(seed)),
+        }
+    }
+
+    /// Loads the seed from `RNG_SEED`, seeding from entropy when it is not
+    /// set.
+    pub fn from_env() -> Result<Self, Error> {
+        match dotenvy::var("RNG_SEED") {
+            Ok(seed) => Ok(Self::seeded(seed.trim().parse()?)),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// The seed everything is drawn from.
+    pub fn seed(&self) -> u64 { self.seed }
+
+    /// Draws from the generator while holding it.
+    pub fn with<R>(&self, f: impl FnOnce(&mut StdRng) -> R) -> R {
+        f(&mut self.inner.lock())
+    }
+
+    /// A generator of its own, seeded from this one, for code that holds it
+    /// across awaits.
+    pub fn fork(&self) -> StdRng {
+        StdRng::seed_from_u64(self.with(|rng| rng.gen()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::{BaseClass, BodyType, MsgRegister};
+    use crate::systems::DropTable;
+
+    fn draws(rng: &GameRng) -> Vec<u32> {
+        let mut forked = rng.fork();
+        let mut draws: Vec<u32> =
+            (0..4).map(|_| rng.with(|r| r.gen())).collect();
+        draws.extend((0..4).map(|_| forked.gen::<u32>()));
+        draws
+    }
+
+    #[test]
+    fn same_seed_same_sequence() {
+        let (a, b) = (GameRng::seeded(42), GameRng::seeded(42));
+        assert_eq!(draws(&a), draws(&b));
+        assert_eq!(a.seed(), 42);
+        assert_ne!(draws(&GameRng::seeded(42)), draws(&GameRng::seeded(43)));
+    }
+
+    #[test]
+    fn new_characters_look_the_same_under_the_same_seed() {
+        let build = |rng: &GameRng| {
+            let c = MsgRegister::build_character_with(
+                String::from("Alice"),
+                BodyType::AgileFemale,
+                BaseClass::Archer,
+                1,
+                1,
+                &mut rng.fork(),
+            )
+            .unwrap();
+            (c.avatar, c.hair_style)
+        };
+        let (a, b) = (GameRng::seeded(7), GameRng::seeded(7));
+        let looks: Vec<_> = (0..8).map(|_| build(&a)).collect();
+        let again: Vec<_> = (0..8).map(|_| build(&b)).collect();
+        assert_eq!(looks, again);
+    }
+
+    #[test]
+    fn drops_are_the_same_under_the_same_seed() {
+        let table = DropTable::new()
+            .with_item(1088000, 1)
+            .with_silver(10..=500, 4)
+            .with_nothing(5)
+            .with_rolls(3);
+        let roll = |rng: &GameRng| -> Vec<_> {
+            (0..16).flat_map(|_| table.roll(&mut rng.fork())).collect()
+        };
+        let (a, b) = (GameRng::seeded(1), GameRng::seeded(1));
+        assert_eq!(roll(&a), roll(&b));
+    }
+}
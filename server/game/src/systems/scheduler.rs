@@ -0,0 +1,475 @@
+//! Jobs that run at set times, like saving every character every few minutes
+//! or warning everyone before the daily restart.
+//!
+//! The world tick drives the [`Scheduler`], which runs every job that is due.
+//! When a job last ran is kept in the database, so a restart neither runs a
+//! job again nor skips it: runs missed while the server was down are caught
+//! up with a single run.
+
+use crate::packets::MsgTalk;
+use crate::{Error, State};
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use parking_lot::Mutex;
+use rand::Rng;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tq_db::scheduled_job::ScheduledJob;
+
+/// How often every character gets saved, unless `AUTOSAVE_SCHEDULE` says
+/// otherwise.
+pub const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How often the world snapshot gets written, unless `SNAPSHOT_SCHEDULE`
+/// says otherwise.
+pub const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What everyone gets told when the `RESTART_WARNING_SCHEDULE` is due.
+const RESTART_WARNING: &str =
+    "The server is about to restart, please find a safe place to log out.";
+
+/// When a job runs, in server time. Written like `every 5m`, `daily 04:00`,
+/// `weekly sat 20:00` or `once 2024-12-25 00:00`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    Every(Duration),
+    Daily(NaiveTime),
+    Weekly(Weekday, NaiveTime),
+    /// Runs a single time, then the job is dropped.
+    Once(NaiveDateTime),
+}
+
+impl Schedule {
+    /// The first time the job is due after `after`.
+    pub fn next_after(&self, after: NaiveDateTime) -> NaiveDateTime {
+        let day = chrono::Duration::days(1);
+        match *self {
+            Self::Every(interval) => {
+                let interval = chrono::Duration::from_std(interval)
+                    .unwrap_or_else(|_| chrono::Duration::days(365));
+                after + interval
+            },
+            Self::Daily(time) => {
+                let today = after.date().and_time(time);
+                if today > after {
+                    today
+                } else {
+                    today + day
+                }
+            },
+            Self::Weekly(weekday, time) => {
+                let days_ahead = (weekday.num_days_from_monday() + 7
+                    - after.weekday().num_days_from_monday())
+                    % 7;
+                let next =
+                    (after.date() + day * days_ahead as i32).and_time(time);
+                if next > after {
+                    next
+                } else {
+                    next + day * 7
+                }
+            },
+            Self::Once(at) if at > after => at,
+            Self::Once(_) => NaiveDateTime::MAX,
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::Other(format!("Invalid schedule: {s:?}"));
+        let time = |t: &str| {
+            NaiveTime::parse_from_str(t.trim(), "%H:%M").map_err(|_| invalid())
+        };
+        let (kind, rest) = s.trim().split_once(' ').ok_or_else(invalid)?;
+        match kind {
+            "every" => {
+                let rest = rest.trim();
+                let unit = rest.chars().last().ok_or_else(invalid)?;
+                let value: u64 = rest[..rest.len() - unit.len_utf8()]
+                    .parse()
+                    .map_err(|_| invalid())?;
+                let secs = match unit {
+                    's' => value,
+                    'm' => value * 60,
+                    'h' => value * 60 * 60,
+                    _ => return Err(invalid()),
+                };
+                if secs == 0 {
+                    return Err(invalid());
+                }
+                Ok(Self::Every(Duration::from_secs(secs)))
+            },
+            "daily" => Ok(Self::Daily(time(rest)?)),
+            "weekly" => {
+                let (weekday, at) =
+                    rest.trim().split_once(' ').ok_or_else(invalid)?;
+                let weekday = weekday.parse().map_err(|_| invalid())?;
+                Ok(Self::Weekly(weekday, time(at)?))
+            },
+            "once" => {
+                NaiveDateTime::parse_from_str(rest.trim(), "%Y-%m-%d %H:%M")
+                    .map(Self::Once)
+                    .map_err(|_| invalid())
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+type JobFn = Arc<
+    dyn for<'a> Fn(&'a State) -> BoxFuture<'a, Result<(), Error>> + Send + Sync,
+>;
+
+/// Something to run on a [`Schedule`].
+#[derive(Clone)]
+pub struct Job {
+    name: String,
+    schedule: Schedule,
+    jitter: Duration,
+    run: JobFn,
+}
+
+impl std::fmt::Debug for Job {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Job")
+            .field("name", &self.name)
+            .field("schedule", &self.schedule)
+            .field("jitter", &self.jitter)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Job {
+    /// A job named `name`, the name is what its last run is kept under so it
+    /// should not change between restarts.
+    pub fn new<F>(name: impl Into<String>, schedule: Schedule, run: F) -> Self
+    where
+        F: for<'a> Fn(&'a State) -> BoxFuture<'a, Result<(), Error>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        Self {
+            name: name.into(),
+            schedule,
+            jitter: Duration::ZERO,
+            run: Arc::new(run),
+        }
+    }
+
+    /// Runs the job up to `jitter` later than it is due, picked anew every
+    /// time, so jobs due at the same time do not all run in the same tick.
+    pub fn with_jitter(self, jitter: Duration) -> Self {
+        Self { jitter, ..self }
+    }
+
+    pub fn name(&self) -> &str { &self.name }
+
+    /// The first time the job is due after `after`, jitter included, drawn
+    /// from `rng`.
+    fn next_after<R: Rng + ?Sized>(
+        &self,
+        after: NaiveDateTime,
+        rng: &mut R,
+    ) -> NaiveDateTime {
+        let next = self.schedule.next_after(after);
+        if self.jitter.is_zero() {
+            return next;
+        }
+        let jitter = self.jitter.mul_f64(rng.gen::<f64>());
+        next + chrono::Duration::from_std(jitter)
+            .unwrap_or_else(|_| chrono::Duration::zero())
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    job: Job,
+    /// When the job runs next, `None` until its last run got loaded.
+    next: Option<NaiveDateTime>,
+}
+
+/// Runs the registered jobs when they are due, see the module docs for more.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    entries: Mutex<Vec<Entry>>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self { Self::default() }
+
+    /// Adds a job, replacing the one with the same name if any.
+    pub fn register(&self, job: Job) {
+        let mut entries = self.entries.lock();
+        entries.retain(|e| e.job.name != job.name);
+        // A job that runs once is due when it says, whenever it last ran.
+        let next = match job.schedule {
+            Schedule::Once(at) => Some(at),
+            _ => None,
+        };
+        entries.push(Entry { job, next });
+    }
+
+    /// Drops the job, returns `false` if there was no such job.
+    pub fn unregister(&self, name: &str) -> bool {
+        let mut entries = self.entries.lock();
+        let before = entries.len();
+        entries.retain(|e| e.job.name != name);
+        entries.len() != before
+    }
+
+    /// When the job runs next, `None` if there is no such job or the
+    /// scheduler was not ticked since it got registered.
+    pub fn next_run(&self, name: &str) -> Option<NaiveDateTime> {
+        let entries = self.entries.lock();
+        entries.iter().find(|e| e.job.name == name)?.next
+    }
+
+    /// Runs every job that is due at `now`. A job runs once however many of
+    /// its runs were missed.
+    ///
+    /// A job that never ran waits until it is first due, instead of running
+    /// as soon as the server starts. A job that fails is logged and runs
+    /// again when it is next due.
+    #[tracing::instrument(skip(self, state))]
+    pub async fn tick(
+        &self,
+        state: &State,
+        now: NaiveDateTime,
+    ) -> Result<(), Error> {
+        if self.entries.lock().iter().any(|e| e.next.is_none()) {
+            let last_runs: HashMap<_, _> = ScheduledJob::all(state.pool())
+                .await?
+                .into_iter()
+                .map(|j| (j.name, j.last_run))
+                .collect();
+            let mut entries = self.entries.lock();
+            for entry in entries.iter_mut().filter(|e| e.next.is_none()) {
+                let last_run = last_runs
+                    .get(&entry.job.name)
+                    .and_then(|&t| NaiveDateTime::from_timestamp_opt(t, 0));
+                let after = last_run.unwrap_or(now);
+                let next =
+                    state.rng().with(|rng| entry.job.next_after(after, rng));
+                entry.next = Some(next);
+            }
+        }
+        let due: Vec<_> = {
+            let mut entries = self.entries.lock();
+            let due = entries
+                .iter_mut()
+                .filter(|e| e.next.is_some_and(|next| next <= now))
+                .map(|e| {
+                    let next =
+                        state.rng().with(|rng| e.job.next_after(now, rng));
+                    e.next = Some(next);
+                    e.job.clone()
+                })
+                .collect();
+            // Never due again.
+            entries.retain(|e| e.next != Some(NaiveDateTime::MAX));
+            due
+        };
+        for job in due {
+            // Marked before it runs, so a crash while it runs does not run it
+            // again on the next start.
+            let marker = ScheduledJob {
+                name: job.name.clone(),
+                last_run: now.timestamp(),
+            };
+            marker.save(state.pool()).await?;
+            match (job.run)(state).await {
+                Ok(()) => tracing::debug!(job = %job.name, "Ran scheduled job"),
+                Err(error) => {
+                    tracing::warn!(%error, job = %job.name, "Scheduled job failed")
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Registers the jobs every server runs: the autosave, on the
+/// `AUTOSAVE_SCHEDULE` or every [`AUTOSAVE_INTERVAL`], the world snapshot,
+/// on the `SNAPSHOT_SCHEDULE` or every [`SNAPSHOT_INTERVAL`], and the restart
+/// warning if the `RESTART_WARNING_SCHEDULE` is configured.
+pub fn register_builtin_jobs(scheduler: &Scheduler) -> Result<(), Error> {
+    let autosave = match dotenvy::var("AUTOSAVE_SCHEDULE") {
+        Ok(schedule) => schedule.parse()?,
+        Err(_) => Schedule::Every(AUTOSAVE_INTERVAL),
+    };
+    scheduler.register(Job::new("autosave", autosave, |state| {
+        state.save_all().boxed()
+    }));
+    let snapshot = match dotenvy::var("SNAPSHOT_SCHEDULE") {
+        Ok(schedule) => schedule.parse()?,
+        Err(_) => Schedule::Every(SNAPSHOT_INTERVAL),
+    };
+    scheduler.register(Job::new("world_snapshot", snapshot, |state| {
+        state.write_snapshot().boxed()
+    }));
+    if let Ok(schedule) = dotenvy::var("RESTART_WARNING_SCHEDULE") {
+        let job = Job::new("restart_warning", schedule.parse()?, |state| {
+            state.broadcast(MsgTalk::announce(RESTART_WARNING)).boxed()
+        });
+        scheduler.register(job);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// 2024-01-01 is a Monday.
+    fn at(time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    /// A job that counts its runs.
+    fn counting(schedule: Schedule) -> (Job, Arc<AtomicUsize>) {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = runs.clone();
+        let job = Job::new("counting", schedule, move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            async { Ok(()) }.boxed()
+        });
+        (job, runs)
+    }
+
+    #[test]
+    fn schedules_parse_and_repeat() {
+        let four = NaiveTime::from_hms_opt(4, 0, 0).unwrap();
+        let every: Schedule = "every 5m".parse().unwrap();
+        assert_eq!(every, Schedule::Every(Duration::from_secs(300)));
+        assert_eq!(
+            every.next_after(at("2024-01-01 10:00")),
+            at("2024-01-01 10:05")
+        );
+
+        let daily: Schedule = "daily 04:00".parse().unwrap();
+        assert_eq!(daily, Schedule::Daily(four));
+        assert_eq!(
+            daily.next_after(at("2024-01-01 03:00")),
+            at("2024-01-01 04:00")
+        );
+        assert_eq!(
+            daily.next_after(at("2024-01-01 04:00")),
+            at("2024-01-02 04:00")
+        );
+
+        let weekly: Schedule = "weekly sat 20:00".parse().unwrap();
+        assert_eq!(
+            weekly.next_after(at("2024-01-01 10:00")),
+            at("2024-01-06 20:00")
+        );
+        assert_eq!(
+            weekly.next_after(at("2024-01-06 20:00")),
+            at("2024-01-13 20:00")
+        );
+
+        let once: Schedule = "once 2024-12-25 00:00".parse().unwrap();
+        assert_eq!(
+            once.next_after(at("2024-12-24 10:00")),
+            at("2024-12-25 00:00")
+        );
+        assert_eq!(once.next_after(at("2024-12-25 00:00")), NaiveDateTime::MAX);
+
+        for invalid in [
+            "every 0s",
+            "every 5d",
+            "daily 25:00",
+            "weekly 20:00",
+            "hourly",
+        ] {
+            assert!(invalid.parse::<Schedule>().is_err(), "{invalid}");
+        }
+
+        let (job, _) = counting(every);
+        let job = job.with_jitter(Duration::from_secs(30));
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut replayed = StdRng::seed_from_u64(7);
+        for _ in 0..10 {
+            let next = job.next_after(at("2024-01-01 10:00"), &mut rng);
+            assert!(next >= at("2024-01-01 10:05"));
+            assert!(
+                next <= at("2024-01-01 10:05") + chrono::Duration::seconds(30)
+            );
+            // The same seed runs the job at the same time.
+            let again = job.next_after(at("2024-01-01 10:00"), &mut replayed);
+            assert_eq!(next, again);
+        }
+    }
+
+    #[tokio::test]
+    async fn missed_runs_catch_up_once_after_restart() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, _| {
+            async move {
+                let daily = "daily 04:00".parse()?;
+                let scheduler = Scheduler::new();
+                let (job, runs) = counting(daily);
+                scheduler.register(job);
+                scheduler.tick(&state, at("2024-01-01 03:00")).await?;
+                assert_eq!(runs.load(Ordering::Relaxed), 0);
+                scheduler.tick(&state, at("2024-01-01 04:00")).await?;
+                assert_eq!(runs.load(Ordering::Relaxed), 1);
+
+                // The server is down from Monday to Wednesday, missing the
+                // runs of Tuesday and Wednesday.
+                let restarted = Scheduler::new();
+                let (job, runs) = counting(daily);
+                restarted.register(job);
+                restarted.tick(&state, at("2024-01-03 10:00")).await?;
+                assert_eq!(runs.load(Ordering::Relaxed), 1);
+                restarted.tick(&state, at("2024-01-03 10:01")).await?;
+                assert_eq!(runs.load(Ordering::Relaxed), 1);
+                assert_eq!(
+                    restarted.next_run("counting"),
+                    Some(at("2024-01-04 04:00"))
+                );
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn runs_are_not_repeated_after_restart() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, _| {
+            async move {
+                let every = "every 1h".parse()?;
+                let scheduler = Scheduler::new();
+                let (job, runs) = counting(every);
+                scheduler.register(job);
+                // Never ran, so it waits for its first hour.
+                scheduler.tick(&state, at("2024-01-01 10:00")).await?;
+                scheduler.tick(&state, at("2024-01-01 10:30")).await?;
+                assert_eq!(runs.load(Ordering::Relaxed), 0);
+                scheduler.tick(&state, at("2024-01-01 11:00")).await?;
+                assert_eq!(runs.load(Ordering::Relaxed), 1);
+
+                let restarted = Scheduler::new();
+                let (job, runs) = counting(every);
+                restarted.register(job);
+                restarted.tick(&state, at("2024-01-01 11:10")).await?;
+                assert_eq!(runs.load(Ordering::Relaxed), 0);
+                restarted.tick(&state, at("2024-01-01 12:00")).await?;
+                assert_eq!(runs.load(Ordering::Relaxed), 1);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
@@ -1,18 +1,24 @@
 use crate::entities::GameEntity;
-use crate::packets::{ActionType, MsgAction};
-use crate::Error;
+use crate::packets::{ActionType, MsgAction, MsgMapItem};
+use crate::sync::RwLock;
+use crate::{systems, Error};
 use arc_swap::ArcSwapWeak;
 use futures::stream::FuturesUnordered;
 use futures::{FutureExt, StreamExt};
-use parking_lot::RwLock;
 use primitives::Location;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::sync::{Arc, Weak};
+use std::time::Duration;
 use tq_network::{ActorHandle, PacketEncode, PacketID};
 use tracing::debug;
 
 type Entities = RwLock<HashMap<u32, Weak<GameEntity>>>;
+
+/// How long a character has to wait between two screen refreshes, see
+/// [`Screen::refresh`].
+pub const SCREEN_REFRESH_COOLDOWN: Duration = Duration::from_secs(2);
+
 /// This struct encapsulates the client's screen system. It handles screen
 /// objects that the player can currently see in the client window as they
 /// enter, move, and leave the screen. It controls the distribution of packets
@@ -43,6 +49,14 @@ impl Screen {
             .map(|c| c.id())
     }
 
+    /// Returns the character that owns this screen.
+    pub fn try_character(&self) -> Result<Arc<GameEntity>, Error> {
+        self.character
+            .load()
+            .upgrade()
+            .ok_or(Error::CharacterNotFound)
+    }
+
     pub fn set_character(&self, character: Weak<GameEntity>) {
         self.character.store(character);
     }
@@ -110,6 +124,14 @@ impl Screen {
                 debug!(npc = o.id(), "Added Npc to Screen");
                 Ok(true)
             },
+            GameEntity::FloorItem(o) => {
+                debug!(item = o.id(), "Added Floor Item to Screen");
+                Ok(true)
+            },
+            GameEntity::Monster(o) => {
+                debug!(monster = o.id(), "Added Monster to Screen");
+                Ok(true)
+            },
         }
     }
 
@@ -136,6 +158,14 @@ impl Screen {
                 debug!(npc = o.id(), "Removed Npc from Screen");
                 Ok(true)
             },
+            GameEntity::FloorItem(o) => {
+                debug!(item = o.id(), "Removed Floor Item from Screen");
+                Ok(true)
+            },
+            GameEntity::Monster(o) => {
+                debug!(monster = o.id(), "Removed Monster from Screen");
+                Ok(true)
+            },
         }
     }
 
@@ -162,6 +192,26 @@ impl Screen {
         Ok(true)
     }
 
+    /// Sends the spawn of everything in the screen again, for a client that
+    /// lost track of them, like after a lag spike. Returns how many got
+    /// sent.
+    #[tracing::instrument(skip(self), fields(me = self.owner.id()))]
+    pub async fn refresh(&self) -> Result<usize, Error> {
+        let me = self
+            .character
+            .load()
+            .upgrade()
+            .ok_or(Error::CharacterNotFound)?;
+        let observers: Vec<_> = self.with_entities(|c| {
+            c.values().filter_map(|v| v.upgrade()).collect()
+        });
+        for o in &observers {
+            o.send_spawn(&me).await?;
+        }
+        tracing::trace!(count = observers.len(), "Refreshed the screen");
+        Ok(observers.len())
+    }
+
     /// This method removes the owner from all observers. It makes use of the
     /// delete method (general action subtype packet) to forcefully remove
     /// the owner from each screen within the owner's screen distance.
@@ -187,11 +237,13 @@ impl Screen {
                         };
                         tasks.spawn(fut);
                     },
-                    GameEntity::Npc(_) => {
-                        tracing::trace!(npc = o.id(), "Found Npc Observer");
-                        // Npc's don't need to be removed from the screen.
-                        // They are removed when the npc is removed from the
-                        // map.
+                    GameEntity::Npc(_)
+                    | GameEntity::FloorItem(_)
+                    | GameEntity::Monster(_) => {
+                        tracing::trace!(id = o.id(), "Found Non-Character");
+                        // Npc's, floor items and monsters don't need to be
+                        // removed from the screen. They are removed when they
+                        // are removed from the map.
                         continue;
                     },
                 }
@@ -270,6 +322,27 @@ impl Screen {
                             .boxed();
                             futures.push(fut);
                         },
+                        GameEntity::FloorItem(_) | GameEntity::Monster(_)
+                            if can_see(&o, &myself) =>
+                        {
+                            let o = o.clone();
+                            let me = entity.clone();
+                            let fut = async move {
+                                let added =
+                                    self.insert_entity(Arc::downgrade(&o))?;
+                                if added {
+                                    o.send_spawn(&me).await?;
+                                }
+                                Result::<_, Error>::Ok(())
+                            }
+                            .boxed();
+                            futures.push(fut);
+                        },
+                        GameEntity::FloorItem(_) | GameEntity::Monster(_) => {
+                            // Items and monsters that are not in the owner's
+                            // screen distance are not loaded into the screen.
+                            continue;
+                        },
                         GameEntity::Npc(_) => {
                             let o = o.clone();
                             let me = entity.clone();
@@ -316,42 +389,45 @@ impl Screen {
     /// act as "send to all" method, this method sends a packet to
     /// each observing client in the owner's screen; however, if the player
     /// is invisible, the message packet will be sent, regardless.
+    ///
+    /// Observers whose client is gone are dropped from the screen, their ids
+    /// are returned so they could be forgotten elsewhere too.
     #[tracing::instrument(skip(self, packet), fields(me = self.owner.id(), packet_id = P::PACKET_ID))]
-    pub async fn send_message<P>(&self, packet: P) -> Result<(), P::Error>
+    pub async fn send_message<P>(&self, packet: P) -> Result<Vec<u32>, P::Error>
     where
         P: PacketEncode + PacketID + Clone,
     {
-        let futures = FuturesUnordered::new();
-        self.with_entities(|c| {
-            let iter = c
-                .values()
-                .filter_map(|v| v.upgrade().and_then(|o| o.owner()));
-            for o in iter {
-                let packet = packet.clone();
-                let fut = async move {
-                    o.send(packet).await?;
-                    Result::<_, P::Error>::Ok(())
-                };
-                futures.push(fut);
-            }
+        self.send_message_with(|_| packet.clone()).await
+    }
+
+    /// Like [`Screen::send_message`], but every observer gets the packet
+    /// `f` makes for them, like an item highlighted only for its owner.
+    #[tracing::instrument(skip(self, f), fields(me = self.owner.id(), packet_id = P::PACKET_ID))]
+    pub async fn send_message_with<P, F>(
+        &self,
+        f: F,
+    ) -> Result<Vec<u32>, P::Error>
+    where
+        P: PacketEncode + PacketID,
+        F: Fn(&GameEntity) -> P,
+    {
+        let sends: Vec<_> = self.with_entities(|c| {
+            c.values()
+                .filter_map(|v| v.upgrade())
+                .filter_map(|o| {
+                    let owner = o.owner()?;
+                    let packet = f(&o);
+                    Some((o.id(), async move { owner.try_send(packet).await }))
+                })
+                .collect()
         });
-        // await all futures to complete.
-        futures
-            .for_each_concurrent(None, |res| async {
-                match res {
-                    Ok(_) => {},
-                    Err(e) => {
-                        tracing::error!(error = ?e, "Failed to send message");
-                    },
-                }
-            })
-            .await;
+        let dead = systems::fan_out(sends).await;
         // take a moment to clean up any weak references that may have been
-        // dropped.
+        // dropped, and the observers that are gone.
         self.with_entities_mut(|c| {
-            c.retain(|_, v| v.upgrade().is_some());
+            c.retain(|id, v| !dead.contains(id) && v.upgrade().is_some());
         });
-        Ok(())
+        Ok(dead)
     }
 
     /// This method sends a movement packet to all observers that fall within
@@ -362,6 +438,11 @@ impl Screen {
     /// owner will send the movement packet to it. If the observer is not
     /// within the new screen distance, the method will attempt to remove it
     /// from the owner's screen.
+    ///
+    /// With a [`State::movement_window`](crate::State::movement_window), the
+    /// movement packets are held back in the
+    /// [`MovementBatch`](crate::systems::MovementBatch) of the map
+    /// instead, anything else sent to an observer flushes its batch first.
     #[tracing::instrument(skip(self, state, packet), fields(me = self.owner.id(), packet_id = P::PACKET_ID))]
     pub async fn send_movement<P>(
         &self,
@@ -370,7 +451,12 @@ impl Screen {
     ) -> Result<(), Error>
     where
         P: PacketEncode + PacketID + Clone + Send + Sync + 'static,
+        Error: From<P::Error>,
     {
+        let batched = !state.movement_window().is_zero();
+        // The observers are clients of the same server as ours, they all go
+        // through the same codec.
+        let encoded = packet.encode_with(self.owner.codec())?;
         let entity = self
             .character
             .load()
@@ -378,6 +464,7 @@ impl Screen {
             .ok_or(Error::CharacterNotFound)?;
         let me = entity.as_character().ok_or(Error::CharacterNotFound)?;
         let mymap = state.try_map(me.entity().map_id())?;
+        let mymap = &*mymap;
         let loc = me.entity().location();
         let myreagions = mymap.surrunding_regions(loc.x, loc.y);
         let futures = FuturesUnordered::new();
@@ -396,18 +483,25 @@ impl Screen {
                         },
                         GameEntity::Character(c) if can_see(&o, &myself) => {
                             let packet = packet.clone();
+                            let encoded = encoded.clone();
                             let o = o.clone();
                             let oowner = c.owner();
                             let fut = async move {
                                 let added =
                                     self.insert_entity(Arc::downgrade(&o))?;
+                                let movements = mymap.movements();
                                 // new, let's exchange spawn packets
                                 if added {
                                     tracing::trace!(
                                         observer = o.id(),
                                         "Loaded Into Screen",
                                     );
+                                    // Whatever they saw before goes first.
+                                    movements.flush_observer(o.id()).await?;
+                                    movements.flush_observer(me.id()).await?;
                                     me.exchange_spawn_packets(&o).await?;
+                                } else if batched {
+                                    movements.push(o.id(), &oowner, encoded);
                                 } else {
                                     // observer is already there, send the
                                     // movement
@@ -421,6 +515,7 @@ impl Screen {
                         },
                         GameEntity::Character(c) => {
                             let packet = packet.clone();
+                            let encoded = encoded.clone();
                             let oowner = c.owner();
                             let observer_id = o.id();
                             let Ok(oscreen) = c.try_screen() else {
@@ -434,11 +529,20 @@ impl Screen {
                                         observer = observer_id,
                                         "UnLoaded Screen"
                                     );
-                                    // send the last packet.
-                                    oowner
-                                        .send(packet)
-                                        .await
-                                        .unwrap_or_default();
+                                    // send the last packet, after the ones
+                                    // held back.
+                                    if batched {
+                                        mymap.movements().push(
+                                            observer_id,
+                                            &oowner,
+                                            encoded,
+                                        );
+                                    } else {
+                                        oowner
+                                            .send(packet)
+                                            .await
+                                            .unwrap_or_default();
+                                    }
                                 }
                                 if self.remove_entity(observer_id)? {
                                     tracing::trace!(
@@ -476,6 +580,56 @@ impl Screen {
                             // Remove it from the screen.
                             let _ = self.remove_entity(o.id());
                         },
+                        GameEntity::FloorItem(_) | GameEntity::Monster(_)
+                            if can_see(&o, &myself) =>
+                        {
+                            let fut = async move {
+                                let added =
+                                    self.insert_entity(Arc::downgrade(&o))?;
+                                if added {
+                                    tracing::trace!(
+                                        item = o.id(),
+                                        "Loaded Into Screen"
+                                    );
+                                    o.send_spawn(&myself).await?;
+                                }
+                                Result::<_, Error>::Ok(())
+                            }
+                            .boxed();
+                            futures.push(fut);
+                        },
+                        GameEntity::FloorItem(item) => {
+                            // The item went out of the screen, hide it.
+                            if let Ok(true) = self.remove_entity(o.id()) {
+                                let msg = MsgMapItem::delete(item);
+                                let owner = self.owner.clone();
+                                let fut = async move {
+                                    owner.send(msg).await?;
+                                    Result::<_, Error>::Ok(())
+                                }
+                                .boxed();
+                                futures.push(fut);
+                            }
+                        },
+                        GameEntity::Monster(monster) => {
+                            // The monster went out of the screen, hide it.
+                            if let Ok(true) = self.remove_entity(o.id()) {
+                                let msg = MsgAction::new(
+                                    monster.id(),
+                                    monster.id(),
+                                    0,
+                                    0,
+                                    ActionType::LeaveMap,
+                                );
+                                let owner = self.owner.clone();
+                                let fut = async move {
+                                    owner.send(msg).await?;
+                                    Result::<_, Error>::Ok(())
+                                }
+                                .boxed();
+                                futures.push(fut);
+                            }
+                        },
                     }
                 }
             });
@@ -511,3 +665,109 @@ fn can_see_npc(a: &GameEntity, b: &GameEntity) -> bool {
     let Location { x: x2, y: y2, .. } = b.basic().location();
     tq_math::in_range((x1, y1), (x2, y2), 16)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::{FloorItem, FloorItemKind};
+    use crate::packets::MapItemMode;
+    use crate::test_utils::*;
+    use crate::world::Maps;
+    use primitives::Size;
+
+    #[tokio::test]
+    async fn only_the_owner_sees_the_item_as_theirs() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let map_id = u32::from(Maps::Arena);
+                let map = state.try_map(map_id)?;
+                map.load_blank(Size::new(100, 100)).await?;
+                let [(dropper, _), (owner, mut owner_rx)] = actors;
+                let (bystander, mut bystander_rx) =
+                    make_extra_actor(&state, "bystander").await?;
+                for (i, actor) in
+                    [&dropper, &owner, &bystander].iter().enumerate()
+                {
+                    let e = actor.entity();
+                    e.basic().set_map_id(map_id);
+                    e.basic().set_location(Location::new(40 + i as u16, 40, 0));
+                    map.insert_entity(e).await?;
+                }
+                dropper.screen().load_surroundings(&state).await?;
+
+                let item = FloorItem::new(
+                    state.ids().allocate(crate::state::IdKind::FloorItem)?,
+                    FloorItemKind::Silver(500),
+                    map_id,
+                    Location::new(40, 41, 0),
+                )
+                .with_owner(owner.entity().id());
+                dropper
+                    .screen()
+                    .send_message_with(|o| {
+                        MsgMapItem::create_for(&item, o.id())
+                    })
+                    .await?;
+
+                let owned = packets_of::<MsgMapItem>(&mut owner_rx);
+                let seen = packets_of::<MsgMapItem>(&mut bystander_rx);
+                assert_eq!(owned.len(), 1);
+                assert_eq!(seen.len(), 1);
+                assert_eq!(owned[0].mode(), MapItemMode::Owned);
+                assert_eq!(seen[0].mode(), MapItemMode::Normal);
+                assert_eq!(owned[0].id, seen[0].id);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn dead_observer_does_not_stop_the_others() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let map_id = u32::from(Maps::Arena);
+                let map = state.try_map(map_id)?;
+                map.load_blank(Size::new(100, 100)).await?;
+                let [(sender, _), (first, mut first_rx)] = actors;
+                let (dead, dead_rx) = make_extra_actor(&state, "third").await?;
+                let (last, mut last_rx) =
+                    make_extra_actor(&state, "fourth").await?;
+                for (i, actor) in
+                    [&sender, &first, &dead, &last].iter().enumerate()
+                {
+                    let e = actor.entity();
+                    e.basic().set_map_id(map_id);
+                    e.basic().set_location(Location::new(40 + i as u16, 40, 0));
+                    map.insert_entity(e).await?;
+                }
+                sender.screen().load_surroundings(&state).await?;
+                let dead_id = dead.entity().id();
+                let in_screen =
+                    |id| sender.screen().with_entities(|c| c.contains_key(&id));
+                assert!(in_screen(dead_id));
+                // The client of the middle one goes away.
+                drop(dead_rx);
+                while first_rx.try_recv().is_ok() {}
+                while last_rx.try_recv().is_ok() {}
+
+                let msg = MsgAction::new(
+                    sender.entity().id(),
+                    0,
+                    0,
+                    0,
+                    ActionType::ChangeFacing,
+                );
+                let gone = sender.screen().send_message(msg).await?;
+                assert_eq!(gone, vec![dead_id]);
+                assert!(first_rx.try_recv().is_ok());
+                assert!(last_rx.try_recv().is_ok());
+                assert!(!in_screen(dead_id));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
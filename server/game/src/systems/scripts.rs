@@ -0,0 +1,592 @@
+use crate::entities::{Character, Flags};
+use crate::packets::{
+    ActionType, MsgAction, MsgItem, MsgItemInfo, MsgTalk, MsgTaskDialog,
+    TalkChannel,
+};
+use crate::{constants, Error, State};
+use parking_lot::{Mutex, RwLock};
+use rhai::packages::{Package, StandardPackage};
+use rhai::{Dynamic, Engine, EvalAltResult, Scope, AST};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tq_db::item::Item;
+
+/// How many operations a script could run on every call before getting
+/// terminated, enough for any dialog while stopping a runaway loop right
+/// away.
+pub const SCRIPT_OPERATIONS_BUDGET: u64 = 100_000;
+
+/// The function a script defines to handle a player talking to its NPC.
+const TALK: &str = "talk";
+
+/// The function a script defines to handle a player picking a dialog option.
+const ANSWER: &str = "answer";
+
+/// What a script asked for, carried out through the [`State`] and the
+/// [`Character`] once the script is done running.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptAction {
+    /// Adds a line to the dialog of the NPC.
+    Say(String),
+    /// Adds an option to the dialog of the NPC.
+    Option(u8, String),
+    GiveItem(u32),
+    TakeItem(u32),
+    Teleport(u32, u16, u16),
+    Broadcast(String),
+    OpenWarehouse,
+}
+
+/// The player talking to a scripted NPC, as seen by the script.
+///
+/// The script only gets a snapshot of the player, whatever it asks for is
+/// recorded as [`ScriptAction`]s.
+#[derive(Debug, Clone)]
+pub struct Player {
+    name: String,
+    level: u16,
+    silver: u64,
+    class: u8,
+    rebirths: u8,
+    /// The bits of the [`Flags`] of the character.
+    flags: u64,
+    /// The types of the items in the inventory.
+    items: Vec<u32>,
+    actions: Arc<Mutex<Vec<ScriptAction>>>,
+}
+
+impl Player {
+    /// Takes a snapshot of the character and its inventory.
+    pub async fn of(state: &State, me: &Character) -> Result<Self, Error> {
+        let items = Item::inventory_of(state.pool(), me.character_id())
+            .await?
+            .into_iter()
+            .map(|item| item.item_type as u32)
+            .collect();
+        Ok(Self {
+            name: me.entity().name().to_string(),
+            level: me.entity().level(),
+            silver: me.silver(),
+            class: me.current_class(),
+            rebirths: me.rebirths(),
+            flags: me.entity().flags().bits(),
+            items,
+            actions: Default::default(),
+        })
+    }
+
+    fn push(&mut self, action: ScriptAction) {
+        self.actions.lock().push(action);
+    }
+
+    fn take_actions(&self) -> Vec<ScriptAction> {
+        std::mem::take(&mut *self.actions.lock())
+    }
+}
+
+/// The NPC scripts, loaded from `<dir>/npcs/<npc id>.rhai`.
+///
+/// Scripts run in a sandbox: they could not reach the filesystem nor import
+/// other modules, and every call runs under [`SCRIPT_OPERATIONS_BUDGET`].
+#[derive(Debug)]
+pub struct Scripts {
+    engine: Engine,
+    dir: PathBuf,
+    npcs: RwLock<HashMap<u32, Arc<AST>>>,
+}
+
+impl Default for Scripts {
+    fn default() -> Self { Self::new("scripts") }
+}
+
+impl Scripts {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            engine: sandbox(),
+            dir: dir.into(),
+            npcs: Default::default(),
+        }
+    }
+
+    /// Loads the scripts from the `SCRIPTS_LOCATION` directory, `scripts`
+    /// by default.
+    pub fn from_env() -> Result<Self, Error> {
+        let dir = dotenvy::var("SCRIPTS_LOCATION")
+            .unwrap_or_else(|_| "scripts".to_owned());
+        let scripts = Self::new(dir);
+        scripts.reload()?;
+        Ok(scripts)
+    }
+
+    pub fn dir(&self) -> &Path { &self.dir }
+
+    /// Reads all the scripts again, replacing the loaded ones. Scripts that
+    /// do not compile are skipped.
+    ///
+    /// Returns how many got loaded.
+    pub fn reload(&self) -> Result<usize, Error> {
+        let dir = self.dir.join("npcs");
+        let mut npcs = HashMap::new();
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tracing::debug!(dir = %dir.display(), "No NPC scripts");
+                *self.npcs.write() = npcs;
+                return Ok(0);
+            },
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "rhai") {
+                continue;
+            }
+            let npc_id = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u32>().ok());
+            let Some(npc_id) = npc_id else {
+                tracing::warn!(path = %path.display(), "Not an NPC script");
+                continue;
+            };
+            let source = std::fs::read_to_string(&path)?;
+            match self.engine.compile(source) {
+                Ok(ast) => {
+                    npcs.insert(npc_id, Arc::new(ast));
+                },
+                Err(error) => {
+                    tracing::warn!(%error, path = %path.display(), "Invalid script");
+                },
+            }
+        }
+        let count = npcs.len();
+        *self.npcs.write() = npcs;
+        tracing::info!(%count, "Loaded NPC scripts");
+        Ok(count)
+    }
+
+    /// Compiles and sets the script of the given NPC.
+    pub fn insert(&self, npc_id: u32, source: &str) -> Result<(), Error> {
+        let ast = self
+            .engine
+            .compile(source)
+            .map_err(Box::<EvalAltResult>::from)?;
+        self.npcs.write().insert(npc_id, Arc::new(ast));
+        Ok(())
+    }
+
+    pub fn npc(&self, npc_id: u32) -> Option<Arc<AST>> {
+        self.npcs.read().get(&npc_id).cloned()
+    }
+
+    /// Runs the script for `player` talking to the NPC, or answering its
+    /// dialog with `option`, returns what the script asked for.
+    pub fn call(
+        &self,
+        script: &AST,
+        player: Player,
+        option: Option<u8>,
+    ) -> Result<Vec<ScriptAction>, Error> {
+        let mut scope = Scope::new();
+        let res = match option {
+            None => self.engine.call_fn::<Dynamic>(
+                &mut scope,
+                script,
+                TALK,
+                (player.clone(),),
+            ),
+            Some(option) => self.engine.call_fn::<Dynamic>(
+                &mut scope,
+                script,
+                ANSWER,
+                (player.clone(), option as i64),
+            ),
+        };
+        match res {
+            Ok(_) => Ok(player.take_actions()),
+            // Scripts without dialog options have nothing to answer.
+            Err(e)
+                if matches!(*e, EvalAltResult::ErrorFunctionNotFound(..)) =>
+            {
+                Ok(Vec::new())
+            },
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Lets the script of the NPC handle `me` talking to it, or answering its
+/// dialog with `option`. Returns `false` if the NPC has no script, so the
+/// dialogs written in Rust could handle it.
+///
+/// A failing script is logged and does nothing.
+#[tracing::instrument(skip(state, me), fields(me = me.id()))]
+pub async fn run_npc_script(
+    state: &State,
+    me: &Character,
+    npc_id: u32,
+    option: Option<u8>,
+) -> Result<bool, Error> {
+    let scripts = state.scripts();
+    let Some(script) = scripts.npc(npc_id) else {
+        return Ok(false);
+    };
+    let player = Player::of(state, me).await?;
+    match scripts.call(&script, player, option) {
+        Ok(actions) => apply(state, me, actions).await?,
+        Err(error) => tracing::warn!(%error, "NPC script failed"),
+    }
+    Ok(true)
+}
+
+/// Carries out the actions of a script, the dialog lines and options are
+/// sent as a single dialog once everything else is done.
+async fn apply(
+    state: &State,
+    me: &Character,
+    actions: Vec<ScriptAction>,
+) -> Result<(), Error> {
+    let mut lines = Vec::new();
+    let mut options = Vec::new();
+    for action in actions {
+        match action {
+            ScriptAction::Say(line) => lines.push(line),
+            ScriptAction::Option(id, text) => options.push((id, text)),
+            ScriptAction::GiveItem(item_type) => {
+                give_item(state, me, item_type).await?
+            },
+            ScriptAction::TakeItem(item_type) => {
+                // Whatever the script meant to give for it is not given.
+                if !take_item(state, me, item_type).await? {
+                    return tell(me, "You do not have the required item.")
+                        .await;
+                }
+            },
+            ScriptAction::Teleport(map_id, x, y) => {
+                let now = tokio::time::Instant::now().into_std();
+                if super::ensure_out_of_combat(state, me, now).await? {
+                    me.teleport(state, map_id, (x, y)).await?
+                }
+            },
+            ScriptAction::Broadcast(message) => {
+                state.broadcast(MsgTalk::announce(message)).await?
+            },
+            ScriptAction::OpenWarehouse => {
+                let msg =
+                    MsgAction::from_character(me, 4, ActionType::OpenDialog);
+                me.owner().send(msg).await?;
+            },
+        }
+    }
+    if lines.is_empty() {
+        return Ok(());
+    }
+    if options.is_empty() {
+        options.push((u8::MAX, "Goodbye.".to_owned()));
+    }
+    let builder = MsgTaskDialog::builder().text(lines.join(" "));
+    let dialog = options
+        .into_iter()
+        .fold(builder, |b, (id, text)| b.with_option(id, text))
+        .and()
+        .with_avatar(47)
+        .build();
+    me.owner().send_all(dialog).await?;
+    Ok(())
+}
+
+async fn give_item(
+    state: &State,
+    me: &Character,
+    item_type: u32,
+) -> Result<(), Error> {
+    let item = Item::give(
+        state.pool(),
+        me.character_id(),
+        item_type as i32,
+        constants::INVENTORY_SIZE,
+    )
+    .await?;
+    match item {
+        Some(item) => me.owner().send(MsgItemInfo::add(me.id(), &item)).await?,
+        None => tell(me, "Your inventory is full.").await?,
+    }
+    Ok(())
+}
+
+/// Takes an item of that type from the inventory, returns `false` if there
+/// was none to take.
+async fn take_item(
+    state: &State,
+    me: &Character,
+    item_type: u32,
+) -> Result<bool, Error> {
+    let item = Item::inventory_of(state.pool(), me.character_id())
+        .await?
+        .into_iter()
+        .find(|item| item.item_type == item_type as i32);
+    let Some(item) = item else {
+        return Ok(false);
+    };
+    // Someone else may have taken it in the meantime.
+    if !item.delete(state.pool()).await? {
+        return Ok(false);
+    }
+    let msg = MsgItem::remove(me.id(), item.item_id as u32);
+    me.owner().send(msg).await?;
+    Ok(true)
+}
+
+async fn tell(me: &Character, message: &str) -> Result<(), Error> {
+    let msg = MsgTalk::from_system(me.id(), TalkChannel::TopLeft, message);
+    me.owner().send(msg).await?;
+    Ok(())
+}
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// Converts an integer from a script, failing the script if it does not
+/// fit.
+fn arg<T: TryFrom<i64>>(value: i64) -> ScriptResult<T> {
+    T::try_from(value).map_err(|_| format!("{value} is out of range").into())
+}
+
+/// The engine scripts run in, it only knows about the standard library and
+/// the [`Player`].
+fn sandbox() -> Engine {
+    // A raw engine has no module resolver, so scripts could not import
+    // anything from the filesystem.
+    let mut engine = Engine::new_raw();
+    engine.register_global_module(StandardPackage::new().as_shared_module());
+    engine
+        .set_max_operations(SCRIPT_OPERATIONS_BUDGET)
+        .set_max_call_levels(32)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(4 * constants::MAX_TXT_LEN)
+        .set_max_array_size(1024)
+        .set_max_map_size(1024)
+        .on_print(|text| tracing::debug!(%text, "Script printed"))
+        .on_debug(|text, _, pos| tracing::debug!(%text, %pos, "Script debug"));
+    engine.disable_symbol("eval");
+    engine
+        .register_type_with_name::<Player>("Player")
+        .register_get("name", |p: &mut Player| p.name.clone())
+        .register_get("level", |p: &mut Player| p.level as i64)
+        .register_get("silver", |p: &mut Player| p.silver as i64)
+        .register_get("class", |p: &mut Player| p.class as i64)
+        .register_get("rebirths", |p: &mut Player| p.rebirths as i64)
+        .register_fn("has_item", |p: &mut Player, item_type: i64| {
+            p.items.iter().any(|&t| t as i64 == item_type)
+        })
+        .register_fn("has_flag", |p: &mut Player, name: &str| {
+            Flags::from_name(&name.to_uppercase()).is_some_and(|flag| {
+                Flags::from_bits_retain(p.flags).contains(flag)
+            })
+        })
+        .register_fn("say", |p: &mut Player, line: &str| {
+            p.push(ScriptAction::Say(line.to_owned()))
+        })
+        .register_fn("option", |p: &mut Player, id: i64, text: &str| {
+            p.push(ScriptAction::Option(arg(id)?, text.to_owned()));
+            ScriptResult::Ok(())
+        })
+        .register_fn("give_item", |p: &mut Player, item_type: i64| {
+            p.push(ScriptAction::GiveItem(arg(item_type)?));
+            ScriptResult::Ok(())
+        })
+        .register_fn("take_item", |p: &mut Player, item_type: i64| {
+            p.push(ScriptAction::TakeItem(arg(item_type)?));
+            ScriptResult::Ok(())
+        })
+        .register_fn(
+            "teleport",
+            |p: &mut Player, map_id: i64, x: i64, y: i64| {
+                let action =
+                    ScriptAction::Teleport(arg(map_id)?, arg(x)?, arg(y)?);
+                p.push(action);
+                ScriptResult::Ok(())
+            },
+        )
+        .register_fn("broadcast", |p: &mut Player, message: &str| {
+            p.push(ScriptAction::Broadcast(message.to_owned()))
+        })
+        .register_fn("open_warehouse", |p: &mut Player| {
+            p.push(ScriptAction::OpenWarehouse)
+        });
+    engine
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use futures::FutureExt;
+
+    const NPC: u32 = 42;
+    const BLADE: i32 = 410_005;
+
+    #[tokio::test]
+    async fn script_gives_an_item() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                state.scripts().insert(
+                    NPC,
+                    "
+                    fn talk(player) {
+                        if !player.has_item(410005) {
+                            player.give_item(410005);
+                        }
+                    }
+                    ",
+                )?;
+
+                assert!(run_npc_script(&state, me, NPC, None).await?);
+                let inventory =
+                    Item::inventory_of(state.pool(), me.character_id()).await?;
+                assert_eq!(inventory.len(), 1);
+                assert_eq!(inventory[0].item_type, BLADE);
+
+                // The script sees the blade now, and gives nothing more.
+                assert!(run_npc_script(&state, me, NPC, None).await?);
+                let inventory =
+                    Item::inventory_of(state.pool(), me.character_id()).await?;
+                assert_eq!(inventory.len(), 1);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn nothing_is_given_for_a_missing_item() -> Result<(), Error> {
+        const POTION: i32 = 1_000_000;
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                state.scripts().insert(
+                    NPC,
+                    "
+                    fn talk(player) {
+                        player.take_item(410005);
+                        player.give_item(1000000);
+                        player.say(\"Thank you!\");
+                    }
+                    ",
+                )?;
+
+                assert!(run_npc_script(&state, me, NPC, None).await?);
+                let inventory =
+                    Item::inventory_of(state.pool(), me.character_id()).await?;
+                assert!(inventory.is_empty());
+                assert_eq!(
+                    told(&mut a_rx),
+                    ["You do not have the required item."]
+                );
+
+                // With the blade, it is traded for the potion.
+                Item::give(
+                    state.pool(),
+                    me.character_id(),
+                    BLADE,
+                    constants::INVENTORY_SIZE,
+                )
+                .await?;
+                assert!(run_npc_script(&state, me, NPC, None).await?);
+                let inventory =
+                    Item::inventory_of(state.pool(), me.character_id()).await?;
+                assert_eq!(inventory.len(), 1);
+                assert_eq!(inventory[0].item_type, POTION);
+                assert!(told(&mut a_rx).is_empty());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn endless_script_runs_out_of_budget() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let scripts = state.scripts();
+                scripts.insert(
+                    NPC,
+                    "
+                    fn talk(player) {
+                        loop { player.say(\"again\"); }
+                    }
+                    ",
+                )?;
+
+                let script = scripts.npc(NPC).unwrap();
+                let player = Player::of(&state, me).await?;
+                let res = scripts.call(&script, player, None);
+                assert!(matches!(
+                    res,
+                    Err(Error::Script(e))
+                        if matches!(*e, EvalAltResult::ErrorTooManyOperations(..))
+                ));
+                // The player does not notice anything.
+                assert!(run_npc_script(&state, me, NPC, None).await?);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn unscripted_npcs_are_left_alone() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                assert!(!run_npc_script(&state, me, NPC, None).await?);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn warehouse_script_opens_the_warehouse() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/../../scripts");
+                let scripts = Scripts::new(dir);
+                assert!(scripts.reload()? >= 1);
+                let script = scripts.npc(8).unwrap();
+
+                let player = Player::of(&state, me).await?;
+                let actions = scripts.call(&script, player, None)?;
+                assert!(matches!(actions[0], ScriptAction::Say(_)));
+                assert!(actions.contains(&ScriptAction::Option(
+                    1,
+                    "Open my warehouse.".to_owned()
+                )));
+
+                let player = Player::of(&state, me).await?;
+                let actions = scripts.call(&script, player, Some(1))?;
+                assert_eq!(actions, vec![ScriptAction::OpenWarehouse]);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
@@ -0,0 +1,165 @@
+//! What players get told about the server: the message of the day at login,
+//! and the `serverinfo` command.
+
+use crate::entities::Character;
+use crate::packets::{MsgTalk, TalkChannel};
+use crate::{Error, State};
+use std::time::{Duration, Instant};
+
+/// Loads the message of the day from the `MOTD` environment variable, one
+/// line per line of the value. Nothing is sent when it is not set.
+pub fn motd_from_env() -> Vec<String> {
+    dotenvy::var("MOTD")
+        .map(|motd| motd_lines(&motd))
+        .unwrap_or_default()
+}
+
+fn motd_lines(motd: &str) -> Vec<String> {
+    let lines = motd.lines().map(|l| l.trim_end().to_owned());
+    let mut lines: Vec<_> = lines.collect();
+    while lines.last().is_some_and(|l| l.is_empty()) {
+        lines.pop();
+    }
+    lines
+}
+
+/// Sends the message of the day to the character, if there is one.
+pub async fn send_motd(state: &State, me: &Character) -> Result<(), Error> {
+    let lines: Vec<_> = state
+        .motd()
+        .iter()
+        .map(|line| MsgTalk::from_system(me.id(), TalkChannel::System, line))
+        .collect();
+    me.owner().send_all(lines).await?;
+    Ok(())
+}
+
+/// What the `serverinfo` command shows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerInfo {
+    pub version: &'static str,
+    /// The latest migration applied to the database, if it could be told.
+    pub db_version: Option<i64>,
+    pub uptime: Duration,
+    pub online: usize,
+    /// The experience bonus on the map of whoever asked, in percent.
+    pub exp_bonus: u32,
+}
+
+impl ServerInfo {
+    pub async fn gather(state: &State, me: &Character, now: Instant) -> Self {
+        let db_version = match tq_db::version(state.pool()).await {
+            Ok(version) => version,
+            Err(error) => {
+                tracing::warn!(%error, "Failed to read the database version");
+                None
+            },
+        };
+        let exp_bonus = state
+            .map_by_id(me.entity().map_id())
+            .map_or(0, |map| map.exp_bonus());
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            db_version,
+            uptime: now.saturating_duration_since(state.started_at()),
+            online: state.online_count(),
+            exp_bonus,
+        }
+    }
+
+    pub fn lines(&self) -> [String; 4] {
+        let db_version = self
+            .db_version
+            .map_or_else(|| String::from("unknown"), |v| v.to_string());
+        [
+            format!("Version: {} (database {db_version})", self.version),
+            format!("Uptime: {}", format_uptime(self.uptime)),
+            format!("Online: {}", self.online),
+            format!("Experience bonus here: +{}%", self.exp_bonus),
+        ]
+    }
+}
+
+/// Like `1d 2h 3m`, leaving out the days and hours while there are none.
+fn format_uptime(uptime: Duration) -> String {
+    let minutes = uptime.as_secs() / 60;
+    let (days, hours, minutes) =
+        (minutes / 1440, minutes / 60 % 24, minutes % 60);
+    match (days, hours) {
+        (0, 0) => format!("{minutes}m"),
+        (0, _) => format!("{hours}h {minutes}m"),
+        _ => format!("{days}d {hours}h {minutes}m"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use futures::FutureExt;
+    use tq_network::{Message, PacketDecode, PacketID};
+
+    #[test]
+    fn uptime_is_short() {
+        let uptime = |secs| format_uptime(Duration::from_secs(secs));
+        assert_eq!(uptime(59), "0m");
+        assert_eq!(uptime(3 * 60), "3m");
+        assert_eq!(uptime(2 * 3600 + 5 * 60), "2h 5m");
+        assert_eq!(uptime(86400 + 3600), "1d 1h 0m");
+    }
+
+    #[tokio::test]
+    async fn motd_is_sent_line_by_line() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |mut state, actors| {
+            async move {
+                state.set_motd(motd_lines("Welcome!\nBe nice.\n\n"));
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                send_motd(&state, entity.as_character().unwrap()).await?;
+                let lines: Vec<_> = std::iter::from_fn(|| a_rx.try_recv().ok())
+                    .filter_map(|msg| match msg {
+                        Message::Packet(MsgTalk::PACKET_ID, bytes) => {
+                            MsgTalk::decode(&bytes).ok()
+                        },
+                        _ => None,
+                    })
+                    .map(|msg| msg.message)
+                    .collect();
+                assert_eq!(lines, ["Welcome!", "Be nice."]);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn server_info_has_everything() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let later = state.started_at() + Duration::from_secs(3 * 3600);
+                let info = ServerInfo::gather(&state, me, later).await;
+                assert_eq!(info.online, 2);
+                assert!(info.db_version.is_some());
+                let [version, uptime, online, bonus] = info.lines();
+                assert_eq!(
+                    version,
+                    format!(
+                        "Version: {} (database {})",
+                        env!("CARGO_PKG_VERSION"),
+                        info.db_version.unwrap()
+                    )
+                );
+                assert_eq!(uptime, "Uptime: 3h 0m");
+                assert_eq!(online, "Online: 2");
+                assert_eq!(bonus, "Experience bonus here: +0%");
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
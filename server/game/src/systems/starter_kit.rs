@@ -0,0 +1,136 @@
+use crate::{constants, Error};
+use sqlx::SqlitePool;
+
+/// The outcome of trying to grant a [`StarterKit`] to a character.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StarterKitGrant {
+    /// The items and silver were given to the character.
+    Granted,
+    /// The character got the kit before, nothing was given.
+    AlreadyReceived,
+    /// The inventory has no room for the kit, nothing was given and the
+    /// character gets another chance on the next login.
+    InventoryFull,
+}
+
+/// The items and silver every new character gets once, on its first login.
+///
+/// It could be configured using the `STARTER_KIT_ITEMS` environment variable
+/// as a comma separated list of `item_type:quantity`, and the
+/// `STARTER_KIT_SILVER` environment variable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StarterKit {
+    items: Vec<(u32, u8)>,
+    silver: u32,
+}
+
+impl Default for StarterKit {
+    fn default() -> Self {
+        Self {
+            // Stancher and Agrypnotic potions.
+            items: vec![(1000000, 5), (1001000, 5)],
+            silver: 500,
+        }
+    }
+}
+
+impl StarterKit {
+    pub fn new() -> Self {
+        Self {
+            items: Vec::new(),
+            silver: 0,
+        }
+    }
+
+    /// Loads the kit from the environment, falling back to the default kit
+    /// for anything that is not configured.
+    pub fn from_env() -> Result<Self, Error> {
+        let mut kit = Self::default();
+        if let Ok(items) = dotenvy::var("STARTER_KIT_ITEMS") {
+            kit.items = Self::parse_items(&items)?;
+        }
+        if let Ok(silver) = dotenvy::var("STARTER_KIT_SILVER") {
+            kit.silver = silver.trim().parse()?;
+        }
+        Ok(kit)
+    }
+
+    /// Adds `quantity` items of the given type to the kit.
+    pub fn with_item(mut self, item_type: u32, quantity: u8) -> Self {
+        self.items.push((item_type, quantity));
+        self
+    }
+
+    /// Sets the silver given with the kit.
+    pub fn with_silver(mut self, silver: u32) -> Self {
+        self.silver = silver;
+        self
+    }
+
+    pub fn items(&self) -> &[(u32, u8)] { &self.items }
+
+    pub fn silver(&self) -> u32 { self.silver }
+
+    /// Gives the kit to the character if it did not get it before.
+    ///
+    /// The character is updated in place, so it should be called before
+    /// spawning the character into the world.
+    #[tracing::instrument(skip(self, pool, character), fields(character_id = character.character_id))]
+    pub async fn grant(
+        &self,
+        pool: &SqlitePool,
+        character: &mut tq_db::character::Character,
+    ) -> Result<StarterKitGrant, Error> {
+        if character.received_starter_kit {
+            return Ok(StarterKitGrant::AlreadyReceived);
+        }
+        let item_types: Vec<_> = self
+            .items
+            .iter()
+            .flat_map(|&(item_type, quantity)| {
+                (0..quantity).map(move |_| item_type as i32)
+            })
+            .collect();
+        let granted = character
+            .grant_starter_kit(
+                pool,
+                &item_types,
+                self.silver as i64,
+                constants::INVENTORY_SIZE,
+            )
+            .await?;
+        if granted {
+            tracing::debug!("Granted the starter kit");
+            Ok(StarterKitGrant::Granted)
+        } else {
+            tracing::warn!("No room in the inventory for the starter kit");
+            Ok(StarterKitGrant::InventoryFull)
+        }
+    }
+
+    fn parse_items(items: &str) -> Result<Vec<(u32, u8)>, Error> {
+        items
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| match item.split_once(':') {
+                Some((item_type, quantity)) => {
+                    Ok((item_type.trim().parse()?, quantity.trim().parse()?))
+                },
+                None => Ok((item.parse()?, 1)),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_starter_kit_items() {
+        let items = StarterKit::parse_items("1000000:5, 410301,").unwrap();
+        assert_eq!(items, [(1000000, 5), (410301, 1)]);
+        assert!(StarterKit::parse_items("1000000:many").is_err());
+    }
+}
@@ -0,0 +1,259 @@
+use crate::entities::Flags;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How often the status effects of every character get checked for expiry.
+pub const STATUS_EFFECTS_TICK: Duration = Duration::from_secs(1);
+
+/// The kinds of temporary effects a character could be under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EffectKind {
+    /// Makes the character move faster.
+    Speed,
+    /// Increases the attack of the character.
+    AttackBoost,
+    /// Increases the defense of the character.
+    DefenseBoost,
+    /// Increases the accuracy of the character.
+    Accuracy,
+    /// Hurts the character on every tick.
+    Poison,
+    /// The damage boost of the Superman XP skill.
+    Superman,
+}
+
+/// What happens when an effect gets applied while it is still active.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stacking {
+    /// The new effect replaces the old one, restarting its timer.
+    Refresh,
+    /// Another stack is added, up to `max` stacks, and the timer restarts.
+    Stack { max: u8 },
+    /// The new effect is ignored until the old one expires.
+    Ignore,
+}
+
+/// The stats that could be changed by status effects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stat {
+    Attack,
+    Defense,
+    Speed,
+    Accuracy,
+}
+
+impl EffectKind {
+    pub const fn stacking(self) -> Stacking {
+        match self {
+            Self::Speed
+            | Self::AttackBoost
+            | Self::DefenseBoost
+            | Self::Superman => Stacking::Refresh,
+            Self::Accuracy => Stacking::Ignore,
+            Self::Poison => Stacking::Stack { max: 3 },
+        }
+    }
+
+    /// The flag the client uses to show that effect on the character.
+    pub const fn flag(self) -> Flags {
+        match self {
+            Self::Speed => Flags::CYCLONE,
+            Self::AttackBoost => Flags::STIGMA,
+            Self::DefenseBoost => Flags::SHIELD,
+            Self::Accuracy => Flags::STAR_OF_ACCURACY,
+            Self::Poison => Flags::POISONED,
+            Self::Superman => Flags::SUPERMAN,
+        }
+    }
+
+    /// The stat the effect changes, if any.
+    pub const fn stat(self) -> Option<Stat> {
+        match self {
+            Self::Speed => Some(Stat::Speed),
+            Self::AttackBoost | Self::Superman => Some(Stat::Attack),
+            Self::DefenseBoost => Some(Stat::Defense),
+            Self::Accuracy => Some(Stat::Accuracy),
+            Self::Poison => None,
+        }
+    }
+
+    /// The flags of all the effects.
+    pub fn all_flags() -> Flags {
+        [
+            Self::Speed,
+            Self::AttackBoost,
+            Self::DefenseBoost,
+            Self::Accuracy,
+            Self::Poison,
+            Self::Superman,
+        ]
+        .into_iter()
+        .fold(Flags::NONE, |flags, kind| flags | kind.flag())
+    }
+}
+
+/// An active status effect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatusEffect {
+    pub kind: EffectKind,
+    /// For stat effects, the percentage added to the stat for every stack.
+    /// For poison, the health points lost on every tick for every stack.
+    pub power: u16,
+    pub stacks: u8,
+    pub expires_at: Instant,
+}
+
+/// The outcome of applying a status effect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Applied {
+    /// The effect was not active before.
+    Added,
+    /// The effect was active, and got replaced by the new one.
+    Refreshed,
+    /// Another stack of the effect was added.
+    Stacked,
+    /// The effect was active and the new one got ignored.
+    Ignored,
+}
+
+/// The status effects a character is under right now.
+#[derive(Debug, Default)]
+pub struct StatusEffects {
+    effects: Mutex<HashMap<EffectKind, StatusEffect>>,
+}
+
+impl StatusEffects {
+    pub fn new() -> Self { Self::default() }
+
+    /// Applies the effect for `duration` starting from `now`, following the
+    /// stacking rules of its kind.
+    pub fn apply(
+        &self,
+        kind: EffectKind,
+        power: u16,
+        duration: Duration,
+        now: Instant,
+    ) -> Applied {
+        let expires_at = now + duration;
+        let mut effects = self.effects.lock();
+        let Some(effect) = effects.get_mut(&kind) else {
+            effects.insert(
+                kind,
+                StatusEffect {
+                    kind,
+                    power,
+                    stacks: 1,
+                    expires_at,
+                },
+            );
+            return Applied::Added;
+        };
+        match kind.stacking() {
+            Stacking::Refresh => {
+                effect.power = power;
+                effect.expires_at = expires_at;
+                Applied::Refreshed
+            },
+            Stacking::Stack { max } => {
+                effect.stacks = (effect.stacks + 1).min(max);
+                effect.expires_at = expires_at;
+                Applied::Stacked
+            },
+            Stacking::Ignore => Applied::Ignored,
+        }
+    }
+
+    pub fn get(&self, kind: EffectKind) -> Option<StatusEffect> {
+        self.effects.lock().get(&kind).copied()
+    }
+
+    pub fn is_active(&self, kind: EffectKind) -> bool {
+        self.effects.lock().contains_key(&kind)
+    }
+
+    pub fn active(&self) -> Vec<StatusEffect> {
+        self.effects.lock().values().copied().collect()
+    }
+
+    /// Removes the effects that expired by `now`, and returns their kinds.
+    pub fn expire(&self, now: Instant) -> Vec<EffectKind> {
+        let mut expired = Vec::new();
+        self.effects.lock().retain(|kind, effect| {
+            let alive = effect.expires_at > now;
+            if !alive {
+                expired.push(*kind);
+            }
+            alive
+        });
+        expired
+    }
+
+    /// The flags of all the active effects.
+    pub fn flags(&self) -> Flags {
+        self.effects
+            .lock()
+            .keys()
+            .fold(Flags::NONE, |flags, kind| flags | kind.flag())
+    }
+
+    /// Applies the active effects to the given base value of a stat.
+    pub fn fold(&self, stat: Stat, base: u32) -> u32 {
+        let percent: u32 = self
+            .effects
+            .lock()
+            .values()
+            .filter(|effect| effect.kind.stat() == Some(stat))
+            .map(|effect| effect.power as u32 * effect.stacks as u32)
+            .sum();
+        base.saturating_mul(100 + percent) / 100
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn refresh_replaces_timer() {
+        let effects = StatusEffects::new();
+        let start = Instant::now();
+        let kind = EffectKind::AttackBoost;
+        assert_eq!(effects.apply(kind, 20, 10 * SECOND, start), Applied::Added);
+        let later = start + 5 * SECOND;
+        assert_eq!(
+            effects.apply(kind, 30, 10 * SECOND, later),
+            Applied::Refreshed
+        );
+        let effect = effects.get(kind).unwrap();
+        assert_eq!(effect.expires_at, later + 10 * SECOND);
+        assert_eq!(effect.power, 30);
+        // The first timer would have expired by now.
+        assert!(effects.expire(start + 12 * SECOND).is_empty());
+        assert_eq!(effects.fold(Stat::Attack, 100), 130);
+        assert_eq!(effects.expire(later + 10 * SECOND), [kind]);
+        assert_eq!(effects.fold(Stat::Attack, 100), 100);
+    }
+
+    #[test]
+    fn stacking_rules() {
+        let effects = StatusEffects::new();
+        let now = Instant::now();
+        for _ in 0..5 {
+            effects.apply(EffectKind::Poison, 10, 10 * SECOND, now);
+        }
+        assert_eq!(effects.get(EffectKind::Poison).unwrap().stacks, 3);
+
+        let kind = EffectKind::Accuracy;
+        assert_eq!(effects.apply(kind, 10, 10 * SECOND, now), Applied::Added);
+        assert_eq!(effects.apply(kind, 50, 20 * SECOND, now), Applied::Ignored);
+        let effect = effects.get(kind).unwrap();
+        assert_eq!(effect.power, 10);
+        assert_eq!(effect.expires_at, now + 10 * SECOND);
+        // Poison does not change any stat.
+        assert_eq!(effects.fold(Stat::Accuracy, 200), 220);
+        assert_eq!(effects.fold(Stat::Defense, 200), 200);
+    }
+}
@@ -0,0 +1,391 @@
+//! The items kept in the warehouse, shared by all the characters of an
+//! account.
+//!
+//! Items go in and out at a warehouseman, one at a time. The client is shown
+//! what is in the warehouse when it is opened, and again after every item
+//! that goes in or out. Two characters of the same account could be online
+//! together, so the warehouse of an account is only ever used by one of
+//! them at a time.
+
+use super::warehouse::near_warehouse;
+use crate::entities::Character;
+use crate::packets::{MsgItem, MsgItemInfo, MsgPackage, MsgTalk, TalkChannel};
+use crate::systems::could_carry;
+use crate::{constants, Error, State};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::OwnedMutexGuard;
+use tq_db::item::Item;
+use tq_db::warehouse::WarehouseItem;
+
+/// How many items the warehouse of an account holds.
+pub const WAREHOUSE_CAPACITY: usize = 20;
+
+/// Why a warehouse action did nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageRejection {
+    NotAtWarehouse,
+    ItemNotFound,
+    WarehouseFull,
+    InventoryFull,
+    /// The item would be more than the character could carry.
+    TooHeavy,
+}
+
+impl StorageRejection {
+    /// What the player gets told.
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::NotAtWarehouse => "You have to be at a warehouseman.",
+            Self::ItemNotFound => "Item not found.",
+            Self::WarehouseFull => "Your warehouse is full.",
+            Self::InventoryFull => "Your inventory is full.",
+            Self::TooHeavy => "You can not carry any more.",
+        }
+    }
+}
+
+/// Takes turns on the warehouse of every account, so the characters of an
+/// account never move its items at the same time.
+#[derive(Debug, Default)]
+pub struct WarehouseAccess {
+    accounts: Mutex<HashMap<u32, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl WarehouseAccess {
+    /// Waits for the warehouse of the account to be free, it is held until
+    /// the guard is dropped.
+    pub async fn lock(&self, account_id: u32) -> OwnedMutexGuard<()> {
+        let turn = self.accounts.lock().entry(account_id).or_default().clone();
+        turn.lock_owned().await
+    }
+}
+
+/// Puts the item from the inventory of the character in the warehouse of
+/// its account, at the warehouseman `npc_id`.
+#[tracing::instrument(skip(state, me), fields(me = me.id()))]
+pub async fn deposit(
+    state: &State,
+    me: &Character,
+    npc_id: u32,
+    item_id: u32,
+) -> Result<(), Error> {
+    if !near_warehouse(state, me, npc_id)? {
+        return tell(me, StorageRejection::NotAtWarehouse).await;
+    }
+    let account_id = me.account_id();
+    let _turn = state.warehouse_access().lock(account_id).await;
+    let pool = state.pool();
+    let item = Item::of_character(pool, item_id as i32, me.character_id())
+        .await?
+        .filter(|item| item.position == Item::INVENTORY);
+    let Some(item) = item else {
+        return tell(me, StorageRejection::ItemNotFound).await;
+    };
+    let stored = WarehouseItem::of_account(pool, account_id as i32).await?;
+    if stored.len() >= WAREHOUSE_CAPACITY {
+        return tell(me, StorageRejection::WarehouseFull).await;
+    }
+    let deposited = WarehouseItem::deposit(
+        pool,
+        &item,
+        account_id as i32,
+        WAREHOUSE_CAPACITY,
+    )
+    .await?;
+    let Some(deposited) = deposited else {
+        // Used up or moved in the meantime.
+        return tell(me, StorageRejection::ItemNotFound).await;
+    };
+    tracing::debug!(
+        %item_id,
+        warehouse_item_id = deposited.warehouse_item_id,
+        "Deposited"
+    );
+    me.owner().send(MsgItem::remove(me.id(), item_id)).await?;
+    show_warehouse(state, me, npc_id).await
+}
+
+/// Takes the item `warehouse_item_id` out of the warehouse of the account
+/// of the character, into its inventory, at the warehouseman `npc_id`.
+#[tracing::instrument(skip(state, me), fields(me = me.id()))]
+pub async fn withdraw(
+    state: &State,
+    me: &Character,
+    npc_id: u32,
+    warehouse_item_id: u32,
+) -> Result<(), Error> {
+    if !near_warehouse(state, me, npc_id)? {
+        return tell(me, StorageRejection::NotAtWarehouse).await;
+    }
+    let account_id = me.account_id();
+    let _turn = state.warehouse_access().lock(account_id).await;
+    let pool = state.pool();
+    let stored = WarehouseItem::of_account(pool, account_id as i32).await?;
+    let Some(stored) = stored
+        .into_iter()
+        .find(|item| item.warehouse_item_id == warehouse_item_id as i32)
+    else {
+        return tell(me, StorageRejection::ItemNotFound).await;
+    };
+    let inventory = Item::inventory_of(pool, me.character_id()).await?;
+    if inventory.len() >= constants::INVENTORY_SIZE {
+        return tell(me, StorageRejection::InventoryFull).await;
+    }
+    if !could_carry(me, &inventory, stored.item_type as u32) {
+        return tell(me, StorageRejection::TooHeavy).await;
+    }
+    let item = WarehouseItem::withdraw(
+        pool,
+        stored.warehouse_item_id,
+        account_id as i32,
+        me.character_id(),
+        constants::INVENTORY_SIZE,
+    )
+    .await?;
+    let Some(item) = item else {
+        // Something else filled the inventory in the meantime.
+        return tell(me, StorageRejection::InventoryFull).await;
+    };
+    tracing::debug!(%warehouse_item_id, item_id = item.item_id, "Withdrew");
+    me.owner().send(MsgItemInfo::add(me.id(), &item)).await?;
+    show_warehouse(state, me, npc_id).await
+}
+
+/// Shows the character what is in the warehouse of its account, kept at
+/// the warehouseman `npc_id`.
+pub async fn show_warehouse(
+    state: &State,
+    me: &Character,
+    npc_id: u32,
+) -> Result<(), Error> {
+    let account_id = me.account_id() as i32;
+    let items = WarehouseItem::of_account(state.pool(), account_id).await?;
+    me.owner()
+        .send(MsgPackage::warehouse(npc_id, &items))
+        .await?;
+    Ok(())
+}
+
+async fn tell(
+    me: &Character,
+    rejection: StorageRejection,
+) -> Result<(), Error> {
+    let msg = MsgTalk::from_system(
+        me.id(),
+        TalkChannel::TopLeft,
+        rejection.message(),
+    );
+    me.owner().send(msg).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use crate::world::Maps;
+    use futures::FutureExt;
+    use primitives::Location;
+    use tokio::sync::mpsc::Receiver;
+    use tq_network::{Message, PacketID, PacketProcess};
+
+    /// The Twin City warehouseman, standing at (409, 351).
+    const WAREHOUSEMAN: u32 = 8;
+
+    /// Drains the actor's channel and returns the ids of the items in every
+    /// warehouse it was shown.
+    fn shown(rx: &mut Receiver<Message>) -> Vec<Vec<u32>> {
+        /// The size of the header and of every item in a [`MsgPackage`].
+        const HEADER: usize = 12;
+        const ITEM: usize = 20;
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .filter_map(|msg| match msg {
+                Message::Packet(MsgPackage::PACKET_ID, bytes) => Some(bytes),
+                _ => None,
+            })
+            .map(|bytes| {
+                bytes[HEADER..]
+                    .chunks(ITEM)
+                    .map(|item| {
+                        u32::from_le_bytes(item[..4].try_into().unwrap())
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn go_to_warehouse(me: &Character) {
+        let entity = me.entity();
+        entity.set_map_id(u32::from(Maps::Newplain));
+        entity.set_location(Location::new(405, 355, 0));
+    }
+
+    async fn give_item(state: &State, me: &Character) -> Result<u32, Error> {
+        let item = Item::give(
+            state.pool(),
+            me.character_id(),
+            410_005,
+            constants::INVENTORY_SIZE,
+        )
+        .await?
+        .expect("Inventory full");
+        Ok(item.item_id as u32)
+    }
+
+    #[tokio::test]
+    async fn deposited_items_are_shared_by_the_account() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, mut b_rx)] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let item_id = give_item(&state, me).await?;
+
+                deposit(&state, me, WAREHOUSEMAN, item_id).await?;
+                assert_eq!(
+                    told(&mut a_rx),
+                    [StorageRejection::NotAtWarehouse.message()]
+                );
+                go_to_warehouse(me);
+                MsgItem::warehouse_deposit(me.id(), item_id, WAREHOUSEMAN)
+                    .process(&state, &a)
+                    .await?;
+                assert!(told(&mut a_rx).is_empty());
+                let inventory =
+                    Item::inventory_of(state.pool(), me.character_id()).await?;
+                assert!(inventory.is_empty());
+                let account_id = me.account_id() as i32;
+                let stored =
+                    WarehouseItem::of_account(state.pool(), account_id).await?;
+                assert_eq!(stored.len(), 1);
+                assert_eq!(stored[0].item_type, 410_005);
+                let stored_id = stored[0].warehouse_item_id as u32;
+
+                // Someone else's account has nothing to take.
+                let b_entity = b.entity();
+                let other = b_entity.as_character().unwrap();
+                go_to_warehouse(other);
+                withdraw(&state, other, WAREHOUSEMAN, stored_id).await?;
+                assert_eq!(
+                    told(&mut b_rx),
+                    [StorageRejection::ItemNotFound.message()]
+                );
+
+                let (alt, mut alt_rx) = make_alt_actor(&state, me).await?;
+                let alt_entity = alt.entity();
+                let alt = alt_entity.as_character().unwrap();
+                go_to_warehouse(alt);
+                withdraw(&state, alt, WAREHOUSEMAN, stored_id).await?;
+                assert!(told(&mut alt_rx).is_empty());
+                let inventory =
+                    Item::inventory_of(state.pool(), alt.character_id())
+                        .await?;
+                assert_eq!(inventory.len(), 1);
+                assert_eq!(inventory[0].item_type, 410_005);
+                assert!(WarehouseItem::of_account(state.pool(), account_id)
+                    .await?
+                    .is_empty());
+                // Only once.
+                withdraw(&state, alt, WAREHOUSEMAN, stored_id).await?;
+                assert_eq!(
+                    told(&mut alt_rx),
+                    [StorageRejection::ItemNotFound.message()]
+                );
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn a_full_warehouse_takes_no_more() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                go_to_warehouse(me);
+                for _ in 1..WAREHOUSE_CAPACITY {
+                    let item_id = give_item(&state, me).await?;
+                    deposit(&state, me, WAREHOUSEMAN, item_id).await?;
+                }
+                assert!(told(&mut a_rx).is_empty());
+
+                // Both characters of the account go for the last spot at
+                // once, only one of them gets it.
+                let (alt, mut alt_rx) = make_alt_actor(&state, me).await?;
+                let alt_entity = alt.entity();
+                let alt = alt_entity.as_character().unwrap();
+                go_to_warehouse(alt);
+                let mine = give_item(&state, me).await?;
+                let theirs = give_item(&state, alt).await?;
+                let (left, right) = tokio::join!(
+                    deposit(&state, me, WAREHOUSEMAN, mine),
+                    deposit(&state, alt, WAREHOUSEMAN, theirs),
+                );
+                left?;
+                right?;
+                let mut refused = told(&mut a_rx);
+                refused.extend(told(&mut alt_rx));
+                assert_eq!(
+                    refused,
+                    [StorageRejection::WarehouseFull.message()]
+                );
+                let account_id = me.account_id() as i32;
+                let stored =
+                    WarehouseItem::of_account(state.pool(), account_id).await?;
+                assert_eq!(stored.len(), WAREHOUSE_CAPACITY);
+                let left_behind =
+                    Item::inventory_of(state.pool(), me.character_id())
+                        .await?
+                        .len()
+                        + Item::inventory_of(state.pool(), alt.character_id())
+                            .await?
+                            .len();
+                assert_eq!(left_behind, 1);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn the_warehouse_is_shown_after_every_move() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                go_to_warehouse(me);
+                show_warehouse(&state, me, WAREHOUSEMAN).await?;
+                assert_eq!(shown(&mut a_rx), [Vec::<u32>::new()]);
+
+                let first = give_item(&state, me).await?;
+                let second = give_item(&state, me).await?;
+                deposit(&state, me, WAREHOUSEMAN, first).await?;
+                deposit(&state, me, WAREHOUSEMAN, second).await?;
+                let account_id = me.account_id() as i32;
+                let stored: Vec<_> =
+                    WarehouseItem::of_account(state.pool(), account_id)
+                        .await?
+                        .iter()
+                        .map(|item| item.warehouse_item_id as u32)
+                        .collect();
+                assert_eq!(shown(&mut a_rx), [vec![stored[0]], stored.clone()]);
+
+                withdraw(&state, me, WAREHOUSEMAN, stored[0]).await?;
+                assert_eq!(shown(&mut a_rx), [vec![stored[1]]]);
+                // Nothing moved, nothing to show.
+                withdraw(&state, me, WAREHOUSEMAN, stored[0]).await?;
+                assert!(shown(&mut a_rx).is_empty());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
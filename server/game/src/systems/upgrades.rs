@@ -0,0 +1,206 @@
+use rand::Rng;
+use tq_db::item::Item;
+
+/// The highest plus level an item could be composed to.
+pub const MAX_PLUS: u8 = 9;
+
+/// The chance, in percent, of composing an item of the given plus level to
+/// the next one.
+const COMPOSE_SUCCESS: [u32; MAX_PLUS as usize] =
+    [100, 100, 90, 80, 70, 60, 45, 30, 15];
+
+/// A socket that got opened but has no gem in it yet.
+pub const OPEN_SOCKET: u8 = 255;
+
+/// A socket that was never opened.
+pub const NO_SOCKET: u8 = 0;
+
+/// Why an upgrade was refused, nothing gets consumed when it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpgradeRejection {
+    MaxPlus,
+    InvalidMinor,
+    NotAGem,
+    NoSocket,
+    SocketTaken,
+}
+
+impl UpgradeRejection {
+    /// What the player gets told.
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::MaxPlus => "This item can not be composed any further.",
+            Self::InvalidMinor => "This item can not be composed with that.",
+            Self::NotAGem => "Only gems could be socketed.",
+            Self::NoSocket => "This socket is not open.",
+            Self::SocketTaken => "This socket already has a gem in it.",
+        }
+    }
+}
+
+/// The level of the plus stone of the given item type, `None` if it is not a
+/// plus stone.
+pub fn plus_stone_level(item_type: u32) -> Option<u8> {
+    match item_type {
+        730001..=730009 => Some((item_type - 730000) as u8),
+        _ => None,
+    }
+}
+
+/// The value a gem of the given item type takes in a socket, `None` if it
+/// is not a gem. The tens are the kind of the gem and the units its grade.
+pub fn gem_of(item_type: u32) -> Option<u8> {
+    let value = item_type.checked_sub(700000)?;
+    let (kind, grade) = (value / 10, value % 10);
+    (kind <= 7 && (1..=3).contains(&grade)).then_some(value as u8)
+}
+
+/// Composes `minor` into `target`, the minor item is used up whether it
+/// works or not, returns whether the plus level went up.
+///
+/// The minor item is either a plus stone of a higher level than the target,
+/// or an item of the same type with at least the same plus.
+pub fn compose(
+    target: &mut Item,
+    minor: &Item,
+    rng: &mut impl Rng,
+) -> Result<bool, UpgradeRejection> {
+    let plus = target.plus as u8;
+    if plus >= MAX_PLUS {
+        return Err(UpgradeRejection::MaxPlus);
+    }
+    let valid = match plus_stone_level(minor.item_type as u32) {
+        _ if minor.item_id == target.item_id => false,
+        Some(level) => level > plus,
+        None => minor.item_type == target.item_type && minor.plus >= plus as _,
+    };
+    if !valid {
+        return Err(UpgradeRejection::InvalidMinor);
+    }
+    let success = rng.gen_range(0..100) < COMPOSE_SUCCESS[plus as usize];
+    if success {
+        target.plus += 1;
+    }
+    Ok(success)
+}
+
+/// Puts the gem into the given socket of the target, `1` or `2`.
+pub fn socket(
+    target: &mut Item,
+    gem: &Item,
+    position: u16,
+) -> Result<(), UpgradeRejection> {
+    let value =
+        gem_of(gem.item_type as u32).ok_or(UpgradeRejection::NotAGem)?;
+    let slot = match position {
+        1 => &mut target.gem_one,
+        2 => &mut target.gem_two,
+        _ => return Err(UpgradeRejection::NoSocket),
+    };
+    match *slot as u8 {
+        OPEN_SOCKET => {
+            *slot = value as i16;
+            Ok(())
+        },
+        NO_SOCKET => Err(UpgradeRejection::NoSocket),
+        _ => Err(UpgradeRejection::SocketTaken),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    fn item(item_id: i32, item_type: i32, plus: i16) -> Item {
+        Item {
+            item_id,
+            item_type,
+            plus,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn compose_at_max_plus_is_rejected() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let mut blade = item(1, 410020, MAX_PLUS as i16);
+        let stone = item(2, 730009, 0);
+        let res = compose(&mut blade, &stone, &mut rng);
+        assert_eq!(res, Err(UpgradeRejection::MaxPlus));
+        assert_eq!(blade.plus, MAX_PLUS as i16);
+    }
+
+    #[test]
+    fn compose_needs_a_matching_minor() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let mut blade = item(1, 410020, 2);
+        for minor in [
+            item(2, 730002, 0),
+            item(2, 410020, 1),
+            item(2, 420020, 2),
+            item(1, 410020, 2),
+        ] {
+            let res = compose(&mut blade, &minor, &mut rng);
+            assert_eq!(res, Err(UpgradeRejection::InvalidMinor), "{minor:?}");
+        }
+        assert_eq!(blade.plus, 2);
+    }
+
+    #[test]
+    fn compose_is_deterministic_with_a_seed() {
+        let run = |seed| {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+            let mut blade = item(1, 410020, 0);
+            let mut results = Vec::new();
+            while (blade.plus as u8) < MAX_PLUS && results.len() < 100 {
+                let stone = item(2, 730009, 0);
+                results.push(compose(&mut blade, &stone, &mut rng).unwrap());
+            }
+            (blade.plus, results)
+        };
+        let (plus, results) = run(7);
+        assert_eq!((plus, results.clone()), run(7));
+        assert_eq!(plus, MAX_PLUS as i16);
+        // The first two levels always work, the last ones rarely do.
+        assert!(results[0] && results[1]);
+        assert!(results.iter().any(|ok| !ok));
+        assert_eq!(results.iter().filter(|ok| **ok).count(), MAX_PLUS as usize);
+    }
+
+    #[test]
+    fn socket_into_a_full_item_is_rejected() {
+        let phoenix = item(2, 700001, 0);
+        let mut blade = Item {
+            gem_one: OPEN_SOCKET as i16,
+            ..item(1, 410020, 0)
+        };
+        assert_eq!(
+            socket(&mut blade, &phoenix, 2),
+            Err(UpgradeRejection::NoSocket)
+        );
+        assert_eq!(socket(&mut blade, &phoenix, 1), Ok(()));
+        assert_eq!(blade.gem_one, 1);
+        let dragon = item(3, 700013, 0);
+        assert_eq!(
+            socket(&mut blade, &dragon, 1),
+            Err(UpgradeRejection::SocketTaken)
+        );
+        assert_eq!(blade.gem_one, 1);
+        let stone = item(4, 730001, 0);
+        assert_eq!(
+            socket(&mut blade, &stone, 1),
+            Err(UpgradeRejection::NotAGem)
+        );
+    }
+
+    #[test]
+    fn gem_values() {
+        assert_eq!(gem_of(700001), Some(1));
+        assert_eq!(gem_of(700073), Some(73));
+        assert_eq!(gem_of(700000), None);
+        assert_eq!(gem_of(700004), None);
+        assert_eq!(gem_of(700081), None);
+        assert_eq!(gem_of(1000000), None);
+    }
+}
@@ -0,0 +1,692 @@
+use crate::entities::{Character, CharacterState};
+use crate::packets::{
+    AttributeKind, MsgItem, MsgItemInfo, MsgItemInfoEx, MsgTalk, MsgUserAttrib,
+    TalkChannel,
+};
+use crate::systems::could_carry;
+use crate::{constants, Error, State};
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use tq_db::item::Item;
+
+/// Why a vending action did nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VendingRejection {
+    /// The character could not open a stall while dead or busy.
+    CannotVend,
+    NotVending,
+    ItemNotFound,
+    InvalidPrice,
+    StallNotFound,
+    OwnStall,
+    SoldOut,
+    NotEnoughSilver,
+    InventoryFull,
+    /// The item would be more than the buyer could carry.
+    TooHeavy,
+    /// The seller could not hold any more silver.
+    SellerFull,
+}
+
+impl VendingRejection {
+    /// What the player gets told.
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::CannotVend => "You can not set up a stall right now.",
+            Self::NotVending => "You have to set up a stall first.",
+            Self::ItemNotFound => "Item not found.",
+            Self::InvalidPrice => "Invalid price.",
+            Self::StallNotFound => "The stall is closed.",
+            Self::OwnStall => "You can not buy from your own stall.",
+            Self::SoldOut => "The item is sold out.",
+            Self::NotEnoughSilver => "You do not have enough silver.",
+            Self::InventoryFull => "Your inventory is full.",
+            Self::TooHeavy => "You can not carry any more.",
+            Self::SellerFull => "The seller can not hold any more silver.",
+        }
+    }
+}
+
+/// The items a vending character put up for sale, by item id, along with
+/// their price in silver.
+///
+/// The listed items are kept in the [`Item::BOOTH`] position until they are
+/// sold or the stall closes.
+#[derive(Debug, Default)]
+pub struct Stall {
+    listings: Mutex<BTreeMap<u32, u32>>,
+    /// Held through a sale, so the buyers of a stall take turns.
+    sales: tokio::sync::Mutex<()>,
+}
+
+impl Stall {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn list(&self, item_id: u32, price: u32) {
+        self.listings.lock().insert(item_id, price);
+    }
+
+    /// Removes the item from the stall, returns its price if it was listed.
+    pub fn unlist(&self, item_id: u32) -> Option<u32> {
+        self.listings.lock().remove(&item_id)
+    }
+
+    pub fn price_of(&self, item_id: u32) -> Option<u32> {
+        self.listings.lock().get(&item_id).copied()
+    }
+
+    /// The listed items and their prices.
+    pub fn listings(&self) -> Vec<(u32, u32)> {
+        self.listings.lock().iter().map(|(&k, &v)| (k, v)).collect()
+    }
+
+    pub fn clear(&self) { self.listings.lock().clear(); }
+
+    /// Waits for the sale in progress to be done, the next one is held until
+    /// the guard is dropped.
+    pub async fn take_turn(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.sales.lock().await
+    }
+}
+
+/// Opens a stall, the character could not move until it closes it.
+#[tracing::instrument(skip_all, fields(me = me.id()))]
+pub async fn open_stall(me: &Character) -> Result<(), Error> {
+    if me.try_transition(CharacterState::Vending).is_err() {
+        return tell(me, VendingRejection::CannotVend).await;
+    }
+    me.stall().clear();
+    tracing::debug!("Opened a stall");
+    Ok(())
+}
+
+/// Closes the stall of the character, taking the unsold items back to its
+/// inventory. Does nothing if the character is not vending.
+#[tracing::instrument(skip_all, fields(me = me.id()))]
+pub async fn close_stall(state: &State, me: &Character) -> Result<(), Error> {
+    if me.state() != CharacterState::Vending {
+        return Ok(());
+    }
+    me.stall().clear();
+    let returned =
+        Item::return_from_booth(state.pool(), me.character_id(), None).await?;
+    me.try_transition(CharacterState::Alive)?;
+    tracing::debug!(%returned, "Closed the stall");
+    Ok(())
+}
+
+/// Puts the item from the inventory up for sale in the stall of `me`.
+///
+/// Returns `false` if it was refused, the character gets told why.
+#[tracing::instrument(skip(state, me), fields(me = me.id()))]
+pub async fn list_item(
+    state: &State,
+    me: &Character,
+    item_id: u32,
+    price: u32,
+) -> Result<bool, Error> {
+    if me.state() != CharacterState::Vending {
+        return refuse(me, VendingRejection::NotVending).await;
+    }
+    if price == 0 {
+        return refuse(me, VendingRejection::InvalidPrice).await;
+    }
+    let item =
+        Item::of_character(state.pool(), item_id as i32, me.character_id())
+            .await?
+            .filter(|item| item.position == Item::INVENTORY);
+    let Some(mut item) = item else {
+        return refuse(me, VendingRejection::ItemNotFound).await;
+    };
+    if !item.list_in_booth(state.pool()).await? {
+        return refuse(me, VendingRejection::ItemNotFound).await;
+    }
+    me.stall().list(item_id, price);
+    Ok(true)
+}
+
+/// Takes the item off the stall of `me`, back to its inventory.
+///
+/// Returns `false` if the item was not for sale.
+#[tracing::instrument(skip(state, me), fields(me = me.id()))]
+pub async fn unlist_item(
+    state: &State,
+    me: &Character,
+    item_id: u32,
+) -> Result<bool, Error> {
+    if me.stall().unlist(item_id).is_none() {
+        return refuse(me, VendingRejection::ItemNotFound).await;
+    }
+    let pool = state.pool();
+    Item::return_from_booth(pool, me.character_id(), Some(item_id as i32))
+        .await?;
+    Ok(true)
+}
+
+/// Shows `me` the items for sale in the stall of `seller_id`.
+#[tracing::instrument(skip(state, me), fields(me = me.id()))]
+pub async fn browse(
+    state: &State,
+    me: &Character,
+    seller_id: u32,
+) -> Result<(), Error> {
+    let Some(entity) = vending(state, me, seller_id) else {
+        return tell(me, VendingRejection::StallNotFound).await;
+    };
+    let Some(seller) = entity.as_character() else {
+        return tell(me, VendingRejection::StallNotFound).await;
+    };
+    let pool = state.pool();
+    for (item_id, price) in seller.stall().listings() {
+        let item =
+            Item::of_character(pool, item_id as i32, seller.character_id())
+                .await?;
+        if let Some(item) = item.filter(|i| i.position == Item::BOOTH) {
+            let msg = MsgItemInfoEx::booth(seller.id(), &item, price);
+            me.owner().send(msg).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Buys the item from the stall of `seller_id`, the silver goes to the
+/// seller and the item to the inventory of `me`.
+#[tracing::instrument(skip(state, me), fields(me = me.id()))]
+pub async fn buy(
+    state: &State,
+    me: &Character,
+    seller_id: u32,
+    item_id: u32,
+) -> Result<(), Error> {
+    if seller_id == me.id() {
+        return tell(me, VendingRejection::OwnStall).await;
+    }
+    let Some(entity) = vending(state, me, seller_id) else {
+        return tell(me, VendingRejection::StallNotFound).await;
+    };
+    let Some(seller) = entity.as_character() else {
+        return tell(me, VendingRejection::StallNotFound).await;
+    };
+    // Until the item is sold, so two buyers do not pay for the same one.
+    let _turn = seller.stall().take_turn().await;
+    let Some(price) = seller.stall().price_of(item_id) else {
+        return tell(me, VendingRejection::SoldOut).await;
+    };
+    let pool = state.pool();
+    let listed =
+        Item::of_character(pool, item_id as i32, seller.character_id()).await?;
+    let Some(listed) = listed else {
+        return tell(me, VendingRejection::SoldOut).await;
+    };
+    let inventory = Item::inventory_of(pool, me.character_id()).await?;
+    if inventory.len() >= constants::INVENTORY_SIZE {
+        return tell(me, VendingRejection::InventoryFull).await;
+    }
+    if !could_carry(me, &inventory, listed.item_type as u32) {
+        return tell(me, VendingRejection::TooHeavy).await;
+    }
+    let price = price as u64;
+    if me.spend_silver(price).is_err() {
+        return tell(me, VendingRejection::NotEnoughSilver).await;
+    }
+    // Paid before the item moves, so a seller that could not hold the
+    // silver does not give the item away for nothing.
+    if seller.add_silver(price).is_err() {
+        me.add_silver(price)?;
+        return tell(me, VendingRejection::SellerFull).await;
+    }
+    let sold = Item::sell(
+        pool,
+        item_id as i32,
+        seller.character_id(),
+        me.character_id(),
+        constants::INVENTORY_SIZE,
+    )
+    .await;
+    let item = match sold {
+        Ok(Some(item)) => item,
+        Ok(None) => {
+            // Someone else bought it first.
+            seller.spend_silver(price)?;
+            me.add_silver(price)?;
+            return tell(me, VendingRejection::SoldOut).await;
+        },
+        Err(e) => {
+            seller.spend_silver(price)?;
+            me.add_silver(price)?;
+            return Err(e.into());
+        },
+    };
+    seller.stall().unlist(item_id);
+    tracing::debug!(%item_id, %price, seller = seller.id(), "Bought");
+    me.save(state).await?;
+    seller.save(state).await?;
+
+    let msg =
+        MsgUserAttrib::single(me.id(), AttributeKind::Silver, me.silver());
+    me.owner().send(msg).await?;
+    me.owner().send(MsgItemInfo::add(me.id(), &item)).await?;
+    let msg = MsgUserAttrib::single(
+        seller.id(),
+        AttributeKind::Silver,
+        seller.silver(),
+    );
+    seller.owner().send(msg).await?;
+    seller
+        .owner()
+        .send(MsgItem::remove(seller.id(), item_id))
+        .await?;
+    Ok(())
+}
+
+/// The seller, if it is vending within sight of `me`.
+fn vending(
+    state: &State,
+    me: &Character,
+    seller_id: u32,
+) -> Option<std::sync::Arc<crate::entities::GameEntity>> {
+    let here = me.entity().location();
+    state.entity(seller_id).filter(|e| {
+        let there = e.basic().location();
+        e.basic().map_id() == me.entity().map_id()
+            && tq_math::in_screen((here.x, here.y), (there.x, there.y))
+            && e.as_character()
+                .is_some_and(|c| c.state() == CharacterState::Vending)
+    })
+}
+
+async fn refuse(
+    me: &Character,
+    rejection: VendingRejection,
+) -> Result<bool, Error> {
+    tell(me, rejection).await?;
+    Ok(false)
+}
+
+async fn tell(
+    me: &Character,
+    rejection: VendingRejection,
+) -> Result<(), Error> {
+    let msg = MsgTalk::from_system(
+        me.id(),
+        TalkChannel::TopLeft,
+        rejection.message(),
+    );
+    me.owner().send(msg).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use futures::FutureExt;
+    use primitives::Size;
+    use tokio::sync::mpsc::Receiver;
+    use tq_network::{Message, PacketID};
+
+    async fn give_item(state: &State, character_id: i32) -> Result<u32, Error> {
+        give(state, character_id, 1000000).await
+    }
+
+    async fn give(
+        state: &State,
+        character_id: i32,
+        item_type: u32,
+    ) -> Result<u32, Error> {
+        let (item_id,) = sqlx::query_as::<_, (i32,)>(
+            "INSERT INTO items (character_id, item_type) VALUES (?, ?) RETURNING item_id;",
+        )
+        .bind(character_id)
+        .bind(item_type)
+        .fetch_one(state.pool())
+        .await?;
+        Ok(item_id as u32)
+    }
+
+    async fn position_of(
+        state: &State,
+        item_id: u32,
+    ) -> Result<(i32, i16), Error> {
+        let row = sqlx::query_as::<_, (i32, i16)>(
+            "SELECT character_id, position FROM items WHERE item_id = ?;",
+        )
+        .bind(item_id as i32)
+        .fetch_one(state.pool())
+        .await?;
+        Ok(row)
+    }
+
+    #[tokio::test]
+    async fn opening_a_stall() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let item_id = give_item(&state, me.character_id()).await?;
+
+                // Nothing could be listed without a stall.
+                assert!(!list_item(&state, me, item_id, 100).await?);
+                let told = packets_of::<MsgTalk>(&mut a_rx);
+                assert_eq!(
+                    told[0].message,
+                    VendingRejection::NotVending.message()
+                );
+
+                open_stall(me).await?;
+                assert_eq!(me.state(), CharacterState::Vending);
+                assert!(!me.state().can_move());
+                assert!(!list_item(&state, me, item_id, 0).await?);
+                assert!(list_item(&state, me, item_id, 100).await?);
+                assert_eq!(me.stall().listings(), [(item_id, 100)]);
+                let (_, position) = position_of(&state, item_id).await?;
+                assert_eq!(position, Item::BOOTH);
+
+                assert!(unlist_item(&state, me, item_id).await?);
+                assert!(me.stall().listings().is_empty());
+                let (_, position) = position_of(&state, item_id).await?;
+                assert_eq!(position, Item::INVENTORY);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn buying_from_a_stall() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), (b, mut b_rx)] = actors;
+                let (a_entity, b_entity) = (a.entity(), b.entity());
+                let seller = a_entity.as_character().unwrap();
+                let buyer = b_entity.as_character().unwrap();
+                let item_id = give_item(&state, seller.character_id()).await?;
+                open_stall(seller).await?;
+                list_item(&state, seller, item_id, 300).await?;
+                let (seller_silver, buyer_silver) =
+                    (seller.silver(), buyer.silver());
+
+                browse(&state, buyer, seller.id()).await?;
+                let mut shown = 0;
+                while let Ok(msg) = b_rx.try_recv() {
+                    if let Message::Packet(MsgItemInfoEx::PACKET_ID, _) = msg {
+                        shown += 1;
+                    }
+                }
+                assert_eq!(shown, 1);
+
+                buy(&state, buyer, seller.id(), item_id).await?;
+                assert_eq!(seller.silver(), seller_silver + 300);
+                assert_eq!(buyer.silver(), buyer_silver - 300);
+                assert_eq!(
+                    position_of(&state, item_id).await?,
+                    (buyer.character_id(), Item::INVENTORY)
+                );
+                assert!(seller.stall().listings().is_empty());
+                let saved = tq_db::character::Character::by_id(
+                    state.pool(),
+                    seller.character_id(),
+                )
+                .await?;
+                assert_eq!(saved.silver as u64, seller.silver());
+
+                // That was the last one.
+                buy(&state, buyer, seller.id(), item_id).await?;
+                let told = packets_of::<MsgTalk>(&mut b_rx);
+                assert_eq!(
+                    told.last().unwrap().message,
+                    VendingRejection::SoldOut.message()
+                );
+                assert_eq!(buyer.silver(), buyer_silver - 300);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn nobody_pays_for_an_item_that_was_not_sold() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), (b, mut b_rx)] = actors;
+                let (a_entity, b_entity) = (a.entity(), b.entity());
+                let seller = a_entity.as_character().unwrap();
+                let buyer = b_entity.as_character().unwrap();
+                let item_id = give_item(&state, seller.character_id()).await?;
+                open_stall(seller).await?;
+                list_item(&state, seller, item_id, 300).await?;
+                let (seller_silver, buyer_silver) =
+                    (seller.silver(), buyer.silver());
+
+                // Taken off the stall behind its back, it is still listed
+                // but could not be sold.
+                sqlx::query("UPDATE items SET position = ? WHERE item_id = ?;")
+                    .bind(Item::INVENTORY)
+                    .bind(item_id as i32)
+                    .execute(state.pool())
+                    .await?;
+                buy(&state, buyer, seller.id(), item_id).await?;
+                let told = packets_of::<MsgTalk>(&mut b_rx);
+                assert_eq!(
+                    told.last().unwrap().message,
+                    VendingRejection::SoldOut.message()
+                );
+                assert_eq!(seller.silver(), seller_silver);
+                assert_eq!(buyer.silver(), buyer_silver);
+
+                // A seller that could not hold the silver keeps its item.
+                sqlx::query("UPDATE items SET position = ? WHERE item_id = ?;")
+                    .bind(Item::BOOTH)
+                    .bind(item_id as i32)
+                    .execute(state.pool())
+                    .await?;
+                let room = constants::MAX_SILVER - seller_silver;
+                seller.add_silver(room - 299)?;
+                buy(&state, buyer, seller.id(), item_id).await?;
+                let told = packets_of::<MsgTalk>(&mut b_rx);
+                assert_eq!(
+                    told.last().unwrap().message,
+                    VendingRejection::SellerFull.message()
+                );
+                assert_eq!(buyer.silver(), buyer_silver);
+                assert_eq!(
+                    position_of(&state, item_id).await?,
+                    (seller.character_id(), Item::BOOTH)
+                );
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn buyers_take_turns() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), (b, b_rx)] = actors;
+                let mut buyers = vec![(b, b_rx)];
+                for i in 0..7 {
+                    buyers.push(
+                        make_extra_actor(&state, &format!("buyer{i}")).await?,
+                    );
+                }
+                let seller_entity = a.entity();
+                let seller = seller_entity.as_character().unwrap();
+                let entities: Vec<_> =
+                    buyers.iter().map(|(actor, _)| actor.entity()).collect();
+                open_stall(seller).await?;
+                let mut listed = Vec::new();
+                for entity in &entities {
+                    entity.as_character().unwrap().add_silver(1_000)?;
+                    let item_id =
+                        give_item(&state, seller.character_id()).await?;
+                    list_item(&state, seller, item_id, 300).await?;
+                    listed.push(item_id);
+                }
+                // Room for only one of them.
+                let room = constants::MAX_SILVER - seller.silver();
+                seller.add_silver(room - 300)?;
+                let total = |seller: &Character| {
+                    seller.silver()
+                        + entities
+                            .iter()
+                            .map(|e| e.as_character().unwrap().silver())
+                            .sum::<u64>()
+                };
+                let silver = total(seller);
+
+                let purchases = entities.iter().zip(&listed).map(|(e, id)| {
+                    buy(&state, e.as_character().unwrap(), seller.id(), *id)
+                });
+                for res in futures::future::join_all(purchases).await {
+                    res?;
+                }
+                assert_eq!(seller.silver(), constants::MAX_SILVER);
+                // No silver got lost on the way.
+                assert_eq!(total(seller), silver);
+                let refused = buyers
+                    .iter_mut()
+                    .flat_map(|(_, rx)| packets_of::<MsgTalk>(rx))
+                    .filter(|m| {
+                        m.message == VendingRejection::SellerFull.message()
+                    })
+                    .count();
+                assert_eq!(refused, listed.len() - 1);
+                let mut sold = 0;
+                for item_id in listed {
+                    if position_of(&state, item_id).await?.0
+                        != seller.character_id()
+                    {
+                        sold += 1;
+                    }
+                }
+                assert_eq!(sold, 1);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn buying_is_limited_by_weight() -> Result<(), Error> {
+        use crate::packets::MsgAllot;
+        use crate::systems::{max_weight, weight_of};
+        use tq_network::PacketProcess;
+
+        const GLAIVE: u32 = 510005;
+        const ARMOR: u32 = 130005;
+        const BLADE: u32 = 410005;
+        const POTION: u32 = 1000000;
+
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), (b, mut b_rx)] = actors;
+                let (a_entity, b_entity) = (a.entity(), b.entity());
+                let seller = a_entity.as_character().unwrap();
+                let buyer = b_entity.as_character().unwrap();
+                buyer.add_silver(1_000)?;
+                // Room for 5 more, less than an armor weighs.
+                let room = max_weight(buyer.strength()) - 5;
+                for _ in 0..room / weight_of(GLAIVE) {
+                    give(&state, buyer.character_id(), GLAIVE).await?;
+                }
+                for _ in 0..room % weight_of(GLAIVE) {
+                    give(&state, buyer.character_id(), POTION).await?;
+                }
+                open_stall(seller).await?;
+                let mut listed = Vec::new();
+                for item_type in [ARMOR, BLADE, BLADE] {
+                    let item_id =
+                        give(&state, seller.character_id(), item_type).await?;
+                    list_item(&state, seller, item_id, 10).await?;
+                    listed.push(item_id);
+                }
+                let [armor, blade, spare] = listed[..] else {
+                    unreachable!()
+                };
+                let last_told = |rx: &mut Receiver<Message>| {
+                    packets_of::<MsgTalk>(rx).pop().map(|m| m.message)
+                };
+
+                buy(&state, buyer, seller.id(), armor).await?;
+                assert_eq!(
+                    last_told(&mut b_rx).as_deref(),
+                    Some(VendingRejection::TooHeavy.message())
+                );
+                assert_eq!(
+                    position_of(&state, armor).await?.0,
+                    seller.character_id()
+                );
+                // A blade weighs just what is left.
+                buy(&state, buyer, seller.id(), blade).await?;
+                assert_eq!(
+                    position_of(&state, blade).await?.0,
+                    buyer.character_id()
+                );
+                buy(&state, buyer, seller.id(), spare).await?;
+                assert_eq!(
+                    last_told(&mut b_rx).as_deref(),
+                    Some(VendingRejection::TooHeavy.message())
+                );
+
+                // A point of strength makes room for it.
+                buyer
+                    .gain_experience(
+                        constants::level_up_experience(buyer.entity().level())
+                            .unwrap(),
+                        std::time::Instant::now(),
+                    )
+                    .await?;
+                let allot = MsgAllot {
+                    character_id: buyer.id(),
+                    strength: 1,
+                    agility: 0,
+                    vitality: 0,
+                    spirit: 0,
+                };
+                allot.process(&state, &b).await?;
+                buy(&state, buyer, seller.id(), spare).await?;
+                assert_eq!(
+                    position_of(&state, spare).await?.0,
+                    buyer.character_id()
+                );
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn stall_closes_on_disconnect() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let map = state.try_map(me.entity().map_id())?;
+                map.load_blank(Size::new(200, 200)).await?;
+                map.insert_entity(a.entity()).await?;
+                let item_id = give_item(&state, me.character_id()).await?;
+                open_stall(me).await?;
+                list_item(&state, me, item_id, 100).await?;
+
+                me.leave_world(&state).await?;
+                assert_eq!(me.state(), CharacterState::Alive);
+                assert!(me.stall().listings().is_empty());
+                assert_eq!(
+                    position_of(&state, item_id).await?,
+                    (me.character_id(), Item::INVENTORY)
+                );
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
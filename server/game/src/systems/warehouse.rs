@@ -0,0 +1,448 @@
+//! The warehouse password, a second password that keeps the warehouse and
+//! the character deletion shut until it is given.
+//!
+//! The password belongs to the account, like the warehouse it keeps shut.
+//! It is set at a warehouseman, and given once per session to unlock the
+//! warehouse. Too many wrong passwords in a row lock the account out of it
+//! for a while, whichever of its characters gave them.
+
+use crate::entities::Character;
+use crate::packets::{MsgTalk, TalkChannel};
+use crate::{ActorState, Error, State};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tq_network::Actor;
+
+/// How many wrong passwords in a row lock the account out.
+pub const WAREHOUSE_PASSWORD_ATTEMPTS: u32 = 3;
+
+/// How long an account stays locked out after too many wrong passwords.
+pub const WAREHOUSE_LOCKOUT: Duration = Duration::from_secs(10 * 60);
+
+/// The bcrypt cost of the warehouse passwords, the tests keep it low so they
+/// do not spend their time hashing.
+const WAREHOUSE_PASSWORD_COST: u32 = if cfg!(test) { 4 } else { 12 };
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Failures {
+    count: u32,
+    locked_until: Option<Instant>,
+}
+
+/// The wrong warehouse passwords given lately, by account.
+///
+/// They are kept apart from the sessions, so logging in again does not get
+/// around the lockout.
+#[derive(Debug, Default)]
+pub struct WarehouseLocks {
+    failures: Mutex<HashMap<u32, Failures>>,
+}
+
+impl WarehouseLocks {
+    /// When the account could try again, `None` if it is not locked out.
+    pub fn locked_until(
+        &self,
+        account_id: u32,
+        now: Instant,
+    ) -> Option<Instant> {
+        let failures = self.failures.lock();
+        failures
+            .get(&account_id)
+            .and_then(|f| f.locked_until)
+            .filter(|until| *until > now)
+    }
+
+    /// Counts a wrong password, and returns how many attempts are left
+    /// before the account gets locked out.
+    fn fail(&self, account_id: u32, now: Instant) -> u32 {
+        let mut failures = self.failures.lock();
+        let f = failures.entry(account_id).or_default();
+        if f.locked_until.is_some_and(|until| until <= now) {
+            *f = Failures::default();
+        }
+        f.count += 1;
+        if f.count >= WAREHOUSE_PASSWORD_ATTEMPTS {
+            f.locked_until = Some(now + WAREHOUSE_LOCKOUT);
+        }
+        WAREHOUSE_PASSWORD_ATTEMPTS.saturating_sub(f.count)
+    }
+
+    fn clear(&self, account_id: u32) {
+        self.failures.lock().remove(&account_id);
+    }
+}
+
+/// How giving or setting the warehouse password went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WarehouseAnswer {
+    /// The warehouse is open for the rest of the session.
+    Unlocked,
+    /// The password is set, and the warehouse is open for the rest of the
+    /// session.
+    Set,
+    Wrong {
+        attempts_left: u32,
+    },
+    LockedOut {
+        remaining: Duration,
+    },
+    /// The password could only be set at a warehouseman.
+    NotAtWarehouse,
+    /// Zero is not a password, the client sends it for none.
+    Invalid,
+}
+
+impl WarehouseAnswer {
+    pub fn message(&self) -> String {
+        match self {
+            Self::Unlocked => "Your warehouse is unlocked.".into(),
+            Self::Set => "Your warehouse password is set.".into(),
+            Self::Wrong { attempts_left: 0 } => Self::LockedOut {
+                remaining: WAREHOUSE_LOCKOUT,
+            }
+            .message(),
+            Self::Wrong { attempts_left } => format!(
+                "Wrong warehouse password, {attempts_left} attempts left."
+            ),
+            Self::LockedOut { remaining } => format!(
+                "Too many wrong warehouse passwords, try again in {} minutes.",
+                remaining.as_secs().div_ceil(60)
+            ),
+            Self::NotAtWarehouse => {
+                "Talk to a warehouseman to set your warehouse password.".into()
+            },
+            Self::Invalid => "That is not a valid password.".into(),
+        }
+    }
+}
+
+/// Whether the warehouse of the account of the character is open, that is
+/// it has no password or the password was given this session.
+pub async fn warehouse_unlocked(
+    state: &State,
+    actor: &Actor<ActorState>,
+    me: &Character,
+) -> Result<bool, Error> {
+    if actor.warehouse_unlocked() {
+        return Ok(true);
+    }
+    let has_password = tq_db::account::Account::has_warehouse_password(
+        state.pool(),
+        me.account_id() as i32,
+    )
+    .await?;
+    Ok(!has_password)
+}
+
+/// Tells the character its warehouse is locked, when it is, and returns
+/// whether it is open.
+pub async fn ensure_warehouse_unlocked(
+    state: &State,
+    actor: &Actor<ActorState>,
+    me: &Character,
+) -> Result<bool, Error> {
+    if warehouse_unlocked(state, actor, me).await? {
+        return Ok(true);
+    }
+    let msg = MsgTalk::from_system(
+        me.id(),
+        TalkChannel::TopLeft,
+        "Your warehouse is locked, enter its password first.",
+    );
+    actor.send(msg).await?;
+    Ok(false)
+}
+
+/// Checks the password against the one of the account, counting it
+/// against the lockout when it is wrong. The warehouse is open for the rest
+/// of the session once it is right, or if there is no password.
+#[tracing::instrument(skip(state, actor, me, password), fields(me = me.id()))]
+pub async fn verify_warehouse_password(
+    state: &State,
+    actor: &Actor<ActorState>,
+    me: &Character,
+    password: u32,
+    now: Instant,
+) -> Result<WarehouseAnswer, Error> {
+    let locks = state.warehouse_locks();
+    let account_id = me.account_id();
+    if let Some(until) = locks.locked_until(account_id, now) {
+        return Ok(WarehouseAnswer::LockedOut {
+            remaining: until - now,
+        });
+    }
+    let matched = tq_db::account::Account::verify_warehouse_password(
+        state.pool(),
+        account_id as i32,
+        &password.to_string(),
+    )
+    .await?;
+    if matched == Some(false) {
+        let attempts_left = locks.fail(account_id, now);
+        tracing::debug!(attempts_left, "Wrong warehouse password");
+        return Ok(WarehouseAnswer::Wrong { attempts_left });
+    }
+    locks.clear(account_id);
+    actor.set_warehouse_unlocked(true);
+    Ok(WarehouseAnswer::Unlocked)
+}
+
+/// Sets the warehouse password of the account, the character has to be
+/// talking to a warehouseman. Changing it takes the `old` one, given the
+/// same way as to [`verify_warehouse_password`], it is ignored if there is
+/// none yet.
+#[tracing::instrument(skip(state, actor, me, old, new), fields(me = me.id()))]
+pub async fn set_warehouse_password(
+    state: &State,
+    actor: &Actor<ActorState>,
+    me: &Character,
+    old: u32,
+    new: u32,
+    now: Instant,
+) -> Result<WarehouseAnswer, Error> {
+    if !at_warehouse(state, me)? {
+        return Ok(WarehouseAnswer::NotAtWarehouse);
+    }
+    if new == 0 {
+        return Ok(WarehouseAnswer::Invalid);
+    }
+    let answer = verify_warehouse_password(state, actor, me, old, now).await?;
+    if answer != WarehouseAnswer::Unlocked {
+        return Ok(answer);
+    }
+    tq_db::account::Account::set_warehouse_password(
+        state.pool(),
+        me.account_id() as i32,
+        &new.to_string(),
+        WAREHOUSE_PASSWORD_COST,
+    )
+    .await?;
+    tracing::info!("Warehouse password set");
+    Ok(WarehouseAnswer::Set)
+}
+
+/// Whether the character is talking to a warehouseman it could see.
+fn at_warehouse(state: &State, me: &Character) -> Result<bool, Error> {
+    match me.dialog_npc() {
+        Some(npc_id) => near_warehouse(state, me, npc_id),
+        None => Ok(false),
+    }
+}
+
+/// Whether the npc with that id is a warehouseman the character could see.
+pub(super) fn near_warehouse(
+    state: &State,
+    me: &Character,
+    npc_id: u32,
+) -> Result<bool, Error> {
+    let map = state.try_map(me.entity().map_id())?;
+    let Some(npc) = map.npc(npc_id) else {
+        return Ok(false);
+    };
+    let (my_loc, npc_loc) = (me.entity().location(), npc.entity().location());
+    Ok(npc.is_storage() && tq_math::in_screen(my_loc.into(), npc_loc.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::{ActionType, MsgAction, MsgItem};
+    use crate::test_utils::*;
+    use crate::world::Maps;
+    use futures::FutureExt;
+    use primitives::Location;
+    use tq_network::PacketProcess;
+
+    async fn set_password(state: &State, me: &Character, password: &str) {
+        tq_db::account::Account::set_warehouse_password(
+            state.pool(),
+            me.account_id() as i32,
+            password,
+            WAREHOUSE_PASSWORD_COST,
+        )
+        .await
+        .unwrap();
+    }
+
+    const LOCKED: &str = "Your warehouse is locked, enter its password first.";
+
+    #[tokio::test]
+    async fn locked_until_the_password_is_given() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                // Nothing to give without a password.
+                assert!(warehouse_unlocked(&state, &a, me).await?);
+                set_password(&state, me, "1234").await;
+                assert!(!warehouse_unlocked(&state, &a, me).await?);
+
+                MsgItem::save_money(me.id(), 100)
+                    .process(&state, &a)
+                    .await?;
+                assert_eq!(told(&mut a_rx), [LOCKED]);
+                MsgAction::new(me.id(), 0, 0, 0, ActionType::DelRole)
+                    .process(&state, &a)
+                    .await?;
+                assert_eq!(told(&mut a_rx), [LOCKED]);
+                let id = me.character_id();
+                assert!(tq_db::character::Character::by_id(state.pool(), id)
+                    .await
+                    .is_ok());
+
+                MsgItem::verify_warehouse_password(me.id(), 1234)
+                    .process(&state, &a)
+                    .await?;
+                assert_eq!(told(&mut a_rx), ["Your warehouse is unlocked."]);
+                MsgItem::save_money(me.id(), 100)
+                    .process(&state, &a)
+                    .await?;
+                assert!(!told(&mut a_rx).contains(&LOCKED.to_owned()));
+                MsgAction::new(me.id(), 0, 0, 0, ActionType::DelRole)
+                    .process(&state, &a)
+                    .await?;
+                assert!(tq_db::character::Character::by_id(state.pool(), id)
+                    .await
+                    .is_err());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn wrong_passwords_lock_out() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                set_password(&state, me, "1234").await;
+                let now = Instant::now();
+                let verify = |password, now| {
+                    verify_warehouse_password(&state, &a, me, password, now)
+                };
+                assert_eq!(
+                    verify(1, now).await?,
+                    WarehouseAnswer::Wrong { attempts_left: 2 }
+                );
+                assert_eq!(
+                    verify(2, now).await?,
+                    WarehouseAnswer::Wrong { attempts_left: 1 }
+                );
+                assert_eq!(
+                    verify(3, now).await?,
+                    WarehouseAnswer::Wrong { attempts_left: 0 }
+                );
+                // Even the right one is turned away now.
+                let later = now + Duration::from_secs(60);
+                assert_eq!(
+                    verify(1234, later).await?,
+                    WarehouseAnswer::LockedOut {
+                        remaining: WAREHOUSE_LOCKOUT - Duration::from_secs(60),
+                    }
+                );
+                assert!(!a.warehouse_unlocked());
+
+                let after = now + WAREHOUSE_LOCKOUT;
+                assert_eq!(
+                    verify(1, after).await?,
+                    WarehouseAnswer::Wrong { attempts_left: 2 }
+                );
+                assert_eq!(
+                    verify(1234, after).await?,
+                    WarehouseAnswer::Unlocked
+                );
+                assert!(a.warehouse_unlocked());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn changing_takes_the_old_password() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let now = Instant::now();
+                let set = |old, new| {
+                    set_warehouse_password(&state, &a, me, old, new, now)
+                };
+                assert_eq!(
+                    set(0, 1234).await?,
+                    WarehouseAnswer::NotAtWarehouse
+                );
+
+                // The Twin City warehouseman stands at (409, 351).
+                entity.basic().set_map_id(u32::from(Maps::Newplain));
+                entity.basic().set_location(Location::new(405, 355, 0));
+                me.set_dialog_npc(Some(8));
+                assert_eq!(set(0, 0).await?, WarehouseAnswer::Invalid);
+                assert_eq!(set(0, 1234).await?, WarehouseAnswer::Set);
+
+                a.set_warehouse_unlocked(false);
+                assert_eq!(
+                    set(4321, 5678).await?,
+                    WarehouseAnswer::Wrong { attempts_left: 2 }
+                );
+                let verify = |password| {
+                    verify_warehouse_password(&state, &a, me, password, now)
+                };
+                assert_eq!(verify(1234).await?, WarehouseAnswer::Unlocked);
+                assert_eq!(set(1234, 5678).await?, WarehouseAnswer::Set);
+                a.set_warehouse_unlocked(false);
+                assert_eq!(
+                    verify(1234).await?,
+                    WarehouseAnswer::Wrong { attempts_left: 2 }
+                );
+                assert_eq!(verify(5678).await?, WarehouseAnswer::Unlocked);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn the_password_covers_the_whole_account() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let (alt, _) = make_alt_actor(&state, me).await?;
+                let alt_entity = alt.entity();
+                let alt_me = alt_entity.as_character().unwrap();
+                set_password(&state, me, "1234").await;
+                assert!(!warehouse_unlocked(&state, &alt, alt_me).await?);
+
+                // The wrong passwords of both characters add up.
+                let now = Instant::now();
+                for password in [1, 2] {
+                    verify_warehouse_password(&state, &a, me, password, now)
+                        .await?;
+                }
+                assert_eq!(
+                    verify_warehouse_password(&state, &alt, alt_me, 3, now)
+                        .await?,
+                    WarehouseAnswer::Wrong { attempts_left: 0 }
+                );
+                assert!(matches!(
+                    verify_warehouse_password(&state, &a, me, 1234, now)
+                        .await?,
+                    WarehouseAnswer::LockedOut { .. }
+                ));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
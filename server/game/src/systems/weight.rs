@@ -0,0 +1,65 @@
+//! How much a character could carry in its inventory, on top of the number
+//! of slots it has.
+//!
+//! The weights go by the kind of item, until the item stats get loaded from
+//! the client data.
+
+use crate::entities::Character;
+use tq_db::item::Item;
+
+/// How much anyone could carry, whatever their strength.
+pub const BASE_CARRY_WEIGHT: u32 = 100;
+
+/// How much more a character could carry for every point of strength.
+pub const CARRY_WEIGHT_PER_STRENGTH: u32 = 10;
+
+/// How heavy an item of that type is.
+pub fn weight_of(item_type: u32) -> u32 {
+    match item_type / 10_000 {
+        // Armors and shields.
+        13 | 90 => 8,
+        // Bows are lighter than the other two handed weapons.
+        50 => 4,
+        51..=59 => 10,
+        40..=49 => 5,
+        // Helmets and boots.
+        11 | 16 => 2,
+        _ => 1,
+    }
+}
+
+/// How much a character with that strength could carry.
+pub fn max_weight(strength: u16) -> u32 {
+    BASE_CARRY_WEIGHT + strength as u32 * CARRY_WEIGHT_PER_STRENGTH
+}
+
+/// How much the items weigh altogether.
+pub fn carried_weight(items: &[Item]) -> u32 {
+    items
+        .iter()
+        .map(|item| weight_of(item.item_type as u32))
+        .sum()
+}
+
+/// Whether the character could take an item of that type on top of its
+/// `inventory`, the limit follows its strength as it changes.
+pub fn could_carry(me: &Character, inventory: &[Item], item_type: u32) -> bool {
+    carried_weight(inventory) + weight_of(item_type)
+        <= max_weight(me.strength())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heavier_items_weigh_more() {
+        let blade = weight_of(410_005);
+        let glaive = weight_of(510_005);
+        let armor = weight_of(130_005);
+        let potion = weight_of(1_000_000);
+        assert!(potion < blade && blade < armor && armor < glaive);
+        assert_eq!(max_weight(0), BASE_CARRY_WEIGHT);
+        assert_eq!(max_weight(5) - max_weight(4), CARRY_WEIGHT_PER_STRENGTH);
+    }
+}
@@ -0,0 +1,238 @@
+use crate::systems::EffectKind;
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
+
+/// The XP points it takes to fill the XP circle.
+pub const XP_FULL: u8 = 100;
+
+/// The XP points gained for every attack.
+pub const XP_PER_ATTACK: u8 = 1;
+
+/// The XP points gained on every world tick while in battle stance.
+pub const XP_PER_TICK: u8 = 1;
+
+/// How long a character stays in battle stance after its last fight.
+pub const BATTLE_STANCE: Duration = Duration::from_secs(10);
+
+/// How long a character could stay out of combat before its XP circle
+/// empties, even if it was full.
+pub const XP_DECAY_AFTER: Duration = Duration::from_secs(60);
+
+/// How long an XP skill lasts once triggered.
+pub const XP_SKILL_DURATION: Duration = Duration::from_secs(20);
+
+/// The skills unlocked by a full XP circle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XpSkill {
+    /// Makes the character move a lot faster.
+    Cyclone,
+    /// Makes the character hit a lot harder.
+    Superman,
+}
+
+impl XpSkill {
+    /// The skill with that magic type, as sent by the client.
+    pub fn from_magic_type(magic_type: u32) -> Option<Self> {
+        match magic_type {
+            1110 => Some(Self::Cyclone),
+            1025 => Some(Self::Superman),
+            _ => None,
+        }
+    }
+
+    /// The status effect the skill puts the character under, and its power.
+    pub const fn effect(self) -> (EffectKind, u16) {
+        match self {
+            Self::Cyclone => (EffectKind::Speed, 100),
+            Self::Superman => (EffectKind::Superman, 100),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct XpState {
+    points: u8,
+    /// When the character last fought, `None` if it never did.
+    last_combat: Option<Instant>,
+}
+
+/// The XP circle of a character, it fills while fighting and unlocks an
+/// [`XpSkill`] once full.
+#[derive(Debug, Default)]
+pub struct XpBar {
+    state: Mutex<XpState>,
+}
+
+impl XpBar {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn points(&self) -> u8 { self.state.lock().points }
+
+    pub fn is_full(&self) -> bool { self.points() >= XP_FULL }
+
+    /// Records that the character got into a fight at `now` without
+    /// attacking, like when it gets attacked.
+    pub fn enter_combat(&self, now: Instant) {
+        self.state.lock().last_combat = Some(now);
+    }
+
+    /// Records an attack made at `now`, returns the new points if they
+    /// changed.
+    pub fn on_attack(&self, now: Instant) -> Option<u8> {
+        let mut state = self.state.lock();
+        state.last_combat = Some(now);
+        Self::add(&mut state, XP_PER_ATTACK)
+    }
+
+    /// Called on every world tick, fills the circle while in battle stance
+    /// and empties it once out of combat for too long.
+    ///
+    /// Returns the new points if they changed.
+    pub fn tick(&self, now: Instant) -> Option<u8> {
+        let mut state = self.state.lock();
+        let idle = state
+            .last_combat
+            .map_or(Duration::MAX, |t| now.saturating_duration_since(t));
+        if idle < BATTLE_STANCE {
+            Self::add(&mut state, XP_PER_TICK)
+        } else if idle >= XP_DECAY_AFTER && state.points > 0 {
+            state.points = 0;
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    /// Empties a full circle, returns `false` if it was not full.
+    pub fn consume(&self) -> bool {
+        let mut state = self.state.lock();
+        if state.points < XP_FULL {
+            return false;
+        }
+        state.points = 0;
+        true
+    }
+
+    fn add(state: &mut XpState, points: u8) -> Option<u8> {
+        let new = state.points.saturating_add(points).min(XP_FULL);
+        (new != state.points).then(|| {
+            state.points = new;
+            new
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::Flags;
+    use crate::packets::{AttributeKind, MsgUserAttrib};
+    use crate::systems::Stat;
+    use crate::test_utils::*;
+    use crate::Error;
+    use futures::FutureExt;
+    use tokio::sync::mpsc::Receiver;
+    use tq_network::Message;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn bar_fills_while_fighting() {
+        let bar = XpBar::new();
+        let start = Instant::now();
+        // Nothing happens before the first fight.
+        assert_eq!(bar.tick(start), None);
+        assert_eq!(bar.on_attack(start), Some(XP_PER_ATTACK));
+        for i in 1..10 {
+            bar.tick(start + i * SECOND);
+        }
+        assert_eq!(bar.points(), XP_PER_ATTACK + 9 * XP_PER_TICK);
+        // Out of battle stance, the bar holds.
+        assert_eq!(bar.tick(start + BATTLE_STANCE), None);
+        assert_eq!(bar.points(), XP_PER_ATTACK + 9 * XP_PER_TICK);
+
+        let mut now = start + BATTLE_STANCE;
+        while !bar.is_full() {
+            bar.on_attack(now);
+            now += SECOND;
+        }
+        assert_eq!(bar.points(), XP_FULL);
+        assert_eq!(bar.on_attack(now), None);
+        assert_eq!(bar.tick(now), None);
+    }
+
+    #[test]
+    fn bar_decays_out_of_combat() {
+        let bar = XpBar::new();
+        let start = Instant::now();
+        bar.on_attack(start);
+        assert_eq!(bar.tick(start + XP_DECAY_AFTER - SECOND), None);
+        assert_eq!(bar.points(), XP_PER_ATTACK);
+        assert_eq!(bar.tick(start + XP_DECAY_AFTER), Some(0));
+        assert_eq!(bar.tick(start + XP_DECAY_AFTER + SECOND), None);
+    }
+
+    #[test]
+    fn triggering_consumes_the_bar() {
+        let bar = XpBar::new();
+        assert!(!bar.consume());
+        let now = Instant::now();
+        for _ in 0..XP_FULL {
+            bar.on_attack(now);
+        }
+        assert!(bar.consume());
+        assert_eq!(bar.points(), 0);
+        assert!(!bar.consume());
+    }
+
+    /// Drains the actor's channel and returns the XP updates sent to it.
+    fn xp_updates(rx: &mut Receiver<Message>) -> Vec<u64> {
+        packets_of::<MsgUserAttrib>(rx)
+            .iter()
+            .flat_map(MsgUserAttrib::attributes)
+            .filter(|(kind, _)| *kind == AttributeKind::XpCircle)
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn xp_skill_lasts_its_duration() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |_state, actors| {
+            async move {
+                let [(a, mut a_rx), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let now = Instant::now();
+                assert!(!me.use_xp_skill(XpSkill::Superman, now).await?);
+
+                let mut updates = Vec::new();
+                for _ in 0..XP_FULL {
+                    me.on_attack(now).await?;
+                    updates.extend(xp_updates(&mut a_rx));
+                }
+                assert_eq!(updates.len(), XP_FULL as usize);
+                assert_eq!(updates.last(), Some(&(XP_FULL as u64)));
+                assert!(me.entity().flags().contains(Flags::XP_CIRCLE));
+
+                assert!(me.use_xp_skill(XpSkill::Superman, now).await?);
+                assert_eq!(me.xp_points(), 0);
+                assert_eq!(xp_updates(&mut a_rx), [0]);
+                let flags = me.entity().flags();
+                assert!(!flags.contains(Flags::XP_CIRCLE));
+                assert!(flags.contains(Flags::SUPERMAN));
+                let effects = me.status_effects();
+                assert_eq!(effects.fold(Stat::Attack, 100), 200);
+
+                let almost = now + XP_SKILL_DURATION - SECOND;
+                me.tick_status_effects(almost).await?;
+                assert!(effects.is_active(EffectKind::Superman));
+                me.tick_status_effects(now + XP_SKILL_DURATION).await?;
+                assert!(!effects.is_active(EffectKind::Superman));
+                assert!(!me.entity().flags().contains(Flags::SUPERMAN));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
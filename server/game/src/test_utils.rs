@@ -1,6 +1,7 @@
 use futures::future::BoxFuture;
 use sqlx::sqlite::SqlitePoolOptions;
-use tq_network::Actor;
+use tokio::sync::mpsc::Receiver;
+use tq_network::{Actor, Codec, Message, PacketDecode, PacketID};
 use tracing_subscriber::prelude::*;
 
 use crate::entities::Character;
@@ -15,7 +16,7 @@ pub async fn with_test_env<'a, F>(
 where
     F: FnOnce(
         crate::State,
-        [Actor<ActorState>; 2],
+        [TestActor; 2],
     ) -> BoxFuture<'a, Result<(), crate::Error>>,
 {
     let root_dir = std::process::Command::new("git")
@@ -40,10 +41,12 @@ where
         .pretty()
         .with_target(true)
         .with_test_writer();
-    tracing_subscriber::registry()
+    // Many tests share the same process, only the first one gets to set the
+    // global subscriber.
+    let _ = tracing_subscriber::registry()
         .with(env_filter)
         .with(logger)
-        .init();
+        .try_init();
 
     let pool = SqlitePoolOptions::new()
         .max_connections(42)
@@ -63,12 +66,66 @@ where
     f(state, actors).await
 }
 
+/// Drains the actor's channel and returns every packet in it, in the order
+/// they were sent.
+pub fn packets(rx: &mut Receiver<Message>) -> Vec<(u16, bytes::Bytes)> {
+    let mut packets = Vec::new();
+    while let Ok(msg) = rx.try_recv() {
+        if let Message::Packet(id, bytes) = msg {
+            packets.push((id, bytes));
+        }
+    }
+    packets
+}
+
+/// Like [`packets`], keeping only the ids.
+pub fn packet_ids(rx: &mut Receiver<Message>) -> Vec<u16> {
+    packets(rx).into_iter().map(|(id, _)| id).collect()
+}
+
+/// Drains the actor's channel and returns the packets with the given id.
+pub fn packets_of<P: PacketID + PacketDecode<Packet = P>>(
+    rx: &mut Receiver<Message>,
+) -> Vec<P> {
+    let mut packets = Vec::new();
+    while let Ok(msg) = rx.try_recv() {
+        match msg {
+            Message::Packet(id, bytes) if id == P::PACKET_ID => {
+                packets.push(P::decode(&bytes).unwrap());
+            },
+            _ => continue,
+        }
+    }
+    packets
+}
+
+/// Drains the actor's channel and returns the messages it was told.
+pub fn told(rx: &mut Receiver<Message>) -> Vec<String> {
+    packets_of::<crate::packets::MsgTalk>(rx)
+        .into_iter()
+        .map(|msg| msg.message)
+        .collect()
+}
+
+/// An actor made for tests, alongside the receiving end of its channel so the
+/// test can inspect the packets sent to that actor.
+pub type TestActor = (Actor<ActorState>, Receiver<Message>);
+
 pub async fn make_test_actor(
     state: &crate::State,
     id: usize,
-) -> Result<Actor<ActorState>, crate::Error> {
-    let (tx, _rx) = tokio::sync::mpsc::channel(50);
-    let actor = Actor::<ActorState>::new(tx);
+) -> Result<TestActor, crate::Error> {
+    make_test_actor_with(state, id, Codec::default()).await
+}
+
+/// Like [`make_test_actor`], for a client whose packets go through `codec`.
+pub async fn make_test_actor_with(
+    state: &crate::State,
+    id: usize,
+    codec: Codec,
+) -> Result<TestActor, crate::Error> {
+    let (tx, rx) = tokio::sync::mpsc::channel(50);
+    let actor = Actor::<ActorState>::new(tx).with_codec(codec);
     actor.set_id(id);
     let inner_character = MsgRegister::build_character_with(
         format!("test{id}"),
@@ -76,6 +133,7 @@ pub async fn make_test_actor(
         crate::packets::BaseClass::Trojan,
         id as _,
         1,
+        &mut state.rng().fork(),
     )?;
     inner_character.save(state.pool()).await?;
     let inner_character =
@@ -83,8 +141,53 @@ pub async fn make_test_actor(
             .await?
             .expect("Failed to load character");
     let character = Character::new(actor.handle(), inner_character);
+    // As if the client was done loading.
+    character.enter_world();
+    let screen = Screen::new(actor.handle());
+    actor.update(character, screen);
+    state.insert_entity(actor.entity());
+    Ok((actor, rx))
+}
+
+/// Makes an actor beyond the two the test starts with, under a new account
+/// named `name`.
+pub async fn make_extra_actor(
+    state: &crate::State,
+    name: &str,
+) -> Result<TestActor, crate::Error> {
+    let account_id = sqlx::query(
+        "INSERT INTO accounts (username, password) VALUES (?, '');",
+    )
+    .bind(name)
+    .execute(state.pool())
+    .await?
+    .last_insert_rowid();
+    make_test_actor(state, account_id as _).await
+}
+
+/// Another character on the same account as `me`, online at the same time.
+pub async fn make_alt_actor(
+    state: &crate::State,
+    me: &Character,
+) -> Result<TestActor, crate::Error> {
+    let (tx, rx) = tokio::sync::mpsc::channel(50);
+    let actor = Actor::<ActorState>::new(tx);
+    actor.set_id(100);
+    let inner_character = MsgRegister::build_character_with(
+        format!("{}alt", me.entity().name()),
+        crate::packets::BodyType::MuscularMale,
+        crate::packets::BaseClass::Trojan,
+        me.account_id(),
+        1,
+        &mut state.rng().fork(),
+    )?;
+    let character_id = inner_character.save(state.pool()).await?;
+    let inner_character =
+        tq_db::character::Character::by_id(state.pool(), character_id).await?;
+    let character = Character::new(actor.handle(), inner_character);
+    character.enter_world();
     let screen = Screen::new(actor.handle());
     actor.update(character, screen);
     state.insert_entity(actor.entity());
-    Ok(actor)
+    Ok((actor, rx))
 }
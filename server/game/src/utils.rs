@@ -1,3 +1,6 @@
+use crate::Error;
+use std::time::Duration;
+
 pub fn current_ts() -> u32 {
     let start = std::time::SystemTime::now();
     let since_the_epoch = start
@@ -6,6 +9,31 @@ pub fn current_ts() -> u32 {
     since_the_epoch.as_secs() as u32
 }
 
+/// Truncates the string to at most `max` bytes, without splitting a
+/// character in half.
+pub fn truncate_str(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Reads a duration in milliseconds from the environment variable `var`,
+/// falling back to `default` if it is not set.
+pub fn duration_ms_from_env(
+    var: &str,
+    default: Duration,
+) -> Result<Duration, Error> {
+    match dotenvy::var(var) {
+        Ok(ms) => Ok(Duration::from_millis(ms.trim().parse()?)),
+        Err(_) => Ok(default),
+    }
+}
+
 pub trait LoHi {
     type Output;
 
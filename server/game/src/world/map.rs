@@ -1,31 +1,52 @@
+use crate::sync::RwLock;
+use arc_swap::ArcSwap;
 use core::fmt;
-use futures::stream::FuturesUnordered;
-use futures::{StreamExt, TryFutureExt};
 use num_enum::{FromPrimitive, IntoPrimitive};
-use parking_lot::RwLock;
 use primitives::{Location, Point, Size};
+use rand::Rng;
 use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Weak};
+use std::time::Instant;
 use tq_math::SCREEN_DISTANCE;
-use tq_network::{PacketEncode, PacketID};
+use tq_network::{PacketEncode, PacketID, SendOutcome};
 
-use super::Portal;
-use crate::entities::{GameEntity, Npc};
-use crate::packets::{MapFlags, MsgWeather, WeatherKind};
-use crate::systems::{Floor, Tile};
+use super::{MapAttributes, Portal};
+use crate::entities::{
+    CharacterState, FloorItem, FloorItemKind, GameEntity, Locations, Monster,
+    MonsterType, Npc,
+};
+use crate::packets::{
+    ActionType, MapFlags, MsgAction, MsgMapItem, MsgWeather, WeatherKind,
+};
+use crate::state::{IdAllocator, IdKind};
+use crate::systems::{
+    fan_out, Drop, EntityKind, Floor, MovementBatch, SharedPacket, Tile,
+    TileAccess,
+};
 use crate::{constants, Error};
 
+/// How far from where they were asked to be put things could be moved to
+/// find a tile for them, see [`Map::find_available_tile`].
+pub const PLACEMENT_RADIUS: u16 = 5;
+/// How far from a killed monster its drops could scatter.
+const DROP_RADIUS: u16 = 1;
+/// How far around a castle gate the tiles are shut by it.
+pub const GATE_REACH: u16 = 1;
+
 type Entities = RwLock<HashMap<u32, Weak<GameEntity>>>;
 type Portals = HashSet<Portal>;
 type Npcs = HashMap<u32, Arc<GameEntity>>;
 type MapRegions = RwLock<Vec<MapRegion>>;
+type FloorItems = RwLock<HashMap<u32, Arc<GameEntity>>>;
+type Monsters = RwLock<HashMap<u32, Arc<GameEntity>>>;
 
 /// This struct encapsulates map information from a compressed map and the
 /// database. It includes the identification of the map, pools and methods for
 /// character tracking and screen updates, and other methods for processing map
 /// actions and events. It composes the floor struct which defines the map's
 /// coordinate tile grid.
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct Map {
     /// The Inner map loaded from the database
     inner: tq_db::map::Map,
@@ -39,6 +60,43 @@ pub struct Map {
     npcs: Npcs,
     /// Holds all MapRegions in that map.
     regions: MapRegions,
+    /// Holds all items lying on the floor of that map.
+    floor_items: FloorItems,
+    /// Holds all monsters spawned on that map.
+    monsters: Monsters,
+    /// Where the ids of the entities spawned at runtime come from.
+    ids: Arc<IdAllocator>,
+    /// The number of tiles in every region of that map.
+    region_size: Size<u32>,
+    /// The movements on that map not sent to their observers yet.
+    movements: MovementBatch,
+    /// The attributes overridden at runtime, over the ones from the database.
+    attributes: ArcSwap<MapAttributes>,
+    /// Held while the map gets loaded, so two loads never build it twice.
+    loading: tokio::sync::Mutex<()>,
+    /// Whether the map stays in memory even with nobody on it.
+    resident: AtomicBool,
+}
+
+impl Default for Map {
+    fn default() -> Self {
+        Self {
+            inner: Default::default(),
+            revive_point: Default::default(),
+            floor: Default::default(),
+            portals: Default::default(),
+            npcs: Default::default(),
+            regions: Default::default(),
+            floor_items: Default::default(),
+            monsters: Default::default(),
+            ids: Default::default(),
+            region_size: MapRegion::SIZE,
+            movements: Default::default(),
+            attributes: Default::default(),
+            loading: Default::default(),
+            resident: Default::default(),
+        }
+    }
 }
 
 impl Map {
@@ -46,12 +104,20 @@ impl Map {
         inner: tq_db::map::Map,
         portals: Vec<tq_db::portal::Portal>,
         npcs: Vec<tq_db::npc::Npc>,
+        ids: Arc<IdAllocator>,
     ) -> Self {
         let portals = portals.into_iter().map(Portal::new).collect();
         let npcs = npcs
             .into_iter()
-            .filter(|npc| !constants::is_terrain_npc(npc.id as _))
-            .map(|v| (v.id as u32, Arc::new(GameEntity::from(Npc::from(v)))))
+            .map(Npc::from)
+            // Of the terrain NPCs, only the pole and the gates of the guild
+            // war are kept, see `crate::events::guild_war`.
+            .filter(|npc| {
+                !constants::is_terrain_npc(npc.id())
+                    || npc.is_pole()
+                    || npc.is_gate()
+            })
+            .map(|npc| (npc.id(), Arc::new(GameEntity::from(npc))))
             .collect();
         Self {
             floor: Floor::new(inner.path.clone()),
@@ -60,40 +126,514 @@ impl Map {
                 inner.revive_point_y as u32,
             ),
             regions: RwLock::new(Vec::new()),
+            floor_items: Default::default(),
+            monsters: Default::default(),
+            ids,
             npcs,
             portals,
             inner,
+            region_size: MapRegion::SIZE,
+            movements: MovementBatch::new(),
+            attributes: Default::default(),
+            loading: Default::default(),
+            resident: Default::default(),
+        }
+    }
+
+    /// A copy of this map under another id, with its own regions, floor items
+    /// and characters, see [`crate::systems::create_instance`].
+    ///
+    /// The copy has the same portals and shares the NPCs of this map, it
+    /// loads its own floor from the same file once it is needed.
+    pub fn instantiate(&self, id: u32) -> Self {
+        let inner = tq_db::map::Map {
+            id: id as i32,
+            ..self.inner.clone()
+        };
+        Self {
+            floor: Floor::new(inner.path.clone()),
+            revive_point: self.revive_point,
+            regions: RwLock::new(Vec::new()),
+            floor_items: Default::default(),
+            monsters: Default::default(),
+            ids: self.ids.clone(),
+            npcs: self.npcs.clone(),
+            portals: self.portals.clone(),
+            inner,
+            region_size: self.region_size,
+            movements: MovementBatch::new(),
+            attributes: ArcSwap::from_pointee(self.attributes()),
+            loading: Default::default(),
+            resident: Default::default(),
         }
     }
 
+    /// Uses regions of the given size instead of [`MapRegion::SIZE`], small
+    /// maps like instances get better locality out of tighter regions.
+    ///
+    /// It has to be set before the map gets loaded.
+    pub fn with_region_size(mut self, size: Size<u32>) -> Result<Self, Error> {
+        if size.width == 0 || size.height == 0 {
+            return Err(Error::InvalidRegionSize(size));
+        }
+        self.region_size = size;
+        Ok(self)
+    }
+
+    /// The number of tiles in every region of that map.
+    pub fn region_size(&self) -> Size<u32> { self.region_size }
+
+    /// The number of regions along the width and the height of the map.
+    fn region_grid(&self) -> Size<u32> {
+        let map_size = self.floor.boundaries();
+        let region_size = self.region_size;
+        // ceil division to get the number of regions
+        Size::new(
+            (map_size.width as u32).div_ceil(region_size.width),
+            (map_size.height as u32).div_ceil(region_size.height),
+        )
+    }
+
     pub fn id(&self) -> u32 { self.inner.id as u32 }
 
     pub fn map_id(&self) -> u32 { self.inner.map_id as u32 }
 
     pub fn weather(&self) -> WeatherKind {
-        WeatherKind::from(self.inner.weather as u32)
+        self.attributes
+            .load()
+            .weather
+            .unwrap_or_else(|| WeatherKind::from(self.inner.weather as u32))
     }
 
     pub fn flags(&self) -> MapFlags {
-        MapFlags::from_bits(self.inner.flags as u32).unwrap_or_default()
+        let mut flags =
+            MapFlags::from_bits(self.inner.flags as u32).unwrap_or_default();
+        if let Some(pk_allowed) = self.attributes.load().pk_allowed {
+            flags.set(MapFlags::PK_DISABLED, !pk_allowed);
+        }
+        flags
+    }
+
+    /// Whether players could attack each other on that map.
+    pub fn pk_allowed(&self) -> bool {
+        !self.flags().contains(MapFlags::PK_DISABLED)
+    }
+
+    pub fn color(&self) -> u32 {
+        self.attributes
+            .load()
+            .argb
+            .unwrap_or(self.inner.color as u32)
+    }
+
+    /// How much more experience is gained on that map, in percent.
+    pub fn exp_bonus(&self) -> u32 {
+        self.attributes.load().exp_bonus.unwrap_or(0)
+    }
+
+    /// The attributes overridden at runtime, see [`MapAttributes`].
+    pub fn attributes(&self) -> MapAttributes { **self.attributes.load() }
+
+    /// Overrides the attributes of the map, telling everyone on it if the
+    /// weather changed.
+    pub async fn set_attributes(
+        &self,
+        attributes: MapAttributes,
+    ) -> Result<(), Error> {
+        let before = self.weather();
+        self.attributes.store(Arc::new(attributes));
+        let weather = self.weather();
+        if weather != before {
+            // There is no weather to go back to, it just clears up.
+            let weather = if weather.is_unknwon() {
+                WeatherKind::None
+            } else {
+                weather
+            };
+            self.broadcast(MsgWeather::new(weather)).await?;
+        }
+        Ok(())
     }
 
-    pub fn color(&self) -> u32 { self.inner.color as u32 }
+    /// Puts the attributes of the map back to the ones from the database.
+    pub async fn reset_attributes(&self) -> Result<(), Error> {
+        self.set_attributes(MapAttributes::default()).await
+    }
 
     pub fn revive_point(&self) -> Point<u32> { self.revive_point }
 
     pub fn is_static(&self) -> bool { self.inner.id == self.inner.map_id }
 
-    pub fn is_copy(&self) -> bool { self.inner.id == self.inner.map_id }
+    pub fn is_copy(&self) -> bool { self.inner.id != self.inner.map_id }
 
     pub fn portals(&self) -> &Portals { &self.portals }
 
+    /// Whether the map stays in memory even with nobody on it, see
+    /// [`crate::systems::MapLoader`].
+    pub fn resident(&self) -> bool { self.resident.load(Ordering::Relaxed) }
+
+    pub fn set_resident(&self, resident: bool) {
+        self.resident.store(resident, Ordering::Relaxed);
+    }
+
     pub fn tile(&self, x: u16, y: u16) -> Option<Tile> { self.floor.tile(x, y) }
 
+    /// Whether the point is inside the map, see [`Floor::boundaries`].
+    pub fn contains(&self, x: u16, y: u16) -> bool {
+        let boundaries = self.floor.boundaries();
+        (x as i32) < boundaries.width && (y as i32) < boundaries.height
+    }
+
+    /// See [`Floor::is_walkable`].
+    pub fn is_walkable(&self, x: u16, y: u16, by: EntityKind) -> bool {
+        self.floor.is_walkable(x, y, by)
+    }
+
+    /// The tile closest to `center` a player could be put on, no further
+    /// than `radius` tiles away on either axis: walkable and with no stall
+    /// open on it. `center` itself if it is fine.
+    pub fn find_available_tile(
+        &self,
+        center: (u16, u16),
+        radius: u16,
+    ) -> Option<(u16, u16)> {
+        let stalls: HashSet<(u16, u16)> = self
+            .players_in_range(center, radius)
+            .iter()
+            .filter_map(|e| e.as_character())
+            .filter(|c| c.state() == CharacterState::Vending)
+            .map(|c| c.entity().location())
+            .map(|loc| (loc.x, loc.y))
+            .collect();
+        self.floor.find_tile(center, radius, |xy, tile| {
+            tile.is_some_and(|t| t.access.walkable_by(EntityKind::Player))
+                && !stalls.contains(&xy)
+        })
+    }
+
+    /// Where the dead get revived on this map, moved to the closest
+    /// available tile if the revive point itself is not.
+    pub fn revive_tile(&self) -> (u16, u16) {
+        let point = (self.revive_point.x as u16, self.revive_point.y as u16);
+        self.find_available_tile(point, PLACEMENT_RADIUS)
+            .unwrap_or(point)
+    }
+
+    /// Whether nothing blocks the sight between the two points, like a wall
+    /// or the edge of the map.
+    pub fn in_sight(&self, from: (u16, u16), to: (u16, u16)) -> bool {
+        tq_math::tiles_between(from, to).all(|(x, y)| {
+            self.tile(x, y)
+                .is_some_and(|t| !matches!(t.access, TileAccess::Terrain))
+        })
+    }
+
     pub fn npc(&self, id: u32) -> Option<&Npc> {
         self.npcs.get(&id).and_then(|v| v.as_npc())
     }
 
+    pub fn npcs(&self) -> impl Iterator<Item = &Npc> {
+        self.npcs.values().filter_map(|v| v.as_npc())
+    }
+
+    /// Whether a castle gate stands on the tile or right next to it, see
+    /// [`crate::systems::can_step`].
+    pub fn is_gate(&self, x: u16, y: u16) -> bool {
+        self.npcs().filter(|npc| npc.is_gate()).any(|npc| {
+            let loc = npc.entity().location();
+            tq_math::in_range((loc.x, loc.y), (x, y), GATE_REACH)
+        })
+    }
+
+    /// The movements on this map waiting to be sent, see
+    /// [`State::movement_window`](crate::State::movement_window).
+    pub fn movements(&self) -> &MovementBatch { &self.movements }
+
+    /// Every item lying on the floor of this map.
+    pub fn floor_items(&self) -> Vec<Arc<GameEntity>> {
+        self.floor_items.read().values().cloned().collect()
+    }
+
+    pub fn floor_item(&self, id: u32) -> Option<Arc<GameEntity>> {
+        self.floor_items.read().get(&id).cloned()
+    }
+
+    /// Drops an item on the floor of this map and shows it to every
+    /// character that can see it.
+    #[tracing::instrument(skip_all, fields(map_id = self.id(), item = item.id()))]
+    pub async fn insert_floor_item(
+        &self,
+        item: FloorItem,
+    ) -> Result<Arc<GameEntity>, Error> {
+        let loc = item.location();
+        let item = Arc::new(GameEntity::from(item));
+        self.floor_items.write().insert(item.id(), item.clone());
+        if let Some(region) = self.region(loc.x, loc.y) {
+            region.insert_entity(item.clone());
+        }
+        for observer in self.players_in_range((loc.x, loc.y), SCREEN_DISTANCE) {
+            let Some(c) = observer.as_character() else {
+                continue;
+            };
+            let screen = c.try_screen()?;
+            if screen.insert_entity(Arc::downgrade(&item))? {
+                item.send_spawn(&observer).await?;
+            }
+        }
+        Ok(item)
+    }
+
+    /// Removes an item from the floor of this map (picked up or expired),
+    /// and removes it from the screen of every character that sees it.
+    #[tracing::instrument(skip(self), fields(map_id = self.id()))]
+    pub async fn remove_floor_item(
+        &self,
+        id: u32,
+    ) -> Result<Option<Arc<GameEntity>>, Error> {
+        let Some(item) = self.floor_items.write().remove(&id) else {
+            return Ok(None);
+        };
+        self.ids.free(id);
+        let loc = item.basic().location();
+        if let Some(region) = self.region(loc.x, loc.y) {
+            region.remove_entity(id);
+        }
+        let Some(floor_item) = item.as_floor_item() else {
+            return Ok(Some(item));
+        };
+        for observer in self.characters_around(loc) {
+            let Some(c) = observer.as_character() else {
+                continue;
+            };
+            let screen = c.try_screen()?;
+            if screen.remove_entity(id)? {
+                // Someone who just left must not keep the others from
+                // seeing the item go away.
+                let msg = MsgMapItem::delete(floor_item);
+                if let SendOutcome::Failed(e) = c.owner().try_send(msg).await {
+                    return Err(e.into());
+                }
+            }
+        }
+        Ok(Some(item))
+    }
+
+    /// Takes back an item that could not be shown to everyone around once
+    /// dropped, nobody could pick it up so its id goes back. Nobody is told.
+    pub fn discard_floor_item(&self, id: u32) {
+        if let Some(item) = self.floor_items.write().remove(&id) {
+            let loc = item.basic().location();
+            if let Some(region) = self.region(loc.x, loc.y) {
+                region.remove_entity(id);
+            }
+        }
+        self.ids.free(id);
+    }
+
+    /// Removes the items that lay on the floor for too long, see
+    /// [`FLOOR_ITEM_EXPIRY`](crate::entities::FLOOR_ITEM_EXPIRY).
+    pub async fn expire_floor_items(&self, now: Instant) -> Result<(), Error> {
+        let expired: Vec<_> = self
+            .floor_items
+            .read()
+            .values()
+            .filter(|e| e.as_floor_item().is_some_and(|i| i.is_expired(now)))
+            .map(|e| e.id())
+            .collect();
+        for id in expired {
+            self.remove_floor_item(id).await?;
+        }
+        Ok(())
+    }
+
+    /// Every monster spawned on this map.
+    pub fn monsters(&self) -> Vec<Arc<GameEntity>> {
+        self.monsters.read().values().cloned().collect()
+    }
+
+    pub fn monster(&self, id: u32) -> Option<Arc<GameEntity>> {
+        self.monsters.read().get(&id).cloned()
+    }
+
+    /// Spawns a monster of the given type at `location`, or the closest tile
+    /// monsters could walk on, and shows it to every character that can see
+    /// it.
+    #[tracing::instrument(skip(self, kind), fields(map_id = self.id(), kind = kind.id()))]
+    pub async fn spawn_monster(
+        &self,
+        kind: Arc<MonsterType>,
+        location: Location,
+    ) -> Result<Arc<GameEntity>, Error> {
+        let center = (location.x, location.y);
+        let (x, y) = self
+            .floor
+            .find_tile(center, PLACEMENT_RADIUS, |_, tile| {
+                tile.is_some_and(|t| t.access.walkable_by(EntityKind::Monster))
+            })
+            .ok_or(Error::TileNotFound(location.x, location.y))?;
+        let location = Location::new(x, y, location.direction);
+        let id = self.ids.allocate(IdKind::Monster)?;
+        let monster = Monster::new(id, kind, self.id(), location);
+        let monster = Arc::new(GameEntity::from(monster));
+        self.monsters.write().insert(id, monster.clone());
+        if let Some(region) = self.region(location.x, location.y) {
+            region.insert_entity(monster.clone());
+        }
+        let (x, y) = (location.x, location.y);
+        for observer in self.players_in_range((x, y), SCREEN_DISTANCE) {
+            let Some(c) = observer.as_character() else {
+                continue;
+            };
+            let screen = c.try_screen()?;
+            if screen.insert_entity(Arc::downgrade(&monster))? {
+                monster.send_spawn(&observer).await?;
+            }
+        }
+        Ok(monster)
+    }
+
+    /// Removes a monster from this map, and from the screen of every
+    /// character that sees it.
+    #[tracing::instrument(skip(self), fields(map_id = self.id()))]
+    pub async fn remove_monster(
+        &self,
+        id: u32,
+    ) -> Result<Option<Arc<GameEntity>>, Error> {
+        let Some(monster) = self.monsters.write().remove(&id) else {
+            return Ok(None);
+        };
+        self.ids.free(id);
+        let loc = monster.basic().location();
+        if let Some(region) = self.region(loc.x, loc.y) {
+            region.remove_entity(id);
+        }
+        for observer in self.characters_around(loc) {
+            let Some(c) = observer.as_character() else {
+                continue;
+            };
+            let screen = c.try_screen()?;
+            if screen.remove_entity(id)? {
+                let msg = MsgAction::new(id, id, 0, 0, ActionType::LeaveMap);
+                if let SendOutcome::Failed(e) = c.owner().try_send(msg).await {
+                    return Err(e.into());
+                }
+            }
+        }
+        Ok(Some(monster))
+    }
+
+    /// Returns all the characters on this map as they are right now, so they
+    /// could be iterated over without holding any lock.
+    ///
+    /// Every region is locked at once while copying, an entity moving between
+    /// two regions is always in at least one of them, and shows up once.
+    pub fn characters_snapshot(&self) -> Vec<Arc<GameEntity>> {
+        let characters: HashMap<_, _> = self.with_regions(|regions| {
+            let guards: Vec<_> =
+                regions.iter().map(|r| r.entities.read()).collect();
+            guards
+                .iter()
+                .flat_map(|entities| entities.values())
+                .filter_map(|v| v.upgrade())
+                .filter(|e| e.is_character())
+                .map(|e| (e.id(), e))
+                .collect()
+        });
+        characters.into_values().collect()
+    }
+
+    /// Returns all the characters in the regions surrounding `location`.
+    fn characters_around(&self, location: Location) -> Vec<Arc<GameEntity>> {
+        self.surrunding_regions(location.x, location.y)
+            .iter()
+            .filter(|region| !region.is_empty())
+            .flat_map(MapRegion::characters)
+            .collect()
+    }
+
+    /// Returns the characters within `radius` tiles of `center`, only
+    /// looking at the regions that could have them instead of the whole map.
+    pub fn players_in_range(
+        &self,
+        center: (u16, u16),
+        radius: u16,
+    ) -> Vec<Arc<GameEntity>> {
+        self.regions_within(center.0, center.1, radius)
+            .iter()
+            .filter(|region| !region.is_empty())
+            .flat_map(MapRegion::characters)
+            .filter(|e| {
+                let loc = e.basic().location();
+                tq_math::in_range(center, (loc.x, loc.y), radius)
+            })
+            .collect()
+    }
+
+    /// Called when a monster of the given type dies at `location`, rolls its
+    /// drop table and spawns the drops as floor items around that location,
+    /// kept for the killer for a while if any.
+    #[tracing::instrument(skip(self, monster, rng), fields(map_id = self.id(), monster = monster.id()))]
+    pub async fn on_monster_killed<R: Rng + ?Sized>(
+        &self,
+        monster: &MonsterType,
+        location: Location,
+        killer: Option<u32>,
+        rng: &mut R,
+    ) -> Result<Vec<Arc<GameEntity>>, Error> {
+        let drops = monster.drops().roll(rng);
+        let mut spots = self.drop_spots(location, drops.len()).into_iter();
+        let mut spawned = Vec::with_capacity(drops.len());
+        for drop in drops {
+            let Some(spot) = spots.next() else {
+                tracing::debug!("No more room to drop items");
+                break;
+            };
+            let kind = match drop {
+                Drop::Item(item_type) => FloorItemKind::Item(item_type),
+                Drop::Silver(amount) => FloorItemKind::Silver(amount),
+            };
+            let id = self.ids.allocate(IdKind::FloorItem)?;
+            let mut item = FloorItem::new(id, kind, self.id(), spot);
+            if let Some(killer) = killer {
+                item = item.with_owner(killer);
+            }
+            match self.insert_floor_item(item).await {
+                Ok(item) => spawned.push(item),
+                Err(e) => {
+                    self.discard_floor_item(id);
+                    return Err(e);
+                },
+            }
+        }
+        Ok(spawned)
+    }
+
+    /// Returns the free spots around `location` where an item could be
+    /// dropped, starting with the location itself, at most `count`.
+    fn drop_spots(&self, location: Location, count: usize) -> Vec<Location> {
+        let taken: HashSet<(u16, u16)> = self
+            .floor_items
+            .read()
+            .values()
+            .map(|item| item.basic().location())
+            .map(|loc| (loc.x, loc.y))
+            .collect();
+        // Only check the tile access if the floor is loaded.
+        let loaded = self.floor.loaded();
+        let center = (location.x, location.y);
+        self.floor
+            .tiles_around(center, DROP_RADIUS, count, |xy, tile| {
+                let open = match tile {
+                    Some(tile) => tile.access != TileAccess::Terrain,
+                    None => !loaded,
+                };
+                open && !taken.contains(&xy)
+            })
+            .into_iter()
+            .map(|(x, y)| Location::new(x, y, location.direction))
+            .collect()
+    }
+
     pub fn with_regions<F, R>(&self, f: F) -> R
     where
         F: FnOnce(&Vec<MapRegion>) -> R,
@@ -103,49 +643,67 @@ impl Map {
 
     #[tracing::instrument(skip(self))]
     pub fn region(&self, x: u16, y: u16) -> Option<MapRegion> {
-        let regions = self.regions.read();
-        let map_size = self.floor.boundaries();
-        let region_size = MapRegion::SIZE;
-        let region_x = x as u32 / region_size.width;
-        let region_y = y as u32 / region_size.height;
-        let width =
-            (map_size.width as f32 / region_size.width as f32).ceil() as u32;
-        let region_index = region_x * width + region_y;
+        let index = self.region_index(x, y)?;
+        self.regions.read().get(index).cloned()
+    }
+
+    /// The index of the region that has `(x, y)` in it.
+    fn region_index(&self, x: u16, y: u16) -> Option<usize> {
+        let grid = self.region_grid();
+        let region_x = x as u32 / self.region_size.width;
+        let region_y = y as u32 / self.region_size.height;
+        if region_x >= grid.width || region_y >= grid.height {
+            return None;
+        }
+        let region_index = region_y * grid.width + region_x;
         tracing::trace!(%x, %y, %region_x, %region_y, %region_index, "Querying Region");
-        regions.get(region_index as usize).cloned()
+        Some(region_index as usize)
     }
 
-    /// Get a list of the regions that surround the given point.
+    /// Get a list of the regions that surround the given point, that is every
+    /// region that could have something within the screen of that point.
+    ///
+    /// The region of the point itself always comes first.
     #[tracing::instrument(skip(self))]
     pub fn surrunding_regions(&self, x: u16, y: u16) -> Vec<MapRegion> {
+        self.regions_within(x, y, SCREEN_DISTANCE)
+    }
+
+    /// Every region that could have something within `radius` tiles of the
+    /// given point, starting with the region of the point itself.
+    fn regions_within(&self, x: u16, y: u16, radius: u16) -> Vec<MapRegion> {
         let regions = self.regions.read();
-        let map_size = self.floor.boundaries();
-        let region_size = MapRegion::SIZE;
-        let height =
-            (map_size.height as f32 / region_size.height as f32).ceil() as u32;
-        let width =
-            (map_size.width as f32 / region_size.width as f32).ceil() as u32;
+        let grid = self.region_grid();
+        let region_size = self.region_size;
         let region_x = x as u32 / region_size.width;
         let region_y = y as u32 / region_size.height;
-        let region_index = |x, y| (x * width + y) as usize;
+        if region_x >= grid.width || region_y >= grid.height {
+            return Vec::new();
+        }
+        // Regions smaller than the radius need more than their direct
+        // neighbours to cover it.
+        let reach_x = (radius as u32).div_ceil(region_size.width);
+        let reach_y = (radius as u32).div_ceil(region_size.height);
+        let xs = region_x.saturating_sub(reach_x)
+            ..=(region_x + reach_x).min(grid.width - 1);
+        let ys = region_y.saturating_sub(reach_y)
+            ..=(region_y + reach_y).min(grid.height - 1);
         let mut result = Vec::new();
-        // insert the current region
-        if let Some(region) = regions.get(region_index(region_x, region_y)) {
+        // insert the current region first
+        if let Some(region) =
+            regions.get((region_y * grid.width + region_x) as usize)
+        {
             result.push(region.clone());
         }
-        for i in 0..constants::WALK_XCOORDS.len() {
-            let view_x = region_x as i32 + constants::WALK_XCOORDS[i] as i32;
-            let view_y = region_y as i32 + constants::WALK_YCOORDS[i] as i32;
-            if view_x.is_negative()
-                || view_y.is_negative()
-                || view_x >= width as _
-                || view_y >= height as _
-            {
-                continue;
-            }
-            let j = region_index(view_x as u32, view_y as u32);
-            if let Some(region) = regions.get(j) {
-                result.push(region.clone());
+        for view_y in ys {
+            for view_x in xs.clone() {
+                if (view_x, view_y) == (region_x, region_y) {
+                    continue;
+                }
+                let j = (view_y * grid.width + view_x) as usize;
+                if let Some(region) = regions.get(j) {
+                    result.push(region.clone());
+                }
             }
         }
         result
@@ -158,18 +716,25 @@ impl Map {
     /// will be loaded for the server.
     #[tracing::instrument(skip_all, fields(map_id = self.id()))]
     pub async fn load(&self) -> Result<(), Error> {
+        if self.loaded() {
+            return Ok(());
+        }
+        let _loading = self.loading.lock().await;
+        // Someone else could have loaded it while we waited.
         if self.loaded() {
             return Ok(());
         }
         tracing::trace!("Loading into memory");
         self.floor.load().await?;
         let map_size = self.floor.boundaries();
-        let region_size = MapRegion::SIZE;
-        // ceil division to get the number of regions
-        let height =
-            (map_size.height as f32 / region_size.height as f32).ceil() as u32;
-        let width =
-            (map_size.width as f32 / region_size.width as f32).ceil() as u32;
+        let region_size = self.region_size;
+        if region_size.width > map_size.width as u32
+            || region_size.height > map_size.height as u32
+        {
+            self.floor.unload();
+            return Err(Error::InvalidRegionSize(region_size));
+        }
+        let Size { width, height } = self.region_grid();
         let number_of_regions = height * width;
         tracing::trace!(
             %map_size,
@@ -184,8 +749,8 @@ impl Map {
         for y in 0..height {
             for x in 0..width {
                 let start_point = Point::new(x, y);
-                let region = MapRegion::new(start_point, map_size);
-                let i = x * width + y;
+                let region = MapRegion::new(start_point, width);
+                let i = y * width + x;
                 regions[i as usize] = region;
                 tracing::trace!(%start_point, "Region created");
             }
@@ -200,6 +765,20 @@ impl Map {
         Ok(())
     }
 
+    /// Loads the map as an empty, fully walkable grid of the given size, for
+    /// tests that do not have the map files around.
+    #[cfg(test)]
+    pub async fn load_blank(&self, boundaries: Size<i32>) -> Result<(), Error> {
+        self.floor.load_blank(boundaries);
+        self.load().await
+    }
+
+    /// Changes a single tile, for tests that need walls on a blank map.
+    #[cfg(test)]
+    pub fn set_tile(&self, x: u16, y: u16, tile: Tile) {
+        self.floor.set_tile(x, y, tile);
+    }
+
     #[tracing::instrument(skip_all, fields(map_id = self.id()))]
     pub fn unload(&self) -> Result<(), Error> {
         tracing::trace!("Unload from memory");
@@ -222,7 +801,58 @@ impl Map {
         if !self.loaded() {
             self.load().await?;
         }
-        self.update_region_for(e);
+        // The entity could be coming from another map, so its previous
+        // location means nothing here.
+        let loc = e.basic().location();
+        match self.region(loc.x, loc.y) {
+            Some(region) => region.insert_entity(e),
+            None => {
+                tracing::warn!(
+                    %loc.x,
+                    %loc.y,
+                    "Can not find a suitable region for entity"
+                )
+            },
+        }
+        Ok(())
+    }
+
+    /// Moves an entity that was at `from_location` on the `from` map to its
+    /// current location on the `to` map. Both maps are locked together, see
+    /// [`with_two_maps`], so the entity is never missing from both.
+    #[tracing::instrument(skip_all, fields(from = from.id(), to = to.id(), entity_id = e.id()))]
+    pub async fn transfer_entity(
+        from: &Map,
+        to: &Map,
+        e: Arc<GameEntity>,
+        from_location: Location,
+    ) -> Result<(), Error> {
+        if !to.loaded() {
+            to.load().await?;
+        }
+        let loc = e.basic().location();
+        let old = from.region_index(from_location.x, from_location.y);
+        let new = to.region_index(loc.x, loc.y);
+        with_two_maps(from, to, |old_regions, new_regions| {
+            match new.and_then(|i| new_regions.get(i)) {
+                Some(region) => region.insert_entity(e.clone()),
+                None => {
+                    tracing::warn!(
+                        %loc.x,
+                        %loc.y,
+                        "Can not find a suitable region for entity"
+                    )
+                },
+            }
+            if let Some(region) = old.and_then(|i| old_regions.get(i)) {
+                region.remove_entity(e.id());
+            }
+        });
+        // if all entities are removed from the old map, unload it.
+        let empty = from.with_regions(|r| r.iter().all(|r| r.is_empty()));
+        if empty && !from.resident() {
+            from.unload()?;
+        }
         Ok(())
     }
 
@@ -242,7 +872,7 @@ impl Map {
         }
         // if all entities are removed from the map, unload it.
         let empty = self.with_regions(|r| r.iter().all(|r| r.is_empty()));
-        if empty {
+        if empty && !self.resident() {
             self.unload()?;
         }
         Ok(())
@@ -259,28 +889,11 @@ impl Map {
         end: (u16, u16),
         elevation: u16,
     ) -> bool {
-        let distance = tq_math::get_distance(start, end) as u16;
-        // If the distance is 0, we are not moving.
-        if distance == 0 {
-            return true;
-        }
-        let delta = tq_math::delta(start, end);
-        for i in 0..distance {
-            let x = start.0 + ((i.saturating_mul(delta.0)) / distance);
-            let y = start.1 + ((i.saturating_mul(delta.1)) / distance);
-            let tile = self.floor.tile(x, y);
-            match tile {
-                Some(tile) => {
-                    let within_elevation =
-                        tq_math::within_elevation(tile.elevation, elevation);
-                    if !within_elevation {
-                        return false;
-                    }
-                },
-                None => return false,
-            }
-        }
-        true
+        tq_math::tiles_between(start, end).all(|(x, y)| {
+            self.floor.tile(x, y).is_some_and(|tile| {
+                tq_math::within_elevation(tile.elevation, elevation)
+            })
+        })
     }
 
     /// Updates the region for an entity. This method is called when an entity
@@ -288,8 +901,10 @@ impl Map {
     /// into the new region.
     #[tracing::instrument(skip_all, fields(map_id = self.id(), entity_id = e.as_ref().id()))]
     pub fn update_region_for(&self, e: Arc<GameEntity>) {
-        let loc = e.basic().location();
-        let prev_loc = e.basic().prev_location();
+        let Locations {
+            current: loc,
+            prev: prev_loc,
+        } = e.basic().locations();
         let region = self.region(loc.x, loc.y);
         let old_region = self.region(prev_loc.x, prev_loc.y);
         match (region, old_region) {
@@ -321,41 +936,57 @@ impl Map {
     /// act as "send to all" method, this method sends a packet to
     /// all characters inside this map.
     ///
-    /// Internally, this method will iterate over all regions and call
-    /// the `broadcast` method on each region.
-    #[tracing::instrument(skip(self, packet), fields(map_id = self.id(), packet_id = P::PACKET_ID))]
+    /// Internally, this method sends to a [`Self::characters_snapshot`], so
+    /// no lock is held while sending. The packet is encoded once for every
+    /// codec the clients use, see [`SharedPacket`], whatever the number of
+    /// characters.
     pub async fn broadcast<P>(&self, packet: P) -> Result<(), P::Error>
     where
-        P: PacketEncode + PacketID + Clone,
+        P: PacketEncode + PacketID,
     {
-        let futs = FuturesUnordered::new();
-        self.with_regions(|regions| {
-            let regions = regions.iter().filter(|r| !r.is_empty()).cloned();
-            for region in regions {
-                let p = packet.clone();
-                let f = async move { region.broadcast(p).await };
-                futs.push(f);
-            }
-        });
-        // await all futures to complete.
-        futs.for_each_concurrent(None, |res| async {
-            match res {
-                Ok(_) => {},
-                Err(e) => {
-                    tracing::error!(error = ?e, "Failed to broadcast packet");
-                },
+        self.broadcast_except(packet, &[]).await
+    }
+
+    /// Like [`Self::broadcast`], but the characters with the given ids are
+    /// left out, like the one that caused whatever the packet is about.
+    #[tracing::instrument(skip(self, packet), fields(map_id = self.id(), packet_id = P::PACKET_ID))]
+    pub async fn broadcast_except<P>(
+        &self,
+        packet: P,
+        exclude_ids: &[u32],
+    ) -> Result<(), P::Error>
+    where
+        P: PacketEncode + PacketID,
+    {
+        let mut shared = SharedPacket::new(&packet);
+        let mut sends = Vec::new();
+        for e in self.characters_snapshot() {
+            if exclude_ids.contains(&e.id()) {
+                continue;
             }
-        })
-        .await;
+            let Some(owner) = e.owner() else {
+                continue;
+            };
+            let msg = shared.encode_for(&owner)?;
+            sends.push((
+                e.id(),
+                async move { owner.try_send_encoded(msg).await },
+            ));
+        }
+        // The dead ones are still on the map until their connection gets
+        // cleaned up, nothing to forget here.
+        fan_out(sends).await;
         Ok(())
     }
 
+    /// Changes the weather of the map, until its attributes get reset.
     pub async fn change_weather(
         &self,
         weather: WeatherKind,
     ) -> Result<(), Error> {
-        let msg = MsgWeather::new(weather);
-        self.broadcast(msg).map_err(Into::into).await
+        let mut attributes = self.attributes();
+        attributes.weather = Some(weather);
+        self.set_attributes(attributes).await
     }
 
     /// A batched version of [`Self::insert_entity`].
@@ -371,6 +1002,32 @@ impl Map {
     }
 }
 
+/// Locks the regions of both maps and hands them to `f`, in the order the
+/// maps were given.
+///
+/// Whatever the order of the arguments, the map with the lower id is always
+/// locked first, so two of these running at once on the same maps could not
+/// deadlock. Anything that needs two maps at once should go through here.
+pub fn with_two_maps<F, R>(a: &Map, b: &Map, f: F) -> R
+where
+    F: FnOnce(&[MapRegion], &[MapRegion]) -> R,
+{
+    if std::ptr::eq(a, b) {
+        let regions = a.regions.read();
+        return f(&regions, &regions);
+    }
+    let key = |m: &Map| (m.id(), m as *const Map as usize);
+    if key(a) < key(b) {
+        let a_regions = a.regions.read();
+        let b_regions = b.regions.read();
+        f(&a_regions, &b_regions)
+    } else {
+        let b_regions = b.regions.read();
+        let a_regions = a.regions.read();
+        f(&a_regions, &b_regions)
+    }
+}
+
 /// A region or a block is a set of the map which will hold a collection with
 /// all entities in an area. This will help us iterating over a limited
 /// number of entites when trying to process AI and movement. Instead of
@@ -378,8 +1035,10 @@ impl Map {
 /// iterate the regions around us.
 #[derive(Debug, Default, Clone)]
 pub struct MapRegion {
+    /// The position of the region in the grid of regions of its map.
     start_point: Point<u32>,
-    map_size: Size<i32>,
+    /// The number of regions along the width of its map.
+    grid_width: u32,
     entities: Arc<Entities>,
 }
 
@@ -397,27 +1056,39 @@ impl fmt::Display for MapRegion {
 }
 
 impl MapRegion {
-    /// WIDTH and HEIGHT are the number of tiles in a region.
+    /// WIDTH and HEIGHT are the number of tiles in a region, unless the map
+    /// uses its own, see [`Map::with_region_size`].
     pub const SIZE: Size<u32> =
         Size::new(SCREEN_DISTANCE as _, SCREEN_DISTANCE as _);
 
-    pub fn new(start_point: Point<u32>, map_size: Size<i32>) -> Self {
+    pub fn new(start_point: Point<u32>, grid_width: u32) -> Self {
         Self {
             start_point,
-            map_size,
+            grid_width,
             entities: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
+    /// The index of the region in its map.
     pub fn id(&self) -> usize {
-        let width = (self.map_size.width as f32 / Self::SIZE.width as f32)
-            .ceil() as u32;
         let Point { x, y } = self.start_point;
-        (x * width + y) as usize
+        (y * self.grid_width + x) as usize
     }
 
+    /// Whether nothing is in the region, cheap enough to skip regions
+    /// before looking into them.
     pub fn is_empty(&self) -> bool { self.with_entities(|c| c.is_empty()) }
 
+    /// The characters in the region right now.
+    pub fn characters(&self) -> Vec<Arc<GameEntity>> {
+        self.with_entities(|c| {
+            c.values()
+                .filter_map(|v| v.upgrade())
+                .filter(|e| e.is_character())
+                .collect()
+        })
+    }
+
     pub fn try_entities(&self, id: u32) -> Option<Weak<GameEntity>> {
         self.with_entities(|c| c.get(&id).cloned())
     }
@@ -453,20 +1124,18 @@ impl MapRegion {
     where
         P: PacketEncode + PacketID + Clone,
     {
-        let futs = FuturesUnordered::new();
-        self.with_entities(|entities| {
-            for character in entities.values() {
-                let p = packet.clone();
-                let Some(owner) = character.upgrade().and_then(|c| c.owner())
-                else {
-                    continue;
-                };
-                let f = async move { owner.send(p).await };
-                futs.push(f);
-            }
+        let sends: Vec<_> = self.with_entities(|entities| {
+            entities
+                .values()
+                .filter_map(|e| e.upgrade())
+                .filter_map(|e| {
+                    let owner = e.owner()?;
+                    let p = packet.clone();
+                    Some((e.id(), async move { owner.try_send(p).await }))
+                })
+                .collect()
         });
-        // await all futures to complete.
-        futs.for_each_concurrent(None, |_| async {}).await;
+        fan_out(sends).await;
         Ok(())
     }
 }
@@ -588,6 +1257,8 @@ pub enum Maps {
 #[cfg(test)]
 mod tests {
     use futures::FutureExt;
+    use tokio::sync::mpsc::Receiver;
+    use tq_network::{Message, PacketDecode};
 
     use super::*;
     use crate::test_utils::*;
@@ -609,4 +1280,557 @@ mod tests {
         })
         .await
     }
+
+    #[tokio::test]
+    async fn custom_region_size() -> Result<(), Error> {
+        let map = Map::default().with_region_size(Size::new(10, 10))?;
+        map.load_blank(Size::new(50, 30)).await?;
+        assert_eq!(map.with_regions(|r| r.len()), 15);
+        let id = |x, y| map.region(x, y).map(|r| r.id());
+        assert_eq!(id(0, 0), Some(0));
+        assert_eq!(id(9, 9), Some(0));
+        assert_eq!(id(15, 5), Some(1));
+        assert_eq!(id(5, 15), Some(5));
+        assert_eq!(id(49, 29), Some(14));
+        assert_eq!(id(50, 0), None);
+        assert_eq!(id(0, 30), None);
+
+        // Regions are smaller than the screen, so two of them on every side
+        // are needed to cover it.
+        let around: HashSet<_> = map
+            .surrunding_regions(5, 5)
+            .iter()
+            .map(MapRegion::id)
+            .collect();
+        assert_eq!(around, HashSet::from([0, 1, 2, 5, 6, 7, 10, 11, 12]));
+        assert_eq!(map.surrunding_regions(25, 15).len(), 15);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn players_in_the_next_region_are_in_range() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let map_id = u32::from(Maps::Arena);
+                let map = state.try_map(map_id)?;
+                map.load_blank(Size::new(200, 200)).await?;
+                let [(near, _), (far, _)] = actors;
+                // The bottom right corner of a region, and far away.
+                for (actor, x) in [(&near, 35), (&far, 150)] {
+                    let e = actor.entity();
+                    e.basic().set_map_id(map_id);
+                    e.basic().set_location(Location::new(x, 35, 0));
+                    map.insert_entity(e).await?;
+                }
+                let (corner, next) = (map.region(35, 35), map.region(36, 36));
+                assert_ne!(corner.map(|r| r.id()), next.map(|r| r.id()));
+
+                let found: Vec<_> = map
+                    .players_in_range((36, 36), 5)
+                    .iter()
+                    .map(|e| e.id())
+                    .collect();
+                assert_eq!(found, [near.entity().id()]);
+                let found = map.players_in_range((45, 35), 5);
+                assert!(found.is_empty());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn own_region_is_surrounding() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let map_id = u32::from(Maps::Arena);
+                let map = state.try_map(map_id)?;
+                map.load_blank(Size::new(200, 200)).await?;
+                let [(a, _), _] = actors;
+                let e = a.entity();
+                e.basic().set_map_id(map_id);
+                e.basic().set_location(Location::new(50, 50, 0));
+                map.insert_entity(e.clone()).await?;
+
+                let own = map.region(50, 50).map(|r| r.id());
+                let around = map.surrunding_regions(50, 50);
+                assert_eq!(around.first().map(|r| r.id()), own);
+                // Nothing reaches out of the region, only it is looked at.
+                let found = map.players_in_range((50, 50), 0);
+                assert_eq!(found.len(), 1);
+                assert_eq!(found[0].id(), e.id());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn snapshot_during_moves_is_consistent() -> Result<(), Error> {
+        with_test_env(tracing::Level::INFO, |_state, actors| {
+            async move {
+                let [(a, _), (b, _)] = actors;
+                let map = Map::default().with_region_size(Size::new(10, 10))?;
+                map.load_blank(Size::new(50, 50)).await?;
+                let (a, b) = (a.entity(), b.entity());
+                let place = |e: &Arc<GameEntity>, x, y| {
+                    let mut loc = e.basic().location();
+                    (loc.x, loc.y) = (x, y);
+                    e.basic().set_location(loc);
+                    map.update_region_for(e.clone());
+                };
+                place(&a, 5, 5);
+                let done = std::sync::atomic::AtomicBool::new(false);
+                std::thread::scope(|s| {
+                    s.spawn(|| {
+                        // b keeps coming in and out, while a keeps moving
+                        // between the first and the last region.
+                        for i in 0..2_000u16 {
+                            if i % 2 == 0 {
+                                place(&b, 25, 25);
+                                place(&a, 45, 45);
+                            } else {
+                                map.remove_entity(&b).unwrap();
+                                place(&a, 5, 5);
+                            }
+                        }
+                        done.store(true, std::sync::atomic::Ordering::SeqCst);
+                    });
+                    while !done.load(std::sync::atomic::Ordering::SeqCst) {
+                        let ids: Vec<_> = map
+                            .characters_snapshot()
+                            .iter()
+                            .map(|e| e.id())
+                            .collect();
+                        let count =
+                            |id| ids.iter().filter(|&&i| i == id).count();
+                        assert_eq!(count(a.id()), 1, "{ids:?}");
+                        assert!(count(b.id()) <= 1, "{ids:?}");
+                        assert_eq!(ids.len(), 1 + count(b.id()));
+                    }
+                });
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn excluded_characters_get_nothing() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, mut a_rx), (b, mut b_rx)] = actors;
+                let (c, mut c_rx) = make_extra_actor(&state, "third").await?;
+                let map = Map::default().with_region_size(Size::new(10, 10))?;
+                map.load_blank(Size::new(50, 50)).await?;
+                // Far apart, in different regions.
+                for (actor, x) in [(&a, 5), (&b, 25), (&c, 45)] {
+                    let e = actor.entity();
+                    e.basic().set_location(Location::new(x, x, 0));
+                    map.update_region_for(e);
+                }
+                let weathers = |rx: &mut Receiver<Message>| {
+                    std::iter::from_fn(|| rx.try_recv().ok())
+                        .filter(|msg| {
+                            matches!(msg, Message::Packet(id, _) if *id == MsgWeather::PACKET_ID)
+                        })
+                        .count()
+                };
+
+                let rain = MsgWeather::rain();
+                map.broadcast_except(rain.clone(), &[b.entity().id()])
+                    .await?;
+                assert_eq!(weathers(&mut a_rx), 1);
+                assert_eq!(weathers(&mut b_rx), 0);
+                assert_eq!(weathers(&mut c_rx), 1);
+
+                map.broadcast(rain).await?;
+                for rx in [&mut a_rx, &mut b_rx, &mut c_rx] {
+                    assert_eq!(weathers(rx), 1);
+                }
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn two_maps_in_any_order_do_not_deadlock() -> Result<(), Error> {
+        let blank = |id| async move {
+            let inner = tq_db::map::Map {
+                id,
+                map_id: id,
+                ..Default::default()
+            };
+            let map =
+                Map::new(inner, Vec::new(), Vec::new(), Default::default());
+            map.load_blank(Size::new(50, 50))
+                .await
+                .map(|_| Arc::new(map))
+        };
+        let (a, b) = (blank(1002).await?, blank(1010).await?);
+        let (tx, rx) = std::sync::mpsc::channel();
+        for i in 0..8 {
+            let (a, b, tx) = (a.clone(), b.clone(), tx.clone());
+            std::thread::spawn(move || {
+                for _ in 0..2_000 {
+                    match i % 4 {
+                        0 => with_two_maps(&a, &b, |x, y| x.len() + y.len()),
+                        1 => with_two_maps(&b, &a, |x, y| x.len() + y.len()),
+                        // Writers in between make a reader wait, so taking
+                        // the locks in the wrong order would hang here.
+                        2 => a.regions.write().len(),
+                        _ => b.regions.write().len(),
+                    };
+                }
+                let _ = tx.send(());
+            });
+        }
+        drop(tx);
+        for _ in 0..8 {
+            rx.recv_timeout(std::time::Duration::from_secs(10))
+                .expect("deadlocked while locking two maps");
+        }
+        // The same map twice is only locked once.
+        let len = a.with_regions(|r| r.len());
+        assert_eq!(with_two_maps(&a, &a, |x, y| x.len() + y.len()), 2 * len);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalid_region_size() -> Result<(), Error> {
+        let zero = Map::default().with_region_size(Size::new(0, 10));
+        assert!(matches!(zero, Err(Error::InvalidRegionSize(_))));
+        let map = Map::default().with_region_size(Size::new(64, 64))?;
+        let loaded = map.load_blank(Size::new(50, 50)).await;
+        assert!(matches!(loaded, Err(Error::InvalidRegionSize(_))));
+        assert!(!map.loaded());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn monster_drops_spawn_floor_items() -> Result<(), Error> {
+        use crate::systems::DropTable;
+        use rand::SeedableRng;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let map = Map::default();
+        let monster = MonsterType::new(1, "Pheasant")
+            .with_drops(DropTable::new().with_silver(10..=20, 1).with_rolls(3));
+        let location = Location::new(50, 50, 0);
+        let items = map
+            .on_monster_killed(&monster, location, None, &mut rng)
+            .await?;
+        assert_eq!(items.len(), 3);
+        let spots: HashSet<_> = items
+            .iter()
+            .map(|item| item.basic().location())
+            .map(|loc| (loc.x, loc.y))
+            .collect();
+        assert_eq!(spots.len(), 3, "drops should not be stacked");
+        for item in &items {
+            let floor_item = item.as_floor_item().expect("floor item");
+            assert!((10..=20).contains(&floor_item.money()));
+            assert!(map.floor_item(item.id()).is_some());
+        }
+
+        // Whatever drops from a kill is kept for the killer.
+        let killed = map
+            .on_monster_killed(&monster, location, Some(1_000_001), &mut rng)
+            .await?;
+        for item in &killed {
+            let floor_item = item.as_floor_item().expect("floor item");
+            assert!(floor_item.is_protected_for(1_000_001));
+            assert!(!floor_item.is_protected_for(1_000_002));
+        }
+
+        let nothing = MonsterType::new(2, "Turtledove");
+        assert!(map
+            .on_monster_killed(&nothing, location, None, &mut rng)
+            .await?
+            .is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_drops_give_their_ids_back() -> Result<(), Error> {
+        use crate::systems::DropTable;
+        use rand::SeedableRng;
+
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let map_id = u32::from(Maps::Arena);
+                let map = state.try_map(map_id)?;
+                map.load_blank(Size::new(100, 100)).await?;
+                let [(a, _), _] = actors;
+                let e = a.entity();
+                e.basic().set_map_id(map_id);
+                e.basic().set_location(Location::new(50, 50, 0));
+                map.insert_entity(e.clone()).await?;
+                // Someone around whose screen is gone, showing them the
+                // drop fails.
+                e.as_character().unwrap().set_screen(Weak::new());
+
+                let next = state.ids().allocate(IdKind::FloorItem)?;
+                state.ids().free(next);
+                let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+                let monster = MonsterType::new(1, "Pheasant")
+                    .with_drops(DropTable::new().with_silver(10..=20, 1));
+                let location = Location::new(52, 50, 0);
+                let killed = map
+                    .on_monster_killed(&monster, location, None, &mut rng)
+                    .await;
+                assert!(matches!(killed, Err(Error::ScreenNotFound)));
+                assert!(map.floor_item(next).is_none());
+                assert_eq!(state.ids().allocate(IdKind::FloorItem)?, next);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn floor_items_enter_and_leave_screen() -> Result<(), Error> {
+        use crate::packets::MapItemAction;
+
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let map_id = u32::from(Maps::Arena);
+                let map = state.try_map(map_id)?;
+                map.load_blank(Size::new(200, 200)).await?;
+                let [(walker, mut walker_rx), (picker, mut picker_rx)] = actors;
+                for (actor, x) in [(&walker, 40), (&picker, 80)] {
+                    let e = actor.entity();
+                    e.basic().set_map_id(map_id);
+                    e.basic().set_location(Location::new(x, 50, 0));
+                    map.insert_entity(e).await?;
+                }
+
+                // Two screens away from the walker, right next to the picker.
+                let item = FloorItem::new(
+                    state.ids().allocate(IdKind::FloorItem)?,
+                    FloorItemKind::Item(1000000),
+                    map_id,
+                    Location::new(76, 50, 0),
+                );
+                let item = map.insert_floor_item(item).await?;
+                let spawns = packets_of::<MsgMapItem>(&mut picker_rx);
+                assert_eq!(spawns.len(), 1);
+                assert_eq!(spawns[0].action(), MapItemAction::Create);
+                assert!(packets_of::<MsgMapItem>(&mut walker_rx).is_empty());
+
+                let walker_screen = walker.screen();
+                for x in 41..=60 {
+                    let e = walker.entity();
+                    e.basic().set_location(Location::new(x, 50, 0));
+                    map.update_region_for(e.clone());
+                    let msg = MsgAction::new(e.id(), 0, 0, 0, ActionType::Jump);
+                    walker_screen.send_movement(&state, msg).await?;
+                    let spawns = packets_of::<MsgMapItem>(&mut walker_rx);
+                    if x == 58 {
+                        assert_eq!(spawns.len(), 1, "item should enter at {x}");
+                        assert_eq!(spawns[0].id, item.id());
+                        assert_eq!(spawns[0].action(), MapItemAction::Create);
+                    } else {
+                        assert!(spawns.is_empty(), "unexpected spawn at {x}");
+                    }
+                }
+                let in_screen =
+                    walker_screen.with_entities(|c| c.contains_key(&item.id()));
+                assert!(in_screen);
+
+                // Someone else picks the item up.
+                map.remove_floor_item(item.id()).await?;
+                let removes = packets_of::<MsgMapItem>(&mut walker_rx);
+                assert_eq!(removes.len(), 1);
+                assert_eq!(removes[0].id, item.id());
+                assert_eq!(removes[0].action(), MapItemAction::Delete);
+                assert_eq!(packets_of::<MsgMapItem>(&mut picker_rx).len(), 1);
+                let in_screen =
+                    walker_screen.with_entities(|c| c.contains_key(&item.id()));
+                assert!(!in_screen);
+                assert!(map.floor_item(item.id()).is_none());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn floor_items_expire() -> Result<(), Error> {
+        use crate::entities::FLOOR_ITEM_EXPIRY;
+        use crate::packets::MapItemAction;
+
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let map_id = u32::from(Maps::Arena);
+                let map = state.try_map(map_id)?;
+                map.load_blank(Size::new(100, 100)).await?;
+                let [(a, mut a_rx), _] = actors;
+                let e = a.entity();
+                e.basic().set_map_id(map_id);
+                e.basic().set_location(Location::new(50, 50, 0));
+                map.insert_entity(e).await?;
+
+                let id = state.ids().allocate(IdKind::FloorItem)?;
+                let item = FloorItem::new(
+                    id,
+                    FloorItemKind::Silver(100),
+                    map_id,
+                    Location::new(52, 50, 0),
+                );
+                let dropped_at = item.dropped_at();
+                map.insert_floor_item(item).await?;
+                packets_of::<MsgMapItem>(&mut a_rx);
+
+                let almost = dropped_at + FLOOR_ITEM_EXPIRY / 2;
+                state.expire_floor_items(almost).await;
+                assert!(map.floor_item(id).is_some());
+
+                state
+                    .expire_floor_items(dropped_at + FLOOR_ITEM_EXPIRY)
+                    .await;
+                assert!(map.floor_item(id).is_none());
+                let removes = packets_of::<MsgMapItem>(&mut a_rx);
+                assert_eq!(removes.len(), 1);
+                assert_eq!(removes[0].action(), MapItemAction::Delete);
+                // Its id could be used again.
+                assert_eq!(state.ids().allocate(IdKind::FloorItem)?, id);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn monsters_enter_and_leave_screen() -> Result<(), Error> {
+        use crate::packets::MsgPlayer;
+
+        fn spawns(rx: &mut Receiver<Message>, id: u32) -> usize {
+            let mut spawns = 0;
+            while let Ok(msg) = rx.try_recv() {
+                if let Message::Packet(MsgPlayer::PACKET_ID, bytes) = msg {
+                    let msg = MsgPlayer::decode(&bytes).unwrap();
+                    if msg.character_id as u32 == id {
+                        assert_eq!(msg.character_name, "Pheasant");
+                        spawns += 1;
+                    }
+                }
+            }
+            spawns
+        }
+
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let map_id = u32::from(Maps::Arena);
+                let map = state.try_map(map_id)?;
+                map.load_blank(Size::new(200, 200)).await?;
+                let [(walker, mut walker_rx), (near, mut near_rx)] = actors;
+                for (actor, x) in [(&walker, 40), (&near, 80)] {
+                    let e = actor.entity();
+                    e.basic().set_map_id(map_id);
+                    e.basic().set_location(Location::new(x, 50, 0));
+                    map.insert_entity(e).await?;
+                }
+
+                let kind = Arc::new(MonsterType::new(1, "Pheasant"));
+                let monster =
+                    map.spawn_monster(kind, Location::new(76, 50, 0)).await?;
+                assert!(monster.is_monster());
+                assert_eq!(spawns(&mut near_rx, monster.id()), 1);
+                assert_eq!(spawns(&mut walker_rx, monster.id()), 0);
+
+                let walker_screen = walker.screen();
+                // Walk until it comes into sight, then back out of it.
+                let there = (41..=60).map(|x| (x, true));
+                let back = (40..=59).rev().map(|x| (x, false));
+                for (x, forward) in there.chain(back) {
+                    let e = walker.entity();
+                    e.basic().set_location(Location::new(x, 50, 0));
+                    map.update_region_for(e.clone());
+                    let msg = MsgAction::new(e.id(), 0, 0, 0, ActionType::Jump);
+                    walker_screen.send_movement(&state, msg).await?;
+                    let seen = spawns(&mut walker_rx, monster.id());
+                    assert_eq!(seen, usize::from(forward && x == 58), "at {x}");
+                    let in_screen = walker_screen
+                        .with_entities(|c| c.contains_key(&monster.id()));
+                    assert_eq!(in_screen, x >= 58, "at {x}");
+                }
+
+                map.remove_monster(monster.id()).await?;
+                let in_screen = near
+                    .screen()
+                    .with_entities(|c| c.contains_key(&monster.id()));
+                assert!(!in_screen);
+                assert!(map.monster(monster.id()).is_none());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn available_tile_spirals_out() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let map_id = u32::from(Maps::Arena);
+                let map = state.try_map(map_id)?;
+                map.load_blank(Size::new(40, 40)).await?;
+                let wall = Tile {
+                    access: TileAccess::Terrain,
+                    elevation: 0,
+                };
+                // Everything within 3 tiles of (20, 20) is blocked, but for
+                // two tiles on the outer ring, and a monster only tile.
+                for x in 17..=23 {
+                    for y in 17..=23 {
+                        map.set_tile(x, y, wall);
+                    }
+                }
+                let open = Tile {
+                    access: TileAccess::Available,
+                    elevation: 0,
+                };
+                map.set_tile(23, 23, open);
+                map.set_tile(17, 22, open);
+                let monsters_only = Tile {
+                    access: TileAccess::Monster,
+                    elevation: 0,
+                };
+                map.set_tile(19, 20, monsters_only);
+
+                assert_eq!(map.find_available_tile((20, 20), 2), None);
+                // The first one on the ring, every time.
+                for _ in 0..3 {
+                    let found = map.find_available_tile((20, 20), 5);
+                    assert_eq!(found, Some((17, 22)));
+                }
+                // A tile that is fine is its own answer.
+                assert_eq!(map.find_available_tile((5, 5), 5), Some((5, 5)));
+
+                // A stall takes its tile.
+                let [(a, _), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                me.entity()
+                    .set_map_id(map_id)
+                    .set_location(Location::new(17, 22, 0));
+                map.insert_entity(entity.clone()).await?;
+                crate::systems::open_stall(me).await?;
+                let found = map.find_available_tile((20, 20), 5);
+                assert_eq!(found, Some((23, 23)));
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
 }
@@ -0,0 +1,157 @@
+//! Overrides of the attributes of a map, that events put in place for a while
+//! without touching the database: no PK on the market, snow in Twin City for
+//! Christmas and the like.
+//!
+//! Anything not overridden comes from the database, and the overrides are
+//! gone with a restart.
+
+use crate::packets::WeatherKind;
+use crate::systems::{Job, Schedule};
+use crate::{Error, State};
+use chrono::NaiveDateTime;
+use futures::FutureExt;
+
+/// The attributes of a map overridden at runtime, `None` for the ones that
+/// come from the database.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MapAttributes {
+    /// Whether players could attack each other.
+    pub pk_allowed: Option<bool>,
+    pub weather: Option<WeatherKind>,
+    /// The ambient color of the map.
+    pub argb: Option<u32>,
+    /// How much more experience is gained on the map, in percent.
+    pub exp_bonus: Option<u32>,
+}
+
+impl MapAttributes {
+    /// Overrides the attribute named `key`, as given to the `mapattr`
+    /// command: `pk on|off`, `weather <kind>`, `argb <hex>` or
+    /// `exp <percent>`.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        let invalid =
+            || Error::Other(format!("Invalid value for {key}: {value:?}"));
+        match key {
+            "pk" => {
+                self.pk_allowed = match value {
+                    "on" => Some(true),
+                    "off" => Some(false),
+                    _ => return Err(invalid()),
+                };
+            },
+            "weather" => {
+                let kind: u32 = value.parse().map_err(|_| invalid())?;
+                self.weather = Some(WeatherKind::from(kind));
+            },
+            "argb" => {
+                let hex = value.trim_start_matches("0x");
+                let argb =
+                    u32::from_str_radix(hex, 16).map_err(|_| invalid())?;
+                self.argb = Some(argb);
+            },
+            "exp" => {
+                self.exp_bonus = Some(value.parse().map_err(|_| invalid())?)
+            },
+            _ => {
+                return Err(Error::Other(format!(
+                    "Unknown map attribute: {key}"
+                )))
+            },
+        }
+        Ok(())
+    }
+}
+
+/// The name of the job that puts the attributes of the map back.
+fn revert_job(map_id: u32) -> String { format!("mapattr-revert-{map_id}") }
+
+/// Puts the attributes of the map back to the ones from the database at
+/// `at`, instead of any revert of that map scheduled before.
+pub fn schedule_attributes_revert(
+    state: &State,
+    map_id: u32,
+    at: NaiveDateTime,
+) {
+    let job = Job::new(revert_job(map_id), Schedule::Once(at), move |state| {
+        async move { state.try_map(map_id)?.reset_attributes().await }.boxed()
+    });
+    state.scheduler().register(job);
+}
+
+/// Drops the scheduled revert of the attributes of the map, if any.
+pub fn cancel_attributes_revert(state: &State, map_id: u32) {
+    state.scheduler().unregister(&revert_job(map_id));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use chrono::Duration;
+    use futures::FutureExt;
+
+    #[test]
+    fn attributes_are_set_by_name() {
+        let mut attrs = MapAttributes::default();
+        attrs.set("pk", "off").unwrap();
+        attrs.set("weather", "3").unwrap();
+        attrs.set("argb", "0xFF8000").unwrap();
+        attrs.set("exp", "50").unwrap();
+        assert_eq!(
+            attrs,
+            MapAttributes {
+                pk_allowed: Some(false),
+                weather: Some(WeatherKind::Snow),
+                argb: Some(0xFF8000),
+                exp_bonus: Some(50),
+            }
+        );
+        assert!(attrs.set("pk", "maybe").is_err());
+        assert!(attrs.set("music", "1").is_err());
+    }
+
+    #[tokio::test]
+    async fn attributes_revert_when_scheduled() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, _| {
+            async move {
+                let map = state.try_map(1002)?;
+                let weather = map.weather();
+                let mut attrs = map.attributes();
+                attrs.set("weather", "3")?;
+                attrs.set("pk", "off")?;
+                map.set_attributes(attrs).await?;
+                assert_eq!(map.weather(), WeatherKind::Snow);
+                assert!(!map.pk_allowed());
+
+                let now = chrono::Local::now().naive_local();
+                schedule_attributes_revert(
+                    &state,
+                    map.id(),
+                    now + Duration::minutes(30),
+                );
+                state.scheduler().tick(&state, now).await?;
+                state
+                    .scheduler()
+                    .tick(&state, now + Duration::minutes(29))
+                    .await?;
+                assert_eq!(map.weather(), WeatherKind::Snow);
+
+                state
+                    .scheduler()
+                    .tick(&state, now + Duration::minutes(31))
+                    .await?;
+                assert_eq!(map.attributes(), MapAttributes::default());
+                assert_eq!(map.weather(), weather);
+                assert!(map.pk_allowed());
+                // It only ran once.
+                assert!(state
+                    .scheduler()
+                    .next_run(&revert_job(1002))
+                    .is_none());
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
@@ -1,5 +1,13 @@
 mod map;
-pub use map::{Map, Maps};
+pub use map::{with_two_maps, Map, Maps, PLACEMENT_RADIUS};
+
+mod map_attributes;
+pub use map_attributes::{
+    cancel_attributes_revert, schedule_attributes_revert, MapAttributes,
+};
 
 mod portal;
-pub use portal::Portal;
+pub use portal::{Portal, PORTAL_COOLDOWN};
+
+mod snapshot;
+pub use snapshot::{snapshot_path_from_env, WorldSnapshot};
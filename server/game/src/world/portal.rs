@@ -1,8 +1,13 @@
 use crate::utils::LoHi;
 use std::hash::Hash;
 use std::ops::Deref;
+use std::time::Duration;
 
-#[derive(Debug)]
+/// How long a character has to wait by default after using a portal before
+/// it could use another one.
+pub const PORTAL_COOLDOWN: Duration = Duration::from_millis(1500);
+
+#[derive(Debug, Clone)]
 pub struct Portal {
     inner: tq_db::portal::Portal,
 }
@@ -20,6 +25,10 @@ impl Portal {
 
     pub fn id(&self) -> u32 { u32::constract(self.from_y(), self.from_x()) }
 
+    pub fn is_city_gate(&self) -> bool {
+        self.inner.flags & tq_db::portal::Portal::CITY_GATE != 0
+    }
+
     #[allow(clippy::wrong_self_convention)]
     pub fn from_map_id(&self) -> u32 { self.inner.from_map_id as u32 }
 
@@ -0,0 +1,343 @@
+//! Snapshots of the parts of the world that only live in memory, so a crash
+//! does not take player property with it.
+//!
+//! The items lying on the floor and the listings of the open stalls are
+//! written to a file every now and then, and restored when the server starts
+//! again. A snapshot that could not be read is skipped, it never stops the
+//! server from starting.
+
+use crate::entities::{
+    CharacterState, FloorItem, FloorItemKind, FLOOR_ITEM_EXPIRY,
+};
+use crate::state::IdKind;
+use crate::{Error, State};
+use primitives::Location;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tq_db::item::Item;
+
+/// Where the snapshot gets written, from the `SNAPSHOT_LOCATION` environment
+/// variable, falling back to `world_snapshot.json` in the data directory.
+pub fn snapshot_path_from_env() -> Result<PathBuf, Error> {
+    match dotenvy::var("SNAPSHOT_LOCATION") {
+        Ok(path) => Ok(PathBuf::from(path)),
+        Err(_) => {
+            let data_dir = dotenvy::var("DATA_LOCATION")?;
+            Ok(Path::new(&data_dir).join("world_snapshot.json"))
+        },
+    }
+}
+
+/// An item lying on the floor.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FloorItemSnapshot {
+    pub map_id: u32,
+    pub x: u16,
+    pub y: u16,
+    pub kind: FloorItemKind,
+    /// How long it was lying there, in milliseconds.
+    pub age: u64,
+    /// The character it was dropped for, if any.
+    #[serde(default)]
+    pub owner: Option<u32>,
+}
+
+/// The items a vending character put up for sale.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StallSnapshot {
+    pub character_id: i32,
+    /// The listed items and their prices.
+    pub listings: Vec<(u32, u32)>,
+}
+
+/// The state of the world that is not kept in the database.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorldSnapshot {
+    pub floor_items: Vec<FloorItemSnapshot>,
+    pub stalls: Vec<StallSnapshot>,
+}
+
+impl WorldSnapshot {
+    /// Takes a snapshot of the world as it is at `now`.
+    ///
+    /// Nothing gets awaited while it is taken, so the world could not change
+    /// halfway through from this task.
+    pub fn take(state: &State, now: Instant) -> Self {
+        let floor_items = state
+            .loaded_maps()
+            .into_iter()
+            .flat_map(|map| map.floor_items())
+            .filter_map(|entity| {
+                let item = entity.as_floor_item()?;
+                let location = item.location();
+                let age = now.saturating_duration_since(item.dropped_at());
+                Some(FloorItemSnapshot {
+                    map_id: item.map_id(),
+                    x: location.x,
+                    y: location.y,
+                    kind: item.kind(),
+                    age: age.as_millis() as u64,
+                    owner: item.owner(),
+                })
+            })
+            .collect();
+        let stalls = state
+            .entities()
+            .iter()
+            .filter_map(|entity| entity.as_character())
+            .filter(|c| c.state() == CharacterState::Vending)
+            .map(|c| StallSnapshot {
+                character_id: c.character_id(),
+                listings: c.stall().listings(),
+            })
+            .collect();
+        Self {
+            floor_items,
+            stalls,
+        }
+    }
+
+    /// Writes the snapshot to `path`. It is written next to it first and
+    /// then moved over it, so a crash while writing leaves the previous one.
+    pub async fn write(&self, path: &Path) -> Result<(), Error> {
+        let json = serde_json::to_vec(self)?;
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, json).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+
+    /// Reads the snapshot at `path`, `None` if there is none.
+    pub async fn read(path: &Path) -> Result<Option<Self>, Error> {
+        let json = match tokio::fs::read(path).await {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(None)
+            },
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(serde_json::from_slice(&json)?))
+    }
+
+    /// Puts the floor items back where they were, as old as they were when
+    /// the snapshot was taken, and returns the listed items to the
+    /// inventories of their owners.
+    ///
+    /// Items that expired meanwhile are left out, and the ones on a map that
+    /// could not be loaded or that could not be put back are skipped.
+    #[tracing::instrument(skip_all)]
+    pub async fn restore(
+        &self,
+        state: &State,
+        now: Instant,
+    ) -> Result<(), Error> {
+        let mut restored = 0;
+        for snapshot in &self.floor_items {
+            let map_id = snapshot.map_id;
+            let map = match state.try_map(map_id) {
+                Ok(map) => map,
+                Err(error) => {
+                    tracing::warn!(%error, %map_id, "Skipping floor item");
+                    continue;
+                },
+            };
+            if let Err(error) = map.load().await {
+                tracing::warn!(%error, %map_id, "Skipping floor item");
+                continue;
+            }
+            let age = Duration::from_millis(snapshot.age);
+            if age >= FLOOR_ITEM_EXPIRY {
+                continue;
+            }
+            let id = state.ids().allocate(IdKind::FloorItem)?;
+            let location = Location::new(snapshot.x, snapshot.y, 0);
+            let dropped_at = now.checked_sub(age).unwrap_or(now);
+            let mut item =
+                FloorItem::new(id, snapshot.kind, map.id(), location)
+                    .with_dropped_at(dropped_at);
+            if let Some(owner) = snapshot.owner {
+                item = item.with_owner(owner);
+            }
+            if let Err(error) = map.insert_floor_item(item).await {
+                tracing::warn!(%error, %map_id, "Skipping floor item");
+                map.discard_floor_item(id);
+                continue;
+            }
+            restored += 1;
+        }
+        let mut returned = 0;
+        for stall in &self.stalls {
+            returned +=
+                Item::return_from_booth(state.pool(), stall.character_id, None)
+                    .await?;
+        }
+        tracing::info!(%restored, %returned, "Restored the world snapshot");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::GameEntity;
+    use crate::systems;
+    use crate::test_utils::*;
+    use futures::FutureExt;
+    use primitives::Size;
+    use std::sync::Weak;
+
+    /// A snapshot file of its own for every test.
+    fn snapshot_path(test: &str) -> PathBuf {
+        let name = format!("coemu-{test}-{}.json", std::process::id());
+        std::env::temp_dir().join(name)
+    }
+
+    fn kinds_at(
+        state: &State,
+        map_id: u32,
+    ) -> Vec<(FloorItemKind, u16, Option<u32>)> {
+        let mut kinds: Vec<_> = state
+            .try_map(map_id)
+            .unwrap()
+            .floor_items()
+            .iter()
+            .filter_map(|e| e.as_floor_item())
+            .map(|i| (i.kind(), i.location().x, i.owner()))
+            .collect();
+        kinds.sort_by_key(|(_, x, _)| *x);
+        kinds
+    }
+
+    #[tokio::test]
+    async fn floor_items_and_stalls_survive_a_restart() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let entity = a.entity();
+                let me = entity.as_character().unwrap();
+                let map = state.try_map(1010)?;
+                map.load_blank(Size::new(100, 100)).await?;
+                let kinds = [
+                    (FloorItemKind::Item(410005), 10, Some(me.id())),
+                    (FloorItemKind::Silver(500), 20, None),
+                ];
+                for (kind, x, owner) in kinds {
+                    let id = state.ids().allocate(IdKind::FloorItem)?;
+                    let location = Location::new(x, 30, 0);
+                    let mut item = FloorItem::new(id, kind, 1010, location);
+                    if let Some(owner) = owner {
+                        item = item.with_owner(owner);
+                    }
+                    map.insert_floor_item(item).await?;
+                }
+                let (item_id,) = sqlx::query_as::<_, (i32,)>(
+                    "INSERT INTO items (character_id, item_type, position) VALUES (?, 410005, ?) RETURNING item_id;",
+                )
+                .bind(me.character_id())
+                .bind(Item::INVENTORY)
+                .fetch_one(state.pool())
+                .await?;
+                systems::open_stall(me).await?;
+                assert!(systems::list_item(&state, me, item_id as u32, 1000).await?);
+
+                let path = snapshot_path("restart");
+                let snapshot = WorldSnapshot::take(&state, Instant::now());
+                assert_eq!(snapshot.floor_items.len(), 2);
+                assert_eq!(snapshot.stalls[0].listings, [(item_id as u32, 1000)]);
+                snapshot.write(&path).await?;
+
+                // Starts again from the same database, with nothing in memory.
+                let mut restarted = State::with_pool(state.pool().clone()).await?;
+                restarted.set_snapshot_path(Some(path.clone()));
+                restarted.try_map(1010)?.load_blank(Size::new(100, 100)).await?;
+                assert!(kinds_at(&restarted, 1010).is_empty());
+                restarted.restore_snapshot().await?;
+                // The loot kept for someone is still theirs.
+                assert_eq!(kinds_at(&restarted, 1010), kinds);
+                let inventory =
+                    Item::inventory_of(state.pool(), me.character_id()).await?;
+                assert!(inventory.iter().any(|i| i.item_id == item_id));
+                let _ = tokio::fs::remove_file(&path).await;
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn floor_items_that_could_not_be_restored_are_skipped(
+    ) -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |state, actors| {
+            async move {
+                let [(a, _), _] = actors;
+                let map = state.try_map(1010)?;
+                map.load_blank(Size::new(100, 100)).await?;
+                let e = a.entity();
+                e.basic().set_map_id(1010);
+                e.basic().set_location(Location::new(80, 30, 0));
+                map.insert_entity(e.clone()).await?;
+                // Someone around whose screen is gone, showing them the
+                // item fails.
+                e.as_character().unwrap().set_screen(Weak::new());
+
+                let item = |x, age| FloorItemSnapshot {
+                    map_id: 1010,
+                    x,
+                    y: 30,
+                    kind: FloorItemKind::Silver(100),
+                    age,
+                    owner: None,
+                };
+                let expired = FLOOR_ITEM_EXPIRY.as_millis() as u64;
+                let snapshot = WorldSnapshot {
+                    floor_items: vec![
+                        item(10, 0),
+                        item(20, expired),
+                        item(80, 0),
+                    ],
+                    stalls: Vec::new(),
+                };
+                let next = state.ids().allocate(IdKind::FloorItem)?;
+                state.ids().free(next);
+                snapshot.restore(&state, Instant::now()).await?;
+                let restored: Vec<_> =
+                    kinds_at(&state, 1010).iter().map(|(_, x, _)| *x).collect();
+                assert_eq!(restored, [10]);
+                assert_eq!(map.floor_items()[0].id(), next);
+                // The one that could not be shown gave its id back.
+                assert_eq!(state.ids().allocate(IdKind::FloorItem)?, next + 1);
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn corrupt_snapshots_are_skipped() -> Result<(), Error> {
+        with_test_env(tracing::Level::DEBUG, |mut state, _| {
+            async move {
+                let path = snapshot_path("corrupt");
+                state.set_snapshot_path(Some(path.clone()));
+                // No snapshot at all is fine too.
+                state.restore_snapshot().await?;
+
+                tokio::fs::write(&path, b"{\"floor_items\": [{\"map").await?;
+                state.restore_snapshot().await?;
+                let items = state
+                    .loaded_maps()
+                    .into_iter()
+                    .flat_map(|m| m.floor_items())
+                    .filter(|e| matches!(**e, GameEntity::FloorItem(_)))
+                    .count();
+                assert_eq!(items, 0);
+                let _ = tokio::fs::remove_file(&path).await;
+                Ok(())
+            }
+            .boxed()
+        })
+        .await
+    }
+}
@@ -16,6 +16,8 @@ pub enum Error {
     Db(#[from] tq_db::Error),
     #[error("Realm not found")]
     RealmNotFound,
+    #[error("TRANSFER_SECRET must be a number")]
+    InvalidTransferSecret,
     #[error("Server timed out")]
     ServerTimedOut,
     #[error("Invalid password")]
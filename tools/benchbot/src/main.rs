@@ -12,7 +12,7 @@ use game::{constants, utils};
 use rand::{Rng, SeedableRng};
 use tokio::net::TcpStream;
 use tokio_stream::StreamExt;
-use tq_codec::{TQCodec, TQEncoder};
+use tq_codec::{Seal, TQCodec, TQEncoder};
 use tq_crypto::{CQCipher, Cipher};
 use tq_db::account::Account;
 use tq_db::realm::Realm;
@@ -32,6 +32,10 @@ async fn main() -> Result<(), Error> {
     let accounts = create_or_get_accounts(&state).await?;
     let maybe_realm = Realm::by_name(state.pool(), "CoEmu").await?;
     let local_ip = local_ip_address::local_ip().expect("local ip");
+    let transfer_secret: u64 = dotenvy::var("TRANSFER_SECRET")?
+        .trim()
+        .parse()
+        .map_err(|_| Error::InvalidTransferSecret)?;
     // Check if there is a realm with that name
     let realm = match maybe_realm {
         Some(realm) => realm,
@@ -56,10 +60,12 @@ async fn main() -> Result<(), Error> {
             };
             let cipher = CQCipher::new();
             let (mut encoder, mut decoder) =
-                TQCodec::new(stream, cipher).split();
+                TQCodec::new(stream, cipher, Seal::None).split();
             let transfer = auth::packets::MsgTransfer {
                 account_id: account.account_id as u32,
                 realm_id: realm.realm_id as u32,
+                version: auth::packets::TRANSFER_VERSION,
+                secret: transfer_secret,
                 ..Default::default()
             };
 
@@ -83,7 +89,7 @@ async fn main() -> Result<(), Error> {
                 TcpStream::connect(format!("127.0.0.1:{port}")).await?;
             let cipher = CQCipher::new();
             let (mut encoder, mut decoder) =
-                TQCodec::new(stream, cipher.clone()).split();
+                TQCodec::new(stream, cipher.clone(), Seal::None).split();
             encoder
                 .send(
                     packets::MsgConnect {
@@ -0,0 +1,347 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::report::Stats;
+use crate::Error;
+use bytes::Bytes;
+use game::constants;
+use game::packets::{self, ActionType, TalkChannel, TalkStyle};
+use game::utils::LoHi;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::net::TcpStream;
+use tokio_stream::StreamExt;
+use tq_codec::{Seal, TQCodec, TQEncoder};
+use tq_crypto::{CQCipher, Cipher};
+use tq_network::{PacketDecode, PacketEncode, PacketID};
+
+/// How long we wait for the server to answer a transfer.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A jump that was not echoed back by then is counted as lost.
+const ECHO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How far a single jump goes on each axis.
+const JUMP_RANGE: i16 = 4;
+
+/// What a simulated player does once in the world.
+#[derive(Debug, Clone)]
+pub struct Scenario {
+    /// Whether it walks around, or just stands there.
+    pub walk: bool,
+    /// How long it waits between two steps.
+    pub step: Duration,
+    /// The chance it says something on every step, from 0 to 1.
+    pub chat: f64,
+    /// The client version it logs in with.
+    pub build_version: u16,
+}
+
+/// How a session with the game server ended.
+enum Outcome {
+    /// The test is over, or the server closed the connection.
+    Done,
+    /// The character got created, the client logs in again with it.
+    Created,
+}
+
+/// Where a player is and what it waits for.
+struct Player {
+    character_id: u32,
+    /// Known once the server told us where we are.
+    location: Option<(u16, u16)>,
+    /// When the jump we wait the echo of was sent.
+    pending: Option<Instant>,
+    rng: StdRng,
+}
+
+/// A headless game client, logging in with a single account.
+pub struct Client {
+    account_id: u32,
+    realm_id: u32,
+    /// Sent with the transfer, like the account server does.
+    transfer_secret: u64,
+    /// The address of the game server.
+    addr: String,
+    scenario: Scenario,
+    stats: Arc<Stats>,
+    logged_in: bool,
+}
+
+impl Client {
+    pub fn new(
+        account_id: u32,
+        realm_id: u32,
+        transfer_secret: u64,
+        addr: String,
+        scenario: Scenario,
+        stats: Arc<Stats>,
+    ) -> Self {
+        Self {
+            account_id,
+            realm_id,
+            transfer_secret,
+            addr,
+            scenario,
+            stats,
+            logged_in: false,
+        }
+    }
+
+    /// Plays until `until`, creating the character first if the account has
+    /// none.
+    #[tracing::instrument(skip_all, fields(account_id = self.account_id))]
+    pub async fn run(mut self, until: Instant) -> Result<(), Error> {
+        let res = self.sessions(until).await;
+        match &res {
+            Err(_) if !self.logged_in => self.stats.connect_failed(),
+            Err(_) => self.stats.disconnected(),
+            Ok(()) => {},
+        }
+        res
+    }
+
+    async fn sessions(&mut self, until: Instant) -> Result<(), Error> {
+        // Once to create the character, and once more to play with it.
+        for _ in 0..2 {
+            let token = self.transfer().await?;
+            match self.play(token, until).await? {
+                Outcome::Done => return Ok(()),
+                Outcome::Created => continue,
+            }
+        }
+        Ok(())
+    }
+
+    /// Asks the game server for a login token, the way the account server
+    /// does.
+    async fn transfer(&self) -> Result<u64, Error> {
+        let stream = TcpStream::connect(&self.addr).await?;
+        let (mut encoder, mut decoder) =
+            TQCodec::new(stream, CQCipher::new(), Seal::None).split();
+        let transfer = auth::packets::MsgTransfer {
+            account_id: self.account_id,
+            realm_id: self.realm_id,
+            version: auth::packets::TRANSFER_VERSION,
+            secret: self.transfer_secret,
+            ..Default::default()
+        };
+        encoder.send(transfer.encode()?).await?;
+        let res = tokio::time::timeout(TRANSFER_TIMEOUT, decoder.next())
+            .await
+            .map_err(|_| Error::ServerTimedOut)?;
+        let transfer = match res {
+            Some(Ok((_, bytes))) => auth::packets::MsgTransfer::decode(&bytes)?,
+            Some(Err(e)) => return Err(e.into()),
+            None => return Err(Error::ServerTimedOut),
+        };
+        encoder.close().await?;
+        Ok(transfer.token)
+    }
+
+    async fn play(
+        &mut self,
+        token: u64,
+        until: Instant,
+    ) -> Result<Outcome, Error> {
+        let stream = TcpStream::connect(&self.addr).await?;
+        let cipher = CQCipher::new();
+        let (mut encoder, mut decoder) =
+            TQCodec::new(stream, cipher.clone(), Seal::None).split();
+        let connect = packets::MsgConnect {
+            token,
+            build_version: self.scenario.build_version,
+            language: String::from("En").into(),
+            file_contents: 10,
+        };
+        encoder.send(connect.encode()?).await?;
+        self.stats.sent();
+        cipher.generate_keys(token);
+
+        let mut me = Player {
+            character_id: 0,
+            location: None,
+            pending: None,
+            rng: StdRng::from_entropy(),
+        };
+        let mut steps = tokio::time::interval(self.scenario.step);
+        steps.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let deadline = tokio::time::sleep_until(until.into());
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                _ = &mut deadline => break,
+                _ = steps.tick() => self.step(&mut encoder, &mut me).await?,
+                packet = decoder.next() => {
+                    let Some(packet) = packet else {
+                        tracing::debug!("Server closed the connection");
+                        self.stats.disconnected();
+                        return Ok(Outcome::Done);
+                    };
+                    let (id, bytes) = packet?;
+                    self.stats.received();
+                    let outcome =
+                        self.handle(&mut encoder, &mut me, token, id, &bytes).await?;
+                    if let Some(outcome) = outcome {
+                        encoder.close().await?;
+                        return Ok(outcome);
+                    }
+                },
+            }
+        }
+        encoder.close().await?;
+        Ok(Outcome::Done)
+    }
+
+    async fn handle(
+        &mut self,
+        encoder: &mut TQEncoder<TcpStream, CQCipher>,
+        me: &mut Player,
+        token: u64,
+        id: u16,
+        bytes: &Bytes,
+    ) -> Result<Option<Outcome>, Error> {
+        match id {
+            packets::MsgTalk::PACKET_ID => {
+                let msg = packets::MsgTalk::decode(bytes)?;
+                match msg.channel.into() {
+                    TalkChannel::Login
+                        if msg.message == constants::ANSWER_OK =>
+                    {
+                        tracing::debug!("Logged in");
+                        self.logged_in = true;
+                        self.stats.logged_in();
+                    },
+                    TalkChannel::Login
+                        if msg.message == constants::NEW_ROLE =>
+                    {
+                        tracing::debug!("Creating character");
+                        let register = packets::MsgRegister {
+                            character_name: format!("lt{}", self.account_id)
+                                .into(),
+                            class: packets::BaseClass::Trojan.into(),
+                            mesh: packets::BodyType::AgileMale.into(),
+                            token: token as _,
+                            ..Default::default()
+                        };
+                        self.send(encoder, register).await?;
+                    },
+                    TalkChannel::Login => {
+                        return Err(Error::LoginRejected(msg.message));
+                    },
+                    TalkChannel::Register
+                        if msg.message == constants::ANSWER_OK =>
+                    {
+                        return Ok(Some(Outcome::Created));
+                    },
+                    TalkChannel::Register => {
+                        return Err(Error::RegisterRejected(msg.message));
+                    },
+                    _ => {},
+                }
+            },
+            packets::MsgUserInfo::PACKET_ID => {
+                let msg = packets::MsgUserInfo::decode(bytes)?;
+                me.character_id = msg.character_id;
+                let location = packets::MsgAction::new(
+                    me.character_id,
+                    0,
+                    0,
+                    0,
+                    ActionType::SendLocation,
+                );
+                self.send(encoder, location).await?;
+            },
+            packets::MsgAction::PACKET_ID => {
+                let msg = packets::MsgAction::decode(bytes)?;
+                if msg.character_id != me.character_id {
+                    return Ok(None);
+                }
+                let at = match msg.action_type.into() {
+                    ActionType::SendLocation | ActionType::Teleport => {
+                        msg.data2
+                    },
+                    ActionType::Jump => msg.data1,
+                    _ => return Ok(None),
+                };
+                me.location = Some((at.lo(), at.hi()));
+                if let Some(sent) = me.pending.take() {
+                    self.stats.record_latency(sent.elapsed());
+                }
+            },
+            packets::MsgPing::PACKET_ID => {
+                // Answered like any client would, or we look lagging.
+                let msg = packets::MsgPing::decode(bytes)?;
+                self.send(encoder, msg).await?;
+            },
+            _ => {},
+        }
+        Ok(None)
+    }
+
+    /// Takes a step to somewhere close, and maybe says something.
+    async fn step(
+        &self,
+        encoder: &mut TQEncoder<TcpStream, CQCipher>,
+        me: &mut Player,
+    ) -> Result<(), Error> {
+        let Some((x, y)) = me.location else {
+            return Ok(());
+        };
+        if let Some(sent) = me.pending {
+            if sent.elapsed() < ECHO_TIMEOUT {
+                return Ok(());
+            }
+            self.stats.timed_out();
+            me.pending = None;
+        }
+        if self.scenario.walk {
+            let mut offset = || me.rng.gen_range(-JUMP_RANGE..=JUMP_RANGE);
+            let (new_x, new_y) = (
+                x.saturating_add_signed(offset()),
+                y.saturating_add_signed(offset()),
+            );
+            let jump = packets::MsgAction::new(
+                me.character_id,
+                u32::constract(new_y, new_x),
+                u32::constract(y, x),
+                0,
+                ActionType::Jump,
+            );
+            self.send(encoder, jump).await?;
+            me.pending = Some(Instant::now());
+        }
+        if me.rng.gen_bool(self.scenario.chat) {
+            let talk = packets::MsgTalk {
+                color: 0x00FF_FFFF,
+                channel: TalkChannel::Talk.into(),
+                style: TalkStyle::Normal.into(),
+                character_id: me.character_id,
+                recipient_mesh: 0,
+                sender_mesh: 0,
+                list_count: 4,
+                sender_name: String::new(),
+                recipient_name: constants::ALL_USERS.to_string(),
+                suffix: String::new(),
+                message: String::from("Hello there!"),
+            };
+            self.send(encoder, talk).await?;
+            self.stats.chatted();
+        }
+        Ok(())
+    }
+
+    async fn send<P>(
+        &self,
+        encoder: &mut TQEncoder<TcpStream, CQCipher>,
+        packet: P,
+    ) -> Result<(), Error>
+    where
+        P: PacketEncode,
+        Error: From<P::Error>,
+    {
+        encoder.send(packet.encode()?).await?;
+        self.stats.sent();
+        Ok(())
+    }
+}
@@ -0,0 +1,27 @@
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Auth(#[from] auth::Error),
+    #[error(transparent)]
+    Network(#[from] tq_network::Error),
+    #[error(transparent)]
+    IO(#[from] std::io::Error),
+    #[error(transparent)]
+    DotEnv(#[from] dotenvy::Error),
+    #[error(transparent)]
+    Env(#[from] std::env::VarError),
+    #[error(transparent)]
+    ParseInt(#[from] std::num::ParseIntError),
+    #[error(transparent)]
+    Sqlx(#[from] sqlx::Error),
+    #[error(transparent)]
+    Db(#[from] tq_db::Error),
+    #[error("Realm not found")]
+    RealmNotFound,
+    #[error("Server timed out")]
+    ServerTimedOut,
+    #[error("Login rejected: {0}")]
+    LoginRejected(String),
+    #[error("Character could not be created: {0}")]
+    RegisterRejected(String),
+}
@@ -0,0 +1,211 @@
+//! Drives simulated players against a running game server, to see how many
+//! of them a single host could take.
+//!
+//! Every player logs in with an account of its own, creating its character
+//! on the first run, then walks around and chats until the test is over. The
+//! time it takes the server to echo every jump back is reported at the end.
+
+mod client;
+mod error;
+mod report;
+
+use std::env;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use argh::FromArgs;
+use client::{Client, Scenario};
+use error::Error;
+use futures::stream::{FuturesUnordered, StreamExt};
+use report::Stats;
+use sqlx::sqlite::{SqlitePool, SqlitePoolOptions};
+use tq_db::realm::Realm;
+
+/// Simulate players logging in, walking and chatting on a game server.
+#[derive(Debug, FromArgs)]
+struct Args {
+    /// how many players to simulate.
+    #[argh(option, default = "100")]
+    clients: usize,
+    /// how long the test runs, like `60s` or `5m`.
+    #[argh(
+        option,
+        default = "Duration::from_secs(60)",
+        from_str_fn(parse_duration)
+    )]
+    duration: Duration,
+    /// how many players log in every second until all of them are in.
+    #[argh(option, default = "50")]
+    ramp_up: u32,
+    /// the percentage of players that walk around, the others stand still.
+    #[argh(option, default = "100")]
+    walkers: u8,
+    /// the percentage of steps a player also says something on.
+    #[argh(option, default = "10")]
+    chat: u8,
+    /// how long a player waits between two steps, like `1s` or `500ms`.
+    #[argh(
+        option,
+        default = "Duration::from_secs(1)",
+        from_str_fn(parse_duration)
+    )]
+    step: Duration,
+    /// the host of the game server, its port is the one of the realm.
+    #[argh(option, default = "String::from(\"127.0.0.1\")")]
+    host: String,
+    /// the client version players log in with.
+    #[argh(option, default = "5017")]
+    build_version: u16,
+}
+
+/// Parses a duration like `60s`, `5m` or `500ms`.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration: {s:?}");
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+    let (value, unit) = s.split_at(split);
+    let value: u64 = value.parse().map_err(|_| invalid())?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(value)),
+        "s" => Ok(Duration::from_secs(value)),
+        "m" => Ok(Duration::from_secs(value * 60)),
+        _ => Err(invalid()),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let args: Args = argh::from_env();
+    dotenvy::dotenv()?;
+    let log_verbosity = env::var("LOG_VERBOSITY")
+        .map(|s| s.parse::<i32>().unwrap_or(1))
+        .unwrap_or(1);
+    setup_logger(log_verbosity)?;
+
+    let pool = connect_db().await?;
+    let realm = Realm::by_name(&pool, "CoEmu")
+        .await?
+        .ok_or(Error::RealmNotFound)?;
+    let accounts = create_or_get_accounts(&pool, args.clients).await?;
+    let transfer_secret = dotenvy::var("TRANSFER_SECRET")?.trim().parse()?;
+    let addr = format!("{}:{}", args.host, realm.game_port);
+    tracing::info!(clients = args.clients, %addr, "Starting the load test");
+
+    let stats = Arc::new(Stats::default());
+    let start = Instant::now();
+    let until = start + args.duration;
+    let mut ramp =
+        tokio::time::interval(Duration::from_secs(1) / args.ramp_up.max(1));
+    let mut tasks = FuturesUnordered::new();
+    for (i, account_id) in accounts.into_iter().enumerate() {
+        ramp.tick().await;
+        if Instant::now() >= until {
+            break;
+        }
+        let scenario = Scenario {
+            walk: i * 100 < args.clients * args.walkers as usize,
+            step: args.step,
+            chat: f64::from(args.chat.min(100)) / 100.0,
+            build_version: args.build_version,
+        };
+        let client = Client::new(
+            account_id,
+            realm.realm_id as u32,
+            transfer_secret,
+            addr.clone(),
+            scenario,
+            stats.clone(),
+        );
+        tasks.push(tokio::spawn(client.run(until)));
+    }
+
+    let all_done = async {
+        while let Some(res) = tasks.next().await {
+            match res {
+                Ok(Ok(())) => {},
+                Ok(Err(error)) => tracing::warn!(%error, "Client failed"),
+                Err(error) => tracing::error!(%error, "Client panicked"),
+            }
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {
+            tracing::info!("Ctrl-C received, reporting so far");
+        },
+        _ = all_done => {},
+    }
+    println!("{}", stats.report(args.clients, start.elapsed()));
+    Ok(())
+}
+
+async fn connect_db() -> Result<SqlitePool, Error> {
+    let data_dir = dotenvy::var("DATA_LOCATION")?;
+    let default_db_location = format!("sqlite://{data_dir}/coemu.db?mode=rwc");
+    let db_url = dotenvy::var("DATABASE_URL").unwrap_or(default_db_location);
+    let pool = SqlitePoolOptions::new()
+        .max_connections(4)
+        .connect(&db_url)
+        .await?;
+    Ok(pool)
+}
+
+/// The ids of the accounts of the simulated players, the missing ones get
+/// created. They never log in through the account server, so they have no
+/// password.
+async fn create_or_get_accounts(
+    pool: &SqlitePool,
+    count: usize,
+) -> Result<Vec<u32>, Error> {
+    let mut ids = Vec::with_capacity(count);
+    for i in 1..=count {
+        let username = format!("loadtest{i}");
+        let (id,) = sqlx::query_as::<_, (i64,)>(
+            "INSERT INTO accounts (username, password) VALUES (?, '')
+             ON CONFLICT (username) DO UPDATE SET username = excluded.username
+             RETURNING account_id;",
+        )
+        .bind(&username)
+        .fetch_one(pool)
+        .await?;
+        ids.push(id as u32);
+    }
+    Ok(ids)
+}
+
+fn setup_logger(verbosity: i32) -> Result<(), Error> {
+    use tracing::Level;
+    let log_level = match verbosity {
+        0 => Level::ERROR,
+        1 => Level::WARN,
+        2 => Level::INFO,
+        3 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
+
+    let env_filter = tracing_subscriber::EnvFilter::from_default_env()
+        .add_directive(format!("tq_db={}", log_level).parse().unwrap())
+        .add_directive(format!("tq_codec={}", log_level).parse().unwrap())
+        .add_directive(format!("tq_network={}", log_level).parse().unwrap())
+        .add_directive(format!("loadtest={}", log_level).parse().unwrap());
+    let logger = tracing_subscriber::fmt()
+        .with_target(true)
+        .with_max_level(log_level)
+        .with_env_filter(env_filter);
+    logger.init();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_parse() {
+        assert_eq!(parse_duration("60s"), Ok(Duration::from_secs(60)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert!(parse_duration("60").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("1h").is_err());
+    }
+}
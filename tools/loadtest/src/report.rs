@@ -0,0 +1,157 @@
+use parking_lot::Mutex;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// What the simulated players went through, shared by all of them.
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// How long every jump took to be echoed back.
+    latencies: Mutex<Vec<Duration>>,
+    connect_failures: AtomicU64,
+    logins: AtomicU64,
+    disconnects: AtomicU64,
+    /// Jumps that were never echoed back.
+    timeouts: AtomicU64,
+    chats: AtomicU64,
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl Stats {
+    pub fn record_latency(&self, latency: Duration) {
+        self.latencies.lock().push(latency);
+    }
+
+    pub fn connect_failed(&self) {
+        self.connect_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn logged_in(&self) { self.logins.fetch_add(1, Ordering::Relaxed); }
+
+    pub fn disconnected(&self) {
+        self.disconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn timed_out(&self) { self.timeouts.fetch_add(1, Ordering::Relaxed); }
+
+    pub fn chatted(&self) { self.chats.fetch_add(1, Ordering::Relaxed); }
+
+    pub fn sent(&self) { self.sent.fetch_add(1, Ordering::Relaxed); }
+
+    pub fn received(&self) { self.received.fetch_add(1, Ordering::Relaxed); }
+
+    /// Sums everything up, for a test that ran for `elapsed`.
+    pub fn report(&self, clients: usize, elapsed: Duration) -> Report {
+        let mut latencies = self.latencies.lock().clone();
+        latencies.sort_unstable();
+        let per_sec = |count: &AtomicU64| {
+            count.load(Ordering::Relaxed) as f64
+                / elapsed.as_secs_f64().max(f64::EPSILON)
+        };
+        Report {
+            clients,
+            elapsed,
+            logins: self.logins.load(Ordering::Relaxed),
+            connect_failures: self.connect_failures.load(Ordering::Relaxed),
+            disconnects: self.disconnects.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            chats: self.chats.load(Ordering::Relaxed),
+            samples: latencies.len(),
+            p50: percentile(&latencies, 50.0),
+            p95: percentile(&latencies, 95.0),
+            p99: percentile(&latencies, 99.0),
+            max: latencies.last().copied(),
+            sent_per_sec: per_sec(&self.sent),
+            received_per_sec: per_sec(&self.received),
+        }
+    }
+}
+
+/// The nearest rank percentile of sorted samples, `None` without samples.
+pub fn percentile(sorted: &[Duration], p: f64) -> Option<Duration> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+/// The outcome of a load test.
+#[derive(Debug)]
+pub struct Report {
+    pub clients: usize,
+    pub elapsed: Duration,
+    pub logins: u64,
+    pub connect_failures: u64,
+    pub disconnects: u64,
+    pub timeouts: u64,
+    pub chats: u64,
+    pub samples: usize,
+    pub p50: Option<Duration>,
+    pub p95: Option<Duration>,
+    pub p99: Option<Duration>,
+    pub max: Option<Duration>,
+    pub sent_per_sec: f64,
+    pub received_per_sec: f64,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |d: Option<Duration>| match d {
+            Some(d) => format!("{:.1}ms", d.as_secs_f64() * 1000.0),
+            None => String::from("-"),
+        };
+        writeln!(
+            f,
+            "Load test of {} clients for {:.1?}",
+            self.clients, self.elapsed
+        )?;
+        writeln!(f, "  logged in:        {}", self.logins)?;
+        writeln!(f, "  connect failures: {}", self.connect_failures)?;
+        writeln!(f, "  disconnects:      {}", self.disconnects)?;
+        writeln!(f, "  chats:            {}", self.chats)?;
+        writeln!(
+            f,
+            "  jump echo:        p50 {} p95 {} p99 {} max {} ({} samples, {} timed out)",
+            ms(self.p50),
+            ms(self.p95),
+            ms(self.p99),
+            ms(self.max),
+            self.samples,
+            self.timeouts,
+        )?;
+        write!(
+            f,
+            "  packets:          {:.0}/s sent, {:.0}/s received",
+            self.sent_per_sec, self.received_per_sec
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let samples: Vec<_> = (1..=200).map(Duration::from_millis).collect();
+        assert_eq!(
+            percentile(&samples, 50.0),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(
+            percentile(&samples, 95.0),
+            Some(Duration::from_millis(190))
+        );
+        assert_eq!(
+            percentile(&samples, 99.0),
+            Some(Duration::from_millis(198))
+        );
+        assert_eq!(
+            percentile(&samples[..1], 99.0),
+            Some(Duration::from_millis(1))
+        );
+        assert_eq!(percentile(&[], 50.0), None);
+    }
+}